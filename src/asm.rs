@@ -0,0 +1,631 @@
+//! Generate x86-64 assembly from BF IR without LLVM. The code is
+//! naive, but it's ready almost instantly, which suits quick
+//! edit-run loops.
+
+use std::cmp::max;
+use std::collections::HashMap;
+
+use crate::bfir::AstNode::*;
+use crate::bfir::{AstNode, Cell, EofBehaviour};
+use crate::metadata::{self, BuildMetadata};
+
+/// Options for generating assembly.
+#[derive(Debug, Clone)]
+pub struct AsmOptions {
+    /// The width of each cell: 8, 16 or 32.
+    pub cell_bits: u32,
+    /// What `,` stores at EOF.
+    pub eof: EofBehaviour,
+    /// How many cells to allocate.
+    pub tape_cells: usize,
+    /// Check the pointer stays on the tape after every `<` and `>`.
+    pub ptr_checks: bool,
+    /// If set, embed this in its own section for `bfc inspect`.
+    pub metadata: Option<BuildMetadata>,
+}
+
+/// Can we generate assembly for this target? We write GNU assembler
+/// syntax for ELF, so we support x86-64 Linux and the BSDs.
+pub fn supports_target(target_triple: &str) -> bool {
+    let mut parts = target_triple.split('-');
+    if parts.next() != Some("x86_64") {
+        return false;
+    }
+    parts.any(|part| {
+        part == "linux"
+            || part.starts_with("freebsd")
+            || part.starts_with("netbsd")
+            || part.starts_with("openbsd")
+            || part.starts_with("dragonfly")
+    })
+}
+
+const PTR_ERROR_MESSAGE: &[u8] = b"Pointer out of bounds\n";
+const ALLOC_ERROR_MESSAGE: &[u8] = b"Could not allocate the tape\n";
+
+/// Return x86-64 assembly (AT&T syntax) for a program that runs
+/// `instrs` from the start, with every cell zero. The tape has
+/// extra cells either side, so accesses at an offset from a valid
+/// cell pointer never leave the allocation.
+pub fn compile_to_asm(instrs: &[AstNode], options: &AsmOptions) -> String {
+    let mut codegen = Codegen {
+        out: String::new(),
+        next_label: 0,
+        strings: vec![],
+        cell_size: (options.cell_bits / 8) as isize,
+        options,
+    };
+    let padding = max_offset(instrs) as isize;
+    codegen.add_prologue(padding);
+    codegen.add_instrs(instrs);
+    codegen.add_epilogue();
+    codegen.out
+}
+
+/// The largest distance from the cell pointer that any instruction
+/// in `instrs` reads or writes.
+fn max_offset(instrs: &[AstNode]) -> usize {
+    let mut offset = 0;
+    for instr in instrs {
+        match *instr {
+            Increment { offset: o, .. } | Set { offset: o, .. } => {
+                offset = max(offset, o.unsigned_abs());
+            }
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
+                for o in changes.keys().chain(adds.keys()) {
+                    offset = max(offset, o.unsigned_abs());
+                }
+            }
+            Loop { ref body, .. } => {
+                offset = max(offset, max_offset(body));
+            }
+            _ => {}
+        }
+    }
+    offset
+}
+
+struct Codegen<'a> {
+    out: String,
+    next_label: usize,
+    /// The bytes of each `WriteString`, written to .rodata at the
+    /// end.
+    strings: Vec<Vec<u8>>,
+    /// The width of each cell in bytes.
+    cell_size: isize,
+    options: &'a AsmOptions,
+}
+
+impl<'a> Codegen<'a> {
+    fn emit(&mut self, line: &str) {
+        self.out.push_str("    ");
+        self.out.push_str(line);
+        self.out.push('\n');
+    }
+
+    fn emit_label(&mut self, label: &str) {
+        self.out.push_str(label);
+        self.out.push_str(":\n");
+    }
+
+    fn new_label(&mut self, name: &str) -> String {
+        let label = format!(".L{}_{}", name, self.next_label);
+        self.next_label += 1;
+        label
+    }
+
+    /// The instruction suffix for a cell sized operand.
+    fn suffix(&self) -> char {
+        match self.options.cell_bits {
+            8 => 'b',
+            16 => 'w',
+            _ => 'l',
+        }
+    }
+
+    /// The cell sized part of `%eax`.
+    fn accumulator(&self) -> &'static str {
+        match self.options.cell_bits {
+            8 => "%al",
+            16 => "%ax",
+            _ => "%eax",
+        }
+    }
+
+    /// The cell sized part of `%ecx`.
+    fn counter(&self) -> &'static str {
+        match self.options.cell_bits {
+            8 => "%cl",
+            16 => "%cx",
+            _ => "%ecx",
+        }
+    }
+
+    /// Wrap `amount` to the cell size, as a signed immediate.
+    fn immediate(&self, amount: Cell) -> i64 {
+        match self.options.cell_bits {
+            8 => i64::from(amount.0 as i8),
+            16 => i64::from(amount.0 as i16),
+            _ => i64::from(amount.0),
+        }
+    }
+
+    /// The cell at `offset` from the cell pointer in `%rbx`.
+    fn cell(&self, offset: isize) -> String {
+        if offset == 0 {
+            "(%rbx)".to_owned()
+        } else {
+            format!("{}(%rbx)", offset * self.cell_size)
+        }
+    }
+
+    /// Load the current cell into `%eax`, zero extended.
+    fn load_current_cell(&mut self) {
+        let load = match self.options.cell_bits {
+            8 => "movzbl",
+            16 => "movzwl",
+            _ => "movl",
+        };
+        self.emit(&format!("{} (%rbx), %eax", load));
+    }
+
+    /// Keep the cell pointer in %rbx, and the start and end of the
+    /// tape in %r12 and %r13. %r14 and %r15 are scratch registers
+    /// that survive calls. Five pushes keep the stack 16 byte
+    /// aligned for calls.
+    fn add_prologue(&mut self, padding: isize) {
+        self.out
+            .push_str("    .text\n    .globl main\n    .type main, @function\n");
+        self.emit_label("main");
+        for register in &["%rbx", "%r12", "%r13", "%r14", "%r15"] {
+            self.emit(&format!("pushq {}", register));
+        }
+        let total_cells = self.options.tape_cells as isize + 2 * padding;
+        self.emit(&format!("movq ${}, %rdi", total_cells));
+        self.emit(&format!("movq ${}, %rsi", self.cell_size));
+        self.emit("call calloc@PLT");
+        self.emit("testq %rax, %rax");
+        self.emit("je .Lalloc_error");
+        self.emit(&format!("leaq {}(%rax), %r12", padding * self.cell_size));
+        self.emit("movq %r12, %rbx");
+        self.emit(&format!(
+            "leaq {}(%r12), %r13",
+            self.options.tape_cells as isize * self.cell_size
+        ));
+    }
+
+    fn add_epilogue(&mut self) {
+        self.emit("xorl %eax, %eax");
+        for register in &["%r15", "%r14", "%r13", "%r12", "%rbx"] {
+            self.emit(&format!("popq {}", register));
+        }
+        self.emit("ret");
+
+        // Show the output so far, then the error.
+        for &(label, message, len) in &[
+            (
+                ".Lptr_error",
+                ".Lptr_error_message",
+                PTR_ERROR_MESSAGE.len(),
+            ),
+            (
+                ".Lalloc_error",
+                ".Lalloc_error_message",
+                ALLOC_ERROR_MESSAGE.len(),
+            ),
+        ] {
+            self.emit_label(label);
+            self.emit("xorl %edi, %edi");
+            self.emit("call fflush@PLT");
+            self.emit("movl $2, %edi");
+            self.emit(&format!("leaq {}(%rip), %rsi", message));
+            self.emit(&format!("movq ${}, %rdx", len));
+            self.emit("call write@PLT");
+            self.emit("call abort@PLT");
+        }
+        self.emit(".size main, .-main");
+
+        self.emit(".section .rodata");
+        self.emit_label(".Lptr_error_message");
+        self.add_bytes(PTR_ERROR_MESSAGE);
+        self.emit_label(".Lalloc_error_message");
+        self.add_bytes(ALLOC_ERROR_MESSAGE);
+        let strings = std::mem::take(&mut self.strings);
+        for (i, bytes) in strings.iter().enumerate() {
+            self.emit_label(&format!(".Lstring_{}", i));
+            self.add_bytes(bytes);
+        }
+
+        if let Some(ref build_metadata) = self.options.metadata {
+            self.emit(&format!(
+                ".section {},\"a\"",
+                metadata::section_name(&build_metadata.target)
+            ));
+            self.add_bytes(&build_metadata.to_bytes());
+        }
+
+        // We don't need an executable stack.
+        self.emit(".section .note.GNU-stack,\"\",@progbits");
+    }
+
+    fn add_bytes(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(16) {
+            let values: Vec<String> = chunk.iter().map(|byte| byte.to_string()).collect();
+            self.emit(&format!(".byte {}", values.join(", ")));
+        }
+    }
+
+    fn add_instrs(&mut self, instrs: &[AstNode]) {
+        for instr in instrs {
+            self.add_instr(instr);
+        }
+    }
+
+    fn add_instr(&mut self, instr: &AstNode) {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let line = format!(
+                    "add{} ${}, {}",
+                    self.suffix(),
+                    self.immediate(amount),
+                    self.cell(offset)
+                );
+                self.emit(&line);
+            }
+            Set { amount, offset, .. } => {
+                let line = format!(
+                    "mov{} ${}, {}",
+                    self.suffix(),
+                    self.immediate(amount),
+                    self.cell(offset)
+                );
+                self.emit(&line);
+            }
+            PointerIncrement { amount, .. } => self.add_ptr_increment(amount),
+            Read { .. } => self.add_read(),
+            SkipRead { count, .. } => {
+                // Read `count` times, storing each byte as we go.
+                let label = self.new_label("skip_read");
+                self.emit(&format!("movq ${}, %r14", count));
+                self.emit_label(&label);
+                self.add_read();
+                self.emit("decq %r14");
+                self.emit(&format!("jne {}", label));
+            }
+            Write { .. } => {
+                self.load_current_cell();
+                self.emit("movl %eax, %edi");
+                self.emit("call putchar@PLT");
+            }
+            WriteString { ref bytes, .. } => self.add_write_string(bytes),
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => self.add_multiply_move(changes, adds),
+            Loop { ref body, .. } => {
+                let start = self.new_label("loop_start");
+                let end = self.new_label("loop_end");
+                self.emit_label(&start);
+                self.emit(&format!("cmp{} $0, (%rbx)", self.suffix()));
+                self.emit(&format!("je {}", end));
+                self.add_instrs(body);
+                self.emit(&format!("jmp {}", start));
+                self.emit_label(&end);
+            }
+        }
+    }
+
+    fn add_ptr_increment(&mut self, amount: isize) {
+        let bytes = amount as i64 * self.cell_size as i64;
+        if bytes == i64::from(bytes as i32) {
+            self.emit(&format!("addq ${}, %rbx", bytes));
+        } else {
+            self.emit(&format!("movabsq ${}, %rax", bytes));
+            self.emit("addq %rax, %rbx");
+        }
+        if self.options.ptr_checks {
+            self.emit("cmpq %r12, %rbx");
+            self.emit("jb .Lptr_error");
+            self.emit("cmpq %r13, %rbx");
+            self.emit("jae .Lptr_error");
+        }
+    }
+
+    /// Read a byte into the current cell. getchar returns -1 at EOF,
+    /// which is what we store by default.
+    fn add_read(&mut self) {
+        self.emit("call getchar@PLT");
+        let store = format!("mov{} {}, (%rbx)", self.suffix(), self.accumulator());
+        match self.options.eof {
+            EofBehaviour::MinusOne => self.emit(&store),
+            EofBehaviour::Zero => {
+                let label = self.new_label("read_byte");
+                self.emit("cmpl $-1, %eax");
+                self.emit(&format!("jne {}", label));
+                self.emit("xorl %eax, %eax");
+                self.emit_label(&label);
+                self.emit(&store);
+            }
+            EofBehaviour::Unchanged => {
+                let label = self.new_label("read_eof");
+                self.emit("cmpl $-1, %eax");
+                self.emit(&format!("je {}", label));
+                self.emit(&store);
+                self.emit_label(&label);
+            }
+        }
+    }
+
+    fn add_write_string(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let string = format!(".Lstring_{}", self.strings.len());
+        self.strings.push(bytes.to_vec());
+        let label = self.new_label("write_string");
+        self.emit(&format!("leaq {}(%rip), %r14", string));
+        self.emit(&format!("leaq {}+{}(%rip), %r15", string, bytes.len()));
+        self.emit_label(&label);
+        self.emit("movzbl (%r14), %edi");
+        self.emit("call putchar@PLT");
+        self.emit("incq %r14");
+        self.emit("cmpq %r15, %r14");
+        self.emit(&format!("jne {}", label));
+    }
+
+    /// If the current cell is nonzero, add it times each factor to
+    /// the cell at that offset and zero it. Then apply `adds`.
+    fn add_multiply_move(&mut self, changes: &HashMap<isize, Cell>, adds: &HashMap<isize, Cell>) {
+        let done = self.new_label("multiply_done");
+        self.load_current_cell();
+        self.emit("testl %eax, %eax");
+        self.emit(&format!("je {}", done));
+
+        // Sort by offset, so the output is deterministic.
+        let mut changes: Vec<_> = changes.iter().collect();
+        changes.sort_by_key(|&(offset, _)| *offset);
+        for (&offset, &factor) in changes {
+            let multiply = format!("imull ${}, %eax, %ecx", self.immediate(factor));
+            self.emit(&multiply);
+            let add = format!(
+                "add{} {}, {}",
+                self.suffix(),
+                self.counter(),
+                self.cell(offset)
+            );
+            self.emit(&add);
+        }
+        self.emit(&format!("mov{} $0, (%rbx)", self.suffix()));
+        self.emit_label(&done);
+
+        let mut adds: Vec<_> = adds.iter().collect();
+        adds.sort_by_key(|&(offset, _)| *offset);
+        for (&offset, &amount) in adds {
+            self.add_instr(&Increment {
+                amount,
+                offset,
+                position: None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use pretty_assertions::assert_eq;
+    use std::num::Wrapping;
+
+    fn options() -> AsmOptions {
+        AsmOptions {
+            cell_bits: 8,
+            eof: EofBehaviour::MinusOne,
+            tape_cells: 30000,
+            ptr_checks: false,
+            metadata: None,
+        }
+    }
+
+    /// The lines between the prologue and the epilogue.
+    fn body_lines(asm: &str) -> Vec<&str> {
+        let lines: Vec<&str> = asm.lines().map(|line| line.trim()).collect();
+        let start = lines
+            .iter()
+            .position(|&line| line == "movq %r12, %rbx")
+            .unwrap()
+            + 2;
+        let end = lines
+            .iter()
+            .position(|&line| line == "xorl %eax, %eax")
+            .unwrap();
+        lines[start..end].to_vec()
+    }
+
+    #[test]
+    fn supported_targets() {
+        assert!(supports_target("x86_64-pc-linux-gnu"));
+        assert!(supports_target("x86_64-unknown-linux-musl"));
+        assert!(supports_target("x86_64-unknown-freebsd12.1"));
+        assert!(!supports_target("x86_64-apple-darwin19.6.0"));
+        assert!(!supports_target("x86_64-pc-windows-msvc"));
+        assert!(!supports_target("aarch64-unknown-linux-gnu"));
+        assert!(!supports_target("i686-pc-linux-gnu"));
+    }
+
+    #[test]
+    fn lower_core_instructions() {
+        let instrs = parse("+>-<[.,]").unwrap();
+        let asm = compile_to_asm(&instrs, &options());
+        assert_eq!(
+            body_lines(&asm),
+            vec![
+                "addb $1, (%rbx)",
+                "addq $1, %rbx",
+                "addb $-1, (%rbx)",
+                "addq $-1, %rbx",
+                ".Lloop_start_0:",
+                "cmpb $0, (%rbx)",
+                "je .Lloop_end_1",
+                "movzbl (%rbx), %eax",
+                "movl %eax, %edi",
+                "call putchar@PLT",
+                "call getchar@PLT",
+                "movb %al, (%rbx)",
+                "jmp .Lloop_start_0",
+                ".Lloop_end_1:",
+            ]
+        );
+    }
+
+    #[test]
+    fn lower_wide_cells_at_offsets() {
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(-2),
+                offset: 3,
+                position: None,
+            },
+            Set {
+                amount: Wrapping(70000),
+                offset: -1,
+                position: None,
+            },
+        ];
+        let asm = compile_to_asm(
+            &instrs,
+            &AsmOptions {
+                cell_bits: 16,
+                ..options()
+            },
+        );
+        assert_eq!(
+            body_lines(&asm),
+            vec!["addw $-2, 6(%rbx)", "movw $4464, -2(%rbx)"]
+        );
+        // The tape has room for the offsets either side.
+        assert!(asm.contains("movq $30006, %rdi"));
+        assert!(asm.contains("leaq 6(%rax), %r12"));
+    }
+
+    #[test]
+    fn lower_multiply_move() {
+        let mut changes = HashMap::new();
+        changes.insert(2, Wrapping(3));
+        changes.insert(1, Wrapping(-1));
+        let mut adds = HashMap::new();
+        adds.insert(1, Wrapping(5));
+        let instrs = vec![MultiplyMove {
+            changes,
+            adds,
+            position: None,
+        }];
+        let asm = compile_to_asm(&instrs, &options());
+        assert_eq!(
+            body_lines(&asm),
+            vec![
+                "movzbl (%rbx), %eax",
+                "testl %eax, %eax",
+                "je .Lmultiply_done_0",
+                "imull $-1, %eax, %ecx",
+                "addb %cl, 1(%rbx)",
+                "imull $3, %eax, %ecx",
+                "addb %cl, 2(%rbx)",
+                "movb $0, (%rbx)",
+                ".Lmultiply_done_0:",
+                "addb $5, 1(%rbx)",
+            ]
+        );
+    }
+
+    #[test]
+    fn lower_read_at_eof() {
+        let instrs = parse(",").unwrap();
+        let asm = compile_to_asm(
+            &instrs,
+            &AsmOptions {
+                eof: EofBehaviour::Unchanged,
+                ..options()
+            },
+        );
+        assert_eq!(
+            body_lines(&asm),
+            vec![
+                "call getchar@PLT",
+                "cmpl $-1, %eax",
+                "je .Lread_eof_0",
+                "movb %al, (%rbx)",
+                ".Lread_eof_0:",
+            ]
+        );
+    }
+
+    #[test]
+    fn write_strings_from_rodata() {
+        let instrs = vec![WriteString {
+            bytes: b"hi".to_vec(),
+            position: None,
+        }];
+        let asm = compile_to_asm(&instrs, &options());
+        assert_eq!(
+            &body_lines(&asm)[..2],
+            &[
+                "leaq .Lstring_0(%rip), %r14",
+                "leaq .Lstring_0+2(%rip), %r15"
+            ]
+        );
+        assert!(asm.contains(".Lstring_0:\n    .byte 104, 105\n"));
+    }
+
+    #[test]
+    fn embed_build_metadata() {
+        let build_metadata = BuildMetadata::new(b"", "x86_64-pc-linux-gnu", vec![]);
+        let asm = compile_to_asm(
+            &[],
+            &AsmOptions {
+                metadata: Some(build_metadata.clone()),
+                ..options()
+            },
+        );
+        let section = asm.find(".section .bfc_metadata,\"a\"").unwrap();
+        let bytes: Vec<u8> = asm[section..]
+            .lines()
+            .skip(1)
+            .take_while(|line| line.trim().starts_with(".byte"))
+            .flat_map(|line| {
+                line.trim()[".byte ".len()..]
+                    .split(", ")
+                    .map(|byte| byte.parse::<u8>().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(bytes, build_metadata.to_bytes());
+    }
+
+    #[test]
+    fn check_pointer_when_asked() {
+        let instrs = parse(">").unwrap();
+        let asm = compile_to_asm(
+            &instrs,
+            &AsmOptions {
+                ptr_checks: true,
+                ..options()
+            },
+        );
+        assert_eq!(
+            body_lines(&asm),
+            vec![
+                "addq $1, %rbx",
+                "cmpq %r12, %rbx",
+                "jb .Lptr_error",
+                "cmpq %r13, %rbx",
+                "jae .Lptr_error",
+            ]
+        );
+    }
+}
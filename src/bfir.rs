@@ -5,17 +5,52 @@
 //! It also provides functions for generating ASTs from source code,
 //! producing good error messages on malformed inputs.
 
+use std::cmp::{max, min};
 use std::collections::HashMap;
 use std::fmt;
 use std::mem::{replace, take};
 use std::num::Wrapping;
 
+use itertools::Itertools;
+
+use crate::diagnostics::Code;
+
 use self::AstNode::*;
 
 /// A cell is the fundamental BF datatype that we work with. BF
-/// requires this to be at least one byte, we provide a cell of
-/// exactly one byte.
-pub type Cell = Wrapping<i8>;
+/// requires this to be at least one byte. We store values in 32 bits
+/// so we can support wider cells, which `--cell-size` chooses at
+/// runtime: see `wrap_cell`.
+pub type Cell = Wrapping<i32>;
+
+/// The width of a cell at runtime, in bits, unless `--cell-size`
+/// says otherwise.
+pub const DEFAULT_CELL_BITS: u32 = 8;
+
+/// Reduce `value` to a cell of `cell_bits` bits, so values that are
+/// equal at runtime are equal here too. The result is sign-extended,
+/// so on 8-bit cells 255 is represented as -1.
+pub fn wrap_cell(value: Cell, cell_bits: u32) -> Cell {
+    if cell_bits >= 32 {
+        value
+    } else {
+        let unused_bits = 32 - cell_bits;
+        Wrapping((value.0 << unused_bits) >> unused_bits)
+    }
+}
+
+/// What `,` does once stdin is exhausted. Implementations disagree,
+/// so programs may rely on any of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofBehaviour {
+    /// Leave the current cell unchanged.
+    Unchanged,
+    /// Set the current cell to 0.
+    Zero,
+    /// Set the current cell to -1, as `getchar` returns at EOF.
+    #[default]
+    MinusOne,
+}
 
 /// An inclusive range used for tracking positions in source code.
 #[derive(PartialEq, Eq, Clone, Copy)]
@@ -38,32 +73,23 @@ pub trait Combine {
     fn combine(&self, _: Self) -> Self;
 }
 
-impl<T: Combine> Combine for Option<T> {
+impl<T: Combine + Clone> Combine for Option<T> {
     fn combine(&self, other: Self) -> Self {
         match (self.as_ref(), other) {
             (Some(a), Some(b)) => Some(a.combine(b)),
-            _ => None,
+            (Some(a), None) => Some(a.clone()),
+            (None, other) => other,
         }
     }
 }
 
 impl Combine for Position {
     fn combine(&self, other: Self) -> Self {
-        let (first_pos, second_pos) = if self.start <= other.start {
-            (*self, other)
-        } else {
-            (other, *self)
-        };
-
-        // If they're not adjacent positions, just use the second position.
-        if first_pos.end + 1 < second_pos.start {
-            return other
-        }
-
-        // If they're adjacent positions, we can merge them.
+        // Take the union of the two positions, so a merged
+        // instruction covers all the source code it came from.
         Position {
-            start: first_pos.start,
-            end: second_pos.end,
+            start: min(self.start, other.start),
+            end: max(self.end, other.end),
         }
     }
 }
@@ -97,8 +123,25 @@ pub enum AstNode {
         offset: isize,
         position: Option<Position>,
     },
+    /// If the current cell is nonzero, add the current cell
+    /// multiplied by each factor in `changes` to the cell at that
+    /// offset, then zero the current cell. Then add each amount in
+    /// `adds` to the cell at that offset.
     MultiplyMove {
         changes: HashMap<isize, Cell>,
+        adds: HashMap<isize, Cell>,
+        position: Option<Position>,
+    },
+    /// Read `count` bytes from stdin, storing only the last one in
+    /// the current cell.
+    SkipRead {
+        count: usize,
+        position: Option<Position>,
+    },
+    /// Write these bytes to stdout. We generate this when we know the
+    /// values of cells at compile time.
+    WriteString {
+        bytes: Vec<u8>,
         position: Option<Position>,
     },
 }
@@ -143,11 +186,25 @@ pub fn get_position(instr: &AstNode) -> Option<Position> {
         Loop { position, .. } => position,
         Set { position, .. } => position,
         MultiplyMove { position, .. } => position,
+        SkipRead { position, .. } => position,
+        WriteString { position, .. } => position,
     }
 }
 
+/// The number of IR nodes in `instrs`, including loop bodies.
+pub fn count_instrs(instrs: &[AstNode]) -> usize {
+    instrs
+        .iter()
+        .map(|instr| match *instr {
+            Loop { ref body, .. } => 1 + count_instrs(body),
+            _ => 1,
+        })
+        .sum()
+}
+
 #[derive(Debug)]
 pub struct ParseError {
+    pub code: Code,
     pub message: String,
     pub position: Position,
 }
@@ -165,6 +222,7 @@ pub fn parse(source: &str) -> Result<Vec<AstNode>, ParseError> {
     if !stack.is_empty() {
         let pos = stack.last().unwrap().1;
         return Err(ParseError {
+            code: Code::UnmatchedOpen,
             message: "This [ has no matching ]".to_owned(),
             position: Position {
                 start: pos,
@@ -244,6 +302,7 @@ pub fn parse_inner(
                     });
                 } else {
                     return Err(ParseError {
+                        code: Code::UnmatchedClose,
                         message: "This ] has no matching [".to_owned(),
                         position: Position {
                             start: index,
@@ -259,12 +318,282 @@ pub fn parse_inner(
     Ok(())
 }
 
+pub(crate) fn serialize_position(position: Option<Position>) -> String {
+    match position {
+        Some(Position { start, end }) => format!("{}-{}", start, end),
+        None => "-".to_owned(),
+    }
+}
+
+fn serialize_with_indent(instrs: &[AstNode], indent: usize, result: &mut String) {
+    for instr in instrs {
+        for _ in 0..indent {
+            result.push_str("  ");
+        }
+
+        match *instr {
+            Increment {
+                amount,
+                offset,
+                position,
+            } => {
+                result.push_str(&format!(
+                    "inc {} {} {}",
+                    amount.0,
+                    offset,
+                    serialize_position(position)
+                ));
+            }
+            PointerIncrement { amount, position } => {
+                result.push_str(&format!("ptr {} {}", amount, serialize_position(position)));
+            }
+            Read { position } => {
+                result.push_str(&format!("read {}", serialize_position(position)));
+            }
+            Write { position } => {
+                result.push_str(&format!("write {}", serialize_position(position)));
+            }
+            SkipRead { count, position } => {
+                result.push_str(&format!(
+                    "skipread {} {}",
+                    count,
+                    serialize_position(position)
+                ));
+            }
+            WriteString {
+                ref bytes,
+                position,
+            } => {
+                let bytes_text = if bytes.is_empty() {
+                    "-".to_owned()
+                } else {
+                    bytes.iter().join(",")
+                };
+                result.push_str(&format!(
+                    "writestr {} {}",
+                    bytes_text,
+                    serialize_position(position)
+                ));
+            }
+            Set {
+                amount,
+                offset,
+                position,
+            } => {
+                result.push_str(&format!(
+                    "set {} {} {}",
+                    amount.0,
+                    offset,
+                    serialize_position(position)
+                ));
+            }
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                position,
+            } => {
+                result.push_str(&format!(
+                    "mul {} {} {}",
+                    serialize_offset_map(changes),
+                    serialize_offset_map(adds),
+                    serialize_position(position)
+                ));
+            }
+            Loop { ref body, position } => {
+                result.push_str(&format!("loop {}\n", serialize_position(position)));
+                serialize_with_indent(body, indent + 1, result);
+                for _ in 0..indent {
+                    result.push_str("  ");
+                }
+                result.push_str("end");
+            }
+        }
+        result.push('\n');
+    }
+}
+
+/// Convert BF IR to a line-based text format that can be passed to
+/// external tools, such as pass plugins. One instruction is written
+/// per line, e.g.
+///
+/// ```text
+/// set 0 0 0-0
+/// loop 1-6
+///   inc -1 0 2-2
+///   mul 1:2,3:1 3-5
+/// end
+/// ```
+///
+/// Positions are written as `START-END`, or `-` if absent.
+pub fn serialize(instrs: &[AstNode]) -> String {
+    let mut result = String::new();
+    serialize_with_indent(instrs, 0, &mut result);
+    result
+}
+
+pub(crate) fn deserialize_position(token: &str) -> Result<Option<Position>, String> {
+    if token == "-" {
+        return Ok(None);
+    }
+
+    let mut parts = token.splitn(2, '-');
+    match (parts.next(), parts.next()) {
+        (Some(start), Some(end)) => match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) => Ok(Some(Position { start, end })),
+            _ => Err(format!("Invalid position '{}'", token)),
+        },
+        _ => Err(format!("Invalid position '{}'", token)),
+    }
+}
+
+/// Serialise a map of offsets to cell values as `offset:value,...`,
+/// or `-` if empty.
+fn serialize_offset_map(map: &HashMap<isize, Cell>) -> String {
+    let mut offsets: Vec<_> = map.keys().collect();
+    offsets.sort();
+    if offsets.is_empty() {
+        "-".to_owned()
+    } else {
+        offsets
+            .into_iter()
+            .map(|offset| format!("{}:{}", offset, map[offset].0))
+            .join(",")
+    }
+}
+
+fn deserialize_offset_map(token: &str) -> Result<HashMap<isize, Cell>, String> {
+    let mut map = HashMap::new();
+    if token != "-" {
+        for change in token.split(',') {
+            let mut parts = change.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(offset), Some(value)) => {
+                    map.insert(
+                        deserialize_number(offset)?,
+                        Wrapping(deserialize_number(value)?),
+                    );
+                }
+                _ => return Err(format!("Invalid change '{}'", change)),
+            }
+        }
+    }
+    Ok(map)
+}
+
+fn deserialize_number<T: std::str::FromStr>(token: &str) -> Result<T, String> {
+    token
+        .parse()
+        .map_err(|_| format!("Invalid number '{}'", token))
+}
+
+/// Parse the text format produced by `serialize` back into BF IR.
+pub fn deserialize(text: &str) -> Result<Vec<AstNode>, String> {
+    // Instructions in the current loop (or toplevel), and the
+    // instructions of open parent loops with their positions.
+    let mut instructions = vec![];
+    let mut stack: Vec<(Vec<AstNode>, Option<Position>)> = vec![];
+
+    for (line_idx, line) in text.lines().enumerate() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let wrong_arity = || {
+            format!(
+                "Line {}: wrong number of arguments for '{}'",
+                line_idx + 1,
+                tokens[0]
+            )
+        };
+        let with_line = |e: String| format!("Line {}: {}", line_idx + 1, e);
+
+        match (tokens[0], tokens.len()) {
+            ("inc", 4) => instructions.push(Increment {
+                amount: Wrapping(deserialize_number(tokens[1]).map_err(with_line)?),
+                offset: deserialize_number(tokens[2]).map_err(with_line)?,
+                position: deserialize_position(tokens[3]).map_err(with_line)?,
+            }),
+            ("ptr", 3) => instructions.push(PointerIncrement {
+                amount: deserialize_number(tokens[1]).map_err(with_line)?,
+                position: deserialize_position(tokens[2]).map_err(with_line)?,
+            }),
+            ("read", 2) => instructions.push(Read {
+                position: deserialize_position(tokens[1]).map_err(with_line)?,
+            }),
+            ("write", 2) => instructions.push(Write {
+                position: deserialize_position(tokens[1]).map_err(with_line)?,
+            }),
+            ("skipread", 3) => instructions.push(SkipRead {
+                count: deserialize_number(tokens[1]).map_err(with_line)?,
+                position: deserialize_position(tokens[2]).map_err(with_line)?,
+            }),
+            ("writestr", 3) => {
+                let mut bytes = vec![];
+                if tokens[1] != "-" {
+                    for byte in tokens[1].split(',') {
+                        bytes.push(deserialize_number(byte).map_err(with_line)?);
+                    }
+                }
+                instructions.push(WriteString {
+                    bytes,
+                    position: deserialize_position(tokens[2]).map_err(with_line)?,
+                });
+            }
+            ("set", 4) => instructions.push(Set {
+                amount: Wrapping(deserialize_number(tokens[1]).map_err(with_line)?),
+                offset: deserialize_number(tokens[2]).map_err(with_line)?,
+                position: deserialize_position(tokens[3]).map_err(with_line)?,
+            }),
+            ("mul", 4) => instructions.push(MultiplyMove {
+                changes: deserialize_offset_map(tokens[1]).map_err(with_line)?,
+                adds: deserialize_offset_map(tokens[2]).map_err(with_line)?,
+                position: deserialize_position(tokens[3]).map_err(with_line)?,
+            }),
+            ("loop", 2) => {
+                let position = deserialize_position(tokens[1]).map_err(with_line)?;
+                stack.push((take(&mut instructions), position));
+            }
+            ("end", 1) => match stack.pop() {
+                Some((parent_instrs, position)) => {
+                    let body = replace(&mut instructions, parent_instrs);
+                    instructions.push(Loop { body, position });
+                }
+                None => return Err(with_line("'end' without a matching 'loop'".to_owned())),
+            },
+            ("inc", _)
+            | ("ptr", _)
+            | ("read", _)
+            | ("write", _)
+            | ("set", _)
+            | ("mul", _)
+            | ("skipread", _)
+            | ("writestr", _)
+            | ("loop", _)
+            | ("end", _) => return Err(wrong_arity()),
+            (other, _) => return Err(with_line(format!("Unknown instruction '{}'", other))),
+        }
+    }
+
+    if !stack.is_empty() {
+        return Err("'loop' without a matching 'end'".to_owned());
+    }
+
+    Ok(instructions)
+}
+
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
+    use quickcheck::quickcheck;
 
     use super::*;
 
+    #[test]
+    fn count_instrs_in_loops() {
+        assert_eq!(count_instrs(&parse("+[>[-]<]").unwrap()), 6);
+    }
+
     #[test]
     fn parse_increment() {
         assert_eq!(
@@ -513,12 +842,29 @@ mod tests {
         assert_eq!(
             pos1.combine(pos2),
             Some(Position {
-                start: 4,
+                start: 1,
                 end: 5,
             })
         );
     }
 
+    #[test]
+    fn test_wrap_cell() {
+        assert_eq!(wrap_cell(Wrapping(256), 8), Wrapping(0));
+        assert_eq!(wrap_cell(Wrapping(255), 8), Wrapping(-1));
+        assert_eq!(wrap_cell(Wrapping(-129), 8), Wrapping(127));
+        assert_eq!(wrap_cell(Wrapping(256), 16), Wrapping(256));
+        assert_eq!(wrap_cell(Wrapping(-5), 32), Wrapping(-5));
+    }
+
+    #[test]
+    fn test_combine_pos_unknown() {
+        let pos = Some(Position { start: 1, end: 2 });
+
+        assert_eq!(pos.combine(None), pos);
+        assert_eq!(None.combine(pos), pos);
+    }
+
     #[test]
     fn test_combine_pos_overlap() {
         let pos1 = Some(Position {
@@ -538,4 +884,63 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn serialize_format() {
+        let instrs = parse("+[-]>,.").unwrap();
+        assert_eq!(
+            serialize(&instrs),
+            "inc 1 0 0-0\nloop 1-3\n  inc -1 0 2-2\nend\nptr 1 4-4\nread 5-5\nwrite 6-6\n"
+        );
+    }
+
+    #[test]
+    fn serialize_round_trip() {
+        let mut changes = HashMap::new();
+        changes.insert(-1, Wrapping(3));
+        changes.insert(2, Wrapping(-1));
+        let mut adds = HashMap::new();
+        adds.insert(2, Wrapping(4));
+        let instrs = vec![
+            Set {
+                amount: Wrapping(5),
+                offset: -2,
+                position: None,
+            },
+            Loop {
+                body: vec![MultiplyMove {
+                    changes,
+                    adds,
+                    position: Some(Position { start: 3, end: 8 }),
+                }],
+                position: Some(Position { start: 1, end: 9 }),
+            },
+            SkipRead {
+                count: 3,
+                position: Some(Position { start: 10, end: 12 }),
+            },
+            WriteString {
+                bytes: b"hi\n".to_vec(),
+                position: Some(Position { start: 13, end: 20 }),
+            },
+        ];
+        assert_eq!(deserialize(&serialize(&instrs)).unwrap(), instrs);
+    }
+
+    #[test]
+    fn quickcheck_serialize_round_trip() {
+        fn round_trips(instrs: Vec<AstNode>) -> bool {
+            deserialize(&serialize(&instrs)) == Ok(instrs)
+        }
+        quickcheck(round_trips as fn(Vec<AstNode>) -> bool);
+    }
+
+    #[test]
+    fn deserialize_invalid() {
+        assert!(deserialize("loop -").is_err());
+        assert!(deserialize("end").is_err());
+        assert!(deserialize("inc 1 0").is_err());
+        assert!(deserialize("inc x 0 -").is_err());
+        assert!(deserialize("jump 1").is_err());
+    }
 }
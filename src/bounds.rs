@@ -1,10 +1,12 @@
 //! Calculate the maximum cell accessed by a BF program.
 
-use std::cmp::{max, Ord, Ordering};
+use std::cmp::{max, min, Ord, Ordering};
+use std::collections::HashMap;
+use std::fmt;
 use std::ops::Add;
 
-use crate::bfir::AstNode;
 use crate::bfir::AstNode::*;
+use crate::bfir::{get_position, AstNode, Combine, Position};
 
 // 100,000 cells, zero-indexed.
 pub const MAX_CELL_INDEX: usize = 99999;
@@ -12,36 +14,409 @@ pub const MAX_CELL_INDEX: usize = 99999;
 /// Return the highest cell index that can be reached during program
 /// execution. Zero-indexed.
 pub fn highest_cell_index(instrs: &[AstNode]) -> usize {
-    let (highest_index, _) = overall_movement(instrs);
+    match highest_cell_bound(instrs) {
+        Some(x) if x <= MAX_CELL_INDEX as i64 => x as usize,
+        // TODO: generate a warning here.
+        _ => MAX_CELL_INDEX,
+    }
+}
+
+/// Return the highest cell index that can be reached during program
+/// execution, or None if we can't bound it. Unlike
+/// `highest_cell_index`, this may be larger than `MAX_CELL_INDEX`.
+pub fn highest_cell_bound(instrs: &[AstNode]) -> Option<i64> {
+    match program_movement(instrs, None).highest {
+        SaturatingInt::Number(x) => Some(x),
+        SaturatingInt::Max => None,
+        SaturatingInt::Min => unreachable!("The highest index is at least the starting cell"),
+    }
+}
+
+/// Return the lowest cell index that can be reached during program
+/// execution, on a tape that extends left of the first cell. This is
+/// zero or negative, and no lower than `-MAX_CELL_INDEX`.
+pub fn lowest_cell_index(instrs: &[AstNode]) -> isize {
+    match lowest_cell_bound(instrs) {
+        Some(x) if x >= -(MAX_CELL_INDEX as i64) => x as isize,
+        _ => -(MAX_CELL_INDEX as isize),
+    }
+}
+
+/// Return the lowest cell index that can be reached during program
+/// execution, or None if we can't bound it.
+pub fn lowest_cell_bound(instrs: &[AstNode]) -> Option<i64> {
+    match program_movement(instrs, None).lowest {
+        SaturatingInt::Number(x) => Some(x),
+        SaturatingInt::Min => None,
+        SaturatingInt::Max => unreachable!("The lowest index is at most the starting cell"),
+    }
+}
+
+/// How many iterations of a loop moving right we assume by default,
+/// when we can't bound the cells it uses.
+pub const DEFAULT_ITERATION_CAP: u64 = 100;
+
+/// How many cells to allocate for a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TapeSize {
+    pub cells: usize,
+    /// True if `cells` is a guess, and the program may need more.
+    pub heuristic: bool,
+}
+
+/// Decide how many cells to allocate for `instrs`. If we can't bound
+/// the highest cell, assume loops moving right run at most
+/// `iteration_cap` times, rather than allocating every cell.
+pub fn tape_size(instrs: &[AstNode], iteration_cap: u64) -> TapeSize {
+    if highest_cell_bound(instrs).is_some() {
+        return TapeSize {
+            cells: highest_cell_index(instrs) + 1,
+            heuristic: false,
+        };
+    }
+
+    let iteration_cap = min(max(iteration_cap, 1), MAX_CELL_INDEX as u64 + 1);
+    match program_movement(instrs, Some(iteration_cap)).highest {
+        SaturatingInt::Number(x) if x < MAX_CELL_INDEX as i64 => TapeSize {
+            cells: x as usize + 1,
+            heuristic: true,
+        },
+        _ => TapeSize {
+            cells: MAX_CELL_INDEX + 1,
+            heuristic: false,
+        },
+    }
+}
 
-    match highest_index {
-        SaturatingInt::Number(x) => {
-            if x > MAX_CELL_INDEX as i64 {
-                // TODO: generate a warning here.
-                MAX_CELL_INDEX
+/// Return the loops that stop us bounding the highest cell index,
+/// because their iterations may move the pointer right. If a loop
+/// is unbounded because of a loop inside it, we only return the
+/// inner loop.
+pub fn unbounded_loops(instrs: &[AstNode]) -> Vec<&AstNode> {
+    let mut loops = vec![];
+    for instr in instrs {
+        if let Loop { ref body, .. } = *instr {
+            let inner_loops = unbounded_loops(body);
+            if inner_loops.is_empty() {
+                if overall_movement(body, None).net_max > SaturatingInt::Number(0) {
+                    loops.push(instr);
+                }
             } else {
-                x as usize
+                loops.extend(inner_loops);
             }
         }
-        SaturatingInt::Max => MAX_CELL_INDEX,
     }
+    loops
+}
+
+/// A range of cell offsets, relative to the starting cell. None
+/// means unbounded in that direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OffsetRange {
+    pub lowest: Option<i64>,
+    pub highest: Option<i64>,
+}
+
+impl OffsetRange {
+    fn new(lowest: SaturatingInt, highest: SaturatingInt) -> Self {
+        OffsetRange {
+            lowest: match lowest {
+                SaturatingInt::Number(x) => Some(x),
+                _ => None,
+            },
+            highest: match highest {
+                SaturatingInt::Number(x) => Some(x),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for OffsetRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.lowest, self.highest) {
+            (Some(lowest), Some(highest)) if lowest == highest => write!(f, "{}", lowest),
+            (lowest, highest) => {
+                if let Some(lowest) = lowest {
+                    write!(f, "{}", lowest)?;
+                }
+                write!(f, "..")?;
+                if let Some(highest) = highest {
+                    write!(f, "{}", highest)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Where the pointer may be when an instruction runs, and which
+/// cells the instruction may access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstrBounds {
+    pub pointer: OffsetRange,
+    pub accessed: OffsetRange,
+}
+
+/// The results of bounds analysis for a whole program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BoundsReport {
+    /// The cells the program may access.
+    pub cells: OffsetRange,
+    /// Bounds for every instruction, in the order they appear in
+    /// the source, so each loop is followed by its body. These don't
+    /// use cell values, so they may be looser than `cells`.
+    pub instrs: Vec<InstrBounds>,
+}
+
+/// Analyse the bounds of `instrs`.
+pub fn bounds_report(instrs: &[AstNode]) -> BoundsReport {
+    let movement = program_movement(instrs, None);
+    let mut instr_bounds = vec![];
+    push_instr_bounds(
+        instrs,
+        SaturatingInt::Number(0),
+        SaturatingInt::Number(0),
+        &mut instr_bounds,
+    );
+    BoundsReport {
+        cells: OffsetRange::new(movement.lowest, movement.highest),
+        instrs: instr_bounds,
+    }
+}
+
+/// Push the bounds of each instruction in `instrs`, given that the
+/// pointer starts between `ptr_min` and `ptr_max`.
+fn push_instr_bounds(
+    instrs: &[AstNode],
+    mut ptr_min: SaturatingInt,
+    mut ptr_max: SaturatingInt,
+    instr_bounds: &mut Vec<InstrBounds>,
+) {
+    for instr in instrs {
+        let instr_movement = movement(instr, None);
+        instr_bounds.push(InstrBounds {
+            pointer: OffsetRange::new(ptr_min, ptr_max),
+            accessed: OffsetRange::new(
+                ptr_min + instr_movement.lowest,
+                ptr_max + instr_movement.highest,
+            ),
+        });
+
+        if let Loop { ref body, .. } = *instr {
+            // Each iteration starts where the loop could finish
+            // after some number of iterations.
+            push_instr_bounds(
+                body,
+                ptr_min + instr_movement.net_min,
+                ptr_max + instr_movement.net_max,
+                instr_bounds,
+            );
+        }
+
+        ptr_min = ptr_min + instr_movement.net_min;
+        ptr_max = ptr_max + instr_movement.net_max;
+    }
+}
+
+/// Return the instructions that may access cells outside a tape of
+/// `num_cells` cells, because we can't prove they don't.
+pub fn unproven_instrs(instrs: &[AstNode], num_cells: usize) -> Vec<&AstNode> {
+    let report = bounds_report(instrs);
+    let mut unproven = vec![];
+    push_unproven_instrs(instrs, num_cells, &mut report.instrs.iter(), &mut unproven);
+    unproven
+}
+
+fn push_unproven_instrs<'a, 'b>(
+    instrs: &'a [AstNode],
+    num_cells: usize,
+    instr_bounds: &mut impl Iterator<Item = &'b InstrBounds>,
+    unproven: &mut Vec<&'a AstNode>,
+) {
+    for instr in instrs {
+        let bounds = instr_bounds
+            .next()
+            .expect("Every instruction should have bounds");
+        let range = match *instr {
+            // The loop itself only checks the current cell.
+            Loop { .. } => bounds.pointer,
+            // WriteString doesn't access any cells.
+            WriteString { .. } => continue,
+            _ => bounds.accessed,
+        };
+        let on_tape = match (range.lowest, range.highest) {
+            (Some(lowest), Some(highest)) => lowest >= 0 && highest < num_cells as i64,
+            _ => false,
+        };
+        if !on_tape {
+            unproven.push(instr);
+        }
+
+        if let Loop { ref body, .. } = *instr {
+            push_unproven_instrs(body, num_cells, instr_bounds, unproven);
+        }
+    }
+}
+
+/// Whether a region is a run of instructions or a loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    /// Consecutive instructions without loops.
+    Block,
+    /// One iteration of a loop body, including any loops inside it.
+    LoopIteration,
+}
+
+/// The cells a region of the program may access, relative to the
+/// cell pointer when the region starts. A runtime check that these
+/// cells are on the tape when entering the region makes checking
+/// each access unnecessary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub position: Option<Position>,
+    /// The lowest offset accessed, or None if we can't bound it.
+    pub lowest: Option<i64>,
+    /// The highest offset accessed, or None if we can't bound it.
+    pub highest: Option<i64>,
+}
+
+impl Region {
+    fn new(kind: RegionKind, position: Option<Position>, movement: Movement) -> Self {
+        Region {
+            kind,
+            position,
+            lowest: match movement.lowest {
+                SaturatingInt::Number(x) => Some(x),
+                _ => None,
+            },
+            highest: match movement.highest {
+                SaturatingInt::Number(x) => Some(x),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Split `instrs` into blocks and loop iterations, and return the
+/// cells each may access. Loop bodies are also split, so every
+/// instruction is in a block, and regions are in source order.
+pub fn regions(instrs: &[AstNode]) -> Vec<Region> {
+    let mut regions = vec![];
+    let mut block_start = 0;
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop { ref body, position } = *instr {
+            push_block(&mut regions, &instrs[block_start..index]);
+            block_start = index + 1;
+
+            regions.push(Region::new(
+                RegionKind::LoopIteration,
+                position,
+                overall_movement(body, None),
+            ));
+            regions.extend(self::regions(body));
+        }
+    }
+    push_block(&mut regions, &instrs[block_start..]);
+    regions
+}
+
+fn push_block(regions: &mut Vec<Region>, block: &[AstNode]) {
+    if block.is_empty() {
+        return;
+    }
+    let position = block.iter().fold(None, |position, instr| {
+        position.combine(get_position(instr))
+    });
+    regions.push(Region::new(
+        RegionKind::Block,
+        position,
+        overall_movement(block, None),
+    ));
+}
+
+/// A cell access outside the tape that happens whenever the
+/// instruction at `position` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overrun {
+    /// An access before the first cell.
+    Underflow {
+        position: Option<Position>,
+        /// The highest cell index that may be accessed.
+        cell_index: i64,
+        /// True if `cell_index` is always the cell accessed.
+        exact: bool,
+    },
+    /// An access after the last cell.
+    Overflow {
+        position: Option<Position>,
+        /// The lowest cell index that may be accessed.
+        cell_index: i64,
+        /// True if `cell_index` is always the cell accessed.
+        exact: bool,
+    },
+}
+
+/// Find the first top-level instruction that always accesses a cell
+/// outside the tape. We don't look inside loops, as their bodies may
+/// never run.
+pub fn certain_overrun(instrs: &[AstNode]) -> Option<Overrun> {
+    let mut net_min = SaturatingInt::Number(0);
+    let mut net_max = SaturatingInt::Number(0);
+    for instr in instrs {
+        let instr_movement = movement(instr, None);
+        // A loop always checks the current cell, but nothing else is
+        // certain.
+        let (lowest, highest) = match *instr {
+            Loop { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
+            _ => (instr_movement.lowest, instr_movement.highest),
+        };
+        let exact = net_min == net_max;
+
+        if let SaturatingInt::Number(cell_index) = net_max + lowest {
+            if cell_index < 0 {
+                return Some(Overrun::Underflow {
+                    position: get_position(instr),
+                    cell_index,
+                    exact,
+                });
+            }
+        }
+        if let SaturatingInt::Number(cell_index) = net_min + highest {
+            if cell_index > MAX_CELL_INDEX as i64 {
+                return Some(Overrun::Overflow {
+                    position: get_position(instr),
+                    cell_index,
+                    exact,
+                });
+            }
+        }
+
+        net_min = net_min + instr_movement.net_min;
+        net_max = net_max + instr_movement.net_max;
+    }
+    None
 }
 
 /// Saturating arithmetic: we have normal integers that work as
-/// expected, but Max is bigger than any Number.
+/// expected, but Min is smaller and Max is bigger than any Number.
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
 enum SaturatingInt {
+    Min,
     Number(i64),
     Max,
 }
 
 impl Add for SaturatingInt {
     type Output = SaturatingInt;
+    /// We only ever add lower bounds to lower bounds, and upper
+    /// bounds to upper bounds, so Min and Max are never added.
     fn add(self, rhs: SaturatingInt) -> SaturatingInt {
-        if let (&SaturatingInt::Number(x), &SaturatingInt::Number(y)) = (&self, &rhs) {
-            SaturatingInt::Number(x + y)
-        } else {
-            SaturatingInt::Max
+        match (self, rhs) {
+            (SaturatingInt::Number(x), SaturatingInt::Number(y)) => SaturatingInt::Number(x + y),
+            (SaturatingInt::Max, _) | (_, SaturatingInt::Max) => SaturatingInt::Max,
+            _ => SaturatingInt::Min,
         }
     }
 }
@@ -49,10 +424,10 @@ impl Add for SaturatingInt {
 impl Ord for SaturatingInt {
     fn cmp(&self, other: &SaturatingInt) -> Ordering {
         match (self, other) {
-            (&SaturatingInt::Max, &SaturatingInt::Max) => Ordering::Equal,
-            (&SaturatingInt::Number(_), &SaturatingInt::Max) => Ordering::Less,
-            (&SaturatingInt::Max, &SaturatingInt::Number(_)) => Ordering::Greater,
             (&SaturatingInt::Number(x), &SaturatingInt::Number(y)) => x.cmp(&y),
+            (x, y) if x == y => Ordering::Equal,
+            (&SaturatingInt::Min, _) | (_, &SaturatingInt::Max) => Ordering::Less,
+            (&SaturatingInt::Max, _) | (_, &SaturatingInt::Min) => Ordering::Greater,
         }
     }
 }
@@ -63,78 +438,319 @@ impl PartialOrd for SaturatingInt {
     }
 }
 
-/// Return a tuple (highest cell index reached, cell index at end).
-/// If movement is unbounded, return Max.
-fn overall_movement(instrs: &[AstNode]) -> (SaturatingInt, SaturatingInt) {
-    let mut net_movement = SaturatingInt::Number(0);
-    let mut max_index = SaturatingInt::Number(0);
+/// How far instructions move the pointer, relative to the cell they
+/// start at.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+struct Movement {
+    /// The lowest and highest cell offsets accessed.
+    lowest: SaturatingInt,
+    highest: SaturatingInt,
+    /// The pointer finishes between these offsets.
+    net_min: SaturatingInt,
+    net_max: SaturatingInt,
+}
 
-    for (instr_highest_offset, instr_net_movement) in instrs.iter().map(movement) {
-        max_index = max(
-            net_movement,
-            max(net_movement + instr_highest_offset, max_index),
-        );
-        net_movement = net_movement + instr_net_movement;
+impl Movement {
+    /// Movement that only touches cells between `lowest` and
+    /// `highest`, and finishes at `net`.
+    fn exact(lowest: i64, highest: i64, net: i64) -> Self {
+        Movement {
+            lowest: SaturatingInt::Number(lowest),
+            highest: SaturatingInt::Number(highest),
+            net_min: SaturatingInt::Number(net),
+            net_max: SaturatingInt::Number(net),
+        }
+    }
+
+    /// Movement that touches the cells between `lowest` and
+    /// `highest` offsets from the current cell, without moving.
+    fn accessing<I: Iterator<Item = isize>>(offsets: I) -> Self {
+        let (lowest, highest) = offsets.fold((0, 0), |(lowest, highest), offset| {
+            (min(lowest, offset as i64), max(highest, offset as i64))
+        });
+        Movement::exact(lowest, highest, 0)
     }
-    (max_index, net_movement)
 }
 
-/// Return a tuple (highest cell index reached, cell index at end).
-/// If movement is unbounded, return Max.
-fn movement(instr: &AstNode) -> (SaturatingInt, SaturatingInt) {
-    match *instr {
-        PointerIncrement { amount, .. } => {
-            if amount < 0 {
-                (
-                    SaturatingInt::Number(0),
-                    SaturatingInt::Number(amount as i64),
-                )
-            } else {
-                (
-                    SaturatingInt::Number(amount as i64),
-                    SaturatingInt::Number(amount as i64),
-                )
-            }
+/// The most iterations of an unbalanced loop we will simulate to
+/// find how far it moves.
+const MAX_SIMULATED_ITERATIONS: u64 = 10_000;
+
+/// The values of cells, relative to the starting cell, where we know
+/// them. Cells we haven't seen are zero.
+#[derive(Clone, Default)]
+struct KnownCells {
+    values: HashMap<i64, Option<i64>>,
+}
+
+impl KnownCells {
+    fn get(&self, cell_index: i64) -> Option<i64> {
+        *self.values.get(&cell_index).unwrap_or(&Some(0))
+    }
+
+    /// Store `value` at `cell_index`. We only keep small values, so
+    /// whether a value is zero doesn't depend on the cell size.
+    fn set(&mut self, cell_index: i64, value: Option<i64>) {
+        let value = value.filter(|value| value.abs() < 128);
+        self.values.insert(cell_index, value);
+    }
+
+    fn forget(&mut self, lowest: i64, highest: i64) {
+        for cell_index in lowest..=highest {
+            self.values.insert(cell_index, None);
         }
-        Increment { offset, .. } | Set { offset, .. } => (
-            SaturatingInt::Number(offset as i64),
-            SaturatingInt::Number(0),
-        ),
-        MultiplyMove { ref changes, .. } => {
-            let mut highest_affected = 0;
-            for cell in changes.keys() {
-                if *cell > highest_affected {
-                    highest_affected = *cell;
-                }
+    }
+
+    /// Update cell values for running `instr` with the pointer at
+    /// `cell_ptr`, and return the new pointer position. `instr`
+    /// must not be a loop.
+    fn apply(&mut self, instr: &AstNode, cell_ptr: i64) -> i64 {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let cell_index = cell_ptr + offset as i64;
+                let value = self.get(cell_index).map(|value| value + amount.0 as i64);
+                self.set(cell_index, value);
             }
-            (
-                SaturatingInt::Number(highest_affected as i64),
-                SaturatingInt::Number(0),
-            )
-        }
-        Loop { ref body, .. } => {
-            let (max_in_body, net_in_body) = overall_movement(body);
-
-            match net_in_body {
-                SaturatingInt::Number(net_loop_movement) => {
-                    if net_loop_movement <= 0 {
-                        // If net movement was negative, conservatively assume
-                        // it was zero (e.g. the loop may run zero times).
-                        (max_in_body, SaturatingInt::Number(0))
-                    } else {
-                        // Net loop movement was positive, so we can't
-                        // assume any bounds.
-                        (SaturatingInt::Max, SaturatingInt::Max)
-                    }
+            Set { amount, offset, .. } => {
+                self.set(cell_ptr + offset as i64, Some(amount.0 as i64));
+            }
+            PointerIncrement { amount, .. } => return cell_ptr + amount as i64,
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
+                let source = self.get(cell_ptr);
+                for (offset, factor) in changes {
+                    let cell_index = cell_ptr + *offset as i64;
+                    let value = match (self.get(cell_index), source) {
+                        (Some(value), Some(source)) => Some(value + source * factor.0 as i64),
+                        _ => None,
+                    };
+                    self.set(cell_index, value);
                 }
-                SaturatingInt::Max => {
-                    // Unbounded movement somewhere inside the loop,
-                    // so this loop is unbounded.
-                    (SaturatingInt::Max, SaturatingInt::Max)
+                self.set(cell_ptr, Some(0));
+                for (offset, amount) in adds {
+                    let cell_index = cell_ptr + *offset as i64;
+                    let value = self.get(cell_index).map(|value| value + amount.0 as i64);
+                    self.set(cell_index, value);
                 }
             }
+            Read { .. } | SkipRead { .. } => self.set(cell_ptr, None),
+            Write { .. } | WriteString { .. } => {}
+            Loop { .. } => unreachable!("Loops must be simulated separately"),
+        }
+        cell_ptr
+    }
+}
+
+/// The lowest and highest offsets `instr` itself accesses, relative
+/// to the current cell. A loop only accesses the current cell before
+/// running its body.
+pub fn accessed_offsets(instr: &AstNode) -> (i64, i64) {
+    match (instr, movement(instr, None)) {
+        (Loop { .. }, _) => (0, 0),
+        (
+            _,
+            Movement {
+                lowest: SaturatingInt::Number(lowest),
+                highest: SaturatingInt::Number(highest),
+                ..
+            },
+        ) => (lowest, highest),
+        _ => unreachable!("Only loops have unbounded movement"),
+    }
+}
+
+/// Run a loop whose counter we know, starting at `cell_ptr`. Return
+/// the lowest and highest cells accessed and the final pointer
+/// position, or None if we can't tell when the loop stops.
+fn simulate_loop(
+    body: &[AstNode],
+    known: &mut KnownCells,
+    cell_ptr: i64,
+) -> Option<(i64, i64, i64)> {
+    if body.iter().any(|instr| matches!(instr, Loop { .. })) {
+        return None;
+    }
+
+    let mut simulated = known.clone();
+    let mut cell_ptr = cell_ptr;
+    let (mut lowest, mut highest) = (cell_ptr, cell_ptr);
+    for _ in 0..MAX_SIMULATED_ITERATIONS {
+        match simulated.get(cell_ptr) {
+            Some(0) => {
+                *known = simulated;
+                return Some((lowest, highest, cell_ptr));
+            }
+            Some(_) => {}
+            None => return None,
+        }
+        for instr in body {
+            let (instr_lowest, instr_highest) = accessed_offsets(instr);
+            lowest = min(lowest, cell_ptr + instr_lowest);
+            highest = max(highest, cell_ptr + instr_highest);
+            cell_ptr = simulated.apply(instr, cell_ptr);
+        }
+    }
+    None
+}
+
+/// The movement of running a whole program. Every cell starts at
+/// zero, so we can often work out how many times an unbalanced loop
+/// runs, until we lose track of the pointer or the cell values.
+fn program_movement(instrs: &[AstNode], iteration_cap: Option<u64>) -> Movement {
+    let mut known = KnownCells::default();
+    let mut cell_ptr = 0;
+    let (mut lowest, mut highest) = (0, 0);
+
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop { ref body, .. } = *instr {
+            if let Some((loop_lowest, loop_highest, new_cell_ptr)) =
+                simulate_loop(body, &mut known, cell_ptr)
+            {
+                lowest = min(lowest, loop_lowest);
+                highest = max(highest, loop_highest);
+                cell_ptr = new_cell_ptr;
+                continue;
+            }
+
+            if let Movement {
+                lowest: SaturatingInt::Number(loop_lowest),
+                highest: SaturatingInt::Number(loop_highest),
+                net_min: SaturatingInt::Number(0),
+                net_max: SaturatingInt::Number(0),
+            } = loop_movement(body, iteration_cap)
+            {
+                // A balanced loop leaves the pointer where it was,
+                // but we don't know what it did to the cells.
+                lowest = min(lowest, cell_ptr + loop_lowest);
+                highest = max(highest, cell_ptr + loop_highest);
+                known.forget(cell_ptr + loop_lowest, cell_ptr + loop_highest);
+                known.set(cell_ptr, Some(0));
+                continue;
+            }
+
+            // We don't know where the pointer is after this loop, so
+            // fall back to the movement of the remaining instructions.
+            let remaining = overall_movement(&instrs[index..], iteration_cap);
+            let cell_ptr = SaturatingInt::Number(cell_ptr);
+            return Movement {
+                lowest: min(SaturatingInt::Number(lowest), cell_ptr + remaining.lowest),
+                highest: max(SaturatingInt::Number(highest), cell_ptr + remaining.highest),
+                net_min: cell_ptr + remaining.net_min,
+                net_max: cell_ptr + remaining.net_max,
+            };
+        }
+
+        let (instr_lowest, instr_highest) = accessed_offsets(instr);
+        lowest = min(lowest, cell_ptr + instr_lowest);
+        highest = max(highest, cell_ptr + instr_highest);
+        cell_ptr = known.apply(instr, cell_ptr);
+    }
+
+    Movement::exact(lowest, highest, cell_ptr)
+}
+
+/// The movement of running `instrs` in order.
+fn overall_movement(instrs: &[AstNode], iteration_cap: Option<u64>) -> Movement {
+    let mut overall = Movement::exact(0, 0, 0);
+    for instr in instrs {
+        let instr_movement = movement(instr, iteration_cap);
+        overall.lowest = min(overall.lowest, overall.net_min + instr_movement.lowest);
+        overall.highest = max(overall.highest, overall.net_max + instr_movement.highest);
+        overall.net_min = overall.net_min + instr_movement.net_min;
+        overall.net_max = overall.net_max + instr_movement.net_max;
+    }
+    overall
+}
+
+/// The movement of a single instruction. Every kind of instruction
+/// is listed explicitly, so new kinds must say how they move the
+/// pointer before this compiles.
+fn movement(instr: &AstNode, iteration_cap: Option<u64>) -> Movement {
+    match *instr {
+        PointerIncrement { amount, .. } => pointer_increment_movement(amount),
+        Increment { offset, .. } | Set { offset, .. } => {
+            Movement::accessing(std::iter::once(offset))
+        }
+        MultiplyMove {
+            ref changes,
+            ref adds,
+            ..
+        } => multiply_move_movement(changes.keys().chain(adds.keys()).cloned()),
+        Loop { ref body, .. } => loop_movement(body, iteration_cap),
+        // I/O only touches the current cell. SkipRead and
+        // WriteString don't touch any cells, but they don't move the
+        // pointer either.
+        Read { .. } | SkipRead { .. } | Write { .. } | WriteString { .. } => {
+            Movement::exact(0, 0, 0)
+        }
+    }
+}
+
+fn pointer_increment_movement(amount: isize) -> Movement {
+    let amount = amount as i64;
+    Movement::exact(min(amount, 0), max(amount, 0), amount)
+}
+
+/// MultiplyMove reads the current cell and writes to every offset
+/// it changes or adds to, in either direction.
+fn multiply_move_movement<I: Iterator<Item = isize>>(offsets: I) -> Movement {
+    Movement::accessing(offsets)
+}
+
+fn loop_movement(body: &[AstNode], iteration_cap: Option<u64>) -> Movement {
+    let body_movement = overall_movement(body, iteration_cap);
+    let zero = SaturatingInt::Number(0);
+
+    if body_movement.net_min == zero && body_movement.net_max == zero {
+        // A balanced loop: every iteration starts at the same
+        // cell, so the loop accesses exactly the cells the
+        // body does.
+        body_movement
+    } else if body_movement.net_max <= zero {
+        // Every iteration starts at or before the previous
+        // one, so the first iteration reaches furthest right,
+        // but we don't know how far left we go. The loop may
+        // not run at all, so we may not move.
+        Movement {
+            lowest: SaturatingInt::Min,
+            highest: body_movement.highest,
+            net_min: SaturatingInt::Min,
+            net_max: zero,
+        }
+    } else if body_movement.net_min >= zero {
+        // Likewise, but moving right. Given an iteration cap, we
+        // assume the loop stops after that many iterations: a guess,
+        // not a bound.
+        let (highest, net_max) = match (iteration_cap, body_movement.net_max, body_movement.highest)
+        {
+            (Some(iteration_cap), SaturatingInt::Number(drift), SaturatingInt::Number(highest)) => {
+                let iteration_cap = iteration_cap as i64;
+                (
+                    SaturatingInt::Number(
+                        highest.saturating_add(drift.saturating_mul(iteration_cap - 1)),
+                    ),
+                    SaturatingInt::Number(drift.saturating_mul(iteration_cap)),
+                )
+            }
+            _ => (SaturatingInt::Max, SaturatingInt::Max),
+        };
+        Movement {
+            lowest: body_movement.lowest,
+            highest,
+            net_min: zero,
+            net_max,
+        }
+    } else {
+        // We don't know which way iterations move.
+        Movement {
+            lowest: SaturatingInt::Min,
+            highest: SaturatingInt::Max,
+            net_min: SaturatingInt::Min,
+            net_max: SaturatingInt::Max,
         }
-        Read { .. } | Write { .. } => (SaturatingInt::Number(0), SaturatingInt::Number(0)),
     }
 }
 
@@ -188,6 +804,7 @@ mod tests {
         let instrs = vec![
             MultiplyMove {
                 changes: dest_cells,
+                adds: HashMap::new(),
                 position: Some(Position { start: 0, end: 0 }),
             },
             // Multiply move should have increased the highest cell
@@ -217,6 +834,7 @@ mod tests {
             // Move (with multiply) to cell #3 (#2 offset 1).
             MultiplyMove {
                 changes: dest_cells,
+                adds: HashMap::new(),
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -235,6 +853,7 @@ mod tests {
             },
             MultiplyMove {
                 changes: dest_cells,
+                adds: HashMap::new(),
                 position: Some(Position { start: 0, end: 0 }),
             },
         ];
@@ -244,7 +863,7 @@ mod tests {
 
     #[test]
     fn unbounded_movement() {
-        let instrs = parse("[>]").unwrap();
+        let instrs = parse(",[>]").unwrap();
         assert_eq!(highest_cell_index(&instrs), MAX_CELL_INDEX);
 
         let instrs = parse(">[<]").unwrap();
@@ -264,18 +883,424 @@ mod tests {
     #[test]
     fn loop_with_no_net_movement() {
         // Max cell index 1, final cell position 0.
-        let instrs = parse("[->+<]").unwrap();
+        let instrs = parse(",[->+<]").unwrap();
         assert_eq!(highest_cell_index(&instrs), 1);
 
         // Max cell index 1, final cell position 1.
-        let instrs = parse("[->+<]>").unwrap();
+        let instrs = parse(",[->+<]>").unwrap();
         assert_eq!(highest_cell_index(&instrs), 1);
 
         // Max cell index 2, final cell position 2.
-        let instrs = parse("[->+<]>>").unwrap();
+        let instrs = parse(",[->+<]>>").unwrap();
         assert_eq!(highest_cell_index(&instrs), 2);
     }
 
+    #[test]
+    fn balanced_loop_excursion() {
+        // Each iteration goes three cells right and back again.
+        let instrs = parse("+[>>>+<<<-]").unwrap();
+        assert_eq!(highest_cell_index(&instrs), 3);
+
+        // Nested balanced loops are exact too.
+        let instrs = parse("+[>+[>>+<<-]<-]>").unwrap();
+        assert_eq!(highest_cell_index(&instrs), 3);
+    }
+
+    #[test]
+    fn balanced_loop_movement_is_exact() {
+        let instrs = parse(">>[<<+>>-]<").unwrap();
+        assert_eq!(overall_movement(&instrs, None), Movement::exact(0, 2, 1));
+    }
+
+    #[test]
+    fn loop_moving_left_bounds() {
+        // The first iteration goes furthest right.
+        let instrs = parse(">>>[>+<<<]").unwrap();
+        let movement = overall_movement(&instrs, None);
+        assert_eq!(movement.highest, SaturatingInt::Number(4));
+        assert_eq!(movement.lowest, SaturatingInt::Min);
+        assert_eq!(movement.net_max, SaturatingInt::Number(3));
+    }
+
+    #[test]
+    fn certain_underflow() {
+        let instrs = parse("+<").unwrap();
+        assert_eq!(
+            certain_overrun(&instrs),
+            Some(Overrun::Underflow {
+                position: Some(Position { start: 1, end: 1 }),
+                cell_index: -1,
+                exact: true,
+            })
+        );
+    }
+
+    #[test]
+    fn certain_underflow_after_loop() {
+        // The loop may move left, but we're definitely before the
+        // first cell afterwards.
+        let instrs = parse(">[<]<<").unwrap();
+        assert_eq!(
+            certain_overrun(&instrs),
+            Some(Overrun::Underflow {
+                position: Some(Position { start: 5, end: 5 }),
+                cell_index: -1,
+                exact: false,
+            })
+        );
+    }
+
+    #[test]
+    fn no_certain_overrun_inside_loop() {
+        // The loop body never runs.
+        let instrs = parse("[<]").unwrap();
+        assert_eq!(certain_overrun(&instrs), None);
+
+        // We don't know where the pointer ends up.
+        let instrs = parse(",[>]<").unwrap();
+        assert_eq!(certain_overrun(&instrs), None);
+    }
+
+    #[test]
+    fn certain_overflow() {
+        let instrs = vec![PointerIncrement {
+            amount: MAX_CELL_INDEX as isize + 1,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        assert_eq!(
+            certain_overrun(&instrs),
+            Some(Overrun::Overflow {
+                position: Some(Position { start: 0, end: 0 }),
+                cell_index: MAX_CELL_INDEX as i64 + 1,
+                exact: true,
+            })
+        );
+    }
+
+    #[test]
+    fn highest_cell_bound_not_truncated() {
+        let instrs = vec![PointerIncrement {
+            amount: MAX_CELL_INDEX as isize + 1,
+            position: None,
+        }];
+        assert_eq!(highest_cell_bound(&instrs), Some(MAX_CELL_INDEX as i64 + 1));
+
+        let instrs = parse(",[>]").unwrap();
+        assert_eq!(highest_cell_bound(&instrs), None);
+    }
+
+    #[test]
+    fn unbounded_loops_innermost() {
+        let instrs = parse("+[>+[>]<<]>[-]").unwrap();
+        let positions: Vec<_> = unbounded_loops(&instrs)
+            .into_iter()
+            .map(get_position)
+            .collect();
+        assert_eq!(positions, vec![Some(Position { start: 4, end: 6 })]);
+    }
+
+    #[test]
+    fn no_unbounded_loops() {
+        let instrs = parse("+[>+<-][<]").unwrap();
+        assert_eq!(unbounded_loops(&instrs), Vec::<&AstNode>::new());
+    }
+
+    #[test]
+    fn lowest_cell_index_no_movement() {
+        let instrs = parse("+>+<.").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), 0);
+    }
+
+    #[test]
+    fn lowest_cell_index_left_of_origin() {
+        let instrs = parse(">>+<<<<+>>").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), -2);
+    }
+
+    #[test]
+    fn lowest_cell_index_offsets() {
+        let instrs = vec![
+            Set {
+                amount: Wrapping(1),
+                offset: -3,
+                position: None,
+            },
+            MultiplyMove {
+                changes: vec![(-5, Wrapping(1))].into_iter().collect(),
+                adds: HashMap::new(),
+                position: None,
+            },
+        ];
+        assert_eq!(lowest_cell_index(&instrs), -5);
+    }
+
+    #[test]
+    fn lowest_cell_index_balanced_loop() {
+        let instrs = parse("+[<<+>>-]").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), -2);
+    }
+
+    #[test]
+    fn lowest_cell_index_unbounded() {
+        let instrs = parse(",[<]").unwrap();
+        assert_eq!(lowest_cell_bound(&instrs), None);
+        assert_eq!(lowest_cell_index(&instrs), -(MAX_CELL_INDEX as isize));
+
+        // Moving right doesn't affect how far left we go.
+        let instrs = parse("<+[>]").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), -1);
+    }
+
+    #[test]
+    fn quickcheck_lowest_cell_index_in_bounds() {
+        fn lowest_cell_index_in_bounds(instrs: Vec<AstNode>) -> bool {
+            let index = lowest_cell_index(&instrs);
+            index <= 0 && index >= -(MAX_CELL_INDEX as isize)
+        }
+        quickcheck(lowest_cell_index_in_bounds as fn(Vec<AstNode>) -> bool);
+    }
+
+    #[test]
+    fn unbalanced_loop_with_known_counter() {
+        // The loop runs once, then finds a zero cell.
+        let instrs = parse("+[>>]>").unwrap();
+        assert_eq!(highest_cell_index(&instrs), 3);
+
+        // Walk along three nonzero cells.
+        let instrs = parse("+>+>+<<[>]>").unwrap();
+        assert_eq!(highest_cell_index(&instrs), 4);
+    }
+
+    #[test]
+    fn unbalanced_loop_with_known_values_left() {
+        // Walk back along three nonzero cells.
+        let instrs = parse(">+>+>+[<]").unwrap();
+        assert_eq!(lowest_cell_index(&instrs), 0);
+        assert_eq!(highest_cell_index(&instrs), 3);
+    }
+
+    #[test]
+    fn unbalanced_loop_with_unknown_counter() {
+        let instrs = parse(",[>]").unwrap();
+        assert_eq!(highest_cell_bound(&instrs), None);
+    }
+
+    #[test]
+    fn unbalanced_loop_that_never_stops() {
+        // We give up simulating, rather than looping forever.
+        let instrs = parse("+[>+]").unwrap();
+        assert_eq!(highest_cell_bound(&instrs), None);
+    }
+
+    #[test]
+    fn known_values_lost_after_balanced_loop() {
+        // We don't know what the balanced loop left in cell 1.
+        let instrs = parse(",[>+<-]>[>]").unwrap();
+        assert_eq!(highest_cell_bound(&instrs), None);
+    }
+
+    #[test]
+    fn regions_of_straight_line_code() {
+        let instrs = parse("+>>-<<<.").unwrap();
+        assert_eq!(
+            regions(&instrs),
+            vec![Region {
+                kind: RegionKind::Block,
+                position: Some(Position { start: 0, end: 7 }),
+                lowest: Some(-1),
+                highest: Some(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn regions_of_loops() {
+        let instrs = parse("+[>+<-]>[<]").unwrap();
+        assert_eq!(
+            regions(&instrs),
+            vec![
+                Region {
+                    kind: RegionKind::Block,
+                    position: Some(Position { start: 0, end: 0 }),
+                    lowest: Some(0),
+                    highest: Some(0),
+                },
+                Region {
+                    kind: RegionKind::LoopIteration,
+                    position: Some(Position { start: 1, end: 6 }),
+                    lowest: Some(0),
+                    highest: Some(1),
+                },
+                Region {
+                    kind: RegionKind::Block,
+                    position: Some(Position { start: 2, end: 5 }),
+                    lowest: Some(0),
+                    highest: Some(1),
+                },
+                Region {
+                    kind: RegionKind::Block,
+                    position: Some(Position { start: 7, end: 7 }),
+                    lowest: Some(0),
+                    highest: Some(1),
+                },
+                Region {
+                    kind: RegionKind::LoopIteration,
+                    position: Some(Position { start: 8, end: 10 }),
+                    lowest: Some(-1),
+                    highest: Some(0),
+                },
+                Region {
+                    kind: RegionKind::Block,
+                    position: Some(Position { start: 9, end: 9 }),
+                    lowest: Some(-1),
+                    highest: Some(0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn regions_with_unbounded_inner_loop() {
+        let instrs = parse(",[[>]+]").unwrap();
+        let outer_iteration = regions(&instrs)[1];
+        assert_eq!(outer_iteration.kind, RegionKind::LoopIteration);
+        assert_eq!(outer_iteration.lowest, Some(0));
+        assert_eq!(outer_iteration.highest, None);
+    }
+
+    #[test]
+    fn report_instr_bounds() {
+        let instrs = parse(">+[<]").unwrap();
+        let report = bounds_report(&instrs);
+        assert_eq!(
+            report.cells,
+            OffsetRange {
+                lowest: Some(0),
+                highest: Some(1),
+            }
+        );
+
+        let pointers: Vec<_> = report
+            .instrs
+            .iter()
+            .map(|bounds| bounds.pointer.to_string())
+            .collect();
+        assert_eq!(pointers, vec!["0", "1", "1", "..1"]);
+
+        let accessed: Vec<_> = report
+            .instrs
+            .iter()
+            .map(|bounds| bounds.accessed.to_string())
+            .collect();
+        assert_eq!(accessed, vec!["0..1", "1", "..1", "..1"]);
+    }
+
+    #[test]
+    fn report_instr_bounds_after_unbounded_loop() {
+        let instrs = parse(",[>]+").unwrap();
+        let report = bounds_report(&instrs);
+        assert_eq!(report.cells.highest, None);
+        assert_eq!(report.instrs[3].pointer.to_string(), "0..");
+    }
+
+    #[test]
+    fn unproven_instrs_on_small_tape() {
+        let instrs = parse("+>+>+").unwrap();
+        assert_eq!(unproven_instrs(&instrs, 3), Vec::<&AstNode>::new());
+
+        // The last two instructions run past the end of a two cell
+        // tape.
+        let positions: Vec<_> = unproven_instrs(&instrs, 2)
+            .into_iter()
+            .map(get_position)
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                Some(Position { start: 3, end: 3 }),
+                Some(Position { start: 4, end: 4 })
+            ]
+        );
+    }
+
+    #[test]
+    fn unproven_instrs_in_loops() {
+        // The loop header is always safe, but we don't know where the
+        // body goes.
+        let instrs = parse("+[>]").unwrap();
+        let positions: Vec<_> = unproven_instrs(&instrs, 10)
+            .into_iter()
+            .map(get_position)
+            .collect();
+        assert_eq!(positions, vec![Some(Position { start: 2, end: 2 })]);
+    }
+
+    #[test]
+    fn accessed_offsets_of_loop() {
+        let instrs = parse("[>>]").unwrap();
+        assert_eq!(accessed_offsets(&instrs[0]), (0, 0));
+    }
+
+    #[test]
+    fn tape_size_exact() {
+        let instrs = parse(">>+").unwrap();
+        assert_eq!(
+            tape_size(&instrs, 10),
+            TapeSize {
+                cells: 3,
+                heuristic: false,
+            }
+        );
+    }
+
+    #[test]
+    fn tape_size_widened() {
+        // Each iteration reaches three cells right and moves two, so
+        // ten iterations reach cell 3 + 2 * 9.
+        let instrs = parse(",[>>>+<-]").unwrap();
+        assert_eq!(
+            tape_size(&instrs, 10),
+            TapeSize {
+                cells: 22,
+                heuristic: true,
+            }
+        );
+    }
+
+    #[test]
+    fn tape_size_widened_after_loop() {
+        let instrs = parse(",[>]>>").unwrap();
+        assert_eq!(
+            tape_size(&instrs, 10),
+            TapeSize {
+                cells: 13,
+                heuristic: true,
+            }
+        );
+    }
+
+    #[test]
+    fn tape_size_too_big_to_widen() {
+        // We don't know which way this loop goes.
+        let instrs = parse(",[>,[<<]]").unwrap();
+        assert_eq!(
+            tape_size(&instrs, 10),
+            TapeSize {
+                cells: MAX_CELL_INDEX + 1,
+                heuristic: false,
+            }
+        );
+
+        let instrs = parse(",[>]").unwrap();
+        assert_eq!(
+            tape_size(&instrs, MAX_CELL_INDEX as u64 + 1),
+            TapeSize {
+                cells: MAX_CELL_INDEX + 1,
+                heuristic: false,
+            }
+        );
+    }
+
     #[test]
     fn quickcheck_highest_cell_index_in_bounds() {
         fn highest_cell_index_in_bounds(instrs: Vec<AstNode>) -> bool {
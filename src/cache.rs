@@ -0,0 +1,551 @@
+//! Caching the results of speculative execution between compiles.
+//!
+//! Speculative execution is deterministic given the IR, the constant
+//! input and the execution limits, so we can store the state it
+//! finished in and reuse it when the same program is compiled
+//! again. Entries are plain text files named after a hash of
+//! everything that affects the result:
+//!
+//! ```text
+//! bfc-speculation-cache 4
+//! steps 22
+//! cell-ptr 0
+//! start 3
+//! cells 2
+//! cell 1 3
+//! output 48690a
+//! input -
+//! read 1 1 3 1
+//! read - 1 3 1
+//! branch 1 1 0 0:2
+//! loop 1 2 1
+//! outcome reached-runtime-value
+//! ```
+//!
+//! `start` is the index of the instruction where runtime execution
+//! begins, counting instructions in the order they appear in the
+//! source (loops before their bodies), or `-` if the whole program
+//! ran at compile time. Each `branch` line gives the read a loop
+//! condition depended on, its scale and offset, then each cell that
+//! differs if the loop was entered with its value in that case. Each
+//! `loop` line gives the index of a loop, followed by its iterations
+//! and exits.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::num::Wrapping;
+use std::path::Path;
+
+use crate::bfir::{self, AstNode, Cell, EofBehaviour};
+use crate::diagnostics::{Lint, Warning};
+use crate::execution::{DeferredBranch, DeferredRead, ExecutionState, Limits, LoopCounts, Outcome};
+
+/// The first line of every cache entry. This is bumped whenever the
+/// format changes, so old entries are ignored.
+const HEADER: &str = "bfc-speculation-cache 4";
+
+/// The starting hash for `fnv1a`.
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// A 64-bit FNV-1a hash. Unlike `DefaultHasher`, this is stable
+/// between Rust versions, so cache entries stay valid.
+pub(crate) fn fnv1a(bytes: &[u8], mut hash: u64) -> u64 {
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// The key for the result of speculatively executing `instrs` with
+/// these settings. Results from other versions of bfc are never
+/// reused, as speculative execution may have changed.
+pub fn cache_key(
+    instrs: &[AstNode],
+    input: &[u8],
+    cell_bits: u32,
+    eof: EofBehaviour,
+    limits: &Limits,
+) -> String {
+    let settings = format!(
+        "{}\nbfc {}\ncell_bits {}\neof {:?}\nsteps {}\nmax_output {}\ngrowable_tape {}\ninput {}\n",
+        HEADER,
+        env!("CARGO_PKG_VERSION"),
+        cell_bits,
+        eof,
+        limits.steps,
+        limits.max_output,
+        limits.growable_tape,
+        hex(input.iter().copied()),
+    );
+    let mut hash = fnv1a(settings.as_bytes(), FNV_OFFSET_BASIS);
+    hash = fnv1a(bfir::serialize(instrs).as_bytes(), hash);
+    format!("{:016x}", hash)
+}
+
+/// Load the cached result of speculatively executing `instrs`, if
+/// there is one. Missing or unreadable entries are treated as cache
+/// misses, so we just execute the program again.
+pub fn load<'a>(
+    dir: &Path,
+    key: &str,
+    instrs: &'a [AstNode],
+) -> Option<(ExecutionState<'a>, Outcome)> {
+    let text = fs::read_to_string(dir.join(key)).ok()?;
+    deserialize(&text, instrs).ok()
+}
+
+/// Store the result of speculatively executing `instrs` under `key`.
+pub fn store(
+    dir: &Path,
+    key: &str,
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    outcome: &Outcome,
+) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    // Write to a temporary file first, so concurrent compiles never
+    // see a partially written entry.
+    let tmp_path = dir.join(format!("{}.tmp{}", key, std::process::id()));
+    fs::write(&tmp_path, serialize(instrs, state, outcome))?;
+    fs::rename(&tmp_path, dir.join(key))
+}
+
+fn hex(bytes: impl Iterator<Item = u8>) -> String {
+    let text: String = bytes.map(|byte| format!("{:02x}", byte)).collect();
+    if text.is_empty() {
+        "-".to_owned()
+    } else {
+        text
+    }
+}
+
+fn unhex(text: &str) -> Result<Vec<u8>, String> {
+    if text == "-" {
+        return Ok(vec![]);
+    }
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| format!("Invalid hex '{}'", text))
+        })
+        .collect()
+}
+
+/// Visit `instrs` in source order, loops before their bodies,
+/// stopping when `f` returns true. Returns the index of the
+/// instruction we stopped at.
+fn find_instr<'a>(
+    instrs: &'a [AstNode],
+    next_index: &mut usize,
+    f: &mut impl FnMut(usize, &'a AstNode) -> bool,
+) -> Option<(usize, &'a AstNode)> {
+    for instr in instrs {
+        let index = *next_index;
+        *next_index += 1;
+        if f(index, instr) {
+            return Some((index, instr));
+        }
+        if let AstNode::Loop { ref body, .. } = *instr {
+            if let Some(found) = find_instr(body, next_index, f) {
+                return Some(found);
+            }
+        }
+    }
+    None
+}
+
+fn instr_index(instrs: &[AstNode], target: &AstNode) -> Option<usize> {
+    find_instr(instrs, &mut 0, &mut |_, instr| std::ptr::eq(instr, target)).map(|(index, _)| index)
+}
+
+fn instr_at(instrs: &[AstNode], target: usize) -> Option<&AstNode> {
+    find_instr(instrs, &mut 0, &mut |index, _| index == target).map(|(_, instr)| instr)
+}
+
+fn serialize_outcome(outcome: &Outcome) -> String {
+    match *outcome {
+        Outcome::Completed(steps_left) => format!("completed {}", steps_left),
+        Outcome::ReachedRuntimeValue => "reached-runtime-value".to_owned(),
+        Outcome::RuntimeError(ref warning) => format!(
+            "runtime-error {} {} {}",
+            warning.lint.name(),
+            bfir::serialize_position(warning.position),
+            warning.message
+        ),
+        Outcome::OutOfSteps => "out-of-steps".to_owned(),
+        Outcome::OutOfTime => "out-of-time".to_owned(),
+        Outcome::OutputTooLarge => "output-too-large".to_owned(),
+        Outcome::OutOfCells => "out-of-cells".to_owned(),
+        Outcome::Interrupted => "interrupted".to_owned(),
+    }
+}
+
+fn deserialize_outcome(text: &str) -> Result<Outcome, String> {
+    let mut parts = text.splitn(4, ' ');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("completed"), Some(steps_left), None, None) => steps_left
+            .parse()
+            .map(Outcome::Completed)
+            .map_err(|_| format!("Invalid step count '{}'", steps_left)),
+        (Some("reached-runtime-value"), None, None, None) => Ok(Outcome::ReachedRuntimeValue),
+        (Some("runtime-error"), Some(lint), Some(position), Some(message)) => {
+            Ok(Outcome::RuntimeError(Warning {
+                lint: Lint::from_name(lint).ok_or_else(|| format!("Unknown lint '{}'", lint))?,
+                message: message.to_owned(),
+                position: bfir::deserialize_position(position)?,
+                notes: vec![],
+            }))
+        }
+        (Some("out-of-steps"), None, None, None) => Ok(Outcome::OutOfSteps),
+        (Some("out-of-time"), None, None, None) => Ok(Outcome::OutOfTime),
+        (Some("output-too-large"), None, None, None) => Ok(Outcome::OutputTooLarge),
+        (Some("out-of-cells"), None, None, None) => Ok(Outcome::OutOfCells),
+        (Some("interrupted"), None, None, None) => Ok(Outcome::Interrupted),
+        _ => Err(format!("Invalid outcome '{}'", text)),
+    }
+}
+
+/// Convert the result of speculative execution to the cache entry
+/// format.
+fn serialize(instrs: &[AstNode], state: &ExecutionState, outcome: &Outcome) -> String {
+    let mut result = format!("{}\n", HEADER);
+    result.push_str(&format!("steps {}\n", state.steps));
+    result.push_str(&format!("cell-ptr {}\n", state.cell_ptr));
+    let start = state
+        .start_instr
+        .and_then(|instr| instr_index(instrs, instr));
+    match start {
+        Some(index) => result.push_str(&format!("start {}\n", index)),
+        None => result.push_str("start -\n"),
+    }
+
+    // Most cells are zero, so only store the others.
+    result.push_str(&format!("cells {}\n", state.cells.len()));
+    for (index, cell) in state.cells.iter().enumerate() {
+        if cell.0 != 0 {
+            result.push_str(&format!("cell {} {}\n", index, cell.0));
+        }
+    }
+
+    result.push_str(&format!(
+        "output {}\n",
+        hex(state.outputs.iter().map(|&byte| byte as u8))
+    ));
+    result.push_str(&format!("input {}\n", hex(state.input.iter().copied())));
+    for read in &state.reads {
+        let cell = match read.cell {
+            Some(cell) => cell.to_string(),
+            None => "-".to_owned(),
+        };
+        result.push_str(&format!(
+            "read {} {} {} {}\n",
+            cell, read.count, read.outputs_before, read.scale.0
+        ));
+    }
+    for branch in &state.branches {
+        result.push_str(&format!(
+            "branch {} {} {}",
+            branch.read, branch.scale.0, branch.offset.0
+        ));
+        for (index, value) in &branch.cells {
+            result.push_str(&format!(" {}:{}", index, value.0));
+        }
+        result.push('\n');
+    }
+    let mut loops = vec![];
+    find_instr(instrs, &mut 0, &mut |index, instr| {
+        if let Some(counts) = state.loop_counts.get(&(instr as *const AstNode)) {
+            loops.push((index, *counts));
+        }
+        false
+    });
+    for (index, counts) in loops {
+        result.push_str(&format!(
+            "loop {} {} {}\n",
+            index, counts.iterations, counts.exits
+        ));
+    }
+    result.push_str(&format!("outcome {}\n", serialize_outcome(outcome)));
+    result
+}
+
+fn parse_number<T: std::str::FromStr>(token: &str) -> Result<T, String> {
+    token
+        .parse()
+        .map_err(|_| format!("Invalid number '{}'", token))
+}
+
+/// Parse a cache entry produced by `serialize`. The start
+/// instruction is looked up in `instrs`, which must be the program
+/// the entry was created from.
+fn deserialize<'a>(
+    text: &str,
+    instrs: &'a [AstNode],
+) -> Result<(ExecutionState<'a>, Outcome), String> {
+    let mut lines = text.lines();
+    if lines.next() != Some(HEADER) {
+        return Err("Unknown cache format".to_owned());
+    }
+
+    let mut state = ExecutionState::initial(&[]);
+    state.cells = vec![];
+    let mut outcome = None;
+
+    for line in lines {
+        let mut parts = line.splitn(2, ' ');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => return Err(format!("Invalid line '{}'", line)),
+        };
+        let tokens: Vec<&str> = value.split(' ').collect();
+
+        match (key, tokens.len()) {
+            ("steps", 1) => state.steps = parse_number(value)?,
+            ("cell-ptr", 1) => state.cell_ptr = parse_number(value)?,
+            ("start", 1) if value == "-" => state.start_instr = None,
+            ("start", 1) => {
+                let index = parse_number(value)?;
+                state.start_instr = Some(
+                    instr_at(instrs, index)
+                        .ok_or_else(|| format!("No instruction at index {}", index))?,
+                );
+            }
+            ("cells", 1) => state.cells = vec![Wrapping(0); parse_number(value)?],
+            ("cell", 2) => {
+                let index: usize = parse_number(tokens[0])?;
+                let value: Cell = Wrapping(parse_number(tokens[1])?);
+                match state.cells.get_mut(index) {
+                    Some(cell) => *cell = value,
+                    None => return Err(format!("Cell {} out of range", index)),
+                }
+            }
+            ("output", 1) => {
+                state.outputs = unhex(value)?.into_iter().map(|byte| byte as i8).collect()
+            }
+            ("input", 1) => state.input = unhex(value)?.into_iter().collect::<VecDeque<_>>(),
+            ("read", 4) => state.reads.push(DeferredRead {
+                cell: if tokens[0] == "-" {
+                    None
+                } else {
+                    Some(parse_number(tokens[0])?)
+                },
+                count: parse_number(tokens[1])?,
+                outputs_before: parse_number(tokens[2])?,
+                scale: Wrapping(parse_number(tokens[3])?),
+            }),
+            ("branch", n) if n >= 3 => {
+                let read: usize = parse_number(tokens[0])?;
+                if read >= state.reads.len() {
+                    return Err(format!("No read at index {}", read));
+                }
+                let mut cells = vec![];
+                for token in &tokens[3..] {
+                    let mut parts = token.splitn(2, ':');
+                    let (index, value) = match (parts.next(), parts.next()) {
+                        (Some(index), Some(value)) => (index, value),
+                        _ => return Err(format!("Invalid branch cell '{}'", token)),
+                    };
+                    let index: usize = parse_number(index)?;
+                    if index >= state.cells.len() {
+                        return Err(format!("Cell {} out of range", index));
+                    }
+                    cells.push((index, Wrapping(parse_number(value)?)));
+                }
+                state.branches.push(DeferredBranch {
+                    read,
+                    scale: Wrapping(parse_number(tokens[1])?),
+                    offset: Wrapping(parse_number(tokens[2])?),
+                    cells,
+                });
+            }
+            ("loop", 3) => {
+                let index = parse_number(tokens[0])?;
+                let instr = instr_at(instrs, index)
+                    .ok_or_else(|| format!("No instruction at index {}", index))?;
+                state.loop_counts.insert(
+                    instr as *const AstNode,
+                    LoopCounts {
+                        iterations: parse_number(tokens[1])?,
+                        exits: parse_number(tokens[2])?,
+                    },
+                );
+            }
+            ("outcome", _) => outcome = Some(deserialize_outcome(value)?),
+            _ => return Err(format!("Invalid line '{}'", line)),
+        }
+    }
+
+    match outcome {
+        Some(outcome) => Ok((state, outcome)),
+        None => Err("Missing outcome".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bfir::parse;
+    use crate::bfir::AstNode::*;
+    use crate::bfir::{EofBehaviour, DEFAULT_CELL_BITS};
+    use crate::execution::execute_with_limits;
+    use pretty_assertions::assert_eq;
+
+    fn round_trip(instrs: &[AstNode], input: &[u8]) {
+        let (state, outcome) = execute_with_limits(
+            instrs,
+            input,
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        );
+        let text = serialize(instrs, &state, &outcome);
+        let (cached_state, cached_outcome) = deserialize(&text, instrs).unwrap();
+        assert_eq!(cached_state, state);
+        assert_eq!(cached_outcome, outcome);
+    }
+
+    #[test]
+    fn round_trip_completed() {
+        round_trip(&parse("++>+++.<.").unwrap(), &[]);
+    }
+
+    #[test]
+    fn round_trip_deferred_read() {
+        let instrs = parse("+.,>,++[-]<.").unwrap();
+        round_trip(&instrs, b"x");
+    }
+
+    #[test]
+    fn round_trip_start_in_loop() {
+        // Runtime execution starts at the inner loop, which depends
+        // on the value read.
+        let instrs = parse("+[>,[.]<-]").unwrap();
+        let (state, _) = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        );
+        assert_eq!(instr_index(&instrs, state.start_instr.unwrap()), Some(4));
+        round_trip(&instrs, &[]);
+    }
+
+    #[test]
+    fn round_trip_branch() {
+        // ,[>+<[-]]>. with the inner loop simplified to a set.
+        let instrs = vec![
+            Read { position: None },
+            Loop {
+                body: vec![
+                    PointerIncrement {
+                        amount: 1,
+                        position: None,
+                    },
+                    Increment {
+                        amount: Wrapping(1),
+                        offset: 0,
+                        position: None,
+                    },
+                    PointerIncrement {
+                        amount: -1,
+                        position: None,
+                    },
+                    Set {
+                        amount: Wrapping(0),
+                        offset: 0,
+                        position: None,
+                    },
+                ],
+                position: None,
+            },
+            PointerIncrement {
+                amount: 1,
+                position: None,
+            },
+            Write { position: None },
+        ];
+        let (state, _) = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        );
+        assert_eq!(state.branches.len(), 1);
+        round_trip(&instrs, &[]);
+    }
+
+    #[test]
+    fn round_trip_runtime_error() {
+        round_trip(&parse("+[]").unwrap(), &[]);
+    }
+
+    #[test]
+    fn start_instr_is_in_given_program() {
+        let instrs = parse("+[,]").unwrap();
+        let (state, outcome) = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        );
+        let text = serialize(&instrs, &state, &outcome);
+        let (cached_state, _) = deserialize(&text, &instrs).unwrap();
+        assert!(std::ptr::eq(
+            cached_state.start_instr.unwrap(),
+            state.start_instr.unwrap()
+        ));
+    }
+
+    #[test]
+    fn invalid_entry_rejected() {
+        let instrs = parse("+").unwrap();
+        assert!(deserialize("", &instrs).is_err());
+        assert!(deserialize("bfc-speculation-cache 0\n", &instrs).is_err());
+        assert!(deserialize(
+            &format!("{}\nstart 5\noutcome out-of-steps\n", HEADER),
+            &instrs
+        )
+        .is_err());
+        assert!(deserialize(&format!("{}\nsteps 1\n", HEADER), &instrs).is_err());
+    }
+
+    #[test]
+    fn key_depends_on_program_and_settings() {
+        let limits = Limits::default();
+        let eof = EofBehaviour::default();
+        let key = cache_key(&parse("+.").unwrap(), &[], 8, eof, &limits);
+        assert_eq!(key, cache_key(&parse("+.").unwrap(), &[], 8, eof, &limits));
+        assert_ne!(key, cache_key(&parse("++.").unwrap(), &[], 8, eof, &limits));
+        assert_ne!(key, cache_key(&parse("+.").unwrap(), b"x", 8, eof, &limits));
+        assert_ne!(key, cache_key(&parse("+.").unwrap(), &[], 16, eof, &limits));
+        assert_ne!(
+            key,
+            cache_key(&parse("+.").unwrap(), &[], 8, EofBehaviour::Zero, &limits)
+        );
+        let fewer_steps = Limits {
+            steps: 10,
+            ..limits
+        };
+        assert_ne!(
+            key,
+            cache_key(&parse("+.").unwrap(), &[], 8, eof, &fewer_steps)
+        );
+        let growable_tape = Limits {
+            growable_tape: true,
+            ..limits
+        };
+        assert_ne!(
+            key,
+            cache_key(&parse("+.").unwrap(), &[], 8, eof, &growable_tape)
+        );
+    }
+}
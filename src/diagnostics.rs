@@ -1,16 +1,479 @@
 //! Human-readable warnings and errors for the CLI.
 
-use ansi_term::ANSIStrings;
-use ansi_term::Colour::{Purple, Red};
+use ansi_term::Colour::{Blue, Purple, Red};
 use ansi_term::Style;
+use ansi_term::{ANSIString, ANSIStrings};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::io::Write;
 
 use crate::bfir::Position;
 
-#[derive(Debug, PartialEq, Eq)]
+/// A piece of rendered diagnostic text, with its colour.
+type Segment = (Style, String);
+
+/// Categories of warning. Each category has a name so users can
+/// enable or disable it with `-W`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lint {
+    /// Instructions that have no effect.
+    RedundantCode,
+    /// Loops that can never be entered.
+    DeadLoop,
+    /// Loops that can never terminate.
+    InfiniteLoop,
+    /// Pointer movement that always goes before the first cell.
+    TapeUnderflow,
+    /// Pointer movement that always goes after the last cell.
+    TapeOverflow,
+    /// Cell accesses found to be out of bounds during speculative
+    /// execution.
+    OutOfBounds,
+    /// Pass plugins that failed.
+    PluginFailure,
+    /// Speculative execution stopping early because of a time or
+    /// output limit.
+    SpeculationLimit,
+    /// The peephole optimiser giving up before it finished.
+    OptimisationLimit,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 9] = [
+        Lint::RedundantCode,
+        Lint::DeadLoop,
+        Lint::InfiniteLoop,
+        Lint::TapeUnderflow,
+        Lint::TapeOverflow,
+        Lint::OutOfBounds,
+        Lint::PluginFailure,
+        Lint::SpeculationLimit,
+        Lint::OptimisationLimit,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::RedundantCode => "redundant-code",
+            Lint::DeadLoop => "dead-loop",
+            Lint::InfiniteLoop => "infinite-loop",
+            Lint::TapeUnderflow => "tape-underflow",
+            Lint::TapeOverflow => "tape-overflow",
+            Lint::OutOfBounds => "out-of-bounds",
+            Lint::PluginFailure => "plugin-failure",
+            Lint::SpeculationLimit => "speculation-limit",
+            Lint::OptimisationLimit => "optimisation-limit",
+        }
+    }
+
+    /// What the warning is about, for listing the categories.
+    pub fn description(self) -> &'static str {
+        match self {
+            Lint::RedundantCode => "Instructions at the end of the program with no effect",
+            Lint::DeadLoop => "Loops that can never be entered",
+            Lint::InfiniteLoop => "Loops that provably never terminate",
+            Lint::TapeUnderflow => "Pointer movements that always go before the first cell",
+            Lint::TapeOverflow => "Pointer movements that always go after the last cell",
+            Lint::OutOfBounds => "Out-of-range cell accesses found by speculative execution",
+            Lint::PluginFailure => "Pass plugins that crashed or returned invalid IR",
+            Lint::SpeculationLimit => "Speculative execution stopped by a time or output limit",
+            Lint::OptimisationLimit => "Peephole optimisation stopped before it finished",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.iter().cloned().find(|lint| lint.name() == name)
+    }
+}
+
+/// Every kind of error and warning bfc reports. Each has a stable
+/// code, shown in messages, that `bfc --explain` describes in more
+/// detail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Code {
+    /// A `[` without a matching `]`.
+    UnmatchedOpen,
+    /// A `]` without a matching `[`.
+    UnmatchedClose,
+    /// A file that couldn't be read or written.
+    FileError,
+    /// An invalid option, or options that can't be combined.
+    InvalidOption,
+    /// An option the target doesn't support.
+    UnsupportedTarget,
+    /// LLVM, the linker or another tool failing.
+    ToolFailure,
+    /// A pass plugin that couldn't be loaded.
+    PluginError,
+    /// A program leaving the tape or looping forever while interpreted
+    /// with `--run`.
+    RuntimeError,
+    /// A program exceeding a `--run` limit.
+    ResourceLimit,
+    /// A file given to `bfc inspect` without build metadata.
+    NoMetadata,
+    /// A category of warning.
+    Lint(Lint),
+}
+
+impl Code {
+    pub const ALL: [Code; 19] = [
+        Code::UnmatchedOpen,
+        Code::UnmatchedClose,
+        Code::FileError,
+        Code::InvalidOption,
+        Code::UnsupportedTarget,
+        Code::ToolFailure,
+        Code::PluginError,
+        Code::RuntimeError,
+        Code::ResourceLimit,
+        Code::NoMetadata,
+        Code::Lint(Lint::RedundantCode),
+        Code::Lint(Lint::DeadLoop),
+        Code::Lint(Lint::InfiniteLoop),
+        Code::Lint(Lint::TapeUnderflow),
+        Code::Lint(Lint::TapeOverflow),
+        Code::Lint(Lint::OutOfBounds),
+        Code::Lint(Lint::PluginFailure),
+        Code::Lint(Lint::SpeculationLimit),
+        Code::Lint(Lint::OptimisationLimit),
+    ];
+
+    /// The code shown to users. Errors count from BF0001 and
+    /// warnings from BF0101. Codes are never reused.
+    pub fn name(self) -> &'static str {
+        match self {
+            Code::UnmatchedOpen => "BF0001",
+            Code::UnmatchedClose => "BF0002",
+            Code::FileError => "BF0003",
+            Code::InvalidOption => "BF0004",
+            Code::UnsupportedTarget => "BF0005",
+            Code::ToolFailure => "BF0006",
+            Code::PluginError => "BF0007",
+            Code::RuntimeError => "BF0008",
+            Code::ResourceLimit => "BF0009",
+            Code::NoMetadata => "BF0010",
+            Code::Lint(Lint::RedundantCode) => "BF0101",
+            Code::Lint(Lint::DeadLoop) => "BF0102",
+            Code::Lint(Lint::InfiniteLoop) => "BF0103",
+            Code::Lint(Lint::TapeUnderflow) => "BF0104",
+            Code::Lint(Lint::TapeOverflow) => "BF0105",
+            Code::Lint(Lint::OutOfBounds) => "BF0106",
+            Code::Lint(Lint::PluginFailure) => "BF0107",
+            Code::Lint(Lint::SpeculationLimit) => "BF0108",
+            Code::Lint(Lint::OptimisationLimit) => "BF0109",
+        }
+    }
+
+    /// Find the code named `name`, ignoring case.
+    pub fn from_name(name: &str) -> Option<Code> {
+        Code::ALL
+            .iter()
+            .cloned()
+            .find(|code| code.name().eq_ignore_ascii_case(name))
+    }
+
+    /// A longer description of the diagnostic, with examples, for
+    /// `--explain`.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            Code::UnmatchedOpen => {
+                "A `[` has no matching `]`.
+
+Every `[` starts a loop, which must end with a `]` later in the
+program. This program never closes its loop:
+
+    +[->+<
+
+Add the missing `]`, or remove the `[`:
+
+    +[->+<]
+"
+            }
+            Code::UnmatchedClose => {
+                "A `]` has no matching `[`.
+
+Every `]` ends a loop, which must have started with a `[` earlier in
+the program. This program ends a loop it never started:
+
+    +->+<]
+
+Add the missing `[`, or remove the `]`:
+
+    +[->+<]
+"
+            }
+            Code::FileError => {
+                "bfc could not read or write a file.
+
+This is usually the source file not existing:
+
+    $ bfc missing.bf
+
+but it may also be a file included by the program, the output file,
+or a file given to an option such as --trace-speculation. The message
+gives the reason the operating system reported. Check the path and
+its permissions.
+"
+            }
+            Code::InvalidOption => {
+                "An option has an invalid value, or options were given that can't
+be used together.
+
+Cell sizes must be 8, 16 or 32 bits, so this fails:
+
+    $ bfc --cell-size=12 foo.bf
+
+Shared libraries need libc, so this fails too:
+
+    $ bfc --shared --freestanding foo.bf
+
+`bfc --help` lists the options and the values they accept. A
+`bfc: allow(CATEGORY)` comment naming an unknown warning category
+fails the same way.
+"
+            }
+            Code::UnsupportedTarget => {
+                "An option needs something the target doesn't have.
+
+--freestanding makes Linux system calls directly, so it can't build
+programs for macOS:
+
+    $ bfc --freestanding --target=x86_64-apple-darwin foo.bf
+
+Choose a target the option supports, or drop the option.
+"
+            }
+            Code::ToolFailure => {
+                "LLVM, the linker or another tool bfc runs failed.
+
+The message includes what the tool reported. The most common cause is
+not having a linker installed:
+
+    $ bfc foo.bf
+    error: Could not find a linker on $PATH (tried clang, cc, gcc).
+
+Install clang or gcc, or choose a linker with --linker.
+"
+            }
+            Code::PluginError => {
+                "A pass plugin given to --load-pass couldn't be loaded.
+
+The library must exist, and export bfc_pass_abi_version, bfc_pass_name,
+bfc_pass_run and bfc_pass_free:
+
+    $ bfc --load-pass=./libmypass.so foo.bf
+
+Plugins built for a different plugin ABI version are rejected too.
+Rebuild the plugin against this version of bfc.
+"
+            }
+            Code::RuntimeError => {
+                "While interpreting the program with --run, it did something that
+can't continue, such as leaving the tape or entering a loop that
+provably never terminates.
+
+This program moves the pointer before the first cell:
+
+    $ echo '<+.' > foo.bf
+    $ bfc --run foo.bf
+
+Compiled programs don't check the pointer unless built with
+--ptr-check, so the same bug may crash them instead. bfc exits with
+status 1 in this case.
+"
+            }
+            Code::ResourceLimit => {
+                "While interpreting the program with --run, it exceeded a limit set
+by --run-max-steps, --run-timeout, --run-max-output or --run-max-cells.
+
+This program prints 255 bytes, so it exceeds an output limit of ten:
+
+    $ echo '+[.+]' > count.bf
+    $ bfc --run --run-max-output=10 count.bf
+
+Raise the limit if the program needs more, or find why it doesn't
+stop. bfc exits with status 3 in this case.
+"
+            }
+            Code::NoMetadata => {
+                "`bfc inspect` found no build metadata in the file.
+
+bfc embeds metadata in the binaries it builds, so this usually means
+the file wasn't built by bfc, or was built for WebAssembly, which has
+no metadata:
+
+    $ bfc inspect /bin/ls
+"
+            }
+            Code::Lint(Lint::RedundantCode) => {
+                "Warning category `redundant-code`: these instructions have no
+effect.
+
+This is usually code at the end of the program, after the last
+output. The final `+` here changes a cell that's never printed:
+
+    +.+
+
+Remove the instructions, or disable the warning with
+-W no-redundant-code.
+"
+            }
+            Code::Lint(Lint::DeadLoop) => {
+                "Warning category `dead-loop`: this loop can never be entered.
+
+A loop runs until the current cell is zero, so a loop straight after
+another loop never runs:
+
+    +[-][.]
+
+Every cell starts at zero, so a loop at the start of a program never
+runs either. Such loops are often used as comments, which you can
+allow with -W no-dead-loop.
+"
+            }
+            Code::Lint(Lint::InfiniteLoop) => {
+                "Warning category `infinite-loop`: this loop never terminates.
+
+bfc found the current cell is never zero when the loop checks it.
+The simplest case is a loop with an empty body:
+
+    +[]
+
+If the program is meant to run forever, disable the warning with
+-W no-infinite-loop.
+"
+            }
+            Code::Lint(Lint::TapeUnderflow) => {
+                "Warning category `tape-underflow`: this instruction always moves
+the pointer before the first cell.
+
+The pointer starts at the first cell, so moving left straight away
+leaves the tape:
+
+    <.
+
+The compiled program will usually crash here. With --strict-bounds
+this is an error.
+"
+            }
+            Code::Lint(Lint::TapeOverflow) => {
+                "Warning category `tape-overflow`: this instruction always moves the
+pointer after the last cell.
+
+The tape has 100,000 cells unless --tape=grow is given, so a program
+that moves further right than that always leaves the tape. The
+compiled program will usually crash here. With --strict-bounds this
+is an error.
+"
+            }
+            Code::Lint(Lint::OutOfBounds) => {
+                "Warning category `out-of-bounds`: while running the program at
+compile time, bfc found it accessing a cell off the tape.
+
+Bounds analysis reports simple cases as `tape-underflow` or
+`tape-overflow`. This warning catches the rest, which only show up
+when the program actually runs. The compiled program will usually
+crash when it reaches this instruction.
+"
+            }
+            Code::Lint(Lint::PluginFailure) => {
+                "Warning category `plugin-failure`: a pass plugin crashed or
+returned invalid IR.
+
+bfc skips the plugin and compiles the program without it, so the
+program still works but misses the plugin's optimisation. Report the
+bug to the plugin's authors, or disable the warning with
+-W no-plugin-failure.
+"
+            }
+            Code::Lint(Lint::SpeculationLimit) => {
+                "Warning category `speculation-limit`: bfc stopped running the program
+at compile time because of --speculate-timeout or
+--speculate-max-output.
+
+The rest of the program runs at runtime as usual, so it still works,
+but it may be slower. Raise the limits to precompute more, or disable
+the warning with -W no-speculation-limit.
+"
+            }
+            Code::Lint(Lint::OptimisationLimit) => {
+                "Warning category `optimisation-limit`: bfc ran its peephole passes
+as many times as it allows, but they were still finding changes to
+make.
+
+The program is compiled correctly, but may miss some optimisations.
+--opt=3 allows many more runs. Please report the program, as it
+probably shows a pair of passes undoing each other's work.
+"
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Warning {
+    pub lint: Lint,
     pub message: String,
     pub position: Option<Position>,
+    pub notes: Vec<Note>,
+}
+
+/// Whether a note explains the diagnostic or suggests a fix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteKind {
+    Note,
+    Help,
+}
+
+impl NoteKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            NoteKind::Note => "note",
+            NoteKind::Help => "help",
+        }
+    }
+}
+
+/// A message shown beneath a diagnostic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Note {
+    pub kind: NoteKind,
+    pub message: String,
+}
+
+impl Note {
+    pub fn explain(msg: impl Into<String>) -> Self {
+        Note {
+            kind: NoteKind::Note,
+            message: msg.into(),
+        }
+    }
+
+    pub fn help(msg: impl Into<String>) -> Self {
+        Note {
+            kind: NoteKind::Help,
+            message: msg.into(),
+        }
+    }
+}
+
+/// A secondary span, underlined beneath the main one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub position: Position,
+    /// The lines containing the span.
+    pub source: String,
+    pub line_col: (u64, u64),
+    pub message: String,
+}
+
+/// The span a diagnostic is about, with the source around it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub position: Position,
+    /// The lines containing the span.
+    pub source: String,
+    pub line_col: (u64, u64),
 }
 
 /// The severity of the Info.
@@ -20,97 +483,893 @@ pub enum Level {
     Error,
 }
 
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Warning => "warning",
+            Level::Error => "error",
+        }
+    }
+}
+
+/// How diagnostics are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// Coloured text for people.
+    Human,
+    /// One JSON object per line, for editors and CI.
+    Json,
+    /// A SARIF 2.1 log, for code-scanning tools.
+    Sarif,
+}
+
+impl ErrorFormat {
+    /// A sink that prints diagnostics to `writer` in this format.
+    /// `styled` colours human-readable output.
+    pub fn sink<W: Write + 'static>(self, writer: W, styled: bool) -> Box<dyn DiagnosticSink> {
+        match self {
+            ErrorFormat::Human => Box::new(TerminalSink::new(writer, styled)),
+            ErrorFormat::Json => Box::new(JsonSink::new(writer)),
+            ErrorFormat::Sarif => Box::new(SarifSink::new(writer)),
+        }
+    }
+}
+
+/// Receives diagnostics as they're found. bfc prints them, but
+/// library users can collect them or handle them however they like.
+pub trait DiagnosticSink {
+    fn emit(&mut self, info: Info);
+
+    /// Called after the last diagnostic.
+    fn finish(&mut self) {}
+}
+
+impl<S: DiagnosticSink + ?Sized> DiagnosticSink for Box<S> {
+    fn emit(&mut self, info: Info) {
+        (**self).emit(info);
+    }
+
+    fn finish(&mut self) {
+        (**self).finish();
+    }
+}
+
+/// Prints each diagnostic as text, coloured if `styled`.
+pub struct TerminalSink<W: Write> {
+    writer: W,
+    styled: bool,
+}
+
+impl<W: Write> TerminalSink<W> {
+    pub fn new(writer: W, styled: bool) -> Self {
+        TerminalSink { writer, styled }
+    }
+}
+
+impl<W: Write> DiagnosticSink for TerminalSink<W> {
+    fn emit(&mut self, info: Info) {
+        // There's nowhere to report a failure to report.
+        let _ = writeln!(self.writer, "{}", info.render(self.styled));
+    }
+}
+
+/// Prints each diagnostic as a JSON object on a line of its own.
+pub struct JsonSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonSink { writer }
+    }
+}
+
+impl<W: Write> DiagnosticSink for JsonSink<W> {
+    fn emit(&mut self, info: Info) {
+        let _ = writeln!(self.writer, "{}", info.to_json());
+    }
+}
+
+/// Prints a SARIF log of every diagnostic when finished. The log is
+/// valid even without any results, so it's always printed.
+pub struct SarifSink<W: Write> {
+    writer: W,
+    infos: Vec<Info>,
+}
+
+impl<W: Write> SarifSink<W> {
+    pub fn new(writer: W) -> Self {
+        SarifSink {
+            writer,
+            infos: vec![],
+        }
+    }
+}
+
+impl<W: Write> DiagnosticSink for SarifSink<W> {
+    fn emit(&mut self, info: Info) {
+        self.infos.push(info);
+    }
+
+    fn finish(&mut self) {
+        let _ = writeln!(self.writer, "{}", sarif_log(&self.infos));
+    }
+}
+
+/// Keeps every diagnostic, for library users to inspect afterwards.
+#[derive(Debug, Default)]
+pub struct CollectingSink {
+    pub infos: Vec<Info>,
+}
+
+impl DiagnosticSink for CollectingSink {
+    fn emit(&mut self, info: Info) {
+        self.infos.push(info);
+    }
+}
+
+/// Passes diagnostics on to `inner` when finished, leaving out
+/// repeats as `dedup` does.
+pub struct DedupSink<S: DiagnosticSink> {
+    inner: S,
+    infos: Vec<Info>,
+}
+
+impl<S: DiagnosticSink> DedupSink<S> {
+    pub fn new(inner: S) -> Self {
+        DedupSink {
+            inner,
+            infos: vec![],
+        }
+    }
+}
+
+impl<S: DiagnosticSink> DiagnosticSink for DedupSink<S> {
+    fn emit(&mut self, info: Info) {
+        self.infos.push(info);
+    }
+
+    fn finish(&mut self) {
+        for info in dedup(std::mem::take(&mut self.infos)) {
+            self.inner.emit(info);
+        }
+        self.inner.finish();
+    }
+}
+
 /// Info represents a message to the user, a warning or an error with
 /// an optional reference to a position in the BF source.
 #[derive(Debug)]
 pub struct Info {
     pub level: Level,
+    pub code: Code,
     pub filename: Option<String>,
     pub message: String,
-    pub position: Option<Position>,
-    pub source: Option<String>,
-    pub line_col: Option<(u64, u64)>,
+    /// Boxed, as many diagnostics don't have one and a small Info
+    /// keeps `Result<_, Info>` small.
+    pub span: Option<Box<Span>>,
+    pub labels: Vec<Label>,
+    pub notes: Vec<Note>,
 }
 
 impl Info {
-    pub fn warn(msg: impl Into<String>) -> Self {
+    pub fn warn(lint: Lint, msg: impl Into<String>) -> Self {
         Info {
             level: Level::Warning,
+            code: Code::Lint(lint),
             filename: None,
             message: msg.into(),
-            position: None,
-            source: None,
-            line_col: None
+            span: None,
+            labels: vec![],
+            notes: vec![],
         }
     }
 
-    pub fn error(msg: impl Into<String>) -> Self {
+    pub fn error(code: Code, msg: impl Into<String>) -> Self {
         Info {
             level: Level::Error,
+            code,
             filename: None,
             message: msg.into(),
-            position: None,
-            source: None,
-            line_col: None
+            span: None,
+            labels: vec![],
+            notes: vec![],
+        }
+    }
+
+    /// Point at `span` in the source.
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(Box::new(span));
+        self
+    }
+
+    /// Underline another span beneath this one.
+    pub fn with_label(mut self, label: Label) -> Self {
+        self.labels.push(label);
+        self
+    }
+
+    /// Add a note or help message beneath this one.
+    pub fn with_note(mut self, note: Note) -> Self {
+        self.notes.push(note);
+        self
+    }
+
+    /// The message as plain text, without colours.
+    pub fn rendered(&self) -> String {
+        self.render(false)
+    }
+
+    /// The message as text, coloured with ANSI escapes if `styled`.
+    pub fn render(&self, styled: bool) -> String {
+        let segments = self.segments();
+        if !styled {
+            return segments.into_iter().map(|(_, text)| text).collect();
+        }
+
+        let strings: Vec<ANSIString> = segments
+            .iter()
+            .map(|(style, text)| style.paint(text.as_str()))
+            .collect();
+        ANSIStrings(&strings).to_string()
+    }
+
+    /// The message as a JSON object on a single line. Spans are byte
+    /// offsets into the file, with the end exclusive, and lines and
+    /// columns count from 1.
+    pub fn to_json(&self) -> String {
+        let optional_string = |text: &Option<String>| match text {
+            Some(text) => json_string(text),
+            None => "null".to_owned(),
+        };
+        let (span, line, column) = match &self.span {
+            Some(span) => (
+                format!(
+                    "{{\"start\": {}, \"end\": {}}}",
+                    span.position.start,
+                    span.position.end + 1
+                ),
+                (span.line_col.0 + 1).to_string(),
+                (span.line_col.1 + 1).to_string(),
+            ),
+            None => ("null".to_owned(), "null".to_owned(), "null".to_owned()),
+        };
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|label| {
+                format!(
+                    "{{\"message\": {}, \"span\": {{\"start\": {}, \"end\": {}}}, \
+                     \"line\": {}, \"column\": {}}}",
+                    json_string(&label.message),
+                    label.position.start,
+                    label.position.end + 1,
+                    label.line_col.0 + 1,
+                    label.line_col.1 + 1
+                )
+            })
+            .collect();
+        let notes: Vec<String> = self
+            .notes
+            .iter()
+            .map(|note| {
+                format!(
+                    "{{\"kind\": {}, \"message\": {}}}",
+                    json_string(note.kind.name()),
+                    json_string(&note.message)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"level\": {}, \"code\": {}, \"message\": {}, \"filename\": {}, \"span\": {}, \
+             \"line\": {}, \"column\": {}, \"labels\": [{}], \"notes\": [{}], \"rendered\": {}}}",
+            json_string(self.level.name()),
+            json_string(self.code.name()),
+            json_string(&self.message),
+            optional_string(&self.filename),
+            span,
+            line,
+            column,
+            labels.join(", "),
+            notes.join(", "),
+            json_string(&self.rendered()),
+        )
+    }
+
+    /// The message split into styled pieces, laid out like rustc's
+    /// diagnostics: the message, its location, the source lines
+    /// beside their line numbers with spans underlined, then notes.
+    fn segments(&self) -> Vec<Segment> {
+        let color = match self.level {
+            Level::Warning => Purple,
+            Level::Error => Red,
+        };
+        let bold = Style::new().bold();
+        let gutter = Blue.bold();
+        let default = Style::default();
+
+        let mut spans = vec![];
+        if let Some(span) = &self.span {
+            let Position { start, end } = span.position;
+            debug_assert!(start <= end);
+            let rows = underline(&span.source, span.line_col.1, end - start, '^', '~');
+            spans.push((span.line_col.0, &span.source, rows, color.bold(), ""));
+        }
+        for label in &self.labels {
+            let width = label.position.end - label.position.start;
+            let rows = underline(&label.source, label.line_col.1, width, '-', '-');
+            let message = label.message.as_str();
+            spans.push((label.line_col.0, &label.source, rows, gutter, message));
+        }
+
+        // Each line of source to show, with the spans underlined on
+        // it. The label text goes after the last line of its span.
+        let mut lines: BTreeMap<u64, (&str, Vec<Segment>)> = BTreeMap::new();
+        for (first_line, source, rows, style, message) in spans {
+            let source_lines: Vec<&str> = source.trim_end_matches('\n').split('\n').collect();
+            let last_row = rows.len().saturating_sub(1);
+            for (i, (line_offset, markers)) in rows.into_iter().enumerate() {
+                let text = if i == last_row && !message.is_empty() {
+                    format!("{} {}", markers, message)
+                } else {
+                    markers
+                };
+                lines
+                    .entry(first_line + line_offset)
+                    .or_insert((source_lines[line_offset as usize], vec![]))
+                    .1
+                    .push((style, text));
+            }
+        }
+
+        let width = match lines.keys().last() {
+            Some(line_idx) => (line_idx + 1).to_string().len(),
+            None => 1,
+        };
+        let pad = " ".repeat(width);
+
+        let mut segments = vec![
+            (
+                color.bold(),
+                format!("{}[{}]", self.level.name(), self.code.name()),
+            ),
+            (bold, format!(": {}", self.message)),
+        ];
+
+        if let Some(filename) = &self.filename {
+            let location = match &self.span {
+                Some(span) => format!(
+                    "{}:{}:{}",
+                    filename,
+                    span.line_col.0 + 1,
+                    span.line_col.1 + 1
+                ),
+                None => filename.clone(),
+            };
+            segments.push((gutter, format!("\n{}--> ", pad)));
+            segments.push((default, location));
+        }
+
+        if !lines.is_empty() {
+            segments.push((gutter, format!("\n{} |", pad)));
+        }
+        let mut previous_line = None;
+        for (line_idx, (source, rows)) in lines {
+            // Skip over the lines between spans.
+            if let Some(previous_line) = previous_line {
+                if line_idx > previous_line + 1 {
+                    segments.push((gutter, "\n...".to_owned()));
+                }
+            }
+            previous_line = Some(line_idx);
+
+            segments.push((gutter, format!("\n{:>w$} | ", line_idx + 1, w = width)));
+            segments.push((default, source.to_owned()));
+            for (style, text) in rows {
+                segments.push((gutter, format!("\n{} | ", pad)));
+                segments.push((style, text));
+            }
+        }
+
+        for note in &self.notes {
+            segments.push((gutter, format!("\n{} = ", pad)));
+            segments.push((bold, format!("{}:", note.kind.name())));
+            segments.push((default, format!(" {}", note.message)));
+        }
+
+        segments
+    }
+}
+
+/// Underline a span of `width` + 1 bytes, starting `column_idx` bytes
+/// into `source`. Returns the markers for each line the span covers,
+/// numbered from the first line of `source`.
+fn underline(
+    source: &str,
+    column_idx: u64,
+    width: usize,
+    first: char,
+    rest: char,
+) -> Vec<(u64, String)> {
+    let start = column_idx as usize;
+    let end = start + width;
+
+    let mut rows = vec![];
+    let mut line_start = 0;
+    for (line_offset, line) in source.trim_end_matches('\n').split('\n').enumerate() {
+        let mut markers = String::new();
+        // A span may start just after the last character of a line,
+        // such as the end of the file.
+        let offsets = line.char_indices().map(|(i, _)| i).chain(Some(line.len()));
+        for i in offsets {
+            let offset = line_start + i;
+            if offset == start {
+                markers.push(first);
+            } else if offset > start && offset <= end && i < line.len() {
+                markers.push(rest);
+            } else {
+                markers.push(' ');
+            }
+        }
+
+        let markers = markers.trim_end();
+        if !markers.is_empty() {
+            rows.push((line_offset as u64, markers.to_owned()));
+        }
+        line_start += line.len() + 1;
+    }
+    rows
+}
+
+impl From<Warning> for Info {
+    /// A warning from the IR. Its position isn't kept, as there's no
+    /// source line to show it with.
+    fn from(warning: Warning) -> Self {
+        Info {
+            notes: warning.notes,
+            ..Info::warn(warning.lint, warning.message)
         }
     }
 }
 
 impl fmt::Display for Info {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        let mut file_text = match &self.filename {
-            Some(v) => v.clone(),
-            None => String::from(""),
+        write!(f, "{}", self.rendered())
+    }
+}
+
+/// How many diagnostics with the same code and message `dedup` keeps.
+pub const MAX_REPEATS: usize = 3;
+
+/// Keep the first `MAX_REPEATS` diagnostics with each code and
+/// message, so generated programs don't flood the terminal. The last
+/// one kept says how many more there were.
+pub fn dedup(infos: Vec<Info>) -> Vec<Info> {
+    let mut totals: HashMap<(&'static str, String), usize> = HashMap::new();
+    for info in &infos {
+        *totals
+            .entry((info.code.name(), info.message.clone()))
+            .or_insert(0) += 1;
+    }
+
+    let mut seen: HashMap<(&'static str, String), usize> = HashMap::new();
+    let mut kept = vec![];
+    for info in infos {
+        let key = (info.code.name(), info.message.clone());
+        let count = seen.entry(key.clone()).or_insert(0);
+        *count += 1;
+        if *count > MAX_REPEATS {
+            continue;
+        }
+
+        let total = totals[&key];
+        if *count == MAX_REPEATS && total > MAX_REPEATS {
+            let more = total - MAX_REPEATS;
+            kept.push(info.with_note(Note::explain(format!(
+                "… and {} more like this (use --no-dedup to see them all)",
+                more
+            ))));
+        } else {
+            kept.push(info);
+        }
+    }
+    kept
+}
+
+/// A SARIF 2.1 log of `infos`. Each warning category is a rule, so
+/// code-scanning tools can group and suppress warnings by category.
+pub fn sarif_log(infos: &[Info]) -> String {
+    let rules: Vec<String> = Lint::ALL
+        .iter()
+        .map(|lint| {
+            format!(
+                "{{\"id\": {}, \"shortDescription\": {{\"text\": {}}}}}",
+                json_string(lint.name()),
+                json_string(lint.description())
+            )
+        })
+        .collect();
+    let results: Vec<String> = infos.iter().map(sarif_result).collect();
+
+    format!(
+        "{{\"$schema\": \"https://json.schemastore.org/sarif-2.1.0.json\",\n\
+         \"version\": \"2.1.0\",\n\
+         \"runs\": [{{\n\
+         \"tool\": {{\"driver\": {{\"name\": \"bfc\", \"version\": {}, \"rules\": [\n{}\n]}}}},\n\
+         \"results\": [{}{}{}]\n\
+         }}]}}",
+        json_string(env!("CARGO_PKG_VERSION")),
+        rules.join(",\n"),
+        if results.is_empty() { "" } else { "\n" },
+        results.join(",\n"),
+        if results.is_empty() { "" } else { "\n" },
+    )
+}
+
+/// A SARIF result object for `info`.
+fn sarif_result(info: &Info) -> String {
+    let mut fields = vec![];
+    if let Code::Lint(lint) = info.code {
+        fields.push(format!("\"ruleId\": {}", json_string(lint.name())));
+    }
+    fields.push(format!("\"level\": {}", json_string(info.level.name())));
+    fields.push(format!(
+        "\"message\": {{\"text\": {}}}",
+        json_string(&info.message)
+    ));
+
+    if let Some(filename) = &info.filename {
+        let mut region = vec![];
+        if let Some(span) = &info.span {
+            let Position { start, end } = span.position;
+            region.push(format!("\"startLine\": {}", span.line_col.0 + 1));
+            region.push(format!("\"startColumn\": {}", span.line_col.1 + 1));
+            region.push(format!("\"byteOffset\": {}", start));
+            region.push(format!("\"byteLength\": {}", end - start + 1));
+        }
+        let region = if region.is_empty() {
+            "".to_owned()
+        } else {
+            format!(", \"region\": {{{}}}", region.join(", "))
         };
+        fields.push(format!(
+            "\"locations\": [{{\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": {}}}{}}}}}]",
+            json_string(filename),
+            region
+        ));
 
-        // Find line and column offsets, if we have an index.
-        let offsets = match (&self.position, &self.line_col) {
-            (&Some(range), &Some((line_idx, column_idx))) => {
-                debug_assert!(range.start <= range.end);
+        let related: Vec<String> = info
+            .labels
+            .iter()
+            .map(|label| {
+                format!(
+                    "{{\"physicalLocation\": {{\"artifactLocation\": {{\"uri\": {}}}, \
+                     \"region\": {{\"startLine\": {}, \"startColumn\": {}, \
+                     \"byteOffset\": {}, \"byteLength\": {}}}}}, \"message\": {{\"text\": {}}}}}",
+                    json_string(filename),
+                    label.line_col.0 + 1,
+                    label.line_col.1 + 1,
+                    label.position.start,
+                    label.position.end - label.position.start + 1,
+                    json_string(&label.message)
+                )
+            })
+            .collect();
+        if !related.is_empty() {
+            fields.push(format!("\"relatedLocations\": [{}]", related.join(", ")));
+        }
+    }
 
-                // let (line_idx, column_idx) = position(source, range.start);
-                //
-                file_text = file_text + &format!(":{}:{}", line_idx + 1, column_idx + 1);
-                Some((column_idx, range.end - range.start))
-            }
-            _ => None,
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// Quote `text` as a JSON string.
+pub fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("a\"b\\c\n\t"), "\"a\\\"b\\\\c\\n\\u0009\"");
+    }
+
+    #[test]
+    fn info_as_json() {
+        let info = Info {
+            level: Level::Warning,
+            code: Code::Lint(Lint::DeadLoop),
+            filename: Some("foo.bf".to_owned()),
+            message: "This loop is never entered".to_owned(),
+            span: Some(Box::new(Span {
+                position: Position { start: 2, end: 4 },
+                source: "+-[>]".to_owned(),
+                line_col: (0, 2),
+            })),
+            labels: vec![],
+            notes: vec![],
         };
+        assert_eq!(
+            info.to_json(),
+            "{\"level\": \"warning\", \"code\": \"BF0102\", \
+             \"message\": \"This loop is never entered\", \
+             \"filename\": \"foo.bf\", \"span\": {\"start\": 2, \"end\": 5}, \
+             \"line\": 1, \"column\": 3, \"labels\": [], \"notes\": [], \"rendered\": \"warning[BF0102]: \
+             This loop is never entered\\n --> foo.bf:1:3\\n  |\\n1 | +-[>]\\n  |   ^~~\"}"
+        );
+    }
 
-        let (color, level_text) = match self.level {
-            Level::Warning => (Purple, " warning: "),
-            Level::Error => (Red, " error: "),
+    #[test]
+    fn sarif_result_with_location() {
+        let info = Info {
+            level: Level::Warning,
+            code: Code::Lint(Lint::DeadLoop),
+            filename: Some("foo.bf".to_owned()),
+            message: "This loop is never entered".to_owned(),
+            span: Some(Box::new(Span {
+                position: Position { start: 2, end: 4 },
+                source: "+-[>]".to_owned(),
+                line_col: (0, 2),
+            })),
+            labels: vec![],
+            notes: vec![],
         };
+        assert_eq!(
+            sarif_result(&info),
+            "{\"ruleId\": \"dead-loop\", \"level\": \"warning\", \
+             \"message\": {\"text\": \"This loop is never entered\"}, \
+             \"locations\": [{\"physicalLocation\": {\"artifactLocation\": {\"uri\": \"foo.bf\"}, \
+             \"region\": {\"startLine\": 1, \"startColumn\": 3, \"byteOffset\": 2, \"byteLength\": 3}}}]}"
+        );
+    }
 
-        let mut context_line = "".to_owned();
-        let mut caret_line = "".to_owned();
-        if let (Some((column_idx, width)), &Some(ref source)) = (offsets, &self.source) {
-            // The faulty line of code.
-            // let line = source.split('\n').nth(line_idx).unwrap();
-            // context_line = "\n".to_owned() + line;
-            context_line = source.clone();
-
-            // Highlight the faulty characters on that line.
-            caret_line += "\n";
-            for _ in 0..column_idx {
-                caret_line += " ";
-            }
-            caret_line += "^";
-            if width > 0 {
-                for _ in 0..width {
-                    caret_line += "~";
-                }
-            }
+    #[test]
+    fn sarif_result_without_location() {
+        assert_eq!(
+            sarif_result(&Info::error(Code::FileError, "No such file")),
+            "{\"level\": \"error\", \"message\": {\"text\": \"No such file\"}}"
+        );
+    }
+
+    #[test]
+    fn sarif_log_lists_every_category() {
+        let log = sarif_log(&[]);
+        assert!(log.contains("\"version\": \"2.1.0\""));
+        assert!(log.contains("\"results\": []"));
+        for lint in &Lint::ALL {
+            assert!(log.contains(&format!("{{\"id\": \"{}\"", lint.name())));
         }
+    }
 
-        let bold = Style::new().bold();
-        let default = Style::default();
-        let strings = [
-            bold.paint(file_text),
-            color.bold().paint(level_text),
-            bold.paint(self.message.clone()),
-            default.paint(context_line),
-            color.bold().paint(caret_line),
+    #[test]
+    fn info_without_position_as_json() {
+        assert_eq!(
+            Info::error(Code::FileError, "No such file").to_json(),
+            "{\"level\": \"error\", \"code\": \"BF0003\", \"message\": \"No such file\", \
+             \"filename\": null, \"span\": null, \"line\": null, \"column\": null, \
+             \"labels\": [], \"notes\": [], \"rendered\": \"error[BF0003]: No such file\"}"
+        );
+    }
+
+    #[test]
+    fn render_notes_beneath_message() {
+        let info = Info::error(Code::FileError, "No such file")
+            .with_note(Note::explain("The file was deleted."))
+            .with_note(Note::help("Check the path."));
+        assert_eq!(
+            info.rendered(),
+            "error[BF0003]: No such file\n  = note: The file was deleted.\n  = help: Check the path."
+        );
+        assert!(info.to_json().contains(
+            "\"notes\": [{\"kind\": \"note\", \"message\": \"The file was deleted.\"}, \
+             {\"kind\": \"help\", \"message\": \"Check the path.\"}]"
+        ));
+    }
+
+    #[test]
+    fn render_labels_beneath_carets() {
+        let info = Info {
+            level: Level::Error,
+            code: Code::UnmatchedClose,
+            filename: Some("foo.bf".to_owned()),
+            message: "This ] has no matching [".to_owned(),
+            span: Some(Box::new(Span {
+                position: Position { start: 6, end: 6 },
+                source: ">]\n".to_owned(),
+                line_col: (1, 1),
+            })),
+            labels: vec![],
+            notes: vec![],
+        }
+        .with_label(Label {
+            position: Position { start: 1, end: 1 },
+            source: "+[-]\n".to_owned(),
+            line_col: (0, 1),
+            message: "this loop is already closed".to_owned(),
+        });
+        assert_eq!(
+            info.rendered(),
+            "error[BF0002]: This ] has no matching [\n --> foo.bf:2:2\n  |\n\
+             1 | +[-]\n  |  - this loop is already closed\n2 | >]\n  |  ^"
+        );
+        assert!(info.to_json().contains(
+            "\"labels\": [{\"message\": \"this loop is already closed\", \
+             \"span\": {\"start\": 1, \"end\": 2}, \"line\": 1, \"column\": 2}]"
+        ));
+        assert!(sarif_result(&info).contains(
+            "\"relatedLocations\": [{\"physicalLocation\": {\"artifactLocation\": {\"uri\": \"foo.bf\"}, \
+             \"region\": {\"startLine\": 1, \"startColumn\": 2, \"byteOffset\": 1, \"byteLength\": 1}}, \
+             \"message\": {\"text\": \"this loop is already closed\"}}]"
+        ));
+    }
+
+    #[test]
+    fn render_span_across_lines() {
+        let info = Info {
+            level: Level::Warning,
+            code: Code::Lint(Lint::RedundantCode),
+            filename: Some("foo.bf".to_owned()),
+            message: "These instructions have no effect.".to_owned(),
+            span: Some(Box::new(Span {
+                position: Position { start: 11, end: 14 },
+                source: ".>+\n+<\n".to_owned(),
+                line_col: (8, 1),
+            })),
+            labels: vec![],
+            notes: vec![],
+        };
+        assert_eq!(
+            info.rendered(),
+            "warning[BF0101]: These instructions have no effect.\n  --> foo.bf:9:2\n   |\n 9 | .>+\n   |  ^~\n\
+             10 | +<\n   | ~"
+        );
+    }
+
+    #[test]
+    fn render_gap_between_lines() {
+        let info =
+            Info::error(Code::UnmatchedClose, "This ] has no matching [").with_label(Label {
+                position: Position { start: 0, end: 0 },
+                source: "[]".to_owned(),
+                line_col: (0, 0),
+                message: "here".to_owned(),
+            });
+        let info = Info {
+            filename: Some("foo.bf".to_owned()),
+            span: Some(Box::new(Span {
+                position: Position { start: 20, end: 20 },
+                source: "]".to_owned(),
+                line_col: (4, 0),
+            })),
+            ..info
+        };
+        assert_eq!(
+            info.rendered(),
+            "error[BF0002]: This ] has no matching [\n --> foo.bf:5:1\n  |\n\
+             1 | []\n  | - here\n...\n5 | ]\n  | ^"
+        );
+    }
+
+    #[test]
+    fn dedup_repeated_warnings() {
+        let mut infos: Vec<Info> = (0..5)
+            .map(|_| Info::warn(Lint::DeadLoop, "This loop is never entered"))
+            .collect();
+        infos.push(Info::warn(Lint::DeadLoop, "Another message"));
+
+        let infos = dedup(infos);
+        assert_eq!(infos.len(), MAX_REPEATS + 1);
+        assert!(infos[..MAX_REPEATS - 1]
+            .iter()
+            .all(|info| info.notes.is_empty()));
+        assert_eq!(
+            infos[MAX_REPEATS - 1].notes,
+            vec![Note::explain(
+                "… and 2 more like this (use --no-dedup to see them all)"
+            )]
+        );
+        assert_eq!(infos[MAX_REPEATS].message, "Another message");
+        assert!(infos[MAX_REPEATS].notes.is_empty());
+    }
+
+    #[test]
+    fn dedup_keeps_few_repeats() {
+        let infos = vec![
+            Info::warn(Lint::DeadLoop, "This loop is never entered"),
+            Info::warn(Lint::DeadLoop, "This loop is never entered"),
         ];
-        write!(f, "{}", ANSIStrings(&strings))
+        let infos = dedup(infos);
+        assert_eq!(infos.len(), 2);
+        assert!(infos.iter().all(|info| info.notes.is_empty()));
+    }
+
+    #[test]
+    fn terminal_sink_colours_only_when_styled() {
+        let mut plain = vec![];
+        TerminalSink::new(&mut plain, false).emit(Info::error(Code::FileError, "No such file"));
+        let plain = String::from_utf8(plain).unwrap();
+        assert_eq!(plain, "error[BF0003]: No such file\n");
+
+        let mut styled = vec![];
+        TerminalSink::new(&mut styled, true).emit(Info::error(Code::FileError, "No such file"));
+        let styled = String::from_utf8(styled).unwrap();
+        assert!(styled.contains("\x1b["));
+        assert_ne!(styled, plain);
+    }
+
+    #[test]
+    fn json_sink_prints_a_line_per_diagnostic() {
+        let mut output = vec![];
+        {
+            let mut sink = JsonSink::new(&mut output);
+            sink.emit(Info::error(Code::FileError, "No such file"));
+            sink.emit(Info::error(Code::FileError, "Permission denied"));
+            sink.finish();
+        }
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output.lines().count(), 2);
+        assert!(output.ends_with("\"rendered\": \"error[BF0003]: Permission denied\"}\n"));
+    }
+
+    #[test]
+    fn sarif_sink_prints_log_when_finished() {
+        let mut output = vec![];
+        {
+            let mut sink = SarifSink::new(&mut output);
+            sink.emit(Info::error(Code::FileError, "No such file"));
+            sink.finish();
+        }
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("\"results\": [\n{\"level\": \"error\""));
+    }
+
+    #[test]
+    fn dedup_sink_forwards_when_finished() {
+        let mut sink = DedupSink::new(CollectingSink::default());
+        for _ in 0..5 {
+            sink.emit(Info::warn(Lint::DeadLoop, "This loop is never entered"));
+        }
+        assert!(sink.inner.infos.is_empty());
+
+        sink.finish();
+        assert_eq!(sink.inner.infos.len(), MAX_REPEATS);
+    }
+
+    #[test]
+    fn codes_are_unique() {
+        for (i, code) in Code::ALL.iter().enumerate() {
+            assert_eq!(Code::from_name(code.name()), Some(*code));
+            assert!(Code::ALL[..i]
+                .iter()
+                .all(|other| other.name() != code.name()));
+        }
+    }
+
+    #[test]
+    fn every_lint_has_a_code() {
+        for lint in &Lint::ALL {
+            assert!(Code::ALL.contains(&Code::Lint(*lint)));
+        }
+    }
+
+    #[test]
+    fn find_code_ignoring_case() {
+        assert_eq!(Code::from_name("bf0001"), Some(Code::UnmatchedOpen));
+        assert_eq!(Code::from_name("BF9999"), None);
     }
 }
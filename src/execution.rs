@@ -1,14 +1,20 @@
 //! Compile time execution of BF programs.
 
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
 use std::num::Wrapping;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, Cell};
+use crate::bfir::{
+    get_position, wrap_cell, AstNode, Cell, EofBehaviour, Position, DEFAULT_CELL_BITS,
+};
 
-use crate::diagnostics::Warning;
+use crate::diagnostics::{Lint, Warning};
 
-use crate::bounds::highest_cell_index;
+use crate::bounds::{highest_cell_index, MAX_CELL_INDEX};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ExecutionState<'a> {
@@ -16,15 +22,140 @@ pub struct ExecutionState<'a> {
     pub cells: Vec<Cell>,
     pub cell_ptr: isize,
     pub outputs: Vec<i8>,
+    /// Reads that compile time execution continued past. These must
+    /// still happen at runtime, interleaved with `outputs`.
+    pub reads: Vec<DeferredRead>,
+    /// Input given at compile time that hasn't been read yet. Reads
+    /// consume this before reading from stdin.
+    pub input: VecDeque<u8>,
+    /// The number of steps executed at compile time.
+    pub steps: u64,
+    /// How often each loop was entered and exited at compile time,
+    /// keyed by the address of the loop instruction. Codegen uses
+    /// this to tell LLVM which way loop branches usually go.
+    pub loop_counts: HashMap<*const AstNode, LoopCounts>,
+    /// Loops on read values that compile time execution explored
+    /// both ways. The cells that differ between the two are set at
+    /// runtime, once the value read is known.
+    pub branches: Vec<DeferredBranch>,
+    pub tape: Tape,
+}
+
+/// What happens when a program moves past the last cell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tape {
+    /// The tape has exactly the cells in `cells`, as in compiled
+    /// programs, so moving past the last one is an error.
+    Fixed,
+    /// The tape grows on demand to at most `max_cells` cells, as it
+    /// would with a runtime that grows the tape. Moving past that is
+    /// an error.
+    Growable { max_cells: usize },
+}
+
+/// How often a loop's condition was checked during compile time
+/// execution.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoopCounts {
+    /// The number of times the loop body was entered.
+    pub iterations: u64,
+    /// The number of times the loop was skipped or finished.
+    pub exits: u64,
+}
+
+impl LoopCounts {
+    /// Did the loop run at most once each time it was entered? Such a
+    /// loop is cold, so unrolling it or inlining it into hotter code
+    /// costs more in code size than it saves.
+    pub fn is_cold(&self) -> bool {
+        self.exits > 0 && self.iterations <= self.exits
+    }
+}
+
+/// A read whose value is unknown at compile time. Execution can
+/// continue past it, provided nothing depends on the value read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeferredRead {
+    /// The cell that should hold the value read, or None if the
+    /// value is overwritten before anything uses it.
+    pub cell: Option<usize>,
+    /// The number of bytes to read. Only the last one is stored.
+    pub count: usize,
+    /// The number of bytes in `outputs` written before this read.
+    pub outputs_before: usize,
+    /// Compile time execution can continue past arithmetic on the
+    /// value read, so `cell` holds the value read multiplied by
+    /// `scale`, plus the value of `cell` in `cells`.
+    pub scale: Cell,
+}
+
+/// A loop whose condition depended on a read. We executed it at
+/// compile time both as if it was entered and as if it was skipped,
+/// and merged the two results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeferredBranch {
+    /// The index in `reads` of the read the condition depends on.
+    pub read: usize,
+    /// The loop was entered if the value read multiplied by `scale`,
+    /// plus `offset`, was nonzero.
+    pub scale: Cell,
+    pub offset: Cell,
+    /// The cells whose values differ between the two, with their
+    /// values if the loop was entered. Their values if it was
+    /// skipped are in `cells`.
+    pub cells: Vec<(usize, Cell)>,
 }
 
 impl<'a> ExecutionState<'a> {
     pub fn initial(instrs: &[AstNode]) -> Self {
+        ExecutionState::with_tape(instrs, Tape::Fixed)
+    }
+
+    /// The initial state with the tape given. A growable tape starts
+    /// with a single cell.
+    pub fn with_tape(instrs: &[AstNode], tape: Tape) -> Self {
+        let num_cells = match tape {
+            Tape::Fixed => highest_cell_index(instrs) + 1,
+            Tape::Growable { .. } => 1,
+        };
         ExecutionState {
             start_instr: None,
-            cells: vec![Wrapping(0); highest_cell_index(instrs) + 1],
+            cells: vec![Wrapping(0); num_cells],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape,
+        }
+    }
+
+    /// The most cells the tape can have.
+    pub fn max_cells(&self) -> usize {
+        match self.tape {
+            Tape::Fixed => self.cells.len(),
+            Tape::Growable { max_cells } => max_cells,
+        }
+    }
+
+    /// Is the cell at `cell_index` on the tape? A growable tape grows
+    /// to include it if it can.
+    fn on_tape(&mut self, cell_index: isize) -> bool {
+        if cell_index < 0 {
+            return false;
+        }
+        let cell_index = cell_index as usize;
+        if cell_index < self.cells.len() {
+            return true;
+        }
+        match self.tape {
+            Tape::Growable { max_cells } if cell_index < max_cells => {
+                self.cells.resize(cell_index + 1, Wrapping(0));
+                true
+            }
+            _ => false,
         }
     }
 }
@@ -36,6 +167,70 @@ pub enum Outcome {
     ReachedRuntimeValue,
     RuntimeError(Warning),
     OutOfSteps,
+    OutOfTime,
+    OutputTooLarge,
+    /// The program moved past the last cell of a tape capped by
+    /// `Limits::max_cells`.
+    OutOfCells,
+    /// The user asked us to stop, with `interrupt`.
+    Interrupted,
+}
+
+/// How much work compile time execution may do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub steps: u64,
+    /// Stop executing when this time is reached, if set.
+    pub deadline: Option<Instant>,
+    /// The maximum number of bytes of output to capture. The cells
+    /// are allocated up front, so output is the only memory that
+    /// grows during execution.
+    pub max_output: usize,
+    /// The most cells `interpret` may allocate. Compile time
+    /// execution always has as many cells as the compiled program,
+    /// so ignores this.
+    pub max_cells: usize,
+    /// The compiled program grows its tape as far as it goes, so
+    /// reaching the last cell stops execution rather than being an
+    /// error. `interpret` grows its tape up to `max_cells`.
+    pub growable_tape: bool,
+}
+
+/// By default, we capture up to 16 MiB of output. Beyond that, the
+/// executable would be dominated by its precomputed output.
+pub const DEFAULT_MAX_OUTPUT: usize = 16 * 1024 * 1024;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            steps: max_steps(),
+            deadline: None,
+            max_output: DEFAULT_MAX_OUTPUT,
+            max_cells: MAX_CELL_INDEX + 1,
+            growable_tape: false,
+        }
+    }
+}
+
+/// How often we check the clock, in steps. Checking on every step
+/// would noticeably slow down execution. This must be a power of two.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+/// Set when the user asks compile time execution to stop early, such
+/// as by pressing Ctrl-C. Execution stops as if it had reached its
+/// deadline, keeping everything computed so far.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Ask compile time execution to stop as soon as possible. This only
+/// touches an atomic flag, so it's safe to call from a signal handler.
+pub fn interrupt() {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Forget any earlier call to `interrupt`, so the next compile time
+/// execution runs in full.
+pub fn clear_interrupt() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
 }
 
 /// The maximum number of steps we should execute at compile time.
@@ -55,8 +250,77 @@ pub fn max_steps() -> u64 {
 /// final state of the cells, any print side effects, and the point in
 /// the code we reached.
 pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warning>) {
+    let (state, outcome) = execute_with_limits(
+        instrs,
+        &[],
+        DEFAULT_CELL_BITS,
+        EofBehaviour::default(),
+        Limits {
+            steps,
+            ..Limits::default()
+        },
+    );
+    let warning = outcome.warning(&state);
+    (state, warning)
+}
+
+/// As `execute`, but also stop when we reach the deadline or output
+/// limit in `limits`. Reads consume `input` before being left for
+/// runtime. Cells are `cell_bits` wide and reads behave as `eof`
+/// says at EOF, matching the compiled program.
+pub fn execute_with_limits<'a>(
+    instrs: &'a [AstNode],
+    input: &[u8],
+    cell_bits: u32,
+    eof: EofBehaviour,
+    limits: Limits,
+) -> (ExecutionState<'a>, Outcome) {
+    execute_speculatively(instrs, input, cell_bits, eof, limits, None)
+}
+
+/// As `execute_with_limits`, but also record the instructions
+/// executed in `trace`.
+pub fn execute_with_trace<'a>(
+    instrs: &'a [AstNode],
+    input: &[u8],
+    cell_bits: u32,
+    eof: EofBehaviour,
+    limits: Limits,
+    trace: &mut Trace,
+) -> (ExecutionState<'a>, Outcome) {
+    execute_speculatively(instrs, input, cell_bits, eof, limits, Some(trace))
+}
+
+fn execute_speculatively<'a>(
+    instrs: &'a [AstNode],
+    input: &[u8],
+    cell_bits: u32,
+    eof: EofBehaviour,
+    limits: Limits,
+    trace: Option<&mut Trace>,
+) -> (ExecutionState<'a>, Outcome) {
     let mut state = ExecutionState::initial(instrs);
-    let outcome = execute_with_state(instrs, &mut state, steps, None);
+    state.input = input.iter().copied().collect();
+    let options = Options {
+        dummy_read_value: None,
+        cell_bits,
+        deadline: limits.deadline,
+        max_output: limits.max_output,
+        split_branches: true,
+        eof_reads: false,
+        eof,
+        capped_tape: limits.growable_tape,
+        interrupt: Some(&INTERRUPTED),
+    };
+    let mut unknown_cells = HashMap::new();
+    let outcome = execute_inner(
+        instrs,
+        &mut state,
+        limits.steps,
+        &options,
+        Some(&mut unknown_cells),
+        trace,
+    );
 
     // Sanity check: if we have a start instruction we
     // can't have executed the entire program at compile time.
@@ -65,9 +329,46 @@ pub fn execute(instrs: &[AstNode], steps: u64) -> (ExecutionState, Option<Warnin
         None => debug_assert!(matches!(outcome, Outcome::Completed(_))),
     }
 
-    match outcome {
-        Outcome::RuntimeError(warning) => (state, Some(warning)),
-        _ => (state, None),
+    (state, outcome)
+}
+
+impl Outcome {
+    /// The warning to show the user for this outcome, if any.
+    pub fn warning(&self, state: &ExecutionState) -> Option<Warning> {
+        // Tell the user if we stopped early for reasons other than
+        // the step budget, as they may want to adjust the limits.
+        let reason = match *self {
+            Outcome::RuntimeError(ref warning) => return Some(warning.clone()),
+            Outcome::OutOfTime => "ran out of time",
+            Outcome::OutputTooLarge => "reached the output limit",
+            Outcome::Interrupted => "was interrupted",
+            _ => return None,
+        };
+        Some(Warning {
+            lint: Lint::SpeculationLimit,
+            message: format!(
+                "Compile time execution {} here, so the rest of the program will run at runtime.",
+                reason
+            ),
+            position: state.start_instr.and_then(get_position),
+            notes: vec![],
+        })
+    }
+
+    /// A short explanation of why execution stopped.
+    pub fn description(&self) -> String {
+        match *self {
+            Outcome::Completed(_) => "the program finished".to_owned(),
+            Outcome::ReachedRuntimeValue => "the next instruction depends on input".to_owned(),
+            Outcome::RuntimeError(ref warning) => warning.message.clone(),
+            Outcome::OutOfSteps => "ran out of steps (see --max-compile-steps)".to_owned(),
+            Outcome::OutOfTime => "ran out of time (see --speculate-timeout)".to_owned(),
+            Outcome::OutputTooLarge => {
+                "reached the output limit (see --speculate-max-output)".to_owned()
+            }
+            Outcome::OutOfCells => "ran out of cells (see --run-max-cells)".to_owned(),
+            Outcome::Interrupted => "interrupted by the user".to_owned(),
+        }
     }
 }
 
@@ -81,515 +382,3472 @@ pub fn execute_with_state<'a>(
     state: &mut ExecutionState<'a>,
     steps: u64,
     dummy_read_value: Option<i8>,
+    cell_bits: u32,
 ) -> Outcome {
-    let mut steps_left = steps;
-    let mut instr_idx = 0;
-    while instr_idx < instrs.len() && steps_left > 0 {
-        let cell_ptr = state.cell_ptr as usize;
+    let options = Options {
+        dummy_read_value,
+        cell_bits,
+        deadline: None,
+        max_output: usize::MAX,
+        split_branches: false,
+        eof_reads: false,
+        eof: EofBehaviour::default(),
+        capped_tape: false,
+        interrupt: None,
+    };
+    execute_inner(instrs, state, steps, &options, None, None)
+}
 
-        match instrs[instr_idx] {
-            Increment { amount, offset, .. } => {
-                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
-                state.cells[target_cell_ptr] += amount;
-                instr_idx += 1;
-            }
-            Set { amount, offset, .. } => {
-                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
-                state.cells[target_cell_ptr] = amount;
-                instr_idx += 1;
-            }
-            PointerIncrement {
-                amount, position, ..
-            } => {
-                let new_cell_ptr = state.cell_ptr + amount;
-                if new_cell_ptr < 0 || new_cell_ptr >= state.cells.len() as isize {
-                    // We can't execute this instruction, so we'll
-                    // execute it at runtime (it'll probably be an
-                    // error).
-                    state.start_instr = Some(&instrs[instr_idx]);
-
-                    let message = if new_cell_ptr < 0 {
-                        format!(
-                            "This instruction moves the pointer to cell {}.",
-                            new_cell_ptr
-                        )
-                    } else {
-                        format!(
-                            "This instruction moves the pointer after the last cell ({}), to \
-                             cell {}.",
-                            state.cells.len() - 1,
-                            new_cell_ptr
-                        )
-                    };
-                    return Outcome::RuntimeError(Warning { message, position });
-                } else {
-                    state.cell_ptr = new_cell_ptr;
-                    instr_idx += 1;
-                }
-            }
-            MultiplyMove {
-                ref changes,
-                position,
-                ..
-            } => {
-                let cell_value = state.cells[cell_ptr];
+/// How a program run by `interpret` finished.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The program ran to completion.
+    Finished,
+    /// The program would fail at runtime, such as by moving the
+    /// pointer out of bounds or entering an infinite loop.
+    RuntimeError(Warning),
+    /// We stopped the program because it reached one of the `Limits`
+    /// given.
+    LimitExceeded(Resource),
+}
 
-                if cell_value.0 != 0 {
-                    // We will multiply by the current cell value.
+/// The resources that `Limits` caps when interpreting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Steps,
+    Time,
+    Output,
+    Cells,
+}
 
-                    for (cell_offset, factor) in changes {
-                        let dest_ptr = cell_ptr as isize + *cell_offset;
-                        if dest_ptr < 0 {
-                            // Tried to access a cell before cell #0.
-                            state.start_instr = Some(&instrs[instr_idx]);
+impl Resource {
+    pub fn name(self) -> &'static str {
+        match self {
+            Resource::Steps => "steps",
+            Resource::Time => "time",
+            Resource::Output => "output",
+            Resource::Cells => "cells",
+        }
+    }
+}
 
-                            // TODO: would be nice to have a Hint: message too in compiler warnings.
-                            let message = format!(
-                                "This multiply loop tried to access cell {} \
-                                 (offset {} from current cell {})",
-                                dest_ptr, *cell_offset, cell_ptr
-                            );
+/// The result of running a whole program with `interpret`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Output {
+    /// Everything the program wrote before it stopped.
+    pub bytes: Vec<u8>,
+    pub status: ExitStatus,
+    /// The number of steps executed.
+    pub steps: u64,
+}
 
-                            return Outcome::RuntimeError(Warning { message, position });
-                        }
-                        if dest_ptr as usize >= state.cells.len() {
-                            state.start_instr = Some(&instrs[instr_idx]);
-                            return Outcome::RuntimeError(Warning {
-                                message: format!(
-                                    "This multiply loop tried to access cell {} (the \
-                                     highest cell is {})",
-                                    dest_ptr,
-                                    state.cells.len() - 1
-                                ),
-                                position,
-                            });
-                        }
+/// Run `instrs` as the compiled program would, with `input` as
+/// stdin. Reads past the end of `input` store -1, as `getchar` does
+/// at EOF, so nothing is left for runtime.
+pub fn interpret(instrs: &[AstNode], input: &[u8]) -> Output {
+    interpret_with_limits(
+        instrs,
+        input,
+        DEFAULT_CELL_BITS,
+        EofBehaviour::default(),
+        Limits::default(),
+    )
+}
 
-                        let current_val = state.cells[dest_ptr as usize];
-                        state.cells[dest_ptr as usize] = current_val + cell_value * (*factor);
-                    }
+/// As `interpret`, but with cells `cell_bits` wide and reads past
+/// the end of `input` behaving as `eof` says, stopping early if we
+/// reach any of `limits`. This is suitable for running
+/// untrusted programs, as steps, time, output and cells are all
+/// capped.
+pub fn interpret_with_limits(
+    instrs: &[AstNode],
+    input: &[u8],
+    cell_bits: u32,
+    eof: EofBehaviour,
+    limits: Limits,
+) -> Output {
+    let mut state = if limits.growable_tape {
+        ExecutionState::with_tape(
+            instrs,
+            Tape::Growable {
+                max_cells: limits.max_cells.max(1),
+            },
+        )
+    } else {
+        ExecutionState::initial(instrs)
+    };
+    state.input = input.iter().copied().collect();
+    let capped_tape = limits.growable_tape || state.cells.len() > limits.max_cells;
+    state.cells.truncate(limits.max_cells.max(1));
+    let options = Options {
+        dummy_read_value: None,
+        cell_bits,
+        deadline: limits.deadline,
+        max_output: limits.max_output,
+        split_branches: false,
+        eof_reads: true,
+        eof,
+        capped_tape,
+        interrupt: None,
+    };
+    let outcome = execute_inner(instrs, &mut state, limits.steps, &options, None, None);
 
-                    // Finally, zero the cell we used.
-                    state.cells[cell_ptr] = Wrapping(0);
-                }
+    let status = match outcome {
+        Outcome::Completed(_) => ExitStatus::Finished,
+        Outcome::RuntimeError(warning) => ExitStatus::RuntimeError(warning),
+        Outcome::OutOfSteps => ExitStatus::LimitExceeded(Resource::Steps),
+        Outcome::OutOfTime => ExitStatus::LimitExceeded(Resource::Time),
+        Outcome::OutputTooLarge => ExitStatus::LimitExceeded(Resource::Output),
+        Outcome::OutOfCells => ExitStatus::LimitExceeded(Resource::Cells),
+        Outcome::ReachedRuntimeValue | Outcome::Interrupted => {
+            unreachable!("Interpreting never waits for input and can't be interrupted")
+        }
+    };
+    Output {
+        bytes: state.outputs.iter().map(|&byte| byte as u8).collect(),
+        status,
+        steps: state.steps,
+    }
+}
+
+/// Finish running `instrs` from where speculative execution left
+/// `state`, as the compiled program would. We do the deferred reads
+/// with `input`, set the cells that depend on which way deferred
+/// branches went, then run the residual program from
+/// `state.start_instr`.
+#[cfg(test)]
+pub fn interpret_residual(
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    input: &[u8],
+    cell_bits: u32,
+    eof: EofBehaviour,
+    steps: u64,
+) -> Output {
+    let residual = residualise(instrs, state);
+    let mut outputs = residual.prefix_output.to_vec();
+    let mut input: VecDeque<u8> = input.iter().copied().collect();
+
+    // Reads only store a function of the byte read, so deferred
+    // reads never leave the cell unchanged at EOF.
+    let mut cells = state.cells.clone();
+    let mut read_values = vec![];
+    for (i, read) in state.reads.iter().enumerate() {
+        let mut last_byte = None;
+        for _ in 0..read.count {
+            last_byte = input.pop_front();
+        }
+        let value = match (last_byte, eof) {
+            (Some(byte), _) => Wrapping(i32::from(byte)),
+            (None, EofBehaviour::Zero) => Wrapping(0),
+            (None, _) => Wrapping(-1),
+        };
+        read_values.push(value);
+        if let Some(cell) = read.cell {
+            cells[cell] = wrap_cell(value * read.scale + state.cells[cell], cell_bits);
+        }
+
+        let next_outputs_start = match state.reads.get(i + 1) {
+            Some(next_read) => next_read.outputs_before,
+            None => state.outputs.len(),
+        };
+        outputs.extend_from_slice(&state.outputs[read.outputs_before..next_outputs_start]);
+    }
+    for branch in &state.branches {
+        let condition = wrap_cell(
+            read_values[branch.read] * branch.scale + branch.offset,
+            cell_bits,
+        );
+        for &(cell, entered_value) in &branch.cells {
+            cells[cell] = if condition.0 != 0 {
+                entered_value
+            } else {
+                state.cells[cell]
+            };
+        }
+    }
+
+    let mut runtime_state = ExecutionState {
+        cells,
+        input,
+        outputs,
+        reads: vec![],
+        branches: vec![],
+        steps: 0,
+        loop_counts: HashMap::new(),
+        ..state.clone()
+    };
+    let program = flatten(residual.instrs);
+    let mut progress = Progress::new(&program, steps);
+    progress.pc = match state.start_instr {
+        Some(start_instr) => program
+            .iter()
+            .position(|instr| std::ptr::eq(instr.node, start_instr))
+            .expect("start_instr should be in the residual program"),
+        None => program.len(),
+    };
+    let options = Options {
+        dummy_read_value: None,
+        cell_bits,
+        deadline: None,
+        max_output: usize::MAX,
+        split_branches: false,
+        eof_reads: true,
+        eof,
+        capped_tape: false,
+        interrupt: None,
+    };
+    let outcome = run(
+        &program,
+        &mut runtime_state,
+        &mut progress,
+        &options,
+        None,
+        None,
+    );
+
+    let status = match outcome {
+        Outcome::Completed(_) => ExitStatus::Finished,
+        Outcome::RuntimeError(warning) => ExitStatus::RuntimeError(warning),
+        _ => ExitStatus::LimitExceeded(Resource::Steps),
+    };
+    Output {
+        bytes: runtime_state.outputs.iter().map(|&b| b as u8).collect(),
+        status,
+        steps: steps - progress.steps_left,
+    }
+}
+
+/// Settings that don't change during execution.
+#[derive(Clone, Copy)]
+struct Options {
+    dummy_read_value: Option<i8>,
+    /// The width of cells. Arithmetic wraps at this width, as it
+    /// does at runtime.
+    cell_bits: u32,
+    deadline: Option<Instant>,
+    max_output: usize,
+    /// Explore loops whose condition depends on a read both ways,
+    /// rather than stopping.
+    split_branches: bool,
+    /// Once known input runs out, reads behave as at EOF, rather
+    /// than waiting for input.
+    eof_reads: bool,
+    /// What reads do at EOF, both with `eof_reads` and at runtime.
+    eof: EofBehaviour,
+    /// The tape has fewer cells than the program may use, so moving
+    /// past the last cell means we're out of cells rather than that
+    /// the program is wrong.
+    capped_tape: bool,
+    /// Stop early once this is set, such as by `interrupt`.
+    interrupt: Option<&'static AtomicBool>,
+}
+
+/// An instruction in the flattened program we execute at compile
+/// time. Loops become a pair of jumps, so we never need to recurse.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Op<'a> {
+    Increment {
+        amount: Cell,
+        offset: isize,
+    },
+    Set {
+        amount: Cell,
+        offset: isize,
+    },
+    PointerIncrement {
+        amount: isize,
+    },
+    MultiplyMove {
+        changes: Vec<(isize, Cell)>,
+        adds: Vec<(isize, Cell)>,
+    },
+    Write,
+    WriteString(&'a [u8]),
+    Read {
+        count: usize,
+    },
+    /// If the current cell is zero, jump to the instruction after
+    /// the matching `LoopEnd`.
+    LoopStart {
+        end: usize,
+    },
+    /// Jump back to the matching `LoopStart`.
+    LoopEnd {
+        start: usize,
+        effect: LoopEffect,
+    },
+    /// A loop whose body is just `step`, a pointer increment by
+    /// `amount`. We run these loops in a single instruction, as
+    /// they're very common.
+    Scan {
+        amount: isize,
+        step: &'a AstNode,
+    },
+}
+
+/// A flattened instruction, along with the IR node it came from, so
+/// we can tell runtime execution where to start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Instruction<'a> {
+    op: Op<'a>,
+    node: &'a AstNode,
+}
+
+/// Flatten `instrs` into a sequence of instructions with explicit
+/// jump targets.
+fn flatten<'a>(instrs: &'a [AstNode]) -> Vec<Instruction<'a>> {
+    let mut program = vec![];
+    flatten_into(instrs, &mut program);
+    program
+}
 
-                instr_idx += 1;
+fn flatten_into<'a>(instrs: &'a [AstNode], program: &mut Vec<Instruction<'a>>) {
+    for node in instrs {
+        let op = match *node {
+            Increment { amount, offset, .. } => Op::Increment { amount, offset },
+            Set { amount, offset, .. } => Op::Set { amount, offset },
+            PointerIncrement { amount, .. } => Op::PointerIncrement { amount },
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => Op::MultiplyMove {
+                changes: changes.iter().map(|(k, v)| (*k, *v)).collect(),
+                adds: adds.iter().map(|(k, v)| (*k, *v)).collect(),
+            },
+            Write { .. } => Op::Write,
+            WriteString { ref bytes, .. } => Op::WriteString(bytes),
+            Read { .. } => Op::Read { count: 1 },
+            SkipRead { count, .. } => Op::Read { count },
+            Loop { ref body, .. } if is_scan(body) => match body[0] {
+                PointerIncrement { amount, .. } => Op::Scan {
+                    amount,
+                    step: &body[0],
+                },
+                _ => unreachable!(),
+            },
+            Loop { ref body, .. } => {
+                let start = program.len();
+                // We don't know where the loop ends until we've
+                // flattened its body.
+                program.push(Instruction {
+                    op: Op::LoopStart { end: 0 },
+                    node,
+                });
+                flatten_into(body, program);
+                let end = program.len();
+                program[start].op = Op::LoopStart { end };
+                Op::LoopEnd {
+                    start,
+                    effect: loop_effect(body),
+                }
             }
-            Write { .. } => {
-                let cell_value = state.cells[state.cell_ptr as usize];
-                state.outputs.push(cell_value.0);
-                instr_idx += 1;
+        };
+        program.push(Instruction { op, node });
+    }
+}
+
+/// Is `body` the body of a loop that just moves the pointer,
+/// such as `[>>]`?
+fn is_scan(body: &[AstNode]) -> bool {
+    match *body {
+        [PointerIncrement { amount, .. }] => amount != 0,
+        _ => false,
+    }
+}
+
+/// What a loop body does to the cells, as far as we can tell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LoopEffect {
+    /// The body is too complex for us to reason about.
+    Unknown,
+    /// The body never changes the loop's condition cell, so the loop
+    /// never terminates once entered.
+    KeepsCondition,
+    /// The body modifies the cells at these offsets from the
+    /// condition cell, and nothing else.
+    Modifies {
+        modified: Vec<isize>,
+        /// The amount added to the condition cell by every
+        /// iteration, if it's always the same.
+        condition_change: Option<Cell>,
+    },
+}
+
+/// Work out the effect of the loop body `body`. We only consider
+/// bodies without loops or reads that leave the pointer where it
+/// started, so every iteration modifies the same cells.
+fn loop_effect(body: &[AstNode]) -> LoopEffect {
+    let mut modified = vec![];
+    let mut condition_change = Some(Wrapping(0));
+    let mut cell_ptr = 0;
+    for instr in body {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                modified.push(cell_ptr + offset);
+                if cell_ptr + offset == 0 {
+                    condition_change = condition_change.map(|change| change + amount);
+                }
             }
-            Read { .. } => {
-                if let Some(read_value) = dummy_read_value {
-                    // If we're given a dummy value to use for the
-                    // read, pretend that we've read that value.
-                    state.cells[state.cell_ptr as usize] = Wrapping(read_value);
-                    instr_idx += 1
-                } else {
-                    // Otherwise, we cannot proceed at compile time,
-                    // so ensure runtime execution starts from here.
-                    state.start_instr = Some(&instrs[instr_idx]);
-                    return Outcome::ReachedRuntimeValue;
+            Set { offset, .. } => {
+                modified.push(cell_ptr + offset);
+                if cell_ptr + offset == 0 {
+                    condition_change = None;
                 }
             }
-            Loop { ref body, .. } => {
-                if state.cells[state.cell_ptr as usize].0 == 0 {
-                    // Step over the loop because the current cell is
-                    // zero.
-                    instr_idx += 1;
-                } else {
-                    // Execute the loop body.
-                    let loop_outcome =
-                        execute_with_state(body, state, steps_left, dummy_read_value);
-                    match loop_outcome {
-                        Outcome::Completed(remaining_steps) => {
-                            // We've run several steps during the loop
-                            // body, so ensure steps_left reflects
-                            // that.
-                            steps_left = remaining_steps;
-                        }
-                        Outcome::ReachedRuntimeValue
-                        | Outcome::RuntimeError(..)
-                        | Outcome::OutOfSteps => {
-                            // If we ran out of steps after a complete
-                            // loop iteration, start_instr will still
-                            // be None, so we set it to the current loop.
-                            if state.start_instr == None {
-                                state.start_instr = Some(&instrs[instr_idx]);
-                            }
-                            return loop_outcome;
-                        }
-                    }
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
+                let start = modified.len();
+                modified.push(cell_ptr);
+                modified.extend(
+                    changes
+                        .keys()
+                        .chain(adds.keys())
+                        .map(|offset| cell_ptr + offset),
+                );
+                if modified[start..].contains(&0) {
+                    condition_change = None;
                 }
             }
+            PointerIncrement { amount, .. } => cell_ptr += amount,
+            Write { .. } | WriteString { .. } => {}
+            Read { .. } | SkipRead { .. } | Loop { .. } => return LoopEffect::Unknown,
         }
+    }
 
-        steps_left -= 1;
+    if cell_ptr != 0 {
+        LoopEffect::Unknown
+    } else if !modified.contains(&0) {
+        LoopEffect::KeepsCondition
+    } else {
+        modified.sort_unstable();
+        modified.dedup();
+        LoopEffect::Modifies {
+            modified,
+            condition_change,
+        }
     }
+}
+
+/// Can a cell holding `value` ever reach zero if we repeatedly add
+/// `change`? With wrapping arithmetic, adding `change` never alters
+/// the bits below its lowest set bit, so those bits in `value` must
+/// already be zero. For example, adding 2 to an odd cell never
+/// reaches zero.
+fn reaches_zero(value: Cell, change: Cell, cell_bits: u32) -> bool {
+    wrap_cell(value, cell_bits).0.trailing_zeros()
+        >= wrap_cell(change, cell_bits).0.trailing_zeros()
+}
 
-    // If we've run out of steps, runtime execution should start
-    // from the next instruction.
-    if steps_left == 0 {
-        // If the next instruction is in the current loop, use that.
-        if instr_idx < instrs.len() {
-            state.start_instr = Some(&instrs[instr_idx]);
+/// Is the cell at `cell_index` unknown at compile time?
+fn is_unknown(unknown_cells: &Option<&mut HashMap<usize, usize>>, cell_index: isize) -> bool {
+    match unknown_cells {
+        Some(unknown_cells) => {
+            !unknown_cells.is_empty()
+                && cell_index >= 0
+                && unknown_cells.contains_key(&(cell_index as usize))
         }
-        // Otherwise, we've run out of steps after executing a
-        // complete loop iteration. We'll set the start instruction as
-        // the loop.
+        None => false,
+    }
+}
 
-        Outcome::OutOfSteps
-    } else {
-        Outcome::Completed(steps_left)
+/// The values a read can store: a byte, or -1 at the end of input.
+const READ_MIN: i64 = -1;
+const READ_MAX: i64 = 255;
+
+/// What we know about the value of an unknown cell, which holds
+/// `scale * byte + offset` for a byte read at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ValueFacts {
+    /// The value is in `[min, max]`, before wrapping to the cell
+    /// size.
+    min: i64,
+    max: i64,
+    /// The value is congruent to `residue` modulo `modulus`, which
+    /// is a power of two no larger than the number of cell values.
+    modulus: i64,
+    residue: i64,
+}
+
+impl ValueFacts {
+    fn new(scale: Cell, offset: Cell, cell_bits: u32) -> Self {
+        let scale = i64::from(wrap_cell(scale, cell_bits).0);
+        let offset = i64::from(wrap_cell(offset, cell_bits).0);
+        let (min, max) = if scale >= 0 {
+            (scale * READ_MIN + offset, scale * READ_MAX + offset)
+        } else {
+            (scale * READ_MAX + offset, scale * READ_MIN + offset)
+        };
+        // Multiplying by 2^k leaves the low k bits of the offset
+        // unchanged, whatever was read.
+        let modulus = 1 << scale.trailing_zeros().min(cell_bits);
+        ValueFacts {
+            min,
+            max,
+            modulus,
+            residue: offset.rem_euclid(modulus),
+        }
+    }
+
+    /// Could the value be zero once wrapped to `cell_bits` bits?
+    fn may_be_zero(&self, cell_bits: u32) -> bool {
+        let cell_modulus = 1i64 << cell_bits;
+        // The smallest value in range that wraps to zero.
+        let first_zero = -(-self.min).div_euclid(cell_modulus) * cell_modulus;
+        first_zero <= self.max && self.residue == 0
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use pretty_assertions::assert_eq;
-    use quickcheck::quickcheck;
-    use std::collections::HashMap;
+/// Is the cell at `cell_index` nonzero? Returns None if that depends
+/// on input we don't know yet.
+fn is_nonzero(
+    state: &ExecutionState,
+    unknown_cells: &Option<&mut HashMap<usize, usize>>,
+    cell_index: isize,
+    cell_bits: u32,
+) -> Option<bool> {
+    let value = state.cells[cell_index as usize];
+    let read_idx = match unknown_cells {
+        Some(unknown_cells) if !unknown_cells.is_empty() => {
+            unknown_cells.get(&(cell_index as usize)).copied()
+        }
+        _ => None,
+    };
+    if let Some(read_idx) = read_idx {
+        let facts = ValueFacts::new(state.reads[read_idx].scale, value, cell_bits);
+        if facts.may_be_zero(cell_bits) {
+            None
+        } else {
+            Some(true)
+        }
+    } else if let Some(entered_value) = branch_value(state, cell_index as usize) {
+        // We know the answer if it's the same either way.
+        if (value.0 != 0) == (entered_value.0 != 0) {
+            Some(value.0 != 0)
+        } else {
+            None
+        }
+    } else {
+        Some(value.0 != 0)
+    }
+}
 
-    use super::*;
-    use crate::bfir::{parse, Position};
-    use crate::bounds::MAX_CELL_INDEX;
+/// If the cell at `cell_index` depends on a branch in
+/// `state.branches`, return its value if the loop was entered.
+fn branch_value(state: &ExecutionState, cell_index: usize) -> Option<Cell> {
+    state
+        .branches
+        .iter()
+        .flat_map(|branch| &branch.cells)
+        .find(|(index, _)| *index == cell_index)
+        .map(|(_, value)| *value)
+}
 
-    /// We can't evaluate outputs of runtime values at compile time.
-    #[test]
-    fn cant_evaluate_inputs() {
-        let instrs = parse(",.").unwrap();
-        let final_state = execute(&instrs, max_steps()).0;
+/// Does the value of the cell at `cell_index` depend on input?
+fn is_runtime_value(
+    state: &ExecutionState,
+    unknown_cells: &Option<&mut HashMap<usize, usize>>,
+    cell_index: isize,
+) -> bool {
+    is_unknown(unknown_cells, cell_index)
+        || (!state.branches.is_empty()
+            && cell_index >= 0
+            && branch_value(state, cell_index as usize).is_some())
+}
 
-        assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: Some(&instrs[0]),
-                cells: vec![Wrapping(0)],
-                cell_ptr: 0,
-                outputs: vec![],
+/// Add `amount` to the cell at `cell_index`, whichever way any
+/// branch it depends on went.
+fn add_to_cell(state: &mut ExecutionState, cell_index: usize, amount: Cell, cell_bits: u32) {
+    state.cells[cell_index] = wrap_cell(state.cells[cell_index] + amount, cell_bits);
+    for branch in &mut state.branches {
+        for (index, value) in &mut branch.cells {
+            if *index == cell_index {
+                *value = wrap_cell(*value + amount, cell_bits);
             }
-        );
+        }
     }
+}
 
-    #[test]
-    fn increment_executed() {
-        let instrs = parse("+").unwrap();
-        let final_state = execute(&instrs, max_steps()).0;
+/// The cell at `cell_index` has been given a new value, so it no
+/// longer depends on any branch.
+fn forget_branch(state: &mut ExecutionState, cell_index: usize) {
+    if state.branches.is_empty() {
+        return;
+    }
+    for branch in &mut state.branches {
+        branch.cells.retain(|(index, _)| *index != cell_index);
+    }
+    state.branches.retain(|branch| !branch.cells.is_empty());
+}
 
-        assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(1)],
-                cell_ptr: 0,
-                outputs: vec![],
-            }
-        );
+/// Can the multiply move `changes` from the unknown cell at
+/// `cell_ptr` run at compile time? The value read can only end up in
+/// one cell, so it must be moved to at most one other cell, which
+/// must be known.
+fn can_move_unknown(
+    changes: &[(isize, Cell)],
+    state: &ExecutionState,
+    unknown_cells: &Option<&mut HashMap<usize, usize>>,
+) -> bool {
+    match changes.len() {
+        0 => true,
+        1 => changes.iter().all(|(offset, _)| {
+            let dest_ptr = state.cell_ptr + offset;
+            dest_ptr >= 0
+                && (dest_ptr as usize) < state.cells.len()
+                && !is_runtime_value(state, unknown_cells, dest_ptr)
+        }),
+        _ => false,
     }
+}
 
-    #[test]
-    fn multiply_move_executed() {
-        let mut changes = HashMap::new();
-        changes.insert(1, Wrapping(2));
-        changes.insert(3, Wrapping(3));
+/// Run the multiply move `changes` from the unknown cell at
+/// `cell_ptr`, which `can_move_unknown` has checked is possible.
+fn move_unknown(
+    changes: &[(isize, Cell)],
+    cell_ptr: usize,
+    state: &mut ExecutionState,
+    unknown_cells: &mut HashMap<usize, usize>,
+    cell_bits: u32,
+) {
+    let read_idx = unknown_cells.remove(&cell_ptr).unwrap();
+    let offset = state.cells[cell_ptr];
+    state.cells[cell_ptr] = Wrapping(0);
+    state.reads[read_idx].cell = None;
 
-        let instrs = [
-            // Initial cells: [2, 1, 0, 0]
-            Increment {
+    if let Some(&(cell_offset, factor)) = changes.first() {
+        let dest_ptr = (cell_ptr as isize + cell_offset) as usize;
+        state.cells[dest_ptr] = wrap_cell(state.cells[dest_ptr] + offset * factor, cell_bits);
+
+        let scale = wrap_cell(state.reads[read_idx].scale * factor, cell_bits);
+        // If the scale wraps to zero, the value read no longer
+        // matters.
+        if scale.0 != 0 {
+            unknown_cells.insert(dest_ptr, read_idx);
+            state.reads[read_idx].cell = Some(dest_ptr);
+            state.reads[read_idx].scale = scale;
+        }
+    }
+}
+
+/// The most steps we spend executing a loop whose condition depends
+/// on a read as if it was entered.
+const MAX_BRANCH_STEPS: u64 = 10_000;
+
+/// Execute `loop_node`, whose condition depends on the read in the
+/// current cell, both as if it was entered and as if it was skipped.
+/// If both leave the pointer, output and unknown cells the same, we
+/// merge them into `state`, recording the known cells that differ
+/// in `state.branches`, and return true. Otherwise `state` is left
+/// unchanged.
+fn split_loop<'a>(
+    loop_node: &'a AstNode,
+    state: &mut ExecutionState<'a>,
+    unknown_cells: Option<&mut HashMap<usize, usize>>,
+    steps_left: &mut u64,
+    options: &Options,
+) -> bool {
+    let cell_ptr = state.cell_ptr as usize;
+    let (unknown_cells, read_idx) = match unknown_cells {
+        Some(unknown_cells) => match unknown_cells.get(&cell_ptr) {
+            Some(&read_idx) => (unknown_cells, read_idx),
+            None => return false,
+        },
+        None => return false,
+    };
+    let body = match *loop_node {
+        Loop { ref body, .. } => body,
+        _ => return false,
+    };
+    // Leave a step for the loop condition we're checking.
+    let budget = (*steps_left - 1).min(MAX_BRANCH_STEPS);
+    if budget == 0 {
+        return false;
+    }
+
+    // If the loop is entered, we run its body once, then the whole
+    // loop. We don't split again, so the cost stays bounded.
+    let mut program = vec![];
+    flatten_into(body, &mut program);
+    flatten_into(std::slice::from_ref(loop_node), &mut program);
+    let mut entered = state.clone();
+    let mut entered_unknown_cells = unknown_cells.clone();
+    let mut progress = Progress::new(&program, budget);
+    let outcome = run(
+        &program,
+        &mut entered,
+        &mut progress,
+        &Options {
+            split_branches: false,
+            ..*options
+        },
+        Some(&mut entered_unknown_cells),
+        None,
+    );
+    if !matches!(outcome, Outcome::Completed(_)) {
+        return false;
+    }
+
+    // If the loop is skipped, the current cell is zero. The loop only
+    // finishes when it's zero too, so either way the value read is
+    // no longer needed in the cell.
+    let mut skipped_unknown_cells = unknown_cells.clone();
+    skipped_unknown_cells.remove(&cell_ptr);
+    let mut skipped_reads = state.reads.clone();
+    skipped_reads[read_idx].cell = None;
+    if entered.cell_ptr != state.cell_ptr
+        || entered.cells.len() != state.cells.len()
+        || entered.outputs != state.outputs
+        || entered.input != state.input
+        || entered.reads != skipped_reads
+        || entered.branches != state.branches
+        || entered_unknown_cells != skipped_unknown_cells
+    {
+        return false;
+    }
+
+    let mut cells = vec![];
+    for (index, (&entered_value, &skipped_value)) in
+        entered.cells.iter().zip(&state.cells).enumerate()
+    {
+        let skipped_value = if index == cell_ptr {
+            Wrapping(0)
+        } else {
+            skipped_value
+        };
+        if entered_value != skipped_value {
+            // We can only choose between two known values.
+            if skipped_unknown_cells.contains_key(&index) || branch_value(state, index).is_some() {
+                return false;
+            }
+            cells.push((index, entered_value));
+        }
+    }
+
+    // We only count the steps if we use the result. Otherwise we
+    // stop here, so we can't waste more than one budget.
+    *steps_left -= budget - progress.steps_left;
+    let branch = DeferredBranch {
+        read: read_idx,
+        scale: state.reads[read_idx].scale,
+        offset: state.cells[cell_ptr],
+        cells,
+    };
+    state.cells[cell_ptr] = Wrapping(0);
+    state.reads[read_idx].cell = None;
+    unknown_cells.remove(&cell_ptr);
+    if !branch.cells.is_empty() {
+        state.branches.push(branch);
+    }
+    true
+}
+
+/// Execute `instrs`, updating `state`. If `unknown_cells` is given,
+/// we continue past reads, treating the cell read as unknown until
+/// it's overwritten. `unknown_cells` maps each unknown cell to the
+/// index of the read in `state.reads` that set it.
+///
+/// Unknown cells hold the value read multiplied by the read's
+/// `scale`, plus their value in `state.cells`, so we can continue
+/// past arithmetic on them. We stop as soon as the exact value
+/// affects the program's behaviour. Loops are still entered if we
+/// can prove an unknown cell is nonzero, such as after reading a
+/// digit and adding 1 with wide cells, or after doubling a read and
+/// adding an odd number.
+fn execute_inner<'a>(
+    instrs: &'a [AstNode],
+    state: &mut ExecutionState<'a>,
+    steps: u64,
+    options: &Options,
+    unknown_cells: Option<&mut HashMap<usize, usize>>,
+    mut trace: Option<&mut Trace>,
+) -> Outcome {
+    let program = flatten(instrs);
+    let mut progress = Progress::new(&program, steps);
+    let outcome = run(
+        &program,
+        state,
+        &mut progress,
+        options,
+        unknown_cells,
+        trace.as_deref_mut(),
+    );
+    state.steps += steps - progress.steps_left;
+    record_loop_counts(&program, &mut progress.loop_counts, state);
+
+    if let Some(trace) = trace {
+        match outcome {
+            // We stopped without executing the last instruction.
+            Outcome::ReachedRuntimeValue | Outcome::RuntimeError(_) | Outcome::OutputTooLarge => {
+                trace.discard_pending()
+            }
+            _ => trace.finish_pending(state, options.cell_bits),
+        }
+    }
+
+    outcome
+}
+
+/// How far we've got executing a flattened program, so we can
+/// resume it.
+struct Progress {
+    /// The index of the next instruction to execute.
+    pc: usize,
+    steps_left: u64,
+    /// We also stop after executing this many instructions. Unlike
+    /// steps, this counts entering a loop.
+    instrs_left: u64,
+    /// The condition checks of each loop, at the index of its
+    /// `LoopStart` or `Scan`.
+    loop_counts: Vec<LoopCounts>,
+    last_iteration: LastIteration,
+}
+
+impl Progress {
+    fn new(program: &[Instruction], steps: u64) -> Self {
+        Progress {
+            pc: 0,
+            steps_left: steps,
+            instrs_left: u64::MAX,
+            loop_counts: vec![LoopCounts::default(); program.len()],
+            last_iteration: LastIteration::default(),
+        }
+    }
+}
+
+/// Add the counts in `loop_counts` to `state.loop_counts`, resetting
+/// them to zero.
+fn record_loop_counts(
+    program: &[Instruction],
+    loop_counts: &mut [LoopCounts],
+    state: &mut ExecutionState,
+) {
+    for (instr, counts) in program.iter().zip(loop_counts) {
+        if *counts != LoopCounts::default() {
+            let total = state
+                .loop_counts
+                .entry(instr.node as *const AstNode)
+                .or_default();
+            total.iterations += counts.iterations;
+            total.exits += counts.exits;
+            *counts = LoopCounts::default();
+        }
+    }
+}
+
+/// Run the flattened `program` from `progress.pc`, until we run out
+/// of steps or instructions in `progress`, finish, or can't
+/// continue.
+fn run<'a>(
+    program: &[Instruction<'a>],
+    state: &mut ExecutionState<'a>,
+    progress: &mut Progress,
+    options: &Options,
+    mut unknown_cells: Option<&mut HashMap<usize, usize>>,
+    mut trace: Option<&mut Trace>,
+) -> Outcome {
+    while progress.pc < program.len() && progress.steps_left > 0 && progress.instrs_left > 0 {
+        let instr = &program[progress.pc];
+
+        if progress.steps_left & (DEADLINE_CHECK_INTERVAL - 1) == 0 {
+            let interrupted = options
+                .interrupt
+                .is_some_and(|interrupt| interrupt.load(Ordering::Relaxed));
+            let outcome = if interrupted {
+                Some(Outcome::Interrupted)
+            } else {
+                match options.deadline {
+                    Some(deadline) if Instant::now() >= deadline => Some(Outcome::OutOfTime),
+                    _ => None,
+                }
+            };
+            if let Some(outcome) = outcome {
+                // Runtime execution should start from the
+                // instruction we haven't executed yet.
+                state.start_instr = Some(instr.node);
+                return outcome;
+            }
+        }
+
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.record(progress.pc, instr, state, options.cell_bits);
+        }
+
+        let cell_ptr = state.cell_ptr as usize;
+        progress.instrs_left -= 1;
+
+        match instr.op {
+            Op::Write if is_runtime_value(state, &unknown_cells, state.cell_ptr) => {
+                // We can't write a value we don't know yet.
+                state.start_instr = Some(instr.node);
+                return Outcome::ReachedRuntimeValue;
+            }
+            Op::LoopStart { .. } | Op::Scan { .. }
+                if is_nonzero(state, &unknown_cells, state.cell_ptr, options.cell_bits)
+                    .is_none() =>
+            {
+                // We can't branch on a value we don't know yet, but
+                // we may be able to take both branches.
+                match instr.op {
+                    Op::LoopStart { end }
+                        if options.split_branches
+                            && split_loop(
+                                instr.node,
+                                state,
+                                unknown_cells.as_deref_mut(),
+                                &mut progress.steps_left,
+                                options,
+                            ) =>
+                    {
+                        progress.pc = end + 1;
+                    }
+                    _ => {
+                        state.start_instr = Some(instr.node);
+                        return Outcome::ReachedRuntimeValue;
+                    }
+                }
+            }
+            Op::MultiplyMove { ref changes, .. }
+                if (is_unknown(&unknown_cells, state.cell_ptr)
+                    && !can_move_unknown(changes, state, &unknown_cells))
+                    || (!state.branches.is_empty() && branch_value(state, cell_ptr).is_some()) =>
+            {
+                state.start_instr = Some(instr.node);
+                return Outcome::ReachedRuntimeValue;
+            }
+            Op::Increment { offset, .. } | Op::Set { offset, .. }
+                if !state.on_tape(state.cell_ptr + offset) =>
+            {
+                // The tape is smaller than the program needs.
+                let target_cell_ptr = state.cell_ptr + offset;
+                state.start_instr = Some(instr.node);
+                if options.capped_tape && target_cell_ptr >= 0 {
+                    return Outcome::OutOfCells;
+                }
+                return Outcome::RuntimeError(Warning {
+                    lint: Lint::OutOfBounds,
+                    message: format!(
+                        "This instruction accesses cell {}, outside the {} cells available.",
+                        target_cell_ptr,
+                        state.max_cells()
+                    ),
+                    position: get_position(instr.node),
+                    notes: vec![],
+                });
+            }
+            Op::Increment { amount, offset } => {
+                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
+                add_to_cell(state, target_cell_ptr, amount, options.cell_bits);
+                progress.pc += 1;
+            }
+            Op::Set { amount, offset } => {
+                let target_cell_ptr = (cell_ptr as isize + offset) as usize;
+                state.cells[target_cell_ptr] = wrap_cell(amount, options.cell_bits);
+                forget_branch(state, target_cell_ptr);
+                // The cell is known again, so the value read into it
+                // is never used.
+                if let Some(read_idx) = unknown_cells
+                    .as_deref_mut()
+                    .and_then(|unknown_cells| unknown_cells.remove(&target_cell_ptr))
+                {
+                    state.reads[read_idx].cell = None;
+                }
+                progress.pc += 1;
+            }
+            Op::PointerIncrement { amount } => {
+                let new_cell_ptr = state.cell_ptr + amount;
+                if !state.on_tape(new_cell_ptr) {
+                    // We can't execute this instruction, so we'll
+                    // execute it at runtime (it'll probably be an
+                    // error).
+                    state.start_instr = Some(instr.node);
+                    if options.capped_tape && new_cell_ptr >= 0 {
+                        return Outcome::OutOfCells;
+                    }
+                    return Outcome::RuntimeError(pointer_out_of_bounds(
+                        new_cell_ptr,
+                        state.max_cells(),
+                        instr.node,
+                    ));
+                } else {
+                    state.cell_ptr = new_cell_ptr;
+                    progress.pc += 1;
+                }
+            }
+            Op::MultiplyMove {
+                ref changes,
+                ref adds,
+            } => {
+                let cell_value = state.cells[cell_ptr];
+                let position = get_position(instr.node);
+
+                // Check the cells we add to before modifying anything,
+                // so runtime execution can restart from this
+                // instruction.
+                for (cell_offset, _) in adds {
+                    let dest_ptr = cell_ptr as isize + *cell_offset;
+                    if !state.on_tape(dest_ptr) {
+                        state.start_instr = Some(instr.node);
+                        if options.capped_tape && dest_ptr >= 0 {
+                            return Outcome::OutOfCells;
+                        }
+                        return Outcome::RuntimeError(Warning {
+                            lint: Lint::OutOfBounds,
+                            message: format!(
+                                "This multiply loop tried to access cell {} (offset {} \
+                                 from current cell {})",
+                                dest_ptr, *cell_offset, cell_ptr
+                            ),
+                            position,
+                            notes: vec![],
+                        });
+                    }
+                }
+
+                if let Some(unknown_cells) = unknown_cells
+                    .as_deref_mut()
+                    .filter(|unknown_cells| unknown_cells.contains_key(&cell_ptr))
+                {
+                    move_unknown(changes, cell_ptr, state, unknown_cells, options.cell_bits);
+                } else if cell_value.0 != 0 {
+                    // We will multiply by the current cell value.
+
+                    for (cell_offset, factor) in changes {
+                        let dest_ptr = cell_ptr as isize + *cell_offset;
+                        if dest_ptr < 0 {
+                            // Tried to access a cell before cell #0.
+                            state.start_instr = Some(instr.node);
+
+                            // TODO: would be nice to have a Hint: message too in compiler warnings.
+                            let message = format!(
+                                "This multiply loop tried to access cell {} \
+                                 (offset {} from current cell {})",
+                                dest_ptr, *cell_offset, cell_ptr
+                            );
+
+                            return Outcome::RuntimeError(Warning {
+                                lint: Lint::OutOfBounds,
+                                message,
+                                position,
+                                notes: vec![],
+                            });
+                        }
+                        if !state.on_tape(dest_ptr) {
+                            state.start_instr = Some(instr.node);
+                            if options.capped_tape {
+                                return Outcome::OutOfCells;
+                            }
+                            return Outcome::RuntimeError(Warning {
+                                lint: Lint::OutOfBounds,
+                                message: format!(
+                                    "This multiply loop tried to access cell {} (the \
+                                     highest cell is {})",
+                                    dest_ptr,
+                                    state.max_cells() - 1
+                                ),
+                                position,
+                                notes: vec![],
+                            });
+                        }
+
+                        add_to_cell(
+                            state,
+                            dest_ptr as usize,
+                            cell_value * (*factor),
+                            options.cell_bits,
+                        );
+                    }
+
+                    // Finally, zero the cell we used.
+                    state.cells[cell_ptr] = Wrapping(0);
+                }
+
+                for (cell_offset, amount) in adds {
+                    let dest_ptr = (cell_ptr as isize + *cell_offset) as usize;
+                    add_to_cell(state, dest_ptr, *amount, options.cell_bits);
+                }
+
+                progress.pc += 1;
+            }
+            Op::Write | Op::WriteString(..)
+                if state.outputs.len() + output_len(instr.node) > options.max_output =>
+            {
+                state.start_instr = Some(instr.node);
+                return Outcome::OutputTooLarge;
+            }
+            Op::Write => {
+                let cell_value = state.cells[state.cell_ptr as usize];
+                state.outputs.push(cell_value.0 as i8);
+                progress.pc += 1;
+            }
+            Op::WriteString(bytes) => {
+                state.outputs.extend(bytes.iter().map(|byte| *byte as i8));
+                progress.pc += 1;
+            }
+            Op::Read { count } => {
+                forget_branch(state, cell_ptr);
+                if let Some(read_value) = options.dummy_read_value {
+                    // If we're given a dummy value to use for the
+                    // read, pretend that we've read that value.
+                    state.cells[state.cell_ptr as usize] = Wrapping(i32::from(read_value));
+                    progress.pc += 1
+                } else if state.input.len() >= count {
+                    // We know the input, so we can read it now.
+                    let byte = state.input.drain(..count).next_back().unwrap();
+                    state.cells[cell_ptr] = wrap_cell(Wrapping(i32::from(byte)), options.cell_bits);
+                    progress.pc += 1
+                } else if options.eof_reads {
+                    // The last byte read is past the end of input.
+                    let last_byte = state.input.drain(..).next_back();
+                    let value = match (options.eof, last_byte) {
+                        // Earlier reads still store what they read.
+                        (EofBehaviour::Unchanged, Some(byte)) => Some(Wrapping(i32::from(byte))),
+                        (EofBehaviour::Unchanged, None) => None,
+                        (EofBehaviour::Zero, _) => Some(Wrapping(0)),
+                        (EofBehaviour::MinusOne, _) => Some(Wrapping(-1)),
+                    };
+                    if let Some(value) = value {
+                        state.cells[cell_ptr] = wrap_cell(value, options.cell_bits);
+                    }
+                    progress.pc += 1
+                } else if let (Some(unknown_cells), false) = (
+                    unknown_cells.as_deref_mut(),
+                    // At EOF the cell keeps its value, which isn't a
+                    // function of the byte read, so we can't defer
+                    // the read.
+                    options.eof == EofBehaviour::Unchanged,
+                ) {
+                    // Leave the read for runtime, and carry on with
+                    // the cell unknown. We've run out of known input,
+                    // so the rest of it comes from stdin.
+                    let count = count - state.input.len();
+                    state.input.clear();
+                    if let Some(prev_read_idx) = unknown_cells.insert(cell_ptr, state.reads.len()) {
+                        state.reads[prev_read_idx].cell = None;
+                    }
+                    state.reads.push(DeferredRead {
+                        cell: Some(cell_ptr),
+                        count,
+                        outputs_before: state.outputs.len(),
+                        scale: Wrapping(1),
+                    });
+                    state.cells[cell_ptr] = Wrapping(0);
+                    progress.pc += 1
+                } else {
+                    // Otherwise, we cannot proceed at compile time,
+                    // so ensure runtime execution starts from here.
+                    state.start_instr = Some(instr.node);
+                    return Outcome::ReachedRuntimeValue;
+                }
+            }
+            Op::LoopStart { end } => {
+                // Unknown cells get here only if they're nonzero.
+                if state.cells[cell_ptr].0 == 0 && !is_unknown(&unknown_cells, state.cell_ptr) {
+                    // Step over the loop because the current cell is
+                    // zero.
+                    progress.loop_counts[progress.pc].exits += 1;
+                    progress.pc = end + 1;
+                } else {
+                    // Enter the loop body. Checking the loop condition
+                    // costs a step at the end of each iteration, so
+                    // entering the body is free.
+                    progress.loop_counts[progress.pc].iterations += 1;
+                    progress.pc += 1;
+                    continue;
+                }
+            }
+            Op::LoopEnd { start, ref effect } => {
+                // We only look for infinite loops when we know the
+                // current cell. Otherwise, the loop start checks it.
+                if state.cells[cell_ptr].0 != 0
+                    && !is_runtime_value(state, &unknown_cells, state.cell_ptr)
+                {
+                    let value = state.cells[cell_ptr];
+                    let message = match *effect {
+                        LoopEffect::Unknown => None,
+                        LoopEffect::KeepsCondition => Some(
+                            "This loop never terminates, because its body doesn't change the \
+                             current cell."
+                                .to_owned(),
+                        ),
+                        LoopEffect::Modifies {
+                            condition_change: Some(change),
+                            ..
+                        } => {
+                            // An iteration can only leave the cells
+                            // unchanged if the change is zero, so we
+                            // don't need to check that too.
+                            let change = wrap_cell(change, options.cell_bits);
+                            if reaches_zero(value, change, options.cell_bits) {
+                                None
+                            } else if change.0 == 0 {
+                                Some(
+                                    "This loop never terminates, because each iteration leaves \
+                                     the current cell unchanged."
+                                        .to_owned(),
+                                )
+                            } else {
+                                Some(format!(
+                                    "This loop never terminates, because each iteration changes \
+                                     the current cell by {}, so it never reaches zero from {}.",
+                                    change, value
+                                ))
+                            }
+                        }
+                        LoopEffect::Modifies {
+                            ref modified,
+                            condition_change: None,
+                        } => {
+                            // We only compare the known part of
+                            // unknown cells, so ignore them.
+                            let modifies_unknown = modified.iter().any(|offset| {
+                                is_runtime_value(state, &unknown_cells, state.cell_ptr + offset)
+                            });
+                            if !modifies_unknown
+                                && progress
+                                    .last_iteration
+                                    .repeated(modified, progress.pc, state)
+                            {
+                                Some(
+                                    "This loop never terminates, because each iteration leaves \
+                                     the cells unchanged."
+                                        .to_owned(),
+                                )
+                            } else {
+                                None
+                            }
+                        }
+                    };
+                    if let Some(message) = message {
+                        state.start_instr = Some(instr.node);
+                        return Outcome::RuntimeError(Warning {
+                            lint: Lint::InfiniteLoop,
+                            message,
+                            position: get_position(instr.node),
+                            notes: vec![],
+                        });
+                    }
+                } else {
+                    // The loop is about to finish, so iterations of
+                    // a later run of it shouldn't be compared with
+                    // this one.
+                    progress.last_iteration.at = None;
+                }
+
+                // Go back and check the loop condition again.
+                progress.pc = start;
+            }
+            Op::Scan { amount, step } => {
+                if state.cells[cell_ptr].0 == 0 && !is_unknown(&unknown_cells, state.cell_ptr) {
+                    progress.loop_counts[progress.pc].exits += 1;
+                    progress.pc += 1;
+                } else {
+                    // Run the whole loop, spending the same steps as
+                    // if we ran the pointer increment and loop
+                    // condition each time.
+                    loop {
+                        progress.loop_counts[progress.pc].iterations += 1;
+                        let new_cell_ptr = state.cell_ptr + amount;
+                        if !state.on_tape(new_cell_ptr) {
+                            state.start_instr = Some(step);
+                            if options.capped_tape && new_cell_ptr >= 0 {
+                                return Outcome::OutOfCells;
+                            }
+                            return Outcome::RuntimeError(pointer_out_of_bounds(
+                                new_cell_ptr,
+                                state.max_cells(),
+                                step,
+                            ));
+                        }
+                        state.cell_ptr = new_cell_ptr;
+
+                        // One step for the pointer increment, and one
+                        // for checking the loop condition. If we run
+                        // out, runtime execution starts from the loop.
+                        progress.steps_left -= 1;
+                        if progress.steps_left == 0 {
+                            break;
+                        }
+                        progress.steps_left -= 1;
+                        if progress.steps_left == 0 {
+                            break;
+                        }
+
+                        match is_nonzero(state, &unknown_cells, state.cell_ptr, options.cell_bits) {
+                            Some(true) => {}
+                            Some(false) => {
+                                progress.loop_counts[progress.pc].exits += 1;
+                                break;
+                            }
+                            None => {
+                                state.start_instr = Some(instr.node);
+                                return Outcome::ReachedRuntimeValue;
+                            }
+                        }
+                    }
+
+                    if progress.steps_left == 0 {
+                        continue;
+                    }
+                    // The final check of the loop condition is
+                    // counted below.
+                    progress.pc += 1;
+                }
+            }
+        }
+
+        progress.steps_left -= 1;
+    }
+
+    // If we've run out of steps or instructions, runtime execution
+    // should start from the next instruction. If that's the end of a
+    // loop body, we start from the loop itself. Running out of steps
+    // on the last instruction still finishes the program.
+    if progress.pc < program.len() {
+        state.start_instr = Some(program[progress.pc].node);
+        Outcome::OutOfSteps
+    } else {
+        Outcome::Completed(progress.steps_left)
+    }
+}
+
+/// Where a `Debugger` should pause.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Breakpoint<'a> {
+    /// Before executing this instruction. For a loop, this is
+    /// before each check of its condition.
+    Instr(&'a AstNode),
+    /// Before executing an instruction from this offset in the
+    /// source. A loop matches the offsets of its brackets.
+    Position(usize),
+}
+
+impl<'a> Breakpoint<'a> {
+    fn matches(&self, instr: &Instruction) -> bool {
+        match *self {
+            Breakpoint::Instr(node) => {
+                std::ptr::eq(node, instr.node) && !matches!(instr.op, Op::LoopEnd { .. })
+            }
+            Breakpoint::Position(offset) => match (&instr.op, get_position(instr.node)) {
+                (Op::LoopStart { .. }, Some(position)) => offset == position.start,
+                (Op::LoopEnd { .. }, Some(position)) => offset == position.end,
+                (_, Some(position)) => position.start <= offset && offset <= position.end,
+                (_, None) => false,
+            },
+        }
+    }
+}
+
+/// Why a `Debugger` paused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Pause {
+    /// We executed the instruction or steps requested.
+    Stepped,
+    /// The next instruction has a breakpoint.
+    Breakpoint,
+    /// The condition given to `run_until` held.
+    Condition,
+    /// We can't execute the next instruction. If the outcome is
+    /// `ReachedRuntimeValue`, execution continues once more input is
+    /// given.
+    Stopped(Outcome),
+}
+
+/// Executes a program an instruction at a time, so a frontend or
+/// library user can inspect the state as it goes. Unlike
+/// speculative execution, reads only use the input given, and
+/// execution stops when it runs out.
+pub struct Debugger<'a> {
+    program: Vec<Instruction<'a>>,
+    state: ExecutionState<'a>,
+    progress: Progress,
+    options: Options,
+    breakpoints: Vec<Breakpoint<'a>>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(instrs: &'a [AstNode], input: &[u8], cell_bits: u32) -> Self {
+        let program = flatten(instrs);
+        let mut state = ExecutionState::initial(instrs);
+        state.input = input.iter().copied().collect();
+        state.start_instr = instrs.first();
+        let progress = Progress::new(&program, 0);
+        Debugger {
+            program,
+            state,
+            progress,
+            options: Options {
+                dummy_read_value: None,
+                cell_bits,
+                deadline: None,
+                max_output: usize::MAX,
+                split_branches: false,
+                eof_reads: false,
+                eof: EofBehaviour::default(),
+                capped_tape: false,
+                interrupt: None,
+            },
+            breakpoints: vec![],
+        }
+    }
+
+    /// The state so far. `start_instr` is the next instruction to
+    /// execute.
+    pub fn state(&self) -> &ExecutionState<'a> {
+        &self.state
+    }
+
+    /// The value of the cell at `index`, if there is one.
+    pub fn peek(&self, index: usize) -> Option<Cell> {
+        self.state.cells.get(index).copied()
+    }
+
+    /// The instruction that executes next, or None if the program
+    /// has finished.
+    pub fn next_instr(&self) -> Option<&'a AstNode> {
+        self.program.get(self.progress.pc).map(|instr| instr.node)
+    }
+
+    /// Give reads more input.
+    pub fn push_input(&mut self, input: &[u8]) {
+        self.state.input.extend(input);
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint<'a>) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    /// Remove `breakpoint`, returning false if it wasn't set.
+    pub fn remove_breakpoint(&mut self, breakpoint: Breakpoint<'a>) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|b| *b != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    /// Execute the next instruction.
+    pub fn step(&mut self) -> Pause {
+        self.execute(u64::MAX, 1, |_| false)
+    }
+
+    /// Execute until we reach a breakpoint or have spent `steps`.
+    pub fn run(&mut self, steps: u64) -> Pause {
+        self.execute(steps, u64::MAX, |_| false)
+    }
+
+    /// Execute until we reach a breakpoint, `condition` holds after
+    /// an instruction, or we have spent `steps`.
+    pub fn run_until<F>(&mut self, steps: u64, condition: F) -> Pause
+    where
+        F: FnMut(&ExecutionState) -> bool,
+    {
+        self.execute(steps, u64::MAX, condition)
+    }
+
+    /// Execute at most `instrs` instructions, one at a time. The
+    /// instruction we're paused at runs even if it has a breakpoint,
+    /// so we can continue past it.
+    fn execute<F>(&mut self, steps: u64, mut instrs: u64, mut condition: F) -> Pause
+    where
+        F: FnMut(&ExecutionState) -> bool,
+    {
+        self.progress.steps_left = steps;
+        let pause = loop {
+            if self.progress.steps_left == 0 || instrs == 0 {
+                break Pause::Stepped;
+            }
+
+            self.state.start_instr = None;
+            self.progress.instrs_left = 1;
+            let steps_left = self.progress.steps_left;
+            let outcome = run(
+                &self.program,
+                &mut self.state,
+                &mut self.progress,
+                &self.options,
+                None,
+                None,
+            );
+            self.state.steps += steps_left - self.progress.steps_left;
+            instrs -= 1;
+            if outcome != Outcome::OutOfSteps {
+                break Pause::Stopped(outcome);
+            }
+
+            if condition(&self.state) {
+                break Pause::Condition;
+            }
+            if let Some(next) = self.program.get(self.progress.pc) {
+                if self.breakpoints.iter().any(|b| b.matches(next)) {
+                    break Pause::Breakpoint;
+                }
+            }
+        };
+        record_loop_counts(
+            &self.program,
+            &mut self.progress.loop_counts,
+            &mut self.state,
+        );
+        pause
+    }
+}
+
+/// The most events we record in a trace by default. Each event
+/// takes tens of bytes once written out, so this keeps traces to a
+/// manageable size.
+pub const DEFAULT_MAX_TRACE_EVENTS: usize = 1_000_000;
+
+/// A record of what compile time execution did, for external tools
+/// to visualise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    /// Only record every `sample_every`th instruction executed.
+    pub sample_every: u64,
+    /// Stop recording once we have this many events.
+    pub max_events: usize,
+    pub events: Vec<TraceEvent>,
+    /// The number of sampled instructions that we didn't record,
+    /// because we already had `max_events` events.
+    pub dropped: u64,
+    /// The number of instructions executed so far.
+    executed: u64,
+    /// The index of the next instruction to sample.
+    next_sample: u64,
+    /// The cells the most recent event might modify, with their
+    /// values beforehand. We fill in the event's `cell_deltas` when
+    /// the next instruction starts.
+    pending: Option<Vec<(usize, Cell)>>,
+}
+
+/// A single instruction executed at compile time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    /// The number of instructions executed before this one.
+    pub index: u64,
+    /// The index of this instruction in the flattened program. Loops
+    /// have two instructions, one at each end.
+    pub instr: usize,
+    pub position: Option<Position>,
+    /// The cell pointer before executing this instruction.
+    pub cell_ptr: isize,
+    /// The cells changed by this instruction, and how much they
+    /// changed by.
+    pub cell_deltas: Vec<(usize, Cell)>,
+}
+
+impl Trace {
+    pub fn new(sample_every: u64, max_events: usize) -> Self {
+        Trace {
+            sample_every: sample_every.max(1),
+            max_events,
+            events: vec![],
+            dropped: 0,
+            executed: 0,
+            next_sample: 0,
+            pending: None,
+        }
+    }
+
+    /// We're about to execute the instruction `instr` at `pc`.
+    fn record(&mut self, pc: usize, instr: &Instruction, state: &ExecutionState, cell_bits: u32) {
+        self.finish_pending(state, cell_bits);
+
+        let index = self.executed;
+        self.executed += 1;
+        if index < self.next_sample {
+            return;
+        }
+        self.next_sample += self.sample_every;
+        if self.events.len() >= self.max_events {
+            self.dropped += 1;
+            return;
+        }
+
+        let before = touched_cells(&instr.op, state.cell_ptr)
+            .into_iter()
+            .filter(|&cell_index| cell_index >= 0 && (cell_index as usize) < state.cells.len())
+            .map(|cell_index| (cell_index as usize, state.cells[cell_index as usize]))
+            .collect();
+        self.pending = Some(before);
+        self.events.push(TraceEvent {
+            index,
+            instr: pc,
+            position: get_position(instr.node),
+            cell_ptr: state.cell_ptr,
+            cell_deltas: vec![],
+        });
+    }
+
+    /// Fill in the cell deltas of the most recent event, now that
+    /// its instruction has executed.
+    fn finish_pending(&mut self, state: &ExecutionState, cell_bits: u32) {
+        if let Some(before) = self.pending.take() {
+            let event = self.events.last_mut().unwrap();
+            for (cell_index, old_value) in before {
+                let delta = wrap_cell(state.cells[cell_index] - old_value, cell_bits);
+                if delta.0 != 0 {
+                    event.cell_deltas.push((cell_index, delta));
+                }
+            }
+        }
+    }
+
+    /// Remove the most recent event, as its instruction never
+    /// executed.
+    fn discard_pending(&mut self) {
+        if self.pending.take().is_some() {
+            self.events.pop();
+        }
+    }
+}
+
+/// The cells that `op` might modify, when the cell pointer is at
+/// `cell_ptr`.
+fn touched_cells(op: &Op, cell_ptr: isize) -> Vec<isize> {
+    match *op {
+        Op::Increment { offset, .. } | Op::Set { offset, .. } => vec![cell_ptr + offset],
+        Op::MultiplyMove {
+            ref changes,
+            ref adds,
+        } => {
+            let mut cells: Vec<isize> = changes
+                .iter()
+                .chain(adds.iter())
+                .map(|(offset, _)| cell_ptr + offset)
+                .collect();
+            cells.push(cell_ptr);
+            cells.sort_unstable();
+            cells.dedup();
+            cells
+        }
+        Op::Read { .. } => vec![cell_ptr],
+        _ => vec![],
+    }
+}
+
+/// The state at the end of the most recent iteration of a simple
+/// loop, so we can tell if an iteration changes nothing.
+#[derive(Debug, Default)]
+struct LastIteration {
+    /// The position of the `LoopEnd`, the cell pointer and the value
+    /// of the current cell.
+    at: Option<(usize, isize, Cell)>,
+    /// Whether we've recorded `cells`. We only record them once the
+    /// current cell stops changing, as recording them on every
+    /// iteration would be slow.
+    recorded: bool,
+    /// The modified cells.
+    cells: Vec<Cell>,
+}
+
+impl LastIteration {
+    /// We've finished an iteration of the simple loop ending at `pc`
+    /// that modifies the cells at offsets `modified`, and the
+    /// current cell is nonzero. Is the state the same as at the end
+    /// of the previous iteration?
+    ///
+    /// The body is a function of the cells it modifies, so if so,
+    /// every later iteration is the same and the loop never
+    /// terminates.
+    fn repeated(&mut self, modified: &[isize], pc: usize, state: &ExecutionState) -> bool {
+        let at = Some((pc, state.cell_ptr, state.cells[state.cell_ptr as usize]));
+        if self.at != at {
+            self.at = at;
+            self.recorded = false;
+            return false;
+        }
+
+        // A growable tape may not have reached cells the body only
+        // modifies sometimes, but they're zero.
+        let cells = modified.iter().map(|offset| {
+            state
+                .cells
+                .get((state.cell_ptr + offset) as usize)
+                .copied()
+                .unwrap_or(Wrapping(0))
+        });
+        if self.recorded {
+            cells.eq(self.cells.iter().copied())
+        } else {
+            self.cells.clear();
+            self.cells.extend(cells);
+            self.recorded = true;
+            false
+        }
+    }
+}
+
+/// The warning for a pointer increment `instr` that moves the
+/// pointer to `cell_ptr`, outside the `num_cells` cells available.
+fn pointer_out_of_bounds(cell_ptr: isize, num_cells: usize, instr: &AstNode) -> Warning {
+    let message = if cell_ptr < 0 {
+        format!("This instruction moves the pointer to cell {}.", cell_ptr)
+    } else {
+        format!(
+            "This instruction moves the pointer after the last cell ({}), to \
+             cell {}.",
+            num_cells - 1,
+            cell_ptr
+        )
+    };
+    Warning {
+        lint: Lint::OutOfBounds,
+        message,
+        position: get_position(instr),
+        notes: vec![],
+    }
+}
+
+/// The part of a program that still needs to run after compile time
+/// execution.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Residual<'a> {
+    /// Output to write with a single constant write at startup. Any
+    /// later outputs are interleaved with the deferred reads.
+    pub prefix_output: &'a [i8],
+    /// The top-level instructions that still need to be compiled.
+    /// Runtime execution begins at `start_instr`, which is one of
+    /// these or inside one of these loops.
+    pub instrs: &'a [AstNode],
+}
+
+/// Split `instrs` into what compile time execution has already done
+/// and what remains for runtime. Top-level instructions before the
+/// one containing `state.start_instr` have already run, so they're
+/// dropped entirely.
+pub fn residualise<'a>(instrs: &'a [AstNode], state: &'a ExecutionState) -> Residual<'a> {
+    let prefix_output = match state.reads.first() {
+        Some(read) => &state.outputs[..read.outputs_before],
+        None => &state.outputs[..],
+    };
+
+    let remaining_instrs = match state.start_instr {
+        Some(start_instr) => {
+            let start_idx = instrs
+                .iter()
+                .position(|instr| contains_instr(instr, start_instr))
+                .expect("start_instr should be in the instructions executed");
+            &instrs[start_idx..]
+        }
+        None => &[],
+    };
+
+    Residual {
+        prefix_output,
+        instrs: remaining_instrs,
+    }
+}
+
+/// Is `target` either `instr` itself, or nested inside it?
+pub fn contains_instr(instr: &AstNode, target: &AstNode) -> bool {
+    if std::ptr::eq(instr, target) {
+        return true;
+    }
+    match *instr {
+        Loop { ref body, .. } => body.iter().any(|instr| contains_instr(instr, target)),
+        _ => false,
+    }
+}
+
+/// A loop that ran at compile time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HotLoop<'a> {
+    pub instr: &'a AstNode,
+    pub counts: LoopCounts,
+}
+
+/// The loops in `instrs` that ran at compile time, hottest first.
+/// We estimate a loop's hotness from the number of iterations it ran
+/// at compile time, so heuristics that trade code size for speed can
+/// favour the loops most likely to dominate runtime. Codegen uses the
+/// same counts, through `LoopCounts::is_cold`.
+pub fn hot_loops<'a>(instrs: &'a [AstNode], state: &ExecutionState) -> Vec<HotLoop<'a>> {
+    let mut loops = vec![];
+    collect_loops(instrs, state, &mut loops);
+    // Sorting is stable, so equally hot loops stay in source order.
+    loops.sort_by_key(|hot_loop| Reverse(hot_loop.counts.iterations));
+    loops
+}
+
+fn collect_loops<'a>(instrs: &'a [AstNode], state: &ExecutionState, loops: &mut Vec<HotLoop<'a>>) {
+    for instr in instrs {
+        if let Loop { ref body, .. } = *instr {
+            if let Some(&counts) = state.loop_counts.get(&(instr as *const AstNode)) {
+                loops.push(HotLoop { instr, counts });
+            }
+            collect_loops(body, state, loops);
+        }
+    }
+}
+
+/// The instructions in `instrs` that ran at least once during
+/// compile time execution. We don't record every step, so we work
+/// this out from where execution stopped and which loop bodies were
+/// entered.
+pub fn executed_instrs(instrs: &[AstNode], state: &ExecutionState) -> HashSet<*const AstNode> {
+    let mut executed = HashSet::new();
+    collect_executed(instrs, state, state.start_instr, &mut executed);
+    executed
+}
+
+/// Add the instructions in `instrs` that ran to `executed`,
+/// stopping at `stop`. Returns true if `stop` is in `instrs`.
+fn collect_executed(
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    stop: Option<&AstNode>,
+    executed: &mut HashSet<*const AstNode>,
+) -> bool {
+    for instr in instrs {
+        if stop.is_some_and(|stop| std::ptr::eq(stop, instr)) {
+            return true;
+        }
+        executed.insert(instr as *const AstNode);
+
+        if let Loop { ref body, .. } = *instr {
+            let counts = state
+                .loop_counts
+                .get(&(instr as *const AstNode))
+                .copied()
+                .unwrap_or_default();
+            if counts.iterations > 0 && collect_executed(body, state, stop, executed) {
+                // We stopped part way through the body. If this
+                // wasn't the first iteration, all of the body ran.
+                if counts.iterations > 1 {
+                    collect_executed(body, state, None, executed);
+                }
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// The number of bytes written by this instruction.
+fn output_len(instr: &AstNode) -> usize {
+    match *instr {
+        Write { .. } => 1,
+        WriteString { ref bytes, .. } => bytes.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use quickcheck::quickcheck;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::bfir::{parse, Position};
+    use crate::bounds::MAX_CELL_INDEX;
+
+    /// Build the expected `loop_counts`, given the iterations and
+    /// exits of each loop.
+    fn loop_counts(counts: &[(&AstNode, u64, u64)]) -> HashMap<*const AstNode, LoopCounts> {
+        counts
+            .iter()
+            .map(|&(instr, iterations, exits)| {
+                (instr as *const AstNode, LoopCounts { iterations, exits })
+            })
+            .collect()
+    }
+
+    /// We can't evaluate outputs of runtime values at compile time.
+    #[test]
+    fn cant_evaluate_inputs() {
+        let instrs = parse(",.").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[1]),
+                cells: vec![Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![DeferredRead {
+                    cell: Some(0),
+                    count: 1,
+                    outputs_before: 0,
+                    scale: Wrapping(1),
+                }],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn increment_executed() {
+        let instrs = parse("+").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(1)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn multiply_move_executed() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        changes.insert(3, Wrapping(3));
+
+        let instrs = [
+            // Initial cells: [2, 1, 0, 0]
+            Increment {
                 amount: Wrapping(2),
                 offset: 0,
                 position: Some(Position { start: 0, end: 0 }),
             },
             PointerIncrement {
                 amount: 1,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Increment {
-                amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            PointerIncrement {
-                amount: -1,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            MultiplyMove {
-                changes,
-                position: Some(Position { start: 0, end: 0 }),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            PointerIncrement {
+                amount: -1,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(0), Wrapping(5), Wrapping(0), Wrapping(6)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 5,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    /// When the current cell is zero, we shouldn't execute a multiply move instruction.
+    /// Otherwise, the BF program [-<+>] (which is well formed and does nothing) becomes
+    /// undefined behaviour when we have a multiply move instruction.
+    #[test]
+    fn multiply_move_when_current_cell_is_zero() {
+        let mut changes = HashMap::new();
+        changes.insert(-1, Wrapping(2));
+
+        let instrs = [MultiplyMove {
+            changes,
+            adds: HashMap::new(),
+            position: None,
+        }];
+
+        let (final_state, warning) = execute(&instrs, max_steps());
+        assert_eq!(warning, None);
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn multiply_move_adds_when_current_cell_is_zero() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        let mut adds = HashMap::new();
+        adds.insert(1, Wrapping(3));
+
+        let instrs = [MultiplyMove {
+            changes,
+            adds,
+            position: None,
+        }];
+
+        let (final_state, warning) = execute(&instrs, max_steps());
+        assert_eq!(warning, None);
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(0), Wrapping(3)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn multiply_move_wrapping() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(3));
+        let instrs = [
+            Increment {
+                amount: Wrapping(100),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                // 100 * 3 mod 256 == 44
+                cells: vec![Wrapping(0), Wrapping(44)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 2,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn multiply_move_offset_too_high() {
+        let mut changes: HashMap<isize, Cell> = HashMap::new();
+        changes.insert(MAX_CELL_INDEX as isize + 1, Wrapping(1));
+        let instrs = [
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        let mut expected_cells = vec![Wrapping(0); MAX_CELL_INDEX + 1];
+        expected_cells[0] = Wrapping(1);
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[1]),
+                cells: expected_cells,
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn multiply_move_offset_too_low() {
+        let mut changes = HashMap::new();
+        changes.insert(-1, Wrapping(1));
+        let instrs = [
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[1]),
+                cells: vec![Wrapping(1)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn set_executed() {
+        let instrs = [Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(2)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn set_wraps() {
+        let instrs = [Set {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        }];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(-1)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn decrement_executed() {
+        let instrs = parse("-").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(-1)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn increment_wraps() {
+        let instrs = [
+            Increment {
+                amount: Wrapping(-1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            },
+        ];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 2,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn ptr_increment_executed() {
+        let instrs = parse(">").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(0), Wrapping(0)],
+                cell_ptr: 1,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn ptr_out_of_range() {
+        let instrs = parse("<").unwrap();
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[0]),
+                cells: vec![Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 0,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+
+        assert!(warning.is_some());
+    }
+
+    #[test]
+    fn limit_to_steps_specified() {
+        let instrs = parse("++++").unwrap();
+        let final_state = execute(&instrs, 2).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[2]),
+                cells: vec![Wrapping(2)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 2,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn limit_to_deadline() {
+        let instrs = parse("+[]").unwrap();
+        let limits = Limits {
+            steps: DEADLINE_CHECK_INTERVAL,
+            deadline: Some(Instant::now()),
+            ..Limits::default()
+        };
+        let (final_state, outcome) = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        let warning = outcome.warning(&final_state);
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[0]),
+                cells: vec![Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 0,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+        assert_eq!(warning.map(|w| w.lint), Some(Lint::SpeculationLimit));
+    }
+
+    #[test]
+    fn limit_output() {
+        let instrs = parse("+..>").unwrap();
+        let limits = Limits {
+            max_output: 1,
+            ..Limits::default()
+        };
+        let (final_state, outcome) = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        let warning = outcome.warning(&final_state);
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: Some(&instrs[2]),
+                cells: vec![Wrapping(1), Wrapping(0)],
+                cell_ptr: 0,
+                outputs: vec![1],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 2,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+        let warning = warning.unwrap();
+        assert_eq!(warning.lint, Lint::SpeculationLimit);
+        assert_eq!(warning.position, Some(Position { start: 2, end: 2 }));
+    }
+
+    #[test]
+    fn limit_output_of_write_string() {
+        let instrs = vec![WriteString {
+            bytes: vec![1, 2, 3],
+            position: None,
+        }];
+        let limits = Limits {
+            max_output: 2,
+            ..Limits::default()
+        };
+        let final_state = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        )
+        .0;
+
+        assert_eq!(final_state.start_instr, Some(&instrs[0]));
+        assert_eq!(final_state.outputs, Vec::<i8>::new());
+    }
+
+    #[test]
+    fn out_of_steps_is_silent() {
+        // This loop never terminates, but we can't prove it.
+        let instrs = parse("+[>+[-]<]").unwrap();
+        let warning = execute(&instrs, 10).1;
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn deadline_checked_during_loops() {
+        let instrs = parse("+[>+[-]<]").unwrap();
+        let mut state = ExecutionState::initial(&instrs);
+        let deadline = Instant::now() + std::time::Duration::from_millis(10);
+        let options = Options {
+            dummy_read_value: None,
+            cell_bits: DEFAULT_CELL_BITS,
+            deadline: Some(deadline),
+            max_output: usize::MAX,
+            split_branches: false,
+            eof_reads: false,
+            eof: EofBehaviour::default(),
+            capped_tape: false,
+            interrupt: None,
+        };
+        let outcome = execute_inner(&instrs, &mut state, u64::MAX, &options, None, None);
+
+        assert_eq!(outcome, Outcome::OutOfTime);
+        assert!(contains_instr(&instrs[1], state.start_instr.unwrap()));
+    }
+
+    #[test]
+    fn growable_tape_grows_on_demand() {
+        let instrs = parse(">>+<<[>+]").unwrap();
+        let mut state = ExecutionState::with_tape(&instrs, Tape::Growable { max_cells: 10 });
+        assert_eq!(state.cells.len(), 1);
+
+        let outcome = execute_with_state(&instrs, &mut state, max_steps(), None, DEFAULT_CELL_BITS);
+        assert!(matches!(outcome, Outcome::Completed(_)));
+        assert_eq!(state.cells, vec![Wrapping(0), Wrapping(0), Wrapping(1)]);
+    }
+
+    #[test]
+    fn speculation_stops_at_end_of_growable_tape() {
+        let instrs = parse("+[>+]").unwrap();
+        let limits = Limits {
+            growable_tape: true,
+            ..Limits::default()
+        };
+        let (final_state, outcome) = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+
+        // The compiled program grows the tape, so this isn't an error.
+        assert_eq!(outcome, Outcome::OutOfCells);
+        assert_eq!(outcome.warning(&final_state), None);
+        assert!(final_state.start_instr.is_some());
+    }
+
+    #[test]
+    fn growable_tape_out_of_bounds() {
+        let instrs = parse("+[>+]").unwrap();
+        let mut state = ExecutionState::with_tape(&instrs, Tape::Growable { max_cells: 10 });
+
+        let outcome = execute_with_state(&instrs, &mut state, max_steps(), None, DEFAULT_CELL_BITS);
+        assert_eq!(
+            outcome,
+            Outcome::RuntimeError(Warning {
+                lint: Lint::OutOfBounds,
+                message: "This instruction moves the pointer after the last cell (9), to cell 10."
+                    .to_owned(),
+                position: Some(Position { start: 2, end: 2 }),
+                notes: vec![],
+            })
+        );
+        assert_eq!(state.cells.len(), 10);
+    }
+
+    #[test]
+    fn stop_when_interrupted() {
+        // Tests run in parallel, so don't set the global flag.
+        static INTERRUPT: AtomicBool = AtomicBool::new(true);
+        let instrs = parse("+[>+[-]<]").unwrap();
+        let mut state = ExecutionState::initial(&instrs);
+        let options = Options {
+            dummy_read_value: None,
+            cell_bits: DEFAULT_CELL_BITS,
+            deadline: None,
+            max_output: usize::MAX,
+            split_branches: false,
+            eof_reads: false,
+            eof: EofBehaviour::default(),
+            capped_tape: false,
+            interrupt: Some(&INTERRUPT),
+        };
+        let outcome = execute_inner(&instrs, &mut state, u64::MAX, &options, None, None);
+
+        assert_eq!(outcome, Outcome::Interrupted);
+        assert!(contains_instr(&instrs[1], state.start_instr.unwrap()));
+        assert_eq!(
+            outcome.warning(&state).map(|warning| warning.lint),
+            Some(Lint::SpeculationLimit)
+        );
+    }
+
+    #[test]
+    fn continue_past_read() {
+        let instrs = parse("+.,>++.").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(
+            final_state,
+            ExecutionState {
+                start_instr: None,
+                cells: vec![Wrapping(0), Wrapping(2)],
+                cell_ptr: 1,
+                outputs: vec![1, 2],
+                reads: vec![DeferredRead {
+                    cell: Some(0),
+                    count: 1,
+                    outputs_before: 1,
+                    scale: Wrapping(1),
+                }],
+                input: VecDeque::new(),
+                steps: 7,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
+            }
+        );
+    }
+
+    #[test]
+    fn continue_past_increment_of_unknown_cell() {
+        let instrs = parse(",+>+.<.").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        // We can't write the unknown cell.
+        assert_eq!(final_state.start_instr, Some(&instrs[6]));
+        assert_eq!(final_state.outputs, vec![1]);
+        assert_eq!(final_state.cells, vec![Wrapping(1), Wrapping(1)]);
+        assert_eq!(
+            final_state.reads,
+            vec![DeferredRead {
+                cell: Some(0),
+                count: 1,
+                outputs_before: 0,
+                scale: Wrapping(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn multiply_move_of_unknown_cell() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        let instrs = vec![
+            Read { position: None },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        // The value read is doubled and moved to the next cell.
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.cells, vec![Wrapping(0), Wrapping(0)]);
+        assert_eq!(
+            final_state.reads,
+            vec![DeferredRead {
+                cell: Some(1),
+                count: 1,
+                outputs_before: 0,
+                scale: Wrapping(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn multiply_move_of_unknown_cell_wrapping_to_zero() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(-128));
+        let instrs = vec![
+            Read { position: None },
+            MultiplyMove {
+                changes: changes.clone(),
+                adds: HashMap::new(),
+                position: None,
+            },
+            PointerIncrement {
+                amount: 1,
+                position: None,
+            },
+            // 2 * -128 is zero in an 8-bit cell, so nothing is left
+            // of the value read.
+            MultiplyMove {
+                changes: {
+                    let mut changes = HashMap::new();
+                    changes.insert(1, Wrapping(2));
+                    changes
+                },
+                adds: HashMap::new(),
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.cells, vec![Wrapping(0); 3]);
+        assert_eq!(final_state.reads[0].cell, None);
+    }
+
+    #[test]
+    fn stop_on_multiply_move_of_unknown_cell_to_several_cells() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        changes.insert(2, Wrapping(1));
+        let instrs = vec![
+            Read { position: None },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+    }
+
+    #[test]
+    fn stop_on_multiply_move_of_unknown_cell_into_unknown_cell() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        let instrs = vec![
+            PointerIncrement {
+                amount: 1,
+                position: None,
+            },
+            Read { position: None },
+            PointerIncrement {
+                amount: -1,
+                position: None,
+            },
+            Read { position: None },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.start_instr, Some(&instrs[4]));
+    }
+
+    #[test]
+    fn multiply_move_into_unknown_cell() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            PointerIncrement {
+                amount: 1,
+                position: None,
+            },
+            Read { position: None },
+            PointerIncrement {
+                amount: -1,
+                position: None,
+            },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: None,
+            },
+        ];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.cells, vec![Wrapping(0), Wrapping(2)]);
+        assert_eq!(final_state.reads[0].cell, Some(1));
+    }
+
+    #[test]
+    fn value_facts_range() {
+        // A byte read plus 2 is between 1 and 257.
+        let facts = ValueFacts::new(Wrapping(1), Wrapping(2), 16);
+        assert!(!facts.may_be_zero(16));
+        // That's zero in an 8-bit cell if we read 254.
+        let facts = ValueFacts::new(Wrapping(1), Wrapping(2), 8);
+        assert!(facts.may_be_zero(8));
+        // A byte read plus 1 is zero at EOF.
+        let facts = ValueFacts::new(Wrapping(1), Wrapping(1), 16);
+        assert!(facts.may_be_zero(16));
+    }
+
+    #[test]
+    fn value_facts_congruence() {
+        // Twice a byte read, plus 1, is always odd.
+        let facts = ValueFacts::new(Wrapping(2), Wrapping(1), 8);
+        assert!(!facts.may_be_zero(8));
+        let facts = ValueFacts::new(Wrapping(2), Wrapping(2), 8);
+        assert!(facts.may_be_zero(8));
+    }
+
+    #[test]
+    fn enter_scan_on_nonzero_unknown_cell() {
+        let instrs = parse(",++[>]+.").unwrap();
+        let limits = Limits {
+            steps: max_steps(),
+            ..Limits::default()
+        };
+        let final_state = execute_with_limits(&instrs, &[], 16, EofBehaviour::default(), limits).0;
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.outputs, vec![1]);
+        assert_eq!(final_state.reads[0].cell, Some(0));
+    }
+
+    #[test]
+    fn stop_on_scan_of_unknown_cell_that_may_be_zero() {
+        let instrs = parse(",++[>]+.").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.start_instr, Some(&instrs[3]));
+    }
+
+    #[test]
+    fn enter_loop_on_odd_unknown_cell() {
+        let mut changes = HashMap::new();
+        changes.insert(1, Wrapping(2));
+        let mut instrs = vec![
+            Read { position: None },
+            MultiplyMove {
+                changes,
+                adds: HashMap::new(),
+                position: None,
+            },
+            PointerIncrement {
+                amount: 1,
+                position: None,
             },
         ];
-
+        instrs.extend(parse("+[>]+.").unwrap());
         let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.outputs, vec![1]);
         assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(0), Wrapping(5), Wrapping(0), Wrapping(6)],
-                cell_ptr: 0,
-                outputs: vec![],
-            }
+            final_state.reads,
+            vec![DeferredRead {
+                cell: Some(1),
+                count: 1,
+                outputs_before: 0,
+                scale: Wrapping(2),
+            }]
         );
     }
 
-    /// When the current cell is zero, we shouldn't execute a multiply move instruction.
-    /// Otherwise, the BF program [-<+>] (which is well formed and does nothing) becomes
-    /// undefined behaviour when we have a multiply move instruction.
-    #[test]
-    fn multiply_move_when_current_cell_is_zero() {
-        let mut changes = HashMap::new();
-        changes.insert(-1, Wrapping(2));
+    /// `[-]`, as the peephole optimiser would simplify it.
+    fn clear_cell() -> AstNode {
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: None,
+        }
+    }
 
-        let instrs = [MultiplyMove {
-            changes,
+    #[test]
+    fn split_loop_on_read_value() {
+        // ,[>+<[-]]+.>++.
+        let mut body = parse(">+<").unwrap();
+        body.push(clear_cell());
+        let mut instrs = parse(",").unwrap();
+        instrs.push(Loop {
+            body,
             position: None,
-        }];
+        });
+        instrs.extend(parse("+.>++.").unwrap());
+        let final_state = execute(&instrs, max_steps()).0;
 
-        let (final_state, warning) = execute(&instrs, max_steps());
-        assert_eq!(warning, None);
+        // Either way, the first cell is zero after the loop, so we
+        // know the first output. The second cell depends on the
+        // loop, so we stop when writing it.
         assert_eq!(
             final_state,
             ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(0)],
-                cell_ptr: 0,
-                outputs: vec![],
+                start_instr: Some(&instrs[7]),
+                cells: vec![Wrapping(1), Wrapping(2)],
+                cell_ptr: 1,
+                outputs: vec![1],
+                reads: vec![DeferredRead {
+                    cell: None,
+                    count: 1,
+                    outputs_before: 0,
+                    scale: Wrapping(1),
+                }],
+                input: VecDeque::new(),
+                // Including the steps exploring the loop body.
+                steps: 12,
+                loop_counts: HashMap::new(),
+                branches: vec![DeferredBranch {
+                    read: 0,
+                    scale: Wrapping(1),
+                    offset: Wrapping(0),
+                    cells: vec![(1, Wrapping(3))],
+                }],
+                tape: Tape::Fixed,
             }
         );
     }
 
     #[test]
-    fn multiply_move_wrapping() {
-        let mut changes = HashMap::new();
-        changes.insert(1, Wrapping(3));
-        let instrs = [
-            Increment {
-                amount: Wrapping(100),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            MultiplyMove {
-                changes,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
+    fn enter_loop_on_branch_cell_nonzero_either_way() {
+        // ,[>+<[-]]>+[[-]>+<]>.
+        let mut body = parse(">+<").unwrap();
+        body.push(clear_cell());
+        let mut instrs = parse(",").unwrap();
+        instrs.push(Loop {
+            body,
+            position: None,
+        });
+        instrs.extend(parse(">+").unwrap());
+        let mut body = vec![clear_cell()];
+        body.extend(parse(">+<").unwrap());
+        instrs.push(Loop {
+            body,
+            position: None,
+        });
+        instrs.extend(parse(">.").unwrap());
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.outputs, vec![1]);
+        assert_eq!(final_state.branches, vec![]);
+    }
+
+    #[test]
+    fn stop_on_loop_writing_read_value() {
+        let instrs = parse(",[.[-]]").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(final_state.branches, vec![]);
+    }
 
+    #[test]
+    fn stop_on_loop_on_read_value_moving_pointer() {
+        // ,[[-]>]
+        let mut instrs = parse(",").unwrap();
+        instrs.push(Loop {
+            body: vec![
+                clear_cell(),
+                PointerIncrement {
+                    amount: 1,
+                    position: None,
+                },
+            ],
+            position: None,
+        });
         let final_state = execute(&instrs, max_steps()).0;
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(final_state.cell_ptr, 0);
+    }
+
+    #[test]
+    fn debugger_step() {
+        let instrs = parse("+>++").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+        assert_eq!(debugger.next_instr(), Some(&instrs[0]));
+
+        assert_eq!(debugger.step(), Pause::Stepped);
+        assert_eq!(debugger.peek(0), Some(Wrapping(1)));
+        assert_eq!(debugger.next_instr(), Some(&instrs[1]));
+        assert_eq!(debugger.state().start_instr, Some(&instrs[1]));
+
+        assert_eq!(debugger.step(), Pause::Stepped);
+        assert_eq!(debugger.step(), Pause::Stepped);
+        assert!(matches!(
+            debugger.step(),
+            Pause::Stopped(Outcome::Completed(_))
+        ));
+        assert_eq!(debugger.next_instr(), None);
+        assert_eq!(debugger.state().cells, vec![Wrapping(1), Wrapping(2)]);
+        assert_eq!(debugger.state().steps, 4);
+    }
+
+    #[test]
+    fn debugger_step_into_loop() {
+        let instrs = parse("+[-]").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+        debugger.step();
+        debugger.step();
+
+        // Entering the loop is a single instruction.
+        let body = match instrs[1] {
+            Loop { ref body, .. } => body,
+            _ => unreachable!(),
+        };
+        assert_eq!(debugger.next_instr(), Some(&body[0]));
+    }
+
+    #[test]
+    fn debugger_breakpoint_on_instr() {
+        let instrs = parse("+++[>+<-]>.").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+        debugger.add_breakpoint(Breakpoint::Instr(&instrs[4]));
+
+        assert_eq!(debugger.run(max_steps()), Pause::Breakpoint);
+        assert_eq!(debugger.next_instr(), Some(&instrs[4]));
+        assert_eq!(debugger.peek(1), Some(Wrapping(3)));
+
+        // We can continue past the breakpoint.
+        assert!(matches!(
+            debugger.run(max_steps()),
+            Pause::Stopped(Outcome::Completed(_))
+        ));
+        assert_eq!(debugger.state().outputs, vec![3]);
+    }
+
+    #[test]
+    fn debugger_breakpoint_on_loop() {
+        let instrs = parse("+++[>+<-]").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+        debugger.add_breakpoint(Breakpoint::Instr(&instrs[3]));
+
+        // We stop before every check of the loop condition.
+        let mut conditions = vec![];
+        while debugger.run(max_steps()) == Pause::Breakpoint {
+            conditions.push(debugger.peek(0).unwrap().0);
+        }
+        assert_eq!(conditions, vec![3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn debugger_breakpoint_on_position() {
+        let instrs = parse("+>.+").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+        debugger.add_breakpoint(Breakpoint::Position(2));
+
+        assert_eq!(debugger.run(max_steps()), Pause::Breakpoint);
+        assert_eq!(debugger.next_instr(), Some(&instrs[2]));
+    }
+
+    #[test]
+    fn debugger_remove_breakpoint() {
+        let instrs = parse("+>.+").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+        debugger.add_breakpoint(Breakpoint::Position(2));
+
+        assert!(debugger.remove_breakpoint(Breakpoint::Position(2)));
+        assert!(!debugger.remove_breakpoint(Breakpoint::Position(2)));
+        assert!(matches!(
+            debugger.run(max_steps()),
+            Pause::Stopped(Outcome::Completed(_))
+        ));
+    }
+
+    #[test]
+    fn debugger_run_until() {
+        let instrs = parse("++++++++[>+<-]").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+
+        let pause = debugger.run_until(max_steps(), |state| state.cells[1] == Wrapping(5));
+        assert_eq!(pause, Pause::Condition);
+        assert_eq!(debugger.peek(0), Some(Wrapping(4)));
+    }
+
+    #[test]
+    fn debugger_run_out_of_steps() {
+        let instrs = parse("+++").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+
+        assert_eq!(debugger.run(2), Pause::Stepped);
+        assert_eq!(debugger.next_instr(), Some(&instrs[2]));
+    }
+
+    #[test]
+    fn debugger_waits_for_input() {
+        let instrs = parse(",.").unwrap();
+        let mut debugger = Debugger::new(&instrs, &[], DEFAULT_CELL_BITS);
+
         assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                // 100 * 3 mod 256 == 44
-                cells: vec![Wrapping(0), Wrapping(44)],
-                cell_ptr: 0,
-                outputs: vec![],
-            }
+            debugger.step(),
+            Pause::Stopped(Outcome::ReachedRuntimeValue)
         );
+        assert_eq!(debugger.next_instr(), Some(&instrs[0]));
+
+        debugger.push_input(b"a");
+        assert!(matches!(
+            debugger.run(max_steps()),
+            Pause::Stopped(Outcome::Completed(_))
+        ));
+        assert_eq!(debugger.state().outputs, vec![b'a' as i8]);
     }
 
     #[test]
-    fn multiply_move_offset_too_high() {
-        let mut changes: HashMap<isize, Cell> = HashMap::new();
-        changes.insert(MAX_CELL_INDEX as isize + 1, Wrapping(1));
-        let instrs = [
-            Increment {
-                amount: Wrapping(1),
-                offset: 0,
-                position: None,
-            },
-            MultiplyMove {
-                changes,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
+    fn interpret_whole_program() {
+        // Reading past the end of input stores -1, which ends the
+        // loop.
+        let instrs = parse(",+[-.,+]").unwrap();
+        let output = interpret(&instrs, b"abc");
+        assert_eq!(output.bytes, b"abc".to_vec());
+        assert_eq!(output.status, ExitStatus::Finished);
+    }
 
-        let final_state = execute(&instrs, max_steps()).0;
-        let mut expected_cells = vec![Wrapping(0); MAX_CELL_INDEX + 1];
-        expected_cells[0] = Wrapping(1);
-        assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: Some(&instrs[1]),
-                cells: expected_cells,
-                cell_ptr: 0,
-                outputs: vec![],
-            }
+    #[test]
+    fn interpret_reads_eof() {
+        let instrs = parse(",+[-].").unwrap();
+        let output = interpret(&instrs, &[]);
+        assert_eq!(output.bytes, vec![0]);
+        assert_eq!(output.status, ExitStatus::Finished);
+        assert_eq!(output.steps, 4);
+    }
+
+    #[test]
+    fn interpret_reads_eof_with_each_behaviour() {
+        let instrs = parse("+,.").unwrap();
+        let output_with = |eof| {
+            interpret_with_limits(&instrs, &[], DEFAULT_CELL_BITS, eof, Limits::default()).bytes
+        };
+        assert_eq!(output_with(EofBehaviour::Unchanged), vec![1]);
+        assert_eq!(output_with(EofBehaviour::Zero), vec![0]);
+        assert_eq!(output_with(EofBehaviour::MinusOne), vec![255]);
+    }
+
+    #[test]
+    fn interpret_unchanged_eof_keeps_last_byte_read() {
+        let instrs = parse(",,.").unwrap();
+        let output = interpret_with_limits(
+            &instrs,
+            b"a",
+            DEFAULT_CELL_BITS,
+            EofBehaviour::Unchanged,
+            Limits::default(),
         );
+        assert_eq!(output.bytes, b"a".to_vec());
     }
 
     #[test]
-    fn multiply_move_offset_too_low() {
-        let mut changes = HashMap::new();
-        changes.insert(-1, Wrapping(1));
-        let instrs = [
-            Increment {
-                amount: Wrapping(1),
+    fn interpret_runtime_error() {
+        let instrs = parse("+.<").unwrap();
+        let output = interpret(&instrs, &[]);
+        assert_eq!(output.bytes, vec![1]);
+        assert!(matches!(output.status, ExitStatus::RuntimeError(_)));
+    }
+
+    #[test]
+    fn interpret_with_output_limit() {
+        let instrs = parse("+...").unwrap();
+        let limits = Limits {
+            max_output: 2,
+            ..Limits::default()
+        };
+        let output = interpret_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        assert_eq!(output.bytes, vec![1, 1]);
+        assert_eq!(output.status, ExitStatus::LimitExceeded(Resource::Output));
+    }
+
+    #[test]
+    fn interpret_with_growable_tape() {
+        let limits = Limits {
+            max_cells: 10,
+            growable_tape: true,
+            ..Limits::default()
+        };
+        let instrs = parse(">>>+.").unwrap();
+        let output = interpret_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        assert_eq!(output.bytes, vec![1]);
+        assert_eq!(output.status, ExitStatus::Finished);
+
+        let instrs = parse("+[>+]").unwrap();
+        let output = interpret_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        assert_eq!(output.status, ExitStatus::LimitExceeded(Resource::Cells));
+    }
+
+    #[test]
+    fn interpret_with_cell_limit() {
+        let limits = Limits {
+            max_cells: 2,
+            ..Limits::default()
+        };
+        let instrs = parse(">>+").unwrap();
+        let output = interpret_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        assert_eq!(output.status, ExitStatus::LimitExceeded(Resource::Cells));
+
+        let instrs = vec![Increment {
+            amount: Wrapping(1),
+            offset: 2,
+            position: None,
+        }];
+        let output = interpret_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        assert_eq!(output.status, ExitStatus::LimitExceeded(Resource::Cells));
+
+        // Moving before the first cell is still an error.
+        let instrs = parse("<>>").unwrap();
+        let output = interpret_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        );
+        assert!(matches!(output.status, ExitStatus::RuntimeError(_)));
+    }
+
+    /// If we overwrite a cell, we don't need to store the value read.
+    #[test]
+    fn set_makes_unknown_cell_known() {
+        let instrs = vec![
+            Read { position: None },
+            Set {
+                amount: Wrapping(3),
                 offset: 0,
                 position: None,
             },
-            MultiplyMove {
-                changes,
-                position: Some(Position { start: 0, end: 0 }),
-            },
+            Write { position: None },
         ];
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.outputs, vec![3]);
+        assert_eq!(
+            final_state.reads,
+            vec![DeferredRead {
+                cell: None,
+                count: 1,
+                outputs_before: 0,
+                scale: Wrapping(1),
+            }]
+        );
+    }
 
+    #[test]
+    fn only_last_read_into_cell_is_stored() {
+        let instrs = parse(",>,<,").unwrap();
         let final_state = execute(&instrs, max_steps()).0;
+
         assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: Some(&instrs[1]),
-                cells: vec![Wrapping(1)],
-                cell_ptr: 0,
-                outputs: vec![],
-            }
+            final_state.reads,
+            vec![
+                DeferredRead {
+                    cell: None,
+                    count: 1,
+                    outputs_before: 0,
+                    scale: Wrapping(1),
+                },
+                DeferredRead {
+                    cell: Some(1),
+                    count: 1,
+                    outputs_before: 0,
+                    scale: Wrapping(1),
+                },
+                DeferredRead {
+                    cell: Some(0),
+                    count: 1,
+                    outputs_before: 0,
+                    scale: Wrapping(1),
+                },
+            ]
         );
     }
 
     #[test]
-    fn set_executed() {
-        let instrs = [Set {
-            amount: Wrapping(2),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+    fn continue_past_skip_read() {
+        let instrs = vec![SkipRead {
+            count: 3,
+            position: None,
         }];
         let final_state = execute(&instrs, max_steps()).0;
 
         assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(2)],
-                cell_ptr: 0,
-                outputs: vec![],
-            }
+            final_state.reads,
+            vec![DeferredRead {
+                cell: Some(0),
+                count: 3,
+                outputs_before: 0,
+                scale: Wrapping(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn read_known_input() {
+        let instrs = parse(",.>,.").unwrap();
+        let final_state = execute_with_limits(
+            &instrs,
+            b"ab",
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        )
+        .0;
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.outputs, vec![97, 98]);
+        assert_eq!(final_state.reads, vec![]);
+    }
+
+    #[test]
+    fn read_known_input_then_stdin() {
+        let instrs = vec![
+            SkipRead {
+                count: 3,
+                position: None,
+            },
+            Write { position: None },
+        ];
+        let final_state = execute_with_limits(
+            &instrs,
+            b"ab",
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        )
+        .0;
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(final_state.input, VecDeque::new());
+        assert_eq!(
+            final_state.reads,
+            vec![DeferredRead {
+                cell: Some(0),
+                count: 1,
+                outputs_before: 0,
+                scale: Wrapping(1),
+            }]
         );
     }
 
     #[test]
-    fn set_wraps() {
-        let instrs = [Set {
-            amount: Wrapping(-1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        }];
-        let final_state = execute(&instrs, max_steps()).0;
+    fn reads_not_deferred_when_eof_leaves_cell_unchanged() {
+        let instrs = parse("+,.").unwrap();
+        let final_state = execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::Unchanged,
+            Limits::default(),
+        )
+        .0;
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(final_state.reads, vec![]);
+    }
+
+    #[test]
+    fn unread_input_is_kept() {
+        let instrs = parse(",+[]").unwrap();
+        let limits = Limits {
+            steps: 3,
+            ..Limits::default()
+        };
+        let final_state = execute_with_limits(
+            &instrs,
+            b"ab",
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            limits,
+        )
+        .0;
+
+        assert_eq!(final_state.cells, vec![Wrapping(98)]);
+        assert_eq!(final_state.input, VecDeque::from(vec![b'b']));
+    }
+
+    /// Known input is stored in cells as signed bytes.
+    #[test]
+    fn read_known_input_wraps() {
+        let instrs = parse(",").unwrap();
+        let final_state = execute_with_limits(
+            &instrs,
+            &[255],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        )
+        .0;
+        assert_eq!(final_state.cells, vec![Wrapping(-1)]);
+    }
+
+    #[test]
+    fn wide_cells_wrap_at_their_width() {
+        let instrs = parse("-.>++++++++[<++++++++>-]<[>++++<-]>.").unwrap();
+        let final_state =
+            execute_with_limits(&instrs, &[], 16, EofBehaviour::default(), Limits::default()).0;
 
-        assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(-1)],
-                cell_ptr: 0,
-                outputs: vec![],
-            }
-        );
+        assert_eq!(final_state.cells, vec![Wrapping(0), Wrapping(252)]);
+        assert_eq!(final_state.outputs, vec![-1, -4]);
     }
 
+    /// Bytes read into wide cells aren't sign extended, as the runtime
+    /// stores the byte from getchar.
     #[test]
-    fn decrement_executed() {
-        let instrs = parse("-").unwrap();
-        let final_state = execute(&instrs, max_steps()).0;
+    fn read_known_input_into_wide_cells() {
+        let instrs = parse(",").unwrap();
+        let final_state = execute_with_limits(
+            &instrs,
+            &[255],
+            16,
+            EofBehaviour::default(),
+            Limits::default(),
+        )
+        .0;
+        assert_eq!(final_state.cells, vec![Wrapping(255)]);
+    }
+
+    #[test]
+    fn residual_of_complete_execution() {
+        let instrs = parse("+.").unwrap();
+        let state = execute(&instrs, max_steps()).0;
 
         assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(-1)],
-                cell_ptr: 0,
-                outputs: vec![],
+            residualise(&instrs, &state),
+            Residual {
+                prefix_output: &[1],
+                instrs: &[],
             }
         );
     }
 
     #[test]
-    fn increment_wraps() {
-        let instrs = [
-            Increment {
-                amount: Wrapping(-1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-            Increment {
-                amount: Wrapping(1),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            },
-        ];
-        let final_state = execute(&instrs, max_steps()).0;
+    fn residual_drops_executed_instrs() {
+        let instrs = parse("+.>+[-]<,+.").unwrap();
+        let state = execute(&instrs, max_steps()).0;
 
+        assert_eq!(state.start_instr, Some(&instrs[8]));
         assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(0)],
-                cell_ptr: 0,
-                outputs: vec![],
+            residualise(&instrs, &state),
+            Residual {
+                prefix_output: &[1],
+                instrs: &instrs[8..],
             }
         );
     }
 
+    /// If execution stops inside a loop, the whole loop is still
+    /// needed for later iterations.
     #[test]
-    fn ptr_increment_executed() {
-        let instrs = parse(">").unwrap();
-        let final_state = execute(&instrs, max_steps()).0;
+    fn residual_keeps_enclosing_loop() {
+        let instrs = parse("+[-]+[,+]").unwrap();
+        let state = execute(&instrs, max_steps()).0;
 
-        assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: None,
-                cells: vec![Wrapping(0), Wrapping(0)],
-                cell_ptr: 1,
-                outputs: vec![],
-            }
-        );
+        assert_eq!(residualise(&instrs, &state).instrs, &instrs[3..]);
     }
 
+    /// Outputs after a deferred read must be written after the read.
     #[test]
-    fn ptr_out_of_range() {
-        let instrs = parse("<").unwrap();
-        let (final_state, warning) = execute(&instrs, max_steps());
+    fn residual_prefix_stops_at_first_read() {
+        let instrs = parse("+.,>.").unwrap();
+        let state = execute(&instrs, max_steps()).0;
 
-        assert_eq!(
-            final_state,
-            ExecutionState {
-                start_instr: Some(&instrs[0]),
-                cells: vec![Wrapping(0)],
-                cell_ptr: 0,
-                outputs: vec![],
-            }
-        );
+        assert_eq!(state.outputs, vec![1, 0]);
+        assert_eq!(residualise(&instrs, &state).prefix_output, &[1]);
+    }
 
-        assert!(warning.is_some());
+    /// `execute_with_state` never defers reads.
+    #[test]
+    fn execute_with_state_stops_at_reads() {
+        let instrs = parse(",.").unwrap();
+        let mut state = ExecutionState::initial(&instrs);
+        let outcome = execute_with_state(&instrs, &mut state, max_steps(), None, DEFAULT_CELL_BITS);
+
+        assert_eq!(outcome, Outcome::ReachedRuntimeValue);
+        assert_eq!(state.start_instr, Some(&instrs[0]));
+        assert_eq!(state.reads, vec![]);
     }
 
     #[test]
-    fn limit_to_steps_specified() {
-        let instrs = parse("++++").unwrap();
-        let final_state = execute(&instrs, 2).0;
+    fn write_executed() {
+        let instrs = parse("+.").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
 
         assert_eq!(
             final_state,
             ExecutionState {
-                start_instr: Some(&instrs[2]),
-                cells: vec![Wrapping(2)],
+                start_instr: None,
+                cells: vec![Wrapping(1)],
                 cell_ptr: 0,
-                outputs: vec![],
+                outputs: vec![1],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 2,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
 
     #[test]
-    fn write_executed() {
-        let instrs = parse("+.").unwrap();
+    fn write_string_executed() {
+        let instrs = vec![WriteString {
+            bytes: vec![104, 255],
+            position: None,
+        }];
         let final_state = execute(&instrs, max_steps()).0;
 
         assert_eq!(
             final_state,
             ExecutionState {
                 start_instr: None,
-                cells: vec![Wrapping(1)],
+                cells: vec![Wrapping(0)],
                 cell_ptr: 0,
-                outputs: vec![1],
+                outputs: vec![104, -1],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 1,
+                loop_counts: HashMap::new(),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
@@ -606,6 +3864,12 @@ mod tests {
                 cells: vec![Wrapping(0)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 7,
+                loop_counts: loop_counts(&[(&instrs[2], 2, 1)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
@@ -617,30 +3881,30 @@ mod tests {
         let instrs = parse("+[[,]]").unwrap();
         let final_state = execute(&instrs, 10).0;
 
-        // Get the inner read instruction
+        // Get the inner loop, which depends on the value read.
         let start_instr = match instrs[1] {
-            Loop { ref body, .. } => match body[0] {
-                Loop {
-                    body: ref body2, ..
-                } => &body2[0],
-                _ => unreachable!(),
-            },
+            Loop { ref body, .. } => &body[0],
             _ => unreachable!(),
         };
-        assert_eq!(
-            *start_instr,
-            Read {
-                position: Some(Position { start: 3, end: 3 })
-            }
-        );
 
         assert_eq!(
             final_state,
             ExecutionState {
                 start_instr: Some(start_instr),
-                cells: vec![Wrapping(1)],
+                cells: vec![Wrapping(0)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![DeferredRead {
+                    cell: Some(0),
+                    count: 1,
+                    outputs_before: 0,
+                    scale: Wrapping(1),
+                }],
+                input: VecDeque::new(),
+                steps: 3,
+                loop_counts: loop_counts(&[(&instrs[1], 1, 0), (start_instr, 1, 0)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
@@ -650,7 +3914,7 @@ mod tests {
         let instrs = parse(",").unwrap();
 
         let mut state = ExecutionState::initial(&instrs[..]);
-        execute_with_state(&instrs[..], &mut state, 5, Some(1));
+        execute_with_state(&instrs[..], &mut state, 5, Some(1), DEFAULT_CELL_BITS);
 
         assert_eq!(state.cells[0], Wrapping(1));
     }
@@ -661,7 +3925,7 @@ mod tests {
         let instrs = parse("+[[,]]").unwrap();
 
         let mut state = ExecutionState::initial(&instrs[..]);
-        let outcome = execute_with_state(&instrs[..], &mut state, 20, Some(0));
+        let outcome = execute_with_state(&instrs[..], &mut state, 20, Some(0), DEFAULT_CELL_BITS);
 
         assert!(matches!(outcome, Outcome::Completed(_)));
     }
@@ -670,8 +3934,8 @@ mod tests {
     /// executing a top-level loop.
     #[test]
     fn partially_execute_complete_toplevel_loop() {
-        let instrs = parse("+[-],").unwrap();
-        let final_state = execute(&instrs, 10).0;
+        let instrs = parse("+[-].").unwrap();
+        let final_state = execute(&instrs, 4).0;
 
         assert_eq!(
             final_state,
@@ -680,6 +3944,12 @@ mod tests {
                 cells: vec![Wrapping(0)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 4,
+                loop_counts: loop_counts(&[(&instrs[1], 1, 1)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
@@ -701,6 +3971,12 @@ mod tests {
                 cells: vec![Wrapping(3)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 3,
+                loop_counts: loop_counts(&[(&instrs[1], 1, 0)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
@@ -719,36 +3995,245 @@ mod tests {
                 cells: vec![Wrapping(1)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 4,
+                loop_counts: loop_counts(&[(&instrs[2], 1, 0)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
 
     #[test]
-    fn loop_with_read_body() {
-        // We can't execute the whole loop, so our start instruction
-        // should be the read.
-        let instrs = parse("+[+,]").unwrap();
-        let final_state = execute(&instrs, 4).0;
+    fn flatten_loops_to_jumps() {
+        let instrs = parse("+[-[.]]").unwrap();
+        let program = flatten(&instrs);
 
-        // Get the inner read instruction
-        let start_instr = match instrs[1] {
-            Loop { ref body, .. } => &body[1],
+        let ops: Vec<_> = program.iter().map(|instr| instr.op.clone()).collect();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                },
+                Op::LoopStart { end: 6 },
+                Op::Increment {
+                    amount: Wrapping(-1),
+                    offset: 0,
+                },
+                Op::LoopStart { end: 5 },
+                Op::Write,
+                Op::LoopEnd {
+                    start: 3,
+                    effect: LoopEffect::KeepsCondition,
+                },
+                Op::LoopEnd {
+                    start: 1,
+                    effect: LoopEffect::Unknown,
+                },
+            ]
+        );
+
+        // Both ends of a loop refer to the loop itself.
+        assert!(std::ptr::eq(program[1].node, &instrs[1]));
+        assert!(std::ptr::eq(program[6].node, &instrs[1]));
+    }
+
+    #[test]
+    fn out_of_steps_at_end_of_loop_body() {
+        // If we run out of steps after the last instruction in a loop
+        // body, runtime execution starts from the loop.
+        let instrs = parse("+[-]").unwrap();
+        let final_state = execute(&instrs, 2).0;
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(final_state.cells, vec![Wrapping(0)]);
+    }
+
+    #[test]
+    fn scan_loop_executed() {
+        let instrs = parse("+>+<[>]").unwrap();
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(final_state.cell_ptr, 2);
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn count_nested_loop_iterations() {
+        let instrs = parse("++[>+++[-]<-]").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        let inner = match instrs[2] {
+            Loop { ref body, .. } => &body[4],
             _ => unreachable!(),
         };
         assert_eq!(
-            *start_instr,
-            Read {
-                position: Some(Position { start: 3, end: 3 })
-            }
+            final_state.loop_counts,
+            loop_counts(&[(&instrs[2], 2, 1), (inner, 6, 2)])
+        );
+    }
+
+    #[test]
+    fn executed_instrs_skips_loops_not_entered() {
+        let instrs = parse("+[-]>[+]").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        let body_ran = match instrs[1] {
+            Loop { ref body, .. } => &body[0],
+            _ => unreachable!(),
+        };
+        let expected: HashSet<_> = vec![&instrs[0], &instrs[1], body_ran, &instrs[2], &instrs[3]]
+            .into_iter()
+            .map(|instr| instr as *const AstNode)
+            .collect();
+        assert_eq!(executed_instrs(&instrs, &final_state), expected);
+    }
+
+    #[test]
+    fn executed_instrs_stopped_in_loop_body() {
+        let instrs = parse("+[>+<-]").unwrap();
+        let body = match instrs[1] {
+            Loop { ref body, .. } => body,
+            _ => unreachable!(),
+        };
+        let state = |iterations| ExecutionState {
+            start_instr: Some(&body[2]),
+            loop_counts: loop_counts(&[(&instrs[1], iterations, 0)]),
+            ..ExecutionState::initial(&instrs)
+        };
+
+        let first_iteration: HashSet<_> = vec![&instrs[0], &instrs[1], &body[0], &body[1]]
+            .into_iter()
+            .map(|instr| instr as *const AstNode)
+            .collect();
+        assert_eq!(executed_instrs(&instrs, &state(1)), first_iteration);
+
+        let mut later_iteration = first_iteration;
+        later_iteration.insert(&body[2] as *const AstNode);
+        later_iteration.insert(&body[3] as *const AstNode);
+        assert_eq!(executed_instrs(&instrs, &state(2)), later_iteration);
+    }
+
+    #[test]
+    fn loops_running_at_most_once_per_entry_are_cold() {
+        let instrs = parse("+[-]++[>+++[-]<-]").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+        let cold: Vec<_> = hot_loops(&instrs, &final_state)
+            .iter()
+            .map(|hot_loop| hot_loop.counts.is_cold())
+            .collect();
+        // Only the first loop, which is the least hot, runs at most
+        // once each time it's entered.
+        assert_eq!(cold, vec![false, false, true]);
+        assert!(!LoopCounts::default().is_cold());
+    }
+
+    #[test]
+    fn hot_loops_sorted_by_iterations() {
+        let instrs = parse("+[-]++[>+++[-]<-]").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        let inner = match instrs[4] {
+            Loop { ref body, .. } => &body[4],
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            hot_loops(&instrs, &final_state),
+            vec![
+                HotLoop {
+                    instr: inner,
+                    counts: LoopCounts {
+                        iterations: 6,
+                        exits: 2
+                    },
+                },
+                HotLoop {
+                    instr: &instrs[4],
+                    counts: LoopCounts {
+                        iterations: 2,
+                        exits: 1
+                    },
+                },
+                HotLoop {
+                    instr: &instrs[1],
+                    counts: LoopCounts {
+                        iterations: 1,
+                        exits: 1
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn count_scan_loop_iterations() {
+        let instrs = parse("+>+<[>]").unwrap();
+        let final_state = execute(&instrs, max_steps()).0;
+
+        assert_eq!(final_state.loop_counts, loop_counts(&[(&instrs[4], 2, 1)]));
+    }
+
+    #[test]
+    fn scan_loop_up_to_step_limit() {
+        // Scanning takes the same number of steps as running the
+        // pointer increment and loop condition each time.
+        let instrs = parse("+>+<[>]").unwrap();
+        let final_state = execute(&instrs, 6).0;
+
+        assert_eq!(final_state.start_instr, Some(&instrs[4]));
+        assert_eq!(final_state.cell_ptr, 1);
+    }
+
+    #[test]
+    fn scan_loop_out_of_bounds() {
+        let instrs = parse("+[<]").unwrap();
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        let body = match instrs[1] {
+            Loop { ref body, .. } => body,
+            _ => unreachable!(),
+        };
+        assert_eq!(final_state.start_instr, Some(&body[0]));
+        assert_eq!(
+            warning,
+            Some(Warning {
+                lint: Lint::OutOfBounds,
+                message: "This instruction moves the pointer to cell -1.".to_owned(),
+                position: Some(Position { start: 2, end: 2 }),
+                notes: vec![],
+            })
         );
+    }
+
+    #[test]
+    fn loop_with_read_body() {
+        // We can't decide whether to run the loop again, so our start
+        // instruction should be the loop.
+        let instrs = parse("+[+,]").unwrap();
+        let final_state = execute(&instrs, 4).0;
 
         assert_eq!(
             final_state,
             ExecutionState {
-                start_instr: Some(start_instr),
-                cells: vec![Wrapping(2)],
+                start_instr: Some(&instrs[1]),
+                cells: vec![Wrapping(0)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![DeferredRead {
+                    cell: Some(0),
+                    count: 1,
+                    outputs_before: 0,
+                    scale: Wrapping(1),
+                }],
+                input: VecDeque::new(),
+                steps: 4,
+                loop_counts: loop_counts(&[(&instrs[1], 1, 0)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
@@ -765,6 +4250,12 @@ mod tests {
                 cells: vec![Wrapping(2)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 2,
+                loop_counts: loop_counts(&[(&instrs[2], 1, 0)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
@@ -781,10 +4272,204 @@ mod tests {
                 cells: vec![Wrapping(11)],
                 cell_ptr: 0,
                 outputs: vec![],
+                reads: vec![],
+                input: VecDeque::new(),
+                steps: 20,
+                loop_counts: loop_counts(&[(&instrs[1], 10, 0)]),
+                branches: vec![],
+                tape: Tape::Fixed,
             }
         );
     }
 
+    #[test]
+    fn warn_loop_never_changing_condition() {
+        let instrs = parse("+[>+<]").unwrap();
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(final_state.cells, vec![Wrapping(1), Wrapping(1)]);
+        assert_eq!(
+            warning,
+            Some(Warning {
+                lint: Lint::InfiniteLoop,
+                message: "This loop never terminates, because its body doesn't change the \
+                          current cell."
+                    .to_owned(),
+                position: Some(Position { start: 1, end: 5 }),
+                notes: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn warn_loop_with_unchanging_iterations() {
+        // +[>+<[-]+]
+        let instrs = vec![
+            Increment {
+                amount: Wrapping(1),
+                offset: 0,
+                position: None,
+            },
+            Loop {
+                body: vec![
+                    Set {
+                        amount: Wrapping(1),
+                        offset: 1,
+                        position: None,
+                    },
+                    Set {
+                        amount: Wrapping(1),
+                        offset: 0,
+                        position: None,
+                    },
+                ],
+                position: None,
+            },
+        ];
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(
+            warning.map(|warning| warning.message),
+            Some(
+                "This loop never terminates, because each iteration leaves the cells unchanged."
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn warn_loop_never_reaching_zero() {
+        let instrs = parse("+[++]").unwrap();
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(final_state.start_instr, Some(&instrs[1]));
+        assert_eq!(
+            warning.map(|warning| warning.message),
+            Some(
+                "This loop never terminates, because each iteration changes the current cell \
+                 by 2, so it never reaches zero from 3."
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn warn_loop_with_unchanging_condition() {
+        let instrs = parse("+[>+<-+]").unwrap();
+        let warning = execute(&instrs, max_steps()).1;
+
+        assert_eq!(
+            warning.map(|warning| warning.message),
+            Some(
+                "This loop never terminates, because each iteration leaves the current cell \
+                 unchanged."
+                    .to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn no_infinite_loop_warning_when_reaching_zero() {
+        // These loops all wrap around to zero eventually.
+        for program in &["+[+]", "++[++]", "++++[++++++]", "+[---]"] {
+            let instrs = parse(program).unwrap();
+            let (final_state, warning) = execute(&instrs, max_steps());
+
+            assert_eq!(final_state.start_instr, None, "{}", program);
+            assert_eq!(warning, None, "{}", program);
+        }
+    }
+
+    #[test]
+    fn reaches_zero_depends_on_low_bits() {
+        assert!(reaches_zero(Wrapping(4), Wrapping(6), 8));
+        assert!(!reaches_zero(Wrapping(2), Wrapping(4), 8));
+        assert!(!reaches_zero(Wrapping(1), Wrapping(256), 8));
+        assert!(reaches_zero(Wrapping(256), Wrapping(256), 16));
+    }
+
+    #[test]
+    fn no_infinite_loop_warning_for_repeated_loop() {
+        // Both loops finish with the cell at 2 after their first
+        // iteration, but they both terminate.
+        let instrs = parse("++++[--]++++[--]").unwrap();
+        let (final_state, warning) = execute(&instrs, max_steps());
+
+        assert_eq!(final_state.start_instr, None);
+        assert_eq!(warning, None);
+    }
+
+    #[test]
+    fn trace_records_cell_deltas() {
+        let instrs = parse(">+<-").unwrap();
+        let mut trace = Trace::new(1, 100);
+        execute_with_trace(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+            &mut trace,
+        );
+
+        let deltas: Vec<_> = trace
+            .events
+            .iter()
+            .map(|event| (event.instr, event.cell_ptr, event.cell_deltas.clone()))
+            .collect();
+        assert_eq!(
+            deltas,
+            vec![
+                (0, 0, vec![]),
+                (1, 1, vec![(1, Wrapping(1))]),
+                (2, 1, vec![]),
+                (3, 0, vec![(0, Wrapping(-1))]),
+            ]
+        );
+        assert_eq!(
+            trace.events[1].position,
+            Some(Position { start: 1, end: 1 })
+        );
+    }
+
+    #[test]
+    fn trace_sampling_and_limit() {
+        let instrs = parse("++++>").unwrap();
+        let mut trace = Trace::new(2, 2);
+        execute_with_trace(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+            &mut trace,
+        );
+
+        let indexes: Vec<_> = trace.events.iter().map(|event| event.index).collect();
+        assert_eq!(indexes, vec![0, 2]);
+        assert_eq!(trace.dropped, 1);
+    }
+
+    #[test]
+    fn trace_omits_instruction_not_executed() {
+        // We can't write the unknown cell at compile time.
+        let instrs = parse(",.").unwrap();
+        let mut trace = Trace::new(1, 100);
+        execute_with_trace(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+            &mut trace,
+        );
+
+        let pcs: Vec<_> = trace.events.iter().map(|event| event.instr).collect();
+        assert_eq!(pcs, vec![0]);
+    }
+
     #[test]
     fn quickcheck_cell_ptr_in_bounds() {
         fn cell_ptr_in_bounds(instrs: Vec<AstNode>) -> bool {
@@ -1,16 +1,38 @@
+use std::ascii;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Read};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Read, Write};
 use std::mem::replace;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
 use getopts::Matches;
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, NamedTempFileOptions};
 use std::collections::HashMap;
 use regex::Regex;
 
-use crate::{bfir, executable_name, execution, link_object_file, llvm, peephole, strip_executable};
-use crate::bfir::{AstNode, Position};
-use crate::diagnostics::{Info, Level};
-use crate::execution::ExecutionState;
+use crate::log::{CompileLog, LogSink};
+use crate::shell;
+use crate::{
+    check_static_executable, executable_name, is_windows_target, library_header, library_name,
+    link_object_files, links_internally, musl_triple, strip_executable, LinkOptions,
+};
+use bfc::bfir::{AstNode, EofBehaviour, Position, DEFAULT_CELL_BITS};
+use bfc::diagnostics::{
+    json_string, Code, DiagnosticSink, ErrorFormat, Info, Label, Level, Lint, Note, Span,
+};
+use bfc::execution::{
+    ExecutionState, ExitStatus, Limits, Outcome, Trace, DEFAULT_MAX_TRACE_EVENTS,
+};
+use bfc::peephole::{OptConfig, OptGoal};
+use bfc::plugin::PassPlugin;
+use bfc::bounds::{BoundsReport, InstrBounds, MAX_CELL_INDEX};
+use bfc::llvm::{
+    CodeModel, CodegenOptions, Coverage, Profile, PtrChecks, RelocationModel, SourceMap,
+    TargetOptions,
+};
+use bfc::asm::AsmOptions;
+use bfc::metadata::BuildMetadata;
+use bfc::{asm, bfir, bounds, cache, execution, llvm, peephole};
 
 pub trait IncludesResolver<R: Read> {
     fn try_include(&mut self, include: String) -> Result<BufReader<R>, Info>;
@@ -70,9 +92,46 @@ pub struct ErrorContext {
     pub file: String,
 }
 
+/// Warning categories allowed by `bfc: allow(CATEGORY, ...)` comments.
+/// A comment before the first instruction applies to the whole file,
+/// and any other comment applies to the line after it.
+#[derive(Debug, Default)]
+pub struct Suppressions {
+    file: Vec<Lint>,
+    lines: HashMap<u64, Vec<Lint>>,
+    line_starts: Vec<usize>,
+}
+
+impl Suppressions {
+    /// Whether a `lint` warning at `position` is allowed.
+    pub fn allows(&self, lint: Lint, position: Option<Position>) -> bool {
+        if self.file.contains(&lint) {
+            return true;
+        }
+        let position = match position {
+            Some(position) => position,
+            None => return false,
+        };
+        let line_idx = match self
+            .line_starts
+            .iter()
+            .filter(|&&start| start <= position.start)
+            .count()
+        {
+            0 => return false,
+            count => count as u64 - 1,
+        };
+        match self.lines.get(&line_idx) {
+            Some(lints) => lints.contains(&lint),
+            None => false,
+        }
+    }
+}
+
 pub struct SingleFileReader {
     inner: BufReader<File>,
     path: String,
+    pub suppressions: Suppressions,
 }
 
 impl SingleFileReader {
@@ -82,14 +141,16 @@ impl SingleFileReader {
             Ok(file) => Ok(SingleFileReader {
                 inner: BufReader::new(file),
                 path,
+                suppressions: Suppressions::default(),
             }),
             Err(e) => Err(Info {
                 level: Level::Error,
+                code: Code::FileError,
                 filename: Some(path),
                 message: format!("{}", e),
-                position: None,
-                source: None,
-                line_col: None,
+                span: None,
+                labels: vec![],
+                notes: vec![],
             }),
         }
     }
@@ -97,43 +158,189 @@ impl SingleFileReader {
     pub fn parse(&mut self) -> Result<Vec<AstNode>, Vec<Info>> {
         let mut offset = 0;
         let mut linenum = 0;
+        let mut line_starts = vec![];
+        let mut lines = vec![];
         let mut buffer = String::default();
 
         let mut instructions = Vec::new();
         let mut stack = Vec::new();
 
+        let allow_directive = Regex::new(r"bfc:\s*allow\(([^)]*)\)").unwrap();
+        let mut suppressions = Suppressions::default();
+
         let mut errors = Vec::new();
         loop {
+            buffer.clear();
             let line_len = match self.inner.read_line(&mut buffer) {
                 Ok(0) => break,
                 Ok(v) => v,
                 Err(e) => {
                     errors.push(Info {
                         level: Level::Error,
+                        code: Code::FileError,
                         filename: Some(self.path.clone()),
                         message: format!("{}", e),
-                        position: None,
-                        source: None,
-                        line_col: None,
+                        span: None,
+                        labels: vec![],
+                        notes: vec![],
                     });
                     return Err(errors);
                 }
             };
+            line_starts.push(offset);
+            lines.push(buffer.clone());
+
+            // Directives contain - and , so blank them out before
+            // parsing the line.
+            let directives: Vec<_> = allow_directive
+                .captures_iter(&buffer)
+                .map(|captures| {
+                    let directive = captures.get(0).unwrap();
+                    (directive.range(), captures[1].to_owned())
+                })
+                .collect();
+            for (range, names) in directives {
+                buffer.replace_range(range.clone(), &" ".repeat(range.len()));
+
+                let mut allowed = vec![];
+                for name in names.split(',').map(str::trim) {
+                    match Lint::from_name(name) {
+                        Some(lint) => allowed.push(lint),
+                        None => errors.push(Info {
+                            level: Level::Error,
+                            code: Code::InvalidOption,
+                            filename: Some(self.path.clone()),
+                            message: format!("Unknown warning category '{}' in bfc: allow", name),
+                            span: Some(Box::new(Span {
+                                position: Position {
+                                    start: offset + range.start,
+                                    end: offset + range.end - 1,
+                                },
+                                source: lines[linenum as usize].clone(),
+                                line_col: (linenum, range.start as u64),
+                            })),
+                            labels: vec![],
+                            notes: vec![],
+                        }),
+                    }
+                }
+
+                if instructions.is_empty() && stack.is_empty() {
+                    suppressions.file.extend(allowed);
+                } else {
+                    suppressions
+                        .lines
+                        .entry(linenum + 1)
+                        .or_insert_with(Vec::new)
+                        .extend(allowed);
+                }
+            }
             if let Err(e) = bfir::parse_inner(&buffer, offset, &mut instructions, &mut stack) {
-                errors.push(Info {
+                let mut info = Info {
                     level: Level::Error,
+                    code: e.code,
                     filename: Some(self.path.clone()),
                     message: e.message,
-                    position: Some(e.position),
-                    source: Some(buffer.clone()),
-                    line_col: Some((linenum, (e.position.start - offset) as u64)),
-                });
+                    span: Some(Box::new(Span {
+                        position: e.position,
+                        source: buffer.clone(),
+                        line_col: (linenum, (e.position.start - offset) as u64),
+                    })),
+                    labels: vec![],
+                    notes: vec![Note::help(
+                        "Remove this ], or add a [ where the loop should start.",
+                    )],
+                };
+
+                // The ] most likely belongs to the latest loop, whose
+                // [ is already closed.
+                let last_loop = instructions
+                    .iter()
+                    .rev()
+                    .find(|instr| matches!(instr, AstNode::Loop { .. }));
+                if let Some(&AstNode::Loop {
+                    position: Some(loop_pos),
+                    ..
+                }) = last_loop
+                {
+                    let line_idx = line_starts
+                        .iter()
+                        .filter(|&&start| start <= loop_pos.start)
+                        .count()
+                        - 1;
+                    info = info.with_label(Label {
+                        position: Position {
+                            start: loop_pos.start,
+                            end: loop_pos.start,
+                        },
+                        source: lines[line_idx].clone(),
+                        line_col: (
+                            line_idx as u64,
+                            (loop_pos.start - line_starts[line_idx]) as u64,
+                        ),
+                        message: "this loop is already closed".to_owned(),
+                    });
+                }
+
+                errors.push(info);
             }
 
             offset += line_len;
             linenum += 1;
         }
 
+        // Any loop still open at the end of the file was never closed.
+        if let Some(&(_, open_index)) = stack.last() {
+            let mut notes = vec![Note::help("Add a ] where the loop should end.")];
+            match stack.len() - 1 {
+                0 => {}
+                1 => notes.push(Note::explain("The loop containing it is never closed either.")),
+                others => notes.push(Note::explain(format!(
+                    "The {} loops containing it are never closed either.",
+                    others
+                ))),
+            }
+
+            let open_line = line_starts
+                .iter()
+                .filter(|&&start| start <= open_index)
+                .count()
+                - 1;
+            // Point just after the last character of the file.
+            let last_line = lines.len() - 1;
+            let last_source = lines[last_line].trim_end_matches('\n');
+            errors.push(Info {
+                level: Level::Error,
+                code: Code::UnmatchedOpen,
+                filename: Some(self.path.clone()),
+                message: "This [ has no matching ]".to_owned(),
+                span: Some(Box::new(Span {
+                    position: Position {
+                        start: open_index,
+                        end: open_index,
+                    },
+                    source: lines[open_line].clone(),
+                    line_col: (
+                        open_line as u64,
+                        (open_index - line_starts[open_line]) as u64,
+                    ),
+                })),
+                labels: vec![Label {
+                    position: Position {
+                        start: line_starts[last_line] + last_source.len(),
+                        end: line_starts[last_line] + last_source.len(),
+                    },
+                    source: last_source.to_owned(),
+                    line_col: (last_line as u64, last_source.len() as u64),
+                    message: "the file ends before the loop is closed".to_owned(),
+                }],
+                notes,
+            });
+        }
+
+        suppressions.line_starts = line_starts;
+        self.suppressions = suppressions;
+
         if !errors.is_empty() {
             Err(errors)
         } else {
@@ -141,52 +348,137 @@ impl SingleFileReader {
         }
     }
 
-    pub fn get_err_context(&mut self, mut idx: u64) -> Result<ErrorContext, Info> {
+    pub fn get_err_context(&mut self, idx: u64) -> Result<ErrorContext, Info> {
+        self.get_span_context(Position {
+            start: idx as usize,
+            end: idx as usize,
+        })
+    }
+
+    /// Like `get_err_context`, but `line` holds every line that
+    /// `position` covers.
+    pub fn get_span_context(&mut self, position: Position) -> Result<ErrorContext, Info> {
         if let Err(e) = self.inner.seek(SeekFrom::Start(0)) {
             return Err(Info {
                 level: Level::Error,
+                code: Code::FileError,
                 filename: Some(self.path.clone()),
                 message: format!("{}", e),
-                position: None,
-                source: None,
-                line_col: None,
+                span: None,
+                labels: vec![],
+                notes: vec![],
             });
         }
 
         let mut line = 0;
+        let mut offset = 0;
         let mut buffer = String::default();
+        let mut line_col = None;
+        let mut lines = String::default();
 
         loop {
+            buffer.clear();
             match self.inner.read_line(&mut buffer) {
-                Ok(0) => return Err(Info::error("Reached EOF before error context could be found")),
+                Ok(0) => break,
                 Ok(len) => {
-                    if len as u64 > idx {
-                        return Ok(ErrorContext {
-                            line_col: (line, idx),
-                            line: buffer,
-                            file: self.path.clone(),
-                        });
+                    if line_col.is_none() && offset + len > position.start {
+                        line_col = Some((line, (position.start - offset) as u64));
+                    }
+                    if line_col.is_some() {
+                        lines.push_str(&buffer);
+                        if offset + len > position.end {
+                            break;
+                        }
                     }
-                    idx -= len as u64;
+                    offset += len;
                     line += 1;
                 }
-                Err(e) => return Err(Info {
-                    level: Level::Error,
-                    filename: Some(self.path.clone()),
-                    message: format!("{}", e),
-                    position: None,
-                    source: None,
-                    line_col: None,
-                }),
+                Err(e) => {
+                    return Err(Info {
+                        level: Level::Error,
+                        code: Code::FileError,
+                        filename: Some(self.path.clone()),
+                        message: format!("{}", e),
+                        span: None,
+                        labels: vec![],
+                        notes: vec![],
+                    })
+                }
             }
         }
+
+        match line_col {
+            Some(line_col) => Ok(ErrorContext {
+                line_col,
+                line: lines,
+                file: self.path.clone(),
+            }),
+            None => Err(Info::error(
+                Code::FileError,
+                "Reached EOF before error context could be found",
+            )),
+        }
+    }
+}
+
+
+/// Passes diagnostics on to another sink, counting them.
+struct CountingSink<'a> {
+    inner: &'a mut dyn DiagnosticSink,
+    count: usize,
+}
+
+impl DiagnosticSink for CountingSink<'_> {
+    fn emit(&mut self, info: Info) {
+        self.count += 1;
+        self.inner.emit(info);
     }
 }
 
+/// Compile the file named in `matches`, sending warnings and errors
+/// to `sink` as they're found. Returns false if there were any.
+pub fn compile_file(matches: &Matches, sink: &mut dyn DiagnosticSink) -> bool {
+    let args: Vec<String> = std::env::args().collect();
+    let log = CompileLog::new(&matches.free[0], &args);
 
-// TODO: return a Vec<Info> that may contain warnings or errors,
-// instead of printing in lots of different places here.
-pub fn compile_file(matches: &Matches) -> Result<(), Vec<Info>> {
+    let succeeded = {
+        let mut log_sink = LogSink {
+            inner: &mut *sink,
+            log: &log,
+        };
+        let mut sink = CountingSink {
+            inner: &mut log_sink,
+            count: 0,
+        };
+        if let Err(errors) = compile(matches, &mut sink, &log) {
+            for error in errors {
+                sink.emit(error);
+            }
+        }
+        sink.count == 0
+    };
+    log.commands(&shell::take_commands());
+
+    if let Some(log_path) = matches.opt_str("log-file") {
+        if let Err(e) = std::fs::write(&log_path, log.finish(succeeded)) {
+            sink.emit(Info::error(
+                Code::FileError,
+                format!("Could not write log to {}: {}", log_path, e),
+            ));
+            return false;
+        }
+    }
+    succeeded
+}
+
+/// Compile the file named in `matches`. Warnings and errors that
+/// don't stop compilation go to `sink`, and the rest are returned.
+/// Phases and pass statistics go to `log`.
+fn compile(
+    matches: &Matches,
+    sink: &mut dyn DiagnosticSink,
+    log: &CompileLog,
+) -> Result<(), Vec<Info>> {
     let path: &String = &matches.free[0];
 
     let mut reader = match SingleFileReader::new(path) {
@@ -194,74 +486,1699 @@ pub fn compile_file(matches: &Matches) -> Result<(), Vec<Info>> {
         Err(e) => return Err(vec![e])
     };
 
+    let parse_start = Instant::now();
     let mut instrs = reader.parse()?;
-    let mut errors = Vec::new();
+    log.phase("parse", parse_start.elapsed());
     let mut unformatted_warnings = Vec::new();
 
-    let opt_level = matches.opt_str("opt").unwrap_or_else(|| String::from("2"));
-    if opt_level != "0" {
-        let pass_specification = matches.opt_str("passes");
-        let (opt_instrs, warnings) = peephole::optimize(instrs, &pass_specification);
+    let disabled_lints = parse_lint_options(matches).map_err(|e| vec![e])?;
+
+    let mut plugins = vec![];
+    for plugin_path in matches.opt_strs("load-pass") {
+        match PassPlugin::load(&plugin_path) {
+            Ok(plugin) => plugins.push(plugin),
+            Err(e) => return Err(vec![e]),
+        }
+    }
+
+    let opt_level_raw = matches.opt_str("opt").unwrap_or_else(|| String::from("2"));
+    let (opt_level, goal) = match opt_level_raw.as_str() {
+        "0" => (0, OptGoal::Speed),
+        "1" => (1, OptGoal::Speed),
+        "2" => (2, OptGoal::Speed),
+        "3" => (3, OptGoal::Speed),
+        // -Os optimises as much as -O2, but prefers smaller code.
+        "s" => (2, OptGoal::Size),
+        _ => {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                format!(
+                    "Invalid optimisation level '{}' (expected 0, 1, 2, 3 or s)",
+                    opt_level_raw
+                ),
+            )])
+        }
+    };
+
+    let fuel = parse_count_option(matches, "opt-fuel", "optimisation fuel").map_err(|e| vec![e])?;
+    let eof = parse_eof_behaviour(matches).map_err(|e| vec![e])?;
+    let cell_bits = parse_cell_size(matches).map_err(|e| vec![e])?;
+    let max_compile_steps =
+        parse_count_option(matches, "max-compile-steps", "step limit").map_err(|e| vec![e])?;
+    let speculate_timeout =
+        parse_count_option(matches, "speculate-timeout", "timeout").map_err(|e| vec![e])?;
+    let speculate_max_output =
+        parse_count_option(matches, "speculate-max-output", "output limit").map_err(|e| vec![e])?;
+    let const_input = matches.opt_str("const-input").unwrap_or_default();
+    let exec_state_format = parse_state_format(matches).map_err(|e| vec![e])?;
+    let trace_path = matches.opt_str("trace-speculation");
+    let trace_every = parse_count_option(matches, "trace-every", "trace sampling interval")
+        .map_err(|e| vec![e])?;
+    let iteration_cap = parse_count_option(matches, "bounds-iteration-cap", "iteration cap")
+        .map_err(|e| vec![e])?
+        .unwrap_or(bounds::DEFAULT_ITERATION_CAP);
+    let stack_tape_limit = parse_count_option(matches, "stack-tape-limit", "cell limit")
+        .map_err(|e| vec![e])?
+        .map_or(llvm::DEFAULT_STACK_TAPE_LIMIT, |cells| cells as usize);
+    let function_size_limit =
+        parse_count_option(matches, "function-size-limit", "function size limit")
+            .map_err(|e| vec![e])?
+            .map_or(llvm::DEFAULT_FUNCTION_SIZE_LIMIT, |instrs| instrs as usize);
+    let cache_dir = matches.opt_str("speculation-cache");
+    let bare_metal = matches.opt_present("bare-metal");
+    if bare_metal && matches.opt_present("freestanding") {
+        return Err(vec![Info::error(
+            Code::InvalidOption,
+            "--bare-metal and --freestanding can't be combined".to_owned(),
+        )]);
+    }
+    let tape_address = match matches.opt_str("tape-address") {
+        Some(_) if !bare_metal => {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                "--tape-address requires --bare-metal".to_owned(),
+            )])
+        }
+        Some(raw) => Some(parse_tape_address(&raw).map_err(|e| vec![e])?),
+        None => None,
+    };
+    // Shared libraries are dynamically linked and use the host's
+    // libc.
+    let library = matches.opt_present("shared");
+    if library {
+        for flag in &["static", "musl", "freestanding", "bare-metal"] {
+            if matches.opt_present(flag) {
+                return Err(vec![Info::error(
+                    Code::InvalidOption,
+                    format!("--shared and --{} can't be combined", flag),
+                )]);
+            }
+        }
+    }
+    // Growing the tape needs realloc, so needs libc. Guard pages
+    // need mmap and a signal handler, which a shared library
+    // shouldn't install in its host, and replace pointer checks.
+    let tape_mode = parse_tape_mode(matches).map_err(|e| vec![e])?;
+    let conflicts: &[&str] = match tape_mode {
+        TapeMode::Fixed => &[],
+        TapeMode::Grow => &["freestanding", "bare-metal"],
+        TapeMode::Guard => &["freestanding", "bare-metal", "shared", "ptr-check"],
+    };
+    for flag in conflicts {
+        if matches.opt_present(flag) {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                format!(
+                    "--tape={} and --{} can't be combined",
+                    matches.opt_str("tape").unwrap_or_default(),
+                    flag
+                ),
+            )]);
+        }
+    }
+    let grow_tape = tape_mode == TapeMode::Grow;
+    // BF_TAPE_SIZE is read with getenv, and guard pages fix the
+    // tape's size.
+    let runtime_tape_size = matches.opt_present("runtime-tape-size");
+    if runtime_tape_size {
+        for flag in &["freestanding", "bare-metal"] {
+            if matches.opt_present(flag) {
+                return Err(vec![Info::error(
+                    Code::InvalidOption,
+                    format!("--runtime-tape-size and --{} can't be combined", flag),
+                )]);
+            }
+        }
+        if tape_mode == TapeMode::Guard {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                "--runtime-tape-size and --tape=guard can't be combined".to_owned(),
+            )]);
+        }
+    }
+    // The debug runtime's signal handlers need an OS, and would
+    // replace the guard page handler or the host's handlers.
+    if matches.opt_present("debug-runtime") {
+        for flag in &["freestanding", "bare-metal", "shared"] {
+            if matches.opt_present(flag) {
+                return Err(vec![Info::error(
+                    Code::InvalidOption,
+                    format!("--debug-runtime and --{} can't be combined", flag),
+                )]);
+            }
+        }
+        if tape_mode == TapeMode::Guard {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                "--debug-runtime and --tape=guard can't be combined".to_owned(),
+            )]);
+        }
+    }
+    // The JIT runs the program in bfc's own process, so it can only
+    // target the host, and can't enforce the --run limits.
+    if matches.opt_present("jit") {
+        if !matches.opt_present("run") {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                "--jit requires --run".to_owned(),
+            )]);
+        }
+        let conflicts = [
+            "target",
+            "musl",
+            "static",
+            "freestanding",
+            "bare-metal",
+            "shared",
+            "lto",
+            "sanitize",
+            "run-max-steps",
+            "run-timeout",
+            "run-max-output",
+            "run-max-cells",
+        ];
+        for flag in &conflicts {
+            if matches.opt_present(flag) {
+                return Err(vec![Info::error(
+                    Code::InvalidOption,
+                    format!("--jit and --{} can't be combined", flag),
+                )]);
+            }
+        }
+    }
+    // The fast backend doesn't use LLVM, and only supports the
+    // usual libc runtime.
+    let backend = parse_backend(matches).map_err(|e| vec![e])?;
+    if backend == Backend::Fast {
+        let conflicts = [
+            "dump-llvm",
+            "dump-llvm-opt",
+            "dump-llvm-diff",
+            "llvm-opt",
+            "llvm-passes",
+            "jit",
+            "lto",
+            "jobs",
+            "mcpu",
+            "mattr",
+            "code-model",
+            "musl",
+            "freestanding",
+            "bare-metal",
+            "shared",
+            "g",
+            "debuggable",
+            "perf-friendly",
+            "debug-runtime",
+            "profile",
+            "coverage",
+            "runtime-tape-size",
+        ];
+        for flag in &conflicts {
+            if matches.opt_present(flag) {
+                return Err(vec![Info::error(
+                    Code::InvalidOption,
+                    format!("--backend=fast and --{} can't be combined", flag),
+                )]);
+            }
+        }
+        if tape_mode != TapeMode::Fixed {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                "--backend=fast only supports --tape=fixed".to_owned(),
+            )]);
+        }
+    }
+    // A debuggable build keeps its symbols and debug info, and only
+    // links sanitizers when asked.
+    let debuggable = matches.opt_present("debuggable");
+    if debuggable && matches.opt_str("strip").as_deref() == Some("yes") {
+        return Err(vec![Info::error(
+            Code::InvalidOption,
+            "--debuggable and --strip=yes can't be combined".to_owned(),
+        )]);
+    }
+    if matches.opt_present("sanitize") {
+        if !debuggable {
+            return Err(vec![Info::error(
+                Code::InvalidOption,
+                "--sanitize requires --debuggable".to_owned(),
+            )]);
+        }
+        // Sanitizer runtimes need libc and dynamic linking.
+        for flag in &["static", "musl", "freestanding", "bare-metal", "shared"] {
+            if matches.opt_present(flag) {
+                return Err(vec![Info::error(
+                    Code::InvalidOption,
+                    format!("--sanitize and --{} can't be combined", flag),
+                )]);
+            }
+        }
+    }
+    // Profiles and coverage reports are written by the program's
+    // exit path, which bare metal programs and libraries don't have.
+    for report in &["profile", "coverage"] {
+        if !matches.opt_present(report) {
+            continue;
+        }
+        for flag in &["freestanding", "bare-metal", "shared"] {
+            if matches.opt_present(flag) {
+                return Err(vec![Info::error(
+                    Code::InvalidOption,
+                    format!("--{} and --{} can't be combined", report, flag),
+                )]);
+            }
+        }
+    }
+
+    let config = OptConfig {
+        pass_specification: matches.opt_str("passes"),
+        goal,
+        level: opt_level,
+        cell_bits,
+        fuel,
+        plugins: &plugins,
+        eof,
+    };
+    let optimise_start = Instant::now();
+    if opt_level > 0 {
+        let (opt_instrs, warnings) = if matches.opt_present("log-file") {
+            let (opt_instrs, warnings, stats) = peephole::optimize_with_stats(instrs, &config);
+            log.passes(&stats);
+            (opt_instrs, warnings)
+        } else {
+            peephole::optimize(instrs, &config)
+        };
         instrs = opt_instrs;
         unformatted_warnings = warnings;
+    } else {
+        // Without optimisation we don't run any lints, but we can
+        // still spot programs that always leave the tape.
+        unformatted_warnings.extend(peephole::tape_overrun_warning(&instrs));
     }
+    log.phase("optimise", optimise_start.elapsed());
 
     if matches.opt_present("dump-ir") {
-        for instr in &instrs {
-            println!("{}", instr);
-        }
+        let report = bounds::bounds_report(&instrs);
+        print!("{}", format_ir_with_bounds(&instrs, &report));
+        return Ok(());
+    }
+
+    if matches.opt_present("dump-bounds") {
+        print!("{}", bounds_report(&instrs, iteration_cap, &mut reader));
         return Ok(());
     }
 
-    let (state, execution_warning) = if opt_level == "2" {
-        execution::execute(&instrs, execution::max_steps())
+    let steps = match max_compile_steps {
+        Some(steps) => steps,
+        // Spend much longer on speculative execution at -O3, so we
+        // can precompute more of large programs.
+        None if opt_level >= 3 => execution::max_steps().saturating_mul(10),
+        None => execution::max_steps(),
+    };
+
+    let mut trace = trace_path
+        .as_ref()
+        .map(|_| Trace::new(trace_every.unwrap_or(1), DEFAULT_MAX_TRACE_EVENTS));
+    let speculation_start = Instant::now();
+    // Speculative execution removes the code it runs, so debuggers
+    // and sanitizers would never see it. The fast backend always
+    // starts from the beginning, and skips it to compile quickly.
+    let (state, outcome) = if opt_level >= 2 && steps > 0 && !debuggable && backend == Backend::Llvm
+    {
+        // Ctrl-C stops speculation early, but we still compile what
+        // we've precomputed.
+        let _interrupt_handler = InterruptHandler::install();
+        let limits = Limits {
+            steps,
+            deadline: speculate_timeout.map(|secs| Instant::now() + Duration::from_secs(secs)),
+            max_output: speculate_max_output
+                .map_or(execution::DEFAULT_MAX_OUTPUT, |bytes| bytes as usize),
+            growable_tape: grow_tape,
+            ..Limits::default()
+        };
+        let cache_key = cache::cache_key(
+            &instrs,
+            const_input.as_bytes(),
+            config.cell_bits,
+            config.eof,
+            &limits,
+        );
+        // A cached result has no trace, so always execute when tracing.
+        let cached = match (&cache_dir, &trace) {
+            (Some(cache_dir), None) => cache::load(Path::new(cache_dir), &cache_key, &instrs),
+            _ => None,
+        };
+        let cache_hit = cached.is_some();
+        let (state, outcome) = match (cached, trace.as_mut()) {
+            (Some(cached), _) => cached,
+            (None, Some(trace)) => execution::execute_with_trace(
+                &instrs,
+                const_input.as_bytes(),
+                config.cell_bits,
+                config.eof,
+                limits,
+                trace,
+            ),
+            (None, None) => execution::execute_with_limits(
+                &instrs,
+                const_input.as_bytes(),
+                config.cell_bits,
+                config.eof,
+                limits,
+            ),
+        };
+
+        // Running out of time depends on the machine, so a later
+        // compile might get further.
+        if let (Some(cache_dir), false) = (&cache_dir, cache_hit) {
+            if outcome != Outcome::OutOfTime && outcome != Outcome::Interrupted {
+                if let Err(e) =
+                    cache::store(Path::new(cache_dir), &cache_key, &instrs, &state, &outcome)
+                {
+                    sink.emit(Info::error(
+                        Code::FileError,
+                        format!("Could not write speculation cache to {}: {}", cache_dir, e),
+                    ));
+                }
+            }
+        }
+        (state, Some(outcome))
     } else {
         let mut init_state = execution::ExecutionState::initial(&instrs[..]);
-        // TODO: this will crash on the empty program.
-        init_state.start_instr = Some(&instrs[0]);
+        // An empty program has nothing to run at runtime either.
+        init_state.start_instr = instrs.first();
+        init_state.input = const_input.bytes().collect();
         (init_state, None)
     };
+    let speculation_time = speculation_start.elapsed();
+    log.phase("speculate", speculation_time);
 
+    if let (Some(trace_path), Some(trace)) = (&trace_path, &trace) {
+        if let Err(e) = std::fs::write(trace_path, trace_json(trace)) {
+            sink.emit(Info::error(
+                Code::FileError,
+                format!("Could not write trace to {}: {}", trace_path, e),
+            ));
+        }
+    }
+
+    let execution_warning = outcome.as_ref().and_then(|outcome| outcome.warning(&state));
     if let Some(execution_warning) = execution_warning {
-        unformatted_warnings.push(execution_warning);
+        // Don't report the same problem twice if a lint already
+        // found it.
+        if !unformatted_warnings
+            .iter()
+            .any(|warning| warning.position == execution_warning.position)
+        {
+            unformatted_warnings.push(execution_warning);
+        }
     }
 
+    // With --strict-bounds, leaving the tape is an error, even if
+    // the warning is disabled.
+    let strict_bounds = matches.opt_present("strict-bounds");
+    let is_overrun = |lint: Lint| lint == Lint::TapeUnderflow || lint == Lint::TapeOverflow;
+    let overrun_error = strict_bounds
+        && unformatted_warnings
+            .iter()
+            .any(|warning| is_overrun(warning.lint));
+    unformatted_warnings.retain(|warning| {
+        let allowed = disabled_lints.contains(&warning.lint)
+            || reader.suppressions.allows(warning.lint, warning.position);
+        !allowed || (strict_bounds && is_overrun(warning.lint))
+    });
+
     for warning in unformatted_warnings {
+        let level = if strict_bounds && is_overrun(warning.lint) {
+            Level::Error
+        } else {
+            Level::Warning
+        };
         let info = match warning.position {
-            Some(Position {start, ..}) => {
-                match reader.get_err_context(start as u64) {
-                    Ok(ErrorContext { line_col, line, file }) => Info {
-                        level: Level::Warning,
-                        filename: Some(file),
-                        message: warning.message,
-                        position: warning.position,
-                        source: Some(line),
-                        line_col: Some(line_col),
-                    },
-                    Err(e) => e,
+            Some(position) => match reader.get_span_context(position) {
+                Ok(ErrorContext {
+                    line_col,
+                    line,
+                    file,
+                }) => Info {
+                    level,
+                    code: Code::Lint(warning.lint),
+                    filename: Some(file),
+                    message: warning.message,
+                    span: Some(Box::new(Span {
+                        position,
+                        source: line,
+                        line_col,
+                    })),
+                    labels: vec![],
+                    notes: warning.notes,
+                },
+                Err(e) => e,
+            },
+            None => Info {
+                level,
+                ..Info::from(warning)
+            },
+        };
+
+        sink.emit(info);
+    }
+
+    // Don't produce a binary that we know will fault. The errors
+    // have already gone to the sink.
+    if overrun_error {
+        return Err(vec![]);
+    }
+
+    // If we can only guess how many cells the program uses, start
+    // with that many and grow the tape at runtime if we guessed
+    // wrong. Without libc we can't grow the tape, so freestanding
+    // and bare metal programs get the whole tape up front. With
+    // --tape=grow, programs that may need more than the usual
+    // allocation grow too.
+    let freestanding = matches.opt_present("freestanding");
+    let tape = bounds::tape_size(&instrs, iteration_cap);
+    let may_outgrow_tape = grow_tape && tape.cells == MAX_CELL_INDEX + 1;
+    let ptr_checks = if tape_mode == TapeMode::Guard {
+        PtrChecks {
+            messages: ptr_check_messages(&instrs, state.cells.len(), "Tape overrun", &mut reader),
+            growable_from: None,
+            grow_without_limit: false,
+        }
+    } else if (tape.heuristic || may_outgrow_tape) && !freestanding && !bare_metal {
+        PtrChecks {
+            messages: ptr_check_messages(&instrs, tape.cells, "Pointer out of bounds", &mut reader),
+            growable_from: Some(tape.cells),
+            grow_without_limit: grow_tape,
+        }
+    } else if matches.opt_present("ptr-check") {
+        PtrChecks {
+            messages: ptr_check_messages(
+                &instrs,
+                state.cells.len(),
+                "Pointer out of bounds",
+                &mut reader,
+            ),
+            growable_from: None,
+            grow_without_limit: false,
+        }
+    } else {
+        PtrChecks::default()
+    };
+    let codegen_options = CodegenOptions {
+        ptr_checks,
+        stack_tape_limit,
+        function_size_limit,
+        perf_friendly: matches.opt_present("perf-friendly") || debuggable,
+        debug_info: if matches.opt_present("g") || debuggable {
+            Some(source_map(path).map_err(|e| vec![e])?)
+        } else {
+            None
+        },
+        freestanding,
+        bare_metal,
+        tape_address,
+        library,
+        // Bare metal programs may never exit, and shared libraries
+        // already collect output in memory.
+        buffered_output: !matches.opt_present("flush-every-write") && !bare_metal && !library,
+        eof,
+        cell_bits,
+        guard_pages: tape_mode == TapeMode::Guard,
+        runtime_tape_size,
+        debug_runtime: matches.opt_present("debug-runtime"),
+        profile: if matches.opt_present("profile") {
+            Some(Profile {
+                loops: profile_loops(&instrs, &mut reader),
+                path: format!("{}.profile", executable_name(path)),
+            })
+        } else {
+            None
+        },
+        coverage: if matches.opt_present("coverage") {
+            let source_map = source_map(path).map_err(|e| vec![e])?;
+            Some(Coverage {
+                instrs: coverage_instrs(&instrs, &source_map),
+                source_path: Path::new(&source_map.directory)
+                    .join(&source_map.path)
+                    .to_string_lossy()
+                    .into_owned(),
+                path: format!("{}.lcov", executable_name(path)),
+            })
+        } else {
+            None
+        },
+        // The JIT doesn't write a binary.
+        metadata: if matches.opt_present("jit") {
+            None
+        } else {
+            Some(build_metadata(path, matches).map_err(|e| vec![e])?)
+        },
+    };
+
+    if matches.opt_present("show-precomputed-output") {
+        print_precomputed_output(&state, outcome.as_ref(), &mut reader);
+    } else if let Some(format) = exec_state_format {
+        let start = describe_start(&state, &mut reader);
+        print!("{}", format_exec_state(&state, start.as_ref(), format));
+    } else if matches.opt_present("hot-loop-report") {
+        print!("{}", hot_loop_report(&instrs, &state, &mut reader));
+    } else if backend == Backend::Fast {
+        let asm_options = AsmOptions {
+            cell_bits,
+            eof,
+            tape_cells: tape.cells,
+            ptr_checks: tape.heuristic || matches.opt_present("ptr-check"),
+            metadata: codegen_options.metadata.clone(),
+        };
+        let codegen_start = Instant::now();
+        let result = handoff_to_asm(path, matches, &instrs, &asm_options);
+        log.phase("codegen", codegen_start.elapsed());
+        result.map_err(|e| vec![e])?;
+    } else {
+        let codegen_start = Instant::now();
+        let result = handoff_to_llvm(path, matches, &instrs[..], &state, config.goal, &codegen_options);
+        log.phase("codegen", codegen_start.elapsed());
+        result.map_err(|e| vec![e])?;
+
+        if matches.opt_present("speculation-summary") {
+            print!(
+                "{}",
+                speculation_summary(&instrs, &state, outcome.as_ref(), speculation_time)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// While this exists, SIGINT stops compile time execution rather
+/// than killing bfc.
+struct InterruptHandler {
+    previous: libc::sighandler_t,
+}
+
+extern "C" fn handle_interrupt(_signal: libc::c_int) {
+    execution::interrupt();
+}
+
+impl InterruptHandler {
+    fn install() -> Self {
+        execution::clear_interrupt();
+        let handler = handle_interrupt as extern "C" fn(libc::c_int);
+        let previous = unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
+        InterruptHandler { previous }
+    }
+}
+
+impl Drop for InterruptHandler {
+    fn drop(&mut self) {
+        unsafe {
+            libc::signal(libc::SIGINT, self.previous);
+        }
+    }
+}
+
+/// Interpret the program with stdin as input, writing its output to
+/// stdout. Every resource can be capped with the `--run-*` options,
+/// so this is safe for running untrusted programs.
+pub fn run_file(matches: &Matches) -> Result<ExitStatus, Vec<Info>> {
+    let mut reader = SingleFileReader::new(&matches.free[0]).map_err(|e| vec![e])?;
+    let instrs = reader.parse()?;
+
+    let max_steps =
+        parse_count_option(matches, "run-max-steps", "step limit").map_err(|e| vec![e])?;
+    let timeout = parse_count_option(matches, "run-timeout", "timeout").map_err(|e| vec![e])?;
+    let max_output =
+        parse_count_option(matches, "run-max-output", "output limit").map_err(|e| vec![e])?;
+    let max_cells =
+        parse_count_option(matches, "run-max-cells", "cell limit").map_err(|e| vec![e])?;
+    let eof = parse_eof_behaviour(matches).map_err(|e| vec![e])?;
+    let cell_bits = parse_cell_size(matches).map_err(|e| vec![e])?;
+    let tape_mode = parse_tape_mode(matches).map_err(|e| vec![e])?;
+
+    let mut input = vec![];
+    if let Err(e) = io::stdin().read_to_end(&mut input) {
+        return Err(vec![Info::error(
+            Code::FileError,
+            format!("Could not read stdin: {}", e),
+        )]);
+    }
+
+    // Optimising first makes interpreting much faster.
+    let config = OptConfig {
+        cell_bits,
+        eof,
+        ..OptConfig::default()
+    };
+    let (instrs, _) = peephole::optimize(instrs, &config);
+    let limits = Limits {
+        steps: max_steps.unwrap_or(u64::MAX),
+        deadline: timeout.map(|secs| Instant::now() + Duration::from_secs(secs)),
+        max_output: max_output.map_or(usize::MAX, |bytes| bytes as usize),
+        max_cells: max_cells.map_or(Limits::default().max_cells, |cells| cells as usize),
+        growable_tape: tape_mode == TapeMode::Grow,
+    };
+    let output = execution::interpret_with_limits(&instrs, &input, cell_bits, eof, limits);
+
+    let mut stdout = io::stdout();
+    if let Err(e) = stdout.write_all(&output.bytes).and_then(|_| stdout.flush()) {
+        return Err(vec![Info::error(
+            Code::FileError,
+            format!("Could not write output: {}", e),
+        )]);
+    }
+    Ok(output.status)
+}
+
+/// Print the output found by speculative execution, and where and
+/// why it stopped.
+fn print_precomputed_output(
+    state: &ExecutionState,
+    outcome: Option<&Outcome>,
+    reader: &mut SingleFileReader,
+) {
+    println!(
+        "Precomputed output ({} bytes): \"{}\"",
+        state.outputs.len(),
+        escape_output(&state.outputs)
+    );
+    if !state.reads.is_empty() {
+        println!("Reads left for runtime: {}", state.reads.len());
+    }
+
+    let (instr_summary, context) = match describe_start(state, reader) {
+        Some(start) => start,
+        None => {
+            println!("The whole program ran at compile time.");
+            return;
+        }
+    };
+
+    let reason = match outcome {
+        Some(outcome) => outcome.description(),
+        None => "speculative execution is disabled".to_owned(),
+    };
+    println!(
+        "Stopped at {}{}: {}",
+        instr_summary,
+        format_location(context.as_ref()),
+        reason
+    );
+}
+
+/// The instruction where runtime execution will begin, summarised on
+/// one line, and where it is in the source. Returns None if the whole
+/// program ran at compile time.
+fn describe_start(
+    state: &ExecutionState,
+    reader: &mut SingleFileReader,
+) -> Option<(String, Option<ErrorContext>)> {
+    let start_instr = state.start_instr?;
+
+    // Loops print their whole body, so only show the first line.
+    let instr_text = start_instr.to_string();
+    let instr_summary = instr_text.lines().next().unwrap_or_default().to_owned();
+    let context = bfir::get_position(start_instr)
+        .and_then(|position| reader.get_err_context(position.start as u64).ok());
+    Some((instr_summary, context))
+}
+
+/// Format `context` as " at FILE:LINE:COLUMN", or an empty string if
+/// we don't know where the instruction is.
+fn format_location(context: Option<&ErrorContext>) -> String {
+    match context {
+        Some(context) => {
+            let (line, column) = context.line_col;
+            format!(" at {}:{}:{}", context.file, line + 1, column + 1)
+        }
+        None => String::new(),
+    }
+}
+
+/// The number of loops shown by `--hot-loop-report`.
+const HOT_LOOP_REPORT_SIZE: usize = 10;
+
+/// Summarise how much of the program compile time execution
+/// precomputed, and why it stopped.
+fn speculation_summary(
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    outcome: Option<&Outcome>,
+    elapsed: Duration,
+) -> String {
+    let total = bfir::count_instrs(instrs);
+    let remaining = bfir::count_instrs(execution::residualise(instrs, state).instrs);
+    let precomputed = total - remaining;
+    let percentage = if total == 0 {
+        100.0
+    } else {
+        precomputed as f64 * 100.0 / total as f64
+    };
+    let reason = match outcome {
+        Some(outcome) => outcome.description(),
+        None => "speculative execution is disabled".to_owned(),
+    };
+    format!(
+        "Speculation summary:\n  IR nodes precomputed: {} of {} ({:.1}%)\n  Steps executed: {}\n  \
+         Time: {:.3}s\n  Output in binary: {} bytes\n  Stopped: {}\n",
+        precomputed,
+        total,
+        percentage,
+        state.steps,
+        elapsed.as_secs_f64(),
+        state.outputs.len(),
+        reason
+    )
+}
+
+/// Describe the loops that ran most often at compile time, and where
+/// they are in the source.
+fn hot_loop_report(
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    reader: &mut SingleFileReader,
+) -> String {
+    let loops = execution::hot_loops(instrs, state);
+    let total: u64 = loops
+        .iter()
+        .map(|hot_loop| hot_loop.counts.iterations)
+        .sum();
+    if total == 0 {
+        return "No loops ran at compile time.\n".to_owned();
+    }
+
+    let mut report = format!(
+        "Hottest loops at compile time ({} iterations in total):\n",
+        total
+    );
+    for hot_loop in loops.iter().take(HOT_LOOP_REPORT_SIZE) {
+        let context = bfir::get_position(hot_loop.instr)
+            .and_then(|position| reader.get_err_context(position.start as u64).ok());
+        report.push_str(&format!(
+            "  {:5.1}% {} iterations, {} exits{}\n",
+            hot_loop.counts.iterations as f64 * 100.0 / total as f64,
+            hot_loop.counts.iterations,
+            hot_loop.counts.exits,
+            format_location(context.as_ref())
+        ));
+    }
+    if loops.len() > HOT_LOOP_REPORT_SIZE {
+        report.push_str(&format!(
+            "  ... and {} more\n",
+            loops.len() - HOT_LOOP_REPORT_SIZE
+        ));
+    }
+    report
+}
+
+/// How a line changed between two texts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineChange {
+    Same,
+    Removed,
+    Added,
+}
+
+/// The lines of context around each change in `unified_diff`.
+const DIFF_CONTEXT_LINES: usize = 3;
+
+/// Find the shortest edit from `before` to `after`, appending each
+/// line index and how it changed to `changes`. Same and removed
+/// lines index `before`, added lines index `after`.
+///
+/// This is Myers' O(ND) algorithm in linear space: we find a point on
+/// the shortest edit by searching from both ends at once, then
+/// recurse either side of it.
+fn diff_range(
+    before: &[usize],
+    after: &[usize],
+    offsets: (usize, usize),
+    changes: &mut Vec<(LineChange, usize)>,
+) {
+    // Common prefixes and suffixes are always part of the shortest
+    // edit, and trimming them guarantees the split below makes
+    // progress.
+    let prefix = before.iter().zip(after).take_while(|(x, y)| x == y).count();
+    let suffix = before[prefix..]
+        .iter()
+        .rev()
+        .zip(after[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    changes.extend((0..prefix).map(|i| (LineChange::Same, offsets.0 + i)));
+    let a = &before[prefix..before.len() - suffix];
+    let b = &after[prefix..after.len() - suffix];
+    let (a_offset, b_offset) = (offsets.0 + prefix, offsets.1 + prefix);
+
+    let middle = if a.is_empty() || b.is_empty() {
+        None
+    } else {
+        middle_point(a, b)
+    };
+    match middle {
+        Some((x, y)) => {
+            diff_range(&a[..x], &b[..y], (a_offset, b_offset), changes);
+            diff_range(&a[x..], &b[y..], (a_offset + x, b_offset + y), changes);
+        }
+        None => {
+            changes.extend((0..a.len()).map(|i| (LineChange::Removed, a_offset + i)));
+            changes.extend((0..b.len()).map(|i| (LineChange::Added, b_offset + i)));
+        }
+    }
+
+    let suffix_start = offsets.0 + before.len() - suffix;
+    changes.extend((0..suffix).map(|i| (LineChange::Same, suffix_start + i)));
+}
+
+/// A point `(x, y)` that the shortest edit from `a` to `b` passes
+/// through, other than the start and the end, or None if `a` and
+/// `b` have no lines in common. `a` and `b` must be non-empty and
+/// differ in their first and last lines.
+fn middle_point(a: &[usize], b: &[usize]) -> Option<(usize, usize)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max_d = (n + m + 1) / 2;
+    let v_offset = max_d;
+    let v_len = 2 * max_d as usize + 2;
+    // The furthest x reached on each diagonal k = x - y, searching
+    // forwards from the start and backwards from the end.
+    let mut forward = vec![-1; v_len];
+    let mut backward = vec![-1; v_len];
+    forward[(v_offset + 1) as usize] = 0;
+    backward[(v_offset + 1) as usize] = 0;
+    let delta = n - m;
+    // If delta is odd, the paths meet while searching forwards.
+    let front = delta % 2 != 0;
+
+    for d in 0..max_d {
+        let mut k = -d;
+        while k <= d {
+            let k_index = (v_offset + k) as usize;
+            let mut x = if k == -d || (k != d && forward[k_index - 1] < forward[k_index + 1]) {
+                forward[k_index + 1]
+            } else {
+                forward[k_index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[k_index] = x;
+            if front && x <= n && y <= m {
+                let other = v_offset + delta - k;
+                if other >= 0
+                    && (other as usize) < v_len
+                    && backward[other as usize] != -1
+                    && x >= n - backward[other as usize]
+                {
+                    return Some((x as usize, y as usize));
                 }
             }
-            None => Info::warn(warning.message),
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let k_index = (v_offset + k) as usize;
+            let mut x = if k == -d || (k != d && backward[k_index - 1] < backward[k_index + 1]) {
+                backward[k_index + 1]
+            } else {
+                backward[k_index - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[(n - x - 1) as usize] == b[(m - y - 1) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[k_index] = x;
+            if !front && x <= n && y <= m {
+                let other = v_offset + delta - k;
+                if other >= 0 && (other as usize) < v_len && forward[other as usize] != -1 {
+                    let forward_x = forward[other as usize];
+                    let forward_y = v_offset + forward_x - other;
+                    if forward_x >= n - x {
+                        return Some((forward_x as usize, forward_y as usize));
+                    }
+                }
+            }
+            k += 2;
+        }
+    }
+    None
+}
+
+/// Compare `before` and `after` line by line, in the unified format
+/// of `diff -u`.
+fn unified_diff(before: &str, after: &str) -> String {
+    // Comparing numbers is much faster than comparing lines.
+    let mut line_ids = HashMap::new();
+    let before_lines: Vec<&str> = before.lines().collect();
+    let after_lines: Vec<&str> = after.lines().collect();
+    let mut line_id = |line| {
+        let next_id = line_ids.len();
+        *line_ids.entry(line).or_insert(next_id)
+    };
+    let before_ids: Vec<usize> = before_lines.iter().map(|line| line_id(*line)).collect();
+    let after_ids: Vec<usize> = after_lines.iter().map(|line| line_id(*line)).collect();
+
+    let mut changes = vec![];
+    diff_range(&before_ids, &after_ids, (0, 0), &mut changes);
+
+    // Show each change with the lines of context around it, merging
+    // changes whose context overlaps.
+    let mut shown = vec![false; changes.len()];
+    for (i, &(change, _)) in changes.iter().enumerate() {
+        if change != LineChange::Same {
+            let start = i.saturating_sub(DIFF_CONTEXT_LINES);
+            let end = (i + DIFF_CONTEXT_LINES + 1).min(changes.len());
+            for shown_line in &mut shown[start..end] {
+                *shown_line = true;
+            }
+        }
+    }
+
+    let mut diff = String::from("--- before\n+++ after\n");
+    // How many lines of `before` and `after` precede each change.
+    let (mut before_pos, mut after_pos) = (0, 0);
+    let mut i = 0;
+    while i < changes.len() {
+        if !shown[i] {
+            match changes[i].0 {
+                LineChange::Same => {
+                    before_pos += 1;
+                    after_pos += 1;
+                }
+                LineChange::Removed => before_pos += 1,
+                LineChange::Added => after_pos += 1,
+            }
+            i += 1;
+            continue;
+        }
+
+        let hunk_end = (i..changes.len())
+            .find(|&j| !shown[j])
+            .unwrap_or(changes.len());
+        let hunk = &changes[i..hunk_end];
+        let before_count = hunk
+            .iter()
+            .filter(|(change, _)| *change != LineChange::Added)
+            .count();
+        let after_count = hunk
+            .iter()
+            .filter(|(change, _)| *change != LineChange::Removed)
+            .count();
+        // Like diff, an empty side names the line before the hunk.
+        let before_start = if before_count > 0 {
+            before_pos + 1
+        } else {
+            before_pos
         };
+        let after_start = if after_count > 0 {
+            after_pos + 1
+        } else {
+            after_pos
+        };
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            before_start, before_count, after_start, after_count
+        ));
+        for &(change, index) in hunk {
+            let (prefix, line) = match change {
+                LineChange::Same => (' ', before_lines[index]),
+                LineChange::Removed => ('-', before_lines[index]),
+                LineChange::Added => ('+', after_lines[index]),
+            };
+            diff.push(prefix);
+            diff.push_str(line);
+            diff.push('\n');
+        }
+        before_pos += before_count;
+        after_pos += after_count;
+        i = hunk_end;
+    }
+    diff
+}
+
+/// Format `instrs` as `--dump-ir` does, with the pointer range before
+/// each instruction.
+fn format_ir_with_bounds(instrs: &[AstNode], report: &BoundsReport) -> String {
+    fn format_instrs<'a>(
+        instrs: &[AstNode],
+        indent: usize,
+        instr_bounds: &mut impl Iterator<Item = &'a InstrBounds>,
+        output: &mut String,
+    ) {
+        for instr in instrs {
+            // Loops print their whole body, so only take the first line.
+            let instr_text = instr.to_string();
+            let line = instr_text.lines().next().unwrap_or_default();
+            match instr_bounds.next() {
+                Some(bounds) => output.push_str(&format!(
+                    "{}{}  ; pointer {}\n",
+                    "  ".repeat(indent),
+                    line,
+                    bounds.pointer
+                )),
+                None => output.push_str(&format!("{}{}\n", "  ".repeat(indent), line)),
+            }
 
-        errors.push(info);
+            if let AstNode::Loop { ref body, .. } = *instr {
+                format_instrs(body, indent + 1, instr_bounds, output);
+            }
+        }
     }
 
-    if let Err(e) = handoff_to_llvm(path, matches, &instrs[..], &state) {
-        errors.push(e);
+    let mut output = String::new();
+    format_instrs(instrs, 0, &mut report.instrs.iter(), &mut output);
+    output
+}
+
+/// Read the BF source at `path`, so `-g` can map positions to lines.
+fn source_map(path: &str) -> Result<SourceMap, Info> {
+    let source = std::fs::read_to_string(path)
+        .map_err(|e| Info::error(Code::FileError, format!("Could not read {}: {}", path, e)))?;
+    let directory = std::env::current_dir()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| ".".to_owned());
+    Ok(SourceMap::new(path, &directory, &source))
+}
+
+/// For `--ptr-check`, find the instructions we can't prove stay on a
+/// tape of `num_cells` cells, and the message to print if they
+/// don't, starting with `problem`.
+fn ptr_check_messages(
+    instrs: &[AstNode],
+    num_cells: usize,
+    problem: &str,
+    reader: &mut SingleFileReader,
+) -> HashMap<*const AstNode, String> {
+    bounds::unproven_instrs(instrs, num_cells)
+        .into_iter()
+        .map(|instr| {
+            let context = bfir::get_position(instr)
+                .and_then(|position| reader.get_err_context(position.start as u64).ok());
+            let message = format!("{}{}", problem, format_location(context.as_ref()));
+            (instr as *const AstNode, message)
+        })
+        .collect()
+}
+
+/// Every loop in `instrs` that has a position, in source order,
+/// with its `file:line:column` for the `--profile` output.
+fn profile_loops(
+    instrs: &[AstNode],
+    reader: &mut SingleFileReader,
+) -> Vec<(*const AstNode, String)> {
+    let mut loops = vec![];
+    for instr in instrs {
+        if let AstNode::Loop { ref body, .. } = *instr {
+            let context = bfir::get_position(instr)
+                .and_then(|position| reader.get_err_context(position.start as u64).ok());
+            if let Some(context) = context {
+                let (line, column) = context.line_col;
+                let location = format!("{}:{}:{}", context.file, line + 1, column + 1);
+                loops.push((instr as *const AstNode, location));
+            }
+            loops.extend(profile_loops(body, reader));
+        }
     }
+    loops
+}
 
-    if !errors.is_empty() {
-        return Err(errors);
+/// Every instruction in `instrs` that has a position, with the line
+/// it starts on, for `--coverage`.
+fn coverage_instrs(instrs: &[AstNode], source_map: &SourceMap) -> Vec<(*const AstNode, usize)> {
+    let mut covered = vec![];
+    for instr in instrs {
+        if let Some(position) = bfir::get_position(instr) {
+            let (line, _) = source_map.line_col(position.start);
+            covered.push((instr as *const AstNode, line));
+        }
+        if let AstNode::Loop { ref body, .. } = *instr {
+            covered.extend(coverage_instrs(body, source_map));
+        }
+    }
+    covered
+}
+
+/// Describe the highest cell the program can reach, the loops that
+/// stopped us bounding it, and how many cells the binary allocates.
+fn bounds_report(
+    instrs: &[AstNode],
+    iteration_cap: u64,
+    reader: &mut SingleFileReader,
+) -> String {
+    let mut report = String::new();
+    match bounds::highest_cell_bound(instrs) {
+        Some(highest) => report.push_str(&format!("Highest cell index: {}\n", highest)),
+        None => {
+            report.push_str("Highest cell index: unknown\n");
+            report.push_str("Loops that may move the pointer right without limit:\n");
+            for unbounded_loop in bounds::unbounded_loops(instrs) {
+                let context = bfir::get_position(unbounded_loop)
+                    .and_then(|position| reader.get_err_context(position.start as u64).ok());
+                report.push_str(&format!("  loop{}\n", format_location(context.as_ref())));
+            }
+        }
+    }
+
+    let tape = bounds::tape_size(instrs, iteration_cap);
+    if tape.heuristic {
+        report.push_str(&format!(
+            "Tape allocation: {} cells, growing to at most {}\n",
+            tape.cells,
+            MAX_CELL_INDEX + 1
+        ));
+    } else if tape.cells == MAX_CELL_INDEX + 1 {
+        report.push_str(&format!("Tape allocation: {} cells (the maximum)\n", tape.cells));
+    } else {
+        report.push_str(&format!("Tape allocation: {} cells\n", tape.cells));
+    }
+    report
+}
+
+/// The formats supported by `--dump-exec-state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StateFormat {
+    Text,
+    Json,
+}
+
+/// Parse the format passed to `--dump-exec-state`, if the option was
+/// given.
+fn parse_state_format(matches: &Matches) -> Result<Option<StateFormat>, Info> {
+    if !matches.opt_present("dump-exec-state") {
+        return Ok(None);
+    }
+    match matches.opt_str("dump-exec-state").as_deref() {
+        None | Some("text") => Ok(Some(StateFormat::Text)),
+        Some("json") => Ok(Some(StateFormat::Json)),
+        Some(other) => Err(Info::error(
+            Code::InvalidOption,
+            format!("Invalid state format '{}' (expected text or json)", other),
+        )),
+    }
+}
+
+/// The tapes supported by `--tape`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TapeMode {
+    /// Stop at the usual allocation.
+    Fixed,
+    /// Grow as far as the program goes.
+    Grow,
+    /// Catch overruns with guard pages rather than checks.
+    Guard,
+}
+
+/// Parse `--tape`.
+fn parse_tape_mode(matches: &Matches) -> Result<TapeMode, Info> {
+    match matches.opt_str("tape").as_deref() {
+        None | Some("fixed") => Ok(TapeMode::Fixed),
+        Some("grow") => Ok(TapeMode::Grow),
+        Some("guard") => Ok(TapeMode::Guard),
+        Some(other) => Err(Info::error(
+            Code::InvalidOption,
+            format!("Invalid tape '{}' (expected fixed, grow or guard)", other),
+        )),
+    }
+}
+
+/// The code generators supported by `--backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Backend {
+    /// Optimise and compile with LLVM.
+    Llvm,
+    /// Write naive x86-64 assembly ourselves, which is much faster.
+    Fast,
+}
+
+/// Parse `--backend`.
+fn parse_backend(matches: &Matches) -> Result<Backend, Info> {
+    match matches.opt_str("backend").as_deref() {
+        None | Some("llvm") => Ok(Backend::Llvm),
+        Some("fast") => Ok(Backend::Fast),
+        Some(other) => Err(Info::error(
+            Code::InvalidOption,
+            format!("Invalid backend '{}' (expected llvm or fast)", other),
+        )),
+    }
+}
+
+/// Parse `--error-format`.
+pub fn parse_error_format(matches: &Matches) -> Result<ErrorFormat, Info> {
+    match matches.opt_str("error-format").as_deref() {
+        None | Some("human") => Ok(ErrorFormat::Human),
+        Some("json") => Ok(ErrorFormat::Json),
+        Some("sarif") => Ok(ErrorFormat::Sarif),
+        Some(other) => Err(Info::error(
+            Code::InvalidOption,
+            format!(
+                "Invalid error format '{}' (expected human, json or sarif)",
+                other
+            ),
+        )),
+    }
+}
+
+/// Parse `--relocation-model`. We only tell the linker about PIE if
+/// asked, so we don't override the platform default unnecessarily.
+fn parse_relocation_model(matches: &Matches) -> Result<Option<RelocationModel>, Info> {
+    match matches.opt_str("relocation-model").as_deref() {
+        None => Ok(None),
+        Some("static") => Ok(Some(RelocationModel::Static)),
+        Some("pic") => Ok(Some(RelocationModel::Pic)),
+        Some(model) => Err(Info::error(
+            Code::InvalidOption,
+            format!(
+                "Invalid relocation model '{}' (expected static or pic)",
+                model
+            ),
+        )),
+    }
+}
+
+/// Parse `--sanitize`, a comma-separated list of the sanitizer
+/// runtimes to link.
+fn parse_sanitizers(raw: &str) -> Result<String, Info> {
+    for sanitizer in raw.split(',') {
+        if !["address", "undefined", "leak"].contains(&sanitizer) {
+            return Err(Info::error(
+                Code::InvalidOption,
+                format!(
+                    "Invalid sanitizer '{}' (expected address, undefined or leak)",
+                    sanitizer
+                ),
+            ));
+        }
+    }
+    Ok(raw.to_owned())
+}
+
+/// Parse `--cell-size`, the width of cells in bits.
+fn parse_cell_size(matches: &Matches) -> Result<u32, Info> {
+    match matches.opt_str("cell-size").as_deref() {
+        None => Ok(DEFAULT_CELL_BITS),
+        Some("8") => Ok(8),
+        Some("16") => Ok(16),
+        Some("32") => Ok(32),
+        Some(other) => Err(Info::error(
+            Code::InvalidOption,
+            format!("Invalid cell size '{}' (expected 8, 16 or 32)", other),
+        )),
+    }
+}
+
+/// Parse `--eof`, which chooses what `,` stores once stdin is
+/// exhausted.
+fn parse_eof_behaviour(matches: &Matches) -> Result<EofBehaviour, Info> {
+    match matches.opt_str("eof").as_deref() {
+        None => Ok(EofBehaviour::default()),
+        Some("unchanged") => Ok(EofBehaviour::Unchanged),
+        Some("0") => Ok(EofBehaviour::Zero),
+        Some("-1") => Ok(EofBehaviour::MinusOne),
+        Some(other) => Err(Info::error(
+            Code::InvalidOption,
+            format!(
+                "Invalid EOF behaviour '{}' (expected unchanged, 0 or -1)",
+                other
+            ),
+        )),
+    }
+}
+
+/// Describe the state after speculative execution: the cells, the
+/// output captured, the steps taken and where runtime execution will
+/// begin (as given by `describe_start`).
+fn format_exec_state(
+    state: &ExecutionState,
+    start: Option<&(String, Option<ErrorContext>)>,
+    format: StateFormat,
+) -> String {
+    let nonzero_cells: Vec<(usize, i32)> = state
+        .cells
+        .iter()
+        .enumerate()
+        .filter(|(_, cell)| cell.0 != 0)
+        .map(|(index, cell)| (index, cell.0))
+        .collect();
+
+    match format {
+        StateFormat::Text => {
+            let mut text = format!(
+                "Steps executed: {}\nOutput: {} bytes\nCell pointer: {}\nNonzero cells:",
+                state.steps,
+                state.outputs.len(),
+                state.cell_ptr
+            );
+            if nonzero_cells.is_empty() {
+                text.push_str(" none");
+            }
+            for (index, value) in &nonzero_cells {
+                text.push_str(&format!("\n  {}: {}", index, value));
+            }
+            match start {
+                Some((instr_summary, context)) => text.push_str(&format!(
+                    "\nRuntime starts at: {}{}\n",
+                    instr_summary,
+                    format_location(context.as_ref())
+                )),
+                None => text.push_str(
+                    "\nRuntime starts at: nothing (the whole program ran at compile time)\n",
+                ),
+            }
+            text
+        }
+        StateFormat::Json => {
+            let cells: Vec<String> = nonzero_cells
+                .iter()
+                .map(|(index, value)| format!("\"{}\": {}", index, value))
+                .collect();
+            let start = match start {
+                Some((instr_summary, Some(context))) => format!(
+                    "{{\"instr\": {}, \"file\": {}, \"line\": {}, \"column\": {}}}",
+                    json_string(instr_summary),
+                    json_string(&context.file),
+                    context.line_col.0 + 1,
+                    context.line_col.1 + 1
+                ),
+                Some((instr_summary, None)) => {
+                    format!("{{\"instr\": {}}}", json_string(instr_summary))
+                }
+                None => "null".to_owned(),
+            };
+            format!(
+                "{{\"steps\": {}, \"output_bytes\": {}, \"cell_ptr\": {}, \
+                 \"nonzero_cells\": {{{}}}, \"start\": {}}}\n",
+                state.steps,
+                state.outputs.len(),
+                state.cell_ptr,
+                cells.join(", "),
+                start
+            )
+        }
+    }
+}
+
+/// Serialise `trace` as JSON, with one event per line.
+fn trace_json(trace: &Trace) -> String {
+    let events: Vec<String> = trace
+        .events
+        .iter()
+        .map(|event| {
+            let position = match event.position {
+                Some(position) => format!("[{}, {}]", position.start, position.end),
+                None => "null".to_owned(),
+            };
+            let deltas: Vec<String> = event
+                .cell_deltas
+                .iter()
+                .map(|(cell_index, delta)| format!("\"{}\": {}", cell_index, delta))
+                .collect();
+            format!(
+                "{{\"index\": {}, \"instr\": {}, \"position\": {}, \"cell_ptr\": {}, \
+                 \"cell_deltas\": {{{}}}}}",
+                event.index,
+                event.instr,
+                position,
+                event.cell_ptr,
+                deltas.join(", ")
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"sample_every\": {}, \"max_events\": {}, \"dropped\": {}, \"events\": [\n{}\n]}}\n",
+        trace.sample_every,
+        trace.max_events,
+        trace.dropped,
+        events.join(",\n")
+    )
+}
+
+/// Escape `outputs` so they can be printed on one line.
+fn escape_output(outputs: &[i8]) -> String {
+    outputs
+        .iter()
+        .flat_map(|byte| ascii::escape_default(*byte as u8))
+        .map(char::from)
+        .collect()
+}
+
+/// Parse the non-negative integer passed to `--NAME`, if any.
+fn parse_count_option(
+    matches: &Matches,
+    name: &str,
+    description: &str,
+) -> Result<Option<u64>, Info> {
+    match matches.opt_str(name) {
+        Some(raw) => match raw.parse::<u64>() {
+            Ok(count) => Ok(Some(count)),
+            Err(_) => Err(Info::error(
+                Code::InvalidOption,
+                format!(
+                    "Invalid {} '{}' (expected a non-negative integer)",
+                    description, raw
+                ),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parse the address passed to `--tape-address`, in hex (with a 0x
+/// prefix) or decimal.
+fn parse_tape_address(raw: &str) -> Result<u64, Info> {
+    let parsed = match raw.strip_prefix("0x").or_else(|| raw.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => raw.parse::<u64>(),
+    };
+    parsed.map_err(|_| {
+        Info::error(
+            Code::InvalidOption,
+            format!("Invalid tape address '{}' (expected e.g. 0x20000000)", raw),
+        )
+    })
+}
+
+/// Return the lints disabled by the user with `-W no-CATEGORY`. Later
+/// options override earlier ones.
+fn parse_lint_options(matches: &Matches) -> Result<Vec<Lint>, Info> {
+    let mut disabled = vec![];
+    for option in matches.opt_strs("W") {
+        let (name, enable) = match option.strip_prefix("no-") {
+            Some(name) => (name, false),
+            None => (&option[..], true),
+        };
+
+        let lint = match Lint::from_name(name) {
+            Some(lint) => lint,
+            None => {
+                let names: Vec<_> = Lint::ALL.iter().map(|lint| lint.name()).collect();
+                return Err(Info::error(
+                    Code::InvalidOption,
+                    format!(
+                        "Unknown warning category '{}' (expected one of: {})",
+                        name,
+                        names.join(", ")
+                    ),
+                ));
+            }
+        };
+
+        disabled.retain(|disabled_lint| *disabled_lint != lint);
+        if !enable {
+            disabled.push(lint);
+        }
+    }
+    Ok(disabled)
+}
+
+/// Write assembly for `instrs` and let the linker assemble and link
+/// it, without LLVM.
+fn handoff_to_asm(
+    outfile: &str,
+    matches: &Matches,
+    instrs: &[AstNode],
+    options: &AsmOptions,
+) -> Result<(), Info> {
+    let target_triple = matches.opt_str("target");
+    let triple = target_triple.clone().unwrap_or_else(default_target_triple);
+    if !asm::supports_target(&triple) {
+        return Err(Info::error(
+            Code::UnsupportedTarget,
+            format!(
+                "--backend=fast doesn't support '{}' (expected x86_64 Linux or a BSD)",
+                triple
+            ),
+        ));
+    }
+
+    // The linker only assembles files ending in .s.
+    let mut asm_file = match NamedTempFileOptions::new().suffix(".s").create() {
+        Ok(asm_file) => asm_file,
+        Err(e) => return Err(Info::error(Code::FileError, format!("{}", e))),
+    };
+    if let Err(e) = asm_file.write_all(asm::compile_to_asm(instrs, options).as_bytes()) {
+        return Err(Info::error(
+            Code::FileError,
+            format!("Could not write assembly: {}", e),
+        ));
+    }
+    let asm_file_path = asm_file
+        .path()
+        .to_str()
+        .expect("path not valid utf-8")
+        .to_owned();
+
+    let link_options = LinkOptions {
+        target_triple,
+        relocation_model: parse_relocation_model(matches)?,
+        lto: false,
+        linker: matches.opt_str("linker"),
+        static_link: matches.opt_present("static"),
+        freestanding: false,
+        shared: false,
+        sanitizers: None,
+    };
+    let output_name = executable_name(outfile);
+    link_object_files(&[asm_file_path], &output_name, &link_options)?;
+    if link_options.static_link {
+        check_static_executable(&output_name)?;
+    }
+    if matches.opt_str("strip").as_deref() != Some("no") {
+        strip_executable(&output_name)?
     }
 
     Ok(())
 }
 
-pub fn handoff_to_llvm(outfile: &str, matches: &Matches, instrs: &[AstNode], state: &ExecutionState) -> Result<(), Info> {
-    llvm::init_llvm();
+/// The host's target triple.
+fn default_target_triple() -> String {
+    llvm::get_default_target_triple()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The target triple we're compiling for, or None for the host.
+/// `--musl` swaps the target's libc for musl.
+fn target_triple(matches: &Matches) -> Option<String> {
     let target_triple = matches.opt_str("target");
-    let mut llvm_module = llvm::compile_to_module(outfile, target_triple.clone(), &instrs, &state);
+    if matches.opt_present("musl") {
+        let triple = target_triple.unwrap_or_else(default_target_triple);
+        return Some(musl_triple(&triple));
+    }
+    target_triple
+}
+
+/// Describe how we're compiling the program at `path`, so we can
+/// embed it in the binary.
+fn build_metadata(path: &str, matches: &Matches) -> Result<BuildMetadata, Info> {
+    let source = match std::fs::read(path) {
+        Ok(source) => source,
+        Err(e) => {
+            return Err(Info::error(
+                Code::FileError,
+                format!("Could not read {}: {}", path, e),
+            ))
+        }
+    };
+    let triple = target_triple(matches).unwrap_or_else(default_target_triple);
+    let options = std::env::args()
+        .skip(1)
+        .filter(|arg| !matches.free.contains(arg))
+        .collect();
+    Ok(BuildMetadata::new(&source, &triple, options))
+}
+
+pub fn handoff_to_llvm(outfile: &str, matches: &Matches, instrs: &[AstNode], state: &ExecutionState, goal: OptGoal, options: &CodegenOptions) -> Result<(), Info> {
+    llvm::init_llvm();
+    let target_triple = target_triple(matches);
+    let triple = target_triple.clone().unwrap_or_else(default_target_triple);
+    if options.freestanding && !llvm::supports_freestanding(&triple) {
+        return Err(Info::error(
+            Code::UnsupportedTarget,
+            format!(
+                "--freestanding doesn't support '{}' (expected Linux on x86, x86_64 or aarch64)",
+                triple
+            ),
+        ));
+    }
+    if options.guard_pages && !llvm::supports_guard_pages(&triple) {
+        return Err(Info::error(
+            Code::UnsupportedTarget,
+            format!(
+                "--tape=guard doesn't support '{}' (expected Linux, macOS or a BSD)",
+                triple
+            ),
+        ));
+    }
+    let wasm = llvm::is_wasm_target(&triple);
+    let windows = is_windows_target(&triple);
+    if options.library && wasm {
+        return Err(Info::error(
+            Code::UnsupportedTarget,
+            "--shared doesn't support WebAssembly".to_owned(),
+        ));
+    }
+    if options.debug_runtime && wasm {
+        return Err(Info::error(
+            Code::UnsupportedTarget,
+            "--debug-runtime doesn't support WebAssembly".to_owned(),
+        ));
+    }
+    if matches.opt_present("sanitize") && wasm {
+        return Err(Info::error(
+            Code::UnsupportedTarget,
+            "--sanitize doesn't support WebAssembly".to_owned(),
+        ));
+    }
+    let mut llvm_module = llvm::compile_to_module_with_options(
+        outfile,
+        target_triple.clone(),
+        instrs,
+        state,
+        options,
+    );
+    let mut target_options = TargetOptions::default();
+    if let Some(cpu) = matches.opt_str("mcpu") {
+        target_options.cpu = cpu;
+    }
+    if let Some(features) = matches.opt_str("mattr") {
+        target_options.features = features;
+    }
+    let relocation_model = parse_relocation_model(matches)?;
+    if options.library && relocation_model == Some(RelocationModel::Static) {
+        return Err(Info::error(
+            Code::InvalidOption,
+            "--shared needs position independent code, not --relocation-model=static".to_owned(),
+        ));
+    }
+    if let Some(model) = relocation_model {
+        target_options.relocation_model = model;
+    } else if wasm || options.bare_metal {
+        // LLVM can't generate position independent WebAssembly, and
+        // bare metal link scripts expect fixed addresses.
+        target_options.relocation_model = RelocationModel::Static;
+    }
+    target_options.code_model = match matches.opt_str("code-model").as_deref() {
+        None => None,
+        Some("small") => Some(CodeModel::Small),
+        Some("medium") => Some(CodeModel::Medium),
+        Some("large") => Some(CodeModel::Large),
+        Some(model) => {
+            return Err(Info::error(
+                Code::InvalidOption,
+                format!(
+                    "Invalid code model '{}' (expected small, medium or large)",
+                    model
+                ),
+            ))
+        }
+    };
+    llvm::set_data_layout(&mut llvm_module, &target_options)?;
 
     if matches.opt_present("dump-llvm") {
         let llvm_ir_cstr = llvm_module.to_cstring();
@@ -269,34 +2186,169 @@ pub fn handoff_to_llvm(outfile: &str, matches: &Matches, instrs: &[AstNode], sta
         println!("{}", llvm_ir);
         return Ok(());
     }
+    // --dump-llvm still shows invalid IR, to help debug it.
+    llvm::verify_module(&llvm_module)?;
 
+    let dump_llvm_diff = matches.opt_present("dump-llvm-diff");
+    let dump_llvm_opt = matches.opt_present("dump-llvm-opt") || dump_llvm_diff;
+    let unoptimised_ir = if dump_llvm_diff {
+        Some(llvm_module.to_cstring())
+    } else {
+        None
+    };
+
+    // When optimising for size, default to -O2 (as clang does for
+    // -Os) and ask LLVM to prefer small code.
+    let (default_llvm_opt, size_level) = match goal {
+        OptGoal::Speed => (3, 0),
+        OptGoal::Size => (2, 1),
+    };
     let llvm_opt_raw = matches
         .opt_str("llvm-opt")
-        .unwrap_or_else(|| "3".to_owned());
-    let mut llvm_opt = llvm_opt_raw.parse::<i64>().unwrap_or(3);
+        .unwrap_or_else(|| default_llvm_opt.to_string());
+    let mut llvm_opt = llvm_opt_raw.parse::<i64>().unwrap_or(default_llvm_opt);
     if llvm_opt < 0 || llvm_opt > 3 {
         // TODO: warn on unrecognised input.
-        llvm_opt = 3;
+        llvm_opt = default_llvm_opt;
+    }
+
+    let llvm_passes = matches.opt_str("llvm-passes");
+    let optimise = |module: &mut llvm::Module| match llvm_passes {
+        Some(ref pipeline) => llvm::optimise_ir_with_pipeline(module, pipeline),
+        None => {
+            llvm::optimise_ir(module, llvm_opt, size_level);
+            Ok(())
+        }
+    };
+
+    if dump_llvm_opt {
+        optimise(&mut llvm_module)?;
+        let llvm_ir_cstr = llvm_module.to_cstring();
+        let llvm_ir = String::from_utf8_lossy(llvm_ir_cstr.as_bytes());
+        match unoptimised_ir {
+            Some(unoptimised_ir) => {
+                let unoptimised_ir = String::from_utf8_lossy(unoptimised_ir.as_bytes());
+                print!("{}", unified_diff(&unoptimised_ir, &llvm_ir));
+            }
+            None => println!("{}", llvm_ir),
+        }
+        return Ok(());
     }
 
+    // The JIT compiles and runs the program in memory, so there's
+    // nothing to write or link.
+    if matches.opt_present("jit") {
+        optimise(&mut llvm_module)?;
+        let status = llvm::run_jit(llvm_module, &target_options)?;
+        if status != 0 {
+            return Err(Info::error(
+                Code::ToolFailure,
+                format!("Program exited with status {}", status),
+            ));
+        }
+        return Ok(());
+    }
 
-    llvm::optimise_ir(&mut llvm_module, llvm_opt);
+    // Bare metal programs are linked by the user with their own
+    // startup code and link script, so just write an object file.
+    if options.bare_metal {
+        let obj_file_path = format!("{}.o", executable_name(outfile));
+        return llvm::write_object_files(
+            &mut llvm_module,
+            &[obj_file_path],
+            &target_options,
+            optimise,
+        );
+    }
 
-    // Compile the LLVM IR to a temporary object file.
-    // let object_file = convert_io_error(NamedTempFile::new())?;
-    let object_file = match NamedTempFile::new() {
-        Ok(v) => v,
-        Err(e) => return Err(Info::error(format!("{}", e))),
+    // With LTO, the linker optimises the whole program, so we
+    // don't split it.
+    let lto = matches.opt_present("lto");
+    let jobs = match parse_count_option(matches, "jobs", "job count")? {
+        Some(0) => {
+            return Err(Info::error(
+                Code::InvalidOption,
+                "--jobs must be at least 1".to_owned(),
+            ))
+        }
+        Some(jobs) if !lto => (jobs as usize).min(llvm::max_jobs(&llvm_module)),
+        _ => 1,
     };
 
-    let obj_file_path = object_file.path().to_str().expect("path not valid utf-8");
-    llvm::write_object_file(&mut llvm_module, &obj_file_path)?;
+    // Compile the LLVM IR to temporary object files.
+    let mut object_files = vec![];
+    for _ in 0..jobs {
+        match NamedTempFile::new() {
+            Ok(object_file) => object_files.push(object_file),
+            Err(e) => return Err(Info::error(Code::FileError, format!("{}", e))),
+        }
+    }
+    let obj_file_paths: Vec<String> = object_files
+        .iter()
+        .map(|object_file| {
+            object_file
+                .path()
+                .to_str()
+                .expect("path not valid utf-8")
+                .to_owned()
+        })
+        .collect();
 
-    let output_name = executable_name(outfile);
-    link_object_file(&obj_file_path, &output_name, target_triple)?;
+    let link_options = LinkOptions {
+        target_triple,
+        relocation_model,
+        lto,
+        linker: matches.opt_str("linker"),
+        static_link: matches.opt_present("static")
+            || matches.opt_present("musl")
+            || options.freestanding,
+        freestanding: options.freestanding,
+        shared: options.library,
+        sanitizers: match matches.opt_str("sanitize") {
+            Some(raw) => Some(parse_sanitizers(&raw)?),
+            None => None,
+        },
+    };
+    if link_options.lto {
+        optimise(&mut llvm_module)?;
+        llvm::write_bitcode_file(&mut llvm_module, &obj_file_paths[0])?;
+    } else {
+        llvm::write_object_files(&mut llvm_module, &obj_file_paths, &target_options, optimise)?;
+    }
 
-    let strip_opt = matches.opt_str("strip").unwrap_or_else(|| "yes".to_owned());
-    if strip_opt == "yes" {
+    let mut output_name = executable_name(outfile);
+    if options.library {
+        output_name = library_name(outfile, &triple);
+        let header_name = format!("{}.h", executable_name(outfile));
+        if let Err(e) = std::fs::write(&header_name, library_header(outfile)) {
+            return Err(Info::error(
+                Code::FileError,
+                format!("Could not write {}: {}", header_name, e),
+            ));
+        }
+    } else if wasm {
+        output_name.push_str(".wasm");
+    } else if windows {
+        output_name.push_str(".exe");
+    }
+    link_object_files(&obj_file_paths, &output_name, &link_options)?;
+    if link_options.static_link {
+        check_static_executable(&output_name)?;
+    }
+
+    // Stripping would remove the debug info we asked for.
+    let default_strip = if options.debug_info.is_some() {
+        "no"
+    } else {
+        "yes"
+    };
+    let strip_opt = matches
+        .opt_str("strip")
+        .unwrap_or_else(|| default_strip.to_owned());
+    // strip doesn't understand WebAssembly, and Windows linkers put
+    // debug info in a separate .pdb rather than the executable. Our
+    // own linker leaves nothing to strip.
+    if strip_opt == "yes" && !wasm && !windows && !links_internally(&link_options)? {
         strip_executable(&output_name)?
     }
 
@@ -305,6 +2357,411 @@ pub fn handoff_to_llvm(outfile: &str, matches: &Matches, instrs: &[AstNode], sta
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use bfc::execution::TraceEvent;
+    use pretty_assertions::assert_eq;
+    use std::io::Write;
+    use std::num::Wrapping;
+
+    fn reader_for(source: &str) -> (NamedTempFile, SingleFileReader) {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        let reader = SingleFileReader::new(file.path().to_str().unwrap()).unwrap();
+        (file, reader)
+    }
+
+    #[test]
+    fn parse_multiple_lines() {
+        let (_file, mut reader) = reader_for("+\n.\n");
+        let instrs = reader.parse().unwrap();
+        assert_eq!(
+            instrs,
+            vec![
+                AstNode::Increment {
+                    amount: Wrapping(1),
+                    offset: 0,
+                    position: Some(Position { start: 0, end: 0 }),
+                },
+                AstNode::Write {
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unclosed_loop() {
+        let (_file, mut reader) = reader_for("+\n[>[-]\n");
+        let errors = reader.parse().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::UnmatchedOpen);
+        let span = errors[0].span.as_ref().unwrap();
+        assert_eq!(span.position, Position { start: 2, end: 2 });
+        assert_eq!(span.line_col, (1, 0));
+        assert_eq!(
+            errors[0].notes,
+            vec![Note::help("Add a ] where the loop should end.")]
+        );
+        assert_eq!(
+            errors[0].labels,
+            vec![Label {
+                position: Position { start: 7, end: 7 },
+                source: "[>[-]".to_owned(),
+                line_col: (1, 5),
+                message: "the file ends before the loop is closed".to_owned(),
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_stray_close_labels_previous_loop() {
+        let (_file, mut reader) = reader_for("+[-]\n>]\n");
+        let errors = reader.parse().unwrap_err();
+        assert_eq!(errors[0].code, Code::UnmatchedClose);
+        assert_eq!(
+            errors[0].notes,
+            vec![Note::help(
+                "Remove this ], or add a [ where the loop should start."
+            )]
+        );
+        assert_eq!(
+            errors[0].labels,
+            vec![Label {
+                position: Position { start: 1, end: 1 },
+                source: "+[-]\n".to_owned(),
+                line_col: (0, 1),
+                message: "this loop is already closed".to_owned(),
+            }]
+        );
+    }
 
+    #[test]
+    fn parse_allow_directive_for_next_line() {
+        let (_file, mut reader) = reader_for("+.\nbfc: allow(dead-loop, redundant-code)\n[-]\n");
+        let instrs = reader.parse().unwrap();
+        // The - and , in the directive aren't instructions.
+        assert_eq!(instrs.len(), 3);
 
+        let loop_pos = Some(Position { start: 41, end: 43 });
+        assert!(reader.suppressions.allows(Lint::DeadLoop, loop_pos));
+        assert!(reader.suppressions.allows(Lint::RedundantCode, loop_pos));
+        assert!(!reader.suppressions.allows(Lint::InfiniteLoop, loop_pos));
+        assert!(!reader
+            .suppressions
+            .allows(Lint::DeadLoop, Some(Position { start: 0, end: 0 })));
+        assert!(!reader.suppressions.allows(Lint::DeadLoop, None));
+    }
+
+    #[test]
+    fn parse_allow_directive_for_file() {
+        let (_file, mut reader) = reader_for("bfc: allow(tape-underflow)\n\n+.\n<.\n");
+        reader.parse().unwrap();
+        assert!(reader
+            .suppressions
+            .allows(Lint::TapeUnderflow, Some(Position { start: 31, end: 31 })));
+        assert!(reader.suppressions.allows(Lint::TapeUnderflow, None));
+        assert!(!reader.suppressions.allows(Lint::DeadLoop, None));
+    }
+
+    #[test]
+    fn parse_allow_directive_unknown_category() {
+        let (_file, mut reader) = reader_for("+.\nbfc: allow(dead-lop)\n");
+        let errors = reader.parse().unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, Code::InvalidOption);
+        assert_eq!(
+            errors[0].message,
+            "Unknown warning category 'dead-lop' in bfc: allow"
+        );
+        assert_eq!(errors[0].span.as_ref().unwrap().line_col, (1, 0));
+    }
+
+    #[test]
+    fn parse_empty_file() {
+        let (_file, mut reader) = reader_for("");
+        assert_eq!(reader.parse().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn err_context_on_later_line() {
+        let (_file, mut reader) = reader_for("+\n>.\n");
+        let context = reader.get_err_context(3).unwrap();
+        assert_eq!(context.line_col, (1, 1));
+        assert_eq!(context.line, ">.\n");
+    }
+
+    #[test]
+    fn span_context_across_lines() {
+        let (_file, mut reader) = reader_for("+\n>.\n<-\n,\n");
+        let context = reader
+            .get_span_context(Position { start: 3, end: 6 })
+            .unwrap();
+        assert_eq!(context.line_col, (1, 1));
+        assert_eq!(context.line, ">.\n<-\n");
+    }
+
+    #[test]
+    fn report_hot_loops() {
+        let (file, mut reader) = reader_for("+[-]\n++[>+++[-]<-]\n");
+        let instrs = reader.parse().unwrap();
+        let (state, _) = execution::execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        );
+
+        let path = file.path().to_str().unwrap();
+        assert_eq!(
+            hot_loop_report(&instrs, &state, &mut reader),
+            format!(
+                "Hottest loops at compile time (9 iterations in total):
+   66.7% 6 iterations, 2 exits at {0}:2:8
+   22.2% 2 iterations, 1 exits at {0}:2:3
+   11.1% 1 iterations, 1 exits at {0}:1:2
+",
+                path
+            )
+        );
+    }
+
+    #[test]
+    fn ir_with_bounds() {
+        let (_file, mut reader) = reader_for("+[>]");
+        let instrs = reader.parse().unwrap();
+        let report = bounds::bounds_report(&instrs);
+        assert_eq!(
+            format_ir_with_bounds(&instrs, &report),
+            "Increment { amount: 1, offset: 0, position: Some(0) }  ; pointer 0
+Loop position: Some(1-3)  ; pointer 0
+  PointerIncrement { amount: 1, position: Some(2) }  ; pointer 0..
+"
+        );
+    }
+
+    #[test]
+    fn ptr_check_unproven_instrs() {
+        let (file, mut reader) = reader_for("+[\n>]");
+        let instrs = reader.parse().unwrap();
+        let messages = ptr_check_messages(&instrs, 10, "Pointer out of bounds", &mut reader);
+
+        let path = file.path().to_str().unwrap();
+        let expected: Vec<_> = vec![format!("Pointer out of bounds at {}:2:1", path)];
+        assert_eq!(messages.values().cloned().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn profile_loops_in_source_order() {
+        let (file, mut reader) = reader_for("+[>+[-]<\n-]");
+        let instrs = reader.parse().unwrap();
+        let loops = profile_loops(&instrs, &mut reader);
+
+        let path = file.path().to_str().unwrap();
+        let locations: Vec<_> = loops.into_iter().map(|(_, location)| location).collect();
+        assert_eq!(
+            locations,
+            vec![format!("{}:1:2", path), format!("{}:1:5", path)]
+        );
+    }
+
+    #[test]
+    fn coverage_instrs_by_line() {
+        let (_file, mut reader) = reader_for("+[\n-]\n.");
+        let instrs = reader.parse().unwrap();
+        let source_map = SourceMap::new("foo.bf", "/tmp", "+[\n-]\n.");
+        let lines: Vec<_> = coverage_instrs(&instrs, &source_map)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect();
+        assert_eq!(lines, vec![1, 1, 2, 3]);
+    }
+
+    #[test]
+    fn report_bounds() {
+        let (_file, mut reader) = reader_for("+[>>+<<-]>");
+        let instrs = reader.parse().unwrap();
+        assert_eq!(
+            bounds_report(&instrs, bounds::DEFAULT_ITERATION_CAP, &mut reader),
+            "Highest cell index: 2\nTape allocation: 3 cells\n"
+        );
+    }
+
+    #[test]
+    fn report_unbounded_loops() {
+        let (file, mut reader) = reader_for(",\n[>]\n");
+        let instrs = reader.parse().unwrap();
+
+        let path = file.path().to_str().unwrap();
+        assert_eq!(
+            bounds_report(&instrs, bounds::DEFAULT_ITERATION_CAP, &mut reader),
+            format!(
+                "Highest cell index: unknown
+Loops that may move the pointer right without limit:
+  loop at {}:2:1
+Tape allocation: 101 cells, growing to at most 100000
+",
+                path
+            )
+        );
+    }
+
+    #[test]
+    fn summarise_speculation() {
+        let (_file, mut reader) = reader_for("+[-]+.,.");
+        let instrs = reader.parse().unwrap();
+        let (state, outcome) = execution::execute_with_limits(
+            &instrs,
+            &[],
+            DEFAULT_CELL_BITS,
+            EofBehaviour::default(),
+            Limits::default(),
+        );
+
+        assert_eq!(
+            speculation_summary(&instrs, &state, Some(&outcome), Duration::from_millis(1500)),
+            "Speculation summary:\n  IR nodes precomputed: 6 of 7 (85.7%)\n  Steps executed: 7\n  \
+             Time: 1.500s\n  Output in binary: 1 bytes\n  \
+             Stopped: the next instruction depends on input\n"
+        );
+    }
+
+    #[test]
+    fn report_no_hot_loops() {
+        let (_file, mut reader) = reader_for("+.");
+        let instrs = reader.parse().unwrap();
+        let state = ExecutionState::initial(&instrs);
+        assert_eq!(
+            hot_loop_report(&instrs, &state, &mut reader),
+            "No loops ran at compile time.\n"
+        );
+    }
+
+    #[test]
+    fn escape_output_printable() {
+        assert_eq!(escape_output(&[104, 105]), "hi");
+    }
+
+    #[test]
+    fn escape_output_special() {
+        assert_eq!(escape_output(&[10, 34, -1]), "\\n\\\"\\xff");
+    }
+
+    #[test]
+    fn trace_as_json() {
+        let mut trace = Trace::new(1, 10);
+        trace.events.push(TraceEvent {
+            index: 0,
+            instr: 3,
+            position: Some(Position { start: 2, end: 4 }),
+            cell_ptr: 1,
+            cell_deltas: vec![(1, Wrapping(-2))],
+        });
+        trace.events.push(TraceEvent {
+            index: 1,
+            instr: 4,
+            position: None,
+            cell_ptr: 1,
+            cell_deltas: vec![],
+        });
+
+        assert_eq!(
+            trace_json(&trace),
+            "{\"sample_every\": 1, \"max_events\": 10, \"dropped\": 0, \"events\": [\n\
+             {\"index\": 0, \"instr\": 3, \"position\": [2, 4], \"cell_ptr\": 1, \
+             \"cell_deltas\": {\"1\": -2}},\n\
+             {\"index\": 1, \"instr\": 4, \"position\": null, \"cell_ptr\": 1, \
+             \"cell_deltas\": {}}\n\
+             ]}\n"
+        );
+    }
+
+    fn example_state() -> ExecutionState<'static> {
+        let mut state = ExecutionState::initial(&[]);
+        state.cells = vec![Wrapping(0), Wrapping(72)];
+        state.cell_ptr = 1;
+        state.outputs = vec![72, 105];
+        state.steps = 42;
+        state
+    }
+
+    #[test]
+    fn format_exec_state_text() {
+        let state = example_state();
+        let start = (
+            "Write".to_owned(),
+            Some(ErrorContext {
+                line_col: (0, 3),
+                line: ">+>.".to_owned(),
+                file: "foo.bf".to_owned(),
+            }),
+        );
+
+        assert_eq!(
+            format_exec_state(&state, Some(&start), StateFormat::Text),
+            "Steps executed: 42\nOutput: 2 bytes\nCell pointer: 1\nNonzero cells:\n  1: 72\n\
+             Runtime starts at: Write at foo.bf:1:4\n"
+        );
+    }
+
+    #[test]
+    fn format_exec_state_json() {
+        let state = example_state();
+
+        assert_eq!(
+            format_exec_state(&state, None, StateFormat::Json),
+            "{\"steps\": 42, \"output_bytes\": 2, \"cell_ptr\": 1, \"nonzero_cells\": {\"1\": 72}, \
+             \"start\": null}\n"
+        );
+    }
+
+    #[test]
+    fn parse_tape_addresses() {
+        assert_eq!(parse_tape_address("0x20000000").unwrap(), 0x2000_0000);
+        assert_eq!(parse_tape_address("4096").unwrap(), 4096);
+        assert!(parse_tape_address("0xZZ").is_err());
+        assert!(parse_tape_address("ram").is_err());
+    }
+
+    #[test]
+    fn unified_diff_of_ir() {
+        let before = "a\nb\nc\nd\ne\nf\ng\nh\ni\n";
+        let after = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        assert_eq!(
+            unified_diff(before, after),
+            "--- before\n+++ after\n\
+             @@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n d\n e\n\
+             @@ -7,3 +7,4 @@\n g\n h\n i\n+j\n"
+        );
+        assert_eq!(unified_diff(before, before), "--- before\n+++ after\n");
+        assert_eq!(
+            unified_diff("", "a\n"),
+            "--- before\n+++ after\n@@ -0,0 +1,1 @@\n+a\n"
+        );
+    }
+
+    #[test]
+    fn diff_finds_shortest_edit() {
+        let before = [1, 2, 3, 1, 2, 2, 1];
+        let after = [3, 2, 1, 2, 1, 3];
+        let mut changes = vec![];
+        diff_range(&before, &after, (0, 0), &mut changes);
+        let same = changes
+            .iter()
+            .filter(|(change, _)| *change == LineChange::Same)
+            .count();
+        // The longest common subsequence has four lines.
+        assert_eq!(same, 4);
+        assert_eq!(changes.len(), before.len() + after.len() - same);
+    }
+
+    #[test]
+    fn parse_sanitizer_lists() {
+        assert_eq!(
+            parse_sanitizers("address,undefined").unwrap(),
+            "address,undefined"
+        );
+        assert!(parse_sanitizers("thread").is_err());
+        assert!(parse_sanitizers("address,").is_err());
+    }
 }
@@ -0,0 +1,79 @@
+#![warn(trivial_numeric_casts)]
+//! bfc is a highly optimising compiler for BF. This library exposes
+//! the compiler's IR, optimisation passes and code generation, so
+//! other tools can use them directly.
+
+#[macro_use]
+extern crate matches;
+
+// Each LLVM release has its own llvm-sys, so we pick one with a
+// feature. `llvm` papers over the differences in the C API. If
+// several are enabled, we only link the first so the error below is
+// the only one.
+#[cfg(feature = "llvm-8")]
+extern crate llvm_sys_80 as llvm_sys;
+#[cfg(all(feature = "llvm-9", not(feature = "llvm-8")))]
+extern crate llvm_sys_90 as llvm_sys;
+#[cfg(all(feature = "llvm-10", not(any(feature = "llvm-8", feature = "llvm-9"))))]
+extern crate llvm_sys_100 as llvm_sys;
+#[cfg(all(
+    feature = "llvm-11",
+    not(any(feature = "llvm-8", feature = "llvm-9", feature = "llvm-10"))
+))]
+extern crate llvm_sys_110 as llvm_sys;
+#[cfg(all(
+    feature = "llvm-14",
+    not(any(
+        feature = "llvm-8",
+        feature = "llvm-9",
+        feature = "llvm-10",
+        feature = "llvm-11"
+    ))
+))]
+extern crate llvm_sys_140 as llvm_sys;
+
+#[cfg(not(any(
+    feature = "llvm-8",
+    feature = "llvm-9",
+    feature = "llvm-10",
+    feature = "llvm-11",
+    feature = "llvm-14"
+)))]
+compile_error!("bfc needs an LLVM version: enable one of the llvm-8, llvm-9, llvm-10, llvm-11 or llvm-14 features");
+
+// llvm-8 is enabled by default, so picking another version needs
+// --no-default-features.
+#[cfg(any(
+    all(feature = "llvm-8", feature = "llvm-9"),
+    all(feature = "llvm-8", feature = "llvm-10"),
+    all(feature = "llvm-8", feature = "llvm-11"),
+    all(feature = "llvm-8", feature = "llvm-14"),
+    all(feature = "llvm-9", feature = "llvm-10"),
+    all(feature = "llvm-9", feature = "llvm-11"),
+    all(feature = "llvm-9", feature = "llvm-14"),
+    all(feature = "llvm-10", feature = "llvm-11"),
+    all(feature = "llvm-10", feature = "llvm-14"),
+    all(feature = "llvm-11", feature = "llvm-14")
+))]
+compile_error!(
+    "bfc can only use one LLVM version: build with --no-default-features \
+     --features llvm-N to pick a version other than LLVM 8"
+);
+
+pub mod asm;
+pub mod bfir;
+pub mod bounds;
+pub mod cache;
+pub mod diagnostics;
+pub mod execution;
+pub mod llvm;
+pub mod metadata;
+pub mod peephole;
+pub mod plugin;
+
+#[cfg(test)]
+mod llvm_tests;
+#[cfg(test)]
+mod peephole_tests;
+#[cfg(test)]
+mod soundness_tests;
@@ -0,0 +1,666 @@
+//! A minimal static linker for x86-64 Linux, so `--freestanding`
+//! programs can be linked without a C toolchain. It only handles what
+//! LLVM emits for them: relocatable objects with no shared libraries,
+//! TLS or common symbols. The executable has no section headers,
+//! symbols or debug info.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+
+use bfc::diagnostics::{Code, Info};
+
+const EM_X86_64: u16 = 62;
+const ET_REL: u16 = 1;
+const ET_EXEC: u16 = 2;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_RELA: u32 = 4;
+const SHT_NOBITS: u32 = 8;
+const SHT_REL: u32 = 9;
+
+const SHF_WRITE: u64 = 0x1;
+const SHF_ALLOC: u64 = 0x2;
+const SHF_TLS: u64 = 0x400;
+
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+const SHN_COMMON: u16 = 0xfff2;
+
+const STB_LOCAL: u8 = 0;
+const STB_WEAK: u8 = 2;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_GOTPCREL: u32 = 9;
+const R_X86_64_32: u32 = 10;
+const R_X86_64_32S: u32 = 11;
+const R_X86_64_PC64: u32 = 24;
+const R_X86_64_GOTPCRELX: u32 = 41;
+const R_X86_64_REX_GOTPCRELX: u32 = 42;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474_e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+/// Where the executable is loaded. This is the usual address for
+/// non-PIE x86-64 executables.
+const BASE_ADDRESS: u64 = 0x40_0000;
+const PAGE_SIZE: u64 = 0x1000;
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+const GOT_ENTRY_SIZE: u64 = 8;
+
+/// An allocated section of an input object.
+struct Section {
+    data: Vec<u8>,
+    size: u64,
+    align: u64,
+    writable: bool,
+    nobits: bool,
+    relocations: Vec<Relocation>,
+    /// Where the section starts in the executable, once laid out.
+    offset: u64,
+    address: u64,
+}
+
+struct Relocation {
+    offset: u64,
+    kind: u32,
+    symbol: usize,
+    addend: i64,
+}
+
+struct Symbol {
+    name: String,
+    binding: u8,
+    section: u16,
+    value: u64,
+}
+
+struct Object {
+    path: String,
+    /// The allocated sections, indexed by section header number.
+    sections: Vec<Option<Section>>,
+    symbols: Vec<Symbol>,
+}
+
+fn malformed(path: &str, what: &str) -> Info {
+    Info::error(
+        Code::ToolFailure,
+        format!("Could not link {}: {}", path, what),
+    )
+}
+
+/// Read a little-endian field of `size` bytes.
+fn read(bytes: &[u8], offset: u64, size: u64) -> Option<u64> {
+    let start = usize::try_from(offset).ok()?;
+    let field = bytes.get(start..start.checked_add(size as usize)?)?;
+    Some(
+        field
+            .iter()
+            .rev()
+            .fold(0, |acc, &byte| acc << 8 | u64::from(byte)),
+    )
+}
+
+fn read_name(bytes: &[u8], strings_offset: u64, offset: u64) -> Option<String> {
+    let start = usize::try_from(strings_offset.checked_add(offset)?).ok()?;
+    let rest = bytes.get(start..)?;
+    let len = rest.iter().position(|&byte| byte == 0)?;
+    Some(String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+/// A section header's fields that we use.
+struct SectionHeader {
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+}
+
+fn parse_object(path: &str, bytes: &[u8]) -> Result<Object, Info> {
+    let field = |offset: u64, size: u64| {
+        read(bytes, offset, size).ok_or_else(|| malformed(path, "truncated object file"))
+    };
+
+    if !bytes.starts_with(b"\x7fELF") || bytes.len() < ELF_HEADER_SIZE as usize {
+        return Err(malformed(path, "not an ELF object file"));
+    }
+    if bytes[4] != 2
+        || bytes[5] != 1
+        || field(0x10, 2)? != u64::from(ET_REL)
+        || field(0x12, 2)? != u64::from(EM_X86_64)
+    {
+        return Err(malformed(
+            path,
+            "the internal linker only links x86-64 ELF object files",
+        ));
+    }
+
+    let section_headers = field(0x28, 8)?;
+    let header_size = field(0x3a, 2)?;
+    let header_count = field(0x3c, 2)?;
+    let mut headers = vec![];
+    for i in 0..header_count {
+        let header = section_headers + i * header_size;
+        headers.push(SectionHeader {
+            kind: field(header + 0x4, 4)? as u32,
+            flags: field(header + 0x8, 8)?,
+            offset: field(header + 0x18, 8)?,
+            size: field(header + 0x20, 8)?,
+            link: field(header + 0x28, 4)? as u32,
+            info: field(header + 0x2c, 4)? as u32,
+            align: field(header + 0x30, 8)?,
+        });
+    }
+
+    let contents = |header: &SectionHeader| -> Result<&[u8], Info> {
+        let start = header.offset as usize;
+        bytes
+            .get(start..start.saturating_add(header.size as usize))
+            .ok_or_else(|| malformed(path, "truncated object file"))
+    };
+
+    let mut sections = vec![];
+    for header in &headers {
+        if header.flags & SHF_ALLOC == 0 {
+            sections.push(None);
+            continue;
+        }
+        if header.flags & SHF_TLS != 0 {
+            return Err(malformed(path, "thread-local storage isn't supported"));
+        }
+        let nobits = header.kind == SHT_NOBITS;
+        sections.push(Some(Section {
+            data: if nobits {
+                vec![]
+            } else {
+                contents(header)?.to_vec()
+            },
+            size: header.size,
+            align: header.align.max(1),
+            writable: header.flags & SHF_WRITE != 0,
+            nobits,
+            relocations: vec![],
+            offset: 0,
+            address: 0,
+        }));
+    }
+
+    let mut symbols = vec![];
+    for header in &headers {
+        match header.kind {
+            SHT_SYMTAB => {
+                let strings = headers
+                    .get(header.link as usize)
+                    .ok_or_else(|| malformed(path, "symbol table without names"))?;
+                for entry in contents(header)?.chunks_exact(24) {
+                    let name_offset = read(entry, 0, 4).unwrap_or(0);
+                    symbols.push(Symbol {
+                        name: read_name(bytes, strings.offset, name_offset)
+                            .ok_or_else(|| malformed(path, "truncated symbol name"))?,
+                        binding: entry[4] >> 4,
+                        section: read(entry, 6, 2).unwrap_or(0) as u16,
+                        value: read(entry, 8, 8).unwrap_or(0),
+                    });
+                }
+            }
+            SHT_REL => {
+                return Err(malformed(path, "REL relocations aren't supported"));
+            }
+            _ => {}
+        }
+    }
+
+    for header in &headers {
+        if header.kind != SHT_RELA {
+            continue;
+        }
+        // Relocations of debug info and other sections we drop.
+        let target = match sections.get_mut(header.info as usize) {
+            Some(Some(section)) => section,
+            _ => continue,
+        };
+        for entry in contents(header)?.chunks_exact(24) {
+            let info = read(entry, 8, 8).unwrap_or(0);
+            target.relocations.push(Relocation {
+                offset: read(entry, 0, 8).unwrap_or(0),
+                kind: info as u32,
+                symbol: (info >> 32) as usize,
+                addend: read(entry, 16, 8).unwrap_or(0) as i64,
+            });
+        }
+    }
+
+    Ok(Object {
+        path: path.to_owned(),
+        sections,
+        symbols,
+    })
+}
+
+fn is_got_relocation(kind: u32) -> bool {
+    matches!(
+        kind,
+        R_X86_64_GOTPCREL | R_X86_64_GOTPCRELX | R_X86_64_REX_GOTPCRELX
+    )
+}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    value.div_ceil(align) * align
+}
+
+/// The address of a symbol defined in `object`, if it's defined.
+fn defined_address(object: &Object, symbol: &Symbol) -> Result<Option<u64>, Info> {
+    match symbol.section {
+        SHN_UNDEF => Ok(None),
+        SHN_ABS => Ok(Some(symbol.value)),
+        SHN_COMMON => Err(malformed(
+            &object.path,
+            &format!("common symbol '{}' isn't supported", symbol.name),
+        )),
+        section => match object.sections.get(section as usize) {
+            Some(Some(section)) => Ok(Some(section.address.wrapping_add(symbol.value))),
+            // A symbol in a section we didn't keep, such as debug info.
+            _ => Ok(None),
+        },
+    }
+}
+
+/// The address of the symbol at `index` in `object`'s symbol table.
+fn symbol_address(
+    object: &Object,
+    index: usize,
+    globals: &HashMap<&str, (u64, bool)>,
+) -> Result<u64, Info> {
+    let symbol = object
+        .symbols
+        .get(index)
+        .ok_or_else(|| malformed(&object.path, "relocation of a missing symbol"))?;
+    let address = if symbol.binding == STB_LOCAL {
+        defined_address(object, symbol)?
+    } else {
+        globals
+            .get(symbol.name.as_str())
+            .map(|&(address, _)| address)
+    };
+    match address {
+        Some(address) => Ok(address),
+        // Undefined weak symbols are null.
+        None if symbol.binding == STB_WEAK => Ok(0),
+        None => Err(malformed(
+            &object.path,
+            &format!("undefined symbol '{}'", symbol.name),
+        )),
+    }
+}
+
+/// Link x86-64 ELF object files into a static executable, entered at
+/// `_start`.
+pub fn link(objects: &[(String, Vec<u8>)]) -> Result<Vec<u8>, Info> {
+    let mut objects = objects
+        .iter()
+        .map(|(path, bytes)| parse_object(path, bytes))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Read-only sections share a segment with the headers. Writable
+    // sections go on the next page, with zeroed sections last so
+    // they don't need space in the file.
+    let has_writable = objects
+        .iter()
+        .flat_map(|object| object.sections.iter().flatten())
+        .any(|section| section.writable || section.nobits);
+    let program_headers = if has_writable { 3 } else { 2 };
+    let mut end = ELF_HEADER_SIZE + program_headers * PROGRAM_HEADER_SIZE;
+    let place = |section: &mut Section, end: &mut u64| {
+        *end = align_up(*end, section.align);
+        section.offset = *end;
+        section.address = BASE_ADDRESS + *end;
+        *end += section.size;
+    };
+    for section in objects
+        .iter_mut()
+        .flat_map(|object| object.sections.iter_mut().flatten())
+        .filter(|section| !section.writable && !section.nobits)
+    {
+        place(section, &mut end);
+    }
+
+    // Symbols loaded through the global offset table get a slot at
+    // the end of the read-only sections, as their addresses are
+    // known when linking.
+    let mut got_slots: HashMap<(usize, usize), u64> = HashMap::new();
+    for (i, object) in objects.iter().enumerate() {
+        for relocation in object
+            .sections
+            .iter()
+            .flatten()
+            .flat_map(|section| &section.relocations)
+            .filter(|relocation| is_got_relocation(relocation.kind))
+        {
+            let slots = got_slots.len() as u64;
+            got_slots.entry((i, relocation.symbol)).or_insert(slots);
+        }
+    }
+    end = align_up(end, GOT_ENTRY_SIZE);
+    let got_offset = end;
+    end += got_slots.len() as u64 * GOT_ENTRY_SIZE;
+    let text_end = end;
+
+    let writable_start = align_up(end, PAGE_SIZE);
+    end = writable_start;
+    for section in objects
+        .iter_mut()
+        .flat_map(|object| object.sections.iter_mut().flatten())
+        .filter(|section| section.writable && !section.nobits)
+    {
+        place(section, &mut end);
+    }
+    let file_end = end;
+    for section in objects
+        .iter_mut()
+        .flat_map(|object| object.sections.iter_mut().flatten())
+        .filter(|section| section.nobits)
+    {
+        place(section, &mut end);
+    }
+    let memory_end = end;
+
+    // Resolve global symbols. A strong definition wins over a weak
+    // one, but two strong definitions conflict.
+    let mut globals: HashMap<&str, (u64, bool)> = HashMap::new();
+    for object in &objects {
+        for symbol in object.symbols.iter().filter(|s| s.binding != STB_LOCAL) {
+            let address = match defined_address(object, symbol)? {
+                Some(address) => address,
+                None => continue,
+            };
+            let weak = symbol.binding == STB_WEAK;
+            match globals.get(symbol.name.as_str()) {
+                Some(&(_, false)) if !weak => {
+                    return Err(malformed(
+                        &object.path,
+                        &format!("duplicate symbol '{}'", symbol.name),
+                    ))
+                }
+                Some(&(_, existing_weak)) if weak || !existing_weak => {}
+                _ => {
+                    globals.insert(&symbol.name, (address, weak));
+                }
+            }
+        }
+    }
+
+    let entry = match globals.get("_start") {
+        Some(&(address, _)) => address,
+        None => {
+            return Err(Info::error(
+                Code::ToolFailure,
+                "Could not link: no _start symbol".to_owned(),
+            ))
+        }
+    };
+
+    let mut output = vec![0; file_end as usize];
+    for object in &objects {
+        for section in object.sections.iter().flatten() {
+            if section.nobits {
+                continue;
+            }
+            let start = section.offset as usize;
+            output[start..start + section.data.len()].copy_from_slice(&section.data);
+        }
+    }
+
+    for (&(i, symbol), &slot) in &got_slots {
+        let target = symbol_address(&objects[i], symbol, &globals)?;
+        write(
+            &mut output,
+            (got_offset + slot * GOT_ENTRY_SIZE) as usize,
+            8,
+            target,
+        );
+    }
+
+    for (i, object) in objects.iter().enumerate() {
+        for section in object.sections.iter().flatten() {
+            for relocation in &section.relocations {
+                let target = if is_got_relocation(relocation.kind) {
+                    let slot = got_slots[&(i, relocation.symbol)];
+                    BASE_ADDRESS + got_offset + slot * GOT_ENTRY_SIZE
+                } else {
+                    symbol_address(object, relocation.symbol, &globals)?
+                };
+                let value = target.wrapping_add(relocation.addend as u64);
+                let place = section.address + relocation.offset;
+                let pc_relative = value.wrapping_sub(place) as i64;
+
+                let (value, size) = match relocation.kind {
+                    R_X86_64_NONE => continue,
+                    R_X86_64_64 => (value, 8),
+                    R_X86_64_PC64 => (pc_relative as u64, 8),
+                    R_X86_64_PC32
+                    | R_X86_64_PLT32
+                    | R_X86_64_GOTPCREL
+                    | R_X86_64_GOTPCRELX
+                    | R_X86_64_REX_GOTPCRELX
+                        if i32::try_from(pc_relative).is_ok() =>
+                    {
+                        (pc_relative as u64, 4)
+                    }
+                    R_X86_64_32 if u32::try_from(value).is_ok() => (value, 4),
+                    R_X86_64_32S if i32::try_from(value as i64).is_ok() => (value, 4),
+                    R_X86_64_PC32
+                    | R_X86_64_PLT32
+                    | R_X86_64_32
+                    | R_X86_64_32S
+                    | R_X86_64_GOTPCREL
+                    | R_X86_64_GOTPCRELX
+                    | R_X86_64_REX_GOTPCRELX => {
+                        return Err(malformed(&object.path, "relocation out of range"))
+                    }
+                    kind => {
+                        return Err(malformed(
+                            &object.path,
+                            &format!("unsupported relocation type {}", kind),
+                        ))
+                    }
+                };
+                if section.nobits || relocation.offset + size > section.size {
+                    return Err(malformed(&object.path, "relocation outside its section"));
+                }
+                let start = (section.offset + relocation.offset) as usize;
+                output[start..start + size as usize]
+                    .copy_from_slice(&value.to_le_bytes()[..size as usize]);
+            }
+        }
+    }
+
+    // The ELF header.
+    output[..4].copy_from_slice(b"\x7fELF");
+    output[4] = 2; // 64-bit
+    output[5] = 1; // little endian
+    output[6] = 1; // ELF version 1
+    write(&mut output, 0x10, 2, u64::from(ET_EXEC));
+    write(&mut output, 0x12, 2, u64::from(EM_X86_64));
+    write(&mut output, 0x14, 4, 1);
+    write(&mut output, 0x18, 8, entry);
+    write(&mut output, 0x20, 8, ELF_HEADER_SIZE);
+    write(&mut output, 0x34, 2, ELF_HEADER_SIZE);
+    write(&mut output, 0x36, 2, PROGRAM_HEADER_SIZE);
+    write(&mut output, 0x38, 2, program_headers);
+    write(&mut output, 0x3a, 2, 64);
+
+    let mut segments = vec![(PT_LOAD, PF_R | PF_X, 0, text_end, text_end)];
+    if has_writable {
+        segments.push((
+            PT_LOAD,
+            PF_R | PF_W,
+            writable_start,
+            file_end - writable_start,
+            memory_end - writable_start,
+        ));
+    }
+    segments.push((PT_GNU_STACK, PF_R | PF_W, 0, 0, 0));
+    for (i, &(kind, flags, offset, file_size, memory_size)) in segments.iter().enumerate() {
+        let header = ELF_HEADER_SIZE as usize + i * PROGRAM_HEADER_SIZE as usize;
+        let address = if kind == PT_LOAD {
+            BASE_ADDRESS + offset
+        } else {
+            0
+        };
+        write(&mut output, header, 4, u64::from(kind));
+        write(&mut output, header + 0x4, 4, u64::from(flags));
+        write(&mut output, header + 0x8, 8, offset);
+        write(&mut output, header + 0x10, 8, address);
+        write(&mut output, header + 0x18, 8, address);
+        write(&mut output, header + 0x20, 8, file_size);
+        write(&mut output, header + 0x28, 8, memory_size);
+        write(
+            &mut output,
+            header + 0x30,
+            8,
+            if kind == PT_LOAD { PAGE_SIZE } else { 16 },
+        );
+    }
+
+    Ok(output)
+}
+
+/// Write a little-endian field of `size` bytes.
+fn write(output: &mut [u8], offset: usize, size: usize, value: u64) {
+    output[offset..offset + size].copy_from_slice(&value.to_le_bytes()[..size]);
+}
+
+/// Link the object files at `object_file_paths` into an executable.
+pub fn link_files(object_file_paths: &[String], executable_path: &str) -> Result<(), Info> {
+    let mut objects = vec![];
+    for path in object_file_paths {
+        match fs::read(path) {
+            Ok(bytes) => objects.push((path.clone(), bytes)),
+            Err(e) => {
+                return Err(Info::error(
+                    Code::FileError,
+                    format!("Could not read {}: {}", path, e),
+                ))
+            }
+        }
+    }
+    let executable = link(&objects)?;
+
+    let written =
+        fs::write(executable_path, executable).and_then(|()| make_executable(executable_path));
+    written.map_err(|e| {
+        Info::error(
+            Code::FileError,
+            format!("Could not write {}: {}", executable_path, e),
+        )
+    })
+}
+
+#[cfg(unix)]
+fn make_executable(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bfc::bfir::parse_inner;
+    use bfc::execution::ExecutionState;
+    use bfc::llvm::{
+        compile_to_module_with_options, init_llvm, set_data_layout, write_object_file,
+        CodegenOptions, TargetOptions,
+    };
+    use pretty_assertions::assert_eq;
+    use std::process::Command;
+    use tempfile::{NamedTempFile, NamedTempFileOptions};
+
+    /// Compile `source` to an x86-64 Linux object file, running all
+    /// of it at runtime.
+    fn compile_object(source: &str, freestanding: bool) -> (NamedTempFile, String, Vec<u8>) {
+        init_llvm();
+        let mut instrs = vec![];
+        parse_inner(source, 0, &mut instrs, &mut vec![]).unwrap();
+        let mut module = compile_to_module_with_options(
+            "foo",
+            Some("x86_64-unknown-linux-gnu".to_owned()),
+            &instrs,
+            &ExecutionState {
+                start_instr: Some(&instrs[0]),
+                ..ExecutionState::initial(&instrs)
+            },
+            &CodegenOptions {
+                freestanding,
+                ..CodegenOptions::default()
+            },
+        );
+        let options = TargetOptions::default();
+        set_data_layout(&mut module, &options).unwrap();
+
+        let object_file = NamedTempFileOptions::new().suffix(".o").create().unwrap();
+        let object_path = object_file.path().to_str().unwrap().to_owned();
+        write_object_file(&mut module, &object_path, &options).unwrap();
+        let bytes = fs::read(&object_path).unwrap();
+        (object_file, object_path, bytes)
+    }
+
+    #[test]
+    fn link_not_an_object_file() {
+        let result = link(&[("foo.o".to_owned(), b"not an object".to_vec())]);
+        assert_eq!(
+            result.unwrap_err().message,
+            "Could not link foo.o: not an ELF object file"
+        );
+    }
+
+    #[test]
+    fn link_needs_start() {
+        // With libc, the program starts at main and libc provides
+        // _start.
+        let (_file, path, bytes) = compile_object("+.", false);
+        let result = link(&[(path, bytes)]);
+        assert_eq!(
+            result.unwrap_err().message,
+            "Could not link: no _start symbol"
+        );
+    }
+
+    #[test]
+    fn link_duplicate_symbols() {
+        let (_file, path, bytes) = compile_object("+.", true);
+        let object = (path.clone(), bytes);
+        let result = link(&[object.clone(), object]);
+        assert!(result
+            .unwrap_err()
+            .message
+            .starts_with(&format!("Could not link {}: duplicate symbol", path)));
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn link_and_run_freestanding_program() {
+        let (_file, object_path, _) = compile_object("++++++++[>++++++++<-]>+.", true);
+        let executable_path = format!("{}.out", object_path);
+        link_files(&[object_path], &executable_path).unwrap();
+
+        let output = Command::new(&executable_path).output().unwrap();
+        fs::remove_file(&executable_path).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"A");
+    }
+}
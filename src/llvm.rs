@@ -1,26 +1,60 @@
 //! The LLVM module handles converting a BF AST to LLVM IR.
 
+// LLVM 14 deprecates the typed pointer API in favour of one older
+// LLVMs don't have.
+#![cfg_attr(feature = "llvm-14", allow(deprecated))]
+
 use itertools::Itertools;
+use llvm_sys::analysis::{LLVMVerifierFailureAction, LLVMVerifyModule};
+use llvm_sys::bit_reader::LLVMParseBitcodeInContext2;
+use llvm_sys::bit_writer::{LLVMWriteBitcodeToFile, LLVMWriteBitcodeToMemoryBuffer};
 use llvm_sys::core::*;
+use llvm_sys::debuginfo::*;
+use llvm_sys::error::{
+    LLVMConsumeError, LLVMDisposeErrorMessage, LLVMErrorRef, LLVMGetErrorMessage,
+};
+#[cfg(not(feature = "llvm-14"))]
+use llvm_sys::orc::*;
+#[cfg(feature = "llvm-14")]
+use llvm_sys::orc2::{lljit::*, *};
 use llvm_sys::prelude::*;
+#[cfg(not(feature = "llvm-14"))]
+use llvm_sys::support::{LLVMLoadLibraryPermanently, LLVMSearchForAddressOfSymbol};
 use llvm_sys::target::*;
 use llvm_sys::target_machine::*;
+#[cfg(feature = "llvm-14")]
+use llvm_sys::transforms::pass_builder::*;
+#[cfg(not(feature = "llvm-14"))]
 use llvm_sys::transforms::pass_manager_builder::*;
-use llvm_sys::{LLVMBuilder, LLVMIntPredicate, LLVMModule};
+#[cfg(not(feature = "llvm-14"))]
+use llvm_sys::transforms::{instcombine, ipo, scalar, util, vectorize};
+use llvm_sys::{
+    LLVMAttributeFunctionIndex, LLVMAttributeReturnIndex, LLVMBuilder, LLVMDLLStorageClass,
+    LLVMInlineAsmDialect, LLVMIntPredicate, LLVMLinkage, LLVMModule, LLVMModuleFlagBehavior,
+    LLVMVisibility,
+};
 
 use std::ffi::{CStr, CString};
+use std::num::Wrapping;
+#[cfg(not(feature = "llvm-14"))]
+use std::os::raw::{c_char, c_void};
 use std::os::raw::{c_uint, c_ulonglong};
 use std::ptr::null_mut;
+use std::slice;
 use std::str;
+use std::thread;
 
-use std::collections::HashMap;
-use std::num::Wrapping;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{AstNode, Cell};
+use crate::bfir::{
+    count_instrs, get_position, AstNode, Cell, EofBehaviour, Position, DEFAULT_CELL_BITS,
+};
+use crate::bounds::accessed_offsets;
 
-use crate::execution::ExecutionState;
-use crate::diagnostics::Info;
+use crate::diagnostics::{Code, Info};
+use crate::execution::{contains_instr, executed_instrs, residualise, ExecutionState, LoopCounts};
+use crate::metadata::{self, BuildMetadata};
 
 const LLVM_FALSE: LLVMBool = 0;
 const LLVM_TRUE: LLVMBool = 1;
@@ -30,6 +64,9 @@ const LLVM_TRUE: LLVMBool = 1;
 pub struct Module {
     module: *mut LLVMModule,
     strings: Vec<CString>,
+    /// The context the module is in, if it has one of its own rather
+    /// than LLVM's global context.
+    context: Option<LLVMContextRef>,
 }
 
 impl Module {
@@ -71,12 +108,19 @@ impl Drop for Module {
         // Rust requires that drop() is a safe function.
         unsafe {
             LLVMDisposeModule(self.module);
+            if let Some(context) = self.context {
+                LLVMContextDispose(context);
+            }
         }
     }
 }
 
 /// Wraps LLVM's builder class to provide a nicer API and ensure we
-/// always dispose correctly.
+/// always dispose correctly. The instruction methods keep the names
+/// they're given alive in `module`, so callers don't need to.
+///
+/// The instruction methods are unsafe, as they take raw LLVM values
+/// that must be live and belong to the builder's context.
 struct Builder {
     builder: *mut LLVMBuilder,
 }
@@ -91,10 +135,281 @@ impl Builder {
         }
     }
 
-    fn position_at_end(&self, bb: LLVMBasicBlockRef) {
-        unsafe {
-            LLVMPositionBuilderAtEnd(self.builder, bb);
-        }
+    unsafe fn position_at_end(&self, bb: LLVMBasicBlockRef) {
+        LLVMPositionBuilderAtEnd(self.builder, bb);
+    }
+
+    /// `lhs + rhs`.
+    unsafe fn add(
+        &self,
+        module: &mut Module,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildAdd(self.builder, lhs, rhs, module.new_string_ptr(name))
+    }
+
+    /// `lhs - rhs`.
+    unsafe fn sub(
+        &self,
+        module: &mut Module,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildSub(self.builder, lhs, rhs, module.new_string_ptr(name))
+    }
+
+    /// `lhs * rhs`.
+    unsafe fn mul(
+        &self,
+        module: &mut Module,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildMul(self.builder, lhs, rhs, module.new_string_ptr(name))
+    }
+
+    /// `lhs & rhs`.
+    unsafe fn and(
+        &self,
+        module: &mut Module,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildAnd(self.builder, lhs, rhs, module.new_string_ptr(name))
+    }
+
+    /// `lhs | rhs`.
+    unsafe fn or(
+        &self,
+        module: &mut Module,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildOr(self.builder, lhs, rhs, module.new_string_ptr(name))
+    }
+
+    /// `lhs % rhs`, unsigned.
+    unsafe fn urem(
+        &self,
+        module: &mut Module,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildURem(self.builder, lhs, rhs, module.new_string_ptr(name))
+    }
+
+    /// `lhs / rhs`, unsigned.
+    unsafe fn udiv(
+        &self,
+        module: &mut Module,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildUDiv(self.builder, lhs, rhs, module.new_string_ptr(name))
+    }
+
+    /// `-value`.
+    unsafe fn neg(&self, module: &mut Module, value: LLVMValueRef, name: &str) -> LLVMValueRef {
+        LLVMBuildNeg(self.builder, value, module.new_string_ptr(name))
+    }
+
+    /// Compare `lhs` and `rhs`.
+    unsafe fn icmp(
+        &self,
+        module: &mut Module,
+        predicate: LLVMIntPredicate,
+        lhs: LLVMValueRef,
+        rhs: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildICmp(
+            self.builder,
+            predicate,
+            lhs,
+            rhs,
+            module.new_string_ptr(name),
+        )
+    }
+
+    /// `condition ? then : otherwise`.
+    unsafe fn select(
+        &self,
+        module: &mut Module,
+        condition: LLVMValueRef,
+        then: LLVMValueRef,
+        otherwise: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildSelect(
+            self.builder,
+            condition,
+            then,
+            otherwise,
+            module.new_string_ptr(name),
+        )
+    }
+
+    /// Whether `value` is a null pointer.
+    unsafe fn is_null(&self, module: &mut Module, value: LLVMValueRef, name: &str) -> LLVMValueRef {
+        LLVMBuildIsNull(self.builder, value, module.new_string_ptr(name))
+    }
+
+    /// Whether `value` isn't a null pointer.
+    unsafe fn is_not_null(
+        &self,
+        module: &mut Module,
+        value: LLVMValueRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildIsNotNull(self.builder, value, module.new_string_ptr(name))
+    }
+
+    /// Cast the pointer `value` to `ty`.
+    unsafe fn pointer_cast(
+        &self,
+        module: &mut Module,
+        value: LLVMValueRef,
+        ty: LLVMTypeRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildPointerCast(self.builder, value, ty, module.new_string_ptr(name))
+    }
+
+    /// Zero extend `value` to `ty`.
+    unsafe fn zext(
+        &self,
+        module: &mut Module,
+        value: LLVMValueRef,
+        ty: LLVMTypeRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildZExt(self.builder, value, ty, module.new_string_ptr(name))
+    }
+
+    /// Truncate `value` to `ty`.
+    unsafe fn trunc(
+        &self,
+        module: &mut Module,
+        value: LLVMValueRef,
+        ty: LLVMTypeRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildTrunc(self.builder, value, ty, module.new_string_ptr(name))
+    }
+
+    /// Convert the pointer `value` to the integer type `ty`.
+    unsafe fn ptr_to_int(
+        &self,
+        module: &mut Module,
+        value: LLVMValueRef,
+        ty: LLVMTypeRef,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildPtrToInt(self.builder, value, ty, module.new_string_ptr(name))
+    }
+
+    /// Extend or truncate `value` to `ty`.
+    unsafe fn int_cast(
+        &self,
+        module: &mut Module,
+        value: LLVMValueRef,
+        ty: LLVMTypeRef,
+        signed: LLVMBool,
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildIntCast2(self.builder, value, ty, signed, module.new_string_ptr(name))
+    }
+
+    /// Allocate a `ty` on the stack.
+    unsafe fn alloca(&self, module: &mut Module, ty: LLVMTypeRef, name: &str) -> LLVMValueRef {
+        LLVMBuildAlloca(self.builder, ty, module.new_string_ptr(name))
+    }
+
+    /// A phi node, whose incoming values the caller adds.
+    unsafe fn phi(&self, module: &mut Module, ty: LLVMTypeRef, name: &str) -> LLVMValueRef {
+        LLVMBuildPhi(self.builder, ty, module.new_string_ptr(name))
+    }
+
+    /// Load the value at `ptr`.
+    unsafe fn load(&self, module: &mut Module, ptr: LLVMValueRef, name: &str) -> LLVMValueRef {
+        LLVMBuildLoad(self.builder, ptr, module.new_string_ptr(name))
+    }
+
+    /// Store `value` at `ptr`.
+    unsafe fn store(&self, value: LLVMValueRef, ptr: LLVMValueRef) -> LLVMValueRef {
+        LLVMBuildStore(self.builder, value, ptr)
+    }
+
+    /// The address of the element of `ptr` at `indices`.
+    unsafe fn gep(
+        &self,
+        module: &mut Module,
+        ptr: LLVMValueRef,
+        indices: &mut [LLVMValueRef],
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildGEP(
+            self.builder,
+            ptr,
+            indices.as_mut_ptr(),
+            indices.len() as c_uint,
+            module.new_string_ptr(name),
+        )
+    }
+
+    /// Call `function` with `args`.
+    unsafe fn call(
+        &self,
+        module: &mut Module,
+        function: LLVMValueRef,
+        args: &mut [LLVMValueRef],
+        name: &str,
+    ) -> LLVMValueRef {
+        LLVMBuildCall(
+            self.builder,
+            function,
+            args.as_mut_ptr(),
+            args.len() as c_uint,
+            module.new_string_ptr(name),
+        )
+    }
+
+    /// Jump to `bb`.
+    unsafe fn br(&self, bb: LLVMBasicBlockRef) -> LLVMValueRef {
+        LLVMBuildBr(self.builder, bb)
+    }
+
+    /// Jump to `then` if `condition` is true, or `otherwise`.
+    unsafe fn cond_br(
+        &self,
+        condition: LLVMValueRef,
+        then: LLVMBasicBlockRef,
+        otherwise: LLVMBasicBlockRef,
+    ) -> LLVMValueRef {
+        LLVMBuildCondBr(self.builder, condition, then, otherwise)
+    }
+
+    /// Return `value`.
+    unsafe fn ret(&self, value: LLVMValueRef) -> LLVMValueRef {
+        LLVMBuildRet(self.builder, value)
+    }
+
+    /// Return from a void function.
+    unsafe fn ret_void(&self) -> LLVMValueRef {
+        LLVMBuildRetVoid(self.builder)
+    }
+
+    /// Mark the end of a block that can't be reached.
+    unsafe fn unreachable(&self) -> LLVMValueRef {
+        LLVMBuildUnreachable(self.builder)
     }
 }
 
@@ -108,10 +423,258 @@ impl Drop for Builder {
 }
 
 #[derive(Clone)]
-struct CompileContext {
+struct CompileContext<'a> {
     cells: LLVMValueRef,
     cell_index_ptr: LLVMValueRef,
     main_fn: LLVMValueRef,
+    /// The function that reads a byte of input.
+    read_fn: &'static str,
+    /// How often each loop ran during compile time execution.
+    loop_counts: &'a HashMap<*const AstNode, LoopCounts>,
+    /// Instructions that need a runtime check that they stay on the
+    /// tape, with the message to print if they don't.
+    ptr_checks: &'a HashMap<*const AstNode, String>,
+    /// The number of cells on the tape.
+    num_cells: usize,
+    /// Where the tape is stored, if it can grow at runtime.
+    growable: Option<GrowableTape>,
+    /// Where to attach debug locations, if we're emitting debug
+    /// info.
+    debug_scope: Option<DebugScope<'a>>,
+    /// Whether output goes to the buffer from `add_output_buffer`,
+    /// which we must flush before reading or exiting.
+    buffered_output: bool,
+    /// Whether functions we add should keep frame pointers.
+    perf_friendly: bool,
+    /// The most instructions in one LLVM function, or 0 if we
+    /// shouldn't add functions for parts of the program.
+    function_size_limit: usize,
+    /// Whether putchar is libc's, so its output may be waiting in
+    /// stdout's buffer.
+    stdio: bool,
+    /// What reads store at EOF.
+    eof: EofBehaviour,
+    /// The width of cells, in bits.
+    cell_bits: u32,
+    /// Whether the tape has guard pages, so pointer checks only
+    /// record which instruction to blame for an overrun.
+    guard_pages: bool,
+    /// Whether to record the position of each instruction for the
+    /// debug runtime.
+    debug_runtime: bool,
+    /// The counters to update as each loop runs, under `--profile`.
+    loop_counters: &'a HashMap<*const AstNode, LoopCounters>,
+    /// The flags to set as each instruction runs, under `--coverage`.
+    coverage: Option<&'a CoverageFlags>,
+}
+
+#[derive(Clone, Copy)]
+struct DebugScope<'a> {
+    source_map: &'a SourceMap,
+    /// The builder for the module's debug info, which must be
+    /// finalised when we're done.
+    builder: LLVMDIBuilderRef,
+    /// The debug info for the source file.
+    file: LLVMMetadataRef,
+    /// The debug info for the function we're compiling, which
+    /// contains every location in it.
+    subprogram: LLVMMetadataRef,
+}
+
+/// A tape that starts small and grows when instructions go past the
+/// end. Both values are pointers to variables, as realloc may move
+/// the cells.
+#[derive(Clone, Copy)]
+struct GrowableTape {
+    cells_ptr: LLVMValueRef,
+    num_cells_ptr: LLVMValueRef,
+    /// The tape never grows beyond this many cells.
+    max_cells: usize,
+    /// With `runtime_tape_size`, a global holding the limit we read
+    /// at startup, which replaces `max_cells`.
+    max_cells_ptr: Option<LLVMValueRef>,
+}
+
+impl GrowableTape {
+    /// The most cells the tape may hold, as an i32.
+    unsafe fn max_len(&self, module: &mut Module, builder: &Builder) -> LLVMValueRef {
+        match self.max_cells_ptr {
+            Some(max_cells_ptr) => builder.load(module, max_cells_ptr, "max_cells"),
+            None => int32(self.max_cells as c_ulonglong),
+        }
+    }
+}
+
+/// Loops to count under `--profile`.
+#[derive(Debug, Default)]
+pub struct Profile {
+    /// Each loop to count, in source order, with the position to
+    /// show for it in the profile.
+    pub loops: Vec<(*const AstNode, String)>,
+    /// The file the program writes the counts to when it exits,
+    /// relative to the directory it runs in.
+    pub path: String,
+}
+
+/// Instructions to record under `--coverage`.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    /// Each instruction to record, with the line it's on, counting
+    /// from 1.
+    pub instrs: Vec<(*const AstNode, usize)>,
+    /// The source file, as named in the report.
+    pub source_path: String,
+    /// The file the program appends its report to when it exits,
+    /// relative to the directory it runs in.
+    pub path: String,
+}
+
+/// Runtime checks that the pointer stays on the tape.
+#[derive(Debug, Default)]
+pub struct PtrChecks {
+    /// Instructions to check, with the message to print if they
+    /// leave the tape.
+    pub messages: HashMap<*const AstNode, String>,
+    /// If set, start with a tape of this many cells, and grow it
+    /// when a checked instruction goes past the end rather than
+    /// aborting. The tape never grows beyond the usual allocation
+    /// unless `grow_without_limit` is set.
+    pub growable_from: Option<usize>,
+    /// With `growable_from`, grow the tape as far as the program
+    /// goes, rather than stopping at the usual allocation.
+    pub grow_without_limit: bool,
+}
+
+/// The largest tape we put on the stack by default. Larger tapes
+/// could overflow the stack, so they go on the heap.
+pub const DEFAULT_STACK_TAPE_LIMIT: usize = 2000;
+
+/// The most instructions we compile into one LLVM function by
+/// default. LLVM's optimisations slow down sharply on larger
+/// functions.
+pub const DEFAULT_FUNCTION_SIZE_LIMIT: usize = 10_000;
+
+/// Options that change the code we generate, but not what it does.
+#[derive(Debug)]
+pub struct CodegenOptions {
+    pub ptr_checks: PtrChecks,
+    /// Allocate the tape on the stack if it has at most this many
+    /// cells, so the program doesn't need malloc or free.
+    pub stack_tape_limit: usize,
+    /// Split programs with more than this many instructions into
+    /// several functions, or never split them if 0.
+    pub function_size_limit: usize,
+    /// Keep frame pointers and unwind tables, so profilers and
+    /// debuggers can walk the stack.
+    pub perf_friendly: bool,
+    /// If set, emit debug info mapping the binary to lines in this
+    /// source file.
+    pub debug_info: Option<SourceMap>,
+    /// Make system calls rather than calling libc, and start from
+    /// `_start`, so the program links without libc. The target must
+    /// be one `supports_freestanding` accepts.
+    pub freestanding: bool,
+    /// Assume no OS: write and read with the user's `bf_putchar`
+    /// and `bf_getchar`, and leave startup to the user's code.
+    pub bare_metal: bool,
+    /// With `bare_metal`, put the tape at this address rather than on
+    /// the stack.
+    pub tape_address: Option<u64>,
+    /// Export `bf_run` for a shared library, rather than `main`.
+    pub library: bool,
+    /// Collect output in a buffer and write it in chunks, rather
+    /// than calling putchar for every `.`.
+    pub buffered_output: bool,
+    /// What `,` stores at EOF. Our read functions return -1 at EOF
+    /// like getchar, which is what we store by default.
+    pub eof: EofBehaviour,
+    /// The width of cells: 8, 16 or 32 bits. Output writes the low
+    /// byte of the cell.
+    pub cell_bits: u32,
+    /// Map the tape between inaccessible guard pages, and report
+    /// overruns from a SIGSEGV handler rather than checking the
+    /// pointer. The messages in `ptr_checks` say which instruction
+    /// overran. The target must be one `supports_guard_pages`
+    /// accepts.
+    pub guard_pages: bool,
+    /// On SIGINT or SIGSEGV, print the pointer, the cells around it
+    /// and, with `debug_info`, the current instruction's position,
+    /// then exit. Main keeps its state in memory so the handler can
+    /// read it, which makes the program slower.
+    pub debug_runtime: bool,
+    /// If set, count how often each loop runs, and write the counts
+    /// to a file when the program exits.
+    pub profile: Option<Profile>,
+    /// If set, record which instructions run, and append an lcov
+    /// report to a file when the program exits.
+    pub coverage: Option<Coverage>,
+    /// Let the program raise or lower the most cells a growable tape
+    /// may hold at startup, with the `BF_TAPE_SIZE` environment
+    /// variable. It never goes below the cells the tape starts with.
+    pub runtime_tape_size: bool,
+    /// If set, embed this in its own section, so `bfc inspect` can
+    /// say how the binary was built. WebAssembly has no sections,
+    /// so we ignore it there.
+    pub metadata: Option<BuildMetadata>,
+}
+
+impl Default for CodegenOptions {
+    fn default() -> Self {
+        CodegenOptions {
+            ptr_checks: PtrChecks::default(),
+            stack_tape_limit: 0,
+            function_size_limit: DEFAULT_FUNCTION_SIZE_LIMIT,
+            perf_friendly: false,
+            debug_info: None,
+            freestanding: false,
+            bare_metal: false,
+            tape_address: None,
+            library: false,
+            buffered_output: false,
+            eof: EofBehaviour::default(),
+            cell_bits: DEFAULT_CELL_BITS,
+            guard_pages: false,
+            debug_runtime: false,
+            profile: None,
+            coverage: None,
+            runtime_tape_size: false,
+            metadata: None,
+        }
+    }
+}
+
+/// Converts instruction positions to lines and columns in a source
+/// file, for debug info.
+#[derive(Debug, Clone)]
+pub struct SourceMap {
+    /// The source file, as given on the command line.
+    pub path: String,
+    /// The directory `path` is relative to.
+    pub directory: String,
+    /// The byte offset of the start of each line.
+    line_starts: Vec<usize>,
+}
+
+impl SourceMap {
+    pub fn new(path: &str, directory: &str, source: &str) -> Self {
+        let mut line_starts = vec![0];
+        line_starts.extend(source.match_indices('\n').map(|(index, _)| index + 1));
+        SourceMap {
+            path: path.to_owned(),
+            directory: directory.to_owned(),
+            line_starts,
+        }
+    }
+
+    /// The line and column of the byte at `offset`, both counting
+    /// from one as debuggers expect.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next_line) => next_line - 1,
+        };
+        (line + 1, offset - self.line_starts[line] + 1)
+    }
 }
 
 /// Convert this integer to LLVM's representation of a constant
@@ -126,6 +689,12 @@ fn int32(val: c_ulonglong) -> LLVMValueRef {
     unsafe { LLVMConstInt(LLVMInt32Type(), val, LLVM_FALSE) }
 }
 
+/// Convert this integer to LLVM's representation of a constant
+/// 64 bit integer.
+fn int64(val: c_ulonglong) -> LLVMValueRef {
+    unsafe { LLVMConstInt(LLVMInt64Type(), val, LLVM_FALSE) }
+}
+
 fn int1_type() -> LLVMTypeRef {
     unsafe { LLVMInt1Type() }
 }
@@ -138,10 +707,42 @@ fn int32_type() -> LLVMTypeRef {
     unsafe { LLVMInt32Type() }
 }
 
+fn int64_type() -> LLVMTypeRef {
+    unsafe { LLVMInt64Type() }
+}
+
 fn int8_ptr_type() -> LLVMTypeRef {
     unsafe { LLVMPointerType(LLVMInt8Type(), 0) }
 }
 
+/// The type of a cell `cell_bits` wide.
+fn cell_type(cell_bits: u32) -> LLVMTypeRef {
+    unsafe { LLVMIntType(cell_bits) }
+}
+
+fn cell_ptr_type(cell_bits: u32) -> LLVMTypeRef {
+    unsafe { LLVMPointerType(cell_type(cell_bits), 0) }
+}
+
+/// Convert this cell value to a constant of the cell type.
+unsafe fn cell_const(value: Cell, cell_bits: u32) -> LLVMValueRef {
+    LLVMConstInt(cell_type(cell_bits), value.0 as c_ulonglong, LLVM_FALSE)
+}
+
+/// Convert `len`, a number of cells, to the number of bytes they
+/// occupy.
+unsafe fn cells_to_bytes(
+    module: &mut Module,
+    builder: &Builder,
+    len: LLVMValueRef,
+    cell_bits: u32,
+) -> LLVMValueRef {
+    if cell_bits == 8 {
+        return len;
+    }
+    builder.mul(module, len, int32(u64::from(cell_bits / 8)), "len_bytes")
+}
+
 fn add_function(
     module: &mut Module,
     fn_name: &str,
@@ -163,25 +764,27 @@ fn add_c_declarations(module: &mut Module) {
     add_function(
         module,
         "llvm.memset.p0i8.i32",
-        &mut [
-            int8_ptr_type(),
-            int8_type(),
-            int32_type(),
-            int32_type(),
-            int1_type(),
-        ],
+        &mut [int8_ptr_type(), int8_type(), int32_type(), int1_type()],
         void,
     );
 
     add_function(module, "malloc", &mut [int32_type()], int8_ptr_type());
+    unsafe {
+        // The tape never aliases anything else.
+        let malloc_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("malloc"));
+        let noalias = enum_attribute(module, "noalias", 0);
+        LLVMAddAttributeAtIndex(malloc_fn, LLVMAttributeReturnIndex, noalias);
+    }
 
     add_function(module, "free", &mut [int8_ptr_type()], void);
 
+    // ssize_t write(int fd, const void *buf, size_t count)
+    let write_size = unsafe { write_size_type(module) };
     add_function(
         module,
         "write",
-        &mut [int32_type(), int8_ptr_type(), int32_type()],
-        int32_type(),
+        &mut [int32_type(), int8_ptr_type(), write_size],
+        write_size,
     );
 
     add_function(module, "putchar", &mut [int32_type()], int32_type());
@@ -189,6 +792,55 @@ fn add_c_declarations(module: &mut Module) {
     add_function(module, "getchar", &mut [], int32_type());
 }
 
+/// Create the attribute `name`. `value` is the argument of attributes
+/// such as `dereferenceable(N)`, and ignored otherwise.
+unsafe fn enum_attribute(module: &mut Module, name: &str, value: u64) -> LLVMAttributeRef {
+    let kind = LLVMGetEnumAttributeKindForName(module.new_string_ptr(name), name.len());
+    LLVMCreateEnumAttribute(LLVMGetModuleContext(module.module), kind, value)
+}
+
+/// Tell LLVM that `function` never unwinds or recurses, which lets
+/// it treat globals in main as locals. Without unwind tables or
+/// frame pointers, profilers can't walk the stack, so
+/// `perf_friendly` keeps both.
+unsafe fn add_function_attributes(
+    module: &mut Module,
+    function: LLVMValueRef,
+    perf_friendly: bool,
+) {
+    for name in &["nounwind", "norecurse"] {
+        let attribute = enum_attribute(module, name, 0);
+        LLVMAddAttributeAtIndex(function, LLVMAttributeFunctionIndex, attribute);
+    }
+
+    if perf_friendly {
+        let uwtable = enum_attribute(module, "uwtable", 0);
+        LLVMAddAttributeAtIndex(function, LLVMAttributeFunctionIndex, uwtable);
+
+        let (key, value) = FRAME_POINTER_ATTRIBUTE;
+        let frame_pointers = string_attribute(module, key, value);
+        LLVMAddAttributeAtIndex(function, LLVMAttributeFunctionIndex, frame_pointers);
+    }
+}
+
+/// The string attribute that keeps frame pointers. LLVM 10 renamed
+/// it.
+#[cfg(any(feature = "llvm-8", feature = "llvm-9"))]
+const FRAME_POINTER_ATTRIBUTE: (&str, &str) = ("no-frame-pointer-elim", "true");
+#[cfg(not(any(feature = "llvm-8", feature = "llvm-9")))]
+const FRAME_POINTER_ATTRIBUTE: (&str, &str) = ("frame-pointer", "all");
+
+/// Create the attribute `"key"="value"`.
+unsafe fn string_attribute(module: &mut Module, key: &str, value: &str) -> LLVMAttributeRef {
+    LLVMCreateStringAttribute(
+        LLVMGetModuleContext(module.module),
+        module.new_string_ptr(key),
+        key.len() as c_uint,
+        module.new_string_ptr(value),
+        value.len() as c_uint,
+    )
+}
+
 unsafe fn add_function_call(
     module: &mut Module,
     bb: LLVMBasicBlockRef,
@@ -201,13 +853,33 @@ unsafe fn add_function_call(
 
     let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
 
-    LLVMBuildCall(
-        builder.builder,
-        function,
-        args.as_mut_ptr(),
-        args.len() as c_uint,
-        module.new_string_ptr(name),
-    )
+    builder.call(module, function, args, name)
+}
+
+/// Set `len` bytes at `dest`, which is aligned to `align` bytes, to
+/// `value`.
+unsafe fn build_memset(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    dest: LLVMValueRef,
+    value: LLVMValueRef,
+    len: LLVMValueRef,
+    align: u64,
+) {
+    let is_volatile = LLVMConstInt(int1_type(), 0, LLVM_FALSE);
+    let mut memset_args = vec![dest, value, len, is_volatile];
+    let call = add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
+    add_param_align(module, call, 0, align);
+}
+
+/// Tell LLVM that the pointer argument `index` of `call` is aligned
+/// to `align` bytes. Since LLVM 7, this is how memory intrinsics
+/// take their alignment.
+unsafe fn add_param_align(module: &mut Module, call: LLVMValueRef, index: u32, align: u64) {
+    let attribute = enum_attribute(module, "align", align);
+    // Attribute index 0 is the return value, so parameters start
+    // at 1.
+    LLVMAddCallSiteAttribute(call, index + 1, attribute);
 }
 
 /// Given a vector of cells [1, 1, 0, 0, 0, ...] return a vector
@@ -229,41 +901,96 @@ where
         .collect()
 }
 
+/// The most runs of equal cells we initialise with memset. Beyond
+/// this, we copy the initial cells from a constant.
+const MAX_INIT_MEMSETS: usize = 8;
+
 fn add_cells_init(
-    init_values: &[Wrapping<i8>],
+    init_values: &[Cell],
+    storage: TapeStorage,
+    cell_bits: u32,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
 ) -> LLVMValueRef {
     let builder = Builder::new();
-    builder.position_at_end(bb);
 
     unsafe {
-        // char* cells = malloc(num_cells);
-        let num_cells = int32(init_values.len() as c_ulonglong);
-        let mut malloc_args = vec![num_cells];
-        let cells_ptr = add_function_call(module, bb, "malloc", &mut malloc_args, "cells");
+        builder.position_at_end(bb);
 
-        let one = int32(1);
-        let false_ = LLVMConstInt(int1_type(), 1, LLVM_FALSE);
+        let num_cells = int32(init_values.len() as c_ulonglong);
+        let cell_bytes = u64::from(cell_bits / 8);
+        let cells_ptr = match storage {
+            TapeStorage::Stack => {
+                // char cells[num_cells];
+                LLVMBuildArrayAlloca(
+                    builder.builder,
+                    cell_type(cell_bits),
+                    num_cells,
+                    module.new_string_ptr("cells"),
+                )
+            }
+            TapeStorage::Heap => {
+                // char* cells = malloc(num_cells);
+                let mut malloc_args = vec![int32(init_values.len() as u64 * cell_bytes)];
+                let cells = add_function_call(module, bb, "malloc", &mut malloc_args, "cells");
+                builder.pointer_cast(module, cells, cell_ptr_type(cell_bits), "cells")
+            }
+            TapeStorage::Address(address) => {
+                // char* cells = (char*)address;
+                let address = LLVMConstInt(LLVMInt64Type(), address, LLVM_FALSE);
+                LLVMConstIntToPtr(address, cell_ptr_type(cell_bits))
+            }
+            TapeStorage::Guarded => {
+                let cells = add_guarded_tape(module, bb, init_values.len() as u64 * cell_bytes);
+                builder.position_at_end(bb);
+                builder.pointer_cast(module, cells, cell_ptr_type(cell_bits), "cells")
+            }
+        };
 
+        // A few memsets are smaller than a copy of the cells, but
+        // programs with lots of precomputed state are smaller if we
+        // copy the non-zero prefix from a constant. memset only
+        // writes bytes, so wider cells always copy.
         let mut offset = 0;
-        for (cell_val, cell_count) in run_length_encode(init_values) {
+        let nonzero_len = init_values
+            .iter()
+            .rposition(|cell| cell.0 != 0)
+            .map_or(0, |index| index + 1);
+        let runs = run_length_encode(&init_values[..nonzero_len]).len();
+        if runs > MAX_INIT_MEMSETS || (cell_bits != 8 && nonzero_len > 0) {
+            add_cells_copy(
+                &init_values[..nonzero_len],
+                cells_ptr,
+                cell_bits,
+                module,
+                bb,
+            );
+            offset = nonzero_len;
+        }
+
+        for (cell_val, cell_count) in run_length_encode(&init_values[offset..]) {
             let llvm_cell_val = int8(cell_val.0 as c_ulonglong);
-            let llvm_cell_count = int32(cell_count as c_ulonglong);
+            let llvm_cell_count = int32(cell_count as u64 * cell_bytes);
 
             // TODO: factor out a build_gep function.
             let mut offset_vec = vec![int32(offset as c_ulonglong)];
-            let offset_cell_ptr = LLVMBuildGEP(
-                builder.builder,
-                cells_ptr,
-                offset_vec.as_mut_ptr(),
-                offset_vec.len() as u32,
-                module.new_string_ptr("offset_cell_ptr"),
+            let offset_cell_ptr =
+                builder.gep(module, cells_ptr, &mut offset_vec, "offset_cell_ptr");
+            let offset_cell_ptr = builder.pointer_cast(
+                module,
+                offset_cell_ptr,
+                int8_ptr_type(),
+                "offset_cell_bytes",
             );
 
-            let mut memset_args =
-                vec![offset_cell_ptr, llvm_cell_val, llvm_cell_count, one, false_];
-            add_function_call(module, bb, "llvm.memset.p0i8.i32", &mut memset_args, "");
+            build_memset(
+                module,
+                bb,
+                offset_cell_ptr,
+                llvm_cell_val,
+                llvm_cell_count,
+                cell_bytes,
+            );
 
             offset += cell_count;
         }
@@ -272,17 +999,322 @@ fn add_cells_init(
     }
 }
 
-fn add_cells_cleanup(module: &mut Module, bb: LLVMBasicBlockRef, cells: LLVMValueRef) {
+/// Copy `init_values` to the start of `cells` from a constant
+/// global.
+unsafe fn add_cells_copy(
+    init_values: &[Cell],
+    cells: LLVMValueRef,
+    cell_bits: u32,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+) {
     let builder = Builder::new();
     builder.position_at_end(bb);
 
+    let mut llvm_values: Vec<_> = init_values
+        .iter()
+        .map(|cell| cell_const(*cell, cell_bits))
+        .collect();
+    let values_type = LLVMArrayType(cell_type(cell_bits), llvm_values.len() as c_uint);
+    let llvm_values_arr = LLVMConstArray(
+        cell_type(cell_bits),
+        llvm_values.as_mut_ptr(),
+        llvm_values.len() as c_uint,
+    );
+    let initial_cells = LLVMAddGlobal(
+        module.module,
+        values_type,
+        module.new_string_ptr("initial_cells"),
+    );
+    LLVMSetInitializer(initial_cells, llvm_values_arr);
+    LLVMSetGlobalConstant(initial_cells, LLVM_TRUE);
+    LLVMSetLinkage(initial_cells, LLVMLinkage::LLVMInternalLinkage);
+    LLVMSetAlignment(initial_cells, cell_bits / 8);
+
+    let initial_cells_ptr =
+        builder.pointer_cast(module, initial_cells, int8_ptr_type(), "initial_cells_ptr");
+
+    let memcpy = "llvm.memcpy.p0i8.p0i8.i32";
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr(memcpy)).is_null() {
+        add_function(
+            module,
+            memcpy,
+            &mut [int8_ptr_type(), int8_ptr_type(), int32_type(), int1_type()],
+            LLVMVoidType(),
+        );
+    }
+    let cells = builder.pointer_cast(module, cells, int8_ptr_type(), "cell_bytes");
+    let cell_bytes = u64::from(cell_bits / 8);
+    let mut memcpy_args = vec![
+        cells,
+        initial_cells_ptr,
+        int32(init_values.len() as u64 * cell_bytes),
+        LLVMConstInt(int1_type(), 0, LLVM_FALSE),
+    ];
+    let call = add_function_call(module, bb, memcpy, &mut memcpy_args, "");
+    // Both the tape and the constant are arrays of cells.
+    add_param_align(module, call, 0, cell_bytes);
+    add_param_align(module, call, 1, cell_bytes);
+}
+
+fn add_cells_cleanup(module: &mut Module, bb: LLVMBasicBlockRef, cells: LLVMValueRef) {
+    let builder = Builder::new();
+
     unsafe {
+        builder.position_at_end(bb);
+
         // free(cells);
+        let cells = builder.pointer_cast(module, cells, int8_ptr_type(), "cell_bytes");
         let mut free_args = vec![cells];
         add_function_call(module, bb, "free", &mut free_args, "");
     }
 }
 
+/// The `MAP_ANONYMOUS` flag for mmap on this target, if it has the
+/// mmap and signals we need for guard pages.
+fn map_anonymous_flag(target_triple: &str) -> Option<u64> {
+    if target_triple.contains("linux") {
+        Some(0x20)
+    } else if ["apple", "darwin", "freebsd", "netbsd", "openbsd"]
+        .iter()
+        .any(|os| target_triple.contains(os))
+    {
+        Some(0x1000)
+    } else {
+        None
+    }
+}
+
+/// Can `--tape=guard` protect the tape with guard pages on this
+/// target?
+pub fn supports_guard_pages(target_triple: &str) -> bool {
+    map_anonymous_flag(target_triple).is_some()
+}
+
+const PROT_NONE: u64 = 0;
+const PROT_READ_WRITE: u64 = 3;
+const MAP_PRIVATE: u64 = 2;
+const SIGSEGV: u64 = 11;
+/// SIGBUS on macOS and the BSDs, which raise it for some accesses
+/// to protected pages.
+const BSD_SIGBUS: u64 = 10;
+
+unsafe fn add_guard_page_declarations(module: &mut Module) {
+    let word = word_type(module);
+    add_function(module, "getpagesize", &mut [], int32_type());
+    add_function(
+        module,
+        "mmap",
+        &mut [
+            int8_ptr_type(),
+            word,
+            int32_type(),
+            int32_type(),
+            int32_type(),
+            word,
+        ],
+        int8_ptr_type(),
+    );
+    add_function(
+        module,
+        "mprotect",
+        &mut [int8_ptr_type(), word, int32_type()],
+        int32_type(),
+    );
+    add_function(module, "munmap", &mut [int8_ptr_type(), word], int32_type());
+}
+
+/// The page size, and the bytes we map for a tape of `tape_bytes`
+/// bytes: the tape rounded up to whole pages, plus a guard page
+/// either side.
+unsafe fn guarded_tape_size(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    tape_bytes: u64,
+) -> (LLVMValueRef, LLVMValueRef) {
+    let page_size = add_function_call(module, bb, "getpagesize", &mut [], "page_size");
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // data_size = (tape_bytes + page_size - 1) & -page_size;
+    let page_remainder = builder.sub(module, page_size, int32(1), "page_remainder");
+    let rounded_up = builder.add(module, int32(tape_bytes), page_remainder, "rounded_up");
+    let page_mask = builder.neg(module, page_size, "page_mask");
+    let data_size = builder.and(module, rounded_up, page_mask, "data_size");
+    let guard_size = builder.mul(module, page_size, int32(2), "guard_size");
+    let mapped_size = builder.add(module, data_size, guard_size, "mapped_size");
+    (page_size, mapped_size)
+}
+
+/// Map a tape of `tape_bytes` bytes between two inaccessible guard
+/// pages, and return its first byte. The tape starts right after
+/// the left guard page, so going left of the first cell faults
+/// immediately. Going right faults once past the page the last
+/// cell is on.
+unsafe fn add_guarded_tape(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    tape_bytes: u64,
+) -> LLVMValueRef {
+    add_guard_page_declarations(module);
+    let target_triple = CStr::from_ptr(LLVMGetTarget(module.module)).to_string_lossy();
+    let map_anonymous =
+        map_anonymous_flag(&target_triple).expect("--tape=guard on an unsupported target");
+    let word = word_type(module);
+
+    let (page_size, mapped_size) = guarded_tape_size(module, bb, tape_bytes);
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let page_size_word = builder.zext(module, page_size, word, "page_size_word");
+    let mapped_size_word = builder.zext(module, mapped_size, word, "mapped_size_word");
+
+    // char* mapping = mmap(NULL, mapped_size, PROT_READ | PROT_WRITE,
+    //                      MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+    let mut mmap_args = vec![
+        LLVMConstNull(int8_ptr_type()),
+        mapped_size_word,
+        int32(PROT_READ_WRITE),
+        int32(MAP_PRIVATE | map_anonymous),
+        int32(-1i64 as c_ulonglong),
+        LLVMConstInt(word, 0, LLVM_FALSE),
+    ];
+    let mapping = add_function_call(module, bb, "mmap", &mut mmap_args, "mapping");
+
+    // mprotect(mapping, page_size, PROT_NONE);
+    // mprotect(mapping + mapped_size - page_size, page_size, PROT_NONE);
+    builder.position_at_end(bb);
+    let right_guard_offset = builder.sub(module, mapped_size, page_size, "right_guard_offset");
+    let mut indices = vec![right_guard_offset];
+    let right_guard = builder.gep(module, mapping, &mut indices, "right_guard");
+    for guard in &[mapping, right_guard] {
+        let mut mprotect_args = vec![*guard, page_size_word, int32(PROT_NONE)];
+        add_function_call(module, bb, "mprotect", &mut mprotect_args, "");
+    }
+
+    builder.position_at_end(bb);
+    let mut indices = vec![page_size];
+    builder.gep(module, mapping, &mut indices, "tape_start")
+}
+
+/// Unmap a tape of `tape_bytes` bytes from `add_guarded_tape`.
+unsafe fn add_guarded_tape_cleanup(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    cells: LLVMValueRef,
+    tape_bytes: u64,
+) {
+    let (page_size, mapped_size) = guarded_tape_size(module, bb, tape_bytes);
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // munmap(cells - page_size, mapped_size);
+    let cells = builder.pointer_cast(module, cells, int8_ptr_type(), "cell_bytes");
+    let mut indices = vec![builder.neg(module, page_size, "left_guard_offset")];
+    let mapping = builder.gep(module, cells, &mut indices, "mapping");
+    let mapped_size_word = builder.zext(module, mapped_size, word_type(module), "mapped_size_word");
+    add_function_call(module, bb, "munmap", &mut [mapping, mapped_size_word], "");
+}
+
+/// A constant, nul terminated copy of `message`.
+unsafe fn add_overrun_message(module: &mut Module, message: &str) -> LLVMValueRef {
+    let mut bytes = message.as_bytes().to_vec();
+    bytes.push(0);
+    add_const_string(module, &bytes, "overrun_message_text")
+}
+
+/// Install a handler for the fault when the program steps onto a
+/// guard page. It prints the message the last overrun marker
+/// stored, then aborts like a failed pointer check.
+unsafe fn add_overrun_handler(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    options: &CodegenOptions,
+) {
+    // Until an overrun marker runs, we can't say which instruction
+    // overran.
+    let default_message = add_overrun_message(module, "Tape overrun");
+    let message_ptr = LLVMAddGlobal(
+        module.module,
+        int8_ptr_type(),
+        module.new_string_ptr("overrun_message"),
+    );
+    LLVMSetInitializer(message_ptr, default_message);
+    LLVMSetLinkage(message_ptr, LLVMLinkage::LLVMInternalLinkage);
+
+    let void = LLVMVoidType();
+    add_function(module, "strlen", &mut [int8_ptr_type()], int32_type());
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("abort")).is_null() {
+        add_function(module, "abort", &mut [], void);
+    }
+
+    // void overrun_handler(int signal) {
+    //     char* message = overrun_message;
+    //     write(2, message, strlen(message));
+    //     write(2, "\n", 1);
+    //     abort();
+    // }
+    let mut handler_args = vec![int32_type()];
+    let handler_type = LLVMFunctionType(
+        void,
+        handler_args.as_mut_ptr(),
+        handler_args.len() as c_uint,
+        LLVM_FALSE,
+    );
+    let handler = LLVMAddFunction(
+        module.module,
+        module.new_string_ptr("overrun_handler"),
+        handler_type,
+    );
+    LLVMSetLinkage(handler, LLVMLinkage::LLVMInternalLinkage);
+    let handler_bb = LLVMAppendBasicBlock(handler, module.new_string_ptr("entry"));
+
+    let builder = Builder::new();
+    builder.position_at_end(handler_bb);
+    let message = builder.load(module, message_ptr, "message");
+    LLVMSetVolatile(message, LLVM_TRUE);
+    // Show the output so far before the error.
+    if options.buffered_output {
+        add_function_call(module, handler_bb, "flush_output", &mut [], "");
+    }
+    let len = add_function_call(module, handler_bb, "strlen", &mut [message], "len");
+    add_write_all_call(module, handler_bb, int32(2), message, len);
+    let newline = add_const_string(module, b"\n", "overrun_newline");
+    add_write_all_call(module, handler_bb, int32(2), newline, int32(1));
+    add_function_call(module, handler_bb, "abort", &mut [], "");
+    builder.position_at_end(handler_bb);
+    builder.unreachable();
+
+    // signal(SIGSEGV, overrun_handler);
+    let handler_ptr_type = LLVMPointerType(handler_type, 0);
+    add_function(
+        module,
+        "signal",
+        &mut [int32_type(), handler_ptr_type],
+        handler_ptr_type,
+    );
+    let target_triple = CStr::from_ptr(LLVMGetTarget(module.module)).to_string_lossy();
+    let mut signals = vec![SIGSEGV];
+    if !target_triple.contains("linux") {
+        signals.push(BSD_SIGBUS);
+    }
+    for signal in signals {
+        add_function_call(module, bb, "signal", &mut [int32(signal), handler], "");
+    }
+}
+
+/// Record `message` as the one to print if the next tape access
+/// steps onto a guard page. The store is volatile, as LLVM can't
+/// see the signal handler read it.
+unsafe fn compile_overrun_marker(message: &str, module: &mut Module, bb: LLVMBasicBlockRef) {
+    let message = add_overrun_message(module, message);
+    let message_ptr = LLVMGetNamedGlobal(module.module, module.new_string_ptr("overrun_message"));
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let store = builder.store(message, message_ptr);
+    LLVMSetVolatile(store, LLVM_TRUE);
+}
+
 fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
     let c_module_name = CString::new(module_name).unwrap();
     let module_name_char_ptr = c_module_name.to_bytes_with_nul().as_ptr() as *const _;
@@ -294,6 +1326,7 @@ fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
     let mut module = Module {
         module: llvm_module,
         strings: vec![c_module_name],
+        context: None,
     };
 
     let target_triple_cstring = if let Some(target_triple) = target_triple {
@@ -307,8 +1340,8 @@ fn create_module(module_name: &str, target_triple: Option<String>) -> Module {
     unsafe {
         LLVMSetTarget(llvm_module, target_triple_cstring.as_ptr() as *const _);
     }
-    // TODO: add a function to the LLVM C API that gives us the
-    // data layout from the target machine.
+    // We set the data layout in `set_data_layout`, once LLVM's
+    // targets are initialised.
 
     add_c_declarations(&mut module);
     module
@@ -345,6 +1378,7 @@ fn add_initial_bbs(
 /// Initialise the value that contains the current cell index.
 unsafe fn add_cell_index_init(
     init_value: isize,
+    debug_runtime: bool,
     bb: LLVMBasicBlockRef,
     module: &mut Module,
 ) -> LLVMValueRef {
@@ -352,24 +1386,445 @@ unsafe fn add_cell_index_init(
     builder.position_at_end(bb);
 
     // int cell_index = 0;
-    let cell_index_ptr = LLVMBuildAlloca(
-        builder.builder,
+    let cell_index_ptr = add_main_variable(
+        module,
+        &builder,
         int32_type(),
-        module.new_string_ptr("cell_index_ptr"),
+        "cell_index_ptr",
+        debug_runtime,
     );
     let cell_ptr_init = int32(init_value as c_ulonglong);
-    LLVMBuildStore(builder.builder, cell_ptr_init, cell_index_ptr);
+    builder.store(cell_ptr_init, cell_index_ptr);
 
     cell_index_ptr
 }
 
+/// Store the location and length of a growable tape, so we can
+/// update them when it grows.
+unsafe fn add_growable_tape_init(
+    cells: LLVMValueRef,
+    num_cells: usize,
+    max_cells: usize,
+    cell_bits: u32,
+    debug_runtime: bool,
+    bb: LLVMBasicBlockRef,
+    module: &mut Module,
+) -> GrowableTape {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cells_ptr = add_main_variable(
+        module,
+        &builder,
+        cell_ptr_type(cell_bits),
+        "cells_ptr",
+        debug_runtime,
+    );
+    builder.store(cells, cells_ptr);
+
+    let num_cells_ptr = add_main_variable(
+        module,
+        &builder,
+        int32_type(),
+        "num_cells_ptr",
+        debug_runtime,
+    );
+    builder.store(int32(num_cells as c_ulonglong), num_cells_ptr);
+
+    GrowableTape {
+        cells_ptr,
+        num_cells_ptr,
+        max_cells,
+        max_cells_ptr: None,
+    }
+}
+
+/// Read the limit of `tape` from `BF_TAPE_SIZE` at startup, and
+/// return the global we store it in. The tape starts with
+/// `num_cells`, so it can't be any smaller.
+unsafe fn add_runtime_tape_limit(
+    tape: &GrowableTape,
+    num_cells: usize,
+    cell_bits: u32,
+    bb: LLVMBasicBlockRef,
+    module: &mut Module,
+) -> LLVMValueRef {
+    add_tape_limit_reader(module);
+    let max_cells_ptr = LLVMAddGlobal(
+        module.module,
+        int32_type(),
+        module.new_string_ptr("max_cells"),
+    );
+    LLVMSetInitializer(max_cells_ptr, int32(0));
+    LLVMSetLinkage(max_cells_ptr, LLVMLinkage::LLVMInternalLinkage);
+
+    // The size of the tape in bytes must fit in an i32.
+    // max_cells = tape_limit(num_cells, tape.max_cells, INT_MAX / cell_bytes);
+    let hard_limit = i32::MAX as u64 / u64::from(cell_bits / 8);
+    let limit = add_function_call(
+        module,
+        bb,
+        "tape_limit",
+        &mut [
+            int32(num_cells as c_ulonglong),
+            int32(tape.max_cells as c_ulonglong),
+            int32(hard_limit),
+        ],
+        "max_cells",
+    );
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    builder.store(limit, max_cells_ptr);
+    max_cells_ptr
+}
+
+/// Define
+///
+/// ```c
+/// int tape_limit(int min_cells, int default_cells, int max_cells);
+/// ```
+///
+/// unless we already have, which returns the number of cells in the
+/// `BF_TAPE_SIZE` environment variable, clamped between `min_cells`
+/// and `max_cells`, or `default_cells` if it's unset or not a
+/// positive number.
+unsafe fn add_tape_limit_reader(module: &mut Module) {
+    let fn_name = "tape_limit";
+    if !LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name)).is_null() {
+        return;
+    }
+    add_function(
+        module,
+        fn_name,
+        &mut [int32_type(), int32_type(), int32_type()],
+        int32_type(),
+    );
+    let limit_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+    LLVMSetLinkage(limit_fn, LLVMLinkage::LLVMInternalLinkage);
+    for (name, args, ret_type) in &[
+        ("getenv", [int8_ptr_type()], int8_ptr_type()),
+        ("atoi", [int8_ptr_type()], int32_type()),
+    ] {
+        if LLVMGetNamedFunction(module.module, module.new_string_ptr(name)).is_null() {
+            add_function(module, name, &mut args.clone(), *ret_type);
+        }
+    }
+    let min_cells = LLVMGetParam(limit_fn, 0);
+    let default_cells = LLVMGetParam(limit_fn, 1);
+    let max_cells = LLVMGetParam(limit_fn, 2);
+
+    let entry_bb = LLVMAppendBasicBlock(limit_fn, module.new_string_ptr("entry"));
+    let parse_bb = LLVMAppendBasicBlock(limit_fn, module.new_string_ptr("parse"));
+    let clamp_bb = LLVMAppendBasicBlock(limit_fn, module.new_string_ptr("clamp"));
+    let unset_bb = LLVMAppendBasicBlock(limit_fn, module.new_string_ptr("unset"));
+    let builder = Builder::new();
+
+    // entry:
+    //   char *size = getenv("BF_TAPE_SIZE");
+    //   if (size == NULL) goto unset;
+    let name = add_const_string(module, b"BF_TAPE_SIZE\0", "tape_size_name");
+    let size = add_function_call(module, entry_bb, "getenv", &mut [name], "size");
+    builder.position_at_end(entry_bb);
+    let is_set = builder.is_not_null(module, size, "is_set");
+    builder.cond_br(is_set, parse_bb, unset_bb);
+
+    // parse:
+    //   int requested = atoi(size);
+    //   if (requested <= 0) goto unset;
+    let requested = add_function_call(module, parse_bb, "atoi", &mut [size], "requested");
+    builder.position_at_end(parse_bb);
+    let is_positive = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGT,
+        requested,
+        int32(0),
+        "is_positive",
+    );
+    builder.cond_br(is_positive, clamp_bb, unset_bb);
+
+    // clamp:
+    //   return min(max(requested, min_cells), max_cells);
+    builder.position_at_end(clamp_bb);
+    let too_small = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSLT,
+        requested,
+        min_cells,
+        "too_small",
+    );
+    let at_least_min = builder.select(module, too_small, min_cells, requested, "at_least_min");
+    let too_large = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGT,
+        at_least_min,
+        max_cells,
+        "too_large",
+    );
+    let limit = builder.select(module, too_large, max_cells, at_least_min, "limit");
+    builder.ret(limit);
+
+    // unset:
+    //   return default_cells;
+    builder.position_at_end(unset_bb);
+    builder.ret(default_cells);
+}
+
 /// Add prologue to main function.
+/// Define a function `read_const_input` that returns the bytes of
+/// `input`, then falls back to `getchar` once they've all been read.
+///
+/// ```c
+/// int read_const_input(void) {
+///     static int index = 0;
+///     if (index < INPUT_LEN) return const_input[index++];
+///     return getchar();
+/// }
+/// ```
+unsafe fn add_const_input_reader(module: &mut Module, input: &[u8]) -> &'static str {
+    let fn_name = "read_const_input";
+    add_function(module, fn_name, &mut [], int32_type());
+    let read_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+    LLVMSetLinkage(read_fn, LLVMLinkage::LLVMInternalLinkage);
+
+    let mut llvm_input: Vec<_> = input
+        .iter()
+        .map(|byte| int8(*byte as c_ulonglong))
+        .collect();
+    let input_type = LLVMArrayType(int8_type(), llvm_input.len() as c_uint);
+    let llvm_input_arr = LLVMConstArray(
+        int8_type(),
+        llvm_input.as_mut_ptr(),
+        llvm_input.len() as c_uint,
+    );
+    let const_input = LLVMAddGlobal(
+        module.module,
+        input_type,
+        module.new_string_ptr("const_input"),
+    );
+    LLVMSetInitializer(const_input, llvm_input_arr);
+    LLVMSetGlobalConstant(const_input, LLVM_TRUE);
+    LLVMSetLinkage(const_input, LLVMLinkage::LLVMInternalLinkage);
+
+    let index_ptr = LLVMAddGlobal(
+        module.module,
+        int32_type(),
+        module.new_string_ptr("const_input_index"),
+    );
+    LLVMSetInitializer(index_ptr, int32(0));
+    LLVMSetLinkage(index_ptr, LLVMLinkage::LLVMInternalLinkage);
+
+    let entry_bb = LLVMAppendBasicBlock(read_fn, module.new_string_ptr("entry"));
+    let known_bb = LLVMAppendBasicBlock(read_fn, module.new_string_ptr("known"));
+    let stdin_bb = LLVMAppendBasicBlock(read_fn, module.new_string_ptr("stdin"));
+    let builder = Builder::new();
+
+    builder.position_at_end(entry_bb);
+    let index = builder.load(module, index_ptr, "index");
+    let is_known = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntULT,
+        index,
+        int32(input.len() as c_ulonglong),
+        "is_known",
+    );
+    builder.cond_br(is_known, known_bb, stdin_bb);
+
+    builder.position_at_end(known_bb);
+    let mut indices = vec![int32(0), index];
+    let byte_ptr = builder.gep(module, const_input, &mut indices, "byte_ptr");
+    let byte = builder.load(module, byte_ptr, "byte");
+    let next_index = builder.add(module, index, int32(1), "next_index");
+    builder.store(next_index, index_ptr);
+    let byte_as_int = builder.zext(module, byte, int32_type(), "byte_as_int");
+    builder.ret(byte_as_int);
+
+    let mut getchar_args = vec![];
+    let input_char =
+        add_function_call(module, stdin_bb, "getchar", &mut getchar_args, "input_char");
+    builder.position_at_end(stdin_bb);
+    builder.ret(input_char);
+
+    fn_name
+}
+
+/// The size of the buffer from `add_output_buffer`.
+const OUTPUT_BUFFER_SIZE: u64 = 4096;
+
+/// Define functions that collect output in a buffer, so we make one
+/// write call per chunk rather than per byte.
+///
+/// ```c
+/// static char output_buffer[OUTPUT_BUFFER_SIZE];
+/// static int output_len = 0;
+///
+/// void flush_output(void) {
+///     if (output_len != 0) write(1, output_buffer, output_len);
+///     output_len = 0;
+/// }
+/// void output_byte(int c) {
+///     if (output_len == OUTPUT_BUFFER_SIZE) flush_output();
+///     output_buffer[output_len++] = c;
+/// }
+/// void output_bytes(char *bytes, int len) {
+///     for (int i = 0; i < len; i++) output_byte(bytes[i]);
+/// }
+/// ```
+unsafe fn add_output_buffer(module: &mut Module, options: &CodegenOptions) {
+    let buffer_type = LLVMArrayType(int8_type(), OUTPUT_BUFFER_SIZE as c_uint);
+    let buffer = LLVMAddGlobal(
+        module.module,
+        buffer_type,
+        module.new_string_ptr("output_buffer"),
+    );
+    LLVMSetInitializer(buffer, LLVMConstNull(buffer_type));
+    LLVMSetLinkage(buffer, LLVMLinkage::LLVMInternalLinkage);
+    let len_ptr = LLVMAddGlobal(
+        module.module,
+        int32_type(),
+        module.new_string_ptr("output_len"),
+    );
+    LLVMSetInitializer(len_ptr, int32(0));
+    LLVMSetLinkage(len_ptr, LLVMLinkage::LLVMInternalLinkage);
+
+    let void = LLVMVoidType();
+    add_function(module, "flush_output", &mut [], void);
+    add_function(module, "output_byte", &mut [int32_type()], void);
+    add_function(
+        module,
+        "output_bytes",
+        &mut [int8_ptr_type(), int32_type()],
+        void,
+    );
+    let mut functions = vec![];
+    for name in &["flush_output", "output_byte", "output_bytes"] {
+        let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(name));
+        LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage);
+        add_function_attributes(module, function, options.perf_friendly);
+        functions.push(function);
+    }
+    let (flush_fn, byte_fn, bytes_fn) = (functions[0], functions[1], functions[2]);
+    let builder = Builder::new();
+
+    // flush_output
+    let entry_bb = LLVMAppendBasicBlock(flush_fn, module.new_string_ptr("entry"));
+    let write_bb = LLVMAppendBasicBlock(flush_fn, module.new_string_ptr("write"));
+    let done_bb = LLVMAppendBasicBlock(flush_fn, module.new_string_ptr("done"));
+    builder.position_at_end(entry_bb);
+    let len = builder.load(module, len_ptr, "len");
+    let is_empty = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        len,
+        int32(0),
+        "is_empty",
+    );
+    builder.cond_br(is_empty, done_bb, write_bb);
+
+    builder.position_at_end(write_bb);
+    let buffer_ptr = builder.pointer_cast(module, buffer, int8_ptr_type(), "buffer_ptr");
+    add_write_all_call(module, write_bb, int32(1), buffer_ptr, len);
+    builder.position_at_end(write_bb);
+    builder.store(int32(0), len_ptr);
+    builder.br(done_bb);
+
+    builder.position_at_end(done_bb);
+    builder.ret_void();
+
+    // output_byte
+    let entry_bb = LLVMAppendBasicBlock(byte_fn, module.new_string_ptr("entry"));
+    let flush_bb = LLVMAppendBasicBlock(byte_fn, module.new_string_ptr("flush"));
+    let append_bb = LLVMAppendBasicBlock(byte_fn, module.new_string_ptr("append"));
+    builder.position_at_end(entry_bb);
+    let len = builder.load(module, len_ptr, "len");
+    let is_full = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        len,
+        int32(OUTPUT_BUFFER_SIZE),
+        "is_full",
+    );
+    builder.cond_br(is_full, flush_bb, append_bb);
+
+    add_function_call(module, flush_bb, "flush_output", &mut [], "");
+    builder.position_at_end(flush_bb);
+    builder.br(append_bb);
+
+    builder.position_at_end(append_bb);
+    let len = builder.load(module, len_ptr, "len");
+    let mut indices = [int32(0), len];
+    let byte_ptr = builder.gep(module, buffer, &mut indices, "byte_ptr");
+    let byte = builder.trunc(module, LLVMGetParam(byte_fn, 0), int8_type(), "byte");
+    builder.store(byte, byte_ptr);
+    let new_len = builder.add(module, len, int32(1), "new_len");
+    builder.store(new_len, len_ptr);
+    builder.ret_void();
+
+    // output_bytes
+    let entry_bb = LLVMAppendBasicBlock(bytes_fn, module.new_string_ptr("entry"));
+    let header_bb = LLVMAppendBasicBlock(bytes_fn, module.new_string_ptr("loop_header"));
+    let body_bb = LLVMAppendBasicBlock(bytes_fn, module.new_string_ptr("loop_body"));
+    let after_bb = LLVMAppendBasicBlock(bytes_fn, module.new_string_ptr("after_loop"));
+    builder.position_at_end(entry_bb);
+    let index_ptr = builder.alloca(module, int32_type(), "index_ptr");
+    builder.store(int32(0), index_ptr);
+    builder.br(header_bb);
+
+    builder.position_at_end(header_bb);
+    let index = builder.load(module, index_ptr, "index");
+    let done = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGE,
+        index,
+        LLVMGetParam(bytes_fn, 1),
+        "done",
+    );
+    builder.cond_br(done, after_bb, body_bb);
+
+    builder.position_at_end(body_bb);
+    let mut indices = [index];
+    let byte_ptr = builder.gep(module, LLVMGetParam(bytes_fn, 0), &mut indices, "byte_ptr");
+    let byte = builder.load(module, byte_ptr, "byte");
+    let byte_as_int = builder.zext(module, byte, int32_type(), "byte_as_int");
+    add_function_call(module, body_bb, "output_byte", &mut [byte_as_int], "");
+    builder.position_at_end(body_bb);
+    let next_index = builder.add(module, index, int32(1), "next_index");
+    builder.store(next_index, index_ptr);
+    builder.br(header_bb);
+
+    builder.position_at_end(after_bb);
+    builder.ret_void();
+}
+
+/// Define a function `read_after_flush` that flushes buffered output
+/// before calling `read_fn`, so prompts appear before we wait for
+/// input.
+unsafe fn add_flushing_reader(
+    module: &mut Module,
+    read_fn: &str,
+    options: &CodegenOptions,
+) -> &'static str {
+    let fn_name = "read_after_flush";
+    add_function(module, fn_name, &mut [], int32_type());
+    let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+    LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage);
+    add_function_attributes(module, function, options.perf_friendly);
+
+    let bb = LLVMAppendBasicBlock(function, module.new_string_ptr("entry"));
+    add_function_call(module, bb, "flush_output", &mut [], "");
+    let input_char = add_function_call(module, bb, read_fn, &mut [], "input_char");
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    builder.ret(input_char);
+
+    fn_name
+}
+
 unsafe fn add_main_cleanup(bb: LLVMBasicBlockRef) {
     let builder = Builder::new();
     builder.position_at_end(bb);
 
     let zero = int32(0);
-    LLVMBuildRet(builder.builder, zero);
+    builder.ret(zero);
 }
 
 /// Add LLVM IR instructions for accessing the current cell, and
@@ -383,29 +1838,32 @@ unsafe fn add_current_cell_access(
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
+    let cell_index = builder.load(module, cell_index_ptr, "cell_index");
 
     let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        cells,
-        indices.as_mut_ptr(),
-        indices.len() as u32,
-        module.new_string_ptr("current_cell_ptr"),
-    );
-    let current_cell = LLVMBuildLoad(
-        builder.builder,
-        current_cell_ptr,
-        module.new_string_ptr("cell_value"),
-    );
+    let current_cell_ptr = builder.gep(module, cells, &mut indices, "current_cell_ptr");
+    let current_cell = builder.load(module, current_cell_ptr, "cell_value");
 
     (current_cell, current_cell_ptr)
 }
 
+/// Return a pointer to the first cell. If the tape can grow, it may
+/// have moved, so we load its current location.
+unsafe fn cells_base(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMValueRef {
+    match ctx.growable {
+        Some(tape) => {
+            let builder = Builder::new();
+            builder.position_at_end(bb);
+            builder.load(module, tape.cells_ptr, "cells")
+        }
+        None => ctx.cells,
+    }
+}
+
 unsafe fn compile_increment(
     amount: Cell,
     offset: isize,
@@ -416,43 +1874,25 @@ unsafe fn compile_increment(
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
+    let cell_index = builder.load(module, ctx.cell_index_ptr, "cell_index");
 
-    let offset_cell_index = LLVMBuildAdd(
-        builder.builder,
+    let offset_cell_index = builder.add(
+        module,
         cell_index,
         int32(offset as c_ulonglong),
-        module.new_string_ptr("offset_cell_index"),
+        "offset_cell_index",
     );
 
+    let cells = cells_base(module, bb, &ctx);
     let mut indices = vec![offset_cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as c_uint,
-        module.new_string_ptr("current_cell_ptr"),
-    );
+    let current_cell_ptr = builder.gep(module, cells, &mut indices, "current_cell_ptr");
 
-    let cell_val = LLVMBuildLoad(
-        builder.builder,
-        current_cell_ptr,
-        module.new_string_ptr("cell_value"),
-    );
+    let cell_val = builder.load(module, current_cell_ptr, "cell_value");
 
-    let increment_amount = int8(amount.0 as c_ulonglong);
-    let new_cell_val = LLVMBuildAdd(
-        builder.builder,
-        cell_val,
-        increment_amount,
-        module.new_string_ptr("new_cell_value"),
-    );
+    let increment_amount = cell_const(amount, ctx.cell_bits);
+    let new_cell_val = builder.add(module, cell_val, increment_amount, "new_cell_value");
 
-    LLVMBuildStore(builder.builder, new_cell_val, current_cell_ptr);
+    builder.store(new_cell_val, current_cell_ptr);
     bb
 }
 
@@ -466,38 +1906,26 @@ unsafe fn compile_set(
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
+    let cell_index = builder.load(module, ctx.cell_index_ptr, "cell_index");
 
-    let offset_cell_index = LLVMBuildAdd(
-        builder.builder,
+    let offset_cell_index = builder.add(
+        module,
         cell_index,
         int32(offset as c_ulonglong),
-        module.new_string_ptr("offset_cell_index"),
+        "offset_cell_index",
     );
 
+    let cells = cells_base(module, bb, &ctx);
     let mut indices = vec![offset_cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as c_uint,
-        module.new_string_ptr("current_cell_ptr"),
-    );
+    let current_cell_ptr = builder.gep(module, cells, &mut indices, "current_cell_ptr");
 
-    LLVMBuildStore(
-        builder.builder,
-        int8(amount.0 as c_ulonglong),
-        current_cell_ptr,
-    );
+    builder.store(cell_const(amount, ctx.cell_bits), current_cell_ptr);
     bb
 }
 
 unsafe fn compile_multiply_move(
     changes: &HashMap<isize, Cell>,
+    adds: &HashMap<isize, Cell>,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
@@ -509,31 +1937,26 @@ unsafe fn compile_multiply_move(
     builder.position_at_end(bb);
 
     // First, get the current cell value.
-    let (cell_val, cell_val_ptr) =
-        add_current_cell_access(module, bb, ctx.cells, ctx.cell_index_ptr);
+    let cells = cells_base(module, bb, &ctx);
+    let (cell_val, cell_val_ptr) = add_current_cell_access(module, bb, cells, ctx.cell_index_ptr);
 
     // Check if the current cell is zero, as we only do the multiply
     // if it's non-zero.
-    let zero = int8(0);
-    let cell_val_is_zero = LLVMBuildICmp(
-        builder.builder,
+    let zero = cell_const(Wrapping(0), ctx.cell_bits);
+    let cell_val_is_zero = builder.icmp(
+        module,
         LLVMIntPredicate::LLVMIntEQ,
         zero,
         cell_val,
-        module.new_string_ptr("cell_value_is_zero"),
-    );
-    LLVMBuildCondBr(
-        builder.builder,
-        cell_val_is_zero,
-        multiply_after,
-        multiply_body,
+        "cell_value_is_zero",
     );
+    builder.cond_br(cell_val_is_zero, multiply_after, multiply_body);
 
     // In the multiply body, do the mulitply
     builder.position_at_end(multiply_body);
 
     // Zero the current cell.
-    LLVMBuildStore(builder.builder, int8(0), cell_val_ptr);
+    builder.store(zero, cell_val_ptr);
 
     let mut targets: Vec<_> = changes.keys().collect();
     targets.sort();
@@ -543,42 +1966,35 @@ unsafe fn compile_multiply_move(
     for target in targets {
         // Calculate the position of this target cell.
         let mut indices = vec![int32(*target as c_ulonglong)];
-        let target_cell_ptr = LLVMBuildGEP(
-            builder.builder,
-            cell_val_ptr,
-            indices.as_mut_ptr(),
-            indices.len() as c_uint,
-            module.new_string_ptr("target_cell_ptr"),
-        );
+        let target_cell_ptr = builder.gep(module, cell_val_ptr, &mut indices, "target_cell_ptr");
 
         // Get the current value of the target cell.
-        let target_cell_val = LLVMBuildLoad(
-            builder.builder,
-            target_cell_ptr,
-            module.new_string_ptr("target_cell_val"),
-        );
+        let target_cell_val = builder.load(module, target_cell_ptr, "target_cell_val");
 
         // Calculate the new value.
         let factor = *changes.get(target).unwrap();
-        let additional_val = LLVMBuildMul(
-            builder.builder,
+        let additional_val = builder.mul(
+            module,
             cell_val,
-            int8(factor.0 as c_ulonglong),
-            module.new_string_ptr("additional_val"),
-        );
-        let new_target_val = LLVMBuildAdd(
-            builder.builder,
-            target_cell_val,
-            additional_val,
-            module.new_string_ptr("new_target_val"),
+            cell_const(factor, ctx.cell_bits),
+            "additional_val",
         );
-        LLVMBuildStore(builder.builder, new_target_val, target_cell_ptr);
+        let new_target_val = builder.add(module, target_cell_val, additional_val, "new_target_val");
+        builder.store(new_target_val, target_cell_ptr);
     }
 
     // Finally, continue execution from multiply after.
-    LLVMBuildBr(builder.builder, multiply_after);
+    builder.br(multiply_after);
 
-    multiply_after
+    // The additions happen whether or not we multiplied.
+    let mut add_targets: Vec<_> = adds.keys().collect();
+    add_targets.sort();
+
+    let mut bb = multiply_after;
+    for target in add_targets {
+        bb = compile_increment(adds[target], *target, module, bb, ctx.clone());
+    }
+    bb
 }
 
 unsafe fn compile_ptr_increment(
@@ -590,20 +2006,16 @@ unsafe fn compile_ptr_increment(
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
+    let cell_index = builder.load(module, ctx.cell_index_ptr, "cell_index");
 
-    let new_cell_index = LLVMBuildAdd(
-        builder.builder,
+    let new_cell_index = builder.add(
+        module,
         cell_index,
         int32(amount as c_ulonglong),
-        module.new_string_ptr("new_cell_index"),
+        "new_cell_index",
     );
 
-    LLVMBuildStore(builder.builder, new_cell_index, ctx.cell_index_ptr);
+    builder.store(new_cell_index, ctx.cell_index_ptr);
     bb
 }
 
@@ -615,140 +2027,2621 @@ unsafe fn compile_read(
     let builder = Builder::new();
     builder.position_at_end(bb);
 
-    let cell_index = LLVMBuildLoad(
-        builder.builder,
-        ctx.cell_index_ptr,
-        module.new_string_ptr("cell_index"),
-    );
+    let cell_index = builder.load(module, ctx.cell_index_ptr, "cell_index");
 
+    let cells = cells_base(module, bb, &ctx);
     let mut indices = vec![cell_index];
-    let current_cell_ptr = LLVMBuildGEP(
-        builder.builder,
-        ctx.cells,
-        indices.as_mut_ptr(),
-        indices.len() as u32,
-        module.new_string_ptr("current_cell_ptr"),
-    );
+    let current_cell_ptr = builder.gep(module, cells, &mut indices, "current_cell_ptr");
+
+    let eof_value = match ctx.eof {
+        EofBehaviour::Unchanged => Some(builder.load(module, current_cell_ptr, "old_cell")),
+        EofBehaviour::Zero => Some(cell_const(Wrapping(0), ctx.cell_bits)),
+        EofBehaviour::MinusOne => None,
+    };
 
     let mut getchar_args = vec![];
-    let input_char = add_function_call(module, bb, "getchar", &mut getchar_args, "input_char");
-    let input_byte = LLVMBuildTrunc(
+    let input_char = add_function_call(module, bb, ctx.read_fn, &mut getchar_args, "input_char");
+    let input_byte = build_input_byte(module, &builder, input_char, ctx.cell_bits, eof_value);
+
+    builder.store(input_byte, current_cell_ptr);
+    bb
+}
+
+/// Convert `input_char`, as returned by getchar, to the value we
+/// store in a cell `cell_bits` wide. If `eof_value` is given, store
+/// that at EOF rather than -1.
+unsafe fn build_input_byte(
+    module: &mut Module,
+    builder: &Builder,
+    input_char: LLVMValueRef,
+    cell_bits: u32,
+    eof_value: Option<LLVMValueRef>,
+) -> LLVMValueRef {
+    // Cells as wide as an int need no conversion.
+    let input_byte = LLVMBuildIntCast(
         builder.builder,
         input_char,
-        int8_type(),
+        cell_type(cell_bits),
         module.new_string_ptr("input_byte"),
     );
-
-    LLVMBuildStore(builder.builder, input_byte, current_cell_ptr);
-    bb
+    match eof_value {
+        Some(eof_value) => {
+            let at_eof = builder.icmp(
+                module,
+                LLVMIntPredicate::LLVMIntEQ,
+                input_char,
+                LLVMConstInt(int32_type(), c_ulonglong::MAX, LLVM_TRUE),
+                "at_eof",
+            );
+            builder.select(module, at_eof, eof_value, input_byte, "input_or_eof")
+        }
+        None => input_byte,
+    }
 }
 
-unsafe fn compile_write(
+/// Read `count` bytes from stdin, storing only the last one. We
+/// consume the first `count - 1` bytes in a loop, so at EOF we behave
+/// exactly like `count` consecutive reads. If EOF leaves the cell
+/// unchanged, earlier reads may be the ones that set it, so we store
+/// every byte.
+unsafe fn compile_skip_read(
+    count: usize,
     module: &mut Module,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
     let builder = Builder::new();
-    builder.position_at_end(bb);
 
-    let cell_val = add_current_cell_access(module, bb, ctx.cells, ctx.cell_index_ptr).0;
-    let cell_val_as_char = LLVMBuildSExt(
-        builder.builder,
-        cell_val,
-        int32_type(),
-        module.new_string_ptr("cell_val_as_char"),
+    let skip_header_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("skip_header"));
+    builder.position_at_end(bb);
+    builder.br(skip_header_bb);
+
+    let skip_body_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("skip_body"));
+    let skip_after_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("skip_after"));
+
+    // skip_header:
+    //   %skip_count = phi i32 [0, %bb], [%skip_count_next, %skip_body]
+    //   %skip_done = icmp eq i32 %skip_count, COUNT - 1
+    //   br %skip_done, %skip_after, %skip_body
+    builder.position_at_end(skip_header_bb);
+    let skip_count = builder.phi(module, int32_type(), "skip_count");
+    let skip_done = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        skip_count,
+        int32(count as c_ulonglong - 1),
+        "skip_done",
+    );
+    builder.cond_br(skip_done, skip_after_bb, skip_body_bb);
+
+    // skip_body:
+    //   call @getchar()
+    //   %skip_count_next = add i32 %skip_count, 1
+    //   br %skip_header
+    if ctx.eof == EofBehaviour::Unchanged {
+        compile_read(module, skip_body_bb, ctx.clone());
+    } else {
+        let mut getchar_args = vec![];
+        add_function_call(module, skip_body_bb, ctx.read_fn, &mut getchar_args, "");
+    }
+    builder.position_at_end(skip_body_bb);
+    let skip_count_next = builder.add(module, skip_count, int32(1), "skip_count_next");
+    builder.br(skip_header_bb);
+
+    let mut incoming_values = vec![int32(0), skip_count_next];
+    let mut incoming_blocks = vec![bb, skip_body_bb];
+    LLVMAddIncoming(
+        skip_count,
+        incoming_values.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_values.len() as c_uint,
     );
 
-    let mut putchar_args = vec![cell_val_as_char];
-    add_function_call(module, bb, "putchar", &mut putchar_args, "");
-    bb
-}
-
-fn ptr_equal<T>(a: *const T, b: *const T) -> bool {
-    a == b
+    compile_read(module, skip_after_bb, ctx)
 }
 
-unsafe fn compile_loop(
-    loop_body: &[AstNode],
-    start_instr: &AstNode,
+/// Write a constant string to stdout with `write_all`, or to
+/// output_bytes if output is buffered. Bytes from putchar may
+/// still be in libc's stdout buffer, so we flush it first, declaring
+/// fflush on demand so modules that don't use it are unaffected.
+unsafe fn compile_write_string(
+    bytes: &[u8],
     module: &mut Module,
-    main_fn: LLVMValueRef,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
     let builder = Builder::new();
-
-    // First, we branch into the loop header from the previous basic
-    // block.
-    let loop_header_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_header"));
     builder.position_at_end(bb);
-    LLVMBuildBr(builder.builder, loop_header_bb);
-
-    let mut loop_body_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_body"));
-    let loop_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_after"));
-
-    // loop_header:
-    //   %cell_value = ...
-    //   %cell_value_is_zero = icmp ...
-    //   br %cell_value_is_zero, %loop_after, %loop_body
-    builder.position_at_end(loop_header_bb);
 
-    let cell_val =
-        add_current_cell_access(module, &mut *loop_header_bb, ctx.cells, ctx.cell_index_ptr).0;
+    let mut llvm_bytes: Vec<_> = bytes
+        .iter()
+        .map(|byte| int8(*byte as c_ulonglong))
+        .collect();
+    let string_type = LLVMArrayType(int8_type(), llvm_bytes.len() as c_uint);
+    let llvm_string = LLVMConstArray(
+        int8_type(),
+        llvm_bytes.as_mut_ptr(),
+        llvm_bytes.len() as c_uint,
+    );
 
-    let zero = int8(0);
-    let cell_val_is_zero = LLVMBuildICmp(
-        builder.builder,
-        LLVMIntPredicate::LLVMIntEQ,
-        zero,
-        cell_val,
-        module.new_string_ptr("cell_value_is_zero"),
+    let known_string = LLVMAddGlobal(
+        module.module,
+        string_type,
+        module.new_string_ptr("known_string"),
     );
-    LLVMBuildCondBr(builder.builder, cell_val_is_zero, loop_after, loop_body_bb);
+    LLVMSetInitializer(known_string, llvm_string);
+    LLVMSetGlobalConstant(known_string, LLVM_TRUE);
 
-    // Recursively compile instructions in the loop body.
-    for instr in loop_body {
-        if ptr_equal(instr, start_instr) {
-            // This is the point we want to start execution from.
-            loop_body_bb = set_entry_point_after(module, main_fn, loop_body_bb);
-        }
+    let known_string_ptr =
+        builder.pointer_cast(module, known_string, int8_ptr_type(), "known_string_ptr");
 
-        loop_body_bb = compile_instr(
-            instr,
-            start_instr,
+    if ctx.buffered_output {
+        add_function_call(
             module,
-            main_fn,
-            loop_body_bb,
-            ctx.clone(),
+            bb,
+            "output_bytes",
+            &mut [known_string_ptr, int32(bytes.len() as c_ulonglong)],
+            "",
         );
+        return bb;
     }
 
-    // When the loop is finished, jump back to the beginning of the
-    // loop.
-    builder.position_at_end(loop_body_bb);
-    LLVMBuildBr(builder.builder, loop_header_bb);
+    if ctx.stdio {
+        if LLVMGetNamedFunction(module.module, module.new_string_ptr("fflush")).is_null() {
+            add_function(module, "fflush", &mut [int8_ptr_type()], int32_type());
+        }
+        // fflush(NULL) flushes every stream, so we don't need to
+        // know what the target calls stdout.
+        add_function_call(
+            module,
+            bb,
+            "fflush",
+            &mut [LLVMConstNull(int8_ptr_type())],
+            "",
+        );
+    }
 
-    &mut *loop_after
+    add_write_all_call(
+        module,
+        bb,
+        int32(1),
+        known_string_ptr,
+        int32(bytes.len() as c_ulonglong),
+    );
+    bb
 }
 
-/// Append LLVM IR instructions to bb acording to the BF instruction
-/// passed in.
-unsafe fn compile_instr(
-    instr: &AstNode,
-    start_instr: &AstNode,
+unsafe fn compile_write(
     module: &mut Module,
-    main_fn: LLVMValueRef,
     bb: LLVMBasicBlockRef,
     ctx: CompileContext,
 ) -> LLVMBasicBlockRef {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cells = cells_base(module, bb, &ctx);
+    let cell_val = add_current_cell_access(module, bb, cells, ctx.cell_index_ptr).0;
+    // putchar only writes the low byte, so wider cells are
+    // truncated.
+    let cell_val_as_char = LLVMBuildSExtOrBitCast(
+        builder.builder,
+        cell_val,
+        int32_type(),
+        module.new_string_ptr("cell_val_as_char"),
+    );
+
+    let write_fn = if ctx.buffered_output {
+        "output_byte"
+    } else {
+        "putchar"
+    };
+    let mut putchar_args = vec![cell_val_as_char];
+    add_function_call(module, bb, write_fn, &mut putchar_args, "");
+    bb
+}
+
+fn ptr_equal<T>(a: *const T, b: *const T) -> bool {
+    a == b
+}
+
+/// Tell LLVM how often each successor of `branch` was taken during
+/// compile time execution. Weights are 32-bit, so large counts are
+/// scaled down.
+unsafe fn add_branch_weights(module: &mut Module, branch: LLVMValueRef, counts: &[u64]) {
+    let max = counts.iter().copied().max().unwrap_or(0);
+    if max == 0 {
+        return;
+    }
+    let scale = max / u64::from(u32::MAX) + 1;
+
+    let name = "branch_weights";
+    let mut operands = vec![LLVMMDString(module.new_string_ptr(name), name.len() as c_uint)];
+    for &count in counts {
+        operands.push(int32(count / scale));
+    }
+    let weights = LLVMMDNode(operands.as_mut_ptr(), operands.len() as c_uint);
+
+    let kind = "prof";
+    let kind_id = LLVMGetMDKindID(module.new_string_ptr(kind), kind.len() as c_uint);
+    LLVMSetMetadata(branch, kind_id, weights);
+}
+
+/// Give LLVM's loop passes hints from compile time execution, by
+/// adding `llvm.loop` metadata to the branch back to the loop header.
+/// A loop that ran at most once each time it was entered isn't worth
+/// unrolling. Other loops are left to LLVM, which estimates their
+/// trip counts from the branch weights.
+unsafe fn add_loop_metadata(module: &mut Module, back_edge: LLVMValueRef, counts: &LoopCounts) {
+    if !counts.is_cold() {
+        return;
+    }
+
+    // A loop ID starts with a reference to itself, so we start with
+    // a placeholder and replace it once the node exists. LLVM makes
+    // self-referencing nodes distinct, so loops don't share IDs.
+    let context = LLVMGetModuleContext(module.module);
+    let placeholder = LLVMTemporaryMDNode(context, null_mut(), 0);
+
+    let name = "llvm.loop.unroll.disable";
+    let mut hint = vec![LLVMMDString(
+        module.new_string_ptr(name),
+        name.len() as c_uint,
+    )];
+    let mut operands = vec![
+        LLVMMetadataAsValue(context, placeholder),
+        LLVMMDNode(hint.as_mut_ptr(), hint.len() as c_uint),
+    ];
+    let loop_id = LLVMMDNode(operands.as_mut_ptr(), operands.len() as c_uint);
+    LLVMMetadataReplaceAllUsesWith(placeholder, LLVMValueAsMetadata(loop_id));
+
+    let kind = "llvm.loop";
+    let kind_id = LLVMGetMDKindID(module.new_string_ptr(kind), kind.len() as c_uint);
+    LLVMSetMetadata(back_edge, kind_id, loop_id);
+}
+
+/// Print `message` to stderr and abort, unless the cells from
+/// `lowest` to `highest` offsets from the current cell are all on
+/// the tape. If the tape can grow, we grow it instead of aborting
+/// when only `highest` is off the end. We declare abort on demand,
+/// so modules without checks are unaffected.
+unsafe fn compile_ptr_check(
+    lowest: i64,
+    highest: i64,
+    message: &str,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMBasicBlockRef {
+    // Guard pages check the pointer for free, so we only need to
+    // say who to blame.
+    if ctx.guard_pages {
+        compile_overrun_marker(message, module, bb);
+        return bb;
+    }
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cell_index = builder.load(module, ctx.cell_index_ptr, "cell_index");
+    let lowest_index = builder.add(
+        module,
+        cell_index,
+        int32(lowest as c_ulonglong),
+        "lowest_index",
+    );
+    let highest_index = builder.add(
+        module,
+        cell_index,
+        int32(highest as c_ulonglong),
+        "highest_index",
+    );
+    let lowest_on_tape = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGE,
+        lowest_index,
+        int32(0),
+        "lowest_on_tape",
+    );
+    let tape_len = match ctx.growable {
+        Some(tape) => builder.load(module, tape.num_cells_ptr, "num_cells"),
+        None => int32(ctx.num_cells as c_ulonglong),
+    };
+    let highest_on_tape = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSLT,
+        highest_index,
+        tape_len,
+        "highest_on_tape",
+    );
+    let on_tape = builder.and(module, lowest_on_tape, highest_on_tape, "on_tape");
+
+    let check_passed = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("ptr_check_passed"));
+    let check_failed = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("ptr_check_failed"));
+    match ctx.growable {
+        Some(tape) => {
+            let grow_check =
+                LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("ptr_check_grow"));
+            builder.cond_br(on_tape, check_passed, grow_check);
+
+            builder.position_at_end(grow_check);
+            let max_len = tape.max_len(module, &builder);
+            let below_limit = builder.icmp(
+                module,
+                LLVMIntPredicate::LLVMIntSLT,
+                highest_index,
+                max_len,
+                "below_limit",
+            );
+            let can_grow = builder.and(module, lowest_on_tape, below_limit, "can_grow");
+            let grow = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("tape_grow"));
+            builder.cond_br(can_grow, grow, check_failed);
+
+            compile_tape_grow(tape, tape_len, highest_index, module, grow, ctx);
+            builder.position_at_end(grow);
+            builder.br(check_passed);
+        }
+        None => {
+            builder.cond_br(on_tape, check_passed, check_failed);
+        }
+    }
+
+    // ptr_check_failed:
+    //   call void @report_ptr_error(i8* %message_ptr, i32 len, i32 %cell_index)
+    //   unreachable
+    add_ptr_error_reporter(module, ctx.buffered_output);
+    let message_ptr = add_const_string(module, message.as_bytes(), "ptr_check_message");
+    add_function_call(
+        module,
+        check_failed,
+        "report_ptr_error",
+        &mut [message_ptr, int32(message.len() as c_ulonglong), cell_index],
+        "",
+    );
+    builder.position_at_end(check_failed);
+    builder.unreachable();
+
+    check_passed
+}
+
+/// Add a constant global holding `bytes`, and return a pointer to
+/// its first byte.
+unsafe fn add_const_string(module: &mut Module, bytes: &[u8], name: &str) -> LLVMValueRef {
+    let mut llvm_bytes: Vec<_> = bytes
+        .iter()
+        .map(|&byte| int8(byte as c_ulonglong))
+        .collect();
+    let string_type = LLVMArrayType(int8_type(), llvm_bytes.len() as c_uint);
+    let llvm_string = LLVMConstArray(
+        int8_type(),
+        llvm_bytes.as_mut_ptr(),
+        llvm_bytes.len() as c_uint,
+    );
+    let global = LLVMAddGlobal(module.module, string_type, module.new_string_ptr(name));
+    LLVMSetInitializer(global, llvm_string);
+    LLVMSetGlobalConstant(global, LLVM_TRUE);
+    LLVMSetLinkage(global, LLVMLinkage::LLVMInternalLinkage);
+    LLVMConstPointerCast(global, int8_ptr_type())
+}
+
+/// Write the constant `text` to stderr at the end of `bb`.
+unsafe fn add_stderr_write(module: &mut Module, bb: LLVMBasicBlockRef, text: &str) {
+    let text_ptr = add_const_string(module, text.as_bytes(), "stderr_text");
+    let len = int32(text.len() as c_ulonglong);
+    add_write_all_call(module, bb, int32(2), text_ptr, len);
+}
+
+/// Call `void write_all(int fd, char *buf, int len)` at the end of
+/// `bb`, declaring it if we haven't already. `define_write_all` adds
+/// its body once the rest of the program is compiled.
+unsafe fn add_write_all_call(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    fd: LLVMValueRef,
+    buf: LLVMValueRef,
+    len: LLVMValueRef,
+) {
+    let fn_name = "write_all";
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name)).is_null() {
+        add_function(
+            module,
+            fn_name,
+            &mut [int32_type(), int8_ptr_type(), int32_type()],
+            LLVMVoidType(),
+        );
+        let write_all_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+        LLVMSetLinkage(write_all_fn, LLVMLinkage::LLVMInternalLinkage);
+    }
+    add_function_call(module, bb, fn_name, &mut [fd, buf, len], "");
+}
+
+/// EINTR, on every target `errno_location_fn` knows.
+const EINTR: u64 = 4;
+
+/// The libc function that returns a pointer to `errno` on this
+/// target, if we know it.
+fn errno_location_fn(target_triple: &str) -> Option<&'static str> {
+    if target_triple.contains("android") {
+        Some("__errno")
+    } else if target_triple.contains("linux") {
+        Some("__errno_location")
+    } else if ["apple", "darwin", "freebsd"]
+        .iter()
+        .any(|os| target_triple.contains(os))
+    {
+        Some("__error")
+    } else if ["netbsd", "openbsd"]
+        .iter()
+        .any(|os| target_triple.contains(os))
+    {
+        Some("__errno")
+    } else if target_triple.contains("-windows") {
+        Some("_errno")
+    } else {
+        None
+    }
+}
+
+/// Define `write_all`, if the program calls it, to call `write` until
+/// all of `buf` is written. `write` may write fewer bytes than we
+/// asked for, or fail with EINTR if a signal arrives first, so we
+/// retry in both cases. Without `errno_fn` we can't tell why `write`
+/// failed, so we give up on any failure.
+///
+/// ```c
+/// void write_all(int fd, char *buf, int len) {
+///     size_t remaining = len;
+///     while (remaining != 0) {
+///         ssize_t written = write(fd, buf, remaining);
+///         if (written > 0) {
+///             buf += written;
+///             remaining -= written;
+///         } else if (written == 0 || *errno_fn() != EINTR) {
+///             return;
+///         }
+///     }
+/// }
+/// ```
+unsafe fn define_write_all(module: &mut Module, errno_fn: Option<&str>) {
+    let write_all_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("write_all"));
+    if write_all_fn.is_null() {
+        return;
+    }
+    let write_size = write_size_type(module);
+    let zero = LLVMConstInt(write_size, 0, LLVM_FALSE);
+    let fd = LLVMGetParam(write_all_fn, 0);
+    let buf = LLVMGetParam(write_all_fn, 1);
+    let len = LLVMGetParam(write_all_fn, 2);
+    for &(param, name) in &[(fd, "fd"), (buf, "buf"), (len, "len")] {
+        LLVMSetValueName2(param, module.new_string_ptr(name), name.len());
+    }
+
+    let entry_bb = LLVMAppendBasicBlock(write_all_fn, module.new_string_ptr("entry"));
+    let header_bb = LLVMAppendBasicBlock(write_all_fn, module.new_string_ptr("loop_header"));
+    let write_bb = LLVMAppendBasicBlock(write_all_fn, module.new_string_ptr("write"));
+    let wrote_bb = LLVMAppendBasicBlock(write_all_fn, module.new_string_ptr("wrote"));
+    let failed_bb = LLVMAppendBasicBlock(write_all_fn, module.new_string_ptr("failed"));
+    let done_bb = LLVMAppendBasicBlock(write_all_fn, module.new_string_ptr("done"));
+    let builder = Builder::new();
+
+    builder.position_at_end(entry_bb);
+    let len = builder.zext(module, len, write_size, "len_size");
+    builder.br(header_bb);
+
+    builder.position_at_end(header_bb);
+    let remaining_buf = builder.phi(module, int8_ptr_type(), "remaining_buf");
+    let remaining = builder.phi(module, write_size, "remaining");
+    let is_done = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        remaining,
+        zero,
+        "is_done",
+    );
+    builder.cond_br(is_done, done_bb, write_bb);
+
+    let mut write_args = [fd, remaining_buf, remaining];
+    let written = add_function_call(module, write_bb, "write", &mut write_args, "written");
+    builder.position_at_end(write_bb);
+    let wrote_some = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGT,
+        written,
+        zero,
+        "wrote_some",
+    );
+    builder.cond_br(wrote_some, wrote_bb, failed_bb);
+
+    builder.position_at_end(wrote_bb);
+    let mut indices = [written];
+    let next_buf = builder.gep(module, remaining_buf, &mut indices, "next_buf");
+    let next_remaining = builder.sub(module, remaining, written, "next_remaining");
+    builder.br(header_bb);
+
+    let mut incoming_bufs = vec![buf, next_buf];
+    let mut incoming_remaining = vec![len, next_remaining];
+    let mut incoming_blocks = vec![entry_bb, wrote_bb];
+
+    builder.position_at_end(failed_bb);
+    match errno_fn {
+        Some(errno_fn) => {
+            if LLVMGetNamedFunction(module.module, module.new_string_ptr(errno_fn)).is_null() {
+                let errno_ptr_type = LLVMPointerType(int32_type(), 0);
+                add_function(module, errno_fn, &mut [], errno_ptr_type);
+            }
+            let errno_ptr = add_function_call(module, failed_bb, errno_fn, &mut [], "errno_ptr");
+            builder.position_at_end(failed_bb);
+            let errno = builder.load(module, errno_ptr, "errno");
+            let is_error = builder.icmp(
+                module,
+                LLVMIntPredicate::LLVMIntSLT,
+                written,
+                zero,
+                "is_error",
+            );
+            let is_eintr = builder.icmp(
+                module,
+                LLVMIntPredicate::LLVMIntEQ,
+                errno,
+                int32(EINTR),
+                "is_eintr",
+            );
+            let interrupted = builder.and(module, is_error, is_eintr, "interrupted");
+            builder.cond_br(interrupted, header_bb, done_bb);
+
+            incoming_bufs.push(remaining_buf);
+            incoming_remaining.push(remaining);
+            incoming_blocks.push(failed_bb);
+        }
+        None => {
+            builder.br(done_bb);
+        }
+    }
+
+    LLVMAddIncoming(
+        remaining_buf,
+        incoming_bufs.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_blocks.len() as c_uint,
+    );
+    LLVMAddIncoming(
+        remaining,
+        incoming_remaining.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_blocks.len() as c_uint,
+    );
+
+    builder.position_at_end(done_bb);
+    builder.ret_void();
+}
+
+/// The most characters in an i32 written in decimal.
+const MAX_I32_DIGITS: u64 = 11;
+
+/// Define `void write_int(int value)`, which writes `value` to
+/// stderr in decimal, unless we already have.
+unsafe fn add_int_writer(module: &mut Module) {
+    let fn_name = "write_int";
+    if !LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name)).is_null() {
+        return;
+    }
+    add_function(module, fn_name, &mut [int32_type()], LLVMVoidType());
+    let write_int_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+    LLVMSetLinkage(write_int_fn, LLVMLinkage::LLVMInternalLinkage);
+    let value = LLVMGetParam(write_int_fn, 0);
+
+    let entry_bb = LLVMAppendBasicBlock(write_int_fn, module.new_string_ptr("entry"));
+    let digit_bb = LLVMAppendBasicBlock(write_int_fn, module.new_string_ptr("digit"));
+    let digits_done_bb = LLVMAppendBasicBlock(write_int_fn, module.new_string_ptr("digits_done"));
+    let builder = Builder::new();
+
+    // entry:
+    //   char digits[MAX_I32_DIGITS];
+    //   unsigned magnitude = value < 0 ? -value : value;
+    builder.position_at_end(entry_bb);
+    let digits = builder.alloca(
+        module,
+        LLVMArrayType(int8_type(), MAX_I32_DIGITS as c_uint),
+        "digits",
+    );
+    let is_negative = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSLT,
+        value,
+        int32(0),
+        "is_negative",
+    );
+    let negated = builder.neg(module, value, "negated");
+    let magnitude = builder.select(module, is_negative, negated, value, "magnitude");
+    builder.br(digit_bb);
+
+    // We write the digits backwards from the end of the buffer.
+    // digit:
+    //   %pos = phi i32 [MAX_I32_DIGITS, %entry], [%next_pos, %digit]
+    //   %remaining = phi i32 [%magnitude, %entry], [%quotient, %digit]
+    //   %next_pos = sub i32 %pos, 1
+    //   digits[%next_pos] = '0' + %remaining % 10
+    //   %quotient = udiv i32 %remaining, 10
+    //   br %quotient != 0, %digit, %digits_done
+    builder.position_at_end(digit_bb);
+    let pos = builder.phi(module, int32_type(), "pos");
+    let remaining = builder.phi(module, int32_type(), "remaining");
+    let next_pos = builder.sub(module, pos, int32(1), "next_pos");
+    let remainder = builder.urem(module, remaining, int32(10), "remainder");
+    let remainder = builder.trunc(module, remainder, int8_type(), "remainder_byte");
+    let digit = builder.add(module, remainder, int8(u64::from(b'0')), "digit");
+    let mut indices = vec![int32(0), next_pos];
+    let digit_ptr = builder.gep(module, digits, &mut indices, "digit_ptr");
+    builder.store(digit, digit_ptr);
+    let quotient = builder.udiv(module, remaining, int32(10), "quotient");
+    let more_digits = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntNE,
+        quotient,
+        int32(0),
+        "more_digits",
+    );
+    builder.cond_br(more_digits, digit_bb, digits_done_bb);
+
+    let mut incoming_blocks = vec![entry_bb, digit_bb];
+    let mut incoming_values = vec![int32(MAX_I32_DIGITS), next_pos];
+    LLVMAddIncoming(
+        pos,
+        incoming_values.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_values.len() as c_uint,
+    );
+    let mut incoming_values = vec![magnitude, quotient];
+    LLVMAddIncoming(
+        remaining,
+        incoming_values.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_values.len() as c_uint,
+    );
+
+    // There are at most ten digits, so there's always room for the
+    // sign.
+    // digits_done:
+    //   digits[%next_pos - 1] = '-';
+    //   %start = value < 0 ? %next_pos - 1 : %next_pos
+    //   write(2, &digits[%start], MAX_I32_DIGITS - %start);
+    builder.position_at_end(digits_done_bb);
+    let sign_pos = builder.sub(module, next_pos, int32(1), "sign_pos");
+    let mut indices = vec![int32(0), sign_pos];
+    let sign_ptr = builder.gep(module, digits, &mut indices, "sign_ptr");
+    builder.store(int8(u64::from(b'-')), sign_ptr);
+    let start = builder.select(module, is_negative, sign_pos, next_pos, "start");
+    let mut indices = vec![int32(0), start];
+    let number = builder.gep(module, digits, &mut indices, "number");
+    let number_len = builder.sub(module, int32(MAX_I32_DIGITS), start, "number_len");
+    add_write_all_call(module, digits_done_bb, int32(2), number, number_len);
+    builder.position_at_end(digits_done_bb);
+    builder.ret_void();
+}
+
+/// Define
+///
+/// ```c
+/// void report_ptr_error(char *message, int len, int cell_index);
+/// ```
+///
+/// unless we already have, which prints `message` and the cell the
+/// pointer was at, then aborts. Every pointer check shares it, so
+/// each check only passes the position of its instruction.
+unsafe fn add_ptr_error_reporter(module: &mut Module, buffered_output: bool) {
+    let fn_name = "report_ptr_error";
+    if !LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name)).is_null() {
+        return;
+    }
+    let void = LLVMVoidType();
+    add_function(
+        module,
+        fn_name,
+        &mut [int8_ptr_type(), int32_type(), int32_type()],
+        void,
+    );
+    let report_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr(fn_name));
+    LLVMSetLinkage(report_fn, LLVMLinkage::LLVMInternalLinkage);
+    for name in &["cold", "noreturn"] {
+        let attribute = enum_attribute(module, name, 0);
+        LLVMAddAttributeAtIndex(report_fn, LLVMAttributeFunctionIndex, attribute);
+    }
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("abort")).is_null() {
+        add_function(module, "abort", &mut [], void);
+    }
+    add_int_writer(module);
+    let message = LLVMGetParam(report_fn, 0);
+    let len = LLVMGetParam(report_fn, 1);
+    let cell_index = LLVMGetParam(report_fn, 2);
+
+    // write(2, message, len);
+    // write(2, " (pointer at cell ", 18);
+    // write_int(cell_index);
+    // write(2, ")\n", 2);
+    // abort();
+    let bb = LLVMAppendBasicBlock(report_fn, module.new_string_ptr("entry"));
+    // Show the output so far before the error.
+    if buffered_output {
+        add_function_call(module, bb, "flush_output", &mut [], "");
+    }
+    add_write_all_call(module, bb, int32(2), message, len);
+    add_stderr_write(module, bb, " (pointer at cell ");
+    add_function_call(module, bb, "write_int", &mut [cell_index], "");
+    add_stderr_write(module, bb, ")\n");
+    add_function_call(module, bb, "abort", &mut [], "");
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    builder.unreachable();
+}
+
+/// Add a variable to main. The debug runtime's signal handler reads
+/// main's state, so under `debug_runtime` it's a global instead.
+unsafe fn add_main_variable(
+    module: &mut Module,
+    builder: &Builder,
+    ty: LLVMTypeRef,
+    name: &str,
+    debug_runtime: bool,
+) -> LLVMValueRef {
+    if debug_runtime {
+        let global = LLVMAddGlobal(module.module, ty, module.new_string_ptr(name));
+        LLVMSetInitializer(global, LLVMConstNull(ty));
+        LLVMSetLinkage(global, LLVMLinkage::LLVMInternalLinkage);
+        global
+    } else {
+        builder.alloca(module, ty, name)
+    }
+}
+
+/// The cells either side of the pointer that the debug runtime
+/// shows.
+const DEBUG_WINDOW_CELLS: u64 = 8;
+
+const SIGINT: u64 = 2;
+
+/// Install handlers for SIGINT and SIGSEGV that print the signal,
+/// where the pointer is, the cells around it and, with debug info,
+/// the last instruction to start, then exit.
+unsafe fn add_debug_runtime(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+    tape_len: usize,
+    options: &CodegenOptions,
+) {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // A fixed tape doesn't move, so we only need to tell the handler
+    // where it is.
+    let (cells_ptr, num_cells_ptr) = match ctx.growable {
+        Some(tape) => (tape.cells_ptr, tape.num_cells_ptr),
+        None => {
+            let cells_ptr = add_main_variable(
+                module,
+                &builder,
+                cell_ptr_type(ctx.cell_bits),
+                "debug_cells",
+                true,
+            );
+            builder.store(ctx.cells, cells_ptr);
+            let num_cells_ptr =
+                add_main_variable(module, &builder, int32_type(), "debug_num_cells", true);
+            builder.store(int32(tape_len as c_ulonglong), num_cells_ptr);
+            (cells_ptr, num_cells_ptr)
+        }
+    };
+    let location_ptr = if ctx.debug_scope.is_some() {
+        Some(add_main_variable(
+            module,
+            &builder,
+            int8_ptr_type(),
+            "debug_location",
+            true,
+        ))
+    } else {
+        None
+    };
+
+    let void = LLVMVoidType();
+    add_int_writer(module);
+    add_function(module, "_exit", &mut [int32_type()], void);
+    if location_ptr.is_some() {
+        add_function(module, "strlen", &mut [int8_ptr_type()], int32_type());
+    }
+
+    let mut handler_args = vec![int32_type()];
+    let handler_type = LLVMFunctionType(
+        void,
+        handler_args.as_mut_ptr(),
+        handler_args.len() as c_uint,
+        LLVM_FALSE,
+    );
+    let handler = LLVMAddFunction(
+        module.module,
+        module.new_string_ptr("debug_handler"),
+        handler_type,
+    );
+    LLVMSetLinkage(handler, LLVMLinkage::LLVMInternalLinkage);
+    let signal = LLVMGetParam(handler, 0);
+    let entry_bb = LLVMAppendBasicBlock(handler, module.new_string_ptr("entry"));
+    let cell_bb = LLVMAppendBasicBlock(handler, module.new_string_ptr("cell"));
+    let cells_done_bb = LLVMAppendBasicBlock(handler, module.new_string_ptr("cells_done"));
+    let exit_bb = LLVMAppendBasicBlock(handler, module.new_string_ptr("exit"));
+
+    // entry:
+    //   fprintf(stderr, "\nStopped by signal %d\n", signal);
+    //   fprintf(stderr, "Pointer at cell %d\n", cell_index);
+    //   int low = max(cell_index - WINDOW, 0);
+    //   int high = min(cell_index + WINDOW + 1, num_cells);
+    //   fprintf(stderr, "Cells from %d:", low);
+    // Show the output so far first.
+    if options.buffered_output {
+        add_function_call(module, entry_bb, "flush_output", &mut [], "");
+    }
+    add_stderr_write(module, entry_bb, "\nStopped by signal ");
+    add_function_call(module, entry_bb, "write_int", &mut [signal], "");
+    add_stderr_write(module, entry_bb, "\nPointer at cell ");
+    builder.position_at_end(entry_bb);
+    let cell_index = builder.load(module, ctx.cell_index_ptr, "cell_index");
+    LLVMSetVolatile(cell_index, LLVM_TRUE);
+    add_function_call(module, entry_bb, "write_int", &mut [cell_index], "");
+    add_stderr_write(module, entry_bb, "\n");
+
+    builder.position_at_end(entry_bb);
+    let cells = builder.load(module, cells_ptr, "cells");
+    LLVMSetVolatile(cells, LLVM_TRUE);
+    let num_cells = builder.load(module, num_cells_ptr, "num_cells");
+    LLVMSetVolatile(num_cells, LLVM_TRUE);
+    let low = builder.sub(module, cell_index, int32(DEBUG_WINDOW_CELLS), "low");
+    let low_on_tape = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGE,
+        low,
+        int32(0),
+        "low_on_tape",
+    );
+    let low = builder.select(module, low_on_tape, low, int32(0), "window_low");
+    let high = builder.add(module, cell_index, int32(DEBUG_WINDOW_CELLS + 1), "high");
+    let high_on_tape = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSLE,
+        high,
+        num_cells,
+        "high_on_tape",
+    );
+    let high = builder.select(module, high_on_tape, high, num_cells, "window_high");
+    add_stderr_write(module, entry_bb, "Cells from ");
+    add_function_call(module, entry_bb, "write_int", &mut [low], "");
+    add_stderr_write(module, entry_bb, ":");
+    builder.position_at_end(entry_bb);
+    let any_cells = builder.icmp(module, LLVMIntPredicate::LLVMIntSLT, low, high, "any_cells");
+    builder.cond_br(any_cells, cell_bb, cells_done_bb);
+
+    // We mark the current cell with brackets.
+    // cell:
+    //   %index = phi i32 [%low, %entry], [%next_index, %cell]
+    //   fprintf(stderr, %index == cell_index ? " [%d]" : " %d", cells[%index]);
+    //   %next_index = add i32 %index, 1
+    //   br %next_index < %high, %cell, %cells_done
+    builder.position_at_end(cell_bb);
+    let index = builder.phi(module, int32_type(), "index");
+    let is_current = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        index,
+        cell_index,
+        "is_current",
+    );
+    let open = b" [";
+    let open_ptr = add_const_string(module, open, "debug_cell_open");
+    let open_len = builder.select(module, is_current, int32(2), int32(1), "open_len");
+    add_write_all_call(module, cell_bb, int32(2), open_ptr, open_len);
+    builder.position_at_end(cell_bb);
+    let mut indices = vec![index];
+    let cell_ptr = builder.gep(module, cells, &mut indices, "cell_ptr");
+    let cell_value = builder.load(module, cell_ptr, "cell_value");
+    LLVMSetVolatile(cell_value, LLVM_TRUE);
+    // Show cells as unsigned, as BF programs usually treat them.
+    let cell_value = LLVMBuildZExtOrBitCast(
+        builder.builder,
+        cell_value,
+        int32_type(),
+        module.new_string_ptr("cell_int"),
+    );
+    add_function_call(module, cell_bb, "write_int", &mut [cell_value], "");
+    builder.position_at_end(cell_bb);
+    let close_ptr = add_const_string(module, b"]", "debug_cell_close");
+    let close_len = builder.zext(module, is_current, int32_type(), "close_len");
+    add_write_all_call(module, cell_bb, int32(2), close_ptr, close_len);
+    builder.position_at_end(cell_bb);
+    let next_index = builder.add(module, index, int32(1), "next_index");
+    let more_cells = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSLT,
+        next_index,
+        high,
+        "more_cells",
+    );
+    builder.cond_br(more_cells, cell_bb, cells_done_bb);
+
+    let mut incoming_values = vec![low, next_index];
+    let mut incoming_blocks = vec![entry_bb, cell_bb];
+    LLVMAddIncoming(
+        index,
+        incoming_values.as_mut_ptr(),
+        incoming_blocks.as_mut_ptr(),
+        incoming_values.len() as c_uint,
+    );
+
+    // cells_done:
+    //   fprintf(stderr, "\n");
+    //   if (debug_location) fprintf(stderr, "At %s\n", debug_location);
+    add_stderr_write(module, cells_done_bb, "\n");
+    builder.position_at_end(cells_done_bb);
+    match location_ptr {
+        Some(location_ptr) => {
+            let location_bb = LLVMAppendBasicBlock(handler, module.new_string_ptr("location"));
+            let location = builder.load(module, location_ptr, "location");
+            LLVMSetVolatile(location, LLVM_TRUE);
+            let has_location = builder.is_not_null(module, location, "has_location");
+            builder.cond_br(has_location, location_bb, exit_bb);
+
+            add_stderr_write(module, location_bb, "At ");
+            let len = add_function_call(module, location_bb, "strlen", &mut [location], "len");
+            add_write_all_call(module, location_bb, int32(2), location, len);
+            add_stderr_write(module, location_bb, "\n");
+            builder.position_at_end(location_bb);
+            builder.br(exit_bb);
+        }
+        None => {
+            builder.br(exit_bb);
+        }
+    }
+
+    // exit:
+    //   _exit(128 + signal);
+    builder.position_at_end(exit_bb);
+    let exit_code = builder.add(module, signal, int32(128), "exit_code");
+    add_function_call(module, exit_bb, "_exit", &mut [exit_code], "");
+    builder.position_at_end(exit_bb);
+    builder.unreachable();
+
+    // signal(SIGINT, debug_handler);
+    // signal(SIGSEGV, debug_handler);
+    let handler_ptr_type = LLVMPointerType(handler_type, 0);
+    add_function(
+        module,
+        "signal",
+        &mut [int32_type(), handler_ptr_type],
+        handler_ptr_type,
+    );
+    for &signal in &[SIGINT, SIGSEGV] {
+        add_function_call(module, bb, "signal", &mut [int32(signal), handler], "");
+    }
+}
+
+/// Record the position of the instruction we're about to run, for
+/// the debug runtime to show. `position` must be in the source file
+/// of `scope`.
+unsafe fn compile_location_marker(
+    position: Position,
+    scope: DebugScope,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+) {
+    let (line, column) = scope.source_map.line_col(position.start);
+    let location = format!("{}:{}:{}\0", scope.source_map.path, line, column);
+    let location = add_const_string(module, location.as_bytes(), "debug_location_text");
+    let location_ptr = LLVMGetNamedGlobal(module.module, module.new_string_ptr("debug_location"));
+
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let store = builder.store(location, location_ptr);
+    LLVMSetVolatile(store, LLVM_TRUE);
+}
+
+/// Make every load and store in `function` volatile. The debug
+/// runtime's signal handler can interrupt main anywhere, so it only
+/// sees the current pointer and cells if main keeps them in memory
+/// rather than registers.
+unsafe fn make_accesses_volatile(function: LLVMValueRef) {
+    let mut bb = LLVMGetFirstBasicBlock(function);
+    while !bb.is_null() {
+        let mut instr = LLVMGetFirstInstruction(bb);
+        while !instr.is_null() {
+            if !LLVMIsALoadInst(instr).is_null() || !LLVMIsAStoreInst(instr).is_null() {
+                LLVMSetVolatile(instr, LLVM_TRUE);
+            }
+            instr = LLVMGetNextInstruction(instr);
+        }
+        bb = LLVMGetNextBasicBlock(bb);
+    }
+}
+
+/// The counters for a loop under `--profile`: globals holding how
+/// often the loop body was entered, and how often the loop exited.
+#[derive(Clone, Copy)]
+struct LoopCounters {
+    iterations: LLVMValueRef,
+    exits: LLVMValueRef,
+}
+
+/// Add a pair of counters for each loop in `profile`, starting from
+/// the counts compile time execution reached.
+unsafe fn add_loop_counters(
+    module: &mut Module,
+    profile: &Profile,
+    loop_counts: &HashMap<*const AstNode, LoopCounts>,
+) -> HashMap<*const AstNode, LoopCounters> {
+    let add_counter = |module: &mut Module, name: &str, count: u64| {
+        let counter = LLVMAddGlobal(module.module, int64_type(), module.new_string_ptr(name));
+        LLVMSetInitializer(counter, int64(count));
+        LLVMSetLinkage(counter, LLVMLinkage::LLVMInternalLinkage);
+        counter
+    };
+    profile
+        .loops
+        .iter()
+        .map(|&(loop_instr, _)| {
+            let counts = loop_counts.get(&loop_instr).copied().unwrap_or_default();
+            let counters = LoopCounters {
+                iterations: add_counter(module, "loop_iterations", counts.iterations),
+                exits: add_counter(module, "loop_exits", counts.exits),
+            };
+            (loop_instr, counters)
+        })
+        .collect()
+}
+
+/// Add one to `counter` at the end of `bb`.
+unsafe fn compile_counter_increment(
+    counter: LLVMValueRef,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+) {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let count = builder.load(module, counter, "count");
+    let count = builder.add(module, count, int64(1), "new_count");
+    builder.store(count, counter);
+}
+
+/// Declare `fopen`, `fclose` and `fprintf`, unless we already have.
+unsafe fn add_stdio_declarations(module: &mut Module) {
+    if !LLVMGetNamedFunction(module.module, module.new_string_ptr("fopen")).is_null() {
+        return;
+    }
+    let file_type = int8_ptr_type();
+    add_function(
+        module,
+        "fopen",
+        &mut [int8_ptr_type(), int8_ptr_type()],
+        file_type,
+    );
+    add_function(module, "fclose", &mut [file_type], int32_type());
+    let mut fprintf_args = vec![file_type, int8_ptr_type()];
+    let fprintf_type = LLVMFunctionType(
+        int32_type(),
+        fprintf_args.as_mut_ptr(),
+        fprintf_args.len() as c_uint,
+        LLVM_TRUE,
+    );
+    LLVMAddFunction(
+        module.module,
+        module.new_string_ptr("fprintf"),
+        fprintf_type,
+    );
+}
+
+/// Open the file at `path` with `mode` at the end of `bb`, and if it
+/// opened, call `write_contents` to write to it at the end of the
+/// basic block it's given, then close it. If we can't open the file,
+/// we skip writing it. Returns the basic block to continue from.
+unsafe fn add_report_writer<F>(
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    name: &str,
+    path: &str,
+    mode: &[u8],
+    write_contents: F,
+) -> LLVMBasicBlockRef
+where
+    F: FnOnce(&mut Module, LLVMBasicBlockRef, LLVMValueRef),
+{
+    add_stdio_declarations(module);
+    let write_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr(&format!("write_{}", name)));
+    let after_bb = LLVMAppendBasicBlock(main_fn, module.new_string_ptr(&format!("after_{}", name)));
+
+    // FILE *file = fopen(PATH, MODE);
+    // if (file) { ... }
+    let mut path = path.as_bytes().to_vec();
+    path.push(0);
+    let path = add_const_string(module, &path, &format!("{}_path", name));
+    let mode = add_const_string(module, mode, &format!("{}_mode", name));
+    let file = add_function_call(
+        module,
+        bb,
+        "fopen",
+        &mut [path, mode],
+        &format!("{}_file", name),
+    );
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let opened = builder.is_not_null(module, file, "opened");
+    builder.cond_br(opened, write_bb, after_bb);
+
+    write_contents(module, write_bb, file);
+    add_function_call(module, write_bb, "fclose", &mut [file], "");
+    builder.position_at_end(write_bb);
+    builder.br(after_bb);
+
+    after_bb
+}
+
+/// Write a line for each loop in `profile` to the profile file:
+///
+/// ```text
+/// foo.bf:3:5 1200 4
+/// ```
+///
+/// giving the loop's position, how often its body was entered, and
+/// how often it exited. Returns the basic block to continue from.
+unsafe fn add_profile_writer(
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    profile: &Profile,
+    counters: &HashMap<*const AstNode, LoopCounters>,
+) -> LLVMBasicBlockRef {
+    add_report_writer(
+        module,
+        main_fn,
+        bb,
+        "profile",
+        &profile.path,
+        b"w\0",
+        |module, write_bb, file| {
+            // fprintf(file, "%s %llu %llu\n", POSITION, iterations, exits);
+            let format = add_const_string(module, b"%s %llu %llu\n\0", "profile_format");
+            let builder = Builder::new();
+            for (loop_instr, position) in &profile.loops {
+                let loop_counters = counters[loop_instr];
+                let mut position = position.as_bytes().to_vec();
+                position.push(0);
+                let position = add_const_string(module, &position, "profile_position");
+
+                builder.position_at_end(write_bb);
+                let iterations = builder.load(module, loop_counters.iterations, "iterations");
+                let exits = builder.load(module, loop_counters.exits, "exits");
+                let mut fprintf_args = vec![file, format, position, iterations, exits];
+                add_function_call(module, write_bb, "fprintf", &mut fprintf_args, "");
+            }
+        },
+    )
+}
+
+/// Flags recording which instructions ran, under `--coverage`.
+struct CoverageFlags {
+    /// A global `[N x i8]`, with one flag for each instruction.
+    flags: LLVMValueRef,
+    /// The index of each instruction's flag.
+    indexes: HashMap<*const AstNode, usize>,
+}
+
+/// Add a flag for each instruction in `coverage`, set if the
+/// instruction ran during compile time execution.
+unsafe fn add_coverage_flags(
+    module: &mut Module,
+    coverage: &Coverage,
+    executed: &HashSet<*const AstNode>,
+) -> CoverageFlags {
+    let mut initial_flags: Vec<_> = coverage
+        .instrs
+        .iter()
+        .map(|(instr, _)| int8(executed.contains(instr) as c_ulonglong))
+        .collect();
+    let flags_type = LLVMArrayType(int8_type(), initial_flags.len() as c_uint);
+    let flags = LLVMAddGlobal(
+        module.module,
+        flags_type,
+        module.new_string_ptr("coverage_flags"),
+    );
+    LLVMSetInitializer(
+        flags,
+        LLVMConstArray(
+            int8_type(),
+            initial_flags.as_mut_ptr(),
+            initial_flags.len() as c_uint,
+        ),
+    );
+    LLVMSetLinkage(flags, LLVMLinkage::LLVMInternalLinkage);
+
+    let indexes = coverage
+        .instrs
+        .iter()
+        .enumerate()
+        .map(|(index, &(instr, _))| (instr, index))
+        .collect();
+    CoverageFlags { flags, indexes }
+}
+
+/// Set the coverage flag for `instr` at the end of `bb`, if it has
+/// one.
+unsafe fn compile_coverage_marker(
+    instr: &AstNode,
+    coverage: &CoverageFlags,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+) {
+    let index = match coverage.indexes.get(&(instr as *const AstNode)) {
+        Some(&index) => index,
+        None => return,
+    };
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+    let mut indices = vec![int32(0), int32(index as c_ulonglong)];
+    let flag_ptr = builder.gep(module, coverage.flags, &mut indices, "coverage_flag");
+    builder.store(int8(1), flag_ptr);
+}
+
+/// Append an lcov record for the source file to the coverage file:
+///
+/// ```text
+/// TN:
+/// SF:foo.bf
+/// DA:1,1
+/// DA:2,0
+/// LF:2
+/// LH:1
+/// end_of_record
+/// ```
+///
+/// A line counts as run if any instruction on it ran. Returns the
+/// basic block to continue from.
+unsafe fn add_coverage_writer(
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    coverage: &Coverage,
+    coverage_flags: &CoverageFlags,
+) -> LLVMBasicBlockRef {
+    let mut lines: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (index, &(_, line)) in coverage.instrs.iter().enumerate() {
+        lines.entry(line).or_default().push(index);
+    }
+
+    add_report_writer(
+        module,
+        main_fn,
+        bb,
+        "coverage",
+        &coverage.path,
+        b"a\0",
+        |module, write_bb, file| {
+            let builder = Builder::new();
+            builder.position_at_end(write_bb);
+
+            // fprintf(file, "%s", "TN:\nSF:PATH\n");
+            let string_format = add_const_string(module, b"%s\0", "coverage_string_format");
+            let mut header = format!("TN:\nSF:{}\n", coverage.source_path).into_bytes();
+            header.push(0);
+            let header = add_const_string(module, &header, "coverage_header");
+            add_function_call(
+                module,
+                write_bb,
+                "fprintf",
+                &mut [file, string_format, header],
+                "",
+            );
+
+            // fprintf(file, "DA:%u,%u\n", LINE, line_run);
+            let line_format = add_const_string(module, b"DA:%u,%u\n\0", "coverage_line_format");
+            let mut lines_run = int32(0);
+            for (&line, indexes) in &lines {
+                builder.position_at_end(write_bb);
+                let mut line_run = int8(0);
+                for &index in indexes {
+                    let mut indices = vec![int32(0), int32(index as c_ulonglong)];
+                    let flag_ptr =
+                        builder.gep(module, coverage_flags.flags, &mut indices, "coverage_flag");
+                    let flag = builder.load(module, flag_ptr, "flag");
+                    line_run = builder.or(module, line_run, flag, "line_run");
+                }
+                let line_run = builder.zext(module, line_run, int32_type(), "line_run");
+                lines_run = builder.add(module, lines_run, line_run, "lines_run");
+                add_function_call(
+                    module,
+                    write_bb,
+                    "fprintf",
+                    &mut [file, line_format, int32(line as c_ulonglong), line_run],
+                    "",
+                );
+            }
+
+            // fprintf(file, "LF:%u\nLH:%u\nend_of_record\n", LINES, lines_run);
+            let summary_format = add_const_string(
+                module,
+                b"LF:%u\nLH:%u\nend_of_record\n\0",
+                "coverage_summary_format",
+            );
+            add_function_call(
+                module,
+                write_bb,
+                "fprintf",
+                &mut [
+                    file,
+                    summary_format,
+                    int32(lines.len() as c_ulonglong),
+                    lines_run,
+                ],
+                "",
+            );
+        },
+    )
+}
+
+/// Grow the tape so `highest_index` is on it, zeroing the new
+/// cells. We at least double the tape, so programs that keep moving
+/// right don't realloc on every step, but never exceed the tape's
+/// `max_cells`.
+unsafe fn compile_tape_grow(
+    tape: GrowableTape,
+    tape_len: LLVMValueRef,
+    highest_index: LLVMValueRef,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    // new_len = min(max(tape_len * 2, highest_index + 1), max_cells);
+    let doubled_len = builder.mul(module, tape_len, int32(2), "doubled_len");
+    let needed_len = builder.add(module, highest_index, int32(1), "needed_len");
+    let doubling_enough = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGT,
+        doubled_len,
+        needed_len,
+        "doubling_enough",
+    );
+    let wanted_len = builder.select(
+        module,
+        doubling_enough,
+        doubled_len,
+        needed_len,
+        "wanted_len",
+    );
+    let max_len = tape.max_len(module, &builder);
+    let too_long = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntSGT,
+        wanted_len,
+        max_len,
+        "too_long",
+    );
+    let new_len = builder.select(module, too_long, max_len, wanted_len, "new_len");
+
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("realloc")).is_null() {
+        add_function(
+            module,
+            "realloc",
+            &mut [int8_ptr_type(), int32_type()],
+            int8_ptr_type(),
+        );
+    }
+    let old_cells = builder.load(module, tape.cells_ptr, "old_cells");
+    builder.position_at_end(bb);
+    let old_cells = builder.pointer_cast(module, old_cells, int8_ptr_type(), "old_cell_bytes");
+    let new_size = cells_to_bytes(module, &builder, new_len, ctx.cell_bits);
+    let new_cells = add_function_call(
+        module,
+        bb,
+        "realloc",
+        &mut [old_cells, new_size],
+        "new_cells",
+    );
+
+    // memset(new_cells + tape_len, 0, new_len - tape_len);
+    builder.position_at_end(bb);
+    let new_cells =
+        builder.pointer_cast(module, new_cells, cell_ptr_type(ctx.cell_bits), "new_cells");
+    let mut indices = vec![tape_len];
+    let new_cells_start = builder.gep(module, new_cells, &mut indices, "new_cells_start");
+    let added_len = builder.sub(module, new_len, tape_len, "added_len");
+    let new_cells_start =
+        builder.pointer_cast(module, new_cells_start, int8_ptr_type(), "new_cell_bytes");
+    let added_size = cells_to_bytes(module, &builder, added_len, ctx.cell_bits);
+    // realloc aligns the tape for any type, so the new cells are
+    // aligned like a cell.
+    let cell_bytes = u64::from(ctx.cell_bits / 8);
+    build_memset(module, bb, new_cells_start, int8(0), added_size, cell_bytes);
+
+    builder.position_at_end(bb);
+    builder.store(new_cells, tape.cells_ptr);
+    builder.store(new_len, tape.num_cells_ptr);
+}
+
+unsafe fn compile_loop(
+    loop_instr: &AstNode,
+    loop_body: &[AstNode],
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new();
+    let counts = ctx.loop_counts.get(&(loop_instr as *const AstNode));
+    let ptr_check = ctx.ptr_checks.get(&(loop_instr as *const AstNode));
+
+    // First, we branch into the loop header from the previous basic
+    // block.
+    let loop_header_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_header"));
+    builder.position_at_end(bb);
+    builder.br(loop_header_bb);
+
+    let mut loop_body_bb = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_body"));
+    let loop_after = LLVMAppendBasicBlock(ctx.main_fn, module.new_string_ptr("loop_after"));
+    if let Some(counters) = ctx.loop_counters.get(&(loop_instr as *const AstNode)) {
+        compile_counter_increment(counters.iterations, module, loop_body_bb);
+        compile_counter_increment(counters.exits, module, loop_after);
+    }
+
+    // loop_header:
+    //   %cell_value = ...
+    //   %cell_value_is_zero = icmp ...
+    //   br %cell_value_is_zero, %loop_after, %loop_body
+    builder.position_at_end(loop_header_bb);
+
+    // Every iteration may start at a different cell, so check the
+    // pointer in the header.
+    let loop_check_bb = match ptr_check {
+        Some(message) => {
+            let check_passed = compile_ptr_check(0, 0, message, module, loop_header_bb, &ctx);
+            builder.position_at_end(check_passed);
+            check_passed
+        }
+        None => loop_header_bb,
+    };
+
+    let cells = cells_base(module, loop_check_bb, &ctx);
+    let cell_val = add_current_cell_access(module, loop_check_bb, cells, ctx.cell_index_ptr).0;
+
+    let zero = cell_const(Wrapping(0), ctx.cell_bits);
+    let cell_val_is_zero = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        zero,
+        cell_val,
+        "cell_value_is_zero",
+    );
+    let branch = builder.cond_br(cell_val_is_zero, loop_after, loop_body_bb);
+    if let Some(counts) = counts {
+        add_branch_weights(module, branch, &[counts.exits, counts.iterations]);
+    }
+
+    // Recursively compile instructions in the loop body.
+    loop_body_bb = compile_block(loop_body, start_instr, module, main_fn, loop_body_bb, &ctx);
+
+    // When the loop is finished, jump back to the beginning of the
+    // loop.
+    builder.position_at_end(loop_body_bb);
+    let back_edge = builder.br(loop_header_bb);
+    if let Some(counts) = counts {
+        add_loop_metadata(module, back_edge, counts);
+    }
+
+    &mut *loop_after
+}
+
+/// Append LLVM IR instructions to bb for each BF instruction in
+/// `instrs`, starting execution at `start_instr` if it's one of
+/// them.
+unsafe fn compile_block(
+    instrs: &[AstNode],
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMBasicBlockRef {
+    let mut bb = bb;
+    let mut index = 0;
+    while index < instrs.len() {
+        let instr = &instrs[index];
+        if ptr_equal(instr, start_instr) {
+            // This is the point we want to start execution from.
+            bb = set_entry_point_after(module, main_fn, bb);
+        }
+
+        // Everything we add is either at the end of bb or in a new
+        // basic block, so remember where those start.
+        let last_instr = LLVMGetLastInstruction(bb);
+        let last_bb = LLVMGetLastBasicBlock(main_fn);
+        let start_bb = bb;
+
+        if ctx.debug_runtime {
+            if let (Some(scope), Some(position)) = (ctx.debug_scope, get_position(instr)) {
+                compile_location_marker(position, scope, module, bb);
+            }
+        }
+
+        let fill = fill_run(&instrs[index..], start_instr, ctx);
+        if let Some(coverage) = ctx.coverage {
+            let len = fill.map_or(1, |(_, _, len)| len);
+            for covered in &instrs[index..index + len] {
+                compile_coverage_marker(covered, coverage, module, bb);
+            }
+        }
+
+        match fill {
+            Some((amount, offset, len)) => {
+                bb = compile_fill(amount, offset, len, module, bb, ctx.clone());
+                index += len;
+            }
+            // A block that's only the loop is the function we
+            // outlined it into, or small enough already.
+            None if instrs.len() > 1 && is_large_cold_loop(instr, start_instr, ctx) => {
+                bb = compile_part(
+                    slice::from_ref(instr),
+                    start_instr,
+                    COLD_LOOP_FN_NAME,
+                    module,
+                    bb,
+                    ctx,
+                );
+                index += 1;
+            }
+            None => {
+                bb = compile_instr(instr, start_instr, module, main_fn, bb, ctx.clone());
+                index += 1;
+            }
+        }
+
+        if let (Some(scope), Some(position)) = (ctx.debug_scope, get_position(instr)) {
+            add_debug_locations(module, start_bb, last_instr, last_bb, position, scope);
+        }
+    }
+    bb
+}
+
+/// Loops with at least this many instructions get a function of
+/// their own if they were cold during compile time execution.
+const MIN_OUTLINED_LOOP_SIZE: usize = 32;
+
+/// The name of the functions we compile cold loops into. LLVM
+/// numbers them to keep names unique.
+const COLD_LOOP_FN_NAME: &str = "cold_loop";
+
+/// Should we compile `instr` into a function of its own? A large
+/// loop that ran at most once each time it was entered at compile
+/// time would bloat the hotter code around it. Execution can't start
+/// inside another function, so loops containing `start_instr` stay
+/// where they are.
+fn is_large_cold_loop(instr: &AstNode, start_instr: &AstNode, ctx: &CompileContext) -> bool {
+    let is_cold = ctx
+        .loop_counts
+        .get(&(instr as *const AstNode))
+        .is_some_and(LoopCounts::is_cold);
+    is_cold
+        && ctx.function_size_limit > 0
+        && count_instrs(slice::from_ref(instr)) >= MIN_OUTLINED_LOOP_SIZE
+        && !contains_instr(instr, start_instr)
+}
+
+/// Compile the top-level `instrs` into `main_fn`. If there are more
+/// than `ctx.function_size_limit` instructions, we move runs of
+/// them after the start instruction into separate functions that
+/// main calls in turn, as LLVM takes far longer to optimise one huge
+/// function than many small ones. A single loop larger than the
+/// limit still gets a function to itself.
+unsafe fn compile_split_block(
+    instrs: &[AstNode],
+    start_instr: &AstNode,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMBasicBlockRef {
+    let size_limit = ctx.function_size_limit;
+    if size_limit == 0 || instrs.len() < 2 || count_instrs(instrs) <= size_limit {
+        return compile_block(instrs, start_instr, module, ctx.main_fn, bb, ctx);
+    }
+
+    // Execution starts in the first instruction, so it stays in main.
+    let mut bb = compile_block(&instrs[..1], start_instr, module, ctx.main_fn, bb, ctx);
+
+    let mut part_start = 1;
+    let mut part_size = 0;
+    let mut num_parts = 0;
+    for (index, instr) in instrs.iter().enumerate().skip(1) {
+        let size = count_instrs(slice::from_ref(instr));
+        if part_size > 0 && part_size + size > size_limit {
+            num_parts += 1;
+            bb = compile_part(
+                &instrs[part_start..index],
+                start_instr,
+                &format!("{}{}", PART_FN_PREFIX, num_parts),
+                module,
+                bb,
+                ctx,
+            );
+            part_start = index;
+            part_size = 0;
+        }
+        part_size += size;
+    }
+    num_parts += 1;
+    compile_part(
+        &instrs[part_start..],
+        start_instr,
+        &format!("{}{}", PART_FN_PREFIX, num_parts),
+        module,
+        bb,
+        ctx,
+    )
+}
+
+/// The name of each function `compile_split_block` adds, before its
+/// number.
+const PART_FN_PREFIX: &str = "main_part";
+
+/// Compile `instrs` into a new function `fn_name`, and call it at
+/// the end of `bb`. Main passes the tape and the cell index, which
+/// the function updates in place.
+unsafe fn compile_part(
+    instrs: &[AstNode],
+    start_instr: &AstNode,
+    fn_name: &str,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: &CompileContext,
+) -> LLVMBasicBlockRef {
+    let mut args = vec![ctx.cells, ctx.cell_index_ptr];
+    if let Some(tape) = ctx.growable {
+        args.push(tape.cells_ptr);
+        args.push(tape.num_cells_ptr);
+    }
+    let mut param_types: Vec<_> = args.iter().map(|&arg| LLVMTypeOf(arg)).collect();
+
+    let fn_type = LLVMFunctionType(
+        LLVMVoidType(),
+        param_types.as_mut_ptr(),
+        param_types.len() as c_uint,
+        LLVM_FALSE,
+    );
+    let part_fn = LLVMAddFunction(module.module, module.new_string_ptr(fn_name), fn_type);
+    LLVMSetLinkage(part_fn, LLVMLinkage::LLVMInternalLinkage);
+    add_function_attributes(module, part_fn, ctx.perf_friendly);
+    // Inlining would rebuild the huge function we're avoiding.
+    let noinline = enum_attribute(module, "noinline", 0);
+    LLVMAddAttributeAtIndex(part_fn, LLVMAttributeFunctionIndex, noinline);
+    // Main's variables are only accessed through these pointers while
+    // the function runs. The debug runtime reads them from a signal
+    // handler, so we don't promise that then.
+    if !ctx.debug_runtime {
+        for index in 0..args.len() {
+            let noalias = enum_attribute(module, "noalias", 0);
+            LLVMAddAttributeAtIndex(part_fn, index as c_uint + 1, noalias);
+        }
+    }
+
+    let part_ctx = CompileContext {
+        cells: LLVMGetParam(part_fn, 0),
+        cell_index_ptr: LLVMGetParam(part_fn, 1),
+        main_fn: part_fn,
+        growable: ctx.growable.map(|tape| GrowableTape {
+            cells_ptr: LLVMGetParam(part_fn, 2),
+            num_cells_ptr: LLVMGetParam(part_fn, 3),
+            ..tape
+        }),
+        debug_scope: ctx.debug_scope.map(|scope| DebugScope {
+            subprogram: add_subprogram(module, scope.builder, scope.file, part_fn, fn_name),
+            ..scope
+        }),
+        ..ctx.clone()
+    };
+    let entry_bb = LLVMAppendBasicBlock(part_fn, module.new_string_ptr("entry"));
+    let part_bb = compile_block(instrs, start_instr, module, part_fn, entry_bb, &part_ctx);
+    let builder = Builder::new();
+    builder.position_at_end(part_bb);
+    builder.ret_void();
+    if ctx.debug_runtime {
+        make_accesses_volatile(part_fn);
+    }
+
+    // Calls to functions with debug info need a location.
+    let last_instr = LLVMGetLastInstruction(bb);
+    let last_bb = LLVMGetLastBasicBlock(ctx.main_fn);
+    builder.position_at_end(bb);
+    builder.call(module, part_fn, &mut args, "");
+    if let Some(scope) = ctx.debug_scope {
+        let position = instrs
+            .iter()
+            .find_map(get_position)
+            .unwrap_or(Position { start: 0, end: 0 });
+        add_debug_locations(module, bb, last_instr, last_bb, position, scope);
+    }
+    bb
+}
+
+/// Give the LLVM instructions added to `bb` after `last_instr`, and
+/// those in basic blocks after `last_bb`, the location of
+/// `position`. Instructions from loop bodies already have the
+/// location of their own BF instruction, so we leave them alone.
+unsafe fn add_debug_locations(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    last_instr: LLVMValueRef,
+    last_bb: LLVMBasicBlockRef,
+    position: Position,
+    scope: DebugScope,
+) {
+    let (line, column) = scope.source_map.line_col(position.start);
+    let context = LLVMGetModuleContext(module.module);
+    let location = LLVMDIBuilderCreateDebugLocation(
+        context,
+        line as c_uint,
+        column as c_uint,
+        scope.subprogram,
+        null_mut(),
+    );
+    let location = LLVMMetadataAsValue(context, location);
+
+    let kind = "dbg";
+    let kind_id = LLVMGetMDKindID(module.new_string_ptr(kind), kind.len() as c_uint);
+    let set_location = |mut instr: LLVMValueRef| {
+        while !instr.is_null() {
+            if LLVMGetMetadata(instr, kind_id).is_null() {
+                LLVMSetMetadata(instr, kind_id, location);
+            }
+            instr = LLVMGetNextInstruction(instr);
+        }
+    };
+
+    if last_instr.is_null() {
+        set_location(LLVMGetFirstInstruction(bb));
+    } else {
+        set_location(LLVMGetNextInstruction(last_instr));
+    }
+    let mut new_bb = LLVMGetNextBasicBlock(last_bb);
+    while !new_bb.is_null() {
+        set_location(LLVMGetFirstInstruction(new_bb));
+        new_bb = LLVMGetNextBasicBlock(new_bb);
+    }
+}
+
+/// Describe bfc's compile unit for `file`. LLVM 11 added a sysroot
+/// and SDK, which we leave empty.
+unsafe fn add_compile_unit(
+    module: &mut Module,
+    di_builder: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+) {
+    let producer = "bfc";
+    #[cfg(any(feature = "llvm-8", feature = "llvm-9", feature = "llvm-10"))]
+    LLVMDIBuilderCreateCompileUnit(
+        di_builder,
+        LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+        file,
+        module.new_string_ptr(producer),
+        producer.len(),
+        LLVM_TRUE,
+        module.new_string_ptr(""),
+        0,
+        0,
+        module.new_string_ptr(""),
+        0,
+        LLVMDWARFEmissionKind::LLVMDWARFEmissionKindLineTablesOnly,
+        0,
+        LLVM_FALSE,
+        LLVM_FALSE,
+    );
+    #[cfg(not(any(feature = "llvm-8", feature = "llvm-9", feature = "llvm-10")))]
+    LLVMDIBuilderCreateCompileUnit(
+        di_builder,
+        LLVMDWARFSourceLanguage::LLVMDWARFSourceLanguageC,
+        file,
+        module.new_string_ptr(producer),
+        producer.len(),
+        LLVM_TRUE,
+        module.new_string_ptr(""),
+        0,
+        0,
+        module.new_string_ptr(""),
+        0,
+        LLVMDWARFEmissionKind::LLVMDWARFEmissionKindLineTablesOnly,
+        0,
+        LLVM_FALSE,
+        LLVM_FALSE,
+        module.new_string_ptr(""),
+        0,
+        module.new_string_ptr(""),
+        0,
+    );
+}
+
+/// Describe the source file and `main_fn` in debug info, and return
+/// the scope for locations in `main_fn`. BF isn't a DWARF language,
+/// so we claim to be C, which is close enough for debuggers to show
+/// lines.
+unsafe fn add_debug_info<'a>(
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    source_map: &'a SourceMap,
+) -> DebugScope<'a> {
+    let di_builder = LLVMCreateDIBuilder(module.module);
+    let file = LLVMDIBuilderCreateFile(
+        di_builder,
+        module.new_string_ptr(&source_map.path),
+        source_map.path.len(),
+        module.new_string_ptr(&source_map.directory),
+        source_map.directory.len(),
+    );
+
+    add_compile_unit(module, di_builder, file);
+    let subprogram = add_subprogram(module, di_builder, file, main_fn, "main");
+
+    // LLVM ignores debug info without a version.
+    for &(key, version) in &[
+        ("Debug Info Version", LLVMDebugMetadataVersion()),
+        ("Dwarf Version", 4),
+    ] {
+        LLVMAddModuleFlag(
+            module.module,
+            LLVMModuleFlagBehavior::LLVMModuleFlagBehaviorWarning,
+            module.new_string_ptr(key),
+            key.len(),
+            LLVMValueAsMetadata(int32(c_ulonglong::from(version))),
+        );
+    }
+
+    DebugScope {
+        source_map,
+        builder: di_builder,
+        file,
+        subprogram,
+    }
+}
+
+/// No debug info flags. llvm-sys 140 made the flags plain integers.
+#[cfg(not(feature = "llvm-14"))]
+const DI_FLAG_ZERO: LLVMDIFlags = LLVMDIFlags::LLVMDIFlagZero;
+#[cfg(feature = "llvm-14")]
+const DI_FLAG_ZERO: LLVMDIFlags = LLVMDIFlagZero;
+
+/// Describe `function` in debug info, and return its subprogram.
+unsafe fn add_subprogram(
+    module: &mut Module,
+    di_builder: LLVMDIBuilderRef,
+    file: LLVMMetadataRef,
+    function: LLVMValueRef,
+    name: &str,
+) -> LLVMMetadataRef {
+    let fn_type = LLVMDIBuilderCreateSubroutineType(di_builder, file, null_mut(), 0, DI_FLAG_ZERO);
+    let subprogram = LLVMDIBuilderCreateFunction(
+        di_builder,
+        file,
+        module.new_string_ptr(name),
+        name.len(),
+        module.new_string_ptr(name),
+        name.len(),
+        file,
+        1,
+        fn_type,
+        LLVM_FALSE,
+        LLVM_TRUE,
+        1,
+        DI_FLAG_ZERO,
+        LLVM_TRUE,
+    );
+    LLVMSetSubprogram(function, subprogram);
+    subprogram
+}
+
+/// How to make Linux system calls on a target, for
+/// `--freestanding`.
+struct SyscallAbi {
+    /// The instruction that makes the call.
+    instruction: &'static str,
+    /// Inline asm constraints for the result, the call number and
+    /// three arguments.
+    constraints: &'static str,
+    /// The width of the registers, in bits.
+    word_bits: c_uint,
+    read: u64,
+    write: u64,
+    exit: u64,
+}
+
+fn syscall_abi(target_triple: &str) -> Option<SyscallAbi> {
+    if !target_triple.contains("linux") {
+        return None;
+    }
+    let abi = match target_triple.split('-').next().unwrap_or("") {
+        "x86_64" => SyscallAbi {
+            instruction: "syscall",
+            constraints: "={rax},{rax},{rdi},{rsi},{rdx},~{rcx},~{r11},~{memory}",
+            word_bits: 64,
+            read: 0,
+            write: 1,
+            exit: 60,
+        },
+        "i386" | "i486" | "i586" | "i686" => SyscallAbi {
+            instruction: "int $$0x80",
+            constraints: "={eax},{eax},{ebx},{ecx},{edx},~{memory}",
+            word_bits: 32,
+            read: 3,
+            write: 4,
+            exit: 1,
+        },
+        "aarch64" => SyscallAbi {
+            instruction: "svc #0",
+            constraints: "={x0},{x8},{x0},{x1},{x2},~{memory}",
+            word_bits: 64,
+            read: 63,
+            write: 64,
+            exit: 93,
+        },
+        _ => return None,
+    };
+    Some(abi)
+}
+
+/// Does this target produce WebAssembly rather than a native
+/// executable?
+pub fn is_wasm_target(target_triple: &str) -> bool {
+    target_triple.starts_with("wasm32") || target_triple.starts_with("wasm64")
+}
+
+/// Can `--freestanding` make system calls on this target?
+pub fn supports_freestanding(target_triple: &str) -> bool {
+    syscall_abi(target_triple).is_some()
+}
+
+/// Make system call `number` with `args`, which must be register
+/// sized integers, and return its register sized result.
+unsafe fn build_syscall(
+    module: &mut Module,
+    builder: &Builder,
+    abi: &SyscallAbi,
+    number: u64,
+    args: [LLVMValueRef; 3],
+) -> LLVMValueRef {
+    let word = LLVMIntType(abi.word_bits);
+    let mut param_types = [word; 4];
+    let fn_type = LLVMFunctionType(word, param_types.as_mut_ptr(), 4, LLVM_FALSE);
+    #[cfg(not(feature = "llvm-14"))]
+    let asm = LLVMGetInlineAsm(
+        fn_type,
+        module.new_mut_string_ptr(abi.instruction),
+        abi.instruction.len(),
+        module.new_mut_string_ptr(abi.constraints),
+        abi.constraints.len(),
+        LLVM_TRUE,
+        LLVM_FALSE,
+        LLVMInlineAsmDialect::LLVMInlineAsmDialectATT,
+    );
+    // LLVM 13 added whether the asm can unwind, and system calls
+    // can't.
+    #[cfg(feature = "llvm-14")]
+    let asm = LLVMGetInlineAsm(
+        fn_type,
+        module.new_mut_string_ptr(abi.instruction),
+        abi.instruction.len(),
+        module.new_mut_string_ptr(abi.constraints),
+        abi.constraints.len(),
+        LLVM_TRUE,
+        LLVM_FALSE,
+        LLVMInlineAsmDialect::LLVMInlineAsmDialectATT,
+        LLVM_FALSE,
+    );
+
+    let mut call_args = [
+        LLVMConstInt(word, number, LLVM_FALSE),
+        args[0],
+        args[1],
+        args[2],
+    ];
+    builder.call(module, asm, &mut call_args, "syscall_result")
+}
+
+/// Define the libc functions we call in terms of system calls, and
+/// add a `_start` that runs `main_fn` then exits.
+unsafe fn add_freestanding_runtime(module: &mut Module, main_fn: LLVMValueRef, abi: &SyscallAbi) {
+    let word = LLVMIntType(abi.word_bits);
+    let zero_word = LLVMConstInt(word, 0, LLVM_FALSE);
+    let builder = Builder::new();
+
+    // ssize_t write(int fd, char *buf, size_t count)
+    let write_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("write"));
+    builder.position_at_end(LLVMAppendBasicBlock(
+        write_fn,
+        module.new_string_ptr("entry"),
+    ));
+    let fd = builder.int_cast(module, LLVMGetParam(write_fn, 0), word, LLVM_TRUE, "fd");
+    let buf = builder.ptr_to_int(module, LLVMGetParam(write_fn, 1), word, "buf");
+    let count = LLVMGetParam(write_fn, 2);
+    let written = build_syscall(module, &builder, abi, abi.write, [fd, buf, count]);
+    builder.ret(written);
+
+    let putchar_fn = add_putchar_with_write(module, write_fn);
+
+    // int getchar(void), returning -1 (EOF) if there's no input.
+    let getchar_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("getchar"));
+    builder.position_at_end(LLVMAppendBasicBlock(
+        getchar_fn,
+        module.new_string_ptr("entry"),
+    ));
+    let byte_ptr = builder.alloca(module, int8_type(), "byte_ptr");
+    let buf = builder.ptr_to_int(module, byte_ptr, word, "buf");
+    let one_word = LLVMConstInt(word, 1, LLVM_FALSE);
+    let count = build_syscall(module, &builder, abi, abi.read, [zero_word, buf, one_word]);
+    let got_byte = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        count,
+        one_word,
+        "got_byte",
+    );
+    let byte = builder.load(module, byte_ptr, "byte");
+    let byte_as_int = builder.zext(module, byte, int32_type(), "byte_as_int");
+    let eof = LLVMConstInt(int32_type(), c_ulonglong::MAX, LLVM_TRUE);
+    let input_char = builder.select(module, got_byte, byte_as_int, eof, "input_char");
+    builder.ret(input_char);
+
+    let mut defined_fns = vec![write_fn, putchar_fn, getchar_fn];
+
+    // void abort(void), which --ptr-check calls. Exit as if killed
+    // by SIGABRT.
+    let abort_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("abort"));
+    if !abort_fn.is_null() {
+        builder.position_at_end(LLVMAppendBasicBlock(
+            abort_fn,
+            module.new_string_ptr("entry"),
+        ));
+        let status = LLVMConstInt(word, 134, LLVM_FALSE);
+        build_syscall(
+            module,
+            &builder,
+            abi,
+            abi.exit,
+            [status, zero_word, zero_word],
+        );
+        builder.unreachable();
+        defined_fns.push(abort_fn);
+    }
+
+    for function in defined_fns {
+        LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage);
+    }
+
+    // LLVM lowers large llvm.memset and llvm.memcpy calls to calls
+    // to memset and memcpy.
+    add_byte_loop_fn(module, "memset", false, word);
+    add_byte_loop_fn(module, "memcpy", true, word);
+
+    let start_type = LLVMFunctionType(LLVMVoidType(), null_mut(), 0, LLVM_FALSE);
+    let start_fn = LLVMAddFunction(module.module, module.new_string_ptr("_start"), start_type);
+    for name in &["noreturn", "nounwind"] {
+        let attribute = enum_attribute(module, name, 0);
+        LLVMAddAttributeAtIndex(start_fn, LLVMAttributeFunctionIndex, attribute);
+    }
+    // Unlike a called function, _start may not have an aligned
+    // stack.
+    let stack_realign = string_attribute(module, "stackrealign", "");
+    LLVMAddAttributeAtIndex(start_fn, LLVMAttributeFunctionIndex, stack_realign);
+
+    builder.position_at_end(LLVMAppendBasicBlock(
+        start_fn,
+        module.new_string_ptr("entry"),
+    ));
+    let status = LLVMBuildCall(
+        builder.builder,
+        main_fn,
+        null_mut(),
+        0,
+        module.new_string_ptr("status"),
+    );
+    let status = builder.int_cast(module, status, word, LLVM_TRUE, "status_word");
+    build_syscall(
+        module,
+        &builder,
+        abi,
+        abi.exit,
+        [status, zero_word, zero_word],
+    );
+    builder.unreachable();
+}
+
+/// Define `int putchar(int c)` as a one byte `write` to stdout.
+unsafe fn add_putchar_with_write(module: &mut Module, write_fn: LLVMValueRef) -> LLVMValueRef {
+    let putchar_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("putchar"));
+    let builder = Builder::new();
+    builder.position_at_end(LLVMAppendBasicBlock(
+        putchar_fn,
+        module.new_string_ptr("entry"),
+    ));
+    let c = LLVMGetParam(putchar_fn, 0);
+    let byte_ptr = builder.alloca(module, int8_type(), "byte_ptr");
+    let byte = builder.trunc(module, c, int8_type(), "byte");
+    builder.store(byte, byte_ptr);
+    let one = LLVMConstInt(write_size_type(module), 1, LLVM_FALSE);
+    let mut write_args = [int32(1), byte_ptr, one];
+    builder.call(module, write_fn, &mut write_args, "");
+    builder.ret(c);
+
+    putchar_fn
+}
+
+/// The integer type the size of a pointer on the module's target,
+/// i.e. `size_t`.
+unsafe fn word_type(module: &Module) -> LLVMTypeRef {
+    let target_triple = CStr::from_ptr(LLVMGetTarget(module.module)).to_string_lossy();
+    let arch = target_triple.split('-').next().unwrap_or("");
+    LLVMIntType(if arch.contains("64") { 64 } else { 32 })
+}
+
+/// The integer type of `write`'s count and result: `size_t` and
+/// `ssize_t`, except on Windows, where they're `unsigned int` and
+/// `int`.
+unsafe fn write_size_type(module: &Module) -> LLVMTypeRef {
+    let target_triple = CStr::from_ptr(LLVMGetTarget(module.module)).to_string_lossy();
+    if target_triple.contains("-windows") {
+        int32_type()
+    } else {
+        word_type(module)
+    }
+}
+
+/// Add an internal, zero initialised global with a separate value
+/// for each thread.
+unsafe fn add_thread_local(module: &mut Module, name: &str, ty: LLVMTypeRef) -> LLVMValueRef {
+    let global = LLVMAddGlobal(module.module, ty, module.new_string_ptr(name));
+    LLVMSetInitializer(global, LLVMConstNull(ty));
+    LLVMSetLinkage(global, LLVMLinkage::LLVMInternalLinkage);
+    LLVMSetThreadLocal(global, LLVM_TRUE);
+    global
+}
+
+/// Replace main with the exported function
+///
+/// ```c
+/// int bf_run(const char *input, size_t len, char **output, size_t *out_len);
+/// ```
+///
+/// which runs the program with `input` as stdin, and returns what it
+/// wrote in a buffer the caller must free.
+unsafe fn add_library_runtime(
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    options: &CodegenOptions,
+) {
+    let word = word_type(module);
+    let builder = Builder::new();
+
+    // Keep main internal, so it can't clash with the host's main.
+    LLVMSetValueName2(main_fn, module.new_string_ptr("bf_main"), "bf_main".len());
+    LLVMSetLinkage(main_fn, LLVMLinkage::LLVMInternalLinkage);
+
+    // Threads can call bf_run concurrently, so they each need their
+    // own input and output.
+    let input_ptr = add_thread_local(module, "bf_input", int8_ptr_type());
+    let input_end_ptr = add_thread_local(module, "bf_input_end", int8_ptr_type());
+    let output_ptr = add_thread_local(module, "bf_output", int8_ptr_type());
+    let output_len_ptr = add_thread_local(module, "bf_output_len", int32_type());
+    let output_cap_ptr = add_thread_local(module, "bf_output_cap", int32_type());
+
+    // int getchar(void), returning -1 (EOF) at the end of the input.
+    let getchar_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("getchar"));
+    let entry_bb = LLVMAppendBasicBlock(getchar_fn, module.new_string_ptr("entry"));
+    let read_bb = LLVMAppendBasicBlock(getchar_fn, module.new_string_ptr("read"));
+    let eof_bb = LLVMAppendBasicBlock(getchar_fn, module.new_string_ptr("eof"));
+
+    builder.position_at_end(entry_bb);
+    let input = builder.load(module, input_ptr, "input");
+    let input_end = builder.load(module, input_end_ptr, "input_end");
+    let at_end = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntEQ,
+        input,
+        input_end,
+        "at_end",
+    );
+    builder.cond_br(at_end, eof_bb, read_bb);
+
+    builder.position_at_end(read_bb);
+    let byte = builder.load(module, input, "byte");
+    let mut indices = [int32(1)];
+    let next_input = builder.gep(module, input, &mut indices, "next_input");
+    builder.store(next_input, input_ptr);
+    let byte_as_int = builder.zext(module, byte, int32_type(), "byte_as_int");
+    builder.ret(byte_as_int);
+
+    builder.position_at_end(eof_bb);
+    builder.ret(LLVMConstInt(int32_type(), c_ulonglong::MAX, LLVM_TRUE));
+
+    // ssize_t write(int fd, char *buf, size_t count), appending to
+    // the output buffer whatever fd is.
+    if LLVMGetNamedFunction(module.module, module.new_string_ptr("realloc")).is_null() {
+        add_function(
+            module,
+            "realloc",
+            &mut [int8_ptr_type(), int32_type()],
+            int8_ptr_type(),
+        );
+    }
+    add_function(
+        module,
+        "memcpy",
+        &mut [int8_ptr_type(), int8_ptr_type(), word],
+        int8_ptr_type(),
+    );
+    let write_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("write"));
+    let write_size = write_size_type(module);
+    let buf = LLVMGetParam(write_fn, 1);
+    let count_size = LLVMGetParam(write_fn, 2);
+    let entry_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("entry"));
+    let grow_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("grow"));
+    let grown_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("grown"));
+    let failed_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("failed"));
+    let copy_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("copy"));
+
+    builder.position_at_end(entry_bb);
+    let count = builder.int_cast(module, count_size, int32_type(), LLVM_FALSE, "count");
+    let len = builder.load(module, output_len_ptr, "len");
+    let cap = builder.load(module, output_cap_ptr, "cap");
+    let new_len = builder.add(module, len, count, "new_len");
+    let full = builder.icmp(module, LLVMIntPredicate::LLVMIntUGT, new_len, cap, "full");
+    builder.cond_br(full, grow_bb, copy_bb);
+
+    // Double the buffer, so appending a byte at a time takes
+    // amortised constant time.
+    builder.position_at_end(grow_bb);
+    let doubled_cap = builder.mul(module, cap, int32(2), "doubled_cap");
+    let too_small = builder.icmp(
+        module,
+        LLVMIntPredicate::LLVMIntUGT,
+        new_len,
+        doubled_cap,
+        "too_small",
+    );
+    let new_cap = builder.select(module, too_small, new_len, doubled_cap, "new_cap");
+    let old_output = builder.load(module, output_ptr, "old_output");
+    let new_output = add_function_call(
+        module,
+        grow_bb,
+        "realloc",
+        &mut [old_output, new_cap],
+        "new_output",
+    );
+    builder.position_at_end(grow_bb);
+    let failed = builder.is_null(module, new_output, "failed");
+    builder.cond_br(failed, failed_bb, grown_bb);
+
+    builder.position_at_end(grown_bb);
+    builder.store(new_output, output_ptr);
+    builder.store(new_cap, output_cap_ptr);
+    builder.br(copy_bb);
+
+    builder.position_at_end(failed_bb);
+    builder.ret(LLVMConstInt(write_size, c_ulonglong::MAX, LLVM_TRUE));
+
+    builder.position_at_end(copy_bb);
+    let output = builder.load(module, output_ptr, "output");
+    let mut indices = [len];
+    let dest = builder.gep(module, output, &mut indices, "dest");
+    let count_word = builder.zext(module, count, word, "count_word");
+    add_function_call(module, copy_bb, "memcpy", &mut [dest, buf, count_word], "");
+    builder.position_at_end(copy_bb);
+    builder.store(new_len, output_len_ptr);
+    builder.ret(count_size);
+
+    let putchar_fn = add_putchar_with_write(module, write_fn);
+    for &function in &[getchar_fn, write_fn, putchar_fn] {
+        LLVMSetLinkage(function, LLVMLinkage::LLVMInternalLinkage);
+    }
+
+    add_function(
+        module,
+        "bf_run",
+        &mut [
+            int8_ptr_type(),
+            word,
+            LLVMPointerType(int8_ptr_type(), 0),
+            LLVMPointerType(word, 0),
+        ],
+        int32_type(),
+    );
+    let run_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("bf_run"));
+    add_function_attributes(module, run_fn, options.perf_friendly);
+    let target_triple = CStr::from_ptr(LLVMGetTarget(module.module)).to_string_lossy();
+    if target_triple.contains("-windows") {
+        LLVMSetDLLStorageClass(run_fn, LLVMDLLStorageClass::LLVMDLLExportStorageClass);
+    }
+
+    builder.position_at_end(LLVMAppendBasicBlock(run_fn, module.new_string_ptr("entry")));
+    let input = LLVMGetParam(run_fn, 0);
+    let mut indices = [LLVMGetParam(run_fn, 1)];
+    let input_end = builder.gep(module, input, &mut indices, "input_end");
+    builder.store(input, input_ptr);
+    builder.store(input_end, input_end_ptr);
+    builder.store(LLVMConstNull(int8_ptr_type()), output_ptr);
+    builder.store(int32(0), output_len_ptr);
+    builder.store(int32(0), output_cap_ptr);
+
+    let status = LLVMBuildCall(
+        builder.builder,
+        main_fn,
+        null_mut(),
+        0,
+        module.new_string_ptr("status"),
+    );
+
+    let output = builder.load(module, output_ptr, "output");
+    builder.store(output, LLVMGetParam(run_fn, 2));
+    let len = builder.load(module, output_len_ptr, "len");
+    let len = builder.zext(module, len, word, "len_word");
+    builder.store(len, LLVMGetParam(run_fn, 3));
+    builder.ret(status);
+}
+
+/// Send output to `bf_putchar` and take input from `bf_getchar`,
+/// which the user provides, and define the other libc functions we
+/// call.
+unsafe fn add_bare_metal_runtime(module: &mut Module) {
+    for &(libc_name, hook_name) in &[("putchar", "bf_putchar"), ("getchar", "bf_getchar")] {
+        let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(libc_name));
+        LLVMSetValueName2(function, module.new_string_ptr(hook_name), hook_name.len());
+    }
+    let putchar_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("bf_putchar"));
+
+    // ssize_t write(int fd, char *buf, size_t count), writing every
+    // byte with bf_putchar, whatever fd is.
+    let write_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("write"));
+    LLVMSetLinkage(write_fn, LLVMLinkage::LLVMInternalLinkage);
+    let buf = LLVMGetParam(write_fn, 1);
+    let count_size = LLVMGetParam(write_fn, 2);
+
+    let entry_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("entry"));
+    let header_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("loop_header"));
+    let body_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("loop_body"));
+    let after_bb = LLVMAppendBasicBlock(write_fn, module.new_string_ptr("after_loop"));
+    let builder = Builder::new();
+
+    builder.position_at_end(entry_bb);
+    let count = builder.int_cast(module, count_size, int32_type(), LLVM_FALSE, "count");
+    let index_ptr = builder.alloca(module, int32_type(), "index_ptr");
+    builder.store(int32(0), index_ptr);
+    builder.br(header_bb);
+
+    builder.position_at_end(header_bb);
+    let index = builder.load(module, index_ptr, "index");
+    let done = builder.icmp(module, LLVMIntPredicate::LLVMIntSGE, index, count, "done");
+    builder.cond_br(done, after_bb, body_bb);
+
+    builder.position_at_end(body_bb);
+    let mut indices = [index];
+    let byte_ptr = builder.gep(module, buf, &mut indices, "byte_ptr");
+    let byte = builder.load(module, byte_ptr, "byte");
+    let byte_as_int = builder.zext(module, byte, int32_type(), "byte_as_int");
+    let mut putchar_args = [byte_as_int];
+    builder.call(module, putchar_fn, &mut putchar_args, "");
+    let next_index = builder.add(module, index, int32(1), "next_index");
+    builder.store(next_index, index_ptr);
+    builder.br(header_bb);
+
+    builder.position_at_end(after_bb);
+    builder.ret(count_size);
+
+    // void abort(void), which --ptr-check calls. There's nowhere to
+    // exit to, so hang.
+    let abort_fn = LLVMGetNamedFunction(module.module, module.new_string_ptr("abort"));
+    if !abort_fn.is_null() {
+        LLVMSetLinkage(abort_fn, LLVMLinkage::LLVMInternalLinkage);
+        let hang_bb = LLVMAppendBasicBlock(abort_fn, module.new_string_ptr("hang"));
+        builder.position_at_end(hang_bb);
+        builder.br(hang_bb);
+    }
+
+    // LLVM lowers large llvm.memset and llvm.memcpy calls to calls
+    // to memset and memcpy. They're weak, so a libc the user links
+    // takes precedence.
+    let word = word_type(module);
+    for &(name, copy) in &[("memset", false), ("memcpy", true)] {
+        add_byte_loop_fn(module, name, copy, word);
+        let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(name));
+        LLVMSetLinkage(function, LLVMLinkage::LLVMWeakAnyLinkage);
+    }
+}
+
+/// Define `void *name(void *dest, int c, size_t n)` as memset, or
+/// `void *name(void *dest, void *src, size_t n)` as memcpy when
+/// `copy` is set, with a byte-by-byte loop.
+unsafe fn add_byte_loop_fn(module: &mut Module, name: &str, copy: bool, word: LLVMTypeRef) {
+    let source_type = if copy { int8_ptr_type() } else { int32_type() };
+    add_function(
+        module,
+        name,
+        &mut [int8_ptr_type(), source_type, word],
+        int8_ptr_type(),
+    );
+    let function = LLVMGetNamedFunction(module.module, module.new_string_ptr(name));
+
+    // Stop LLVM recognising the loop as a memset or memcpy and
+    // calling ourselves.
+    let no_builtins = string_attribute(module, "no-builtins", "");
+    LLVMAddAttributeAtIndex(function, LLVMAttributeFunctionIndex, no_builtins);
+
+    let dest = LLVMGetParam(function, 0);
+    let source = LLVMGetParam(function, 1);
+    let len = LLVMGetParam(function, 2);
+
+    let entry_bb = LLVMAppendBasicBlock(function, module.new_string_ptr("entry"));
+    let header_bb = LLVMAppendBasicBlock(function, module.new_string_ptr("loop_header"));
+    let body_bb = LLVMAppendBasicBlock(function, module.new_string_ptr("loop_body"));
+    let after_bb = LLVMAppendBasicBlock(function, module.new_string_ptr("after_loop"));
+    let builder = Builder::new();
+
+    builder.position_at_end(entry_bb);
+    let index_ptr = builder.alloca(module, word, "index_ptr");
+    builder.store(LLVMConstInt(word, 0, LLVM_FALSE), index_ptr);
+    builder.br(header_bb);
+
+    builder.position_at_end(header_bb);
+    let index = builder.load(module, index_ptr, "index");
+    let done = builder.icmp(module, LLVMIntPredicate::LLVMIntEQ, index, len, "done");
+    builder.cond_br(done, after_bb, body_bb);
+
+    builder.position_at_end(body_bb);
+    let mut indices = [index];
+    let dest_byte_ptr = builder.gep(module, dest, &mut indices, "dest_byte_ptr");
+    let byte = if copy {
+        let source_byte_ptr = builder.gep(module, source, &mut indices, "source_byte_ptr");
+        builder.load(module, source_byte_ptr, "byte")
+    } else {
+        builder.trunc(module, source, int8_type(), "byte")
+    };
+    builder.store(byte, dest_byte_ptr);
+    let next_index = builder.add(
+        module,
+        index,
+        LLVMConstInt(word, 1, LLVM_FALSE),
+        "next_index",
+    );
+    builder.store(next_index, index_ptr);
+    builder.br(header_bb);
+
+    builder.position_at_end(after_bb);
+    builder.ret(dest);
+}
+
+/// The fewest adjacent cells we set with a single memset.
+const MIN_FILL_CELLS: usize = 4;
+
+/// If `instrs` starts with at least `MIN_FILL_CELLS` instructions
+/// that set adjacent cells to the same value, return the value, the
+/// offset of the first cell and the number of cells. Execution can't
+/// start partway through a fill, and checked instructions must be
+/// compiled on their own.
+fn fill_run(
+    instrs: &[AstNode],
+    start_instr: &AstNode,
+    ctx: &CompileContext,
+) -> Option<(Cell, isize, usize)> {
+    let (amount, offset) = match instrs.first() {
+        Some(&Set { amount, offset, .. }) => (amount, offset),
+        _ => return None,
+    };
+    // memset writes bytes, so it can only fill wider cells with zero.
+    if ctx.cell_bits != 8 && amount.0 != 0 {
+        return None;
+    }
+
+    let len = instrs
+        .iter()
+        .enumerate()
+        .take_while(|&(index, instr)| {
+            let adjacent = match *instr {
+                Set {
+                    amount: instr_amount,
+                    offset: instr_offset,
+                    ..
+                } => instr_amount == amount && instr_offset == offset + index as isize,
+                _ => false,
+            };
+            adjacent
+                && (index == 0 || !ptr_equal(instr, start_instr))
+                && !ctx.ptr_checks.contains_key(&(instr as *const AstNode))
+        })
+        .count();
+
+    if len >= MIN_FILL_CELLS {
+        Some((amount, offset, len))
+    } else {
+        None
+    }
+}
+
+/// Set `len` cells, starting `offset` cells from the current cell, to
+/// `amount`.
+unsafe fn compile_fill(
+    amount: Cell,
+    offset: isize,
+    len: usize,
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let builder = Builder::new();
+    builder.position_at_end(bb);
+
+    let cell_index = builder.load(module, ctx.cell_index_ptr, "cell_index");
+
+    let offset_cell_index = builder.add(
+        module,
+        cell_index,
+        int32(offset as c_ulonglong),
+        "offset_cell_index",
+    );
+
+    let cells = cells_base(module, bb, &ctx);
+    let mut indices = vec![offset_cell_index];
+    let fill_start_ptr = builder.gep(module, cells, &mut indices, "fill_start_ptr");
+
+    let fill_start_ptr =
+        builder.pointer_cast(module, fill_start_ptr, int8_ptr_type(), "fill_start_bytes");
+    // The fill starts at a cell, so it's aligned like one.
+    let cell_bytes = u64::from(ctx.cell_bits / 8);
+    build_memset(
+        module,
+        bb,
+        fill_start_ptr,
+        int8(amount.0 as c_ulonglong),
+        int32(len as u64 * cell_bytes),
+        cell_bytes,
+    );
+    bb
+}
+
+/// Append LLVM IR instructions to bb acording to the BF instruction
+/// passed in.
+unsafe fn compile_instr(
+    instr: &AstNode,
+    start_instr: &AstNode,
+    module: &mut Module,
+    main_fn: LLVMValueRef,
+    bb: LLVMBasicBlockRef,
+    ctx: CompileContext,
+) -> LLVMBasicBlockRef {
+    let ptr_check = ctx.ptr_checks.get(&(instr as *const AstNode));
+
+    // Loops check the pointer on every iteration, so they handle
+    // their own checks.
+    let bb = match ptr_check {
+        Some(message) if !matches!(*instr, Loop { .. }) => {
+            let (lowest, highest) = accessed_offsets(instr);
+            compile_ptr_check(lowest, highest, message, module, bb, &ctx)
+        }
+        _ => bb,
+    };
+
     match *instr {
         Increment { amount, offset, .. } => compile_increment(amount, offset, module, bb, ctx),
         Set { amount, offset, .. } => compile_set(amount, offset, module, bb, ctx),
-        MultiplyMove { ref changes, .. } => compile_multiply_move(changes, module, bb, ctx),
+        MultiplyMove {
+            ref changes,
+            ref adds,
+            ..
+        } => compile_multiply_move(changes, adds, module, bb, ctx),
         PointerIncrement { amount, .. } => compile_ptr_increment(amount, module, bb, ctx),
         Read { .. } => compile_read(module, bb, ctx),
+        SkipRead { count, .. } => compile_skip_read(count, module, bb, ctx),
+        WriteString { ref bytes, .. } => compile_write_string(bytes, module, bb, ctx),
         Write { .. } => compile_write(module, bb, ctx),
-        Loop { ref body, .. } => compile_loop(body, start_instr, module, main_fn, bb, ctx),
+        Loop { ref body, .. } => compile_loop(instr, body, start_instr, module, main_fn, bb, ctx),
     }
 }
 
@@ -780,20 +4673,150 @@ fn compile_static_outputs(module: &mut Module, bb: LLVMBasicBlockRef, outputs: &
         let stdout_fd = int32(1);
         let llvm_num_outputs = int32(outputs.len() as c_ulonglong);
 
-        let known_outputs_ptr = LLVMBuildPointerCast(
-            builder.builder,
-            known_outputs,
-            int8_ptr_type(),
-            module.new_string_ptr("known_outputs_ptr"),
-        );
+        let known_outputs_ptr =
+            builder.pointer_cast(module, known_outputs, int8_ptr_type(), "known_outputs_ptr");
 
-        add_function_call(
+        add_write_all_call(module, bb, stdout_fd, known_outputs_ptr, llvm_num_outputs);
+    }
+}
+
+/// Store `value` in the cell at `cell_index`.
+unsafe fn store_cell(
+    module: &mut Module,
+    builder: &Builder,
+    cells: LLVMValueRef,
+    cell_index: usize,
+    value: LLVMValueRef,
+) {
+    let mut indices = vec![int32(cell_index as c_ulonglong)];
+    let cell_ptr = builder.gep(module, cells, &mut indices, "cell_ptr");
+    builder.store(value, cell_ptr);
+}
+
+/// Compute `byte * scale + offset`, the value of a cell after
+/// compile time execution did arithmetic on the byte read.
+unsafe fn build_read_value(
+    module: &mut Module,
+    builder: &Builder,
+    byte: LLVMValueRef,
+    scale: Cell,
+    offset: Cell,
+    cell_bits: u32,
+) -> LLVMValueRef {
+    let mut value = byte;
+    if scale.0 != 1 {
+        value = builder.mul(module, value, cell_const(scale, cell_bits), "scaled_input");
+    }
+    if offset.0 != 0 {
+        value = builder.add(module, value, cell_const(offset, cell_bits), "offset_input");
+    }
+    value
+}
+
+/// Perform the reads that compile time execution continued past,
+/// along with any outputs written after the first read, then set the
+/// cells that depend on which way loops on the values read went. If
+/// `cells` is None, no instructions run after this, so we discard
+/// the values read.
+unsafe fn compile_deferred_reads(
+    module: &mut Module,
+    bb: LLVMBasicBlockRef,
+    state: &ExecutionState,
+    cells: Option<LLVMValueRef>,
+    options: &CodegenOptions,
+) {
+    let cell_bits = options.cell_bits;
+    // Compile time execution never defers reads that may leave the
+    // cell unchanged, so only zero needs handling here.
+    let eof_value = match options.eof {
+        EofBehaviour::Zero => Some(cell_const(Wrapping(0), cell_bits)),
+        EofBehaviour::Unchanged | EofBehaviour::MinusOne => None,
+    };
+
+    let builder = Builder::new();
+    let mut input_bytes = vec![];
+
+    for (i, read) in state.reads.iter().enumerate() {
+        let mut input_char = None;
+        for _ in 0..read.count {
+            let mut getchar_args = vec![];
+            input_char = Some(add_function_call(
+                module,
+                bb,
+                "getchar",
+                &mut getchar_args,
+                "input_char",
+            ));
+        }
+
+        let needed = read.cell.is_some() || state.branches.iter().any(|branch| branch.read == i);
+        if let (Some(cells), Some(input_char), true) = (cells, input_char, needed) {
+            builder.position_at_end(bb);
+            let input_byte = build_input_byte(module, &builder, input_char, cell_bits, eof_value);
+            input_bytes.push(Some(input_byte));
+
+            // Compile time execution may have done arithmetic on
+            // the value read, which we apply now.
+            if let Some(cell_index) = read.cell {
+                let value = build_read_value(
+                    module,
+                    &builder,
+                    input_byte,
+                    read.scale,
+                    state.cells[cell_index],
+                    cell_bits,
+                );
+                store_cell(module, &builder, cells, cell_index, value);
+            }
+        } else {
+            input_bytes.push(None);
+        }
+
+        let next_outputs_start = match state.reads.get(i + 1) {
+            Some(next_read) => next_read.outputs_before,
+            None => state.outputs.len(),
+        };
+        let outputs = &state.outputs[read.outputs_before..next_outputs_start];
+        if !outputs.is_empty() {
+            compile_static_outputs(module, bb, outputs);
+        }
+    }
+
+    let cells = match cells {
+        Some(cells) => cells,
+        None => return,
+    };
+    for branch in &state.branches {
+        let input_byte = match input_bytes[branch.read] {
+            Some(input_byte) => input_byte,
+            None => continue,
+        };
+        builder.position_at_end(bb);
+        let condition = build_read_value(
             module,
-            bb,
-            "write",
-            &mut [stdout_fd, known_outputs_ptr, llvm_num_outputs],
-            "",
+            &builder,
+            input_byte,
+            branch.scale,
+            branch.offset,
+            cell_bits,
         );
+        let entered = builder.icmp(
+            module,
+            LLVMIntPredicate::LLVMIntNE,
+            condition,
+            cell_const(Wrapping(0), cell_bits),
+            "loop_entered",
+        );
+        for &(cell_index, entered_value) in &branch.cells {
+            let value = builder.select(
+                module,
+                entered,
+                cell_const(entered_value, cell_bits),
+                cell_const(state.cells[cell_index], cell_bits),
+                "branch_value",
+            );
+            store_cell(module, &builder, cells, cell_index, value);
+        }
     }
 }
 
@@ -808,12 +4831,12 @@ unsafe fn set_entry_point_after(
     // From the current bb, we want to continue execution in after_init.
     let builder = Builder::new();
     builder.position_at_end(bb);
-    LLVMBuildBr(builder.builder, after_init_bb);
+    builder.br(after_init_bb);
 
     // We also want to start execution in after_init.
     let init_bb = LLVMGetFirstBasicBlock(main_fn);
     builder.position_at_end(init_bb);
-    LLVMBuildBr(builder.builder, after_init_bb);
+    builder.br(after_init_bb);
 
     after_init_bb
 }
@@ -825,13 +4848,173 @@ pub fn compile_to_module(
     instrs: &[AstNode],
     initial_state: &ExecutionState,
 ) -> Module {
+    compile_to_module_with_options(
+        module_name,
+        target_triple,
+        instrs,
+        initial_state,
+        &CodegenOptions::default(),
+    )
+}
+
+/// Where the tape lives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TapeStorage {
+    Stack,
+    Heap,
+    /// Memory the user reserved for the tape.
+    Address(u64),
+    /// Mapped between guard pages.
+    Guarded,
+}
+
+/// Where to put a tape of `num_cells` cells. We realloc growable
+/// tapes, so they're always on the heap.
+fn tape_storage(num_cells: usize, growable: bool, options: &CodegenOptions) -> TapeStorage {
+    if options.guard_pages {
+        return TapeStorage::Guarded;
+    }
+    if growable {
+        return TapeStorage::Heap;
+    }
+    if let (true, Some(address)) = (options.bare_metal, options.tape_address) {
+        return TapeStorage::Address(address);
+    }
+    // Without libc, there's no malloc.
+    if options.freestanding || options.bare_metal || num_cells <= options.stack_tape_limit {
+        TapeStorage::Stack
+    } else {
+        TapeStorage::Heap
+    }
+}
+
+/// The number of cells a growable tape needs before the program
+/// starts: every cell compile time execution left non-zero, the
+/// current cell, and every cell a deferred read sets.
+fn initial_tape_len(initial_state: &ExecutionState, growable_from: usize) -> usize {
+    let nonzero_cells = initial_state
+        .cells
+        .iter()
+        .rposition(|cell| cell.0 != 0)
+        .map_or(0, |index| index + 1);
+    let read_cells = initial_state
+        .reads
+        .iter()
+        .filter_map(|read| read.cell)
+        .chain(
+            initial_state
+                .branches
+                .iter()
+                .flat_map(|branch| branch.cells.iter().map(|&(index, _)| index)),
+        )
+        .map(|index| index + 1)
+        .max()
+        .unwrap_or(0);
+
+    let len = growable_from
+        .max(nonzero_cells)
+        .max(read_cells)
+        .max(initial_state.cell_ptr as usize + 1);
+    len.min(initial_state.cells.len())
+}
+
+/// Add `metadata` as a constant in a section of its own. Nothing
+/// refers to it, so `llvm.used` stops LLVM removing it.
+unsafe fn add_build_metadata(module: &mut Module, metadata: &BuildMetadata, target_triple: &str) {
+    let mut llvm_bytes: Vec<_> = metadata
+        .to_bytes()
+        .iter()
+        .map(|&byte| int8(u64::from(byte)))
+        .collect();
+    let metadata_type = LLVMArrayType(int8_type(), llvm_bytes.len() as c_uint);
+    let global = LLVMAddGlobal(
+        module.module,
+        metadata_type,
+        module.new_string_ptr("bfc_build_metadata"),
+    );
+    LLVMSetInitializer(
+        global,
+        LLVMConstArray(
+            int8_type(),
+            llvm_bytes.as_mut_ptr(),
+            llvm_bytes.len() as c_uint,
+        ),
+    );
+    LLVMSetGlobalConstant(global, LLVM_TRUE);
+    LLVMSetLinkage(global, LLVMLinkage::LLVMPrivateLinkage);
+    LLVMSetSection(
+        global,
+        module.new_string_ptr(metadata::section_name(target_triple)),
+    );
+
+    let mut used = [LLVMConstPointerCast(global, int8_ptr_type())];
+    let used_global = LLVMAddGlobal(
+        module.module,
+        LLVMArrayType(int8_ptr_type(), used.len() as c_uint),
+        module.new_string_ptr("llvm.used"),
+    );
+    LLVMSetInitializer(
+        used_global,
+        LLVMConstArray(int8_ptr_type(), used.as_mut_ptr(), used.len() as c_uint),
+    );
+    LLVMSetLinkage(used_global, LLVMLinkage::LLVMAppendingLinkage);
+    LLVMSetSection(used_global, module.new_string_ptr("llvm.metadata"));
+}
+
+/// As `compile_to_module`, but with `options`. Each instruction in
+/// `options.ptr_checks` is checked to stay on the tape at runtime.
+/// If it doesn't, the program prints the message given and aborts,
+/// unless the tape can grow.
+pub fn compile_to_module_with_options(
+    module_name: &str,
+    target_triple: Option<String>,
+    instrs: &[AstNode],
+    initial_state: &ExecutionState,
+    options: &CodegenOptions,
+) -> Module {
+    let ptr_checks = &options.ptr_checks;
     let mut module = create_module(module_name, target_triple);
     let main_fn = add_main_fn(&mut module);
+    unsafe {
+        add_function_attributes(&mut module, main_fn, options.perf_friendly);
+
+        // Newer versions of wasi-libc start programs from
+        // __main_void rather than main.
+        let target_triple = CStr::from_ptr(LLVMGetTarget(module.module))
+            .to_string_lossy()
+            .into_owned();
+        if is_wasm_target(&target_triple) {
+            LLVMAddAlias(
+                module.module,
+                LLVMTypeOf(main_fn),
+                main_fn,
+                module.new_string_ptr("__main_void"),
+            );
+        } else if let Some(ref metadata) = options.metadata {
+            add_build_metadata(&mut module, metadata, &target_triple);
+        }
+    }
+    let debug_scope = options
+        .debug_info
+        .as_ref()
+        .map(|source_map| unsafe { add_debug_info(&mut module, main_fn, source_map) });
 
     let (init_bb, mut bb) = add_initial_bbs(&mut module, main_fn);
 
-    if !initial_state.outputs.is_empty() {
-        compile_static_outputs(&mut module, init_bb, &initial_state.outputs);
+    let residual = residualise(instrs, initial_state);
+    let loop_counters = match options.profile {
+        Some(ref profile) => unsafe {
+            add_loop_counters(&mut module, profile, &initial_state.loop_counts)
+        },
+        None => HashMap::new(),
+    };
+    let coverage_flags = options.coverage.as_ref().map(|coverage| unsafe {
+        let executed = executed_instrs(instrs, initial_state);
+        add_coverage_flags(&mut module, coverage, &executed)
+    });
+
+    if !residual.prefix_output.is_empty() {
+        compile_static_outputs(&mut module, init_bb, residual.prefix_output);
     }
 
     unsafe {
@@ -841,48 +5024,236 @@ pub fn compile_to_module(
             Some(start_instr) => {
                 // TODO: decide on a consistent order between module and init_bb as
                 // parameters.
-                let llvm_cells = add_cells_init(&initial_state.cells, &mut module, init_bb);
-                let llvm_cell_index =
-                    add_cell_index_init(initial_state.cell_ptr, init_bb, &mut module);
+                let tape_len = match ptr_checks.growable_from {
+                    Some(growable_from) => initial_tape_len(initial_state, growable_from),
+                    None => initial_state.cells.len(),
+                };
+                let growable = tape_len < initial_state.cells.len()
+                    || (ptr_checks.growable_from.is_some() && ptr_checks.grow_without_limit);
+                let storage = tape_storage(tape_len, growable, options);
+                let llvm_cells = add_cells_init(
+                    &initial_state.cells[..tape_len],
+                    storage,
+                    options.cell_bits,
+                    &mut module,
+                    init_bb,
+                );
+                if storage == TapeStorage::Heap && !growable {
+                    // Every cell stays allocated until we free the
+                    // tape, so LLVM can hoist loads from it.
+                    let tape_bytes = tape_len as u64 * u64::from(options.cell_bits / 8);
+                    let dereferenceable =
+                        enum_attribute(&mut module, "dereferenceable", tape_bytes);
+                    // Wider cells are a cast of the pointer malloc
+                    // returns.
+                    let malloc_call = if options.cell_bits == 8 {
+                        llvm_cells
+                    } else {
+                        LLVMGetOperand(llvm_cells, 0)
+                    };
+                    LLVMAddCallSiteAttribute(
+                        malloc_call,
+                        LLVMAttributeReturnIndex,
+                        dereferenceable,
+                    );
+                }
+                compile_deferred_reads(
+                    &mut module,
+                    init_bb,
+                    initial_state,
+                    Some(llvm_cells),
+                    options,
+                );
+                let llvm_cell_index = add_cell_index_init(
+                    initial_state.cell_ptr,
+                    options.debug_runtime,
+                    init_bb,
+                    &mut module,
+                );
+
+                // Any compile time input we didn't read must be read
+                // before stdin.
+                let read_fn = if initial_state.input.is_empty() {
+                    "getchar"
+                } else {
+                    let input: Vec<u8> = initial_state.input.iter().copied().collect();
+                    let read_fn = add_const_input_reader(&mut module, &input);
+                    let read_fn_value =
+                        LLVMGetNamedFunction(module.module, module.new_string_ptr(read_fn));
+                    add_function_attributes(&mut module, read_fn_value, options.perf_friendly);
+                    read_fn
+                };
+                let read_fn = if options.buffered_output {
+                    add_output_buffer(&mut module, options);
+                    add_flushing_reader(&mut module, read_fn, options)
+                } else {
+                    read_fn
+                };
+                if storage == TapeStorage::Guarded {
+                    add_overrun_handler(&mut module, init_bb, options);
+                }
 
                 let ctx = CompileContext {
                     cells: llvm_cells,
                     cell_index_ptr: llvm_cell_index,
                     main_fn,
+                    read_fn,
+                    loop_counts: &initial_state.loop_counts,
+                    ptr_checks: &ptr_checks.messages,
+                    num_cells: initial_state.cells.len(),
+                    debug_scope,
+                    buffered_output: options.buffered_output,
+                    perf_friendly: options.perf_friendly,
+                    function_size_limit: options.function_size_limit,
+                    stdio: !options.freestanding && !options.bare_metal,
+                    eof: options.eof,
+                    cell_bits: options.cell_bits,
+                    guard_pages: options.guard_pages,
+                    debug_runtime: options.debug_runtime,
+                    loop_counters: &loop_counters,
+                    coverage: coverage_flags.as_ref(),
+                    growable: if growable {
+                        // Without a limit, we stop short of the
+                        // largest tape whose size in bytes fits in
+                        // an i32.
+                        let max_cells = if ptr_checks.grow_without_limit {
+                            i32::MAX as usize / (options.cell_bits / 8) as usize
+                        } else {
+                            initial_state.cells.len()
+                        };
+                        let mut tape = add_growable_tape_init(
+                            llvm_cells,
+                            tape_len,
+                            max_cells,
+                            options.cell_bits,
+                            options.debug_runtime,
+                            init_bb,
+                            &mut module,
+                        );
+                        if options.runtime_tape_size {
+                            tape.max_cells_ptr = Some(add_runtime_tape_limit(
+                                &tape,
+                                tape_len,
+                                options.cell_bits,
+                                init_bb,
+                                &mut module,
+                            ));
+                        }
+                        Some(tape)
+                    } else {
+                        None
+                    },
                 };
+                if options.debug_runtime {
+                    add_debug_runtime(&mut module, init_bb, &ctx, tape_len, options);
+                }
 
-                for instr in instrs {
-                    if ptr_equal(instr, start_instr) {
-                        // This is the point we want to start execution from.
-                        bb = set_entry_point_after(&mut module, main_fn, bb);
-                    }
-
-                    bb = compile_instr(instr, start_instr, &mut module, main_fn, bb, ctx.clone());
+                // We only compile the instructions that haven't
+                // already been executed at compile time.
+                bb = compile_split_block(residual.instrs, start_instr, &mut module, bb, &ctx);
+                if options.buffered_output {
+                    add_function_call(&mut module, bb, "flush_output", &mut [], "");
                 }
 
-                add_cells_cleanup(&mut module, bb, llvm_cells);
+                // The stack tape is freed when main returns.
+                match storage {
+                    TapeStorage::Heap => {
+                        let llvm_cells = cells_base(&mut module, bb, &ctx);
+                        add_cells_cleanup(&mut module, bb, llvm_cells);
+                    }
+                    TapeStorage::Guarded => {
+                        let tape_bytes = tape_len as u64 * u64::from(options.cell_bits / 8);
+                        add_guarded_tape_cleanup(&mut module, bb, llvm_cells, tape_bytes);
+                    }
+                    TapeStorage::Stack | TapeStorage::Address(_) => {}
+                }
+                if options.debug_runtime {
+                    make_accesses_volatile(main_fn);
+                }
             }
             None => {
+                compile_deferred_reads(&mut module, init_bb, initial_state, None, options);
+
                 // We won't have called set_entry_point_after, so set
                 // the entry point.
                 let builder = Builder::new();
                 builder.position_at_end(init_bb);
-                LLVMBuildBr(builder.builder, bb);
+                builder.br(bb);
             }
         }
 
+        if let Some(ref profile) = options.profile {
+            bb = add_profile_writer(&mut module, main_fn, bb, profile, &loop_counters);
+        }
+        if let (Some(coverage), Some(coverage_flags)) = (&options.coverage, &coverage_flags) {
+            bb = add_coverage_writer(&mut module, main_fn, bb, coverage, coverage_flags);
+        }
         add_main_cleanup(bb);
 
+        // Only libc's write sets errno, so with our own runtimes
+        // write_all gives up on any failure.
+        let target_triple = CStr::from_ptr(LLVMGetTarget(module.module))
+            .to_string_lossy()
+            .into_owned();
+        let errno_fn = if options.freestanding || options.bare_metal || options.library {
+            None
+        } else {
+            errno_location_fn(&target_triple)
+        };
+        define_write_all(&mut module, errno_fn);
+
+        if options.freestanding {
+            let target_triple = CStr::from_ptr(LLVMGetTarget(module.module)).to_string_lossy();
+            let abi = syscall_abi(&target_triple).expect("--freestanding on an unsupported target");
+            add_freestanding_runtime(&mut module, main_fn, &abi);
+        }
+        if options.bare_metal {
+            add_bare_metal_runtime(&mut module);
+        }
+        if options.library {
+            add_library_runtime(&mut module, main_fn, options);
+        }
+
+        if let Some(scope) = debug_scope {
+            LLVMDIBuilderFinalize(scope.builder);
+            LLVMDisposeDIBuilder(scope.builder);
+        }
+
         module
     }
 }
 
-pub fn optimise_ir(module: &mut Module, llvm_opt: i64) {
-    // TODO: add a verifier pass too.
+/// Check the module is valid LLVM IR. Optimising or generating code
+/// for invalid IR can crash LLVM, so this is a bug in bfc.
+pub fn verify_module(module: &Module) -> Result<(), Info> {
+    unsafe {
+        let mut message = null_mut();
+        let broken = LLVMVerifyModule(
+            module.module,
+            LLVMVerifierFailureAction::LLVMReturnStatusAction,
+            &mut message,
+        );
+        let details = CStr::from_ptr(message).to_string_lossy().into_owned();
+        LLVMDisposeMessage(message);
+        if broken != LLVM_FALSE {
+            return Err(Info::error(
+                Code::ToolFailure,
+                format!("bfc generated invalid LLVM IR: {}", details.trim_end()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Optimise the module with LLVM. `size_level` is 0 to optimise for
+/// speed, 1 for -Os or 2 for -Oz.
+pub fn optimise_ir(module: &mut Module, llvm_opt: i64, size_level: u32) {
+    #[cfg(not(feature = "llvm-14"))]
     unsafe {
         let builder = LLVMPassManagerBuilderCreate();
         // E.g. if llvm_opt is 3, we want a pass equivalent to -O3.
         LLVMPassManagerBuilderSetOptLevel(builder, llvm_opt as u32);
+        LLVMPassManagerBuilderSetSizeLevel(builder, size_level);
 
         let pass_manager = LLVMCreatePassManager();
         LLVMPassManagerBuilderPopulateModulePassManager(builder, pass_manager);
@@ -897,6 +5268,178 @@ pub fn optimise_ir(module: &mut Module, llvm_opt: i64) {
 
         LLVMDisposePassManager(pass_manager);
     }
+    #[cfg(feature = "llvm-14")]
+    {
+        // E.g. if llvm_opt is 3, we want default<O3>.
+        let preset = match size_level {
+            0 => format!("default<O{}>", llvm_opt),
+            1 => "default<Os>".to_owned(),
+            _ => "default<Oz>".to_owned(),
+        };
+
+        // Run twice, as above.
+        for _ in 0..2 {
+            run_passes(module, &preset).expect("LLVM rejected a default pipeline");
+        }
+    }
+}
+
+/// A function that adds an LLVM pass to a pass manager.
+#[cfg(not(feature = "llvm-14"))]
+type AddPass = unsafe extern "C" fn(LLVMPassManagerRef);
+
+/// The passes `--llvm-passes` accepts, by the names `opt -passes`
+/// uses.
+#[cfg(not(feature = "llvm-14"))]
+const NAMED_PASSES: &[(&str, AddPass)] = &[
+    ("adce", scalar::LLVMAddAggressiveDCEPass),
+    ("always-inline", ipo::LLVMAddAlwaysInlinerPass),
+    ("argpromotion", ipo::LLVMAddArgumentPromotionPass),
+    ("bdce", scalar::LLVMAddBitTrackingDCEPass),
+    ("constmerge", ipo::LLVMAddConstantMergePass),
+    (
+        "correlated-propagation",
+        scalar::LLVMAddCorrelatedValuePropagationPass,
+    ),
+    ("deadargelim", ipo::LLVMAddDeadArgEliminationPass),
+    ("dse", scalar::LLVMAddDeadStoreEliminationPass),
+    ("early-cse", scalar::LLVMAddEarlyCSEPass),
+    ("function-attrs", ipo::LLVMAddFunctionAttrsPass),
+    ("globaldce", ipo::LLVMAddGlobalDCEPass),
+    ("globalopt", ipo::LLVMAddGlobalOptimizerPass),
+    ("gvn", scalar::LLVMAddGVNPass),
+    ("indvars", scalar::LLVMAddIndVarSimplifyPass),
+    ("inline", ipo::LLVMAddFunctionInliningPass),
+    ("instcombine", instcombine::LLVMAddInstructionCombiningPass),
+    ("ipsccp", ipo::LLVMAddIPSCCPPass),
+    ("jump-threading", scalar::LLVMAddJumpThreadingPass),
+    ("licm", scalar::LLVMAddLICMPass),
+    ("loop-deletion", scalar::LLVMAddLoopDeletionPass),
+    ("loop-idiom", scalar::LLVMAddLoopIdiomPass),
+    ("loop-rotate", scalar::LLVMAddLoopRotatePass),
+    ("loop-unroll", scalar::LLVMAddLoopUnrollPass),
+    ("loop-vectorize", vectorize::LLVMAddLoopVectorizePass),
+    ("mem2reg", util::LLVMAddPromoteMemoryToRegisterPass),
+    ("memcpyopt", scalar::LLVMAddMemCpyOptPass),
+    ("mldst-motion", scalar::LLVMAddMergedLoadStoreMotionPass),
+    ("newgvn", scalar::LLVMAddNewGVNPass),
+    ("reassociate", scalar::LLVMAddReassociatePass),
+    ("sccp", scalar::LLVMAddSCCPPass),
+    ("simplifycfg", scalar::LLVMAddCFGSimplificationPass),
+    ("slp-vectorizer", vectorize::LLVMAddSLPVectorizePass),
+    ("sroa", scalar::LLVMAddScalarReplAggregatesPass),
+    ("strip-dead-prototypes", ipo::LLVMAddStripDeadPrototypesPass),
+    ("tailcallelim", scalar::LLVMAddTailCallEliminationPass),
+    ("verify", scalar::LLVMAddVerifierPass),
+];
+
+/// One step of a `--llvm-passes` pipeline.
+#[cfg(not(feature = "llvm-14"))]
+enum PipelineStep {
+    /// A `default<On>` preset, as the optimisation and size level.
+    Preset(u32, u32),
+    Pass(AddPass),
+}
+
+#[cfg(not(feature = "llvm-14"))]
+fn parse_pipeline_step(name: &str) -> Result<PipelineStep, Info> {
+    match name {
+        "default<O0>" => return Ok(PipelineStep::Preset(0, 0)),
+        "default<O1>" => return Ok(PipelineStep::Preset(1, 0)),
+        "default<O2>" => return Ok(PipelineStep::Preset(2, 0)),
+        "default<O3>" => return Ok(PipelineStep::Preset(3, 0)),
+        "default<Os>" => return Ok(PipelineStep::Preset(2, 1)),
+        "default<Oz>" => return Ok(PipelineStep::Preset(2, 2)),
+        _ => {}
+    }
+    if name.contains('(') {
+        return Err(Info::error(
+            Code::InvalidOption,
+            format!(
+            "Nested LLVM pipelines such as '{}' aren't supported, list passes separated by commas",
+            name
+        ),
+        ));
+    }
+    let named_pass = NAMED_PASSES.iter().find(|&&(pass_name, _)| pass_name == name);
+    match named_pass {
+        Some(&(_, add_pass)) => Ok(PipelineStep::Pass(add_pass)),
+        None => Err(Info::error(
+            Code::InvalidOption,
+            format!("Unknown LLVM pass '{}'", name),
+        )),
+    }
+}
+
+/// Optimise the module with the comma-separated passes in
+/// `pipeline`, e.g. "mem2reg,instcombine,default<O2>". This accepts
+/// the flat subset of `opt -passes` syntax.
+#[cfg(not(feature = "llvm-14"))]
+pub fn optimise_ir_with_pipeline(module: &mut Module, pipeline: &str) -> Result<(), Info> {
+    let steps = pipeline
+        .split(',')
+        .map(|name| parse_pipeline_step(name.trim()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    unsafe {
+        let pass_manager = LLVMCreatePassManager();
+        for step in steps {
+            match step {
+                PipelineStep::Preset(opt_level, size_level) => {
+                    let builder = LLVMPassManagerBuilderCreate();
+                    LLVMPassManagerBuilderSetOptLevel(builder, opt_level);
+                    LLVMPassManagerBuilderSetSizeLevel(builder, size_level);
+                    LLVMPassManagerBuilderPopulateModulePassManager(builder, pass_manager);
+                    LLVMPassManagerBuilderDispose(builder);
+                }
+                PipelineStep::Pass(add_pass) => add_pass(pass_manager),
+            }
+        }
+
+        LLVMRunPassManager(pass_manager, module.module);
+        LLVMDisposePassManager(pass_manager);
+    }
+    Ok(())
+}
+
+/// Optimise the module with `pipeline`, in the syntax of `opt
+/// -passes`, e.g. "mem2reg,instcombine,default<O2>".
+#[cfg(feature = "llvm-14")]
+pub fn optimise_ir_with_pipeline(module: &mut Module, pipeline: &str) -> Result<(), Info> {
+    // Pass names never contain spaces, but LLVM doesn't skip them.
+    let pipeline: String = pipeline.split_whitespace().collect();
+    // In a module pipeline, a flat list can mix function passes and
+    // presets, as it could with the legacy pass manager.
+    run_passes(module, &format!("module({})", pipeline)).map_err(|message| {
+        Info::error(
+            Code::InvalidOption,
+            format!("Invalid LLVM pipeline '{}': {}", pipeline, message),
+        )
+    })
+}
+
+/// Run `passes` over the module with the new pass manager, which
+/// LLVM 13 added to the C API. Returns LLVM's message if it can't
+/// parse `passes`.
+#[cfg(feature = "llvm-14")]
+fn run_passes(module: &mut Module, passes: &str) -> Result<(), String> {
+    unsafe {
+        let options = LLVMCreatePassBuilderOptions();
+        // Like the legacy pass manager builder, we don't give LLVM a
+        // target machine.
+        let error = LLVMRunPasses(
+            module.module,
+            module.new_string_ptr(passes),
+            null_mut(),
+            options,
+        );
+        LLVMDisposePassBuilderOptions(options);
+        if error.is_null() {
+            Ok(())
+        } else {
+            Err(take_llvm_error(error))
+        }
+    }
 }
 
 pub fn get_default_target_triple() -> CString {
@@ -914,8 +5457,58 @@ struct TargetMachine {
     tm: LLVMTargetMachineRef,
 }
 
+/// How to generate machine code for the target.
+#[derive(Debug, Clone)]
+pub struct TargetOptions {
+    /// The CPU to generate code for, as `llc -mcpu` takes it, or
+    /// "native" for the host CPU.
+    pub cpu: String,
+    /// Extra CPU features, as `llc -mattr` takes them, e.g. "+avx2".
+    pub features: String,
+    pub relocation_model: RelocationModel,
+    /// How large code and data may be, or None for LLVM's default.
+    pub code_model: Option<CodeModel>,
+}
+
+/// Whether the object file can be loaded at any address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationModel {
+    /// Fixed addresses, which can only be linked without PIE.
+    Static,
+    /// Position independent code, which can be linked as PIE or not.
+    Pic,
+}
+
+/// How far apart code and data may be. Programs whose initial tape
+/// is over 2 GiB need a larger model than small.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodeModel {
+    Small,
+    Medium,
+    Large,
+}
+
+impl Default for TargetOptions {
+    fn default() -> Self {
+        TargetOptions {
+            cpu: "generic".to_owned(),
+            features: String::new(),
+            relocation_model: RelocationModel::Pic,
+            code_model: None,
+        }
+    }
+}
+
+/// Take ownership of a string that LLVM has allocated.
+unsafe fn take_llvm_string(ptr: *mut i8) -> String {
+    let s = CStr::from_ptr(ptr as *const _).to_string_lossy();
+    let owned = s.into_owned();
+    LLVMDisposeMessage(ptr);
+    owned
+}
+
 impl TargetMachine {
-    fn new(target_triple: *const i8) -> Result<Self, Info> {
+    fn new(target_triple: *const i8, options: &TargetOptions) -> Result<Self, Info> {
         let mut target = null_mut();
         let mut err_msg_ptr = null_mut();
         unsafe {
@@ -927,15 +5520,48 @@ impl TargetMachine {
 
                 let err_msg_cstr = CStr::from_ptr(err_msg_ptr as *const _);
                 let err_msg = str::from_utf8(err_msg_cstr.to_bytes()).unwrap();
-                return Err(Info::error(err_msg.to_owned()));
+                return Err(Info::error(Code::UnsupportedTarget, err_msg.to_owned()));
             }
         }
 
-        // TODO: do these strings live long enough?
         // cpu is documented: http://llvm.org/docs/CommandGuide/llc.html#cmdoption-mcpu
-        let cpu = CString::new("generic").unwrap();
         // features are documented: http://llvm.org/docs/CommandGuide/llc.html#cmdoption-mattr
-        let features = CString::new("").unwrap();
+        let (cpu, mut features) = if options.cpu == "native" {
+            unsafe {
+                (
+                    take_llvm_string(LLVMGetHostCPUName()),
+                    take_llvm_string(LLVMGetHostCPUFeatures()),
+                )
+            }
+        } else {
+            (options.cpu.clone(), String::new())
+        };
+        if !options.features.is_empty() {
+            if !features.is_empty() {
+                features.push(',');
+            }
+            features.push_str(&options.features);
+        }
+
+        let invalid_option = |_| {
+            Info::error(
+                Code::InvalidOption,
+                "Target CPU and features cannot contain NUL".to_owned(),
+            )
+        };
+        let cpu = CString::new(cpu).map_err(invalid_option)?;
+        let features = CString::new(features).map_err(invalid_option)?;
+
+        let reloc_mode = match options.relocation_model {
+            RelocationModel::Static => LLVMRelocMode::LLVMRelocStatic,
+            RelocationModel::Pic => LLVMRelocMode::LLVMRelocPIC,
+        };
+        let code_model = match options.code_model {
+            None => LLVMCodeModel::LLVMCodeModelDefault,
+            Some(CodeModel::Small) => LLVMCodeModel::LLVMCodeModelSmall,
+            Some(CodeModel::Medium) => LLVMCodeModel::LLVMCodeModelMedium,
+            Some(CodeModel::Large) => LLVMCodeModel::LLVMCodeModelLarge,
+        };
 
         let target_machine;
         unsafe {
@@ -945,8 +5571,8 @@ impl TargetMachine {
                 cpu.as_ptr() as *const _,
                 features.as_ptr() as *const _,
                 LLVMCodeGenOptLevel::LLVMCodeGenLevelAggressive,
-                LLVMRelocMode::LLVMRelocPIC,
-                LLVMCodeModel::LLVMCodeModelDefault,
+                reloc_mode,
+                code_model,
             );
         }
 
@@ -973,10 +5599,29 @@ pub fn init_llvm() {
     }
 }
 
-pub fn write_object_file(module: &mut Module, path: &str) -> Result<(), Info> {
+/// Set the module's data layout from its target, so optimisation
+/// knows the sizes and alignments of types. This requires
+/// `init_llvm`.
+pub fn set_data_layout(module: &mut Module, options: &TargetOptions) -> Result<(), Info> {
+    unsafe {
+        let target_triple = LLVMGetTarget(module.module);
+        let target_machine = TargetMachine::new(target_triple, options)?;
+
+        let data_layout = LLVMCreateTargetDataLayout(target_machine.tm);
+        LLVMSetModuleDataLayout(module.module, data_layout);
+        LLVMDisposeTargetData(data_layout);
+    }
+    Ok(())
+}
+
+pub fn write_object_file(
+    module: &mut Module,
+    path: &str,
+    options: &TargetOptions,
+) -> Result<(), Info> {
     unsafe {
         let target_triple = LLVMGetTarget(module.module);
-        let target_machine = TargetMachine::new(target_triple)?;
+        let target_machine = TargetMachine::new(target_triple, options)?;
 
         let mut obj_error = module.new_mut_string_ptr("Writing object file failed.");
         let result = LLVMTargetMachineEmitToFile(
@@ -988,8 +5633,416 @@ pub fn write_object_file(module: &mut Module, path: &str) -> Result<(), Info> {
         );
 
         if result != 0 {
-            return Err(Info::error(format!("obj_error: {:?}", CStr::from_ptr(obj_error as *const _))));
+            return Err(Info::error(
+                Code::ToolFailure,
+                format!("obj_error: {:?}", CStr::from_ptr(obj_error as *const _)),
+            ));
         }
     }
     Ok(())
 }
+
+/// Write the module as LLVM bitcode, for the linker to optimise and
+/// compile with LTO.
+pub fn write_bitcode_file(module: &mut Module, path: &str) -> Result<(), Info> {
+    let result = unsafe { LLVMWriteBitcodeToFile(module.module, module.new_string_ptr(path)) };
+    if result != 0 {
+        return Err(Info::error(
+            Code::FileError,
+            format!("Could not write bitcode to {}", path),
+        ));
+    }
+    Ok(())
+}
+
+/// Take ownership of an error from LLVM, and return its message.
+unsafe fn take_llvm_error(error: LLVMErrorRef) -> String {
+    let message = LLVMGetErrorMessage(error);
+    let owned = CStr::from_ptr(message).to_string_lossy().into_owned();
+    LLVMDisposeErrorMessage(message);
+    owned
+}
+
+/// Find the address of `name` for the JIT: first in the code the
+/// JIT has compiled, then in bfc's own process, so the program calls
+/// the same libc we do.
+#[cfg(not(feature = "llvm-14"))]
+extern "C" fn resolve_jit_symbol(name: *const c_char, jit: *mut c_void) -> u64 {
+    unsafe {
+        let mut address = 0;
+        let error = LLVMOrcGetSymbolAddress(jit as LLVMOrcJITStackRef, &mut address, name);
+        if !error.is_null() {
+            LLVMConsumeError(error);
+        }
+        if address != 0 {
+            return address;
+        }
+
+        // The JIT asks for mangled names, but the process has plain
+        // C names.
+        let mut name = CStr::from_ptr(name).to_bytes();
+        if cfg!(target_os = "macos") && name.starts_with(b"_") {
+            name = &name[1..];
+        }
+        match CString::new(name) {
+            Ok(name) => LLVMSearchForAddressOfSymbol(name.as_ptr()) as u64,
+            Err(_) => 0,
+        }
+    }
+}
+
+/// Compile `module` in memory with the ORC JIT and run its main, in
+/// bfc's own process. The program reads our stdin and writes our
+/// stdout, so there's no object file, linker or strip. `module` must
+/// target the host. Returns main's return value.
+pub fn run_jit(module: Module, options: &TargetOptions) -> Result<i32, Info> {
+    unsafe {
+        // Calls from JIT code may be far from libc, so default to the
+        // large code model.
+        let mut options = options.clone();
+        options.code_model = options.code_model.or(Some(CodeModel::Large));
+        let target_machine = TargetMachine::new(LLVMGetTarget(module.module), &options)?;
+        jit_main(module, target_machine)
+    }
+}
+
+/// The JIT failed with `message`.
+fn jit_failed(message: String) -> Info {
+    Info::error(
+        Code::ToolFailure,
+        format!("JIT compilation failed: {}", message),
+    )
+}
+
+/// Compile `module` with the legacy ORC JIT, which LLVM 12 removed,
+/// and run its main.
+#[cfg(not(feature = "llvm-14"))]
+unsafe fn jit_main(mut module: Module, target_machine: TargetMachine) -> Result<i32, Info> {
+    // Let the JIT find libc in our process.
+    if LLVMLoadLibraryPermanently(null_mut()) != 0 {
+        return Err(Info::error(
+            Code::ToolFailure,
+            "Could not load symbols for the JIT".to_owned(),
+        ));
+    }
+
+    // The JIT owns the target machine now.
+    let jit = LLVMOrcCreateInstance(target_machine.tm);
+    std::mem::forget(target_machine);
+
+    // The JIT owns the module now too.
+    let llvm_module = std::mem::replace(&mut module.module, null_mut());
+    let mut handle = 0;
+    let error = LLVMOrcAddEagerlyCompiledIR(
+        jit,
+        &mut handle,
+        llvm_module,
+        Some(resolve_jit_symbol),
+        jit as *mut c_void,
+    );
+    if !error.is_null() {
+        let message = take_llvm_error(error);
+        LLVMOrcDisposeInstance(jit);
+        return Err(jit_failed(message));
+    }
+
+    let mut main_name = null_mut();
+    LLVMOrcGetMangledSymbol(jit, &mut main_name, module.new_string_ptr("main"));
+    let mut main_address = 0;
+    let error = LLVMOrcGetSymbolAddress(jit, &mut main_address, main_name);
+    LLVMOrcDisposeMangledSymbol(main_name);
+    if !error.is_null() || main_address == 0 {
+        let message = if error.is_null() {
+            "no main function".to_owned()
+        } else {
+            take_llvm_error(error)
+        };
+        LLVMOrcDisposeInstance(jit);
+        return Err(jit_failed(message));
+    }
+
+    let main_fn: extern "C" fn() -> i32 = std::mem::transmute(main_address as usize);
+    let status = main_fn();
+
+    let error = LLVMOrcDisposeInstance(jit);
+    if !error.is_null() {
+        LLVMConsumeError(error);
+    }
+    Ok(status)
+}
+
+/// Compile `module` with LLJIT and run its main.
+#[cfg(feature = "llvm-14")]
+unsafe fn jit_main(mut module: Module, target_machine: TargetMachine) -> Result<i32, Info> {
+    // The JIT owns the target machine now.
+    let builder = LLVMOrcCreateLLJITBuilder();
+    LLVMOrcLLJITBuilderSetJITTargetMachineBuilder(
+        builder,
+        LLVMOrcJITTargetMachineBuilderCreateFromTargetMachine(target_machine.tm),
+    );
+    std::mem::forget(target_machine);
+    let mut jit = null_mut();
+    let error = LLVMOrcCreateLLJIT(&mut jit, builder);
+    if !error.is_null() {
+        return Err(jit_failed(take_llvm_error(error)));
+    }
+
+    let result = jit_main_with(jit, &mut module);
+    let error = LLVMOrcDisposeLLJIT(jit);
+    if !error.is_null() {
+        LLVMConsumeError(error);
+    }
+    result
+}
+
+/// Add `module` to `jit`, and run its main.
+#[cfg(feature = "llvm-14")]
+unsafe fn jit_main_with(jit: LLVMOrcLLJITRef, module: &mut Module) -> Result<i32, Info> {
+    // Let the JIT find libc in our process.
+    let dylib = LLVMOrcLLJITGetMainJITDylib(jit);
+    let mut generator = null_mut();
+    let error = LLVMOrcCreateDynamicLibrarySearchGeneratorForProcess(
+        &mut generator,
+        LLVMOrcLLJITGetGlobalPrefix(jit),
+        None,
+        null_mut(),
+    );
+    if !error.is_null() {
+        return Err(jit_failed(take_llvm_error(error)));
+    }
+    LLVMOrcJITDylibAddGenerator(dylib, generator);
+
+    // The JIT needs the module in a context it owns, so copy it over
+    // as bitcode.
+    let context = LLVMOrcCreateNewThreadSafeContext();
+    let bitcode = LLVMWriteBitcodeToMemoryBuffer(module.module);
+    let mut llvm_module = null_mut();
+    let failed = LLVMParseBitcodeInContext2(
+        LLVMOrcThreadSafeContextGetContext(context),
+        bitcode,
+        &mut llvm_module,
+    );
+    LLVMDisposeMemoryBuffer(bitcode);
+    assert!(failed == 0, "LLVM couldn't read the bitcode it wrote");
+    // The module keeps the context alive, and the JIT owns the module.
+    let thread_safe_module = LLVMOrcCreateNewThreadSafeModule(llvm_module, context);
+    LLVMOrcDisposeThreadSafeContext(context);
+    let error = LLVMOrcLLJITAddLLVMIRModule(jit, dylib, thread_safe_module);
+    if !error.is_null() {
+        return Err(jit_failed(take_llvm_error(error)));
+    }
+
+    let mut main_address = 0;
+    let error = LLVMOrcLLJITLookup(jit, &mut main_address, module.new_string_ptr("main"));
+    if !error.is_null() {
+        return Err(jit_failed(take_llvm_error(error)));
+    }
+
+    let main_fn: extern "C" fn() -> i32 = std::mem::transmute(main_address as usize);
+    Ok(main_fn())
+}
+
+/// A module in its own LLVM context, so we can optimise and compile
+/// it on another thread.
+struct SendModule(Module);
+
+// Nothing else refers to the module or its context.
+unsafe impl Send for SendModule {}
+
+/// The name LLVM has for `value`.
+unsafe fn value_name(value: LLVMValueRef) -> String {
+    let mut len = 0;
+    let name = LLVMGetValueName2(value, &mut len);
+    String::from_utf8_lossy(slice::from_raw_parts(name as *const u8, len)).into_owned()
+}
+
+/// The functions that `compile_split_block` split off from main.
+unsafe fn part_fns(module: &Module) -> HashSet<LLVMValueRef> {
+    let mut parts = HashSet::new();
+    let mut function = LLVMGetFirstFunction(module.module);
+    while !function.is_null() {
+        if value_name(function).starts_with(PART_FN_PREFIX) {
+            parts.insert(function);
+        }
+        function = LLVMGetNextFunction(function);
+    }
+    parts
+}
+
+/// The most object files `write_object_files` can usefully split
+/// `module` into: one for main, and one for each function that
+/// `compile_split_block` split off.
+pub fn max_jobs(module: &Module) -> usize {
+    unsafe {
+        // Aliases must be defined alongside the function they point
+        // to, so we don't split modules that have them.
+        if !LLVMGetFirstGlobalAlias(module.module).is_null() {
+            return 1;
+        }
+        1 + part_fns(module).len()
+    }
+}
+
+/// Which of `jobs` jobs compiles the function called `name`. We
+/// share split off functions round robin, and everything else goes
+/// in the first job.
+fn job_for(name: &str, jobs: usize) -> usize {
+    match name
+        .strip_prefix(PART_FN_PREFIX)
+        .and_then(|part_number| part_number.parse::<usize>().ok())
+    {
+        Some(part_number) => part_number % jobs,
+        None => 0,
+    }
+}
+
+/// Whether an instruction in `functions` uses `value`, either
+/// directly or through a constant expression.
+unsafe fn used_in(value: LLVMValueRef, functions: &HashSet<LLVMValueRef>) -> bool {
+    let mut value_use = LLVMGetFirstUse(value);
+    while !value_use.is_null() {
+        let user = LLVMGetUser(value_use);
+        let used = if !LLVMIsAInstruction(user).is_null() {
+            functions.contains(&LLVMGetBasicBlockParent(LLVMGetInstructionParent(user)))
+        } else {
+            !LLVMIsAConstantExpr(user).is_null() && used_in(user, functions)
+        };
+        if used {
+            return true;
+        }
+        value_use = LLVMGetNextUse(value_use);
+    }
+    false
+}
+
+/// Replace `function` with a declaration, so another object defines
+/// it.
+unsafe fn replace_with_declaration(module: &mut Module, function: LLVMValueRef) {
+    let name = value_name(function);
+    LLVMSetValueName2(function, module.new_string_ptr(""), 0);
+    let fn_type = LLVMGetElementType(LLVMTypeOf(function));
+    let declaration = LLVMAddFunction(module.module, module.new_string_ptr(&name), fn_type);
+    LLVMSetVisibility(declaration, LLVMGetVisibility(function));
+    LLVMReplaceAllUsesWith(function, declaration);
+    LLVMDeleteFunction(function);
+}
+
+/// Copy `module` into a module for each of `jobs` jobs, each in its
+/// own context. Each copy keeps the definitions `job_for` gives it,
+/// and declares everything else.
+unsafe fn split_module(module: &mut Module, jobs: usize) -> Vec<SendModule> {
+    // Split off functions, and anything they use, must be visible
+    // to the other objects.
+    let parts = part_fns(module);
+    let mut globals = vec![];
+    let mut function = LLVMGetFirstFunction(module.module);
+    while !function.is_null() {
+        globals.push(function);
+        function = LLVMGetNextFunction(function);
+    }
+    let mut global = LLVMGetFirstGlobal(module.module);
+    while !global.is_null() {
+        globals.push(global);
+        global = LLVMGetNextGlobal(global);
+    }
+    for &global in &globals {
+        let local = matches!(
+            LLVMGetLinkage(global),
+            LLVMLinkage::LLVMInternalLinkage | LLVMLinkage::LLVMPrivateLinkage
+        );
+        if local && (parts.contains(&global) || used_in(global, &parts)) {
+            LLVMSetLinkage(global, LLVMLinkage::LLVMExternalLinkage);
+            LLVMSetVisibility(global, LLVMVisibility::LLVMHiddenVisibility);
+        }
+    }
+
+    let bitcode = LLVMWriteBitcodeToMemoryBuffer(module.module);
+    let job_modules = (0..jobs)
+        .map(|job| {
+            let context = LLVMContextCreate();
+            let mut llvm_module = null_mut();
+            // This doesn't take ownership of the bitcode.
+            let failed = LLVMParseBitcodeInContext2(context, bitcode, &mut llvm_module);
+            assert!(failed == 0, "LLVM couldn't read the bitcode it wrote");
+            let mut job_module = Module {
+                module: llvm_module,
+                strings: vec![],
+                context: Some(context),
+            };
+
+            let mut functions = vec![];
+            let mut function = LLVMGetFirstFunction(job_module.module);
+            while !function.is_null() {
+                if LLVMIsDeclaration(function) == 0 && job_for(&value_name(function), jobs) != job {
+                    functions.push(function);
+                }
+                function = LLVMGetNextFunction(function);
+            }
+            for function in functions {
+                replace_with_declaration(&mut job_module, function);
+            }
+
+            // Global variables are all defined by the first job,
+            // which also keeps the build metadata.
+            if job != 0 {
+                let used =
+                    LLVMGetNamedGlobal(job_module.module, job_module.new_string_ptr("llvm.used"));
+                if !used.is_null() {
+                    LLVMDeleteGlobal(used);
+                }
+                let mut global = LLVMGetFirstGlobal(job_module.module);
+                while !global.is_null() {
+                    if LLVMIsDeclaration(global) == 0 {
+                        LLVMSetInitializer(global, null_mut());
+                        LLVMSetLinkage(global, LLVMLinkage::LLVMExternalLinkage);
+                    }
+                    global = LLVMGetNextGlobal(global);
+                }
+            }
+            SendModule(job_module)
+        })
+        .collect();
+    LLVMDisposeMemoryBuffer(bitcode);
+    job_modules
+}
+
+/// Optimise `module` with `optimise`, and write it to the object
+/// files in `paths`. With several paths, we split the module between
+/// them, and optimise and compile each on its own thread. `paths`
+/// should have at most `max_jobs` paths.
+pub fn write_object_files<F>(
+    module: &mut Module,
+    paths: &[String],
+    options: &TargetOptions,
+    optimise: F,
+) -> Result<(), Info>
+where
+    F: Fn(&mut Module) -> Result<(), Info> + Sync,
+{
+    if paths.len() == 1 {
+        optimise(module)?;
+        return write_object_file(module, &paths[0], options);
+    }
+
+    let job_modules = unsafe { split_module(module, paths.len()) };
+    let optimise = &optimise;
+    let results = thread::scope(|scope| {
+        let handles: Vec<_> = job_modules
+            .into_iter()
+            .zip(paths)
+            .map(|(job_module, path)| {
+                scope.spawn(move || {
+                    let SendModule(mut job_module) = job_module;
+                    optimise(&mut job_module)?;
+                    write_object_file(&mut job_module, path, options)
+                })
+            })
+            .collect();
+        let mut results = vec![];
+        for handle in handles {
+            results.push(handle.join().expect("LLVM codegen thread panicked"));
+        }
+        results
+    });
+    results.into_iter().collect()
+}
@@ -1,13 +1,25 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::CString;
+use std::fs;
+use std::io::Write as _;
 use std::num::Wrapping;
+use std::process::{Command, Stdio};
 
 use crate::bfir::AstNode::*;
-use crate::bfir::Position;
-use crate::execution::ExecutionState;
-use crate::llvm::compile_to_module;
+use crate::bfir::{parse, AstNode, EofBehaviour, Position};
+use crate::execution::{
+    execute_with_limits, interpret_with_limits, DeferredBranch, DeferredRead, ExecutionState,
+    Limits, LoopCounts, Tape,
+};
+use crate::llvm::{
+    compile_to_module, compile_to_module_with_options, init_llvm, is_wasm_target, max_jobs,
+    optimise_ir_with_pipeline, run_jit, set_data_layout, supports_freestanding, verify_module,
+    write_object_file, CodegenOptions, Coverage, Profile, PtrChecks, SourceMap, TargetOptions,
+};
+use crate::metadata::BuildMetadata;
 use itertools::EitherOrBoth::Both;
 use itertools::Itertools;
+use tempfile::NamedTempFileOptions;
 
 /// Assert that two CString values are equal. If they're not, print
 /// the strings nicely (e.g. '\n' as an actual newline).
@@ -58,6 +70,12 @@ fn compile_loop() {
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -65,9 +83,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -77,11 +95,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 1)
+  %cells = call dereferenceable(1) i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 1, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -114,6 +132,7 @@ loop_after:                                       ; preds = %loop_header
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
@@ -129,6 +148,12 @@ fn compile_empty_program() {
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -136,9 +161,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -148,7 +173,7 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
   br label %beginning
 
@@ -157,6 +182,7 @@ beginning:                                        ; preds = %init
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
@@ -177,6 +203,12 @@ fn compile_set_with_offset() {
             cells: vec![Wrapping(0); 50],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -184,9 +216,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -196,11 +228,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 50)
+  %cells = call dereferenceable(50) i8* @malloc(i32 50)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 50, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 50, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -218,6 +250,7 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -236,6 +269,12 @@ fn compile_read() {
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
 
@@ -244,9 +283,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -256,11 +295,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 1)
+  %cells = call dereferenceable(1) i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 1, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -279,12 +318,265 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     println!("actual: {}", result.to_cstring().to_str().unwrap());
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
 
+#[test]
+fn compile_skip_read() {
+    let instrs = vec![SkipRead {
+        count: 3,
+        position: None,
+    }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+    );
+
+    let expected = "; ModuleID = 'foo'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+; Function Attrs: argmemonly nounwind
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
+
+declare noalias i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() #1 {
+init:
+  %cells = call dereferenceable(1) i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 1, i1 false)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  br label %skip_header
+
+skip_header:                                      ; preds = %skip_body, %after_init
+  %skip_count = phi i32 [ 0, %after_init ], [ %skip_count_next, %skip_body ]
+  %skip_done = icmp eq i32 %skip_count, 2
+  br i1 %skip_done, label %skip_after, label %skip_body
+
+skip_body:                                        ; preds = %skip_header
+  %0 = call i32 @getchar()
+  %skip_count_next = add i32 %skip_count, 1
+  br label %skip_header
+
+skip_after:                                       ; preds = %skip_header
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+
+#[test]
+fn compile_write_string() {
+    let instrs = vec![
+        Read { position: None },
+        WriteString {
+            bytes: vec![104, 105],
+            position: None,
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+    );
+
+    let expected = "; ModuleID = 'foo'
+source_filename = \"foo\"
+target triple = \"i686-pc-linux-gnu\"
+
+@known_string = constant [2 x i8] c\"hi\"
+
+; Function Attrs: argmemonly nounwind
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
+
+declare noalias i8* @malloc(i32)
+
+declare void @free(i8*)
+
+declare i32 @write(i32, i8*, i32)
+
+declare i32 @putchar(i32)
+
+declare i32 @getchar()
+
+define i32 @main() #1 {
+init:
+  %cells = call dereferenceable(1) i8* @malloc(i32 1)
+  %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 1, i1 false)
+  %cell_index_ptr = alloca i32
+  store i32 0, i32* %cell_index_ptr
+  br label %after_init
+
+beginning:                                        ; No predecessors!
+  br label %after_init
+
+after_init:                                       ; preds = %init, %beginning
+  %cell_index = load i32, i32* %cell_index_ptr
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %cell_index
+  %input_char = call i32 @getchar()
+  %input_byte = trunc i32 %input_char to i8
+  store i8 %input_byte, i8* %current_cell_ptr
+  %0 = call i32 @fflush(i8* null)
+  call void @write_all(i32 1, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @known_string, i32 0, i32 0), i32 2)
+  call void @free(i8* %cells)
+  ret i32 0
+}
+
+
+declare i32 @fflush(i8*)
+
+define internal void @write_all(i32 %fd, i8* %buf, i32 %len) {
+entry:
+  br label %loop_header
+
+loop_header:                                      ; preds = %failed, %wrote, %entry
+  %remaining_buf = phi i8* [ %buf, %entry ], [ %next_buf, %wrote ], [ %remaining_buf, %failed ]
+  %remaining = phi i32 [ %len, %entry ], [ %next_remaining, %wrote ], [ %remaining, %failed ]
+  %is_done = icmp eq i32 %remaining, 0
+  br i1 %is_done, label %done, label %write
+
+write:                                            ; preds = %loop_header
+  %written = call i32 @write(i32 %fd, i8* %remaining_buf, i32 %remaining)
+  %wrote_some = icmp sgt i32 %written, 0
+  br i1 %wrote_some, label %wrote, label %failed
+
+wrote:                                            ; preds = %write
+  %next_buf = getelementptr i8, i8* %remaining_buf, i32 %written
+  %next_remaining = sub i32 %remaining, %written
+  br label %loop_header
+
+failed:                                           ; preds = %write
+  %errno_ptr = call i32* @__errno_location()
+  %errno = load i32, i32* %errno_ptr
+  %is_error = icmp slt i32 %written, 0
+  %is_eintr = icmp eq i32 %errno, 4
+  %interrupted = and i1 %is_error, %is_eintr
+  br i1 %interrupted, label %loop_header, label %done
+
+done:                                             ; preds = %failed, %loop_header
+  ret void
+}
+
+declare i32* @__errno_location()
+
+attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
+";
+
+    assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
+}
+#[test]
+fn compile_write_with_pointer_sized_count() {
+    let instrs = vec![
+        Read { position: None },
+        WriteString {
+            bytes: vec![104, 105],
+            position: None,
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+    );
+
+    verify_module(&result).unwrap();
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("declare i64 @write(i32, i8*, i64)"));
+    assert!(ir.contains("%written = call i64 @write(i32 %fd, i8* %remaining_buf, i64 %remaining)"));
+    assert!(ir.contains("call i32* @__errno_location()"));
+}
+
+#[test]
+fn compile_write_on_windows() {
+    let instrs = vec![
+        Read { position: None },
+        WriteString {
+            bytes: vec![104, 105],
+            position: None,
+        },
+    ];
+
+    let result = compile_to_module(
+        "foo",
+        Some("x86_64-pc-windows-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+    );
+
+    verify_module(&result).unwrap();
+    let ir = result.to_cstring().into_string().unwrap();
+    // Windows' write takes and returns a 32-bit int, even on 64-bit.
+    assert!(ir.contains("declare i32 @write(i32, i8*, i32)"));
+    assert!(ir.contains("call i32* @_errno()"));
+}
+
 #[test]
 fn compile_write() {
     let instrs = vec![Write { position: None }];
@@ -298,6 +590,12 @@ fn compile_write() {
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
 
@@ -306,9 +604,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -318,11 +616,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 1)
+  %cells = call dereferenceable(1) i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 1, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -341,6 +639,7 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -361,6 +660,12 @@ fn respect_initial_cell_ptr() {
             cells: vec![Wrapping(0); 10],
             cell_ptr: 8,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -368,9 +673,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -380,11 +685,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 10)
+  %cells = call dereferenceable(10) i8* @malloc(i32 10)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 10, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 10, i1 false)
   %cell_index_ptr = alloca i32
   store i32 8, i32* %cell_index_ptr
   br label %after_init
@@ -401,6 +706,7 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -413,6 +719,7 @@ fn compile_multiply_move() {
     changes.insert(2, Wrapping(3));
     let instrs = vec![MultiplyMove {
         changes: changes,
+        adds: HashMap::new(),
         position: Some(Position { start: 0, end: 0 }),
     }];
 
@@ -425,6 +732,12 @@ fn compile_multiply_move() {
             cells: vec![Wrapping(0); 3],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -432,9 +745,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -444,11 +757,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 3)
+  %cells = call dereferenceable(3) i8* @malloc(i32 3)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 3, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 3, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -483,6 +796,7 @@ multiply_after:                                   ; preds = %multiply_body, %aft
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -510,6 +824,12 @@ fn set_initial_cell_values() {
             ],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -517,9 +837,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -529,15 +849,15 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 6)
+  %cells = call dereferenceable(6) i8* @malloc(i32 6)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 1, i32 2, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 1, i32 2, i1 false)
   %offset_cell_ptr1 = getelementptr i8, i8* %cells, i32 2
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr1, i8 2, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr1, i8 2, i32 1, i1 false)
   %offset_cell_ptr2 = getelementptr i8, i8* %cells, i32 3
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr2, i8 0, i32 3, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr2, i8 0, i32 3, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -554,6 +874,7 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -570,6 +891,12 @@ fn compile_static_outputs() {
             cells: vec![],
             cell_ptr: 0,
             outputs: vec![5, 10],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -579,9 +906,9 @@ target triple = \"i686-pc-linux-gnu\"
 @known_outputs = constant [2 x i8] c\"\\05\\0A\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -591,16 +918,51 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %0 = call i32 @write(i32 1, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @known_outputs, i32 0, i32 0), i32 2)
+  call void @write_all(i32 1, i8* getelementptr inbounds ([2 x i8], [2 x i8]* @known_outputs, i32 0, i32 0), i32 2)
   br label %beginning
 
 beginning:                                        ; preds = %init
   ret i32 0
 }
 
+define internal void @write_all(i32 %fd, i8* %buf, i32 %len) {
+entry:
+  br label %loop_header
+
+loop_header:                                      ; preds = %failed, %wrote, %entry
+  %remaining_buf = phi i8* [ %buf, %entry ], [ %next_buf, %wrote ], [ %remaining_buf, %failed ]
+  %remaining = phi i32 [ %len, %entry ], [ %next_remaining, %wrote ], [ %remaining, %failed ]
+  %is_done = icmp eq i32 %remaining, 0
+  br i1 %is_done, label %done, label %write
+
+write:                                            ; preds = %loop_header
+  %written = call i32 @write(i32 %fd, i8* %remaining_buf, i32 %remaining)
+  %wrote_some = icmp sgt i32 %written, 0
+  br i1 %wrote_some, label %wrote, label %failed
+
+wrote:                                            ; preds = %write
+  %next_buf = getelementptr i8, i8* %remaining_buf, i32 %written
+  %next_remaining = sub i32 %remaining, %written
+  br label %loop_header
+
+failed:                                           ; preds = %write
+  %errno_ptr = call i32* @__errno_location()
+  %errno = load i32, i32* %errno_ptr
+  %is_error = icmp slt i32 %written, 0
+  %is_eintr = icmp eq i32 %errno, 4
+  %interrupted = and i1 %is_error, %is_eintr
+  br i1 %interrupted, label %loop_header, label %done
+
+done:                                             ; preds = %failed, %loop_header
+  ret void
+}
+
+declare i32* @__errno_location()
+
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -621,6 +983,12 @@ fn compile_ptr_increment() {
             cells: vec![Wrapping(0); 2],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -628,9 +996,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -640,11 +1008,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 2)
+  %cells = call dereferenceable(2) i8* @malloc(i32 2)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 2, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 2, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -661,6 +1029,7 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -682,6 +1051,12 @@ fn compile_increment() {
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -689,9 +1064,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -701,11 +1076,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 1)
+  %cells = call dereferenceable(1) i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 1, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -725,6 +1100,7 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
@@ -746,6 +1122,12 @@ fn compile_increment_with_offset() {
             cells: vec![Wrapping(0); 4],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -753,9 +1135,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -765,11 +1147,11 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 4)
+  %cells = call dereferenceable(4) i8* @malloc(i32 4)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 4, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 4, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
@@ -789,10 +1171,12 @@ after_init:                                       ; preds = %init, %beginning
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
 
+/// Instructions executed at compile time aren't compiled.
 #[test]
 fn compile_start_instr_midway() {
     let instrs = vec![
@@ -816,6 +1200,12 @@ fn compile_start_instr_midway() {
             cells: vec![Wrapping(0)],
             cell_ptr: 0,
             outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
         },
     );
     let expected = "; ModuleID = \'foo\'
@@ -823,9 +1213,9 @@ source_filename = \"foo\"
 target triple = \"i686-pc-linux-gnu\"
 
 ; Function Attrs: argmemonly nounwind
-declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i32, i1) #0
+declare void @llvm.memset.p0i8.i32(i8* nocapture writeonly, i8, i32, i1) #0
 
-declare i8* @malloc(i32)
+declare noalias i8* @malloc(i32)
 
 declare void @free(i8*)
 
@@ -835,33 +1225,1136 @@ declare i32 @putchar(i32)
 
 declare i32 @getchar()
 
-define i32 @main() {
+define i32 @main() #1 {
 init:
-  %cells = call i8* @malloc(i32 1)
+  %cells = call dereferenceable(1) i8* @malloc(i32 1)
   %offset_cell_ptr = getelementptr i8, i8* %cells, i32 0
-  call void @llvm.memset.p0i8.i32(i8* %offset_cell_ptr, i8 0, i32 1, i32 1, i1 true)
+  call void @llvm.memset.p0i8.i32(i8* align 1 %offset_cell_ptr, i8 0, i32 1, i1 false)
   %cell_index_ptr = alloca i32
   store i32 0, i32* %cell_index_ptr
   br label %after_init
 
 beginning:                                        ; No predecessors!
-  %cell_index = load i32, i32* %cell_index_ptr
-  %offset_cell_index = add i32 %cell_index, 0
-  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
-  store i8 1, i8* %current_cell_ptr
   br label %after_init
 
 after_init:                                       ; preds = %init, %beginning
-  %cell_index1 = load i32, i32* %cell_index_ptr
-  %offset_cell_index2 = add i32 %cell_index1, 0
-  %current_cell_ptr3 = getelementptr i8, i8* %cells, i32 %offset_cell_index2
-  store i8 2, i8* %current_cell_ptr3
+  %cell_index = load i32, i32* %cell_index_ptr
+  %offset_cell_index = add i32 %cell_index, 0
+  %current_cell_ptr = getelementptr i8, i8* %cells, i32 %offset_cell_index
+  store i8 2, i8* %current_cell_ptr
   call void @free(i8* %cells)
   ret i32 0
 }
 
 attributes #0 = { argmemonly nounwind }
+attributes #1 = { norecurse nounwind }
 ";
 
     assert_cstring_eq!(result.to_cstring(), CString::new(expected).unwrap());
 }
+
+#[test]
+fn loop_branch_weights() {
+    let instrs = vec![Loop {
+        body: vec![Read { position: None }],
+        position: None,
+    }];
+
+    let mut loop_counts = HashMap::new();
+    loop_counts.insert(
+        &instrs[0] as *const _,
+        LoopCounts {
+            iterations: 16,
+            exits: 1,
+        },
+    );
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(1)],
+            cell_ptr: 0,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts,
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("label %loop_body, !prof !0"));
+    assert!(ir.contains("!0 = !{!\"branch_weights\", i32 1, i32 16}"));
+}
+
+#[test]
+fn compile_large_cold_loop_separately() {
+    let writes = vec![Write { position: None }; 40];
+    let instrs = vec![
+        Read { position: None },
+        Loop {
+            body: writes.clone(),
+            position: None,
+        },
+        Loop {
+            body: writes,
+            position: None,
+        },
+    ];
+
+    let mut loop_counts = HashMap::new();
+    loop_counts.insert(
+        &instrs[1] as *const _,
+        LoopCounts {
+            iterations: 1,
+            exits: 1,
+        },
+    );
+    loop_counts.insert(
+        &instrs[2] as *const _,
+        LoopCounts {
+            iterations: 16,
+            exits: 1,
+        },
+    );
+    let result = compile_to_module(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            loop_counts,
+            ..ExecutionState::initial(&instrs)
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    // Only the cold loop moves out of main.
+    assert!(ir.contains("define internal void @cold_loop("));
+    assert!(ir.contains("call void @cold_loop(i8* %cells, i32* %cell_index_ptr)"));
+    assert_eq!(ir.matches("define internal void").count(), 1);
+}
+
+#[test]
+fn compile_deferred_branch() {
+    let instrs = vec![Write { position: None }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0), Wrapping(2)],
+            cell_ptr: 1,
+            outputs: vec![],
+            reads: vec![DeferredRead {
+                cell: None,
+                count: 1,
+                outputs_before: 0,
+                scale: Wrapping(1),
+            }],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![DeferredBranch {
+                read: 0,
+                scale: Wrapping(1),
+                offset: Wrapping(-10),
+                cells: vec![(1, Wrapping(3))],
+            }],
+            tape: Tape::Fixed,
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("%offset_input = add i8 %input_byte, -10"));
+    assert!(ir.contains("%loop_entered = icmp ne i8 %offset_input, 0"));
+    assert!(ir.contains("%branch_value = select i1 %loop_entered, i8 3, i8 2"));
+}
+
+#[test]
+fn compile_small_tape_on_stack() {
+    let instrs = vec![Write { position: None }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0), Wrapping(2)],
+            cell_ptr: 1,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+        &CodegenOptions {
+            stack_tape_limit: 2,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("%cells = alloca i8, i32 2"));
+    assert!(!ir.contains("@malloc(i32 "));
+    assert!(!ir.contains("call void @free"));
+}
+
+#[test]
+fn compile_cells_init_from_constant() {
+    let instrs = vec![Write { position: None }];
+    let mut cells = vec![Wrapping(0); 24];
+    for cell in cells.iter_mut().take(19).step_by(2) {
+        *cell = Wrapping(1);
+    }
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells,
+            cell_ptr: 0,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+    );
+
+    verify_module(&result).unwrap();
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@initial_cells = internal constant [19 x i8]"));
+    assert!(ir.contains("call void @llvm.memcpy.p0i8.p0i8.i32(i8* align 1 %cells, i8* align 1 "));
+    assert!(ir.contains("%offset_cell_ptr = getelementptr i8, i8* %cells, i32 19"));
+    assert!(ir.contains("i8* align 1 %offset_cell_ptr, i8 0, i32 5"));
+}
+
+#[test]
+fn compile_wide_cells_init_from_constant() {
+    let instrs = vec![Write { position: None }];
+    let cells = vec![Wrapping(1), Wrapping(300), Wrapping(0)];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells,
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            cell_bits: 16,
+            ..CodegenOptions::default()
+        },
+    );
+
+    verify_module(&result).unwrap();
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@initial_cells = internal constant [2 x i16] [i16 1, i16 300], align 2"));
+    assert!(
+        ir.contains("call void @llvm.memcpy.p0i8.p0i8.i32(i8* align 2 %cell_bytes, i8* align 2 ")
+    );
+}
+
+#[test]
+fn compile_adjacent_sets_as_memset() {
+    let instrs: Vec<_> = (1..5)
+        .map(|offset| Set {
+            amount: Wrapping(0),
+            offset,
+            position: None,
+        })
+        .collect();
+
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(1); 5],
+            cell_ptr: 0,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+    );
+
+    verify_module(&result).unwrap();
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("%offset_cell_index = add i32 %cell_index, 1"));
+    assert!(ir.contains(
+        "call void @llvm.memset.p0i8.i32(i8* align 1 %fill_start_ptr, i8 0, i32 4, i1 false)"
+    ));
+    assert!(!ir.contains("store i8 0"));
+}
+
+#[test]
+fn compile_adjacent_sets_as_aligned_memset() {
+    let instrs: Vec<_> = (1..5)
+        .map(|offset| Set {
+            amount: Wrapping(0),
+            offset,
+            position: None,
+        })
+        .collect();
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            cell_bits: 32,
+            ..CodegenOptions::default()
+        },
+    );
+
+    verify_module(&result).unwrap();
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains(
+        "call void @llvm.memset.p0i8.i32(i8* align 4 %fill_start_bytes, i8 0, i32 16, i1 false)"
+    ));
+}
+
+#[test]
+fn loop_rarely_iterating_not_unrolled() {
+    let instrs = vec![Loop {
+        body: vec![Read { position: None }],
+        position: None,
+    }];
+
+    let mut loop_counts = HashMap::new();
+    loop_counts.insert(
+        &instrs[0] as *const _,
+        LoopCounts {
+            iterations: 1,
+            exits: 4,
+        },
+    );
+    let result = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(1)],
+            cell_ptr: 0,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts,
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("br label %loop_header, !llvm.loop !1"));
+    assert!(ir.contains("!1 = distinct !{!1, !2}"));
+    assert!(ir.contains("!2 = !{!\"llvm.loop.unroll.disable\"}"));
+}
+
+#[test]
+fn source_map_line_col() {
+    let source_map = SourceMap::new("foo.bf", "/tmp", "+\n\n>>.\n");
+    assert_eq!(source_map.line_col(0), (1, 1));
+    assert_eq!(source_map.line_col(2), (2, 1));
+    assert_eq!(source_map.line_col(5), (3, 3));
+}
+
+#[test]
+fn compile_with_debug_info() {
+    let instrs = vec![Write {
+        position: Some(Position { start: 2, end: 2 }),
+    }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(0)],
+            cell_ptr: 0,
+            outputs: vec![],
+            reads: vec![],
+            input: VecDeque::new(),
+            steps: 0,
+            loop_counts: HashMap::new(),
+            branches: vec![],
+            tape: Tape::Fixed,
+        },
+        &CodegenOptions {
+            debug_info: Some(SourceMap::new("foo.bf", "/tmp", "+\n.")),
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@putchar(i32 %cell_val_as_char), !dbg"));
+    assert!(ir.contains("!DIFile(filename: \"foo.bf\", directory: \"/tmp\")"));
+    assert!(ir.contains("DISubprogram(name: \"main\""));
+    assert!(ir.contains("!DILocation(line: 2, column: 1"));
+}
+
+#[test]
+fn compile_with_debug_runtime() {
+    let instrs = vec![Write {
+        position: Some(Position { start: 2, end: 2 }),
+    }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            debug_info: Some(SourceMap::new("foo.bf", "/tmp", "+\n.")),
+            debug_runtime: true,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@signal(i32 2, void (i32)* @debug_handler)"));
+    assert!(ir.contains("@signal(i32 11, void (i32)* @debug_handler)"));
+    assert!(ir.contains("c\"foo.bf:2:1\\00\""));
+    assert!(ir.contains("@cell_index_ptr = internal global i32 0"));
+    assert!(ir.contains("load volatile i32, i32* @cell_index_ptr"));
+}
+
+#[test]
+fn compile_with_profile() {
+    let instrs = vec![Loop {
+        body: vec![Read { position: None }],
+        position: Some(Position { start: 0, end: 2 }),
+    }];
+
+    let mut loop_counts = HashMap::new();
+    loop_counts.insert(
+        &instrs[0] as *const _,
+        LoopCounts {
+            iterations: 3,
+            exits: 0,
+        },
+    );
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            cells: vec![Wrapping(1)],
+            loop_counts,
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            profile: Some(Profile {
+                loops: vec![(&instrs[0] as *const _, "foo.bf:1:1".to_owned())],
+                path: "foo.profile".to_owned(),
+            }),
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    // The counts carry on from compile time execution.
+    assert!(ir.contains("@loop_iterations = internal global i64 3"));
+    assert!(ir.contains("@loop_exits = internal global i64 0"));
+    assert!(ir.contains("c\"foo.profile\\00\""));
+    assert!(ir.contains("c\"foo.bf:1:1\\00\""));
+    assert!(ir.contains("c\"%s %llu %llu\\0A\\00\""));
+    assert!(ir.contains("@fopen("));
+}
+
+#[test]
+fn run_with_jit() {
+    init_llvm();
+    let instrs = vec![Increment {
+        amount: Wrapping(1),
+        offset: 0,
+        position: None,
+    }];
+    let mut module = compile_to_module(
+        "foo",
+        None,
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+    );
+    let options = TargetOptions::default();
+    set_data_layout(&mut module, &options).unwrap();
+
+    assert_eq!(run_jit(module, &options).unwrap(), 0);
+}
+
+#[test]
+fn compile_with_coverage() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Read {
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[1]),
+            cells: vec![Wrapping(1)],
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            coverage: Some(Coverage {
+                instrs: vec![(&instrs[0] as *const _, 1), (&instrs[1] as *const _, 2)],
+                source_path: "/tmp/foo.bf".to_owned(),
+                path: "foo.lcov".to_owned(),
+            }),
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    // The increment ran at compile time.
+    assert!(ir.contains("@coverage_flags = internal global [2 x i8] c\"\\01\\00\""));
+    assert!(ir.contains("c\"TN:\\0ASF:/tmp/foo.bf\\0A\\00\""));
+    assert!(ir.contains("c\"foo.lcov\\00\""));
+    assert!(ir.contains("c\"a\\00\""));
+}
+
+#[test]
+fn compile_split_into_functions() {
+    let instrs = vec![
+        Read { position: None },
+        Write { position: None },
+        Write { position: None },
+        Write { position: None },
+    ];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            function_size_limit: 2,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    // The read stays in main, and the writes fill two functions.
+    assert!(ir.contains("define internal void @main_part1(i8* noalias %0, i32* noalias %1)"));
+    assert!(ir.contains("define internal void @main_part2("));
+    assert!(!ir.contains("@main_part3"));
+    assert!(ir.contains("call void @main_part1(i8* %cells, i32* %cell_index_ptr)"));
+    // main and each part can be compiled separately.
+    assert_eq!(max_jobs(&result), 3);
+}
+
+#[test]
+fn compile_with_build_metadata() {
+    let instrs = vec![Write { position: None }];
+    let metadata = BuildMetadata::new(b".", "x86_64-unknown-linux-gnu", vec![]);
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState::initial(&instrs),
+        &CodegenOptions {
+            metadata: Some(metadata),
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@bfc_build_metadata = private constant"));
+    assert!(ir.contains("c\"bfc-build-metadata 1\\0Aversion "));
+    assert!(ir.contains("section \".bfc_metadata\""));
+    assert!(ir.contains("@llvm.used = appending global [1 x i8*]"));
+    assert!(ir.contains("@bfc_build_metadata to i8*)], section \"llvm.metadata\""));
+}
+
+#[test]
+fn optimise_with_custom_pipeline() {
+    let instrs = vec![Write { position: None }];
+    let mut module = compile_to_module(
+        "foo",
+        Some("i686-pc-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState::initial(&instrs),
+    );
+
+    assert!(optimise_ir_with_pipeline(&mut module, "mem2reg, instcombine,default<O1>").is_ok());
+    assert!(optimise_ir_with_pipeline(&mut module, "no-such-pass").is_err());
+    // Only the new pass manager understands nested pipelines.
+    #[cfg(not(feature = "llvm-14"))]
+    assert!(optimise_ir_with_pipeline(&mut module, "function(instcombine)").is_err());
+    #[cfg(feature = "llvm-14")]
+    assert!(optimise_ir_with_pipeline(&mut module, "function(instcombine)").is_ok());
+}
+
+#[test]
+fn freestanding_targets() {
+    assert!(supports_freestanding("x86_64-unknown-linux-gnu"));
+    assert!(supports_freestanding("aarch64-linux-musl"));
+    assert!(!supports_freestanding("x86_64-apple-darwin"));
+    assert!(!supports_freestanding("wasm32-wasi"));
+}
+
+#[test]
+fn compile_freestanding() {
+    let instrs = vec![Read { position: None }, Write { position: None }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            freestanding: true,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    // The default stack_tape_limit is 0, but there's no malloc.
+    assert!(ir.contains("%cells = alloca i8, i32 1"));
+    assert!(ir.contains("define internal i32 @putchar(i32)"));
+    assert!(ir.contains("define internal i32 @getchar()"));
+    assert!(ir.contains("asm sideeffect \"syscall\""));
+    assert!(ir.contains("define void @_start()"));
+    assert!(ir.contains("define i8* @memset(i8*, i32, i64)"));
+}
+
+#[test]
+fn compile_bare_metal() {
+    let instrs = vec![Read { position: None }, Write { position: None }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("riscv32-unknown-none-elf".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            bare_metal: true,
+            tape_address: Some(0x2000_0000),
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("declare i32 @bf_putchar(i32)"));
+    assert!(ir.contains("declare i32 @bf_getchar()"));
+    assert!(ir.contains("inttoptr (i64 536870912 to i8*)"));
+    assert!(ir.contains("define weak i8* @memset(i8*, i32, i32)"));
+    assert!(!ir.contains("@malloc"));
+    assert!(!ir.contains("@_start"));
+}
+
+#[test]
+fn compile_buffered_output() {
+    let instrs = vec![
+        Read { position: None },
+        Write { position: None },
+        WriteString {
+            bytes: b"hi".to_vec(),
+            position: None,
+        },
+    ];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            buffered_output: true,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@output_buffer = internal global [4096 x i8] zeroinitializer"));
+    assert!(ir.contains("call i32 @read_after_flush()"));
+    assert!(ir.contains("call void @output_byte(i32 %cell_val_as_char)"));
+    assert!(ir.contains("call void @output_bytes("));
+    assert!(ir.contains("call void @flush_output()"));
+    assert!(!ir.contains("@putchar(i32 %"));
+    assert!(!ir.contains("@fflush"));
+}
+
+#[test]
+fn compile_read_with_eof_zero() {
+    let instrs = vec![Read { position: None }, Write { position: None }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            eof: EofBehaviour::Zero,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("%at_eof = icmp eq i32 %input_char, -1"));
+    assert!(ir.contains("%input_or_eof = select i1 %at_eof, i8 0, i8 %input_byte"));
+}
+
+#[test]
+fn compile_read_with_eof_unchanged() {
+    let instrs = vec![Read { position: None }, Write { position: None }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            eof: EofBehaviour::Unchanged,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("%old_cell = load i8, i8* %current_cell_ptr"));
+    assert!(ir.contains("%input_or_eof = select i1 %at_eof, i8 %old_cell, i8 %input_byte"));
+}
+
+#[test]
+fn compile_tape_growing_without_limit() {
+    let instrs = vec![PointerIncrement {
+        amount: 1,
+        position: None,
+    }];
+    let mut messages = HashMap::new();
+    messages.insert(&instrs[0] as *const _, "Went past the end".to_owned());
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            ptr_checks: PtrChecks {
+                messages,
+                growable_from: Some(1),
+                grow_without_limit: true,
+            },
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@realloc("));
+    assert!(ir.contains("icmp slt i32 %highest_index, 2147483647"));
+}
+
+#[test]
+fn compile_tape_size_from_environment() {
+    let instrs = vec![PointerIncrement {
+        amount: 1,
+        position: None,
+    }];
+    let mut messages = HashMap::new();
+    messages.insert(&instrs[0] as *const _, "Went past the end".to_owned());
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            ptr_checks: PtrChecks {
+                messages,
+                growable_from: Some(1),
+                grow_without_limit: false,
+            },
+            runtime_tape_size: true,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("BF_TAPE_SIZE"));
+    assert!(ir.contains("define internal i32 @tape_limit("));
+    assert!(ir.contains("@max_cells = internal global i32 0"));
+    assert!(ir.contains("load i32, i32* @max_cells"));
+}
+
+#[test]
+fn compile_ptr_checks_share_error_reporter() {
+    let instrs = vec![
+        PointerIncrement {
+            amount: -1,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+    ];
+    let mut messages = HashMap::new();
+    messages.insert(&instrs[0] as *const _, "Out of bounds at 1:1".to_owned());
+    messages.insert(&instrs[1] as *const _, "Out of bounds at 1:2".to_owned());
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            ptr_checks: PtrChecks {
+                messages,
+                ..PtrChecks::default()
+            },
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    let reporters = ir
+        .matches("define internal void @report_ptr_error(")
+        .count();
+    assert_eq!(reporters, 1);
+    assert_eq!(ir.matches("call void @report_ptr_error(").count(), 2);
+    assert!(ir.contains("c\" (pointer at cell \""));
+}
+
+#[test]
+fn compile_guard_pages() {
+    let instrs = vec![PointerIncrement {
+        amount: -1,
+        position: None,
+    }];
+    let mut messages = HashMap::new();
+    messages.insert(&instrs[0] as *const _, "Tape overrun".to_owned());
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            ptr_checks: PtrChecks {
+                messages,
+                ..PtrChecks::default()
+            },
+            guard_pages: true,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("i32 3, i32 34, i32 -1, i64 0)"));
+    assert!(ir.contains("@mprotect(i8* %right_guard"));
+    assert!(ir.contains("@signal(i32 11, void (i32)* @overrun_handler)"));
+    assert!(ir.contains("store volatile i8*"));
+    assert!(ir.contains("@munmap("));
+    assert!(!ir.contains("ptr_check_failed"));
+}
+
+#[test]
+fn compile_16_bit_cells() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+        Read { position: None },
+        Write { position: None },
+    ];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            cell_bits: 16,
+            ..CodegenOptions::default()
+        },
+    );
+
+    verify_module(&result).unwrap();
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@malloc(i32 2)"));
+    assert!(ir.contains("bitcast i8* %cells to i16*"));
+    assert!(ir.contains("i8* align 2 %offset_cell_bytes, i8 0, i32 2, i1 false)"));
+    assert!(ir.contains("add i16 %cell_value, 1"));
+    assert!(ir.contains("trunc i32 %input_char to i16"));
+    assert!(ir.contains("sext i16 %cell_value"));
+}
+
+#[test]
+fn compile_32_bit_cells() {
+    let instrs = vec![Read { position: None }, Write { position: None }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            cell_bits: 32,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("@malloc(i32 4)"));
+    // Reads and writes need no conversion.
+    assert!(ir.contains("store i32 %input_char"));
+    assert!(ir.contains("call i32 @putchar(i32 %cell_value"));
+}
+
+#[test]
+fn compile_library() {
+    let instrs = vec![Read { position: None }, Write { position: None }];
+
+    let result = compile_to_module_with_options(
+        "foo",
+        Some("x86_64-unknown-linux-gnu".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+        &CodegenOptions {
+            library: true,
+            ..CodegenOptions::default()
+        },
+    );
+
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("define i32 @bf_run(i8*, i64, i8**, i64*)"));
+    assert!(ir.contains("define internal i32 @bf_main()"));
+    assert!(ir.contains("@bf_input = internal thread_local global i8* null"));
+    assert!(ir.contains("define internal i32 @getchar()"));
+    assert!(!ir.contains("@main("));
+}
+
+#[test]
+fn compile_wasi() {
+    let instrs = vec![Write { position: None }];
+
+    let result = compile_to_module(
+        "foo",
+        Some("wasm32-unknown-wasi".to_owned()),
+        &instrs,
+        &ExecutionState {
+            start_instr: Some(&instrs[0]),
+            ..ExecutionState::initial(&instrs)
+        },
+    );
+
+    assert!(is_wasm_target("wasm32-unknown-wasi"));
+    let ir = result.to_cstring().into_string().unwrap();
+    assert!(ir.contains("target triple = \"wasm32-unknown-wasi\""));
+    assert!(ir.contains("@__main_void = alias i32 (), i32 ()* @main"));
+}
+
+/// Compile `instrs` for the host with cells `cell_bits` wide,
+/// starting from the state compile time execution reaches, link them
+/// with cc and run them on `input`.
+fn run_compiled(instrs: &[AstNode], input: &[u8], cell_bits: u32, eof: EofBehaviour) -> Vec<u8> {
+    let (state, _) = execute_with_limits(instrs, &[], cell_bits, eof, Limits::default());
+    run_compiled_with_options(
+        instrs,
+        &state,
+        input,
+        &CodegenOptions {
+            cell_bits,
+            eof,
+            ..CodegenOptions::default()
+        },
+    )
+}
+
+/// Compile `instrs` for the host from `state`, link them with cc and
+/// run them on `input`.
+fn run_compiled_with_options(
+    instrs: &[AstNode],
+    state: &ExecutionState,
+    input: &[u8],
+    codegen_options: &CodegenOptions,
+) -> Vec<u8> {
+    let mut module = compile_to_module_with_options("foo", None, instrs, state, codegen_options);
+    verify_module(&module).unwrap();
+    let options = TargetOptions::default();
+    set_data_layout(&mut module, &options).unwrap();
+
+    let object_file = NamedTempFileOptions::new().suffix(".o").create().unwrap();
+    let object_path = object_file.path().to_str().unwrap();
+    write_object_file(&mut module, object_path, &options).unwrap();
+    let executable_path = format!("{}.out", object_path);
+    let status = Command::new("cc")
+        .args([object_path, "-o", &executable_path])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut child = Command::new(&executable_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // The program may exit without reading all of its input.
+    let _ = child.stdin.take().unwrap().write_all(input);
+    let output = child.wait_with_output().unwrap();
+    fs::remove_file(&executable_path).unwrap();
+    assert!(output.status.success());
+    output.stdout
+}
+
+#[test]
+fn compiled_programs_match_interpreter_for_each_cell_width_and_eof() {
+    init_llvm();
+    let programs = [
+        // Wrap below zero, and write the low byte.
+        "-.",
+        // 256 is zero only in 8 bit cells.
+        "++++++++++++++++[>++++++++++++++++<-]>[<+>[-]]<.",
+        "++++++++++++++++[>++++++++++++++++<-]>>+<[->-<]>.",
+        // Reads at and past EOF.
+        ",.,.,+.",
+        ">+<,[>-]>.",
+    ];
+    let inputs: [&[u8]; 3] = [b"", b"a", b"\xff\x00"];
+
+    for source in &programs {
+        let instrs = parse(source).unwrap();
+        for &cell_bits in &[8, 16, 32] {
+            for &eof in &[
+                EofBehaviour::Unchanged,
+                EofBehaviour::Zero,
+                EofBehaviour::MinusOne,
+            ] {
+                for input in &inputs {
+                    let expected =
+                        interpret_with_limits(&instrs, input, cell_bits, eof, Limits::default());
+                    assert_eq!(
+                        run_compiled(&instrs, input, cell_bits, eof),
+                        expected.bytes,
+                        "{} with {} bit cells, {:?} at EOF and input {:?}",
+                        source,
+                        cell_bits,
+                        eof,
+                        input
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn compiled_tape_grows_with_zeroed_cells() {
+    // Read input into successive cells, then print the cell after
+    // the last one read, which is on the grown part of the tape.
+    let instrs = parse(",[>,]>.").unwrap();
+    let mut messages = HashMap::new();
+    if let Loop { ref body, .. } = instrs[1] {
+        messages.insert(&body[0] as *const _, "Went past the end".to_owned());
+    }
+    messages.insert(&instrs[2] as *const _, "Went past the end".to_owned());
+
+    for &cell_bits in &[8, 32] {
+        let output = run_compiled_with_options(
+            &instrs,
+            &ExecutionState {
+                start_instr: Some(&instrs[0]),
+                ..ExecutionState::initial(&instrs)
+            },
+            &[b'a'; 100],
+            &CodegenOptions {
+                cell_bits,
+                eof: EofBehaviour::Zero,
+                ptr_checks: PtrChecks {
+                    messages: messages.clone(),
+                    growable_from: Some(1),
+                    grow_without_limit: true,
+                },
+                ..CodegenOptions::default()
+            },
+        );
+        assert_eq!(output, vec![0], "{} bit cells", cell_bits);
+    }
+}
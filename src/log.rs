@@ -0,0 +1,160 @@
+//! A machine-readable record of a compilation, written by
+//! --log-file. Each line is a JSON object describing one event.
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use bfc::diagnostics::{json_string, DiagnosticSink, Info};
+use bfc::peephole::PassStats;
+
+use crate::shell::CommandRun;
+
+/// The events of a compilation so far. Events are recorded through
+/// a shared reference, so diagnostics can be logged while the
+/// compiler is also logging phases.
+pub struct CompileLog {
+    events: RefCell<Vec<String>>,
+    start: Instant,
+}
+
+impl CompileLog {
+    pub fn new(path: &str, args: &[String]) -> Self {
+        let args: Vec<String> = args.iter().map(|arg| json_string(arg)).collect();
+        let log = CompileLog {
+            events: RefCell::new(vec![]),
+            start: Instant::now(),
+        };
+        log.record(format!(
+            "{{\"event\": \"start\", \"file\": {}, \"version\": {}, \"args\": [{}]}}",
+            json_string(path),
+            json_string(env!("CARGO_PKG_VERSION")),
+            args.join(", ")
+        ));
+        log
+    }
+
+    fn record(&self, event: String) {
+        self.events.borrow_mut().push(event);
+    }
+
+    /// Record that a phase of compilation, such as parsing, took
+    /// `time`.
+    pub fn phase(&self, name: &str, time: Duration) {
+        self.record(format!(
+            "{{\"event\": \"phase\", \"name\": {}, \"seconds\": {:.6}}}",
+            json_string(name),
+            time.as_secs_f64()
+        ));
+    }
+
+    pub fn passes(&self, stats: &[PassStats]) {
+        for pass in stats {
+            self.record(format!(
+                "{{\"event\": \"pass\", \"name\": {}, \"runs\": {}, \"removed\": {}, \
+                 \"seconds\": {:.6}}}",
+                json_string(&pass.name),
+                pass.runs,
+                pass.removed,
+                pass.time.as_secs_f64()
+            ));
+        }
+    }
+
+    pub fn commands(&self, commands: &[CommandRun]) {
+        for command in commands {
+            let args: Vec<String> = command.args.iter().map(|arg| json_string(arg)).collect();
+            self.record(format!(
+                "{{\"event\": \"command\", \"program\": {}, \"args\": [{}], \"success\": {}, \
+                 \"seconds\": {:.6}}}",
+                json_string(&command.program),
+                args.join(", "),
+                command.success,
+                command.time.as_secs_f64()
+            ));
+        }
+    }
+
+    pub fn diagnostic(&self, info: &Info) {
+        self.record(format!(
+            "{{\"event\": \"diagnostic\", \"diagnostic\": {}}}",
+            info.to_json()
+        ));
+    }
+
+    /// The whole log, ending with how the compilation finished.
+    pub fn finish(self, success: bool) -> String {
+        self.record(format!(
+            "{{\"event\": \"finish\", \"success\": {}, \"seconds\": {:.6}}}",
+            success,
+            self.start.elapsed().as_secs_f64()
+        ));
+        self.events
+            .into_inner()
+            .iter()
+            .map(|event| format!("{}\n", event))
+            .collect()
+    }
+}
+
+/// Logs diagnostics, then passes them on to another sink.
+pub struct LogSink<'a> {
+    pub inner: &'a mut dyn DiagnosticSink,
+    pub log: &'a CompileLog,
+}
+
+impl DiagnosticSink for LogSink<'_> {
+    fn emit(&mut self, info: Info) {
+        self.log.diagnostic(&info);
+        self.inner.emit(info);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bfc::diagnostics::{Code, CollectingSink};
+
+    #[test]
+    fn log_events_in_order() {
+        let log = CompileLog::new("foo.bf", &["bfc".to_owned(), "foo.bf".to_owned()]);
+        log.phase("parse", Duration::from_millis(5));
+        log.commands(&[CommandRun {
+            program: "gcc".to_owned(),
+            args: vec!["foo.o".to_owned()],
+            success: true,
+            time: Duration::from_secs(1),
+        }]);
+
+        let mut collected = CollectingSink::default();
+        let mut sink = LogSink {
+            inner: &mut collected,
+            log: &log,
+        };
+        sink.emit(Info::error(Code::FileError, "No such file"));
+        assert_eq!(collected.infos.len(), 1);
+
+        let text = log.finish(false);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(
+            lines[0],
+            format!(
+                "{{\"event\": \"start\", \"file\": \"foo.bf\", \"version\": \"{}\", \
+                 \"args\": [\"bfc\", \"foo.bf\"]}}",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(
+            lines[1],
+            "{\"event\": \"phase\", \"name\": \"parse\", \"seconds\": 0.005000}"
+        );
+        assert_eq!(
+            lines[2],
+            "{\"event\": \"command\", \"program\": \"gcc\", \"args\": [\"foo.o\"], \
+             \"success\": true, \"seconds\": 1.000000}"
+        );
+        assert!(lines[3]
+            .starts_with("{\"event\": \"diagnostic\", \"diagnostic\": {\"level\": \"error\""));
+        assert!(lines[4].starts_with("{\"event\": \"finish\", \"success\": false, \"seconds\": "));
+    }
+}
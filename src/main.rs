@@ -1,29 +1,21 @@
 #![warn(trivial_numeric_casts)]
 //! bfc is a highly optimising compiler for BF.
 
-#[macro_use]
-extern crate matches;
-
-use diagnostics::Info;
+use bfc::diagnostics::{Code, DedupSink, DiagnosticSink, ErrorFormat, Info};
+use bfc::execution::ExitStatus;
+use bfc::llvm;
+use bfc::llvm::RelocationModel;
+use bfc::metadata::BuildMetadata;
 use getopts::Options;
 use std::env;
+use std::fs;
+use std::io::IsTerminal;
 use std::path::Path;
 
-mod bfir;
-mod bounds;
-mod diagnostics;
-mod execution;
-mod llvm;
-mod peephole;
 mod shell;
 mod io;
-
-#[cfg(test)]
-mod llvm_tests;
-#[cfg(test)]
-mod peephole_tests;
-#[cfg(test)]
-mod soundness_tests;
+mod link;
+mod log;
 
 /// Convert "foo.bf" to "foo".
 fn executable_name(bf_path: &str) -> String {
@@ -38,30 +30,462 @@ fn executable_name(bf_path: &str) -> String {
     name_parts.join(".")
 }
 
+/// The name of the shared library built from `bf_path` for this
+/// target, following the platform's conventions.
+fn library_name(bf_path: &str, target_triple: &str) -> String {
+    let name = executable_name(bf_path);
+    if is_windows_target(target_triple) {
+        format!("{}.dll", name)
+    } else if target_triple.contains("-apple-") {
+        format!("lib{}.dylib", name)
+    } else {
+        format!("lib{}.so", name)
+    }
+}
+
+/// A C header declaring the `bf_run` function that shared libraries
+/// export.
+fn library_header(bf_path: &str) -> String {
+    let guard = executable_name(bf_path)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!(
+        "#ifndef BF_{guard}_H
+#define BF_{guard}_H
+
+#include <stddef.h>
+
+#ifdef __cplusplus
+extern \"C\" {{
+#endif
+
+/* Run {name} with `input` as its stdin. On return, `*output` points
+ * to everything it wrote, which the caller must free(), and
+ * `*out_len` is its length. Returns the program's exit status.
+ *
+ * Each thread may run the program once at a time. */
+int bf_run(const char *input, size_t len, char **output, size_t *out_len);
+
+#ifdef __cplusplus
+}}
+#endif
+
+#endif
+",
+        guard = guard,
+        name = bf_path,
+    )
+}
+
 fn print_usage(bin_name: &str, opts: Options) {
-    let brief = format!("Usage: {} SOURCE_FILE [options]", bin_name);
+    let brief = format!(
+        "Usage: {} SOURCE_FILE [options]\n       {} inspect BINARY",
+        bin_name, bin_name
+    );
     print!("{}", opts.usage(&brief));
 }
 
-fn link_object_file(
-    object_file_path: &str,
-    executable_path: &str,
+/// How to link the object file into an executable.
+#[derive(Debug, Default)]
+struct LinkOptions {
     target_triple: Option<String>,
+    /// Link with or without PIE to match, or None for the platform
+    /// default.
+    relocation_model: Option<RelocationModel>,
+    /// The object file is LLVM bitcode, so optimise while linking.
+    lto: bool,
+    /// The link driver command, e.g. "gcc" or "zig cc", "internal"
+    /// for our own linker, "external" for the first of
+    /// `default_linkers` we can find, or None to choose.
+    linker: Option<String>,
+    /// Link without any shared libraries.
+    static_link: bool,
+    /// Link without libc or its startup files.
+    freestanding: bool,
+    /// Link a shared library rather than an executable.
+    shared: bool,
+    /// Link these sanitizer runtimes, e.g. "address,undefined".
+    sanitizers: Option<String>,
+}
+
+/// The link drivers to try, in order.
+fn default_linkers(target_triple: Option<&str>) -> Vec<String> {
+    match target_triple {
+        // clang can link for any target, otherwise we need a cross
+        // toolchain.
+        // Linking WebAssembly needs clang and a WASI sysroot.
+        Some(target_triple) if target_triple.starts_with("wasm") => vec!["clang".to_owned()],
+        // MSVC's own linker can't take LLVM bitcode, so try the LLVM
+        // drivers first.
+        target_triple if targets_msvc(target_triple) => vec![
+            "clang-cl".to_owned(),
+            "lld-link".to_owned(),
+            "link".to_owned(),
+        ],
+        // musl toolchains are often gcc wrappers, so prefer them.
+        Some(target_triple) if target_triple.contains("musl") => {
+            let mut linkers = vec![format!("{}-gcc", target_triple)];
+            if target_triple.starts_with(env::consts::ARCH) {
+                linkers.push("musl-gcc".to_owned());
+            }
+            linkers.push("clang".to_owned());
+            linkers
+        }
+        Some(target_triple) => vec!["clang".to_owned(), format!("{}-gcc", target_triple)],
+        None => vec!["clang".to_owned(), "cc".to_owned(), "gcc".to_owned()],
+    }
+}
+
+/// Are we linking with the MSVC toolchain? With no triple, that's
+/// whether bfc itself was built with it.
+fn targets_msvc(target_triple: Option<&str>) -> bool {
+    match target_triple {
+        Some(target_triple) => target_triple.ends_with("-msvc"),
+        None => cfg!(target_env = "msvc"),
+    }
+}
+
+/// Does this target produce Windows executables, which need a .exe
+/// suffix?
+fn is_windows_target(target_triple: &str) -> bool {
+    target_triple.contains("-windows")
+}
+
+/// The name of the program in a linker command.
+fn linker_program_name(linker: &str) -> &str {
+    let program = linker.split_whitespace().next().unwrap_or("");
+    Path::new(program)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("")
+}
+
+/// Does this link driver understand clang's `-target` and LLVM
+/// bitcode?
+fn is_clang_like(linker: &str) -> bool {
+    let name = linker_program_name(linker);
+    name.starts_with("clang") || name == "zig"
+}
+
+/// Can this linker take LLVM bitcode for LTO?
+fn understands_bitcode(linker: &str) -> bool {
+    is_clang_like(linker) || linker_program_name(linker).starts_with("lld-link")
+}
+
+/// Is `program` an executable in one of the directories on $PATH?
+fn on_path(program: &str) -> bool {
+    let file_name = format!("{}{}", program, env::consts::EXE_SUFFIX);
+    match env::var_os("PATH") {
+        Some(paths) => env::split_paths(&paths).any(|dir| dir.join(&file_name).is_file()),
+        None => false,
+    }
+}
+
+/// Can our own linker link this? It only makes static x86-64 Linux
+/// executables without libc.
+fn check_internal_link(options: &LinkOptions) -> Result<(), Info> {
+    let x86_64_linux = match options.target_triple {
+        Some(ref target_triple) => {
+            target_triple.starts_with("x86_64") && target_triple.contains("-linux")
+        }
+        None => cfg!(all(target_arch = "x86_64", target_os = "linux")),
+    };
+    if !x86_64_linux {
+        return Err(Info::error(
+            Code::UnsupportedTarget,
+            "--linker=internal only supports x86-64 Linux".to_owned(),
+        ));
+    }
+    // Linking against libc needs its startup files and the right
+    // libc for the target, which only the system's link driver knows
+    // how to find, so we don't try.
+    let unsupported = if !options.freestanding {
+        Some("programs that use libc, so pass --freestanding")
+    } else if options.shared {
+        Some("--library")
+    } else if options.lto {
+        Some("--lto")
+    } else if options.sanitizers.is_some() {
+        Some("--sanitize")
+    } else if options.relocation_model == Some(RelocationModel::Pic) {
+        Some("--relocation-model=pic")
+    } else {
+        None
+    };
+    match unsupported {
+        Some(option) => Err(Info::error(
+            Code::InvalidOption,
+            format!("--linker=internal cannot link {}", option),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Should we link with our own linker? We do when asked, or when
+/// clang is missing and we can.
+fn links_internally(options: &LinkOptions) -> Result<bool, Info> {
+    match options.linker.as_deref() {
+        Some("internal") => check_internal_link(options).map(|()| true),
+        Some(_) => Ok(false),
+        None => Ok(check_internal_link(options).is_ok() && !on_path("clang")),
+    }
+}
+
+fn find_linker(options: &LinkOptions) -> Result<String, Info> {
+    match options.linker.as_deref() {
+        Some("external") | None => {}
+        Some(linker) => return Ok(linker.to_owned()),
+    }
+
+    let mut candidates = default_linkers(options.target_triple.as_deref());
+    if options.lto {
+        candidates.retain(|linker| understands_bitcode(linker));
+    }
+    match candidates.iter().find(|linker| on_path(linker)) {
+        Some(linker) => Ok(linker.clone()),
+        None => {
+            let mut message = format!(
+                "Could not find a linker on $PATH (tried {}). Use --linker to choose one.",
+                candidates.join(", ")
+            );
+            if !options.freestanding {
+                message.push_str(" bfc can only link --freestanding programs itself.");
+            }
+            Err(Info::error(Code::ToolFailure, message))
+        }
+    }
+}
+
+fn link_object_files(
+    object_file_paths: &[String],
+    executable_path: &str,
+    options: &LinkOptions,
 ) -> Result<(), Info> {
-    // Link the object file.
-    let clang_args = if let Some(ref target_triple) = target_triple {
-        vec![
-            object_file_path,
-            "-target",
-            &target_triple,
-            "-o",
-            &executable_path[..],
-        ]
+    if links_internally(options)? {
+        return link::link_files(object_file_paths, executable_path);
+    }
+
+    let linker = find_linker(options)?;
+    let mut linker_words = linker.split_whitespace();
+    let program = match linker_words.next() {
+        Some(program) => program,
+        None => {
+            return Err(Info::error(
+                Code::InvalidOption,
+                "--linker cannot be empty".to_owned(),
+            ))
+        }
+    };
+
+    if targets_msvc(options.target_triple.as_deref()) {
+        let link_args = msvc_link_args(&linker, object_file_paths, executable_path, options)?;
+        let link_args: Vec<&str> = linker_words
+            .chain(link_args.iter().map(|arg| arg.as_str()))
+            .collect();
+        return shell::run_shell_command(program, &link_args[..]);
+    }
+
+    // Link the object files.
+    let mut link_args: Vec<&str> = linker_words.collect();
+    link_args.extend(object_file_paths.iter().map(|path| path.as_str()));
+    link_args.extend(&["-o", executable_path]);
+    if let Some(ref target_triple) = options.target_triple {
+        // Other drivers are cross toolchains that only build for
+        // their own target.
+        if is_clang_like(&linker) {
+            link_args.push("-target");
+            link_args.push(target_triple);
+        }
+    }
+    match (options.static_link, options.relocation_model) {
+        _ if options.shared => link_args.push("-shared"),
+        (true, Some(RelocationModel::Pic)) => link_args.push("-static-pie"),
+        (true, _) => link_args.push("-static"),
+        (false, Some(RelocationModel::Static)) => link_args.push("-no-pie"),
+        (false, Some(RelocationModel::Pic)) => link_args.push("-pie"),
+        (false, None) => {}
+    }
+    if options.freestanding {
+        link_args.push("-nostdlib");
+    }
+    if options.lto {
+        if !is_clang_like(&linker) {
+            return Err(Info::error(
+                Code::InvalidOption,
+                format!(
+                    "--lto needs a linker that understands LLVM bitcode, such as clang, not '{}'",
+                    linker
+                ),
+            ));
+        }
+        link_args.push("-flto");
+    }
+    let sanitize_arg = options
+        .sanitizers
+        .as_ref()
+        .map(|sanitizers| format!("-fsanitize={}", sanitizers));
+    if let Some(ref sanitize_arg) = sanitize_arg {
+        link_args.push(sanitize_arg);
+    }
+
+    shell::run_shell_command(program, &link_args[..])
+}
+
+/// The arguments for linking with clang-cl, or with link.exe or
+/// lld-link directly. These take MSVC style flags rather than gcc
+/// style ones.
+fn msvc_link_args(
+    linker: &str,
+    object_file_paths: &[String],
+    executable_path: &str,
+    options: &LinkOptions,
+) -> Result<Vec<String>, Info> {
+    let mut link_args = vec!["/nologo".to_owned()];
+    link_args.extend(object_file_paths.iter().cloned());
+
+    if is_clang_like(linker) {
+        link_args.push(format!("/Fe{}", executable_path));
+        if options.shared {
+            link_args.push("/LD".to_owned());
+        }
+        if let Some(ref target_triple) = options.target_triple {
+            link_args.push(format!("--target={}", target_triple));
+        }
+        // Link the C runtime statically or as a DLL.
+        link_args.push(if options.static_link { "/MT" } else { "/MD" }.to_owned());
+        if options.lto {
+            link_args.push("-flto".to_owned());
+            link_args.push("-fuse-ld=lld".to_owned());
+        }
+        if let Some(ref sanitizers) = options.sanitizers {
+            link_args.push(format!("-fsanitize={}", sanitizers));
+        }
+    } else {
+        if options.sanitizers.is_some() {
+            return Err(Info::error(
+                Code::InvalidOption,
+                format!(
+                    "--sanitize needs a link driver such as clang-cl, not '{}'",
+                    linker
+                ),
+            ));
+        }
+        if options.lto && !understands_bitcode(linker) {
+            return Err(Info::error(
+                Code::InvalidOption,
+                format!(
+                    "--lto needs a linker that understands LLVM bitcode, such as lld-link, not '{}'",
+                    linker
+                ),
+            ));
+        }
+        link_args.push(format!("/OUT:{}", executable_path));
+        if options.shared {
+            link_args.push("/DLL".to_owned());
+        } else {
+            link_args.push("/SUBSYSTEM:CONSOLE".to_owned());
+        }
+        let crt = if options.static_link {
+            "libcmt"
+        } else {
+            "msvcrt"
+        };
+        link_args.push(format!("/DEFAULTLIB:{}", crt));
+        // We call write(), which the CRT calls _write().
+        link_args.push("/DEFAULTLIB:oldnames".to_owned());
+    }
+
+    Ok(link_args)
+}
+
+/// Convert a target triple to the same target with musl libc,
+/// e.g. "x86_64-unknown-linux-gnu" to "x86_64-unknown-linux-musl".
+fn musl_triple(target_triple: &str) -> String {
+    let mut parts: Vec<_> = target_triple
+        .split('-')
+        .map(|part| part.to_owned())
+        .collect();
+    match parts.last_mut() {
+        // Keep the ABI, so gnueabihf becomes musleabihf.
+        Some(env) if env.starts_with("gnu") || env.starts_with("musl") => {
+            let abi = env.trim_start_matches("gnu").trim_start_matches("musl");
+            *env = format!("musl{}", abi);
+        }
+        _ => parts.push("musl".to_owned()),
+    }
+    parts.join("-")
+}
+
+/// Does this ELF file ask for a dynamic loader? Files that aren't
+/// ELF never do.
+fn elf_has_interpreter(bytes: &[u8]) -> bool {
+    const PT_INTERP: u64 = 3;
+
+    if !bytes.starts_with(b"\x7fELF") || bytes.len() < 6 {
+        return false;
+    }
+    let is_64_bit = bytes[4] == 2;
+    let is_little_endian = bytes[5] == 1;
+
+    let read = |offset: u64, size: u64| -> Option<u64> {
+        let start = offset as usize;
+        let field = bytes.get(start..start + size as usize)?;
+        let value = if is_little_endian {
+            field
+                .iter()
+                .rev()
+                .fold(0, |acc, &byte| acc << 8 | u64::from(byte))
+        } else {
+            field
+                .iter()
+                .fold(0, |acc, &byte| acc << 8 | u64::from(byte))
+        };
+        Some(value)
+    };
+
+    // Where the program headers are, their size and their number.
+    let header_fields = if is_64_bit {
+        (read(0x20, 8), read(0x36, 2), read(0x38, 2))
     } else {
-        vec![object_file_path, "-o", &executable_path[..]]
+        (read(0x1c, 4), read(0x2a, 2), read(0x2c, 2))
     };
+    match header_fields {
+        (Some(offset), Some(size), Some(count)) => {
+            (0..count).any(|i| read(offset + i * size, 4) == Some(PT_INTERP))
+        }
+        _ => false,
+    }
+}
 
-    shell::run_shell_command("clang", &clang_args[..])
+/// Check that a statically linked executable really has no dynamic
+/// loader.
+fn check_static_executable(executable_path: &str) -> Result<(), Info> {
+    let bytes = match fs::read(executable_path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            return Err(Info::error(
+                Code::FileError,
+                format!("Could not read {}: {}", executable_path, e),
+            ))
+        }
+    };
+    if elf_has_interpreter(&bytes) {
+        return Err(Info::error(
+            Code::ToolFailure,
+            format!(
+                "{} still needs a dynamic loader, so the linker ignored -static",
+                executable_path
+            ),
+        ));
+    }
+    Ok(())
 }
 
 fn strip_executable(executable_path: &str) -> Result<(), Info> {
@@ -69,6 +493,40 @@ fn strip_executable(executable_path: &str) -> Result<(), Info> {
     shell::run_shell_command("strip", &strip_args[..])
 }
 
+/// Describe how a binary was built, for `bfc inspect`.
+fn format_metadata(metadata: &BuildMetadata) -> String {
+    format!(
+        "bfc version: {}\ntarget: {}\nsource hash: {}\noptions: {}\n",
+        metadata.version,
+        metadata.target,
+        metadata.source_hash,
+        metadata.options.join(" ")
+    )
+}
+
+/// Print the build metadata embedded in the binary at `path`.
+fn inspect(path: &str) -> Result<(), Info> {
+    let binary = match fs::read(path) {
+        Ok(binary) => binary,
+        Err(e) => {
+            return Err(Info::error(
+                Code::FileError,
+                format!("Could not read {}: {}", path, e),
+            ))
+        }
+    };
+    match BuildMetadata::find(&binary) {
+        Some(metadata) => {
+            print!("{}", format_metadata(&metadata));
+            Ok(())
+        }
+        None => Err(Info::error(
+            Code::NoMetadata,
+            format!("{} has no bfc build metadata", path),
+        )),
+    }
+}
+
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn main() {
@@ -79,20 +537,328 @@ fn main() {
     opts.optflag("h", "help", "print usage");
     opts.optflag("v", "version", "print bfc version");
     opts.optflag("", "dump-llvm", "print LLVM IR generated");
+    opts.optflag(
+        "",
+        "dump-llvm-opt",
+        "print LLVM IR after LLVM has optimised it",
+    );
+    opts.optflag(
+        "",
+        "dump-llvm-diff",
+        "print a diff of the LLVM IR before and after LLVM optimises it",
+    );
     opts.optflag("", "dump-ir", "print BF IR generated");
+    opts.optflag(
+        "",
+        "dump-bounds",
+        "print the highest cell the program can reach and the tape size allocated",
+    );
+    opts.optflag(
+        "",
+        "show-precomputed-output",
+        "print the output found at compile time, and why compile time execution stopped",
+    );
+    opts.optflagopt(
+        "",
+        "dump-exec-state",
+        "print the cells, output size, steps and runtime start point after compile time \
+         execution",
+        "text|json",
+    );
+    opts.optflag(
+        "",
+        "hot-loop-report",
+        "print the loops that ran most often during compile time execution",
+    );
+
+    opts.optflag(
+        "",
+        "speculation-summary",
+        "after compiling, print how much of the program compile time execution precomputed",
+    );
+    opts.optflag(
+        "",
+        "run",
+        "interpret the program with stdin as input, instead of compiling it",
+    );
+    opts.optflag(
+        "",
+        "jit",
+        "with --run, compile the program in memory with LLVM's JIT rather than interpreting it",
+    );
+    opts.optopt(
+        "",
+        "run-max-steps",
+        "stop --run after this many steps",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "run-timeout",
+        "stop --run after this many seconds",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "run-max-output",
+        "stop --run after this much output",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "run-max-cells",
+        "give --run at most this many cells",
+        "N",
+    );
 
-    opts.optopt("O", "opt", "optimization level (0 to 2)", "LEVEL");
+    opts.optopt(
+        "O",
+        "opt",
+        "optimization level (0 to 3, or s to optimise for size)",
+        "LEVEL",
+    );
+    opts.optopt(
+        "",
+        "backend",
+        "compile with LLVM, or write naive x86-64 assembly for fast builds (default: llvm)",
+        "llvm|fast",
+    );
     opts.optopt("", "llvm-opt", "LLVM optimization level (0 to 3)", "LEVEL");
+    opts.optopt(
+        "",
+        "llvm-passes",
+        "run these LLVM passes instead of --llvm-opt (e.g. instcombine,gvn)",
+        "PIPELINE",
+    );
     opts.optopt(
         "",
         "passes",
         "limit bfc optimisations to those specified",
         "PASS-SPECIFICATION",
     );
+    opts.optopt(
+        "",
+        "max-compile-steps",
+        "maximum steps of compile time execution (0 to disable)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "speculate-timeout",
+        "stop compile time execution after this many seconds",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "speculate-max-output",
+        "stop compile time execution after this much output (default: 16 MiB)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "const-input",
+        "input to read before stdin, known at compile time",
+        "TEXT",
+    );
+    opts.optopt(
+        "",
+        "trace-speculation",
+        "write a JSON trace of compile time execution to FILE",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "trace-every",
+        "only trace every Nth instruction executed (default: 1)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "speculation-cache",
+        "reuse the results of compile time execution stored in DIR",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "opt-fuel",
+        "stop optimising after N rewrites, for finding optimisation bugs",
+        "N",
+    );
+    opts.optmulti(
+        "",
+        "load-pass",
+        "load an optimisation pass from a shared library",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "bounds-iteration-cap",
+        "when a loop keeps moving right, size the tape for N iterations and grow it beyond that (default: 100)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "stack-tape-limit",
+        "allocate the tape on the stack if it has at most N cells (default: 2000)",
+        "N",
+    );
+    opts.optopt(
+        "j",
+        "jobs",
+        "optimise and compile split programs on N threads (default: 1)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "function-size-limit",
+        "split programs into LLVM functions of about N instructions (default: 10000, 0 to never split)",
+        "N",
+    );
+    opts.optflag(
+        "g",
+        "",
+        "include debug info, so debuggers and profilers show BF source lines",
+    );
+    opts.optflag(
+        "",
+        "perf-friendly",
+        "keep frame pointers and unwind tables, so profilers can walk the stack",
+    );
+    opts.optflag(
+        "",
+        "debuggable",
+        "build for debuggers and sanitizers: implies -g and --perf-friendly, and skips speculative execution",
+    );
+    opts.optopt(
+        "",
+        "sanitize",
+        "with --debuggable, link these sanitizer runtimes, e.g. address,undefined",
+        "LIST",
+    );
+    opts.optflag(
+        "",
+        "ptr-check",
+        "abort at runtime if the pointer leaves the tape, where bfc can't prove it doesn't",
+    );
+    opts.optflag(
+        "",
+        "debug-runtime",
+        "on Ctrl-C or a segfault, print the pointer and nearby cells before exiting",
+    );
+    opts.optflag(
+        "",
+        "profile",
+        "count how often each loop runs, and write the counts to a .profile file on exit",
+    );
+    opts.optflag(
+        "",
+        "coverage",
+        "record which instructions run, and append an lcov report to a .lcov file on exit",
+    );
+    opts.optflag(
+        "",
+        "strict-bounds",
+        "make pointer movements that always leave the tape an error",
+    );
+    opts.optmulti(
+        "W",
+        "",
+        "enable or disable a category of warning",
+        "[no-]CATEGORY",
+    );
+    opts.optopt(
+        "",
+        "error-format",
+        "print warnings and errors as text, one JSON object per line, or a SARIF log",
+        "human|json|sarif",
+    );
+    opts.optopt(
+        "",
+        "log-file",
+        "write a JSON log of the compilation's phases, passes, commands and diagnostics",
+        "PATH",
+    );
+    opts.optflag(
+        "",
+        "no-dedup",
+        "print every warning, even when the same one repeats many times",
+    );
+    opts.optopt(
+        "",
+        "explain",
+        "describe an error or warning code in detail, e.g. BF0001",
+        "CODE",
+    );
+    opts.optflag(
+        "",
+        "static",
+        "link statically, so the binary needs no shared libraries",
+    );
+    opts.optflag(
+        "",
+        "freestanding",
+        "make system calls directly, so the binary needs no libc (Linux only)",
+    );
+    opts.optopt(
+        "",
+        "tape",
+        "grow the tape as far as the program goes, or catch overruns with guard pages",
+        "fixed|grow|guard",
+    );
+    opts.optflag(
+        "",
+        "runtime-tape-size",
+        "let BF_TAPE_SIZE set the most cells a growable tape may hold at startup",
+    );
+    opts.optopt(
+        "",
+        "cell-size",
+        "the width of each cell, in bits (default 8)",
+        "8|16|32",
+    );
+    opts.optopt(
+        "",
+        "eof",
+        "what , stores once stdin is exhausted (default -1)",
+        "unchanged|0|-1",
+    );
+    opts.optflag(
+        "",
+        "flush-every-write",
+        "write output as soon as it's produced, rather than buffering it",
+    );
+    opts.optflag(
+        "",
+        "shared",
+        "build a shared library exporting bf_run, and a C header declaring it",
+    );
+    opts.optflag(
+        "",
+        "bare-metal",
+        "assume no OS and write an object file calling bf_putchar and bf_getchar",
+    );
+    opts.optopt(
+        "",
+        "tape-address",
+        "with --bare-metal, put the tape at this address",
+        "ADDR",
+    );
+    opts.optflag(
+        "",
+        "musl",
+        "target musl rather than the default libc, implying --static",
+    );
+    opts.optopt(
+        "",
+        "linker",
+        "link with this command, e.g. gcc or \"zig cc\", or \"internal\" or \"external\" \
+         (default: clang, cc or gcc, or internal for --freestanding without clang)",
+        "COMMAND",
+    );
     opts.optopt(
         "",
         "strip",
-        "strip symbols from the binary (default: yes)",
+        "strip symbols from the binary (default: yes, or no with -g or --debuggable)",
         "yes|no",
     );
 
@@ -105,6 +871,35 @@ fn main() {
         &format!("LLVM target triple (default: {})", default_triple),
         "TARGET",
     );
+    opts.optopt(
+        "",
+        "relocation-model",
+        "static or pic, also linking without or with PIE to match (default: pic)",
+        "MODEL",
+    );
+    opts.optflag(
+        "",
+        "lto",
+        "emit LLVM bitcode and optimise it again while linking",
+    );
+    opts.optopt(
+        "",
+        "code-model",
+        "small, medium or large; large allows an initial tape over 2 GiB",
+        "MODEL",
+    );
+    opts.optopt(
+        "",
+        "mcpu",
+        "target CPU, or native for this machine's (default: generic)",
+        "CPU",
+    );
+    opts.optopt(
+        "",
+        "mattr",
+        "target CPU features to enable or disable (e.g. +avx2,-sse4.1)",
+        "FEATURES",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -124,27 +919,125 @@ fn main() {
         return;
     }
 
+    let error_format = match io::parse_error_format(&matches) {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("{}", e.render(stderr_is_styled()));
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(name) = matches.opt_str("explain") {
+        match Code::from_name(&name) {
+            Some(code) => print!("{}: {}", code.name(), code.explanation()),
+            None => {
+                let message = format!("Unknown code '{}' (expected e.g. BF0001)", name);
+                report(
+                    vec![Info::error(Code::InvalidOption, message)],
+                    error_format,
+                );
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.free.len() == 2 && matches.free[0] == "inspect" {
+        if let Err(e) = inspect(&matches.free[1]) {
+            report(vec![e], error_format);
+            std::process::exit(2);
+        }
+        return;
+    }
+
     if matches.free.len() != 1 {
         print_usage(&args[0], opts);
         std::process::exit(1);
     }
 
-    match io::compile_file(&matches) {
-        Ok(_) => {}
+    // The JIT compiles the program as usual, then runs it rather
+    // than linking it.
+    if matches.opt_present("run") && !matches.opt_present("jit") {
+        run(&matches, error_format);
+        return;
+    }
+
+    let mut sink = error_format.sink(std::io::stderr(), stderr_is_styled());
+    // Tools reading JSON or SARIF can group warnings themselves.
+    if error_format == ErrorFormat::Human && !matches.opt_present("no-dedup") {
+        sink = Box::new(DedupSink::new(sink));
+    }
+    let succeeded = io::compile_file(&matches, &mut sink);
+    sink.finish();
+    if !succeeded {
+        std::process::exit(2);
+    }
+}
+
+/// Interpret the program, exiting with 1 if it fails at runtime and 3
+/// if it exceeds a resource limit.
+fn run(matches: &getopts::Matches, error_format: ErrorFormat) {
+    match io::run_file(matches) {
+        Ok(ExitStatus::Finished) => report(vec![], error_format),
+        Ok(ExitStatus::RuntimeError(warning)) => {
+            report(
+                vec![Info::error(Code::RuntimeError, warning.message)],
+                error_format,
+            );
+            std::process::exit(1);
+        }
+        Ok(ExitStatus::LimitExceeded(resource)) => {
+            let message = format!("Resource limit exceeded: {}", resource.name());
+            report(
+                vec![Info::error(Code::ResourceLimit, message)],
+                error_format,
+            );
+            std::process::exit(3);
+        }
         Err(errors) => {
-            for error in errors {
-                eprintln!("{}", error);
-            }
+            report(errors, error_format);
             std::process::exit(2);
         }
     }
 }
 
+/// Whether diagnostics on stderr should be coloured. We don't colour
+/// output that's redirected to a file or pipe, or when the user has
+/// set NO_COLOR (see https://no-color.org).
+fn stderr_is_styled() -> bool {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    !no_color && std::io::stderr().is_terminal()
+}
+
+/// Print warnings and errors to stderr.
+fn report(infos: Vec<Info>, error_format: ErrorFormat) {
+    let mut sink = error_format.sink(std::io::stderr(), stderr_is_styled());
+    for info in infos {
+        sink.emit(info);
+    }
+    sink.finish();
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use pretty_assertions::assert_eq;
 
+    #[test]
+    fn format_build_metadata() {
+        let metadata = BuildMetadata {
+            version: "1.8.0".to_owned(),
+            target: "x86_64-pc-linux-gnu".to_owned(),
+            source_hash: "cbf29ce484222325".to_owned(),
+            options: vec!["-O3".to_owned(), "--cell-size=16".to_owned()],
+        };
+        assert_eq!(
+            format_metadata(&metadata),
+            "bfc version: 1.8.0\ntarget: x86_64-pc-linux-gnu\n\
+             source hash: cbf29ce484222325\noptions: -O3 --cell-size=16\n"
+        );
+    }
+
     #[test]
     fn executable_name_bf() {
         assert_eq!(executable_name("foo.bf"), "foo");
@@ -159,4 +1052,226 @@ mod tests {
     fn executable_name_relative_path() {
         assert_eq!(executable_name("bar/baz.bf"), "baz");
     }
+
+    #[test]
+    fn library_names() {
+        assert_eq!(
+            library_name("bar/baz.bf", "x86_64-unknown-linux-gnu"),
+            "libbaz.so"
+        );
+        assert_eq!(
+            library_name("baz.bf", "aarch64-apple-darwin"),
+            "libbaz.dylib"
+        );
+        assert_eq!(library_name("baz.bf", "x86_64-pc-windows-msvc"), "baz.dll");
+    }
+
+    #[test]
+    fn library_header_guard() {
+        let header = library_header("hello-world.bf");
+        assert!(header.starts_with("#ifndef BF_HELLO_WORLD_H\n"));
+        assert!(header.contains(
+            "int bf_run(const char *input, size_t len, char **output, size_t *out_len);"
+        ));
+    }
+
+    #[test]
+    fn default_linkers_cross_toolchain() {
+        assert_eq!(
+            default_linkers(Some("aarch64-linux-gnu")),
+            vec!["clang", "aarch64-linux-gnu-gcc"]
+        );
+        assert_eq!(default_linkers(None), vec!["clang", "cc", "gcc"]);
+        assert_eq!(default_linkers(Some("wasm32-wasi")), vec!["clang"]);
+    }
+
+    #[test]
+    fn clang_like_linkers() {
+        assert!(is_clang_like("clang"));
+        assert!(is_clang_like("/usr/bin/clang-15"));
+        assert!(is_clang_like("zig cc"));
+        assert!(!is_clang_like("gcc"));
+        assert!(!is_clang_like("aarch64-linux-gnu-gcc"));
+    }
+
+    #[test]
+    fn default_linkers_musl() {
+        assert_eq!(
+            default_linkers(Some("riscv64-linux-musl")),
+            vec!["riscv64-linux-musl-gcc", "clang"]
+        );
+    }
+
+    #[test]
+    fn default_linkers_msvc() {
+        assert_eq!(
+            default_linkers(Some("x86_64-pc-windows-msvc")),
+            vec!["clang-cl", "lld-link", "link"]
+        );
+        // MinGW uses gcc style drivers.
+        assert_eq!(
+            default_linkers(Some("x86_64-pc-windows-gnu")),
+            vec!["clang", "x86_64-pc-windows-gnu-gcc"]
+        );
+    }
+
+    #[test]
+    fn windows_targets() {
+        assert!(is_windows_target("x86_64-pc-windows-msvc"));
+        assert!(is_windows_target("x86_64-pc-windows-gnu"));
+        assert!(!is_windows_target("x86_64-unknown-linux-gnu"));
+    }
+
+    #[test]
+    fn internal_link_freestanding_only() {
+        let options = LinkOptions {
+            target_triple: Some("x86_64-unknown-linux-gnu".to_owned()),
+            linker: Some("internal".to_owned()),
+            ..LinkOptions::default()
+        };
+        assert_eq!(
+            links_internally(&options).unwrap_err().message,
+            "--linker=internal cannot link programs that use libc, so pass --freestanding"
+        );
+
+        let options = LinkOptions {
+            freestanding: true,
+            ..options
+        };
+        assert!(links_internally(&options).unwrap());
+    }
+
+    #[test]
+    fn internal_link_x86_64_linux_only() {
+        let options = LinkOptions {
+            target_triple: Some("aarch64-unknown-linux-gnu".to_owned()),
+            linker: Some("internal".to_owned()),
+            freestanding: true,
+            ..LinkOptions::default()
+        };
+        assert!(links_internally(&options).is_err());
+    }
+
+    #[test]
+    fn external_link_never_internal() {
+        let options = LinkOptions {
+            target_triple: Some("x86_64-unknown-linux-gnu".to_owned()),
+            linker: Some("external".to_owned()),
+            freestanding: true,
+            ..LinkOptions::default()
+        };
+        assert!(!links_internally(&options).unwrap());
+    }
+
+    #[test]
+    fn msvc_link_args_link_exe() {
+        let options = LinkOptions {
+            target_triple: Some("x86_64-pc-windows-msvc".to_owned()),
+            static_link: true,
+            ..LinkOptions::default()
+        };
+        assert_eq!(
+            msvc_link_args("link", &["foo.obj".to_owned()], "foo.exe", &options).unwrap(),
+            vec![
+                "/nologo",
+                "foo.obj",
+                "/OUT:foo.exe",
+                "/SUBSYSTEM:CONSOLE",
+                "/DEFAULTLIB:libcmt",
+                "/DEFAULTLIB:oldnames"
+            ]
+        );
+    }
+
+    #[test]
+    fn msvc_link_args_clang_cl() {
+        let options = LinkOptions {
+            target_triple: Some("x86_64-pc-windows-msvc".to_owned()),
+            ..LinkOptions::default()
+        };
+        assert_eq!(
+            msvc_link_args("clang-cl", &["foo.obj".to_owned()], "foo.exe", &options).unwrap(),
+            vec![
+                "/nologo",
+                "foo.obj",
+                "/Fefoo.exe",
+                "--target=x86_64-pc-windows-msvc",
+                "/MD"
+            ]
+        );
+    }
+
+    #[test]
+    fn msvc_lto_needs_bitcode_linker() {
+        let options = LinkOptions {
+            target_triple: Some("x86_64-pc-windows-msvc".to_owned()),
+            lto: true,
+            ..LinkOptions::default()
+        };
+        assert!(msvc_link_args("link", &["foo.obj".to_owned()], "foo.exe", &options).is_err());
+        assert!(msvc_link_args("lld-link", &["foo.obj".to_owned()], "foo.exe", &options).is_ok());
+    }
+
+    #[test]
+    fn msvc_sanitize_needs_clang_cl() {
+        let options = LinkOptions {
+            target_triple: Some("x86_64-pc-windows-msvc".to_owned()),
+            sanitizers: Some("address".to_owned()),
+            ..LinkOptions::default()
+        };
+        assert!(msvc_link_args("link", &["foo.obj".to_owned()], "foo.exe", &options).is_err());
+        assert_eq!(
+            msvc_link_args("clang-cl", &["foo.obj".to_owned()], "foo.exe", &options).unwrap(),
+            vec![
+                "/nologo",
+                "foo.obj",
+                "/Fefoo.exe",
+                "--target=x86_64-pc-windows-msvc",
+                "/MD",
+                "-fsanitize=address"
+            ]
+        );
+    }
+
+    #[test]
+    fn musl_triples() {
+        assert_eq!(
+            musl_triple("x86_64-unknown-linux-gnu"),
+            "x86_64-unknown-linux-musl"
+        );
+        assert_eq!(
+            musl_triple("armv7-unknown-linux-gnueabihf"),
+            "armv7-unknown-linux-musleabihf"
+        );
+        assert_eq!(musl_triple("aarch64-linux-musl"), "aarch64-linux-musl");
+        assert_eq!(musl_triple("x86_64-pc-linux"), "x86_64-pc-linux-musl");
+    }
+
+    /// A 64-bit little-endian ELF header followed by one program
+    /// header of type `p_type`.
+    fn elf_with_program_header(p_type: u8) -> Vec<u8> {
+        let mut bytes = vec![0; 0x40 + 0x38];
+        bytes[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        bytes[0x20] = 0x40;
+        bytes[0x36] = 0x38;
+        bytes[0x38] = 1;
+        bytes[0x40] = p_type;
+        bytes
+    }
+
+    #[test]
+    fn elf_interpreter_detected() {
+        assert!(elf_has_interpreter(&elf_with_program_header(3)));
+        assert!(!elf_has_interpreter(&elf_with_program_header(1)));
+        assert!(!elf_has_interpreter(b"MZ not an ELF file"));
+    }
+
+    #[test]
+    fn explicit_linker_not_probed() {
+        let options = LinkOptions {
+            linker: Some("no-such-linker".to_owned()),
+            ..LinkOptions::default()
+        };
+        assert_eq!(find_linker(&options).unwrap(), "no-such-linker");
+    }
 }
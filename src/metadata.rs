@@ -0,0 +1,192 @@
+//! Build metadata embedded in compiled programs, so a binary found
+//! in the wild can be traced back to how it was built.
+//!
+//! The metadata is plain text in a section of its own, ending with
+//! a NUL byte:
+//!
+//! ```text
+//! bfc-build-metadata 1
+//! version 1.8.0
+//! target x86_64-pc-linux-gnu
+//! source-hash 8e3a1f0c5d9b2a47
+//! option -O3
+//! option --cell-size=16
+//! ```
+//!
+//! Each `option` line is an argument bfc was given, other than the
+//! source file, with backslashes and newlines escaped. `bfc inspect`
+//! finds the metadata by its first line, so it doesn't need to
+//! understand the binary's format.
+
+use crate::cache::{fnv1a, FNV_OFFSET_BASIS};
+
+/// The first line of the metadata. This is bumped whenever the
+/// format changes.
+const HEADER: &str = "bfc-build-metadata 1";
+
+/// How a binary was built.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildMetadata {
+    /// The version of bfc that compiled it.
+    pub version: String,
+    /// The target triple it was compiled for.
+    pub target: String,
+    /// A 64-bit FNV-1a hash of the source file, in hex.
+    pub source_hash: String,
+    /// The arguments bfc was given, other than the source file.
+    pub options: Vec<String>,
+}
+
+impl BuildMetadata {
+    /// Metadata for compiling `source` with this version of bfc.
+    pub fn new(source: &[u8], target: &str, options: Vec<String>) -> Self {
+        BuildMetadata {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            target: target.to_owned(),
+            source_hash: format!("{:016x}", fnv1a(source, FNV_OFFSET_BASIS)),
+            options,
+        }
+    }
+
+    /// The bytes to embed, including the final NUL.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut text = format!(
+            "{}\nversion {}\ntarget {}\nsource-hash {}\n",
+            HEADER, self.version, self.target, self.source_hash
+        );
+        for option in &self.options {
+            text.push_str("option ");
+            text.push_str(&escape(option));
+            text.push('\n');
+        }
+        let mut bytes = text.into_bytes();
+        bytes.push(0);
+        bytes
+    }
+
+    /// Find the metadata in the bytes of a binary, if it has any.
+    pub fn find(binary: &[u8]) -> Option<Self> {
+        let header = format!("{}\n", HEADER);
+        let start = binary
+            .windows(header.len())
+            .position(|window| window == header.as_bytes())?;
+        let rest = &binary[start + header.len()..];
+        let end = rest.iter().position(|&byte| byte == 0)?;
+        let text = std::str::from_utf8(&rest[..end]).ok()?;
+
+        let mut metadata = BuildMetadata {
+            version: String::new(),
+            target: String::new(),
+            source_hash: String::new(),
+            options: vec![],
+        };
+        for line in text.lines() {
+            let (key, value) = match line.find(' ') {
+                Some(i) => (&line[..i], &line[i + 1..]),
+                None => (line, ""),
+            };
+            match key {
+                "version" => metadata.version = value.to_owned(),
+                "target" => metadata.target = value.to_owned(),
+                "source-hash" => metadata.source_hash = value.to_owned(),
+                "option" => metadata.options.push(unescape(value)),
+                _ => return None,
+            }
+        }
+        Some(metadata)
+    }
+}
+
+/// The name of the section holding the metadata on this target.
+/// Mach-O needs a segment too, and Windows executables only allow
+/// eight characters.
+pub fn section_name(target_triple: &str) -> &'static str {
+    if target_triple.contains("apple") {
+        "__TEXT,__bfc_metadata"
+    } else if target_triple.contains("windows") {
+        ".bfcmeta"
+    } else {
+        ".bfc_metadata"
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape(text: &str) -> String {
+    let mut result = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some(other) => result.push(other),
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn example() -> BuildMetadata {
+        BuildMetadata::new(
+            b"+[.]",
+            "x86_64-pc-linux-gnu",
+            vec!["-O3".to_owned(), "--const-input=a\nb\\".to_owned()],
+        )
+    }
+
+    #[test]
+    fn metadata_as_text() {
+        let bytes = example().to_bytes();
+        let text = String::from_utf8(bytes).unwrap();
+        assert_eq!(
+            text,
+            format!(
+                "bfc-build-metadata 1\nversion {}\ntarget x86_64-pc-linux-gnu\n\
+                 source-hash {}\noption -O3\noption --const-input=a\\nb\\\\\n\0",
+                env!("CARGO_PKG_VERSION"),
+                example().source_hash
+            )
+        );
+    }
+
+    #[test]
+    fn find_metadata_in_binary() {
+        let mut binary = b"\x7fELF\0\0junk".to_vec();
+        binary.extend(example().to_bytes());
+        binary.extend(b"more junk\0");
+        assert_eq!(BuildMetadata::find(&binary), Some(example()));
+    }
+
+    #[test]
+    fn find_no_metadata() {
+        assert_eq!(BuildMetadata::find(b"\x7fELF\0\0junk"), None);
+        // The metadata must end with a NUL.
+        assert_eq!(
+            BuildMetadata::find(b"bfc-build-metadata 1\nversion 1"),
+            None
+        );
+    }
+
+    #[test]
+    fn source_hash_is_stable() {
+        let metadata = BuildMetadata::new(b"", "x86_64-pc-linux-gnu", vec![]);
+        assert_eq!(metadata.source_hash, "cbf29ce484222325");
+    }
+
+    #[test]
+    fn section_names() {
+        assert_eq!(section_name("x86_64-pc-linux-gnu"), ".bfc_metadata");
+        assert_eq!(section_name("x86_64-apple-darwin"), "__TEXT,__bfc_metadata");
+        assert_eq!(section_name("x86_64-pc-windows-msvc"), ".bfcmeta");
+    }
+}
@@ -2,22 +2,142 @@
 //! equivalents.
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
 use std::num::Wrapping;
+use std::time::{Duration, Instant};
 
 use itertools::Itertools;
 
 use crate::bfir::AstNode::*;
-use crate::bfir::{get_position, AstNode, Cell, Combine, Position};
-use crate::diagnostics::Warning;
+use crate::bfir::{
+    count_instrs, get_position, wrap_cell, AstNode, Cell, Combine, EofBehaviour, Position,
+    DEFAULT_CELL_BITS,
+};
+use crate::bounds::{certain_overrun, Overrun, MAX_CELL_INDEX};
+use crate::diagnostics::{Lint, Note, Warning};
+use crate::plugin::PassPlugin;
 
 const MAX_OPT_ITERATIONS: u64 = 40;
 
+/// At -O3 we're willing to run many more iterations to reach a fixed
+/// point on huge programs.
+const MAX_AGGRESSIVE_OPT_ITERATIONS: u64 = 400;
+
+/// The maximum number of steps we'll spend evaluating a single loop
+/// at compile time.
+const MAX_LOOP_EVAL_STEPS: u64 = 10_000;
+
+/// The largest number of instructions we'll unroll a single loop
+/// into.
+const MAX_UNROLLED_INSTRS: usize = 64;
+
+/// How many more rewrites passes may make, or None if there's no
+/// limit. See `OptConfig::fuel`. Each call to `optimize` gets its
+/// own, which it hands to every pass.
+struct Fuel(std::cell::Cell<Option<u64>>);
+
+impl Fuel {
+    fn new(limit: Option<u64>) -> Self {
+        Fuel(std::cell::Cell::new(limit))
+    }
+
+    fn unlimited() -> Self {
+        Fuel::new(None)
+    }
+
+    /// Returns true if a pass may make another rewrite, consuming one
+    /// unit of fuel.
+    fn consume(&self) -> bool {
+        match self.0.get() {
+            None => true,
+            Some(0) => false,
+            Some(remaining) => {
+                self.0.set(Some(remaining - 1));
+                true
+            }
+        }
+    }
+}
+
+/// What the optimiser should prioritise when a transformation makes
+/// code faster at the expense of making it bigger. Optimising for
+/// size skips loop evaluation, loop unrolling and constant write
+/// folding, which can all grow the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptGoal {
+    Speed,
+    Size,
+}
+
+/// Settings for the peephole optimiser.
+pub struct OptConfig<'a> {
+    /// A comma-separated list of passes to run, or None to run all
+    /// passes.
+    pub pass_specification: Option<String>,
+    pub goal: OptGoal,
+    /// The optimisation level, from 1 to 3. Level 3 also unrolls
+    /// counted loops, and runs the passes many more times to reach a
+    /// fixed point on huge programs.
+    pub level: u32,
+    /// The width of cells at runtime, in bits.
+    pub cell_bits: u32,
+    /// The maximum number of rewrites to make, or None for no
+    /// limit. This is useful for finding which rewrite introduced a
+    /// bug.
+    pub fuel: Option<u64>,
+    pub plugins: &'a [PassPlugin],
+    /// What reads store at EOF.
+    pub eof: EofBehaviour,
+}
+
+impl<'a> Default for OptConfig<'a> {
+    fn default() -> Self {
+        OptConfig {
+            pass_specification: None,
+            goal: OptGoal::Speed,
+            level: 2,
+            cell_bits: DEFAULT_CELL_BITS,
+            fuel: None,
+            plugins: &[],
+            eof: EofBehaviour::default(),
+        }
+    }
+}
+
 /// Given a sequence of BF instructions, apply peephole optimisations
 /// (repeatedly if necessary).
-pub fn optimize(
+pub fn optimize(instrs: Vec<AstNode>, config: &OptConfig) -> (Vec<AstNode>, Vec<Warning>) {
+    optimize_inner(instrs, config, None)
+}
+
+/// How much work a pass did during `optimize_with_stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassStats {
+    pub name: String,
+    /// How many times the pass ran.
+    pub runs: u64,
+    /// How many IR nodes the pass removed overall. Passes that
+    /// unroll loops may add nodes, making this negative.
+    pub removed: i64,
+    pub time: Duration,
+}
+
+/// As `optimize`, but also return statistics for each pass, in the
+/// order they first ran.
+pub fn optimize_with_stats(
     instrs: Vec<AstNode>,
-    pass_specification: &Option<String>,
+    config: &OptConfig,
+) -> (Vec<AstNode>, Vec<Warning>, Vec<PassStats>) {
+    let mut stats = vec![];
+    let (instrs, warnings) = optimize_inner(instrs, config, Some(&mut stats));
+    (instrs, warnings, stats)
+}
+
+fn optimize_inner(
+    instrs: Vec<AstNode>,
+    config: &OptConfig,
+    mut stats: Option<&mut Vec<PassStats>>,
 ) -> (Vec<AstNode>, Vec<Warning>) {
     // Many of our individual peephole optimisations remove
     // instructions, creating new opportunities to combine. We run
@@ -25,92 +145,261 @@ pub fn optimize(
     // can be made.
     let mut prev = instrs.clone();
     let mut warnings = vec![];
+    let fuel = Fuel::new(config.fuel);
 
-    let (mut result, warning) = optimize_once(instrs, pass_specification);
+    let (mut result, new_warnings) = optimize_once(instrs, config, &fuel, stats.as_deref_mut());
+    add_warnings(&mut warnings, new_warnings);
 
-    if let Some(warning) = warning {
-        warnings.push(warning);
-    }
+    let max_iterations = if config.level >= 3 {
+        MAX_AGGRESSIVE_OPT_ITERATIONS
+    } else {
+        MAX_OPT_ITERATIONS
+    };
 
-    for _ in 0..MAX_OPT_ITERATIONS {
+    let mut reached_fixed_point = false;
+    for _ in 0..max_iterations {
         if prev == result {
-            return (result, warnings);
+            reached_fixed_point = true;
+            break;
         }
         prev = result.clone();
 
-        let (new_result, new_warning) = optimize_once(result, pass_specification);
-
-        if let Some(warning) = new_warning {
-            warnings.push(warning);
-        }
+        let (new_result, new_warnings) = optimize_once(result, config, &fuel, stats.as_deref_mut());
+        add_warnings(&mut warnings, new_warnings);
         result = new_result;
     }
 
-    // TODO: use proper Info here.
-    eprintln!(
-        "Warning: ran peephole optimisations {} times but did not reach a fixed point!",
-        MAX_OPT_ITERATIONS
+    if !reached_fixed_point {
+        warnings.push(Warning {
+            lint: Lint::OptimisationLimit,
+            message: format!(
+                "Ran peephole optimisations {} times but did not reach a fixed point",
+                max_iterations
+            ),
+            position: None,
+            notes: vec![],
+        });
+    }
+
+    // The optimised IR makes known cell values and pointer movements
+    // explicit, so it's the easiest place to spot likely bugs.
+    add_warnings(
+        &mut warnings,
+        infinite_loop_warnings(&result, config.cell_bits),
+    );
+    add_warnings(
+        &mut warnings,
+        tape_overrun_warning(&result).into_iter().collect(),
     );
 
     (result, warnings)
 }
 
+/// Add `new_warnings` to `warnings`, ignoring any we've already seen
+/// on a previous iteration.
+fn add_warnings(warnings: &mut Vec<Warning>, new_warnings: Vec<Warning>) {
+    for warning in new_warnings {
+        if !warnings.contains(&warning) {
+            warnings.push(warning);
+        }
+    }
+}
+
+/// The names of our built-in passes, in the order they run.
+pub const PASS_NAMES: [&str; 18] = [
+    "reassociate",
+    "combine_inc",
+    "cell_wrap",
+    "combine_ptr",
+    "known_zero",
+    "multiply",
+    "multiply_adds",
+    "zeroing_loop",
+    "combine_set",
+    "dead_loop",
+    "loop_eval",
+    "unroll",
+    "redundant_set",
+    "read_clobber",
+    "combine_read",
+    "const_write",
+    "pure_removal",
+    "offset_sort",
+];
+
+/// The error returned when asked to run a pass that doesn't exist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownPass(pub String);
+
+impl fmt::Display for UnknownPass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "Unknown pass '{}' (valid passes are: {})",
+            self.0,
+            PASS_NAMES.join(", ")
+        )
+    }
+}
+
+/// Run the built-in pass called `name` once over `instrs`, returning
+/// the new instructions and any warnings the pass produced.
+///
+/// `instrs` may be any fragment, such as a loop body, so passes don't
+/// assume the cells start at zero. Passes run as they would at -O3,
+/// so -O3-only passes still do something.
+pub fn run_pass(
+    name: &str,
+    instrs: Vec<AstNode>,
+) -> Result<(Vec<AstNode>, Vec<Warning>), UnknownPass> {
+    let config = OptConfig {
+        level: 3,
+        ..OptConfig::default()
+    };
+    run_pass_with_config(
+        name,
+        instrs,
+        &config,
+        false,
+        &Fuel::unlimited(),
+    )
+}
+
+/// Run the pass called `name`. If `at_program_start` is true,
+/// `instrs` is the whole program, so every cell starts at zero.
+fn run_pass_with_config(
+    name: &str,
+    instrs: Vec<AstNode>,
+    config: &OptConfig,
+    at_program_start: bool,
+    fuel: &Fuel,
+) -> Result<(Vec<AstNode>, Vec<Warning>), UnknownPass> {
+    let mut warnings = vec![];
+    let instrs = match name {
+        "reassociate" => reassociate_inner(instrs, fuel),
+        "combine_inc" => combine_increments_inner(instrs, fuel),
+        "cell_wrap" => wrap_cell_amounts_inner(instrs, config.cell_bits, fuel),
+        "combine_ptr" => combine_ptr_increments_inner(instrs, fuel),
+        "known_zero" => annotate_known_zero_inner(instrs, at_program_start, fuel),
+        "multiply" => extract_multiply_inner(instrs, fuel),
+        "multiply_adds" => absorb_multiply_adds_inner(instrs, fuel),
+        "zeroing_loop" => zeroing_loops_inner(instrs, fuel),
+        "combine_set" => combine_set_and_increments_inner(instrs, fuel),
+        "dead_loop" => {
+            warnings.extend(dead_loop_warnings(&instrs, config.cell_bits));
+            remove_dead_loops_inner(instrs, fuel)
+        }
+        // Passes that trade size for speed don't run under -Os.
+        "loop_eval" if config.goal == OptGoal::Size => instrs,
+        "loop_eval" => evaluate_pure_loops_inner(instrs, config.cell_bits, at_program_start, fuel),
+        // Unrolling grows the program, so only -O3 does it.
+        "unroll" if config.level < 3 || config.goal == OptGoal::Size => instrs,
+        "unroll" => unroll_counted_loops_inner(instrs, config.cell_bits, fuel),
+        "redundant_set" => remove_redundant_sets_inner(instrs, at_program_start, fuel),
+        // A read at EOF may leave the cell unchanged, so earlier
+        // modifications aren't dead.
+        "read_clobber" if config.eof == EofBehaviour::Unchanged => instrs,
+        "read_clobber" => remove_read_clobber_inner(instrs, fuel),
+        "combine_read" => combine_reads_inner(instrs, fuel),
+        "const_write" if config.goal == OptGoal::Size => instrs,
+        "const_write" => fold_constant_writes_inner(instrs, at_program_start, fuel),
+        "pure_removal" => {
+            let (removed, pure_warning) = remove_pure_code_inner(instrs, fuel);
+            warnings.extend(pure_warning);
+            removed
+        }
+        "offset_sort" => sort_by_offset_inner(instrs, fuel),
+        _ => return Err(UnknownPass(name.to_owned())),
+    };
+    Ok((instrs, warnings))
+}
+
+/// Record a run of the pass `name` in `stats`.
+fn record_pass(
+    stats: &mut Vec<PassStats>,
+    name: &str,
+    before: usize,
+    after: usize,
+    time: Duration,
+) {
+    let index = match stats.iter().position(|pass| pass.name == name) {
+        Some(index) => index,
+        None => {
+            stats.push(PassStats {
+                name: name.to_owned(),
+                runs: 0,
+                removed: 0,
+                time: Duration::default(),
+            });
+            stats.len() - 1
+        }
+    };
+    let pass = &mut stats[index];
+    pass.runs += 1;
+    pass.removed += before as i64 - after as i64;
+    pass.time += time;
+}
+
 /// Apply all our peephole optimisations once and return the result.
 fn optimize_once(
     instrs: Vec<AstNode>,
-    pass_specification: &Option<String>,
-) -> (Vec<AstNode>, Option<Warning>) {
-    let pass_specification = pass_specification.clone().unwrap_or_else(|| {
-        "combine_inc,combine_ptr,known_zero,\
-         multiply,zeroing_loop,combine_set,\
-         dead_loop,redundant_set,read_clobber,\
-         pure_removal,offset_sort"
-            .into()
+    config: &OptConfig,
+    fuel: &Fuel,
+    mut stats: Option<&mut Vec<PassStats>>,
+) -> (Vec<AstNode>, Vec<Warning>) {
+    let plugins = config.plugins;
+    let pass_specification = config.pass_specification.clone().unwrap_or_else(|| {
+        let mut default_passes = PASS_NAMES.join(",");
+        // Plugin passes run by default once loaded.
+        for plugin in plugins {
+            default_passes.push(',');
+            default_passes.push_str(&plugin.name);
+        }
+        default_passes
     });
     let passes: Vec<_> = pass_specification.split(',').collect();
 
     let mut instrs = instrs;
+    let mut warnings = vec![];
 
-    if passes.contains(&"combine_inc") {
-        instrs = combine_increments(instrs);
-    }
-    if passes.contains(&"combine_ptr") {
-        instrs = combine_ptr_increments(instrs);
-    }
-    if passes.contains(&"known_zero") {
-        instrs = annotate_known_zero(instrs);
-    }
-    if passes.contains(&"multiply") {
-        instrs = extract_multiply(instrs);
-    }
-    if passes.contains(&"zeroing_loop") {
-        instrs = zeroing_loops(instrs);
-    }
-    if passes.contains(&"combine_set") {
-        instrs = combine_set_and_increments(instrs);
-    }
-    if passes.contains(&"dead_loop") {
-        instrs = remove_dead_loops(instrs);
-    }
-    if passes.contains(&"redundant_set") {
-        instrs = remove_redundant_sets(instrs);
-    }
-    if passes.contains(&"read_clobber") {
-        instrs = remove_read_clobber(instrs);
+    // Built-in passes always run in the same order, regardless of
+    // the order they were specified.
+    for name in &PASS_NAMES {
+        if passes.contains(name) {
+            // Counting nodes is as slow as some passes, so only do it
+            // when asked.
+            let before = stats.as_ref().map(|_| count_instrs(&instrs));
+            let start = Instant::now();
+            let (new_instrs, new_warnings) = run_pass_with_config(name, instrs, config, true, fuel)
+                .expect("PASS_NAMES should only contain known passes");
+            if let (Some(stats), Some(before)) = (stats.as_deref_mut(), before) {
+                let after = count_instrs(&new_instrs);
+                record_pass(stats, name, before, after, start.elapsed());
+            }
+            instrs = new_instrs;
+            warnings.extend(new_warnings);
+        }
     }
-    let warning = if passes.contains(&"pure_removal") {
-        let (removed, pure_warning) = remove_pure_code(instrs);
-        instrs = removed;
-        pure_warning
-    } else {
-        None
-    };
 
-    if passes.contains(&"offset_sort") {
-        instrs = sort_by_offset(instrs);
+    // Plugin passes run after our own passes, in the order they were
+    // specified.
+    for pass_name in &passes {
+        if let Some(plugin) = plugins.iter().find(|plugin| plugin.name == *pass_name) {
+            let before = stats.as_ref().map(|_| count_instrs(&instrs));
+            let start = Instant::now();
+            let (plugin_instrs, plugin_warning) = plugin.run(instrs.clone());
+            if plugin_instrs != instrs && fuel.consume() {
+                instrs = plugin_instrs;
+            }
+            if let (Some(stats), Some(before)) = (stats.as_deref_mut(), before) {
+                let after = count_instrs(&instrs);
+                record_pass(stats, pass_name, before, after, start.elapsed());
+            }
+            warnings.extend(plugin_warning);
+        }
     }
 
-    (instrs, warning)
+    (instrs, warnings)
 }
 
 /// Defines a method on iterators to map a function over all loop bodies.
@@ -154,9 +443,13 @@ pub fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
             PointerIncrement { amount, .. } => {
                 needed_offset += amount;
             }
-            MultiplyMove { ref changes, .. } => {
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
                 // These cells are written to.
-                let mut offsets: Vec<isize> = changes.keys().cloned().collect();
+                let mut offsets: Vec<isize> = changes.keys().chain(adds.keys()).cloned().collect();
                 // This cell is zeroed.
                 offsets.push(0);
 
@@ -165,10 +458,10 @@ pub fn previous_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                 }
             }
             // No cells changed, so just keep working backwards.
-            Write { .. } => {}
+            Write { .. } | WriteString { .. } => {}
             // These instructions may have modified the cell, so
             // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Read { .. } | SkipRead { .. } | Loop { .. } => return None,
         }
     }
     None
@@ -197,9 +490,13 @@ pub fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                 // Unlike previous_cell_change we must subtract the desired amount.
                 needed_offset -= amount;
             }
-            MultiplyMove { ref changes, .. } => {
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
                 // These cells are written to.
-                let mut offsets: Vec<isize> = changes.keys().cloned().collect();
+                let mut offsets: Vec<isize> = changes.keys().chain(adds.keys()).cloned().collect();
                 // This cell is zeroed.
                 offsets.push(0);
 
@@ -208,10 +505,10 @@ pub fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
                 }
             }
             // No cells changed, so just keep working backwards.
-            Write { .. } => {}
+            Write { .. } | WriteString { .. } => {}
             // These instructions may have modified the cell, so
             // we return None for "I don't know".
-            Read { .. } | Loop { .. } => return None,
+            Read { .. } | SkipRead { .. } | Loop { .. } => return None,
         }
     }
     None
@@ -220,6 +517,10 @@ pub fn next_cell_change(instrs: &[AstNode], index: usize) -> Option<usize> {
 /// Combine consecutive increments into a single increment
 /// instruction.
 pub fn combine_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    combine_increments_inner(instrs, &Fuel::unlimited())
+}
+
+fn combine_increments_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     instrs
         .into_iter()
         .coalesce(|prev_instr, instr| {
@@ -236,7 +537,7 @@ pub fn combine_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     position,
                 } = instr
                 {
-                    if prev_offset == offset {
+                    if prev_offset == offset && fuel.consume() {
                         return Ok(Increment {
                             amount: amount + prev_amount,
                             offset,
@@ -254,14 +555,88 @@ pub fn combine_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
                 ..
             } = *instr
             {
-                return false;
+                return !fuel.consume();
             }
             true
         })
-        .map_loops(combine_increments)
+        .map_loops(|body| combine_increments_inner(body, fuel))
+}
+
+/// Reduce amounts to the range of a cell, since e.g. adding 256 to an
+/// 8-bit cell does nothing. Increments that become zero are removed.
+pub fn wrap_cell_amounts(instrs: Vec<AstNode>, cell_bits: u32) -> Vec<AstNode> {
+    wrap_cell_amounts_inner(instrs, cell_bits, &Fuel::unlimited())
+}
+
+fn wrap_cell_amounts_inner(instrs: Vec<AstNode>, cell_bits: u32, fuel: &Fuel) -> Vec<AstNode> {
+    let wrap_map = |map: HashMap<isize, Cell>| -> HashMap<isize, Cell> {
+        map.into_iter()
+            .map(|(offset, amount)| (offset, wrap_cell(amount, cell_bits)))
+            .filter(|&(_, amount)| amount.0 != 0)
+            .collect()
+    };
+
+    instrs
+        .into_iter()
+        .filter_map(|instr| {
+            let wrapped = match instr {
+                Increment {
+                    amount,
+                    offset,
+                    position,
+                } => {
+                    let amount = wrap_cell(amount, cell_bits);
+                    if amount.0 == 0 {
+                        None
+                    } else {
+                        Some(Increment {
+                            amount,
+                            offset,
+                            position,
+                        })
+                    }
+                }
+                Set {
+                    amount,
+                    offset,
+                    position,
+                } => Some(Set {
+                    amount: wrap_cell(amount, cell_bits),
+                    offset,
+                    position,
+                }),
+                MultiplyMove {
+                    ref changes,
+                    ref adds,
+                    position,
+                } => Some(MultiplyMove {
+                    changes: wrap_map(changes.clone()),
+                    adds: wrap_map(adds.clone()),
+                    position,
+                }),
+                Loop { body, position } => {
+                    return Some(Loop {
+                        body: wrap_cell_amounts_inner(body, cell_bits, fuel),
+                        position,
+                    })
+                }
+                other => return Some(other),
+            };
+
+            if wrapped.as_ref() != Some(&instr) && fuel.consume() {
+                wrapped
+            } else {
+                Some(instr)
+            }
+        })
+        .collect()
 }
 
 pub fn combine_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    combine_ptr_increments_inner(instrs, &Fuel::unlimited())
+}
+
+fn combine_ptr_increments_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     instrs
         .into_iter()
         .coalesce(|prev_instr, instr| {
@@ -272,10 +647,12 @@ pub fn combine_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
             } = prev_instr
             {
                 if let PointerIncrement { amount, position } = instr {
-                    return Ok(PointerIncrement {
-                        amount: amount + prev_amount,
-                        position: prev_pos.combine(position),
-                    });
+                    if fuel.consume() {
+                        return Ok(PointerIncrement {
+                            amount: amount + prev_amount,
+                            position: prev_pos.combine(position),
+                        });
+                    }
                 }
             }
             Err((prev_instr, instr))
@@ -283,23 +660,483 @@ pub fn combine_ptr_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .filter(|instr| {
             // Remove any pointer increments of 0.
             if let PointerIncrement { amount: 0, .. } = *instr {
-                return false;
+                return !fuel.consume();
             }
             true
         })
-        .map_loops(combine_ptr_increments)
+        .map_loops(|body| combine_ptr_increments_inner(body, fuel))
+}
+
+/// The number of bytes read from stdin by this instruction, if it is
+/// a read.
+fn read_count(instr: &AstNode) -> Option<(usize, Option<Position>)> {
+    match *instr {
+        Read { position } => Some((1, position)),
+        SkipRead { count, position } => Some((count, position)),
+        _ => None,
+    }
+}
+
+/// Collapse consecutive reads into a single `SkipRead`. Only the
+/// last value read is stored, but we still consume every byte.
+pub fn combine_reads(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    combine_reads_inner(instrs, &Fuel::unlimited())
+}
+
+fn combine_reads_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
+    instrs
+        .into_iter()
+        .coalesce(|prev_instr, instr| {
+            if let (Some((prev_count, prev_pos)), Some((count, position))) =
+                (read_count(&prev_instr), read_count(&instr))
+            {
+                if !fuel.consume() {
+                    return Err((prev_instr, instr));
+                }
+                return Ok(SkipRead {
+                    count: prev_count + count,
+                    position: prev_pos.combine(position),
+                });
+            }
+            Err((prev_instr, instr))
+        })
+        .map_loops(|body| combine_reads_inner(body, fuel))
+}
+
+/// The cell values we know during straight-line code, indexed by
+/// offset from the cell pointer at the start of the sequence.
+struct KnownCells {
+    values: HashMap<isize, Option<Cell>>,
+    /// Are cells we haven't seen known to be zero? This is true at
+    /// the start of the program.
+    default_zero: bool,
+}
+
+impl KnownCells {
+    fn get(&self, offset: isize) -> Option<Cell> {
+        match self.values.get(&offset) {
+            Some(value) => *value,
+            None if self.default_zero => Some(Wrapping(0)),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, offset: isize, value: Option<Cell>) {
+        self.values.insert(offset, value);
+    }
+
+    /// Update the known values to reflect executing `instr`, where
+    /// `cell_ptr` is the current cell pointer.
+    fn update(&mut self, instr: &AstNode, cell_ptr: &mut isize) {
+        match *instr {
+            Increment { amount, offset, .. } => {
+                let value = self.get(*cell_ptr + offset).map(|value| value + amount);
+                self.set(*cell_ptr + offset, value);
+            }
+            Set { amount, offset, .. } => {
+                self.set(*cell_ptr + offset, Some(amount));
+            }
+            PointerIncrement { amount, .. } => {
+                *cell_ptr += amount;
+            }
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
+                let source = self.get(*cell_ptr);
+                for (offset, factor) in changes {
+                    let dest = *cell_ptr + offset;
+                    let value = match (self.get(dest), source) {
+                        (Some(dest_value), Some(source_value)) => {
+                            Some(dest_value + source_value * factor)
+                        }
+                        _ => None,
+                    };
+                    self.set(dest, value);
+                }
+                self.set(*cell_ptr, Some(Wrapping(0)));
+                for (offset, amount) in adds {
+                    let dest = *cell_ptr + offset;
+                    let value = self.get(dest).map(|value| value + *amount);
+                    self.set(dest, value);
+                }
+            }
+            Read { .. } | SkipRead { .. } => {
+                self.set(*cell_ptr, None);
+            }
+            Write { .. } | WriteString { .. } => {}
+            Loop { .. } => {
+                // We know nothing about the other cells or the cell
+                // pointer after a loop, but the current cell is zero.
+                self.values = HashMap::new();
+                self.default_zero = false;
+                *cell_ptr = 0;
+                self.set(0, Some(Wrapping(0)));
+            }
+        }
+    }
+}
+
+/// Replace writes of cells with known values with `WriteString`
+/// instructions, merging them into a single `WriteString` where
+/// possible. `instrs` must be a whole program, as we assume cells
+/// start at zero.
+pub fn fold_constant_writes(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    fold_constant_writes_inner(instrs, true, &Fuel::unlimited())
+}
+
+fn fold_constant_writes_inner(
+    instrs: Vec<AstNode>,
+    at_program_start: bool,
+    fuel: &Fuel,
+) -> Vec<AstNode> {
+    let mut result = vec![];
+    let mut known = KnownCells {
+        values: HashMap::new(),
+        default_zero: at_program_start,
+    };
+    let mut cell_ptr = 0;
+    // The index in `result` of the WriteString we're currently
+    // appending to. Cell updates don't affect a WriteString, so it's
+    // safe to move them after it.
+    let mut string_index: Option<usize> = None;
+
+    for instr in instrs {
+        let known_bytes = match instr {
+            Write { position } => known
+                .get(cell_ptr)
+                .filter(|_| fuel.consume())
+                .map(|value| (vec![value.0 as u8], position)),
+            WriteString {
+                ref bytes,
+                position,
+            } => Some((bytes.clone(), position)),
+            _ => None,
+        };
+        if let Some((bytes, position)) = known_bytes {
+            match string_index {
+                Some(index) if fuel.consume() => {
+                    if let WriteString {
+                        bytes: ref mut prev_bytes,
+                        position: ref mut prev_pos,
+                    } = result[index]
+                    {
+                        prev_bytes.extend(bytes);
+                        *prev_pos = prev_pos.combine(position);
+                    }
+                }
+                _ => {
+                    string_index = Some(result.len());
+                    result.push(WriteString { bytes, position });
+                }
+            }
+            continue;
+        }
+
+        known.update(&instr, &mut cell_ptr);
+        match instr {
+            // The output order must be preserved relative to reads
+            // and loops, so we start a new string.
+            Read { .. } | SkipRead { .. } | Write { .. } | Loop { .. } => {
+                string_index = None;
+            }
+            _ => {}
+        }
+
+        result.push(match instr {
+            Loop { body, position } => Loop {
+                body: fold_constant_writes_inner(body, false, fuel),
+                position,
+            },
+            other => other,
+        });
+    }
+
+    result
+}
+
+/// Replace loops without side effects with the `Set` instructions
+/// they're equivalent to, when we know the values of all the cells
+/// they use. For example, "[-]++[->+++<]" sets the next cell to 6.
+/// `instrs` must be a whole program, as we assume cells start at
+/// zero.
+pub fn evaluate_pure_loops(instrs: Vec<AstNode>, cell_bits: u32) -> Vec<AstNode> {
+    evaluate_pure_loops_inner(instrs, cell_bits, true, &Fuel::unlimited())
+}
+
+fn evaluate_pure_loops_inner(
+    instrs: Vec<AstNode>,
+    cell_bits: u32,
+    at_program_start: bool,
+    fuel: &Fuel,
+) -> Vec<AstNode> {
+    let mut result = vec![];
+    let mut known = KnownCells {
+        values: HashMap::new(),
+        default_zero: at_program_start,
+    };
+    let mut cell_ptr = 0;
+
+    for instr in instrs {
+        let replacement = match instr {
+            // Multiply loops are still loops, so we can evaluate them
+            // the same way.
+            Loop { .. } | MultiplyMove { .. } => evaluate_loop(&instr, &known, cell_ptr, cell_bits),
+            _ => None,
+        };
+
+        match replacement {
+            Some(replacement) if fuel.consume() => {
+                for new_instr in &replacement {
+                    known.update(new_instr, &mut cell_ptr);
+                }
+                result.extend(replacement);
+            }
+            _ => {
+                known.update(&instr, &mut cell_ptr);
+                result.push(match instr {
+                    Loop { body, position } => Loop {
+                        body: evaluate_pure_loops_inner(body, cell_bits, false, fuel),
+                        position,
+                    },
+                    other => other,
+                });
+            }
+        }
+    }
+
+    result
+}
+
+/// Evaluate `loop_instr`, returning the equivalent `Set` and
+/// `PointerIncrement` instructions. Returns None if the loop has
+/// side effects, uses cells we don't know, or takes too long.
+fn evaluate_loop(
+    loop_instr: &AstNode,
+    known: &KnownCells,
+    cell_ptr: isize,
+    cell_bits: u32,
+) -> Option<Vec<AstNode>> {
+    let mut cells = KnownCells {
+        values: known.values.clone(),
+        default_zero: known.default_zero,
+    };
+    let mut eval_ptr = cell_ptr;
+    let mut written = HashSet::new();
+    let mut steps = 0;
+
+    evaluate_pure(
+        std::slice::from_ref(loop_instr),
+        &mut cells,
+        &mut eval_ptr,
+        &mut written,
+        &mut steps,
+        cell_bits,
+    )?;
+
+    let position = get_position(loop_instr);
+    let mut written: Vec<_> = written.into_iter().collect();
+    written.sort_unstable();
+
+    let mut result: Vec<AstNode> = written
+        .into_iter()
+        .map(|cell| Set {
+            amount: wrap_cell(cells.get(cell).unwrap(), cell_bits),
+            offset: cell - cell_ptr,
+            position,
+        })
+        .collect();
+    if eval_ptr != cell_ptr {
+        result.push(PointerIncrement {
+            amount: eval_ptr - cell_ptr,
+            position,
+        });
+    }
+    Some(result)
+}
+
+/// Execute `instrs` on `cells`, recording which cells we modify.
+/// Returns None if we can't evaluate these instructions.
+fn evaluate_pure(
+    instrs: &[AstNode],
+    cells: &mut KnownCells,
+    cell_ptr: &mut isize,
+    written: &mut HashSet<isize>,
+    steps: &mut u64,
+    cell_bits: u32,
+) -> Option<()> {
+    for instr in instrs {
+        count_step(steps)?;
+
+        match *instr {
+            Increment { offset, .. } | Set { offset, .. } => {
+                let cell = *cell_ptr + offset;
+                if let Increment { .. } = *instr {
+                    cells.get(cell)?;
+                }
+                cells.update(instr, cell_ptr);
+                written.insert(cell);
+            }
+            PointerIncrement { .. } => {
+                cells.update(instr, cell_ptr);
+            }
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
+                // If the current cell is zero, the multiplied cells
+                // aren't modified, so we don't need to know them.
+                let source = wrap_cell(cells.get(*cell_ptr)?, cell_bits);
+                if source.0 != 0 {
+                    for offset in changes.keys() {
+                        cells.get(*cell_ptr + offset)?;
+                        written.insert(*cell_ptr + offset);
+                    }
+                }
+                for offset in adds.keys() {
+                    cells.get(*cell_ptr + offset)?;
+                    written.insert(*cell_ptr + offset);
+                }
+                written.insert(*cell_ptr);
+                cells.update(instr, cell_ptr);
+            }
+            Loop { ref body, .. } => loop {
+                let value = wrap_cell(cells.get(*cell_ptr)?, cell_bits);
+                if value.0 == 0 {
+                    break;
+                }
+                evaluate_pure(body, cells, cell_ptr, written, steps, cell_bits)?;
+                count_step(steps)?;
+            },
+            Read { .. } | SkipRead { .. } | Write { .. } | WriteString { .. } => {
+                return None;
+            }
+        }
+    }
+    Some(())
+}
+
+/// Increment `steps`, returning None if we've exceeded our limit.
+fn count_step(steps: &mut u64) -> Option<()> {
+    *steps += 1;
+    if *steps > MAX_LOOP_EVAL_STEPS {
+        None
+    } else {
+        Some(())
+    }
+}
+
+/// Replace loops that run a known number of times with that many
+/// copies of their body. For example, "+++[>.<-]" becomes three
+/// copies of ">.<-". This grows the program, but later passes can
+/// often fold the copies, e.g. into a `WriteString`.
+///
+/// A loop is counted if we know the value of its cell, its body has
+/// no nested loops and no overall pointer movement, and the body
+/// decrements the loop cell exactly once.
+pub fn unroll_counted_loops(instrs: Vec<AstNode>, cell_bits: u32) -> Vec<AstNode> {
+    unroll_counted_loops_inner(instrs, cell_bits, &Fuel::unlimited())
+}
+
+fn unroll_counted_loops_inner(instrs: Vec<AstNode>, cell_bits: u32, fuel: &Fuel) -> Vec<AstNode> {
+    let mut result = vec![];
+    for (index, instr) in instrs.iter().enumerate() {
+        match *instr {
+            Loop { ref body, .. } => match counted_loop_iterations(&instrs, index, cell_bits) {
+                Some(iterations) if fuel.consume() => {
+                    for _ in 0..iterations {
+                        result.extend(body.iter().cloned());
+                    }
+                }
+                _ => result.push(instr.clone()),
+            },
+            _ => result.push(instr.clone()),
+        }
+    }
+    result
+        .into_iter()
+        .map_loops(|body| unroll_counted_loops_inner(body, cell_bits, fuel))
+}
+
+/// If the instruction at `index` is a counted loop that's small
+/// enough to unroll, return how many times it runs.
+fn counted_loop_iterations(instrs: &[AstNode], index: usize, cell_bits: u32) -> Option<usize> {
+    let body = match instrs[index] {
+        Loop { ref body, .. } => body,
+        _ => return None,
+    };
+
+    let prev_change_index = previous_cell_change(instrs, index)?;
+    let iterations = match instrs[prev_change_index] {
+        Set {
+            amount, offset: 0, ..
+        } => wrap_cell(amount, cell_bits).0,
+        _ => return None,
+    };
+    // Negative values are large unsigned values, far too many
+    // iterations to unroll.
+    if iterations <= 0 || iterations as usize * body.len() > MAX_UNROLLED_INSTRS {
+        return None;
+    }
+
+    let mut cell_ptr = 0;
+    let mut decremented = false;
+    for instr in body {
+        match *instr {
+            Increment { amount, offset, .. } if cell_ptr + offset == 0 => {
+                if decremented || wrap_cell(amount, cell_bits).0 != -1 {
+                    return None;
+                }
+                decremented = true;
+            }
+            Set { offset, .. } if cell_ptr + offset == 0 => return None,
+            Increment { .. } | Set { .. } | Write { .. } | WriteString { .. } => {}
+            PointerIncrement { amount, .. } => {
+                cell_ptr += amount;
+            }
+            Read { .. } | SkipRead { .. } if cell_ptr == 0 => return None,
+            Read { .. } | SkipRead { .. } => {}
+            MultiplyMove {
+                ref changes,
+                ref adds,
+                ..
+            } => {
+                let touches_loop_cell = cell_ptr == 0
+                    || changes
+                        .keys()
+                        .chain(adds.keys())
+                        .any(|offset| cell_ptr + offset == 0);
+                if touches_loop_cell {
+                    return None;
+                }
+            }
+            Loop { .. } => return None,
+        }
+    }
+
+    if cell_ptr == 0 && decremented {
+        Some(iterations as usize)
+    } else {
+        None
+    }
 }
 
 /// Don't bother updating cells if they're immediately overwritten
 /// by a value from stdin.
 // TODO: this should generate a warning too.
 pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    remove_read_clobber_inner(instrs, &Fuel::unlimited())
+}
+
+fn remove_read_clobber_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     let mut redundant_instr_positions = HashSet::new();
     let mut last_write_index = None;
 
     for (index, instr) in instrs.iter().enumerate() {
         match *instr {
-            Read { .. } => {
+            Read { .. } | SkipRead { .. } => {
                 // If we can find the time this cell was modified:
                 if let Some(prev_modify_index) = previous_cell_change(&instrs, index) {
                     // This modify instruction is not redundant if we
@@ -316,7 +1153,9 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
                         continue;
                     }
 
-                    redundant_instr_positions.insert(prev_modify_index);
+                    if !redundant_instr_positions.contains(&prev_modify_index) && fuel.consume() {
+                        redundant_instr_positions.insert(prev_modify_index);
+                    }
                 }
             }
             Write { .. } => {
@@ -331,11 +1170,15 @@ pub fn remove_read_clobber(instrs: Vec<AstNode>) -> Vec<AstNode> {
         .enumerate()
         .filter(|&(index, _)| !redundant_instr_positions.contains(&index))
         .map(|(_, instr)| instr)
-        .map_loops(remove_read_clobber)
+        .map_loops(|body| remove_read_clobber_inner(body, fuel))
 }
 
 /// Convert [-] to Set 0.
 pub fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    zeroing_loops_inner(instrs, &Fuel::unlimited())
+}
+
+fn zeroing_loops_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     instrs
         .into_iter()
         .map(|instr| {
@@ -348,21 +1191,27 @@ pub fn zeroing_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
                         ..
                     } = body[0]
                     {
-                        return Set {
-                            amount: Wrapping(0),
-                            offset: 0,
-                            position,
-                        };
+                        if fuel.consume() {
+                            return Set {
+                                amount: Wrapping(0),
+                                offset: 0,
+                                position,
+                            };
+                        }
                     }
                 }
             }
             instr
         })
-        .map_loops(zeroing_loops)
+        .map_loops(|body| zeroing_loops_inner(body, fuel))
 }
 
 /// Remove any loops where we know the current cell is zero.
 pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    remove_dead_loops_inner(instrs, &Fuel::unlimited())
+}
+
+fn remove_dead_loops_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     instrs
         .clone()
         .into_iter()
@@ -387,13 +1236,142 @@ pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     ..
                 } = *prev_instr
                 {
-                    return false;
+                    return !fuel.consume();
                 }
             }
             true
         })
         .map(|(_, instr)| instr)
-        .map_loops(remove_dead_loops)
+        .map_loops(|body| remove_dead_loops_inner(body, fuel))
+}
+
+/// Is the instruction at `index` a loop where we know the current
+/// cell is `Set` to a value matching `predicate`?
+fn loop_with_known_cell<F>(instrs: &[AstNode], index: usize, cell_bits: u32, predicate: F) -> bool
+where
+    F: Fn(Cell) -> bool,
+{
+    if let Loop { .. } = instrs[index] {
+        if let Some(prev_change_index) = previous_cell_change(instrs, index) {
+            if let Set {
+                amount, offset: 0, ..
+            } = instrs[prev_change_index]
+            {
+                return predicate(wrap_cell(amount, cell_bits));
+            }
+        }
+    }
+    false
+}
+
+/// Warn about loops that can never be entered, because the current
+/// cell is always zero. This should run before `remove_dead_loops`.
+///
+/// Loops at the very start of the program or straight after another
+/// loop are a common idiom for writing comments, as are loops with
+/// no BF commands, so we don't warn about them.
+pub fn dead_loop_warnings(instrs: &[AstNode], cell_bits: u32) -> Vec<Warning> {
+    let mut warnings = vec![];
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop { ref body, position } = *instr {
+            if loop_with_known_cell(instrs, index, cell_bits, |amount| amount.0 == 0) {
+                let prev_change_index = previous_cell_change(instrs, index).unwrap();
+                // `annotate_known_zero` gives the sets it adds at the
+                // start of the program and after loops an empty
+                // position, so the cell is only zero because nothing
+                // has changed it yet or a loop just ended.
+                let is_comment_loop = body.is_empty()
+                    || match get_position(&instrs[prev_change_index]) {
+                        Some(set_pos) => set_pos.start == set_pos.end,
+                        None => false,
+                    };
+                if !is_comment_loop {
+                    warnings.push(Warning {
+                        lint: Lint::DeadLoop,
+                        message: "This loop is never entered, because the current cell \
+                                  is always zero here."
+                            .to_owned(),
+                        position,
+                        notes: vec![Note::help(
+                            "Loops at the start of the program or straight after another \
+                             loop are treated as comments, so move it there if it's a \
+                             comment.",
+                        )],
+                    });
+                }
+            } else {
+                warnings.extend(dead_loop_warnings(body, cell_bits));
+            }
+        }
+    }
+    warnings
+}
+
+/// Warn about empty loops where the current cell is always nonzero,
+/// so the loop can never terminate.
+pub fn infinite_loop_warnings(instrs: &[AstNode], cell_bits: u32) -> Vec<Warning> {
+    let mut warnings = vec![];
+    for (index, instr) in instrs.iter().enumerate() {
+        if let Loop { ref body, position } = *instr {
+            if body.is_empty()
+                && loop_with_known_cell(instrs, index, cell_bits, |amount| amount.0 != 0)
+            {
+                warnings.push(Warning {
+                    lint: Lint::InfiniteLoop,
+                    message: "This loop never terminates, because the current cell \
+                              is always nonzero here."
+                        .to_owned(),
+                    position,
+                    notes: vec![Note::help(
+                        "If the program should run forever, disable this warning with \
+                         -W no-infinite-loop.",
+                    )],
+                });
+            }
+            warnings.extend(infinite_loop_warnings(body, cell_bits));
+        }
+    }
+    warnings
+}
+
+/// Warn if the program always accesses a cell outside the tape.
+pub fn tape_overrun_warning(instrs: &[AstNode]) -> Option<Warning> {
+    let (lint, message, position, note) = match certain_overrun(instrs)? {
+        Overrun::Underflow {
+            position,
+            cell_index,
+            exact,
+        } => (
+            Lint::TapeUnderflow,
+            format!(
+                "This instruction always moves the pointer before the first cell, to cell {}{}.",
+                cell_index,
+                if exact { "" } else { " or earlier" }
+            ),
+            position,
+            "The pointer starts at cell 0, the first cell.".to_owned(),
+        ),
+        Overrun::Overflow {
+            position,
+            cell_index,
+            exact,
+        } => (
+            Lint::TapeOverflow,
+            format!(
+                "This instruction always moves the pointer after the last cell, to cell {}{}.",
+                cell_index,
+                if exact { "" } else { " or later" }
+            ),
+            position,
+            format!("The tape has cells 0 to {}.", MAX_CELL_INDEX),
+        ),
+    };
+    Some(Warning {
+        lint,
+        message,
+        position,
+        notes: vec![Note::explain(note)],
+    })
 }
 
 /// Reorder flat sequences of instructions so we use offsets and only
@@ -404,6 +1382,10 @@ pub fn remove_dead_loops(instrs: Vec<AstNode>) -> Vec<AstNode> {
 /// Increment { amount: 2, offset: 2 }
 /// PointerIncrement(1)
 pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    sort_by_offset_inner(instrs, &Fuel::unlimited())
+}
+
+fn sort_by_offset_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     let mut sequence = vec![];
     let mut result = vec![];
 
@@ -414,12 +1396,12 @@ pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
             }
             _ => {
                 if !sequence.is_empty() {
-                    result.extend(sort_sequence_by_offset(sequence));
+                    result.extend(sort_sequence_with_fuel(sequence, fuel));
                     sequence = vec![];
                 }
                 if let Loop { body, position } = instr {
                     result.push(Loop {
-                        body: sort_by_offset(body),
+                        body: sort_by_offset_inner(body, fuel),
                         position,
                     });
                 } else {
@@ -430,7 +1412,113 @@ pub fn sort_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
     }
 
     if !sequence.is_empty() {
-        result.extend(sort_sequence_by_offset(sequence));
+        result.extend(sort_sequence_with_fuel(sequence, fuel));
+    }
+
+    result
+}
+
+/// Sort `instrs` if it changes anything and we have fuel to do so.
+fn sort_sequence_with_fuel(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
+    let sorted = sort_sequence_by_offset(instrs.clone());
+    if sorted != instrs && fuel.consume() {
+        sorted
+    } else {
+        instrs
+    }
+}
+
+/// Move increments and sets next to the previous change to the same
+/// cell, so they can be combined even when pointer movement or I/O
+/// on other cells separates them. For example, given "+>.<+" we
+/// return:
+/// Increment { amount: 1, offset: 0 }
+/// Increment { amount: 1, offset: 0 }
+/// PointerIncrement(1)
+/// Write
+/// PointerIncrement(-1)
+pub fn reassociate(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    reassociate_inner(instrs, &Fuel::unlimited())
+}
+
+fn reassociate_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = vec![];
+    let mut cell_ptr = 0;
+    // For each cell, the index in `result` of the last change to it,
+    // and the cell pointer at that point. We forget a cell once it's
+    // used, as changes must not move before I/O on that cell.
+    let mut last_changes: HashMap<isize, (usize, isize)> = HashMap::new();
+
+    for instr in instrs {
+        match instr {
+            Increment { offset, .. } | Set { offset, .. } => {
+                let cell = cell_ptr + offset;
+                match last_changes.get(&cell) {
+                    // Moving to the end of `result` is a no-op.
+                    Some(&(index, prev_cell_ptr))
+                        if index + 1 != result.len() && fuel.consume() =>
+                    {
+                        let new_offset = cell - prev_cell_ptr;
+                        let moved = match instr {
+                            Increment {
+                                amount, position, ..
+                            } => Increment {
+                                amount,
+                                offset: new_offset,
+                                position,
+                            },
+                            Set {
+                                amount, position, ..
+                            } => Set {
+                                amount,
+                                offset: new_offset,
+                                position,
+                            },
+                            _ => unreachable!(),
+                        };
+
+                        // Everything after the insertion point moves
+                        // along by one.
+                        for (other_index, _) in last_changes.values_mut() {
+                            if *other_index > index {
+                                *other_index += 1;
+                            }
+                        }
+                        result.insert(index + 1, moved);
+                        last_changes.insert(cell, (index + 1, prev_cell_ptr));
+                    }
+                    _ => {
+                        last_changes.insert(cell, (result.len(), cell_ptr));
+                        result.push(instr);
+                    }
+                }
+            }
+            PointerIncrement { amount, .. } => {
+                cell_ptr += amount;
+                result.push(instr);
+            }
+            Read { .. } | SkipRead { .. } | Write { .. } => {
+                last_changes.remove(&cell_ptr);
+                result.push(instr);
+            }
+            WriteString { .. } => {
+                result.push(instr);
+            }
+            MultiplyMove { .. } => {
+                last_changes.clear();
+                result.push(instr);
+            }
+            Loop { body, position } => {
+                // We don't know where the cell pointer is after a
+                // loop.
+                last_changes.clear();
+                cell_ptr = 0;
+                result.push(Loop {
+                    body: reassociate_inner(body, fuel),
+                    position,
+                });
+            }
+        }
     }
 
     result
@@ -484,7 +1572,10 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
             }
             PointerIncrement { amount, position } => {
                 current_offset += amount;
-                last_ptr_inc_pos = Some(position);
+                last_ptr_inc_pos = Some(match last_ptr_inc_pos {
+                    Some(prev_pos) => position.combine(prev_pos),
+                    None => position,
+                });
             }
             // We assume that we were only given a Vec of
             // Increment/Set/PointerIncrement instructions. It's
@@ -514,6 +1605,10 @@ fn sort_sequence_by_offset(instrs: Vec<AstNode>) -> Vec<AstNode> {
 /// Combine set instructions with other set instructions or
 /// increments.
 pub fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    combine_set_and_increments_inner(instrs, &Fuel::unlimited())
+}
+
+fn combine_set_and_increments_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     // It's sufficient to consider immediately adjacent instructions
     // as sort_sequence_by_offset ensures that if the offset is the
     // same, the instruction is adjacent.
@@ -535,7 +1630,7 @@ pub fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
                 },
             ) = (&prev_instr, &instr)
             {
-                if inc_offset == set_offset {
+                if inc_offset == set_offset && fuel.consume() {
                     return Ok(Set {
                         amount: set_amount,
                         offset: set_offset,
@@ -561,7 +1656,7 @@ pub fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     position: inc_pos,
                 } = instr
                 {
-                    if inc_offset == set_offset {
+                    if inc_offset == set_offset && fuel.consume() {
                         return Ok(Set {
                             amount: set_amount + inc_amount,
                             offset: set_offset,
@@ -587,7 +1682,7 @@ pub fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
                 },
             ) = (&prev_instr, &instr)
             {
-                if offset1 == offset2 {
+                if offset1 == offset2 && fuel.consume() {
                     return Ok(Set {
                         amount,
                         offset: offset1,
@@ -599,88 +1694,85 @@ pub fn combine_set_and_increments(instrs: Vec<AstNode>) -> Vec<AstNode> {
             }
             Err((prev_instr, instr))
         })
-        .map_loops(combine_set_and_increments)
+        .map_loops(|body| combine_set_and_increments_inner(body, fuel))
 }
 
+/// Remove `Set` instructions where we know the cell already holds
+/// that value, such as a `Set 0` after a loop or a repeated `Set`.
+/// `instrs` must be a whole program, as we assume cells start at
+/// zero.
 pub fn remove_redundant_sets(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut reduced = remove_redundant_sets_inner(instrs);
-
-    // Remove a set zero at the beginning of the program, since cells
-    // are initialised to zero anyway.
-    if let Some(&Set {
-        amount: Wrapping(0),
-        offset: 0,
-        ..
-    }) = reduced.first()
-    {
-        reduced.remove(0);
-    }
-
-    reduced
+    // Cells are initialised to zero, so a set zero at the beginning
+    // of the program is redundant too.
+    remove_redundant_sets_inner(instrs, true, &Fuel::unlimited())
 }
 
-fn remove_redundant_sets_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut redundant_instr_positions = HashSet::new();
+fn remove_redundant_sets_inner(
+    instrs: Vec<AstNode>,
+    at_program_start: bool,
+    fuel: &Fuel,
+) -> Vec<AstNode> {
+    let mut result = vec![];
+    let mut known = KnownCells {
+        values: HashMap::new(),
+        default_zero: at_program_start,
+    };
+    let mut cell_ptr = 0;
 
-    for (index, instr) in instrs.iter().enumerate() {
-        match *instr {
-            Loop { .. } | MultiplyMove { .. } => {
-                // There's no point setting to zero after a loop, as
-                // the cell is already zero.
-                if let Some(next_index) = next_cell_change(&instrs, index) {
-                    if let Set {
-                        amount: Wrapping(0),
-                        offset: 0,
-                        ..
-                    } = instrs[next_index]
-                    {
-                        redundant_instr_positions.insert(next_index);
-                    }
-                }
+    for instr in instrs {
+        if let Set { amount, offset, .. } = instr {
+            if known.get(cell_ptr + offset) == Some(amount) && fuel.consume() {
+                continue;
             }
-            _ => {}
         }
+
+        known.update(&instr, &mut cell_ptr);
+        result.push(match instr {
+            Loop { body, position } => Loop {
+                body: remove_redundant_sets_inner(body, false, fuel),
+                position,
+            },
+            other => other,
+        });
     }
 
-    instrs
-        .into_iter()
-        .enumerate()
-        .filter(|&(index, _)| !redundant_instr_positions.contains(&index))
-        .map(|(_, instr)| instr)
-        .map_loops(remove_redundant_sets_inner)
+    result
 }
 
 pub fn annotate_known_zero(instrs: Vec<AstNode>) -> Vec<AstNode> {
-    let mut result = vec![];
-
-    let position = if instrs.is_empty() {
-        None
-    } else {
-        get_position(&instrs[0]).map(|first_instr_pos| Position {
-            start: first_instr_pos.start,
-            end: first_instr_pos.start,
-        })
-    };
-
-    // Cells in BF are initialised to zero, so we know the current
-    // cell is zero at the start of execution.
-    let set_instr = Set {
-        amount: Wrapping(0),
-        offset: 0,
-        position,
-    };
-    // Insert the set instruction unless there is one already present.
-    if instrs.first() != Some(&set_instr) {
-        result.push(set_instr);
-    }
-
-    result.extend(annotate_known_zero_inner(instrs));
-    result
+    annotate_known_zero_inner(instrs, true, &Fuel::unlimited())
 }
 
-fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
+fn annotate_known_zero_inner(
+    instrs: Vec<AstNode>,
+    at_program_start: bool,
+    fuel: &Fuel,
+) -> Vec<AstNode> {
     let mut result = vec![];
 
+    if at_program_start {
+        let position = if instrs.is_empty() {
+            None
+        } else {
+            get_position(&instrs[0]).map(|first_instr_pos| Position {
+                start: first_instr_pos.start,
+                end: first_instr_pos.start,
+            })
+        };
+
+        // Cells in BF are initialised to zero, so we know the current
+        // cell is zero at the start of execution.
+        let set_instr = Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position,
+        };
+        // Insert the set instruction unless there is one already present.
+        if instrs.first() != Some(&set_instr) && fuel.consume() {
+            result.push(set_instr);
+        }
+    }
+
     for (i, instr) in instrs.iter().enumerate() {
         let instr = instr.clone();
 
@@ -688,7 +1780,7 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
             // After a loop, we know the cell is currently zero.
             Loop { body, position } => {
                 result.push(Loop {
-                    body: annotate_known_zero_inner(body),
+                    body: annotate_known_zero_inner(body, false, fuel),
                     position,
                 });
                 // Treat this set as positioned at the ].
@@ -702,7 +1794,7 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
                     offset: 0,
                     position: set_pos,
                 };
-                if instrs.get(i + 1) != Some(&set_instr) {
+                if instrs.get(i + 1) != Some(&set_instr) && fuel.consume() {
                     result.push(set_instr.clone());
                 }
             }
@@ -718,13 +1810,20 @@ fn annotate_known_zero_inner(instrs: Vec<AstNode>) -> Vec<AstNode> {
 /// Remove code at the end of the program that has no side
 /// effects. This means we have no write commands afterwards, nor
 /// loops (which may not terminate so we should not remove).
-pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>) {
+pub fn remove_pure_code(instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>) {
+    remove_pure_code_inner(instrs, &Fuel::unlimited())
+}
+
+fn remove_pure_code_inner(
+    mut instrs: Vec<AstNode>,
+    fuel: &Fuel,
+) -> (Vec<AstNode>, Option<Warning>) {
     let mut pure_instrs = vec![];
     while !instrs.is_empty() {
         let last_instr = instrs.pop().unwrap();
 
         match last_instr {
-            Read { .. } | Write { .. } | Loop { .. } => {
+            Read { .. } | SkipRead { .. } | Write { .. } | WriteString { .. } | Loop { .. } => {
                 instrs.push(last_instr);
                 break;
             }
@@ -734,18 +1833,43 @@ pub fn remove_pure_code(mut instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warni
         }
     }
 
-    let warning = if pure_instrs.is_empty() {
+    if !pure_instrs.is_empty() && !fuel.consume() {
+        // We're not allowed to remove anything, but we still warn.
+        instrs.extend(pure_instrs.iter().rev().cloned());
+    }
+
+    // Only warn about code that comes after the last side effect in
+    // the source. Other passes (such as `fold_constant_writes`) can
+    // make earlier code redundant, but that isn't the user's fault.
+    let last_effect_end = instrs
+        .iter()
+        .filter_map(get_position)
+        .map(|pos| pos.end)
+        .max();
+    let trailing_instrs: Vec<_> = pure_instrs
+        .into_iter()
+        .filter(|instr| match (get_position(instr), last_effect_end) {
+            (Some(pos), Some(end)) => pos.start > end,
+            _ => true,
+        })
+        .collect();
+
+    let warning = if trailing_instrs.is_empty() {
         None
     } else {
-        let position = pure_instrs
+        let position = trailing_instrs
             .into_iter()
             .map(|instr| get_position(&instr))
             .filter(|pos| pos.is_some())
             .fold1(|pos1, pos2| pos1.combine(pos2))
             .map(|pos| pos.unwrap());
         Some(Warning {
+            lint: Lint::RedundantCode,
             message: "These instructions have no effect.".to_owned(),
             position,
+            notes: vec![Note::explain(
+                "Nothing reads or writes after them, so their changes are never seen.",
+            )],
         })
     };
 
@@ -810,21 +1934,29 @@ fn cell_changes(instrs: &[AstNode]) -> HashMap<isize, Cell> {
 }
 
 pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    extract_multiply_inner(instrs, &Fuel::unlimited())
+}
+
+fn extract_multiply_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
     instrs
         .into_iter()
         .map(|instr| {
             match instr {
                 Loop { body, position } => {
-                    if is_multiply_loop_body(&body) {
+                    if is_multiply_loop_body(&body) && fuel.consume() {
                         let mut changes = cell_changes(&body);
                         // MultiplyMove is for where we move to, so ignore
                         // the cell we're moving from.
                         changes.remove(&0);
 
-                        MultiplyMove { changes, position }
+                        MultiplyMove {
+                            changes,
+                            adds: HashMap::new(),
+                            position,
+                        }
                     } else {
                         Loop {
-                            body: extract_multiply(body),
+                            body: extract_multiply_inner(body, fuel),
                             position,
                         }
                     }
@@ -834,3 +1966,69 @@ pub fn extract_multiply(instrs: Vec<AstNode>) -> Vec<AstNode> {
         })
         .collect()
 }
+
+/// Fold increments after a multiply into the multiply itself, so
+/// `[->+<]>++` becomes a single affine update of cell #1.
+pub fn absorb_multiply_adds(instrs: Vec<AstNode>) -> Vec<AstNode> {
+    absorb_multiply_adds_inner(instrs, &Fuel::unlimited())
+}
+
+fn absorb_multiply_adds_inner(instrs: Vec<AstNode>, fuel: &Fuel) -> Vec<AstNode> {
+    let mut result: Vec<AstNode> = vec![];
+    // The index in `result` of the last MultiplyMove, if we haven't
+    // seen anything since that would stop us moving increments into
+    // it.
+    let mut multiply_index: Option<usize> = None;
+
+    for instr in instrs {
+        if let (
+            Some(index),
+            Increment {
+                amount,
+                offset,
+                position,
+            },
+        ) = (multiply_index, &instr)
+        {
+            if let MultiplyMove {
+                ref changes,
+                ref mut adds,
+                position: ref mut multiply_pos,
+            } = result[index]
+            {
+                if changes.contains_key(offset) && fuel.consume() {
+                    let total = *adds.get(offset).unwrap_or(&Wrapping(0)) + *amount;
+                    if total == Wrapping(0) {
+                        adds.remove(offset);
+                    } else {
+                        adds.insert(*offset, total);
+                    }
+                    *multiply_pos = multiply_pos.combine(*position);
+                    continue;
+                }
+                // Increments of other cells can be moved before the
+                // multiply, unless they change the cell we multiply
+                // by.
+                if *offset == 0 {
+                    multiply_index = None;
+                }
+            }
+            result.push(instr);
+            continue;
+        }
+
+        multiply_index = match instr {
+            MultiplyMove { .. } => Some(result.len()),
+            _ => None,
+        };
+        result.push(match instr {
+            Loop { body, position } => Loop {
+                body: absorb_multiply_adds_inner(body, fuel),
+                position,
+            },
+            other => other,
+        });
+    }
+
+    result
+}
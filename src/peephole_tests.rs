@@ -6,7 +6,7 @@ use quickcheck::quickcheck;
 
 use crate::bfir::AstNode::*;
 use crate::bfir::{AstNode, Position};
-use crate::diagnostics::Warning;
+use crate::diagnostics::{Lint, Note, Warning};
 
 use crate::bfir::parse;
 use crate::peephole::*;
@@ -41,9 +41,19 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
-        3 => Read {
-            position: Some(Position { start: 0, end: 0 }),
-        },
+        3 => {
+            let skip: bool = Arbitrary::arbitrary(g);
+            if !skip {
+                Read {
+                    position: Some(Position { start: 0, end: 0 }),
+                }
+            } else {
+                SkipRead {
+                    count: 2 + g.next_u32() as usize % 5,
+                    position: Some(Position { start: 0, end: 0 }),
+                }
+            }
+        }
         4 => Write {
             position: Some(Position { start: 0, end: 0 }),
         },
@@ -52,6 +62,7 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             changes.insert(1, Wrapping(-1));
             MultiplyMove {
                 changes,
+                adds: HashMap::new(),
                 position: Some(Position { start: 0, end: 0 }),
             }
         }
@@ -59,8 +70,21 @@ fn arbitrary_instr<G: Gen>(g: &mut G, max_depth: usize) -> AstNode {
             let mut changes = HashMap::new();
             changes.insert(1, Wrapping(2));
             changes.insert(4, Wrapping(10));
+            // absorb_multiply_adds folds increments after the loop
+            // into the cells it multiplies into.
+            let mut adds = HashMap::new();
+            for &offset in &[1, 4] {
+                let amount: i32 = Arbitrary::arbitrary(g);
+                if amount != 0 {
+                    adds.insert(offset, Wrapping(amount));
+                }
+            }
+            if adds.is_empty() {
+                adds.insert(1, Wrapping(1));
+            }
             MultiplyMove {
                 changes,
+                adds,
                 position: Some(Position { start: 0, end: 0 }),
             }
         }
@@ -223,7 +247,7 @@ fn should_combine_before_read() {
             position: Some(Position { start: 2, end: 2 }),
         },
     ];
-    assert_eq!(optimize(initial, &None).0, expected);
+    assert_eq!(optimize(initial, &OptConfig::default()).0, expected);
 }
 
 #[test]
@@ -259,7 +283,7 @@ fn should_combine_before_read_nested() {
             position: Some(Position { start: 1, end: 4 }),
         },
     ];
-    assert_eq!(optimize(initial, &None).0, expected);
+    assert_eq!(optimize(initial, &OptConfig::default()).0, expected);
 }
 
 #[test]
@@ -310,6 +334,7 @@ fn no_combine_before_read_after_multiply() {
     let initial = vec![
         MultiplyMove {
             changes,
+            adds: HashMap::new(),
             position: None,
         },
         Read { position: None },
@@ -380,7 +405,7 @@ fn remove_repeated_loops() {
             position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(optimize(initial, &None).0, expected);
+    assert_eq!(optimize(initial, &OptConfig::default()).0, expected);
 }
 
 #[test]
@@ -468,8 +493,8 @@ fn remove_dead_loops_not_adjacent() {
 fn quickcheck_should_combine_set_and_increment() {
     fn should_combine_set_and_increment(
         offset: isize,
-        set_amount: i8,
-        increment_amount: i8,
+        set_amount: i32,
+        increment_amount: i32,
     ) -> bool {
         let set_amount = Wrapping(set_amount);
         let increment_amount = Wrapping(increment_amount);
@@ -493,7 +518,7 @@ fn quickcheck_should_combine_set_and_increment() {
         }];
         combine_set_and_increments(initial) == expected
     }
-    quickcheck(should_combine_set_and_increment as fn(isize, i8, i8) -> bool);
+    quickcheck(should_combine_set_and_increment as fn(isize, i32, i32) -> bool);
 }
 
 // TODO: rename our quickcheck property functions to something shorter.
@@ -501,9 +526,9 @@ fn quickcheck_should_combine_set_and_increment() {
 fn quickcheck_combine_set_and_increment_different_offsets() {
     fn combine_set_and_increment_different_offsets(
         set_offset: isize,
-        set_amount: i8,
+        set_amount: i32,
         inc_offset: isize,
-        inc_amount: i8,
+        inc_amount: i32,
     ) -> TestResult {
         if set_offset == inc_offset {
             return TestResult::discard();
@@ -526,7 +551,7 @@ fn quickcheck_combine_set_and_increment_different_offsets() {
         TestResult::from_bool(combine_set_and_increments(initial) == expected)
     }
     quickcheck(
-        combine_set_and_increment_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
+        combine_set_and_increment_different_offsets as fn(isize, i32, isize, i32) -> TestResult,
     );
 }
 
@@ -534,9 +559,9 @@ fn quickcheck_combine_set_and_increment_different_offsets() {
 fn quickcheck_combine_increment_and_set_different_offsets() {
     fn combine_increment_and_set_different_offsets(
         set_offset: isize,
-        set_amount: i8,
+        set_amount: i32,
         inc_offset: isize,
-        inc_amount: i8,
+        inc_amount: i32,
     ) -> TestResult {
         if set_offset == inc_offset {
             return TestResult::discard();
@@ -559,13 +584,13 @@ fn quickcheck_combine_increment_and_set_different_offsets() {
         TestResult::from_bool(combine_set_and_increments(initial) == expected)
     }
     quickcheck(
-        combine_increment_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult,
+        combine_increment_and_set_different_offsets as fn(isize, i32, isize, i32) -> TestResult,
     );
 }
 
 #[test]
 fn quickcheck_combine_set_and_set() {
-    fn combine_set_and_set(offset: isize, set_amount_before: i8, set_amount_after: i8) -> bool {
+    fn combine_set_and_set(offset: isize, set_amount_before: i32, set_amount_after: i32) -> bool {
         let initial = vec![
             Set {
                 amount: Wrapping(set_amount_before),
@@ -585,16 +610,16 @@ fn quickcheck_combine_set_and_set() {
         }];
         combine_set_and_increments(initial) == expected
     }
-    quickcheck(combine_set_and_set as fn(isize, i8, i8) -> bool);
+    quickcheck(combine_set_and_set as fn(isize, i32, i32) -> bool);
 }
 
 #[test]
 fn quickcheck_combine_set_and_set_different_offsets() {
     fn combine_set_and_set_different_offsets(
         offset1: isize,
-        amount1: i8,
+        amount1: i32,
         offset2: isize,
-        amount2: i8,
+        amount2: i32,
     ) -> TestResult {
         if offset1 == offset2 {
             return TestResult::discard();
@@ -616,7 +641,7 @@ fn quickcheck_combine_set_and_set_different_offsets() {
 
         TestResult::from_bool(combine_set_and_increments(initial) == expected)
     }
-    quickcheck(combine_set_and_set_different_offsets as fn(isize, i8, isize, i8) -> TestResult);
+    quickcheck(combine_set_and_set_different_offsets as fn(isize, i32, isize, i32) -> TestResult);
 }
 
 #[test]
@@ -712,6 +737,7 @@ fn should_remove_redundant_set_multiply() {
     let initial = vec![
         MultiplyMove {
             changes: changes.clone(),
+            adds: HashMap::new(),
             position: Some(Position { start: 0, end: 0 }),
         },
         Set {
@@ -722,227 +748,215 @@ fn should_remove_redundant_set_multiply() {
     ];
     let expected = vec![MultiplyMove {
         changes,
+        adds: HashMap::new(),
         position: Some(Position { start: 0, end: 0 }),
     }];
     assert_eq!(remove_redundant_sets(initial), expected);
 }
 
-/// After a loop, if we set to a value other than zero, we shouldn't
-/// remove it.
 #[test]
-fn not_redundant_set_when_nonzero() {
-    let instrs = vec![
-        Loop {
-            body: vec![],
+fn should_remove_repeated_set() {
+    let initial = vec![
+        Read {
             position: Some(Position { start: 0, end: 0 }),
         },
         Set {
-            amount: Wrapping(1),
+            amount: Wrapping(3),
             offset: 0,
+            position: Some(Position { start: 1, end: 5 }),
+        },
+        Write {
+            position: Some(Position { start: 6, end: 6 }),
+        },
+        Set {
+            amount: Wrapping(3),
+            offset: 0,
+            position: Some(Position { start: 7, end: 11 }),
+        },
+        Write {
+            position: Some(Position { start: 12, end: 12 }),
+        },
+    ];
+    let expected = vec![
+        Read {
             position: Some(Position { start: 0, end: 0 }),
         },
+        Set {
+            amount: Wrapping(3),
+            offset: 0,
+            position: Some(Position { start: 1, end: 5 }),
+        },
+        Write {
+            position: Some(Position { start: 6, end: 6 }),
+        },
+        Write {
+            position: Some(Position { start: 12, end: 12 }),
+        },
     ];
-    assert_eq!(remove_redundant_sets(instrs.clone()), instrs);
-}
-
-fn is_pure(instrs: &[AstNode]) -> bool {
-    for instr in instrs {
-        match *instr {
-            Loop { .. } => {
-                return false;
-            }
-            Read { .. } => {
-                return false;
-            }
-            Write { .. } => {
-                return false;
-            }
-            _ => (),
-        }
-    }
-    true
-}
-
-#[test]
-fn quickcheck_should_annotate_known_zero_at_start() {
-    fn should_annotate_known_zero_at_start(instrs: Vec<AstNode>) -> bool {
-        let annotated = annotate_known_zero(instrs);
-        matches!(annotated[0], Set { amount: Wrapping(0), offset: 0, .. })
-    }
-    quickcheck(should_annotate_known_zero_at_start as fn(Vec<AstNode>) -> bool);
+    assert_eq!(remove_redundant_sets(initial), expected);
 }
 
 #[test]
-fn annotate_known_zero_idempotent() {
-    fn is_idempotent(instrs: Vec<AstNode>) -> bool {
-        let annotated = annotate_known_zero(instrs);
-        let annotated_again = annotate_known_zero(annotated.clone());
-        if annotated == annotated_again {
-            true
-        } else {
-            println!("intermediate: {:?}", annotated);
-            println!("final: {:?}", annotated_again);
-            false
-        }
-    }
-    quickcheck(is_idempotent as fn(Vec<AstNode>) -> bool);
+fn should_remove_set_to_known_value() {
+    let initial = parse(",[-]>+<+>[-]+").unwrap();
+    let initial = combine_set_and_increments(zeroing_loops(initial));
+    let expected = parse(",[-]>+<+>").unwrap();
+    let expected = combine_set_and_increments(zeroing_loops(expected));
+    assert_eq!(remove_redundant_sets(initial), expected);
 }
 
 #[test]
-fn should_annotate_known_zero() {
-    let initial = parse("+[]").unwrap();
-    let expected = vec![
-        Set {
-            amount: Wrapping(0),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
-        Increment {
-            amount: Wrapping(1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
+fn should_remove_set_zero_after_loop_and_moves() {
+    let initial = vec![
         Loop {
             body: vec![],
-            position: Some(Position { start: 1, end: 2 }),
+            position: Some(Position { start: 0, end: 1 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 3, end: 3 }),
         },
         Set {
             amount: Wrapping(0),
             offset: 0,
-            position: Some(Position { start: 2, end: 2 }),
+            position: Some(Position { start: 4, end: 6 }),
         },
     ];
-    assert_eq!(annotate_known_zero(initial), expected);
+    let expected = initial[..3].to_vec();
+    assert_eq!(remove_redundant_sets(initial), expected);
 }
 
+/// After a loop, if we set to a value other than zero, we shouldn't
+/// remove it.
 #[test]
-fn should_annotate_known_zero_nested() {
-    let initial = parse("[[]]").unwrap();
-    let expected = vec![
-        Set {
-            amount: Wrapping(0),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
-        },
+fn not_redundant_set_when_nonzero() {
+    let instrs = vec![
         Loop {
-            body: vec![
-                Loop {
-                    body: vec![],
-                    position: Some(Position { start: 1, end: 2 }),
-                },
-                Set {
-                    amount: Wrapping(0),
-                    offset: 0,
-                    position: Some(Position { start: 2, end: 2 }),
-                },
-            ],
-            position: Some(Position { start: 0, end: 3 }),
+            body: vec![],
+            position: Some(Position { start: 0, end: 0 }),
         },
         Set {
-            amount: Wrapping(0),
+            amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 3, end: 3 }),
+            position: Some(Position { start: 0, end: 0 }),
         },
     ];
-    assert_eq!(annotate_known_zero(initial), expected);
+    assert_eq!(remove_redundant_sets(instrs.clone()), instrs);
 }
 
-/// When we annotate known zeroes, we have new opportunities for
-/// combining instructions and loop removal. However, we should later
-/// remove the Set 0 if we haven't combined it.
 #[test]
-fn should_annotate_known_zero_cleaned_up() {
-    let initial = vec![Write {
-        position: Some(Position { start: 0, end: 0 }),
+fn should_remove_increments_of_whole_cell_width() {
+    let instrs = vec![Increment {
+        amount: Wrapping(256),
+        offset: 0,
+        position: Some(Position { start: 0, end: 255 }),
     }];
-    assert_eq!(optimize(initial.clone(), &None).0, initial);
+    assert_eq!(wrap_cell_amounts(instrs, 8), vec![]);
 }
 
 #[test]
-fn should_preserve_set_0_in_loop() {
-    // Regression test.
-    let initial = vec![
-        Read {
-            position: Some(Position { start: 0, end: 0 }),
+fn should_wrap_increment_amounts() {
+    let instrs = vec![
+        Increment {
+            amount: Wrapping(257),
+            offset: 0,
+            position: Some(Position { start: 0, end: 256 }),
         },
-        Loop {
-            body: vec![Set {
-                amount: Wrapping(0),
-                offset: 0,
-                position: Some(Position { start: 0, end: 0 }),
-            }],
-            position: Some(Position { start: 0, end: 0 }),
+        Set {
+            amount: Wrapping(300),
+            offset: 1,
+            position: Some(Position {
+                start: 257,
+                end: 257,
+            }),
         },
     ];
-    assert_eq!(optimize(initial.clone(), &None).0, initial);
-}
-
-#[test]
-fn should_remove_pure_code() {
-    // The final increment here is side-effect free and can be
-    // removed.
-    let initial = parse("+.+").unwrap();
     let expected = vec![
-        Set {
+        Increment {
             amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 0, end: 256 }),
         },
-        Write {
-            position: Some(Position { start: 1, end: 1 }),
+        Set {
+            amount: Wrapping(44),
+            offset: 1,
+            position: Some(Position {
+                start: 257,
+                end: 257,
+            }),
         },
     ];
-
-    let (result, warnings) = optimize(initial, &None);
-
-    assert_eq!(result, expected);
-    assert_eq!(
-        warnings,
-        vec![Warning {
-            message: "These instructions have no effect.".to_owned(),
-            position: Some(Position { start: 2, end: 2 }),
-        }]
-    );
+    assert_eq!(wrap_cell_amounts(instrs, 8), expected);
 }
 
 #[test]
-fn quickcheck_should_remove_dead_pure_code() {
-    fn should_remove_dead_pure_code(instrs: Vec<AstNode>) -> TestResult {
-        if !is_pure(&instrs) {
-            return TestResult::discard();
-        }
-        TestResult::from_bool(optimize(instrs, &None).0 == vec![])
-    }
-    quickcheck(should_remove_dead_pure_code as fn(Vec<AstNode>) -> TestResult);
+fn should_wrap_amounts_to_configured_cell_width() {
+    let instrs = vec![Increment {
+        amount: Wrapping(256),
+        offset: 0,
+        position: Some(Position { start: 0, end: 255 }),
+    }];
+    assert_eq!(wrap_cell_amounts(instrs.clone(), 16), instrs);
 }
 
 #[test]
-fn quickcheck_optimize_should_be_idempotent() {
-    fn optimize_should_be_idempotent(instrs: Vec<AstNode>) -> bool {
-        // Once we've optimized once, running again shouldn't reduce the
-        // instructions further. If it does, we're probably running our
-        // optimisations in the wrong order.
-        let minimal = optimize(instrs.clone(), &None).0;
-        optimize(minimal.clone(), &None).0 == minimal
-    }
-    quickcheck(optimize_should_be_idempotent as fn(Vec<AstNode>) -> bool);
+fn should_wrap_multiply_factors() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(256));
+    changes.insert(2, Wrapping(-257));
+    let instrs = vec![MultiplyMove {
+        changes,
+        adds: HashMap::new(),
+        position: Some(Position { start: 0, end: 10 }),
+    }];
+
+    let mut expected_changes = HashMap::new();
+    expected_changes.insert(2, Wrapping(-1));
+    let expected = vec![MultiplyMove {
+        changes: expected_changes,
+        adds: HashMap::new(),
+        position: Some(Position { start: 0, end: 10 }),
+    }];
+    assert_eq!(wrap_cell_amounts(instrs, 8), expected);
 }
 
 #[test]
-fn pathological_optimisation_opportunity() {
-    let instrs = vec![
-        Read {
+fn should_reassociate_across_io() {
+    let instrs = parse("+>.<+").unwrap();
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
             position: Some(Position { start: 0, end: 0 }),
         },
         Increment {
             amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 4, end: 4 }),
         },
         PointerIncrement {
             amount: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Write {
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 3, end: 3 }),
         },
+    ];
+    assert_eq!(reassociate(instrs), expected);
+}
+
+#[test]
+fn should_reassociate_with_offset() {
+    let instrs = parse("+>.>+<<.>>+").unwrap();
+    let expected = vec![
         Increment {
             amount: Wrapping(1),
             offset: 0,
@@ -950,28 +964,864 @@ fn pathological_optimisation_opportunity() {
         },
         PointerIncrement {
             amount: 1,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Write {
+            position: Some(Position { start: 2, end: 2 }),
         },
         PointerIncrement {
-            amount: -1,
-            position: Some(Position { start: 0, end: 0 }),
+            amount: 1,
+            position: Some(Position { start: 3, end: 3 }),
         },
         Increment {
-            amount: Wrapping(-1),
+            amount: Wrapping(1),
             offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 4, end: 4 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 10, end: 10 }),
         },
         PointerIncrement {
             amount: -1,
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 5, end: 5 }),
         },
-        Increment {
-            amount: Wrapping(-1),
-            offset: 0,
-            position: Some(Position { start: 0, end: 0 }),
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 6, end: 6 }),
         },
         Write {
-            position: Some(Position { start: 0, end: 0 }),
+            position: Some(Position { start: 7, end: 7 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 8, end: 8 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 9, end: 9 }),
+        },
+    ];
+    assert_eq!(reassociate(instrs), expected);
+}
+
+#[test]
+fn should_not_reassociate_across_io_on_same_cell() {
+    let instrs = parse("+>+<.+").unwrap();
+    assert_eq!(reassociate(instrs.clone()), instrs);
+}
+
+#[test]
+fn run_pass_by_name() {
+    let instrs = parse("++").unwrap();
+    let (result, warnings) = run_pass("combine_inc", instrs.clone()).unwrap();
+    assert_eq!(result, combine_increments(instrs));
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn run_pass_returns_warnings() {
+    let instrs = parse("+.+").unwrap();
+    let (_, warnings) = run_pass("pure_removal", instrs).unwrap();
+    assert_eq!(warnings.len(), 1);
+}
+
+#[test]
+fn run_pass_does_not_assume_cells_start_at_zero() {
+    // The cell may be nonzero when a fragment starts, so neither the
+    // set nor the write is known.
+    let instrs = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: None,
+        },
+        Write { position: None },
+    ];
+    let (result, _) = run_pass("redundant_set", instrs.clone()).unwrap();
+    assert_eq!(result, instrs);
+
+    let instrs = parse(".").unwrap();
+    let (result, _) = run_pass("const_write", instrs.clone()).unwrap();
+    assert_eq!(result, instrs);
+}
+
+#[test]
+fn run_pass_unknown() {
+    let result = run_pass("no_such_pass", vec![]);
+    assert_eq!(result, Err(UnknownPass("no_such_pass".to_owned())));
+}
+
+#[test]
+fn all_passes_can_be_run() {
+    for name in &PASS_NAMES {
+        assert!(run_pass(name, vec![]).is_ok());
+    }
+}
+
+#[test]
+fn should_evaluate_pure_loop() {
+    // Cell #1 is set to 6 and cell #0 is zero afterwards.
+    let instrs = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 1, end: 5 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 1,
+            position: Some(Position { start: 6, end: 8 }),
+        },
+        Loop {
+            body: parse("->+++<").unwrap(),
+            position: Some(Position { start: 9, end: 16 }),
+        },
+    ];
+    let expected = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 1, end: 5 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 1,
+            position: Some(Position { start: 6, end: 8 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 9, end: 16 }),
+        },
+        Set {
+            amount: Wrapping(6),
+            offset: 1,
+            position: Some(Position { start: 9, end: 16 }),
+        },
+    ];
+    assert_eq!(evaluate_pure_loops(instrs, 8), expected);
+}
+
+#[test]
+fn should_evaluate_pure_loop_with_pointer_movement() {
+    // Move to the first zero cell.
+    let instrs = parse("+>+>+<<[>]").unwrap();
+    let result = evaluate_pure_loops(instrs, 8);
+    assert_eq!(
+        result.last(),
+        Some(&PointerIncrement {
+            amount: 3,
+            position: Some(Position { start: 7, end: 9 }),
+        })
+    );
+}
+
+#[test]
+fn should_evaluate_multiply_move() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(3));
+    let instrs = vec![
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        MultiplyMove {
+            changes,
+            adds: HashMap::new(),
+            position: Some(Position { start: 1, end: 7 }),
+        },
+    ];
+    let expected = vec![
+        Set {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 1, end: 7 }),
+        },
+        Set {
+            amount: Wrapping(6),
+            offset: 1,
+            position: Some(Position { start: 1, end: 7 }),
+        },
+    ];
+    assert_eq!(evaluate_pure_loops(instrs, 8), expected);
+}
+
+#[test]
+fn should_not_evaluate_loop_with_unknown_cell() {
+    let instrs = parse(",[->+<]").unwrap();
+    assert_eq!(evaluate_pure_loops(instrs.clone(), 8), instrs);
+}
+
+#[test]
+fn should_not_evaluate_loop_with_io() {
+    let instrs = parse("+++[-.]").unwrap();
+    assert_eq!(evaluate_pure_loops(instrs.clone(), 8), instrs);
+}
+
+#[test]
+fn should_not_evaluate_infinite_loop() {
+    let instrs = parse("+[]").unwrap();
+    assert_eq!(evaluate_pure_loops(instrs.clone(), 8), instrs);
+}
+
+#[test]
+fn should_evaluate_loop_with_cell_wrapping() {
+    // Counting up from 1 only terminates when the cell wraps around.
+    let instrs = parse("+[+>+<]").unwrap();
+    let result = evaluate_pure_loops(instrs, 8);
+    assert_eq!(
+        result.last(),
+        Some(&Set {
+            amount: Wrapping(-1),
+            offset: 1,
+            position: Some(Position { start: 1, end: 6 }),
+        })
+    );
+}
+
+#[test]
+fn should_unroll_counted_loop() {
+    let body = vec![
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+        Write { position: None },
+        PointerIncrement {
+            amount: -1,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: None,
+        },
+    ];
+    let set = Set {
+        amount: Wrapping(3),
+        offset: 0,
+        position: None,
+    };
+    let instrs = vec![
+        set.clone(),
+        Loop {
+            body: body.clone(),
+            position: None,
+        },
+    ];
+
+    let mut expected = vec![set];
+    for _ in 0..3 {
+        expected.extend(body.clone());
+    }
+    assert_eq!(unroll_counted_loops(instrs, 8), expected);
+}
+
+#[test]
+fn should_not_unroll_loop_with_unknown_cell() {
+    let instrs = parse(",[>.<-]").unwrap();
+    assert_eq!(unroll_counted_loops(instrs.clone(), 8), instrs);
+}
+
+#[test]
+fn should_not_unroll_loop_reading_its_cell() {
+    let instrs = annotate_known_zero(parse("+++[,.-]").unwrap());
+    let instrs = combine_set_and_increments(instrs);
+    assert_eq!(unroll_counted_loops(instrs.clone(), 8), instrs);
+}
+
+#[test]
+fn should_not_unroll_loop_moving_pointer() {
+    let instrs = annotate_known_zero(parse("+++[>.-]").unwrap());
+    let instrs = combine_set_and_increments(instrs);
+    assert_eq!(unroll_counted_loops(instrs.clone(), 8), instrs);
+}
+
+#[test]
+fn should_unroll_only_at_o3() {
+    // The loop writes, so we can't evaluate it, but at -O3 we
+    // unroll it and then fold the writes.
+    let instrs = parse("+++[>+++.<-]").unwrap();
+
+    let (result, _) = optimize(instrs.clone(), &OptConfig::default());
+    assert!(result.iter().any(|instr| matches!(instr, Loop { .. })));
+
+    let config = OptConfig {
+        level: 3,
+        ..OptConfig::default()
+    };
+    let (result, _) = optimize(instrs, &config);
+    assert!(!result.iter().any(|instr| matches!(instr, Loop { .. })));
+    assert!(result.iter().any(|instr| match instr {
+        WriteString { bytes, .. } => bytes == &[3, 6, 9],
+        _ => false,
+    }));
+}
+
+#[test]
+fn should_combine_reads() {
+    let instrs = parse(",,,.").unwrap();
+    let expected = vec![
+        SkipRead {
+            count: 3,
+            position: Some(Position { start: 0, end: 2 }),
+        },
+        Write {
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    assert_eq!(combine_reads(instrs), expected);
+}
+
+#[test]
+fn should_combine_skip_read_with_read() {
+    let instrs = vec![
+        SkipRead {
+            count: 2,
+            position: Some(Position { start: 0, end: 1 }),
+        },
+        Read {
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    let expected = vec![SkipRead {
+        count: 3,
+        position: Some(Position { start: 0, end: 2 }),
+    }];
+    assert_eq!(combine_reads(instrs), expected);
+}
+
+#[test]
+fn should_not_combine_separated_reads() {
+    let instrs = parse(",>,").unwrap();
+    assert_eq!(combine_reads(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_fold_constant_writes() {
+    let instrs = parse("+.+.").unwrap();
+    let expected = vec![
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        WriteString {
+            bytes: vec![1, 2],
+            position: Some(Position { start: 1, end: 3 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    assert_eq!(fold_constant_writes(instrs), expected);
+}
+
+#[test]
+fn should_not_fold_writes_after_read() {
+    let instrs = parse(",.").unwrap();
+    assert_eq!(fold_constant_writes(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_fold_writes_in_loop_after_set() {
+    let instrs = vec![
+        Read { position: None },
+        Loop {
+            body: vec![
+                Set {
+                    amount: Wrapping(65),
+                    offset: 0,
+                    position: None,
+                },
+                Write { position: None },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: None,
+                },
+            ],
+            position: None,
+        },
+    ];
+    let expected = vec![
+        Read { position: None },
+        Loop {
+            body: vec![
+                Set {
+                    amount: Wrapping(65),
+                    offset: 0,
+                    position: None,
+                },
+                WriteString {
+                    bytes: vec![65],
+                    position: None,
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: None,
+                },
+            ],
+            position: None,
+        },
+    ];
+    assert_eq!(fold_constant_writes(instrs), expected);
+}
+
+#[test]
+fn should_not_fold_writes_across_reads() {
+    let instrs = parse("+.,>.").unwrap();
+    let result = fold_constant_writes(instrs);
+
+    let strings: Vec<_> = result
+        .iter()
+        .filter(|instr| matches!(instr, WriteString { .. }))
+        .collect();
+    assert_eq!(strings.len(), 2);
+}
+
+#[test]
+fn should_absorb_adds_into_multiply() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let instrs = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            adds: HashMap::new(),
+            position: Some(Position { start: 0, end: 5 }),
+        },
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: Some(Position { start: 7, end: 8 }),
+        },
+    ];
+
+    let mut adds = HashMap::new();
+    adds.insert(1, Wrapping(2));
+    let expected = vec![MultiplyMove {
+        changes,
+        adds,
+        position: Some(Position { start: 0, end: 8 }),
+    }];
+    assert_eq!(absorb_multiply_adds(instrs), expected);
+}
+
+#[test]
+fn should_absorb_adds_past_other_cells() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let instrs = vec![
+        MultiplyMove {
+            changes: changes.clone(),
+            adds: HashMap::new(),
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(5),
+            offset: 2,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: None,
+        },
+    ];
+
+    let mut adds = HashMap::new();
+    adds.insert(1, Wrapping(2));
+    let expected = vec![
+        MultiplyMove {
+            changes,
+            adds,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(5),
+            offset: 2,
+            position: None,
+        },
+    ];
+    assert_eq!(absorb_multiply_adds(instrs), expected);
+}
+
+#[test]
+fn should_not_absorb_adds_after_source_changes() {
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let instrs = vec![
+        MultiplyMove {
+            changes,
+            adds: HashMap::new(),
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(2),
+            offset: 1,
+            position: None,
+        },
+    ];
+    assert_eq!(absorb_multiply_adds(instrs.clone()), instrs);
+}
+
+#[test]
+fn should_fold_copy_loop_and_adds() {
+    let instrs = parse(",[->+<]>++.").unwrap();
+    let result = optimize(instrs, &OptConfig::default()).0;
+
+    let mut changes = HashMap::new();
+    changes.insert(1, Wrapping(1));
+    let mut adds = HashMap::new();
+    adds.insert(1, Wrapping(2));
+    assert!(matches!(
+        result[1],
+        MultiplyMove { changes: ref c, adds: ref a, .. } if *c == changes && *a == adds
+    ));
+}
+
+fn is_pure(instrs: &[AstNode]) -> bool {
+    for instr in instrs {
+        match *instr {
+            Loop { .. } => {
+                return false;
+            }
+            Read { .. } | SkipRead { .. } => {
+                return false;
+            }
+            Write { .. } | WriteString { .. } => {
+                return false;
+            }
+            _ => (),
+        }
+    }
+    true
+}
+
+#[test]
+fn quickcheck_should_annotate_known_zero_at_start() {
+    fn should_annotate_known_zero_at_start(instrs: Vec<AstNode>) -> bool {
+        let annotated = annotate_known_zero(instrs);
+        matches!(annotated[0], Set { amount: Wrapping(0), offset: 0, .. })
+    }
+    quickcheck(should_annotate_known_zero_at_start as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn annotate_known_zero_idempotent() {
+    fn is_idempotent(instrs: Vec<AstNode>) -> bool {
+        let annotated = annotate_known_zero(instrs);
+        let annotated_again = annotate_known_zero(annotated.clone());
+        if annotated == annotated_again {
+            true
+        } else {
+            println!("intermediate: {:?}", annotated);
+            println!("final: {:?}", annotated_again);
+            false
+        }
+    }
+    quickcheck(is_idempotent as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn should_annotate_known_zero() {
+    let initial = parse("+[]").unwrap();
+    let expected = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![],
+            position: Some(Position { start: 1, end: 2 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+    assert_eq!(annotate_known_zero(initial), expected);
+}
+
+#[test]
+fn should_annotate_known_zero_nested() {
+    let initial = parse("[[]]").unwrap();
+    let expected = vec![
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![
+                Loop {
+                    body: vec![],
+                    position: Some(Position { start: 1, end: 2 }),
+                },
+                Set {
+                    amount: Wrapping(0),
+                    offset: 0,
+                    position: Some(Position { start: 2, end: 2 }),
+                },
+            ],
+            position: Some(Position { start: 0, end: 3 }),
+        },
+        Set {
+            amount: Wrapping(0),
+            offset: 0,
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    assert_eq!(annotate_known_zero(initial), expected);
+}
+
+/// When we annotate known zeroes, we have new opportunities for
+/// combining instructions and loop removal. However, we should later
+/// remove the Set 0 if we haven't combined it.
+#[test]
+fn should_annotate_known_zero_cleaned_up() {
+    let initial = vec![Write {
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    let expected = vec![WriteString {
+        bytes: vec![0],
+        position: Some(Position { start: 0, end: 0 }),
+    }];
+    assert_eq!(optimize(initial, &OptConfig::default()).0, expected);
+}
+
+#[test]
+fn should_preserve_set_0_in_loop() {
+    // Regression test.
+    let initial = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Loop {
+            body: vec![Set {
+                amount: Wrapping(0),
+                offset: 0,
+                position: Some(Position { start: 0, end: 0 }),
+            }],
+            position: Some(Position { start: 0, end: 0 }),
+        },
+    ];
+    assert_eq!(optimize(initial.clone(), &OptConfig::default()).0, initial);
+}
+
+#[test]
+fn should_remove_pure_code() {
+    // The final increment here is side-effect free and can be
+    // removed.
+    let initial = parse(",+.+").unwrap();
+    let expected = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 1, end: 1 }),
+        },
+        Write {
+            position: Some(Position { start: 2, end: 2 }),
+        },
+    ];
+
+    let (result, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(result, expected);
+    assert_eq!(
+        warnings,
+        vec![Warning {
+            lint: Lint::RedundantCode,
+            message: "These instructions have no effect.".to_owned(),
+            position: Some(Position { start: 3, end: 3 }),
+            notes: vec![Note::explain(
+                "Nothing reads or writes after them, so their changes are never seen."
+            )],
+        }]
+    );
+}
+
+fn lints(warnings: &[Warning]) -> Vec<Lint> {
+    warnings.iter().map(|warning| warning.lint).collect()
+}
+
+#[test]
+fn should_warn_on_dead_loop() {
+    let initial = parse("+.[-][.].").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(lints(&warnings), vec![Lint::DeadLoop]);
+    assert_eq!(warnings[0].position, Some(Position { start: 5, end: 7 }));
+}
+
+#[test]
+fn should_not_warn_on_comment_loop() {
+    let initial = parse("[this is a comment.]+.").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn should_not_warn_on_comment_loop_after_loop() {
+    // From sample_programs/life.bf. The loop before the comment
+    // leaves the cell zero.
+    let initial = parse(",[>>>+>+<<<<-][   lft@df.lth.se   ]>>>>.").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn should_not_warn_on_dead_loop_without_commands() {
+    let initial = parse("+.[-][no commands here].").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn should_warn_on_infinite_loop() {
+    let initial = parse(",[-]+[]").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(lints(&warnings), vec![Lint::InfiniteLoop]);
+    assert_eq!(warnings[0].position, Some(Position { start: 5, end: 6 }));
+}
+
+#[test]
+fn should_not_warn_on_possibly_finite_loop() {
+    let initial = parse(",[]").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn should_warn_on_tape_underflow() {
+    let initial = parse("+><<.").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(lints(&warnings), vec![Lint::TapeUnderflow]);
+}
+
+#[test]
+fn should_warn_on_tape_underflow_after_balanced_loop() {
+    // We know exactly where the pointer is after a balanced loop.
+    let initial = parse(",[->+<]<.").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(lints(&warnings), vec![Lint::TapeUnderflow]);
+}
+
+#[test]
+fn should_not_warn_on_underflow_after_loop() {
+    // We don't know where the pointer is after a loop.
+    let initial = parse(",[>]<.").unwrap();
+    let (_, warnings) = optimize(initial, &OptConfig::default());
+
+    assert_eq!(warnings, vec![]);
+}
+
+#[test]
+fn quickcheck_should_remove_dead_pure_code() {
+    fn should_remove_dead_pure_code(instrs: Vec<AstNode>) -> TestResult {
+        if !is_pure(&instrs) {
+            return TestResult::discard();
+        }
+        TestResult::from_bool(optimize(instrs, &OptConfig::default()).0 == vec![])
+    }
+    quickcheck(should_remove_dead_pure_code as fn(Vec<AstNode>) -> TestResult);
+}
+
+#[test]
+fn quickcheck_optimize_should_be_idempotent() {
+    fn optimize_should_be_idempotent(instrs: Vec<AstNode>) -> bool {
+        // Once we've optimized once, running again shouldn't reduce the
+        // instructions further. If it does, we're probably running our
+        // optimisations in the wrong order.
+        let minimal = optimize(instrs.clone(), &OptConfig::default()).0;
+        optimize(minimal.clone(), &OptConfig::default()).0 == minimal
+    }
+    quickcheck(optimize_should_be_idempotent as fn(Vec<AstNode>) -> bool);
+}
+
+#[test]
+fn pathological_optimisation_opportunity() {
+    let instrs = vec![
+        Read {
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: 1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        PointerIncrement {
+            amount: -1,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Increment {
+            amount: Wrapping(-1),
+            offset: 0,
+            position: Some(Position { start: 0, end: 0 }),
+        },
+        Write {
+            position: Some(Position { start: 0, end: 0 }),
         },
     ];
 
@@ -984,7 +1834,7 @@ fn pathological_optimisation_opportunity() {
         },
     ];
 
-    assert_eq!(optimize(instrs, &None).0, expected);
+    assert_eq!(optimize(instrs, &OptConfig::default()).0, expected);
 }
 
 fn count_instrs(instrs: &[AstNode]) -> u64 {
@@ -1003,12 +1853,112 @@ fn quickcheck_optimize_should_decrease_size() {
     fn optimize_should_decrease_size(instrs: Vec<AstNode>) -> bool {
         // The result of optimize() should never increase the number of
         // instructions.
-        let result = optimize(instrs.clone(), &None).0;
+        let result = optimize(instrs.clone(), &OptConfig::default()).0;
         count_instrs(&result) <= count_instrs(&instrs)
     }
     quickcheck(optimize_should_decrease_size as fn(Vec<AstNode>) -> bool);
 }
 
+#[test]
+fn optimize_with_stats_accounts_for_every_node() {
+    let instrs = parse("+++[->+<]>>><<<.").unwrap();
+    let config = OptConfig::default();
+    let (result, warnings, stats) = optimize_with_stats(instrs.clone(), &config);
+    assert_eq!(
+        optimize(instrs.clone(), &config),
+        (result.clone(), warnings)
+    );
+
+    let removed: i64 = stats.iter().map(|pass| pass.removed).sum();
+    assert_eq!(
+        removed,
+        count_instrs(&instrs) as i64 - count_instrs(&result) as i64
+    );
+    assert_eq!(stats[0].name, PASS_NAMES[0]);
+    assert!(stats.iter().all(|pass| pass.runs > 0));
+}
+
+#[test]
+fn optimize_without_fuel_does_nothing() {
+    let instrs = parse("+[->+<]>++<<.").unwrap();
+    let config = OptConfig {
+        fuel: Some(0),
+        ..OptConfig::default()
+    };
+    assert_eq!(optimize(instrs.clone(), &config).0, instrs);
+}
+
+#[test]
+fn optimize_stops_when_fuel_runs_out() {
+    let instrs = parse("+++.").unwrap();
+    let config = OptConfig {
+        pass_specification: Some("combine_inc".to_owned()),
+        fuel: Some(1),
+        ..OptConfig::default()
+    };
+    let expected = vec![
+        Increment {
+            amount: Wrapping(2),
+            offset: 0,
+            position: Some(Position { start: 0, end: 1 }),
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 0,
+            position: Some(Position { start: 2, end: 2 }),
+        },
+        Write {
+            position: Some(Position { start: 3, end: 3 }),
+        },
+    ];
+    assert_eq!(optimize(instrs, &config).0, expected);
+}
+
+#[test]
+fn fuel_only_limits_its_own_optimize_call() {
+    let instrs = parse("+++.").unwrap();
+    let config = OptConfig {
+        fuel: Some(0),
+        ..OptConfig::default()
+    };
+    optimize(instrs.clone(), &config);
+
+    let (combined, _) = run_pass("combine_inc", instrs).unwrap();
+    assert_eq!(combined.len(), 2);
+}
+
+#[test]
+fn optimize_for_size_keeps_multiplies_and_writes() {
+    let instrs = parse("+[->++<]>.").unwrap();
+    let config = OptConfig {
+        goal: OptGoal::Size,
+        ..OptConfig::default()
+    };
+    let (result, _) = optimize(instrs, &config);
+    assert!(matches!(result[1], MultiplyMove { .. }));
+    assert!(matches!(result.last(), Some(Write { .. })));
+
+    let (result, _) = optimize(parse("+[->++<]>.").unwrap(), &OptConfig::default());
+    assert!(matches!(result.last(), Some(WriteString { .. })));
+}
+
+#[test]
+fn optimize_for_size_is_smaller() {
+    // At -O2 we evaluate the loop, setting each cell separately. At
+    // -Os we only turn it into a multiply.
+    let instrs = parse("++++[>+>+>+>+>+>+>+>+<<<<<<<<-]>[.>]").unwrap();
+    let config = OptConfig {
+        goal: OptGoal::Size,
+        ..OptConfig::default()
+    };
+
+    let (speed_result, _) = optimize(instrs.clone(), &OptConfig::default());
+    let (size_result, _) = optimize(instrs, &config);
+
+    assert!(matches!(size_result[1], MultiplyMove { .. }));
+    assert!(count_instrs(&size_result) < count_instrs(&speed_result));
+}
+
 #[test]
 fn should_extract_multiply_simple() {
     let instrs = parse("[->+++<]").unwrap();
@@ -1017,6 +1967,7 @@ fn should_extract_multiply_simple() {
     dest_cells.insert(1, Wrapping(3));
     let expected = vec![MultiplyMove {
         changes: dest_cells,
+        adds: HashMap::new(),
         position: Some(Position { start: 0, end: 7 }),
     }];
 
@@ -1032,6 +1983,7 @@ fn should_extract_multiply_nested() {
     let expected = vec![Loop {
         body: vec![MultiplyMove {
             changes: dest_cells,
+            adds: HashMap::new(),
             position: Some(Position { start: 1, end: 6 }),
         }],
         position: Some(Position { start: 0, end: 7 }),
@@ -1048,6 +2000,7 @@ fn should_extract_multiply_negative_number() {
     dest_cells.insert(1, Wrapping(-2));
     let expected = vec![MultiplyMove {
         changes: dest_cells,
+        adds: HashMap::new(),
         position: Some(Position { start: 0, end: 6 }),
     }];
 
@@ -1063,6 +2016,7 @@ fn should_extract_multiply_multiple_cells() {
     dest_cells.insert(4, Wrapping(1));
     let expected = vec![MultiplyMove {
         changes: dest_cells,
+        adds: HashMap::new(),
         position: Some(Position { start: 0, end: 14 }),
     }];
 
@@ -1123,7 +2077,7 @@ fn sort_by_offset_increment() {
         },
         PointerIncrement {
             amount: 2,
-            position: Some(Position { start: 3, end: 3 }),
+            position: Some(Position { start: 1, end: 3 }),
         },
     ];
     assert_eq!(sort_by_offset(instrs), expected);
@@ -1146,7 +2100,7 @@ fn sort_by_offset_increment_nested() {
             },
             PointerIncrement {
                 amount: 2,
-                position: Some(Position { start: 4, end: 4 }),
+                position: Some(Position { start: 2, end: 4 }),
             },
         ]),
         position: Some(Position { start: 0, end: 5 }),
@@ -1168,14 +2122,14 @@ fn sort_by_offset_read() {
     let expected = vec![
         PointerIncrement {
             amount: 2,
-            position: Some(Position { start: 1, end: 1 }),
+            position: Some(Position { start: 0, end: 1 }),
         },
         Read {
             position: Some(Position { start: 2, end: 2 }),
         },
         PointerIncrement {
             amount: 2,
-            position: Some(Position { start: 4, end: 4 }),
+            position: Some(Position { start: 3, end: 4 }),
         },
     ];
     assert_eq!(sort_by_offset(instrs), expected);
@@ -1183,7 +2137,7 @@ fn sort_by_offset_read() {
 
 #[test]
 fn quickcheck_sort_by_offset_set() {
-    fn sort_by_offset_set(amount1: i8, amount2: i8) -> bool {
+    fn sort_by_offset_set(amount1: i32, amount2: i32) -> bool {
         let instrs = vec![
             Set {
                 amount: Wrapping(amount1),
@@ -1219,7 +2173,7 @@ fn quickcheck_sort_by_offset_set() {
         ];
         sort_by_offset(instrs) == expected
     }
-    quickcheck(sort_by_offset_set as fn(i8, i8) -> bool);
+    quickcheck(sort_by_offset_set as fn(i32, i32) -> bool);
 }
 
 #[test]
@@ -1274,7 +2228,7 @@ fn combine_increments_non_adjacent_instrs() {
     let expected = vec![Increment {
         amount: Wrapping(2),
         offset: 0,
-        position: Some(Position { start: 2, end: 2 }),
+        position: Some(Position { start: 0, end: 2 }),
     }];
     assert_eq!(combine_increments(instrs), expected);
 }
@@ -1298,7 +2252,7 @@ fn combine_set_and_increment_non_adjacent_instrs() {
     let expected = vec![Set {
         amount: Wrapping(2),
         offset: 0,
-        position: Some(Position { start: 2, end: 2 }),
+        position: Some(Position { start: 0, end: 2 }),
     }];
     assert_eq!(combine_set_and_increments(instrs), expected);
 }
@@ -1315,7 +2269,7 @@ fn combine_increments_after_sort() {
         Increment {
             amount: Wrapping(2),
             offset: 0,
-            position: Some(Position { start: 5, end: 5 }),
+            position: Some(Position { start: 1, end: 5 }),
         },
         Increment {
             amount: Wrapping(1),
@@ -1326,7 +2280,7 @@ fn combine_increments_after_sort() {
             position: Some(Position { start: 6, end: 6 }),
         },
     ];
-    assert_eq!(optimize(instrs, &None).0, expected);
+    assert_eq!(optimize(instrs, &OptConfig::default()).0, expected);
 }
 
 #[test]
@@ -1386,6 +2340,7 @@ fn prev_mutate_multiply_offset_matches() {
     let instrs = vec![
         MultiplyMove {
             changes: changes.clone(),
+            adds: HashMap::new(),
             position: Some(Position { start: 0, end: 0 }),
         },
         PointerIncrement {
@@ -1407,6 +2362,7 @@ fn prev_mutate_multiply_offset_doesnt_match() {
     let instrs = vec![
         MultiplyMove {
             changes: changes.clone(),
+            adds: HashMap::new(),
             position: Some(Position { start: 0, end: 0 }),
         },
         PointerIncrement {
@@ -1430,6 +2386,7 @@ fn prev_mutate_multiply_ignore_offset() {
     let instrs = vec![
         MultiplyMove {
             changes: changes.clone(),
+            adds: HashMap::new(),
             position: Some(Position { start: 0, end: 0 }),
         },
         Read {
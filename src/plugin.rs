@@ -0,0 +1,411 @@
+//! Optimisation passes loaded from shared libraries at runtime.
+//!
+//! A pass plugin is a shared library that exports the following C
+//! functions:
+//!
+//! ```c
+//! // Must return BFC_PASS_ABI_VERSION (currently 4).
+//! uint32_t bfc_pass_abi_version(void);
+//! // The name used to enable this pass with --passes.
+//! const char *bfc_pass_name(void);
+//! // Transform the serialised IR in `ir`, returning 0 on success and
+//! // storing the new serialised IR in `out`.
+//! int32_t bfc_pass_run(const uint8_t *ir, size_t ir_len,
+//!                      uint8_t **out, size_t *out_len);
+//! // Release a buffer previously returned by bfc_pass_run.
+//! void bfc_pass_free(uint8_t *out, size_t out_len);
+//! ```
+//!
+//! IR is exchanged in the text format defined by `bfir::serialize`.
+//! Plugins are run in a child process, so a plugin that crashes or
+//! returns malformed IR produces a warning rather than taking down
+//! the compiler. This needs `dlopen` and `fork`, so plugins are only
+//! supported on Unix.
+
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+#[cfg(unix)]
+use std::os::raw::{c_char, c_void};
+#[cfg(unix)]
+use std::ptr::null_mut;
+
+use crate::bfir::{self, AstNode};
+use crate::diagnostics::{Code, Info, Lint, Warning};
+
+/// The version of the plugin interface implemented by this version
+/// of bfc. This is bumped whenever the function signatures or the
+/// serialised IR format change.
+pub const ABI_VERSION: u32 = 4;
+
+#[cfg(unix)]
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+#[cfg(unix)]
+type NameFn = unsafe extern "C" fn() -> *const c_char;
+#[cfg(unix)]
+type RunFn = unsafe extern "C" fn(*const u8, usize, *mut *mut u8, *mut usize) -> i32;
+#[cfg(unix)]
+type FreeFn = unsafe extern "C" fn(*mut u8, usize);
+
+/// An optimisation pass provided by a shared library.
+pub struct PassPlugin {
+    pub name: String,
+    path: String,
+    #[cfg(unix)]
+    handle: *mut c_void,
+    #[cfg(unix)]
+    run: RunFn,
+    #[cfg(unix)]
+    free: FreeFn,
+}
+
+/// Return the most recent dynamic loader error.
+#[cfg(unix)]
+fn dl_error() -> String {
+    unsafe {
+        let err = libc::dlerror();
+        if err.is_null() {
+            "unknown error".to_owned()
+        } else {
+            CStr::from_ptr(err).to_string_lossy().into_owned()
+        }
+    }
+}
+
+/// Look up `symbol` in the library `handle`.
+#[cfg(unix)]
+unsafe fn find_symbol(handle: *mut c_void, path: &str, symbol: &str) -> Result<*mut c_void, Info> {
+    let c_symbol = CString::new(symbol).unwrap();
+    let ptr = libc::dlsym(handle, c_symbol.as_ptr());
+    if ptr.is_null() {
+        Err(Info::error(
+            Code::PluginError,
+            format!(
+                "Pass plugin {} does not export {}: {}",
+                path,
+                symbol,
+                dl_error()
+            ),
+        ))
+    } else {
+        Ok(ptr)
+    }
+}
+
+impl PassPlugin {
+    /// Load the pass plugin at `path`, checking that it was built
+    /// against a compatible plugin interface.
+    #[cfg(unix)]
+    pub fn load(path: &str) -> Result<Self, Info> {
+        let c_path = match CString::new(path) {
+            Ok(c_path) => c_path,
+            Err(_) => {
+                return Err(Info::error(
+                    Code::PluginError,
+                    format!("Invalid plugin path: {}", path),
+                ))
+            }
+        };
+
+        unsafe {
+            let handle = libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL);
+            if handle.is_null() {
+                return Err(Info::error(
+                    Code::PluginError,
+                    format!("Could not load pass plugin {}: {}", path, dl_error()),
+                ));
+            }
+
+            let symbols = (
+                find_symbol(handle, path, "bfc_pass_abi_version"),
+                find_symbol(handle, path, "bfc_pass_name"),
+                find_symbol(handle, path, "bfc_pass_run"),
+                find_symbol(handle, path, "bfc_pass_free"),
+            );
+            let (abi_version, name, run, free) = match symbols {
+                (Ok(abi_version), Ok(name), Ok(run), Ok(free)) => (
+                    std::mem::transmute::<*mut c_void, AbiVersionFn>(abi_version),
+                    std::mem::transmute::<*mut c_void, NameFn>(name),
+                    std::mem::transmute::<*mut c_void, RunFn>(run),
+                    std::mem::transmute::<*mut c_void, FreeFn>(free),
+                ),
+                (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+                    libc::dlclose(handle);
+                    return Err(e);
+                }
+            };
+
+            let version = abi_version();
+            if version != ABI_VERSION {
+                libc::dlclose(handle);
+                return Err(Info::error(
+                    Code::PluginError,
+                    format!(
+                        "Pass plugin {} uses plugin ABI version {}, but bfc requires version {}",
+                        path, version, ABI_VERSION
+                    ),
+                ));
+            }
+
+            let name_ptr = name();
+            if name_ptr.is_null() {
+                libc::dlclose(handle);
+                return Err(Info::error(
+                    Code::PluginError,
+                    format!("Pass plugin {} did not provide a name", path),
+                ));
+            }
+            let name = CStr::from_ptr(name_ptr).to_string_lossy().into_owned();
+
+            Ok(PassPlugin {
+                name,
+                path: path.to_owned(),
+                handle,
+                run,
+                free,
+            })
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn load(path: &str) -> Result<Self, Info> {
+        Err(Info::error(
+            Code::PluginError,
+            format!(
+                "Could not load pass plugin {}: pass plugins are only supported on Unix",
+                path
+            ),
+        ))
+    }
+
+    /// Run this pass over `instrs`. If the plugin fails, we return
+    /// the original instructions unchanged along with a warning.
+    pub fn run(&self, instrs: Vec<AstNode>) -> (Vec<AstNode>, Option<Warning>) {
+        match self.run_in_child(&bfir::serialize(&instrs)) {
+            Ok(output) => match bfir::deserialize(&output) {
+                Ok(new_instrs) => (new_instrs, None),
+                Err(e) => (
+                    instrs,
+                    Some(self.failure_warning(&format!("returned invalid IR: {}", e))),
+                ),
+            },
+            Err(e) => (instrs, Some(self.failure_warning(&e))),
+        }
+    }
+
+    fn failure_warning(&self, reason: &str) -> Warning {
+        Warning {
+            lint: Lint::PluginFailure,
+            message: format!(
+                "Pass plugin '{}' ({}) {}. Its output was ignored.",
+                self.name, self.path, reason
+            ),
+            position: None,
+            notes: vec![],
+        }
+    }
+
+    /// Call `bfc_pass_run` in a forked child process, so crashes in
+    /// the plugin don't affect the compiler. Returns the serialised
+    /// IR produced, or a description of how the plugin failed.
+    #[cfg(unix)]
+    fn run_in_child(&self, input: &str) -> Result<String, String> {
+        unsafe {
+            let mut fds = [0; 2];
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return Err("could not be started (pipe failed)".to_owned());
+            }
+            let (read_fd, write_fd) = (fds[0], fds[1]);
+
+            let pid = libc::fork();
+            if pid < 0 {
+                libc::close(read_fd);
+                libc::close(write_fd);
+                return Err("could not be started (fork failed)".to_owned());
+            }
+
+            if pid == 0 {
+                // Child: run the pass and send the result to the parent.
+                libc::close(read_fd);
+
+                let mut out: *mut u8 = null_mut();
+                let mut out_len: usize = 0;
+                let status = (self.run)(input.as_ptr(), input.len(), &mut out, &mut out_len);
+
+                let exit_code = if status == 0 && !out.is_null() {
+                    write_all(write_fd, std::slice::from_raw_parts(out, out_len));
+                    (self.free)(out, out_len);
+                    0
+                } else {
+                    write_all(write_fd, status.to_string().as_bytes());
+                    1
+                };
+                libc::close(write_fd);
+                libc::_exit(exit_code);
+            }
+
+            // Parent: read everything the child sends, then reap it.
+            libc::close(write_fd);
+            let mut output = vec![];
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = libc::read(read_fd, buf.as_mut_ptr() as *mut c_void, buf.len());
+                if n <= 0 {
+                    break;
+                }
+                output.extend_from_slice(&buf[..n as usize]);
+            }
+            libc::close(read_fd);
+
+            let mut wait_status = 0;
+            libc::waitpid(pid, &mut wait_status, 0);
+
+            let output = String::from_utf8_lossy(&output).into_owned();
+            if libc::WIFSIGNALED(wait_status) {
+                Err(format!("crashed (signal {})", libc::WTERMSIG(wait_status)))
+            } else if libc::WIFEXITED(wait_status) && libc::WEXITSTATUS(wait_status) == 0 {
+                Ok(output)
+            } else {
+                Err(format!("returned error code {}", output))
+            }
+        }
+    }
+
+    // `load` always fails on other hosts, so this is never called.
+    #[cfg(not(unix))]
+    fn run_in_child(&self, _input: &str) -> Result<String, String> {
+        Err("is not supported on this host".to_owned())
+    }
+}
+
+/// Write all of `bytes` to the file descriptor `fd`.
+#[cfg(unix)]
+unsafe fn write_all(fd: i32, mut bytes: &[u8]) {
+    while !bytes.is_empty() {
+        let n = libc::write(fd, bytes.as_ptr() as *const c_void, bytes.len());
+        if n <= 0 {
+            return;
+        }
+        bytes = &bytes[n as usize..];
+    }
+}
+
+#[cfg(unix)]
+impl Drop for PassPlugin {
+    fn drop(&mut self) {
+        unsafe {
+            libc::dlclose(self.handle);
+        }
+    }
+}
+
+// The fixtures need a C compiler and Unix shared libraries.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::process::Command;
+
+    use tempfile::NamedTempFileOptions;
+
+    use crate::bfir::parse;
+    use crate::bfir::AstNode::*;
+
+    /// A pass plugin that appends a `write` to the IR it's given.
+    /// Define ABI_VERSION to choose the version it reports, and CRASH
+    /// to make it abort.
+    const FIXTURE_SOURCE: &str = r#"
+#include <stdint.h>
+#include <stdlib.h>
+#include <string.h>
+
+uint32_t bfc_pass_abi_version(void) { return ABI_VERSION; }
+
+const char *bfc_pass_name(void) { return "append_write"; }
+
+int32_t bfc_pass_run(const uint8_t *ir, size_t ir_len,
+                     uint8_t **out, size_t *out_len) {
+#ifdef CRASH
+    abort();
+#endif
+    const char *suffix = "write -\n";
+    *out_len = ir_len + strlen(suffix);
+    *out = malloc(*out_len);
+    memcpy(*out, ir, ir_len);
+    memcpy(*out + ir_len, suffix, strlen(suffix));
+    return 0;
+}
+
+void bfc_pass_free(uint8_t *out, size_t out_len) { free(out); }
+"#;
+
+    /// Build the fixture plugin with cc and load it, passing `-D` for
+    /// each of `defines`.
+    fn load_fixture(defines: &[String]) -> Result<PassPlugin, Info> {
+        let source = NamedTempFileOptions::new().suffix(".c").create().unwrap();
+        fs::write(source.path(), FIXTURE_SOURCE).unwrap();
+        let library_path = format!("{}.so", source.path().to_str().unwrap());
+        let status = Command::new("cc")
+            .args(["-shared", "-fPIC", "-o", &library_path])
+            .arg(source.path())
+            .args(defines.iter().map(|define| format!("-D{}", define)))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let plugin = PassPlugin::load(&library_path);
+        fs::remove_file(&library_path).unwrap();
+        plugin
+    }
+
+    fn current_abi_version() -> String {
+        format!("ABI_VERSION={}", ABI_VERSION)
+    }
+
+    #[test]
+    fn load_missing_plugin() {
+        assert!(PassPlugin::load("/nonexistent/libmissing.so").is_err());
+    }
+
+    #[test]
+    fn load_plugin_with_wrong_abi_version() {
+        let defines = [format!("ABI_VERSION={}", ABI_VERSION + 1)];
+        let message = match load_fixture(&defines) {
+            Ok(_) => panic!("Loaded a plugin with the wrong ABI version"),
+            Err(info) => info.message,
+        };
+        assert!(message.contains(&format!(
+            "uses plugin ABI version {}, but bfc requires version {}",
+            ABI_VERSION + 1,
+            ABI_VERSION
+        )));
+    }
+
+    #[test]
+    fn run_plugin_round_trips_ir() {
+        let plugin = load_fixture(&[current_abi_version()]).unwrap();
+        assert_eq!(plugin.name, "append_write");
+
+        let instrs = parse("+[->+<],").unwrap();
+        let mut expected = instrs.clone();
+        expected.push(Write { position: None });
+
+        assert_eq!(plugin.run(instrs), (expected, None));
+    }
+
+    #[test]
+    fn crashing_plugin_warns() {
+        let plugin = load_fixture(&[current_abi_version(), "CRASH".to_owned()]).unwrap();
+
+        let instrs = parse("+[->+<],").unwrap();
+        let (result, warning) = plugin.run(instrs.clone());
+
+        // The compiler survives, and keeps the IR it had.
+        assert_eq!(result, instrs);
+        let warning = warning.unwrap();
+        assert_eq!(warning.lint, Lint::PluginFailure);
+        assert!(warning
+            .message
+            .contains(&format!("crashed (signal {})", libc::SIGABRT)));
+    }
+}
@@ -1,11 +1,32 @@
 //! This module defines a convenient API for shelling out to commands,
 //! handling stderr when they fail.
 
+use std::cell::RefCell;
 use std::process::Command;
-use crate::diagnostics::Info;
+use std::time::{Duration, Instant};
+use bfc::diagnostics::{Code, Info};
 
 // TODO: release this as a simple rust package.
 
+/// A command we ran, for --log-file.
+#[derive(Debug, Clone)]
+pub struct CommandRun {
+    pub program: String,
+    pub args: Vec<String>,
+    pub success: bool,
+    pub time: Duration,
+}
+
+thread_local! {
+    /// Every command run since the last `take_commands`.
+    static COMMANDS: RefCell<Vec<CommandRun>> = const { RefCell::new(vec![]) };
+}
+
+/// The commands run since the last call, oldest first.
+pub fn take_commands() -> Vec<CommandRun> {
+    COMMANDS.with(|commands| commands.replace(vec![]))
+}
+
 /// Execute the CLI command specified. If the command succeeds,
 /// returns stdout.
 ///
@@ -20,17 +41,31 @@ fn shell_command(command: &str, args: &[&str]) -> Result<String, Info> {
         c.arg(arg);
     }
 
-    match c.output() {
+    let start = Instant::now();
+    let output = c.output();
+    COMMANDS.with(|commands| {
+        commands.borrow_mut().push(CommandRun {
+            program: command.to_owned(),
+            args: args.iter().map(|arg| (*arg).to_owned()).collect(),
+            success: matches!(&output, Ok(result) if result.status.success()),
+            time: start.elapsed(),
+        })
+    });
+
+    match output {
         Ok(result) => {
             if result.status.success() {
                 let stdout = String::from_utf8_lossy(&result.stdout);
                 Ok((*stdout).to_owned())
             } else {
                 let stderr = String::from_utf8_lossy(&result.stderr);
-                Err(Info::error((*stderr).to_owned()))
+                Err(Info::error(Code::ToolFailure, (*stderr).to_owned()))
             }
         }
-        Err(_) => Err(Info::error(format!("Could not execute '{}'. Is it on $PATH?", command))),
+        Err(_) => Err(Info::error(
+            Code::ToolFailure,
+            format!("Could not execute '{}'. Is it on $PATH?", command),
+        )),
     }
 }
 
@@ -1,8 +1,16 @@
+use std::collections::HashMap;
+use std::num::Wrapping;
+
 use quickcheck::{quickcheck, TestResult};
 
-use crate::bfir::AstNode;
+use crate::bfir::AstNode::*;
+use crate::bfir::{parse, AstNode, EofBehaviour, DEFAULT_CELL_BITS};
+use crate::bounds::{highest_cell_index, MAX_CELL_INDEX};
 use crate::execution::Outcome::*;
-use crate::execution::{execute_with_state, ExecutionState};
+use crate::execution::{
+    execute_with_limits, execute_with_state, interpret_residual, interpret_with_limits,
+    ExecutionState, ExitStatus, Limits, Tape,
+};
 use crate::peephole::*;
 
 fn transform_is_sound<F>(
@@ -11,6 +19,27 @@ fn transform_is_sound<F>(
     check_cells: bool,
     dummy_read_value: Option<i8>,
 ) -> TestResult
+where
+    F: Fn(Vec<AstNode>) -> Vec<AstNode>,
+{
+    transform_is_sound_with_cell_bits(
+        instrs,
+        transform,
+        check_cells,
+        dummy_read_value,
+        DEFAULT_CELL_BITS,
+    )
+}
+
+/// As `transform_is_sound`, but executing with cells of `cell_bits`
+/// bits.
+fn transform_is_sound_with_cell_bits<F>(
+    instrs: Vec<AstNode>,
+    transform: F,
+    check_cells: bool,
+    dummy_read_value: Option<i8>,
+    cell_bits: u32,
+) -> TestResult
 where
     F: Fn(Vec<AstNode>) -> Vec<AstNode>,
 {
@@ -18,7 +47,13 @@ where
 
     // First, we execute the program given.
     let mut state = ExecutionState::initial(&instrs[..]);
-    let result = execute_with_state(&instrs[..], &mut state, max_steps, dummy_read_value);
+    let result = execute_with_state(
+        &instrs[..],
+        &mut state,
+        max_steps,
+        dummy_read_value,
+        cell_bits,
+    );
 
     // Optimisations may change malformed programs to well-formed
     // programs, so we ignore programs that don't terminate nicely.
@@ -39,6 +74,7 @@ where
         &mut state2,
         max_steps,
         dummy_read_value,
+        cell_bits,
     );
 
     // Compare the outcomes: they should be the same.
@@ -173,10 +209,34 @@ fn sort_by_offset_is_sound() {
     quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
 }
 
+#[test]
+fn reassociate_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, reassociate, true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn evaluate_pure_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, |instrs| evaluate_pure_loops(instrs, 8), true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
+#[test]
+fn unroll_counted_loops_is_sound() {
+    fn is_sound(instrs: Vec<AstNode>) -> TestResult {
+        transform_is_sound(instrs, |instrs| unroll_counted_loops(instrs, 8), true, None)
+    }
+    quickcheck(is_sound as fn(Vec<AstNode>) -> TestResult)
+}
+
 #[test]
 fn test_overall_optimize_is_sound() {
     fn optimize_ignore_warnings(instrs: Vec<AstNode>) -> Vec<AstNode> {
-        optimize(instrs, &None).0
+        optimize(instrs, &OptConfig::default()).0
     }
 
     fn optimizations_sound_together(instrs: Vec<AstNode>, read_value: Option<i8>) -> TestResult {
@@ -188,3 +248,333 @@ fn test_overall_optimize_is_sound() {
 
     quickcheck(optimizations_sound_together as fn(Vec<AstNode>, Option<i8>) -> TestResult);
 }
+
+#[test]
+fn optimize_with_fuel_is_sound() {
+    fn optimizations_sound_with_fuel(instrs: Vec<AstNode>, fuel: u8) -> TestResult {
+        let optimize_with_fuel = |instrs| {
+            let config = OptConfig {
+                fuel: Some(u64::from(fuel)),
+                ..OptConfig::default()
+            };
+            optimize(instrs, &config).0
+        };
+        transform_is_sound(instrs, optimize_with_fuel, false, None)
+    }
+
+    quickcheck(optimizations_sound_with_fuel as fn(Vec<AstNode>, u8) -> TestResult);
+}
+
+#[test]
+fn optimize_is_sound_for_each_cell_size() {
+    fn optimizations_sound_with_cell_bits(
+        instrs: Vec<AstNode>,
+        read_value: Option<i8>,
+        cell_size: u8,
+    ) -> TestResult {
+        let cell_bits = [8, 16, 32][cell_size as usize % 3];
+        let optimize_with_cell_bits = |instrs| {
+            let config = OptConfig {
+                cell_bits,
+                ..OptConfig::default()
+            };
+            optimize(instrs, &config).0
+        };
+        transform_is_sound_with_cell_bits(
+            instrs,
+            optimize_with_cell_bits,
+            false,
+            read_value,
+            cell_bits,
+        )
+    }
+
+    quickcheck(
+        optimizations_sound_with_cell_bits as fn(Vec<AstNode>, Option<i8>, u8) -> TestResult,
+    );
+}
+
+#[test]
+fn optimize_is_sound_for_each_eof_behaviour() {
+    fn optimizations_sound_with_eof(
+        instrs: Vec<AstNode>,
+        input: Vec<u8>,
+        behaviour: u8,
+    ) -> TestResult {
+        let eof = [
+            EofBehaviour::Unchanged,
+            EofBehaviour::Zero,
+            EofBehaviour::MinusOne,
+        ][behaviour as usize % 3];
+        let limits = Limits {
+            steps: 1000,
+            ..Limits::default()
+        };
+
+        // Interpret rather than execute, so reads past the end of
+        // input really hit EOF.
+        let output = interpret_with_limits(&instrs, &input, DEFAULT_CELL_BITS, eof, limits);
+        if output.status != ExitStatus::Finished {
+            return TestResult::discard();
+        }
+
+        let config = OptConfig {
+            eof,
+            ..OptConfig::default()
+        };
+        let (optimised_instrs, _) = optimize(instrs, &config);
+        let optimised_output =
+            interpret_with_limits(&optimised_instrs, &input, DEFAULT_CELL_BITS, eof, limits);
+        if optimised_output.status != ExitStatus::Finished {
+            println!("Optimised program did not terminate properly!");
+            return TestResult::failed();
+        }
+        if output.bytes != optimised_output.bytes {
+            println!(
+                "Different outputs! Original outputs: {:?} Optimised: {:?}",
+                output.bytes, optimised_output.bytes
+            );
+            return TestResult::failed();
+        }
+        TestResult::passed()
+    }
+
+    quickcheck(optimizations_sound_with_eof as fn(Vec<AstNode>, Vec<u8>, u8) -> TestResult);
+}
+
+/// Check that speculatively executing `instrs` with `steps` steps,
+/// then running the residual program with `input` as the compiled
+/// program would, behaves the same as interpreting `instrs`.
+fn speculation_is_sound(
+    instrs: &[AstNode],
+    input: &[u8],
+    steps: u64,
+    cell_bits: u32,
+    eof: EofBehaviour,
+) -> TestResult {
+    let limits = Limits {
+        steps: 1000,
+        ..Limits::default()
+    };
+    let output = interpret_with_limits(instrs, input, cell_bits, eof, limits);
+    if output.status != ExitStatus::Finished {
+        return TestResult::discard();
+    }
+
+    let (state, outcome) = execute_with_limits(
+        instrs,
+        &[],
+        cell_bits,
+        eof,
+        Limits {
+            steps,
+            ..Limits::default()
+        },
+    );
+    if let RuntimeError(_) = outcome {
+        println!("Speculative execution failed, but the program finished");
+        return TestResult::failed();
+    }
+
+    let residual_output = interpret_residual(instrs, &state, input, cell_bits, eof, 100_000);
+    if residual_output.status != ExitStatus::Finished {
+        println!(
+            "Residual program did not terminate properly: {:?}",
+            residual_output.status
+        );
+        return TestResult::failed();
+    }
+    if output.bytes != residual_output.bytes {
+        println!(
+            "Different outputs! Interpreted: {:?} Speculative and residual: {:?}",
+            output.bytes, residual_output.bytes
+        );
+        return TestResult::failed();
+    }
+    TestResult::passed()
+}
+
+#[test]
+fn speculative_execution_is_sound() {
+    fn is_sound(
+        instrs: Vec<AstNode>,
+        input: Vec<u8>,
+        steps: u16,
+        cell_size: u8,
+        behaviour: u8,
+    ) -> TestResult {
+        let cell_bits = [8, 16, 32][cell_size as usize % 3];
+        let eof = [
+            EofBehaviour::Unchanged,
+            EofBehaviour::Zero,
+            EofBehaviour::MinusOne,
+        ][behaviour as usize % 3];
+        // Optimising gives us multiply moves and sets, which
+        // speculative execution can continue past on values read.
+        let (instrs, _) = optimize(
+            instrs,
+            &OptConfig {
+                cell_bits,
+                eof,
+                ..OptConfig::default()
+            },
+        );
+        speculation_is_sound(&instrs, &input, u64::from(steps % 500), cell_bits, eof)
+    }
+
+    quickcheck(is_sound as fn(Vec<AstNode>, Vec<u8>, u16, u8, u8) -> TestResult);
+}
+
+#[test]
+fn speculation_past_reads_is_sound() {
+    // Each of these continues past a read at compile time: deferring
+    // the read, doing arithmetic on it, moving it with a multiply
+    // move, splitting a loop on it, or entering a loop it must be
+    // nonzero for.
+    let programs = [
+        ",>+++.<.",
+        ",+++>++.<.",
+        ",[->++<]>.",
+        ",[>+<[-]]>.>,.",
+        ",[[-]>+++<]>.",
+        ",>,<[->>+<<]>>.",
+        ",+[>++<[-]]>.",
+        "+>,[-]<.",
+    ];
+    let inputs: [&[u8]; 4] = [b"", b"\x00", b"a", b"\xffz"];
+    for program in &programs {
+        for &cell_bits in &[8, 16, 32] {
+            for &eof in &[EofBehaviour::Zero, EofBehaviour::MinusOne] {
+                let (instrs, _) = optimize(
+                    parse(program).unwrap(),
+                    &OptConfig {
+                        cell_bits,
+                        eof,
+                        ..OptConfig::default()
+                    },
+                );
+                let (state, _) =
+                    execute_with_limits(&instrs, &[], cell_bits, eof, Limits::default());
+                assert!(!state.reads.is_empty(), "{} didn't defer a read", program);
+
+                for input in &inputs {
+                    for steps in 0..20 {
+                        let result = speculation_is_sound(&instrs, input, steps, cell_bits, eof);
+                        assert!(
+                            !result.is_failure(),
+                            "{} with input {:?} after {} steps",
+                            program,
+                            input,
+                            steps
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Execute `instrs` on a tape that grows as cells are accessed, and
+/// check the tape never grows beyond the bound we calculated.
+fn bounds_are_sound(instrs: Vec<AstNode>) -> bool {
+    let max_steps = 1000;
+
+    let mut state = ExecutionState::with_tape(
+        &instrs[..],
+        Tape::Growable {
+            max_cells: MAX_CELL_INDEX + 1,
+        },
+    );
+    execute_with_state(
+        &instrs[..],
+        &mut state,
+        max_steps,
+        Some(0),
+        DEFAULT_CELL_BITS,
+    );
+
+    let highest_index = highest_cell_index(&instrs);
+    if state.cells.len() > highest_index + 1 {
+        println!(
+            "Used {} cells, but bounds allowed {}",
+            state.cells.len(),
+            highest_index + 1
+        );
+        return false;
+    }
+    true
+}
+
+#[test]
+fn highest_cell_index_is_sound() {
+    quickcheck(bounds_are_sound as fn(Vec<AstNode>) -> bool)
+}
+
+#[test]
+fn offset_bounds_are_sound() {
+    let instrs = vec![
+        Set {
+            amount: Wrapping(1),
+            offset: 3,
+            position: None,
+        },
+        Increment {
+            amount: Wrapping(1),
+            offset: 5,
+            position: None,
+        },
+    ];
+    assert!(bounds_are_sound(instrs));
+}
+
+#[test]
+fn multiply_move_bounds_are_sound() {
+    let mut changes = HashMap::new();
+    changes.insert(-1, Wrapping(1));
+    changes.insert(4, Wrapping(2));
+    let instrs = vec![
+        PointerIncrement {
+            amount: 1,
+            position: None,
+        },
+        Set {
+            amount: Wrapping(3),
+            offset: 0,
+            position: None,
+        },
+        MultiplyMove {
+            changes,
+            adds: HashMap::new(),
+            position: None,
+        },
+    ];
+    assert!(bounds_are_sound(instrs));
+}
+
+#[test]
+fn io_bounds_are_sound() {
+    let instrs = vec![
+        WriteString {
+            bytes: vec![b'a'],
+            position: None,
+        },
+        SkipRead {
+            count: 2,
+            position: None,
+        },
+        PointerIncrement {
+            amount: 2,
+            position: None,
+        },
+        Read { position: None },
+        Write { position: None },
+    ];
+    assert!(bounds_are_sound(instrs));
+}
+
+#[test]
+fn loop_bounds_are_sound() {
+    for source in &["+[>>+<<-]", "+>>>[<+<]", "+[>[-]+>+]", "+[>+[>]<<-]"] {
+        assert!(bounds_are_sound(parse(source).unwrap()));
+    }
+}